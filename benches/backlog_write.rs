@@ -0,0 +1,90 @@
+//! Compares writing a backlog of many small messages (e.g. DataRows) with one try_write per message,
+//! to flushing it with Connection::write_backlog, which writes it with try_write_vectored in batches.
+//! Reports the time per message. Requires unix sockets.
+//!
+//! Run with: cargo bench --bench backlog_write
+
+#[cfg(unix)]
+mod unix {
+    use std::io::Read;
+    use std::time::Instant;
+
+    use bytes::Bytes;
+    use tokio::io::Interest;
+
+    use riverdb::riverdb::config::{init_config, Settings};
+    use riverdb::riverdb::pg::{ClientConn, Connection};
+    use riverdb::riverdb::server::{Connection as ServerConnection, Connections};
+
+    const ITERATIONS: u64 = 20_000;
+    /// The number of messages in the backlog, matches MAX_WRITE_CHUNKS in pg/connection.rs
+    const BACKLOG_MESSAGES: usize = 64;
+
+    /// Write all of backlog to client, with a single try_write per message, or by adding it to the
+    /// client's backlog and flushing that with Connection::try_write_backlog if vectored is true.
+    async fn write_backlog(client: &ClientConn, backlog: &[Bytes], vectored: bool) {
+        if vectored {
+            client.backlog().lock().unwrap().extend(backlog.iter().cloned());
+            client.set_has_backlog(true);
+            while client.has_backlog() {
+                client.transport().ready(Interest::WRITABLE).await.unwrap();
+                client.try_write_backlog().unwrap();
+            }
+            return;
+        }
+        for bytes in backlog {
+            let mut offset = 0;
+            while offset < bytes.len() {
+                let n = client.transport().try_write(&bytes[offset..]).unwrap();
+                if n == 0 {
+                    client.transport().ready(Interest::WRITABLE).await.unwrap();
+                }
+                offset += n;
+            }
+        }
+    }
+
+    async fn bench(name: &str, message_size: usize, vectored: bool) {
+        let (writer, mut reader) = std::os::unix::net::UnixStream::pair().unwrap();
+        writer.set_nonblocking(true).unwrap();
+        let client = ClientConn::new_unix(tokio::net::UnixStream::from_std(writer).unwrap(), Connections::new(1, 0));
+        let drain = std::thread::spawn(move || {
+            let mut buf = vec![0; 256 * 1024];
+            while reader.read(&mut buf).unwrap() != 0 {}
+        });
+
+        let backlog: Vec<Bytes> = (0..BACKLOG_MESSAGES).map(|_| Bytes::from(vec![b'D'; message_size])).collect();
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            write_backlog(&client, &backlog, vectored).await;
+        }
+        let elapsed = start.elapsed();
+        client.transport().close();
+        drain.join().unwrap();
+
+        let messages = (ITERATIONS * BACKLOG_MESSAGES as u64) as f64;
+        println!("{:<13} {:>5} bytes {:>8.1} ns/message",
+                 name,
+                 message_size,
+                 elapsed.as_nanos() as f64 / messages);
+    }
+
+    pub fn main() {
+        // ClientConn reads the settings
+        init_config(Settings::default()).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap();
+        runtime.block_on(async {
+            for message_size in [32, 256, 2048] {
+                bench("try_write", message_size, false).await;
+                bench("write_backlog", message_size, true).await;
+            }
+        });
+    }
+}
+
+fn main() {
+    #[cfg(unix)]
+    unix::main();
+    #[cfg(not(unix))]
+    println!("backlog_write requires unix sockets, skipping");
+}
@@ -0,0 +1,131 @@
+//! Measures the per-message overhead of dispatching an event defined with define_event!,
+//! with no plugins, with a plugin, and with a plugin that's skipped by its filter or disabled.
+//! Reports the time and the number of heap allocations per dispatch.
+//!
+//! Run with: cargo bench --bench event_dispatch
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::future::Future;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::time::Instant;
+
+use riverdb::{define_event, event_listener, Plugin};
+use riverdb::riverdb::Result;
+use riverdb::plugins::set_listener_enabled;
+
+/// Counts the allocations made by the process, to check the fast paths don't allocate
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const ITERATIONS: u64 = 1_000_000;
+
+pub struct Session {
+    database: &'static str,
+}
+
+impl Session {
+    async fn no_plugins(&self, _: &mut no_plugins::Event, len: usize) -> Result<usize> {
+        Ok(len)
+    }
+
+    async fn one_plugin(&self, _: &mut one_plugin::Event, len: usize) -> Result<usize> {
+        Ok(len)
+    }
+
+    async fn filtered_plugin(&self, _: &mut filtered_plugin::Event, len: usize) -> Result<usize> {
+        Ok(len)
+    }
+
+    async fn disabled_plugin(&self, _: &mut disabled_plugin::Event, len: usize) -> Result<usize> {
+        Ok(len)
+    }
+}
+
+define_event!(no_plugins, (session: &'a Session, len: usize) -> Result<usize>);
+define_event!(one_plugin, (session: &'a Session, len: usize) -> Result<usize>);
+define_event!(filtered_plugin, (session: &'a Session, len: usize) -> Result<usize>);
+define_event!(disabled_plugin, (session: &'a Session, len: usize) -> Result<usize>);
+
+/// A plugin that counts messages, like a simple metrics or logging plugin would
+#[derive(Default)]
+struct CountingPlugin {
+    messages: AtomicU64,
+}
+
+impl CountingPlugin {
+    fn wants_session(&self, session: &Session) -> bool {
+        session.database == "audited"
+    }
+
+    async fn one_plugin(&self, ev: &mut one_plugin::Event, session: &Session, len: usize) -> Result<usize> {
+        self.messages.fetch_add(1, Relaxed);
+        ev.next(session, len).await
+    }
+
+    async fn filtered_plugin(&self, ev: &mut filtered_plugin::Event, session: &Session, len: usize) -> Result<usize> {
+        self.messages.fetch_add(1, Relaxed);
+        ev.next(session, len).await
+    }
+
+    async fn disabled_plugin(&self, ev: &mut disabled_plugin::Event, session: &Session, len: usize) -> Result<usize> {
+        self.messages.fetch_add(1, Relaxed);
+        ev.next(session, len).await
+    }
+}
+
+impl Plugin for CountingPlugin {}
+
+/// Run the future returned by f ITERATIONS times and print the time and allocations per iteration.
+async fn bench<'a, F, Fut>(name: &str, f: F)
+    where F: Fn() -> Fut,
+          Fut: Future<Output=Result<usize>> + 'a
+{
+    let allocations = ALLOCATIONS.load(Relaxed);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(f().await.unwrap());
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Relaxed) - allocations;
+    println!("{:<16} {:>8.1} ns/message {:>6.2} allocations/message",
+             name,
+             elapsed.as_nanos() as f64 / ITERATIONS as f64,
+             allocations as f64 / ITERATIONS as f64);
+}
+
+fn main() {
+    let plugin: &'static CountingPlugin = Box::leak(Box::new(CountingPlugin::default()));
+    event_listener!(plugin, CountingPlugin:one_plugin<'a>(len: usize) -> Result<usize>);
+    event_listener!(plugin, CountingPlugin:filtered_plugin<'a>(len: usize) -> Result<usize>, if wants_session);
+    event_listener!(plugin, CountingPlugin:disabled_plugin<'a>(len: usize) -> Result<usize>);
+    unsafe {
+        riverdb::configure();
+    }
+    set_listener_enabled("CountingPlugin", Some("disabled_plugin"), false);
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    runtime.block_on(async {
+        let session = Session{database: "app"};
+        let session = &session;
+        bench("no_plugins", || no_plugins::run(session, black_box(1))).await;
+        bench("one_plugin", || one_plugin::run(session, black_box(1))).await;
+        bench("filtered_plugin", || filtered_plugin::run(session, black_box(1))).await;
+        bench("disabled_plugin", || disabled_plugin::run(session, black_box(1))).await;
+    });
+}
@@ -8,17 +8,26 @@ pub use crate::riverdb::*;
 
 use std::io;
 
-use tokio::runtime::{Runtime, Builder};
+use tokio::runtime::{Runtime, Builder, Handle};
 use tracing_subscriber::FmtSubscriber;
-use tracing::{info_span, Level};
+use tracing::{info_span, info, warn, Level};
 
 use crate::riverdb::worker::Worker;
 use crate::riverdb::config::{Settings, load_config};
-use crate::riverdb::pg::PostgresService;
+use crate::riverdb::pg::{PostgresService, PostgresCluster, log_startup, register_query_guard};
+use crate::riverdb::server::ListenerOptions;
+use crate::riverdb::http::HttpService;
+use crate::riverdb::pg::Reloader;
+#[cfg(unix)]
+use crate::riverdb::pg::{dump_state_on_signal, reload_on_signal};
+use crate::riverdb::peers::Peers;
+use crate::riverdb::memory_governor::run_memory_governor;
 use crate::riverdb::worker::init_workers;
 use crate::riverdb::common::{Result, coarse_monotonic_clock_updater};
 
 
+/// Install a global tracing subscriber logging events up to max_level to stdout.
+/// The server configures logging from the log settings instead, see logging::init_logging.
 pub fn init_tracing(max_level: Level) {
     let subscriber = FmtSubscriber::builder()
         // all spans/events with a level higher than TRACE (e.g, debug, info, warn, etc.)
@@ -37,6 +46,18 @@ pub fn init_settings() -> Result<&'static Settings> {
     load_config("riverdb.yaml")
 }
 
+/// Order the event listeners registered with event_listener! and configure the plugins.
+/// Call after registering the plugins and loading the settings, before run_servers.
+pub fn init_plugins(conf: &'static Settings) -> Result<()> {
+    // The built-in plugins are only registered if they're configured
+    register_query_guard(conf)?;
+    // This is unsafe to call after the server starts. It's safe here.
+    unsafe {
+        configure();
+    }
+    configure_plugins(conf)
+}
+
 pub fn init_runtime(conf: &'static Settings) -> io::Result<Runtime> {
     // This is unsafe to call after the server starts. It's safe here.
     unsafe {
@@ -54,9 +75,26 @@ pub fn init_runtime(conf: &'static Settings) -> io::Result<Runtime> {
 
 pub fn run_servers(conf: &'static Settings, tokio: &Runtime) {
     tokio.block_on(async move {
+        // Let plugins spawn their background tasks
+        start_plugins(&Handle::current());
+
         // Update the coarse monotonic clock on a periodic basis
         tokio::spawn(coarse_monotonic_clock_updater());
 
+        // Exchange state with other riverdb instances in cluster mode
+        if conf.peers.enabled {
+            tokio::spawn(Peers::singleton().run());
+        }
+
+        // Shed memory from the cache, pools, and backlogs while over the soft memory limit
+        if conf.memory_limit.soft_limit_bytes != 0 {
+            tokio::spawn(run_memory_governor(&conf.memory_limit));
+        }
+
+        for cluster in PostgresCluster::all() {
+            cluster.spawn_background_tasks();
+        }
+
         let mut handles = Vec::new();
         // If reuseport is false, we create a single TcpListener.
         // Otherwise we create one per tokio worker. This reduces contention sharing accepted
@@ -64,28 +102,51 @@ pub fn run_servers(conf: &'static Settings, tokio: &Runtime) {
         // in accept. The downside is it won't error if you assign a port that is in use.
         // (hopefully these end up distributed nicely across tokio worker threads,
         // but I don't see a way to control that.)
-        let _num_listeners = if conf.reuseport { conf.num_workers } else { 1 };
-
-        // Postgres service
-        if conf.postgres.port != 0 {
-            handles.push(tokio::spawn(async move {
-                let service = PostgresService::new(
-                    conf.postgres_listen_address(),
-                    conf.postgres.max_connections,
-                    conf.postgres.idle_timeout_seconds,
-                    conf.reuseport);
-                service.run().await
-            }));
+        let num_listeners = if conf.reuseport { conf.num_workers } else { 1 };
+
+        // Postgres services, one per cluster, each listening on its own port
+        let mut services = Vec::new();
+        for cluster in PostgresCluster::all() {
+            if cluster.config.port == 0 {
+                continue;
+            }
+            let service: &'static PostgresService = Box::leak(Box::new(PostgresService::with_addresses(
+                conf.cluster_listen_addresses(cluster.config),
+                num_listeners,
+                cluster,
+                ListenerOptions::from_settings(conf))));
+
+            for i in 0..service.listener_count() {
+                handles.push(tokio::spawn(service.run_listener(i)));
+            }
+            #[cfg(unix)]
+            handles.push(tokio::spawn(service.run_unix()));
+            services.push(service);
         }
 
-        // // HTTP service
-        // if conf.http_port != 0 {
-        //     handles.push(tokio::spawn(async {
-        //         let service = HttpService::new(conf.http_listen_address(), conf.reuseport);
-        //         service.run().await
-        //     }));
-        // }
-        //
+        // Reload the config file on SIGHUP (or the RELOAD admin command)
+        Reloader::singleton().set_services(services.clone());
+        #[cfg(unix)]
+        tokio::spawn(reload_on_signal());
+
+        // Write a diagnostic snapshot of the server state to the log on SIGUSR1
+        #[cfg(unix)]
+        tokio::spawn(dump_state_on_signal(services.clone()));
+
+        // HTTP service for health checks, metrics, and status
+        if conf.http_port != 0 {
+            let tls_config = if conf.http_tls { conf.postgres.tls_config.clone() } else { None };
+            let service: &'static HttpService = Box::leak(Box::new(HttpService::new(
+                conf.http_listen_address(),
+                ListenerOptions::from_settings(conf),
+                tls_config,
+                services.clone())));
+            handles.push(tokio::spawn(service.run()));
+        }
+
+        // The listeners are bound, tell supervisors (and tests) we're ready
+        log_startup(&services);
+
         // // HTTPS service
         // if conf.https_port != 0 {
         //     handles.push(tokio::spawn(async {
@@ -94,9 +155,36 @@ pub fn run_servers(conf: &'static Settings, tokio: &Runtime) {
         //     }));
         // }
 
-        // Wait for all listener tasks to shutdown
-        for handle in handles.drain(..) {
-            handle.await.expect("join failed");
+        // Wait for all listener tasks to shutdown, or for a signal to shut down gracefully
+        tokio::select! {
+            results = futures::future::join_all(handles) => {
+                for result in results {
+                    result.expect("join failed");
+                }
+            },
+            _ = shutdown_signal() => info!("shutting down"),
         }
+
+        // Give plugins a chance to flush their state before the process exits
+        shutdown_plugins();
     });
+}
+
+/// Completes when the process receives SIGTERM or SIGINT (Ctrl-C).
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => (),
+                    _ = tokio::signal::ctrl_c() => (),
+                }
+                return;
+            },
+            Err(e) => warn!(?e, "could not install SIGTERM handler"),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
 }
\ No newline at end of file
@@ -3,29 +3,35 @@
 
 pub mod riverdb;
 
-use tracing::{info_span, Level};
+use tracing::info_span;
 
-use ::riverdb::{init_tracing, init_settings, init_runtime, run_servers};
+use ::riverdb::{init_settings, init_plugins, init_runtime, run_servers};
+use ::riverdb::logging::init_logging;
+#[cfg(unix)]
+use ::riverdb::supervisor::{supervise, SUPERVISE_FLAG};
 
 fn main() {
-    // TODO start a watchdog process (that won't die when this process dies!)
-    // which monitors this process and restarts it with the same command line arguments if it dies.
-    // If we intentionally shut it down, we kill the watchdog here first before exiting.
+    let conf = init_settings().expect("could not load config");
+
+    // With --supervise this process only restarts the worker process running the server if it dies
+    #[cfg(unix)]
+    {
+        if std::env::args().skip(1).any(|arg| arg == SUPERVISE_FLAG) {
+            supervise(conf).expect("supervisor failed");
+            return;
+        }
+    }
 
-    init_tracing(Level::TRACE);
+    init_logging(&conf.log).expect("could not configure logging");
 
     let _span = info_span!("startup").entered();
 
-    let conf = init_settings().expect("could not load config");
+    init_plugins(conf).expect("could not configure plugins");
 
     let tokio = init_runtime(conf).expect("could not create tokio runtime");
 
-    // TODO catch panics and gracefully shutdown the process
-    // The most common cause of a panic will be OOM, and that's best dealt with by
-    // restarting gracefully to eliminate any memory fragmentation.
-    // The next most common causes would be bugs and hardware errors. Neither of those
-    // necessarily leave the system in a good state, so restarting is the best we can hope for.
-    // std::panic::set_hook();
+    // Panics in connection tasks are caught and only close the affected connections (see catch_panic.)
+    // Anything worse, like running out of memory, takes down the process, which --supervise restarts.
 
     run_servers(conf, &tokio);
 
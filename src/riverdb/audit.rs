@@ -0,0 +1,416 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicPtr, AtomicU64};
+use std::sync::atomic::Ordering::{Acquire, AcqRel, Relaxed};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+use chrono::{SecondsFormat, Utc};
+use serde_json::json;
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::config::{conf, AuditSettings};
+use crate::riverdb::pg::sql::{QueryMessage, QueryType};
+
+
+/// The path of the local syslog socket
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// The syslog priority of the records: facility authpriv (10), severity notice (5)
+#[cfg(unix)]
+const SYSLOG_PRIORITY: u32 = 10 * 8 + 5;
+
+/// Returns the type of the first statement in query (including every Parse of a pipelined group)
+/// that's audited (DDL, SET ROLE, or COPY), if any. A truncated query is audited with the type of its
+/// first statement, the statements after max_normalize_bytes can't be checked.
+pub fn audited_type(query: &QueryMessage) -> Option<QueryType> {
+    let audited = query.statements().map(|q| q.query_type()).find(|ty| matches!(ty,
+        QueryType::Create | QueryType::Alter | QueryType::Drop | QueryType::Grant | QueryType::Revoke |
+        QueryType::SetRole | QueryType::Copy));
+    if audited.is_none() && query.is_truncated() {
+        return Some(query.query().query_type());
+    }
+    audited
+}
+
+/// An audited query, as recorded in the audit log.
+pub struct AuditQuery<'a> {
+    pub client_id: u32,
+    pub ip: Option<IpAddr>,
+    pub user: &'a str,
+    pub database: &'a str,
+    /// the type of the first audited statement in the query
+    pub ty: QueryType,
+    /// the full SQL text of the query as sent by the client
+    pub sql: &'a str,
+}
+
+/// AuditLog is the append-only log of audited queries (see audit settings.) Each query is recorded before it's
+/// sent to the database, and flushed to disk if fsync is set, then its outcome is recorded when it completes.
+/// Records are JSON objects, one per line, linked by their id. They're written by a dedicated thread (see AuditWriter.)
+pub struct AuditLog {
+    fsync: bool,
+    next_id: AtomicU64,
+    records: mpsc::UnboundedSender<AuditRecord>,
+}
+
+/// A record for the AuditWriter, with the channel to report the result of writing it, if the sender waits for it.
+struct AuditRecord {
+    line: String,
+    sync: bool,
+    written: Option<oneshot::Sender<Result<()>>>,
+}
+
+/// AuditWriter appends the records to the file and syslog on its own thread,
+/// so the blocking writes and fsyncs don't stall the tokio worker threads.
+struct AuditWriter {
+    path: PathBuf,
+    max_file_bytes: u64,
+    max_files: u32,
+    syslog: bool,
+    file: Option<AuditFile>,
+}
+
+/// The open audit log file and its size
+struct AuditFile {
+    file: File,
+    size: u64,
+}
+
+impl AuditLog {
+    /// Create the AuditLog and start its writer thread, which exits when the AuditLog is dropped.
+    pub fn new(settings: &AuditSettings) -> Self {
+        let (records, receiver) = mpsc::unbounded_channel();
+        let writer = AuditWriter{
+            path: PathBuf::from(&settings.file),
+            max_file_bytes: settings.max_file_bytes,
+            max_files: settings.max_files,
+            syslog: settings.syslog,
+            file: None,
+        };
+        std::thread::Builder::new()
+            .name("riverdb-audit".to_string())
+            .spawn(move || writer.run(receiver))
+            .expect("could not start the audit log writer thread");
+        Self{
+            fsync: settings.fsync,
+            next_id: AtomicU64::new(1),
+            records,
+        }
+    }
+
+    /// Returns the audit log for the configured audit settings, or None if auditing is disabled.
+    pub fn singleton() -> Option<&'static Self> {
+        static SINGLETON_AUDIT_LOG: AtomicPtr<AuditLog> = AtomicPtr::new(std::ptr::null_mut());
+        let settings = &conf().audit;
+        if !settings.is_enabled() {
+            return None;
+        }
+        unsafe {
+            let mut p = SINGLETON_AUDIT_LOG.load(Acquire);
+            if p.is_null() {
+                let mut log = Box::new(AuditLog::new(settings));
+                p = log.as_mut() as _;
+                match SINGLETON_AUDIT_LOG.compare_exchange(std::ptr::null_mut(), p, AcqRel, Acquire) {
+                    Ok(_) => {
+                        std::mem::forget(log);
+                    },
+                    Err(current) => {
+                        p = current;
+                    },
+                }
+            }
+            Some(&*p)
+        }
+    }
+
+    /// Record query before it's sent to the database, returns the id of the record for record_outcome
+    /// once it's written (and flushed to disk if fsync is set.) If this returns an error, the query must not be sent.
+    pub async fn record_query(&self, query: &AuditQuery<'_>) -> Result<u64> {
+        let id = self.next_id.fetch_add(1, Relaxed);
+        let record = json!({
+            "time": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "id": id,
+            "client": query.client_id,
+            "ip": query.ip.map(|ip| ip.to_string()),
+            "user": query.user,
+            "database": query.database,
+            "type": query.ty.to_string(),
+            "sql": query.sql,
+        });
+        let (written, result) = oneshot::channel();
+        self.send(AuditRecord{line: record.to_string(), sync: self.fsync, written: Some(written)})?;
+        result.await.map_err(|_| Error::new("the audit log writer stopped"))??;
+        Ok(id)
+    }
+
+    /// Record the outcome (e.g. success or error) of the query with the given record id.
+    /// This doesn't wait for the record to be written, errors writing it are logged by the writer.
+    pub fn record_outcome(&self, id: u64, outcome: &str) -> Result<()> {
+        let record = json!({
+            "time": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "id": id,
+            "outcome": outcome,
+        });
+        self.send(AuditRecord{line: record.to_string(), sync: false, written: None})
+    }
+
+    fn send(&self, record: AuditRecord) -> Result<()> {
+        self.records.send(record).map_err(|_| Error::new("the audit log writer stopped"))
+    }
+}
+
+impl AuditWriter {
+    /// Write the records received until the AuditLog is dropped.
+    fn run(mut self, mut receiver: mpsc::UnboundedReceiver<AuditRecord>) {
+        while let Some(record) = receiver.blocking_recv() {
+            let result = self.write(&record.line, record.sync);
+            match record.written {
+                Some(written) => {
+                    // The sender may have given up waiting, there's nothing else to do with the result
+                    let _ = written.send(result);
+                },
+                None => if let Err(e) = result {
+                    warn!(%e, "could not write audit record");
+                },
+            }
+        }
+    }
+
+    /// Append the record to the file, and send it to syslog if enabled.
+    fn write(&mut self, record: &str, sync: bool) -> Result<()> {
+        #[cfg(unix)]
+        if self.syslog {
+            if let Err(e) = send_syslog(record) {
+                // The file is the durable record, unless it's the only sink
+                if self.path.as_os_str().is_empty() {
+                    return Err(e);
+                }
+                warn!(%e, "could not send audit record to syslog");
+            }
+        }
+        if self.path.as_os_str().is_empty() {
+            return Ok(());
+        }
+
+        if self.file.is_none() {
+            self.file = Some(AuditFile::open(&self.path)?);
+        }
+        let f = self.file.as_mut().unwrap();
+        if let Err(e) = f.append(record, sync) {
+            // Reopen it for the next record
+            self.file = None;
+            return Err(e);
+        }
+        if self.max_file_bytes != 0 && f.size >= self.max_file_bytes {
+            self.file = None;
+            // The record was written, so this doesn't fail it. If the file wasn't renamed, it's reopened and appended to.
+            if let Err(e) = self.rotate() {
+                warn!(%e, path = %self.path.display(), "could not rotate the audit log");
+            }
+        }
+        Ok(())
+    }
+
+    /// Renames the file to file.1, file.1 to file.2, and so on, removing the oldest if there are max_files.
+    fn rotate(&self) -> Result<()> {
+        let rotated = |i: u32| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", i));
+            PathBuf::from(name)
+        };
+        let _ = fs::remove_file(rotated(self.max_files));
+        for i in (1..self.max_files).rev() {
+            let from = rotated(i);
+            if from.exists() {
+                fs::rename(&from, rotated(i + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated(1))?;
+        Ok(())
+    }
+}
+
+impl AuditFile {
+    fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| Error::new(format!("could not open audit log {}: {}", path.display(), e)))?;
+        let size = file.metadata()?.len();
+        Ok(Self{file, size})
+    }
+
+    fn append(&mut self, record: &str, sync: bool) -> Result<()> {
+        let mut line = String::with_capacity(record.len() + 1);
+        line.push_str(record);
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        if sync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn send_syslog(record: &str) -> Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to(format!("<{}>riverdb: {}", SYSLOG_PRIORITY, record).as_bytes(), SYSLOG_SOCKET)?;
+    Ok(())
+}
+
+/// PendingAudits matches the audited queries of a client to their completion by the database, which answers
+/// the requests (Query or Sync messages) in the order they were sent.
+#[derive(Default)]
+pub struct PendingAudits {
+    /// the number of requests sent
+    sent: u64,
+    /// the number of requests completed
+    completed: u64,
+    /// the request that completes each audited query, and the id of its audit record
+    pending: VecDeque<(u64, u64)>,
+}
+
+impl PendingAudits {
+    /// Called when a query with the given number of requests is sent to the database,
+    /// with the id of its audit record if it's audited.
+    pub fn sent(&mut self, requests: u32, id: Option<u64>) {
+        self.sent += requests as u64;
+        if let Some(id) = id {
+            // Extended query protocol messages ending in Flush complete with the next Sync
+            let request = if requests == 0 { self.sent + 1 } else { self.sent };
+            self.pending.push_back((request, id));
+        }
+    }
+
+    /// Called when a Sync sent isn't a request, because the database ignores it during COPY FROM STDIN.
+    pub fn ignored(&mut self) {
+        self.sent = self.sent.saturating_sub(1);
+    }
+
+    /// Called when the database completes a request.
+    pub fn completed(&mut self) {
+        self.completed += 1;
+    }
+
+    /// Called when the database connection failed, the requests sent but not completed never will be.
+    /// Returns the ids of the audit records of the queries that were waiting for them.
+    pub fn abandon(&mut self) -> Vec<u64> {
+        self.completed = self.sent;
+        self.pending.drain(..).map(|(_, id)| id).collect()
+    }
+
+    /// Returns the id of the audit record of a query completed by the requests completed so far, if any.
+    pub fn pop_completed(&mut self) -> Option<u64> {
+        match self.pending.front() {
+            Some(&(request, id)) if request <= self.completed => {
+                self.pending.pop_front();
+                Some(id)
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riverdb::pg::protocol::{MessageBuilder, Tag};
+    use crate::riverdb::pg::sql::NormalizeOptions;
+
+    fn query(sql: &str) -> QueryMessage {
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str(sql);
+        QueryMessage::new(mb.finish()).unwrap()
+    }
+
+    #[test]
+    fn test_audited_type() {
+        assert_eq!(audited_type(&query("create table t (id int)"), Some(QueryType::Create));
+        assert_eq!(audited_type(&query("select 1; drop table t"), Some(QueryType::Drop));
+        assert_eq!(audited_type(&query("set role admin"), Some(QueryType::SetRole));
+        assert_eq!(audited_type(&query("copy t to stdout"), Some(QueryType::Copy));
+        assert_eq!(audited_type(&query("grant select on t to web"), Some(QueryType::Grant));
+        assert_eq!(audited_type(&query("insert into t values (1)"), None);
+        assert_eq!(audited_type(&query("set search_path to app"), None);
+
+        // The statements after max_normalize_bytes can't be checked, so a truncated query is audited
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str("insert into t values (1), (2), (3); drop table t");
+        let truncated = QueryMessage::new_with_options(mb.finish(), NormalizeOptions{max_bytes: 24, ..Default::default()}).unwrap();
+        assert!(truncated.is_truncated());
+        assert_eq!(audited_type(&truncated), Some(QueryType::Insert));
+    }
+
+    #[test]
+    fn test_pending_audits() {
+        let mut pending = PendingAudits::default();
+        pending.sent(1, None);
+        pending.sent(1, Some(7));
+        pending.sent(0, Some(8));
+        pending.sent(1, None);
+        pending.completed();
+        assert_eq!(pending.pop_completed(), None);
+        pending.completed();
+        assert_eq!(pending.pop_completed(), Some(7));
+        assert_eq!(pending.pop_completed(), None);
+        pending.completed();
+        assert_eq!(pending.pop_completed(), Some(8));
+        assert_eq!(pending.pop_completed(), None);
+
+        // The queries sent on a failed connection are abandoned, the next connection's completions don't match them
+        pending.sent(1, Some(9));
+        pending.sent(1, Some(10));
+        assert_eq!(pending.abandon(), vec![9, 10]);
+        pending.sent(1, Some(11));
+        pending.completed();
+        assert_eq!(pending.pop_completed(), Some(11));
+    }
+
+    #[tokio::test]
+    async fn test_write_and_rotate() {
+        let dir = std::env::temp_dir().join(format!("riverdb-audit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let settings = AuditSettings{
+            file: path.to_str().unwrap().to_string(),
+            max_file_bytes: 400,
+            max_files: 2,
+            fsync: true,
+            syslog: false,
+        };
+        let log = AuditLog::new(&settings);
+        let sql = "DROP TABLE accounts";
+        let record = AuditQuery{client_id: 3, ip: Some("10.0.0.1".parse().unwrap()), user: "admin", database: "app", ty: QueryType::Drop, sql};
+        let id = log.record_query(&record).await.unwrap();
+        log.record_outcome(id, "success").unwrap();
+        // Records are written in order, so the outcome was written before this returns
+        let next_id = log.record_query(&record).await.unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["id"], id);
+        assert_eq!(lines[0]["sql"], sql);
+        assert_eq!(lines[0]["type"], "Drop");
+        assert_eq!(lines[0]["ip"], "10.0.0.1");
+        assert_eq!(lines[1]["id"], id);
+        assert_eq!(lines[1]["outcome"], "success");
+        assert_eq!(lines[2]["id"], next_id);
+
+        for _ in 0..10 {
+            log.record_query(&record).await.unwrap();
+        }
+        let rotated = |i: u32| PathBuf::from(format!("{}.{}", path.display(), i));
+        assert!(rotated(1).exists());
+        assert!(rotated(2).exists());
+        assert!(!rotated(3).exists());
+        assert!(fs::metadata(rotated(1)).unwrap().len() >= 400);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt::Write;
+
+use crate::riverdb::config::CachePolicy;
+use crate::riverdb::cache::{CacheEntry, ResultCache};
+use crate::riverdb::pg::protocol::{Messages, Tag};
+use crate::riverdb::pg::sql::Query;
+
+
+/// Returns the result cache key for query, sent by user to database on the cluster listening on port,
+/// in a session with the session parameters set by settings (see SessionSettings::replay_sql), which can change the result.
+/// The key is made of the normalized query and its parameter values, each prefixed by its length,
+/// so different parts can't run together to form the same key.
+pub fn cache_key(port: u16, database: &str, user: &str, settings: &str, query: &Query) -> String {
+    let mut key = String::with_capacity(query.normalized().len() + query.params_buf.len() + settings.len() + 64);
+    let _ = write!(key, "{}", port);
+    for part in [database, user, settings, query.normalized()] {
+        let _ = write!(key, "/{}:{}", part.len(), part);
+    }
+    for param in query.params() {
+        let value = query.param(param);
+        let _ = write!(key, "/{:?}{}:{}", param.ty, value.len(), value);
+    }
+    key
+}
+
+/// Returns the tables read or written by statements, identified as they are in the result cache's index
+/// of the tables read by each cached result (see table_identity.) Sorted and deduplicated.
+pub fn cache_tables<'a, I: Iterator<Item=&'a Query>>(statements: I) -> Vec<String> {
+    let mut tables: Vec<String> = statements.flat_map(|query| query.tables()).map(table_identity).collect();
+    tables.sort_unstable();
+    tables.dedup();
+    tables
+}
+
+/// Returns the name of a table as it appears in a normalized query, the way Postgres identifies it:
+/// unquoted identifiers are folded to lowercase, and quoted identifiers are unquoted. The schema is dropped,
+/// it depends on the search_path, so a write to public.users invalidates the results of queries reading users.
+pub fn table_identity(table: &str) -> String {
+    let mut quoted = false;
+    let mut name_start = 0;
+    for (i, c) in table.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '.' if !quoted => name_start = i + 1,
+            _ => (),
+        }
+    }
+    let name = &table[name_start..];
+    match name.strip_prefix('"').and_then(|name| name.strip_suffix('"')) {
+        Some(name) => name.replace("\"\"", "\""),
+        None => name.to_ascii_lowercase(),
+    }
+}
+
+/// ResultCapture collects the messages returned by the database for a query matching a CachePolicy,
+/// up to and including the ReadyForQuery, to store them in the ResultCache.
+/// Only complete, successful results outside of a transaction are cached.
+pub struct ResultCapture {
+    key: String,
+    tables: Vec<String>,
+    ttl_seconds: u32,
+    stale_seconds: u32,
+    max_entry_bytes: u32,
+    invalidations: u64,
+    msgs: Option<Messages>, // None if the result can't be cached
+}
+
+impl ResultCapture {
+    /// Create a ResultCapture for the result of the query with the given key, which reads tables.
+    /// invalidations is ResultCache::invalidations() from before the query was sent.
+    pub fn new(key: String, tables: Vec<String>, policy: &CachePolicy, invalidations: u64) -> Self {
+        Self {
+            key,
+            tables,
+            ttl_seconds: policy.ttl_seconds,
+            stale_seconds: policy.stale_while_revalidate_seconds,
+            max_entry_bytes: policy.max_entry_bytes,
+            invalidations,
+            msgs: Some(Messages::default()),
+        }
+    }
+
+    /// Returns the cache key for the result
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Add msgs received from the database to the result. msgs must not contain anything after
+    /// the ReadyForQuery ending the result. Returns true if msgs contained the ReadyForQuery.
+    pub fn add(&mut self, msgs: &Messages) -> bool {
+        let mut complete = false;
+        let mut cacheable = true;
+        for msg in msgs.iter(0) {
+            match msg.tag() {
+                Tag::ROW_DESCRIPTION | Tag::DATA_ROW | Tag::COMMAND_COMPLETE | Tag::EMPTY_QUERY => (),
+                Tag::READY_FOR_QUERY => {
+                    complete = true;
+                    // Only cache results outside of a transaction (status I for idle)
+                    cacheable &= msg.body().first() == Some(&b'I');
+                },
+                // Errors, notices, notifications, and COPY results are never cached
+                _ => cacheable = false,
+            }
+        }
+
+        if cacheable {
+            if let Some(captured) = self.msgs.take() {
+                let captured = captured.append(msgs.clone());
+                if captured.len() <= self.max_entry_bytes {
+                    self.msgs = Some(captured);
+                }
+            }
+        } else {
+            self.msgs = None;
+        }
+        complete
+    }
+
+    /// Store the captured result in cache, if it can be cached. Returns true if it was stored.
+    pub async fn finish(self, cache: &ResultCache) -> bool {
+        match self.msgs {
+            Some(msgs) if !msgs.is_empty() => {
+                let entry = CacheEntry::new(msgs, self.ttl_seconds, self.stale_seconds);
+                cache.put_for_tables(&self.key, entry, &self.tables, self.invalidations).await
+            },
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut, Buf, BufMut};
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::pg::protocol::{Messages, Header};
+
+
+/// Version byte of the serialized CacheEntry format, incremented on incompatible changes.
+const ENTRY_FORMAT_VERSION: u8 = 1;
+/// Size of the serialized CacheEntry header: version + fresh_until + stale_until
+const ENTRY_HEADER_SIZE: usize = 1 + 8 + 8;
+
+/// Returns the current wall clock time in seconds since the unix epoch.
+/// We use wall clock time rather than the coarse monotonic clock because
+/// entries may be shared between riverdb instances via a remote cache backend.
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// A cached query result, the Messages returned by the backend for the query
+/// along with the time until which they're fresh and until which they may be served stale.
+#[derive(Clone)]
+pub struct CacheEntry {
+    pub msgs: Messages,
+    /// fresh_until is the unix time in seconds after which the entry is expired
+    pub fresh_until: u64,
+    /// stale_until is the unix time in seconds until which the expired entry may be served while it's refreshed
+    pub stale_until: u64,
+}
+
+impl CacheEntry {
+    /// Create a new CacheEntry that's fresh for ttl_seconds and then may be served stale for stale_seconds.
+    pub fn new(msgs: Messages, ttl_seconds: u32, stale_seconds: u32) -> Self {
+        let fresh_until = unix_now() + ttl_seconds as u64;
+        Self {
+            msgs,
+            fresh_until,
+            stale_until: fresh_until + stale_seconds as u64,
+        }
+    }
+
+    /// Returns true if the entry has not yet expired at time now
+    pub fn is_fresh(&self, now: u64) -> bool {
+        now < self.fresh_until
+    }
+
+    /// Returns true if the entry can still be returned at time now (it's fresh or may be served stale)
+    pub fn is_usable(&self, now: u64) -> bool {
+        now < self.stale_until
+    }
+
+    /// Returns the number of seconds from now until the entry can no longer be used, or 0.
+    pub fn remaining_seconds(&self, now: u64) -> u64 {
+        self.stale_until.saturating_sub(now)
+    }
+
+    /// Returns the approximate size in bytes of the entry in memory
+    pub fn size(&self) -> usize {
+        self.msgs.len() as usize + std::mem::size_of::<Self>()
+    }
+
+    /// Serialize the entry to bytes, for storing in a remote cache backend
+    pub fn serialize(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(ENTRY_HEADER_SIZE + self.msgs.len() as usize);
+        buf.put_u8(ENTRY_FORMAT_VERSION);
+        buf.put_u64(self.fresh_until);
+        buf.put_u64(self.stale_until);
+        buf.put_slice(self.msgs.as_slice());
+        buf.freeze()
+    }
+
+    /// Deserialize an entry created with serialize.
+    pub fn deserialize(mut buf: Bytes) -> Result<Self> {
+        if buf.len() < ENTRY_HEADER_SIZE {
+            return Err(Error::new("serialized cache entry is too short"));
+        }
+        let version = buf.get_u8();
+        if version != ENTRY_FORMAT_VERSION {
+            return Err(Error::new(format!("unsupported cache entry version {}", version)));
+        }
+        let fresh_until = buf.get_u64();
+        let stale_until = buf.get_u64();
+        if !buf.is_empty() && !matches!(Header::parse(buf.chunk()), Ok(Some(_))) {
+            return Err(Error::new("serialized cache entry does not contain valid messages"));
+        }
+        Ok(Self {
+            msgs: Messages::new(buf),
+            fresh_until,
+            stale_until,
+        })
+    }
+}
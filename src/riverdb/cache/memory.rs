@@ -0,0 +1,322 @@
+use std::sync::{Mutex, PoisonError};
+use std::hash::{Hash, Hasher};
+
+use fnv::{FnvHashMap, FnvHasher};
+
+use crate::riverdb::cache::CacheEntry;
+
+
+const NIL: usize = usize::MAX;
+
+struct Node {
+    key: String,
+    entry: Option<CacheEntry>,
+    size: usize,
+    prev: usize,
+    next: usize,
+}
+
+/// A single shard of the LRU cache, a doubly-linked list threaded through a Vec of nodes,
+/// with a hash map from key to node index. Most recently used entries are at the head.
+struct LruShard {
+    map: FnvHashMap<String, usize>,
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    head: usize,
+    tail: usize,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl LruShard {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            map: FnvHashMap::default(),
+            nodes: Vec::new(),
+            free: Vec::new(),
+            head: NIL,
+            tail: NIL,
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    fn unlink(&mut self, i: usize) {
+        let (prev, next) = {
+            let node = &self.nodes[i];
+            (node.prev, node.next)
+        };
+        if prev != NIL {
+            self.nodes[prev].next = next;
+        } else {
+            self.head = next;
+        }
+        if next != NIL {
+            self.nodes[next].prev = prev;
+        } else {
+            self.tail = prev;
+        }
+    }
+
+    fn push_front(&mut self, i: usize) {
+        self.nodes[i].prev = NIL;
+        self.nodes[i].next = self.head;
+        if self.head != NIL {
+            self.nodes[self.head].prev = i;
+        }
+        self.head = i;
+        if self.tail == NIL {
+            self.tail = i;
+        }
+    }
+
+    fn remove_index(&mut self, i: usize) {
+        self.unlink(i);
+        let node = &mut self.nodes[i];
+        node.entry = None;
+        self.bytes -= node.size;
+        let key = std::mem::take(&mut node.key);
+        self.map.remove(&key);
+        self.free.push(i);
+    }
+
+    fn get(&mut self, key: &str, now: u64) -> Option<CacheEntry> {
+        let i = *self.map.get(key)?;
+        let usable = self.nodes[i].entry.as_ref().map(|e| e.is_usable(now)).unwrap_or(false);
+        if !usable {
+            self.remove_index(i);
+            return None;
+        }
+        self.unlink(i);
+        self.push_front(i);
+        self.nodes[i].entry.clone()
+    }
+
+    /// Insert or replace the entry for key, returns the number of entries evicted to make room.
+    fn put(&mut self, key: &str, entry: CacheEntry) -> u64 {
+        let size = key.len() + entry.size();
+        if size > self.max_bytes {
+            // Don't keep serving the entry it would have replaced
+            self.remove(key);
+            return 0;
+        }
+
+        if let Some(&i) = self.map.get(key) {
+            self.unlink(i);
+            let node = &mut self.nodes[i];
+            self.bytes = self.bytes - node.size + size;
+            node.size = size;
+            node.entry = Some(entry);
+            self.push_front(i);
+        } else {
+            let node = Node {
+                key: key.to_string(),
+                entry: Some(entry),
+                size,
+                prev: NIL,
+                next: NIL,
+            };
+            let i = if let Some(i) = self.free.pop() {
+                self.nodes[i] = node;
+                i
+            } else {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            };
+            self.map.insert(key.to_string(), i);
+            self.bytes += size;
+            self.push_front(i);
+        }
+
+        let mut evicted = 0;
+        while self.bytes > self.max_bytes && self.tail != NIL {
+            self.remove_index(self.tail);
+            evicted += 1;
+        }
+        evicted
+    }
+
+    /// Evict the least recently used entries until the shard is at most max_bytes, returns the number evicted.
+    fn shrink(&mut self, max_bytes: usize) -> u64 {
+        let mut evicted = 0;
+        while self.bytes > max_bytes && self.tail != NIL {
+            self.remove_index(self.tail);
+            evicted += 1;
+        }
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        if let Some(&i) = self.map.get(key) {
+            self.remove_index(i);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn clear(&mut self) -> u64 {
+        let count = self.map.len() as u64;
+        self.map.clear();
+        self.nodes.clear();
+        self.free.clear();
+        self.head = NIL;
+        self.tail = NIL;
+        self.bytes = 0;
+        count
+    }
+}
+
+/// An in-memory LRU cache bounded by the total size in bytes of the cached entries.
+/// It's split into independently locked shards by key hash to reduce lock contention.
+pub struct MemoryCache {
+    shards: Box<[Mutex<LruShard>]>,
+    mask: usize,
+}
+
+impl MemoryCache {
+    /// Create a new MemoryCache with a total size of max_bytes, split into shards (a power of two.)
+    pub fn new(max_bytes: u64, shards: u32) -> Self {
+        debug_assert!(shards.is_power_of_two());
+        let shard_bytes = (max_bytes / shards as u64) as usize;
+        let shards: Vec<Mutex<LruShard>> = (0..shards)
+            .map(|_| Mutex::new(LruShard::new(shard_bytes)))
+            .collect();
+        Self {
+            mask: shards.len() - 1,
+            shards: shards.into_boxed_slice(),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<LruShard> {
+        let mut hasher = FnvHasher::default();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize & self.mask]
+    }
+
+    /// Get the entry for key, if it exists and is usable at time now.
+    pub fn get(&self, key: &str, now: u64) -> Option<CacheEntry> {
+        self.shard(key).lock().unwrap_or_else(PoisonError::into_inner).get(key, now)
+    }
+
+    /// Insert or replace the entry for key. Returns the number of entries evicted to make room.
+    /// Entries larger than the shard size are silently not cached.
+    pub fn put(&self, key: &str, entry: CacheEntry) -> u64 {
+        self.shard(key).lock().unwrap_or_else(PoisonError::into_inner).put(key, entry)
+    }
+
+    /// Remove the entry for key, returning true if it existed.
+    pub fn remove(&self, key: &str) -> bool {
+        self.shard(key).lock().unwrap_or_else(PoisonError::into_inner).remove(key)
+    }
+
+    /// Evict the least recently used entries until the cache is at most max_bytes in size.
+    /// Returns the number of entries evicted. This doesn't change the maximum size of the cache.
+    pub fn shrink(&self, max_bytes: u64) -> u64 {
+        let shard_bytes = (max_bytes / self.shards.len() as u64) as usize;
+        self.shards.iter().map(|shard| shard.lock().unwrap_or_else(PoisonError::into_inner).shrink(shard_bytes)).sum()
+    }
+
+    /// Remove all entries, returning the number of entries removed.
+    pub fn clear(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.lock().unwrap_or_else(PoisonError::into_inner).clear()).sum()
+    }
+
+    /// Returns the total size in bytes of all entries in the cache.
+    pub fn bytes(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.lock().unwrap_or_else(PoisonError::into_inner).bytes as u64).sum()
+    }
+
+    /// Returns the number of entries in the cache.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap_or_else(PoisonError::into_inner).map.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riverdb::cache::unix_now;
+    use crate::riverdb::pg::protocol::{Messages, MessageBuilder, Tag};
+
+    fn make_entry(s: &str, ttl: u32) -> CacheEntry {
+        let mut mb = MessageBuilder::new(Tag::DATA_ROW);
+        mb.write_str(s);
+        CacheEntry::new(mb.finish(), ttl, 0)
+    }
+
+    #[test]
+    fn test_lru_evicts_least_recently_used() {
+        let entry_size = "k1".len() + make_entry("value", 10).size();
+        let cache = MemoryCache::new((entry_size * 2) as u64, 1);
+        let now = unix_now();
+
+        assert_eq!(cache.put("k1", make_entry("value", 10)), 0);
+        assert_eq!(cache.put("k2", make_entry("value", 10)), 0);
+        assert!(cache.get("k1", now).is_some());
+        // k2 is now the least recently used
+        assert_eq!(cache.put("k3", make_entry("value", 10)), 1);
+        assert!(cache.get("k2", now).is_none());
+        assert!(cache.get("k1", now).is_some());
+        assert!(cache.get("k3", now).is_some());
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.bytes(), (entry_size * 2) as u64);
+
+        assert!(cache.remove("k1"));
+        assert!(!cache.remove("k1"));
+        assert_eq!(cache.clear(), 1);
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[test]
+    fn test_lru_shrink() {
+        let entry_size = "k1".len() + make_entry("value", 10).size();
+        let cache = MemoryCache::new((entry_size * 4) as u64, 1);
+        let now = unix_now();
+        for key in ["k1", "k2", "k3", "k4"] {
+            cache.put(key, make_entry("value", 10));
+        }
+        assert!(cache.get("k1", now).is_some());
+
+        assert_eq!(cache.shrink((entry_size * 2) as u64), 2);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("k1", now).is_some());
+        assert!(cache.get("k4", now).is_some());
+        assert_eq!(cache.shrink((entry_size * 2) as u64), 0);
+        // The maximum size is unchanged
+        cache.put("k5", make_entry("value", 10));
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn test_lru_oversized_replacement() {
+        let entry_size = "k1".len() + make_entry("value", 10).size();
+        let cache = MemoryCache::new((entry_size * 2) as u64, 1);
+        let now = unix_now();
+        cache.put("k1", make_entry("value", 10));
+        // The new result is too big to cache, the old one is stale
+        assert_eq!(cache.put("k1", make_entry(&"x".repeat(entry_size * 2), 10)), 0);
+        assert!(cache.get("k1", now).is_none());
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[test]
+    fn test_lru_expired_entries() {
+        let cache = MemoryCache::new(1024 * 1024, 4);
+        let now = unix_now();
+        cache.put("k1", make_entry("value", 10));
+        assert!(cache.get("k1", now + 10).is_none());
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn test_entry_serialization() {
+        let entry = make_entry("value", 10);
+        let copy = CacheEntry::deserialize(entry.serialize()).unwrap();
+        assert_eq!(copy.msgs.as_slice(), entry.msgs.as_slice());
+        assert_eq!(copy.fresh_until, entry.fresh_until);
+        assert_eq!(copy.stale_until, entry.stale_until);
+        assert!(CacheEntry::deserialize(Messages::default().into_bytes()).is_err());
+    }
+}
@@ -0,0 +1,13 @@
+mod entry;
+mod stats;
+mod memory;
+mod redis;
+mod result_cache;
+mod capture;
+
+pub use self::entry::{CacheEntry, unix_now};
+pub use self::stats::{CacheStats, CacheStatsSnapshot};
+pub use self::memory::MemoryCache;
+pub use self::redis::{RedisCache, RedisClient, RedisConnection, Reply, write_command, read_reply};
+pub use self::result_cache::{ResultCache, CacheStorage};
+pub use self::capture::{ResultCapture, cache_key, cache_tables, table_identity};
@@ -0,0 +1,217 @@
+use std::sync::{Mutex, PoisonError};
+
+use bytes::Bytes;
+use tokio::net::TcpStream;
+use tokio::io::{BufStream, AsyncWriteExt, AsyncBufReadExt, AsyncReadExt};
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::cache::{CacheEntry, unix_now};
+
+
+/// Maximum number of idle connections to the redis server that we keep open.
+const MAX_IDLE_CONNECTIONS: usize = 32;
+/// Maximum length of a bulk string reply, the default proto-max-bulk-len of the redis server.
+const MAX_BULK_BYTES: i64 = 512 * 1024 * 1024;
+/// Maximum number of items to allocate room for up front when reading an array reply.
+const MAX_ARRAY_PREALLOCATE: usize = 16;
+
+/// A reply from the redis server. We only implement what's needed for GET, SET, DEL, PUBLISH and SUBSCRIBE.
+/// Arrays may not contain nested arrays.
+pub enum Reply {
+    Nil,
+    Status,
+    Integer(i64),
+    Bulk(Bytes),
+    Array(Vec<Reply>),
+}
+
+/// A connection to a redis server
+pub type RedisConnection = BufStream<TcpStream>;
+
+/// A minimal redis client that speaks just enough of the RESP protocol for our needs.
+/// It keeps a list of idle connections which are reused for subsequent commands.
+pub struct RedisClient {
+    address: String,
+    idle: Mutex<Vec<RedisConnection>>,
+}
+
+impl RedisClient {
+    /// Create a new RedisClient that connects to the redis server at address (host:port).
+    /// Connections are opened lazily.
+    pub fn new(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Open a new connection to the redis server, not shared with the idle list.
+    pub async fn connect(&self) -> Result<RedisConnection> {
+        Ok(BufStream::new(TcpStream::connect(&self.address).await?))
+    }
+
+    /// Send the command (a list of arguments) to the redis server and return the reply.
+    /// The connection is returned to the idle list unless there was an error.
+    pub async fn command(&self, args: &[&[u8]]) -> Result<Reply> {
+        let idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner).pop();
+        let mut conn = match idle {
+            Some(conn) => conn,
+            None => self.connect().await?,
+        };
+
+        write_command(&mut conn, args).await?;
+        let reply = read_reply(&mut conn).await?;
+
+        let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(conn);
+        }
+        Ok(reply)
+    }
+
+    /// Publish payload to channel, returns the number of subscribers that received it.
+    pub async fn publish(&self, channel: &str, payload: &[u8]) -> Result<i64> {
+        match self.command(&[b"PUBLISH", channel.as_bytes(), payload]).await? {
+            Reply::Integer(n) => Ok(n),
+            _ => Err(Error::new("unexpected reply from redis for PUBLISH")),
+        }
+    }
+}
+
+/// Write the command (a list of arguments) to conn and flush it.
+pub async fn write_command(conn: &mut RedisConnection, args: &[&[u8]]) -> Result<()> {
+    let mut header = format!("*{}\r\n", args.len());
+    for arg in args {
+        header.push_str(&format!("${}\r\n", arg.len()));
+        conn.write_all(header.as_bytes()).await?;
+        conn.write_all(arg).await?;
+        header.clear();
+        header.push_str("\r\n");
+    }
+    conn.write_all(header.as_bytes()).await?;
+    conn.flush().await?;
+    Ok(())
+}
+
+/// Read a single reply from conn.
+pub async fn read_reply(conn: &mut RedisConnection) -> Result<Reply> {
+    let mut line = Vec::new();
+    read_line(conn, &mut line).await?;
+    if line[0] == b'*' {
+        let len: i64 = std::str::from_utf8(&line[1..line.len()-2])?.parse()?;
+        if len < 0 {
+            return Ok(Reply::Nil);
+        }
+        // The length comes from the server, don't trust it for the allocation
+        let mut items = Vec::with_capacity((len as usize).min(MAX_ARRAY_PREALLOCATE));
+        for _ in 0..len {
+            read_line(conn, &mut line).await?;
+            items.push(read_scalar(conn, &line).await?);
+        }
+        Ok(Reply::Array(items))
+    } else {
+        read_scalar(conn, &line).await
+    }
+}
+
+async fn read_line(conn: &mut RedisConnection, line: &mut Vec<u8>) -> Result<()> {
+    line.clear();
+    conn.read_until(b'\n', line).await?;
+    if line.len() < 3 || !line.ends_with(b"\r\n") {
+        return Err(Error::new("unexpected eof reading reply from redis"));
+    }
+    Ok(())
+}
+
+async fn read_scalar(conn: &mut RedisConnection, line: &[u8]) -> Result<Reply> {
+    let body = std::str::from_utf8(&line[1..line.len()-2])?;
+    match line[0] {
+        b'+' => Ok(Reply::Status),
+        b'-' => Err(Error::new(format!("redis error: {}", body))),
+        b':' => Ok(Reply::Integer(body.parse()?)),
+        b'$' => {
+            let len: i64 = body.parse()?;
+            if len < 0 {
+                return Ok(Reply::Nil);
+            }
+            if len > MAX_BULK_BYTES {
+                return Err(Error::new(format!("bulk reply of {} bytes from redis exceeds the maximum of {}", len, MAX_BULK_BYTES)));
+            }
+            // Grow the buffer as the reply arrives, rather than allocating the length up front
+            let mut buf = Vec::new();
+            (&mut *conn).take(len as u64 + 2).read_to_end(&mut buf).await?;
+            if buf.len() != len as usize + 2 {
+                return Err(Error::new("unexpected eof reading reply from redis"));
+            }
+            buf.truncate(len as usize);
+            Ok(Reply::Bulk(Bytes::from(buf)))
+        },
+        _ => Err(Error::new(format!("unsupported reply type from redis: {}", line[0] as char))),
+    }
+}
+
+/// A cache storage backend that stores entries in a redis server so they can be shared
+/// by multiple riverdb instances.
+pub struct RedisCache {
+    client: RedisClient,
+    key_prefix: String,
+}
+
+impl RedisCache {
+    /// Create a new RedisCache that connects to the redis server at address (host:port)
+    /// and stores all keys prefixed with key_prefix. Connections are opened lazily.
+    pub fn new(address: &str, key_prefix: &str) -> Self {
+        Self {
+            client: RedisClient::new(address),
+            key_prefix: key_prefix.to_string(),
+        }
+    }
+
+    /// Get the entry for key, if it exists and is usable at time now.
+    pub async fn get(&self, key: &str, now: u64) -> Result<Option<CacheEntry>> {
+        let full_key = self.full_key(key);
+        match self.client.command(&[b"GET", full_key.as_bytes()]).await? {
+            Reply::Bulk(buf) => {
+                let entry = CacheEntry::deserialize(buf)?;
+                if entry.is_usable(now) {
+                    Ok(Some(entry))
+                } else {
+                    Ok(None)
+                }
+            },
+            Reply::Nil => Ok(None),
+            _ => Err(Error::new("unexpected reply from redis for GET")),
+        }
+    }
+
+    /// Insert or replace the entry for key. The key expires in redis when the entry is no longer usable.
+    pub async fn put(&self, key: &str, entry: &CacheEntry) -> Result<()> {
+        let ttl = entry.remaining_seconds(unix_now());
+        if ttl == 0 {
+            return Ok(());
+        }
+        let full_key = self.full_key(key);
+        let value = entry.serialize();
+        let ttl = ttl.to_string();
+        match self.client.command(&[b"SET", full_key.as_bytes(), &value, b"EX", ttl.as_bytes()]).await? {
+            Reply::Status => Ok(()),
+            _ => Err(Error::new("unexpected reply from redis for SET")),
+        }
+    }
+
+    /// Remove the entry for key, returning true if it existed.
+    pub async fn remove(&self, key: &str) -> Result<bool> {
+        let full_key = self.full_key(key);
+        match self.client.command(&[b"DEL", full_key.as_bytes()]).await? {
+            Reply::Integer(n) => Ok(n > 0),
+            _ => Err(Error::new("unexpected reply from redis for DEL")),
+        }
+    }
+
+    fn full_key(&self, key: &str) -> String {
+        let mut full_key = String::with_capacity(self.key_prefix.len() + key.len());
+        full_key.push_str(&self.key_prefix);
+        full_key.push_str(key);
+        full_key
+    }
+}
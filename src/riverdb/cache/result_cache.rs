@@ -0,0 +1,261 @@
+use std::sync::{Mutex, PoisonError};
+use std::sync::atomic::{AtomicPtr, AtomicU64};
+use std::sync::atomic::Ordering::{AcqRel, Acquire};
+
+use fnv::{FnvHashMap, FnvHashSet};
+use tracing::{warn};
+
+use crate::riverdb::config::{self, CacheSettings, CacheBackend};
+use crate::riverdb::memory_governor::under_memory_pressure;
+use crate::riverdb::cache::{CacheEntry, CacheStats, CacheStatsSnapshot, MemoryCache, RedisCache, unix_now};
+
+
+/// Prune expired keys from a table's index when it reaches this many keys (and each doubling after.)
+const PRUNE_TABLE_KEYS: usize = 1024;
+
+/// The storage backend for the result cache, selected by the cache backend setting.
+pub enum CacheStorage {
+    Memory(MemoryCache),
+    Redis(RedisCache),
+}
+
+/// ResultCache stores query results (as Messages) by key in the configured storage backend,
+/// and keeps hit/miss/eviction statistics. Errors from remote backends are logged and
+/// counted, and treated as cache misses. The cache is an optimization, it should never fail a query.
+pub struct ResultCache {
+    storage: CacheStorage,
+    stats: CacheStats,
+    /// table name => keys of the cached results that read it, and when they expire (stale_until)
+    tables: Mutex<FnvHashMap<String, FnvHashMap<String, u64>>>,
+    invalidations: AtomicU64,
+}
+
+impl ResultCache {
+    /// Create a new ResultCache with the storage backend described by settings.
+    pub fn new(settings: &CacheSettings) -> Self {
+        let storage = match settings.backend {
+            CacheBackend::Memory => CacheStorage::Memory(MemoryCache::new(settings.max_bytes, settings.shards)),
+            CacheBackend::Redis => CacheStorage::Redis(RedisCache::new(&settings.redis_address, &settings.redis_key_prefix)),
+        };
+        Self {
+            storage,
+            stats: CacheStats::new(),
+            tables: Mutex::new(FnvHashMap::default()),
+            invalidations: AtomicU64::new(0),
+        }
+    }
+
+    /// Return the global ResultCache instance, created from the cache settings on first use.
+    /// Check conf().cache.enabled before using it.
+    pub fn singleton() -> &'static Self {
+        static SINGLETON_CACHE: AtomicPtr<ResultCache> = AtomicPtr::new(std::ptr::null_mut());
+        unsafe {
+            let mut p = SINGLETON_CACHE.load(Acquire);
+            if p.is_null() {
+                let mut cache = Box::new(ResultCache::new(&config::conf().cache));
+                p = cache.as_mut() as _;
+                match SINGLETON_CACHE.compare_exchange(std::ptr::null_mut(), p, AcqRel, Acquire) {
+                    Ok(_) => {
+                        Box::leak(cache);
+                    },
+                    Err(current) => {
+                        p = current;
+                    },
+                }
+            }
+            &*p
+        }
+    }
+
+    /// Return the storage backend
+    pub fn storage(&self) -> &CacheStorage {
+        &self.storage
+    }
+
+    /// Get the entry for key, if it exists and is fresh or can be served stale.
+    pub async fn get(&self, key: &str) -> Option<CacheEntry> {
+        let now = unix_now();
+        let result = match &self.storage {
+            CacheStorage::Memory(cache) => cache.get(key, now),
+            CacheStorage::Redis(cache) => {
+                cache.get(key, now).await.unwrap_or_else(|e| {
+                    warn!(%e, "error getting entry from redis cache");
+                    self.stats.error();
+                    None
+                })
+            },
+        };
+        if result.is_some() {
+            self.stats.hit();
+        } else {
+            self.stats.miss();
+        }
+        result
+    }
+
+    /// Insert or replace the entry for key. Nothing is cached while over the soft memory limit.
+    pub async fn put(&self, key: &str, entry: CacheEntry) {
+        if under_memory_pressure() {
+            return;
+        }
+        match &self.storage {
+            CacheStorage::Memory(cache) => {
+                let evicted = cache.put(key, entry);
+                if evicted != 0 {
+                    self.stats.evict(evicted);
+                }
+            },
+            CacheStorage::Redis(cache) => {
+                if let Err(e) = cache.put(key, &entry).await {
+                    warn!(%e, "error storing entry in redis cache");
+                    self.stats.error();
+                    return;
+                }
+            },
+        }
+        self.stats.insert();
+    }
+
+    /// Insert or replace the entry for key like put, and index it by the tables the query read,
+    /// so a write to any of them removes it (see invalidate_tables.) invalidations is the value of
+    /// self.invalidations() from before the query was sent, if tables were invalidated since then
+    /// the result may already be stale, and it's removed again. Returns true if the entry was kept.
+    /// Tables are matched by name regardless of schema.
+    pub async fn put_for_tables(&self, key: &str, entry: CacheEntry, tables: &[String], invalidations: u64) -> bool {
+        let stale_until = entry.stale_until;
+        self.put(key, entry).await;
+        {
+            let now = unix_now();
+            let mut index = self.tables.lock().unwrap_or_else(PoisonError::into_inner);
+            for table in tables {
+                let keys = index.entry(table.clone()).or_default();
+                keys.insert(key.to_string(), stale_until);
+                if keys.len() >= PRUNE_TABLE_KEYS && keys.len().is_power_of_two() {
+                    keys.retain(|_, until| *until > now);
+                }
+            }
+        }
+        if self.invalidations() != invalidations {
+            self.remove(key).await;
+            return false;
+        }
+        true
+    }
+
+    /// Remove the cached results of the queries that read any of tables (see put_for_tables.)
+    /// Clients call this when a query that writes to any of tables is sent, and again when it completes.
+    /// Executing a prepared statement that wasn't prepared on the same client connection removes
+    /// every cached result that read a table instead (see invalidate_all_tables), since what it writes is unknown.
+    /// Returns the number of entries removed.
+    pub async fn invalidate_tables(&self, tables: &[String]) -> usize {
+        self.invalidations.fetch_add(1, AcqRel);
+        let keys: FnvHashSet<String> = {
+            let mut index = self.tables.lock().unwrap_or_else(PoisonError::into_inner);
+            tables.iter()
+                .filter_map(|table| index.remove(table))
+                .flat_map(|keys| keys.into_keys())
+                .collect()
+        };
+        self.remove_keys(keys).await
+    }
+
+    /// Remove the cached results of all queries that read any table, for a write to tables that aren't known
+    /// (see put_for_tables.) Returns the number of entries removed.
+    pub async fn invalidate_all_tables(&self) -> usize {
+        self.invalidations.fetch_add(1, AcqRel);
+        let keys: FnvHashSet<String> = {
+            let mut index = self.tables.lock().unwrap_or_else(PoisonError::into_inner);
+            index.drain().flat_map(|(_, keys)| keys.into_keys()).collect()
+        };
+        self.remove_keys(keys).await
+    }
+
+    /// Remove the entries for keys, returning the number that existed.
+    async fn remove_keys(&self, keys: FnvHashSet<String>) -> usize {
+        let mut removed = 0;
+        for key in keys {
+            if self.remove(&key).await {
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Returns the number of times invalidate_tables or invalidate_all_tables was called. A result captured while
+    /// this changes may have been read before a write committed, and shouldn't be cached.
+    pub fn invalidations(&self) -> u64 {
+        self.invalidations.load(Acquire)
+    }
+
+    /// Remove the entry for key, returning true if it existed.
+    pub async fn remove(&self, key: &str) -> bool {
+        match &self.storage {
+            CacheStorage::Memory(cache) => cache.remove(key),
+            CacheStorage::Redis(cache) => {
+                cache.remove(key).await.unwrap_or_else(|e| {
+                    warn!(%e, "error removing entry from redis cache");
+                    self.stats.error();
+                    false
+                })
+            },
+        }
+    }
+
+    /// Evict the least recently used entries of the in-memory cache until it's at most max_bytes in size.
+    /// Returns the number of entries evicted. Does nothing for the redis backend, which doesn't use our memory.
+    pub fn shrink(&self, max_bytes: u64) -> u64 {
+        match &self.storage {
+            CacheStorage::Memory(cache) => {
+                let evicted = cache.shrink(max_bytes);
+                if evicted != 0 {
+                    self.stats.evict(evicted);
+                }
+                evicted
+            },
+            CacheStorage::Redis(_) => 0,
+        }
+    }
+
+    /// Return a snapshot of the cache statistics
+    pub fn stats(&self) -> CacheStatsSnapshot {
+        self.stats.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riverdb::pg::protocol::Messages;
+
+    fn entry() -> CacheEntry {
+        CacheEntry::new(Messages::new_warning("01000", "cached"), 60, 0)
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_tables() {
+        let cache = ResultCache::new(&CacheSettings{max_bytes: 1 << 20, shards: 1, ..Default::default()});
+        let tables = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let invalidations = cache.invalidations();
+        assert!(cache.put_for_tables("users", entry(), &tables(&["USERS"]), invalidations).await);
+        assert!(cache.put_for_tables("orders", entry(), &tables(&["ORDERS", "USERS"]), invalidations).await);
+        assert!(cache.put_for_tables("items", entry(), &tables(&["ITEMS"]), invalidations).await);
+
+        assert_eq!(cache.invalidate_tables(&tables(&["USERS"])).await, 2);
+        assert!(cache.get("users").await.is_none());
+        assert!(cache.get("orders").await.is_none());
+        assert!(cache.get("items").await.is_some());
+
+        // A result captured across an invalidation isn't kept
+        assert!(!cache.put_for_tables("users", entry(), &tables(&["USERS"]), invalidations).await);
+        assert!(cache.get("users").await.is_none());
+
+        let invalidations = cache.invalidations();
+        assert!(cache.put_for_tables("users", entry(), &tables(&["USERS"]), invalidations).await);
+        assert!(cache.put_for_tables("constant", entry(), &[], invalidations).await);
+        assert_eq!(cache.invalidate_all_tables().await, 2);
+        assert!(cache.get("users").await.is_none());
+        assert!(cache.get("items").await.is_none());
+        assert!(cache.get("constant").await.is_some());
+    }
+}
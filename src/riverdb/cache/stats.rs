@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicU64};
+use std::sync::atomic::Ordering::{Relaxed};
+
+
+/// Counters for the result cache, shared by all storage backends.
+#[derive(Default)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    inserts: AtomicU64,
+    evictions: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// A point-in-time copy of CacheStats
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct CacheStatsSnapshot {
+    pub hits: u64,
+    pub misses: u64,
+    pub inserts: u64,
+    pub evictions: u64,
+    pub errors: u64,
+}
+
+impl CacheStats {
+    pub const fn new() -> Self {
+        Self {
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            inserts: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Relaxed);
+    }
+
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Relaxed);
+    }
+
+    pub fn insert(&self) {
+        self.inserts.fetch_add(1, Relaxed);
+    }
+
+    pub fn evict(&self, count: u64) {
+        self.evictions.fetch_add(count, Relaxed);
+    }
+
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Relaxed);
+    }
+
+    /// Return a copy of the current counter values
+    pub fn snapshot(&self) -> CacheStatsSnapshot {
+        CacheStatsSnapshot {
+            hits: self.hits.load(Relaxed),
+            misses: self.misses.load(Relaxed),
+            inserts: self.inserts.load(Relaxed),
+            evictions: self.evictions.load(Relaxed),
+            errors: self.errors.load(Relaxed),
+        }
+    }
+}
@@ -14,6 +14,7 @@ use crate::riverdb::pg::protocol::PostgresError;
 custom_error!{pub ErrorKind
     ClosedError = "resource is closed",
     ProtocolError{msg: String} = "{msg}",
+    MessageTooLarge{len: u32, max: u32} = "message length {len} exceeds the maximum of {max}",
     StringError{msg: String} = "{msg}",
     StrError{msg: &'static str} = "{msg}",
     StrumParseError = "matching variant not found",
@@ -50,6 +51,10 @@ impl Error {
         Error(Box::new(ErrorKind::ProtocolError{msg: s.to_string()}))
     }
 
+    pub fn message_too_large(len: u32, max: u32) -> Self {
+        Error(Box::new(ErrorKind::MessageTooLarge{len, max}))
+    }
+
     pub fn closed() -> Self {
         Error(Box::new(ErrorKind::ClosedError))
     }
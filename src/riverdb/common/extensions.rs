@@ -0,0 +1,101 @@
+use std::any::{Any, TypeId};
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Mutex};
+
+use fnv::FnvHashMap;
+
+
+/// A map of values keyed by their type, for plugins to attach their own state to a connection.
+/// Each plugin should use a type it owns (e.g. a struct named after the plugin) so it can't
+/// collide with other plugins. Values are shared as Arc<T>, use interior mutability (atomics
+/// or a Mutex) for state that changes. Values live as long as the connection.
+#[derive(Default)]
+pub struct Extensions {
+    map: Mutex<FnvHashMap<TypeId, Arc<dyn Any + Send + Sync>>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the value of type T, if any.
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        let value = self.map.lock().unwrap().get(&TypeId::of::<T>())?.clone();
+        Some(downcast(value))
+    }
+
+    /// Sets the value of type T, returning the previous value, if any.
+    pub fn insert<T: Any + Send + Sync>(&self, value: T) -> Option<Arc<T>> {
+        let prev = self.map.lock().unwrap().insert(TypeId::of::<T>(), Arc::new(value))?;
+        Some(downcast(prev))
+    }
+
+    /// Returns the value of type T, setting it to init() first if there isn't one.
+    pub fn get_or_insert_with<T: Any + Send + Sync, F: FnOnce() -> T>(&self, init: F) -> Arc<T> {
+        let value = self.map.lock().unwrap()
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Arc::new(init()))
+            .clone();
+        downcast(value)
+    }
+
+    /// Removes and returns the value of type T, if any.
+    pub fn remove<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        let value = self.map.lock().unwrap().remove(&TypeId::of::<T>())?;
+        Some(downcast(value))
+    }
+
+    /// Returns the number of values.
+    pub fn len(&self) -> usize {
+        self.map.lock().unwrap().len()
+    }
+
+    /// Returns true if there are no values.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Downcast a value from the map to its type, which always matches the TypeId key.
+fn downcast<T: Any + Send + Sync>(value: Arc<dyn Any + Send + Sync>) -> Arc<T> {
+    value.downcast::<T>().expect("extension value has the wrong type for its key")
+}
+
+impl Debug for Extensions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("Extensions(len={})", self.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU32;
+    use std::sync::atomic::Ordering::Relaxed;
+
+    use super::*;
+
+    struct SessionCounter(AtomicU32);
+
+    #[derive(Debug, PartialEq)]
+    struct AuthContext(&'static str);
+
+    #[test]
+    fn test_extensions() {
+        let ext = Extensions::new();
+        assert!(ext.get::<SessionCounter>().is_none());
+
+        ext.get_or_insert_with(|| SessionCounter(AtomicU32::new(0))).0.fetch_add(1, Relaxed);
+        ext.get_or_insert_with(|| SessionCounter(AtomicU32::new(0))).0.fetch_add(1, Relaxed);
+        assert_eq!(ext.get::<SessionCounter>().unwrap().0.load(Relaxed), 2);
+
+        assert!(ext.insert(AuthContext("alice")).is_none());
+        assert_eq!(*ext.insert(AuthContext("bob")).unwrap(), AuthContext("alice"));
+        assert_eq!(*ext.get::<AuthContext>().unwrap(), AuthContext("bob"));
+        assert_eq!(ext.len(), 2);
+
+        assert_eq!(*ext.remove::<AuthContext>().unwrap(), AuthContext("bob"));
+        assert!(ext.get::<AuthContext>().is_none());
+        assert_eq!(ext.len(), 1);
+    }
+}
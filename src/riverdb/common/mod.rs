@@ -10,6 +10,8 @@ mod atomic_ref;
 mod spsc;
 mod ark;
 mod utf8;
+mod extensions;
+mod panics;
 
 pub use self::errors::*;
 pub use self::bytes::*;
@@ -21,4 +23,6 @@ pub use self::atomic_cell::AtomicCell;
 pub use self::atomic_ref::AtomicRef;
 pub use self::spsc::SpscQueue;
 pub use self::ark::{Ark, AtomicRefCounted};
-pub use self::utf8::decode_utf8_char;
\ No newline at end of file
+pub use self::utf8::decode_utf8_char;
+pub use self::extensions::Extensions;
+pub use self::panics::{catch_panic, panic_count};
\ No newline at end of file
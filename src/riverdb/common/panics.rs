@@ -0,0 +1,57 @@
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+
+use futures::FutureExt;
+
+/// the number of panics caught by catch_panic
+static PANICS: AtomicU64 = AtomicU64::new(0);
+
+/// Run the future to completion, catching a panic in it instead of unwinding further.
+/// This is used to isolate connection tasks, so a bug in a plugin or protocol path only
+/// takes down the connection it happened on. Returns the panic message if it panicked.
+///
+/// The future is assumed to be unwind safe: callers must not trust the state it was
+/// working on after it panics, and should close the affected connections.
+pub async fn catch_panic<F: Future>(f: F) -> std::result::Result<F::Output, String> {
+    match AssertUnwindSafe(f).catch_unwind().await {
+        Ok(output) => Ok(output),
+        Err(payload) => {
+            PANICS.fetch_add(1, Relaxed);
+            Err(panic_message(payload.as_ref()))
+        },
+    }
+}
+
+/// Returns the number of panics caught by catch_panic since the process started.
+pub fn panic_count() -> u64 {
+    PANICS.load(Relaxed)
+}
+
+/// Returns the message passed to panic!, if it was a string.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&'static str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_catch_panic() {
+        let before = panic_count();
+        assert_eq!(catch_panic(async { 42 }).await, Ok(42));
+        assert_eq!(catch_panic(async { panic!("static message") }).await, Err::<(), _>("static message".to_string()));
+        let n = 7;
+        assert_eq!(catch_panic(async move { panic!("formatted {}", n) }).await, Err::<(), _>("formatted 7".to_string()));
+        assert!(panic_count() >= before + 2);
+    }
+}
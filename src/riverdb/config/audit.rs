@@ -0,0 +1,50 @@
+use serde::{Deserialize};
+
+use crate::riverdb::{Error, Result};
+
+
+/// Configuration for the audit log of DDL (CREATE, ALTER, DROP, GRANT, REVOKE), SET ROLE, and COPY queries.
+/// Each audited query is recorded before it's sent to the database, and its outcome after it completes.
+#[derive(Deserialize, Default)]
+pub struct AuditSettings {
+    /// file is the path of the append-only audit log, one JSON record per line. Default empty (disabled).
+    #[serde(default)]
+    pub file: String,
+    /// max_file_bytes is the size above which the file is rotated to file.1, file.2, etc. 0 to never rotate.
+    /// Default 100MB.
+    #[serde(default = "default_max_file_bytes")]
+    pub max_file_bytes: u64,
+    /// max_files is the number of rotated files kept, older ones are deleted. Default 10.
+    #[serde(default = "default_max_files")]
+    pub max_files: u32,
+    /// fsync syncs the file to disk after recording each query, before it's sent to the database, so a
+    /// query that succeeded is never missing from the audit log. Default true.
+    #[serde(default = "default_fsync")]
+    pub fsync: bool,
+    /// syslog also sends the records to the local syslog daemon (unix only.) Default false.
+    #[serde(default)]
+    pub syslog: bool,
+}
+
+const fn default_max_file_bytes() -> u64 { 100 * 1024 * 1024 }
+const fn default_max_files() -> u32 { 10 }
+const fn default_fsync() -> bool { true }
+
+impl AuditSettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self) -> Result<()> {
+        if self.syslog && !cfg!(unix) {
+            return Err(Error::new("audit syslog is only supported on unix"));
+        }
+        if self.max_files == 0 {
+            self.max_files = default_max_files();
+        }
+        Ok(())
+    }
+
+    /// Returns true if queries are audited.
+    pub fn is_enabled(&self) -> bool {
+        !self.file.is_empty() || self.syslog
+    }
+}
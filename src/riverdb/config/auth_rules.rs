@@ -0,0 +1,106 @@
+use std::net::IpAddr;
+
+use serde::{Deserialize};
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::config::enums::AuthMethod;
+
+
+/// Configuration for choosing how clients authenticate based on where they connect from,
+/// similar to pg_hba.conf in PostgreSQL.
+#[derive(Deserialize, Default)]
+pub struct AuthRuleSettings {
+    /// rules map the client address, database, and user to an authentication method.
+    /// The first matching rule applies. If none match, the client_auth setting applies. Default none.
+    #[serde(default)]
+    pub rules: Vec<AuthRule>,
+}
+
+/// The authentication method for clients connecting from an address to a database as a user.
+#[derive(Deserialize, Default, Clone)]
+pub struct AuthRule {
+    /// address is the client IP address or CIDR network (e.g. 10.0.0.0/8 or ::1/128),
+    /// empty matches any client, including clients with an unknown address. Default empty.
+    #[serde(default)]
+    pub address: String,
+    /// database the client is connecting to, empty matches any database. Default empty.
+    #[serde(default)]
+    pub database: String,
+    /// user the client is connecting as, empty matches any user. Default empty.
+    #[serde(default)]
+    pub user: String,
+    /// method is trust, password, md5, scram, cert, jwt, or reject.
+    pub method: AuthMethod,
+    /// network is the parsed address and prefix length in bits
+    #[serde(skip)]
+    pub network: Option<(IpAddr, u8)>,
+}
+
+impl AuthRule {
+    /// Returns true if this rule applies to clients connecting from ip to database as user.
+    pub fn matches(&self, ip: Option<IpAddr>, database: &str, user: &str) -> bool {
+        let address_matches = match (self.network, ip) {
+            (None, _) => true,
+            (Some(network), Some(ip)) => network_contains(network, ip),
+            (Some(_), None) => false,
+        };
+        address_matches && (self.database.is_empty() || self.database == database) && (self.user.is_empty() || self.user == user)
+    }
+}
+
+impl AuthRuleSettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self) -> Result<()> {
+        for rule in &mut self.rules {
+            if !rule.address.is_empty() {
+                rule.network = Some(parse_network(&rule.address)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the first rule that applies to clients connecting from ip to database as user, if any.
+    pub fn rule_for(&self, ip: Option<IpAddr>, database: &str, user: &str) -> Option<&AuthRule> {
+        self.rules.iter().find(|rule| rule.matches(ip, database, user))
+    }
+}
+
+/// Parses an IP address with an optional /prefix length, e.g. 192.168.0.0/16.
+fn parse_network(s: &str) -> Result<(IpAddr, u8)> {
+    let (addr, prefix) = match s.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (s, None),
+    };
+    let addr: IpAddr = addr.trim().parse()
+        .map_err(|_| Error::new(format!("auth_rules address {} is not a valid IP address or CIDR network", s)))?;
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.trim().parse::<u8>().ok().filter(|&p| p <= max_prefix)
+            .ok_or_else(|| Error::new(format!("auth_rules address {} has an invalid prefix length", s)))?,
+        None => max_prefix,
+    };
+    Ok((addr, prefix))
+}
+
+/// Returns true if ip is in network. IPv4 addresses mapped to IPv6 (::ffff:a.b.c.d) match IPv4 networks.
+fn network_contains((network, prefix): (IpAddr, u8), ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => IpAddr::V4(v6.to_ipv4().unwrap()),
+            _ => ip,
+        },
+        _ => ip,
+    };
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        },
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        },
+        _ => false,
+    }
+}
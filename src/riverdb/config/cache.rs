@@ -0,0 +1,171 @@
+use serde::{Deserialize};
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::config::enums::CacheBackend;
+use crate::riverdb::pg::sql::{QueryMessage, QueryNormalizer};
+
+
+/// Configuration for the query result cache.
+/// Policies can be written in the yaml config file, or as inline JSON (which is valid yaml.)
+/// Only single, simple protocol reads outside of a transaction are cached (see ResultCache::put_for_tables.)
+#[derive(Deserialize, Default)]
+pub struct CacheSettings {
+    /// enabled turns on caching of query results that match one of the policies. Default false.
+    #[serde(default)]
+    pub enabled: bool,
+    /// backend is the storage backend for cached results: memory or redis. Default memory.
+    #[serde(default)]
+    pub backend: CacheBackend,
+    /// max_bytes is the maximum total size of the in-memory cache. Default 64MB.
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+    /// shards is the number of independently locked partitions of the in-memory cache. Default 16.
+    #[serde(default = "default_shards")]
+    pub shards: u32,
+    /// redis_address is the host:port of the redis server used when backend is redis. Default localhost:6379.
+    #[serde(default = "default_redis_address")]
+    pub redis_address: String,
+    /// redis_key_prefix is prepended to all keys stored in redis. Default "riverdb:".
+    #[serde(default = "default_redis_key_prefix")]
+    pub redis_key_prefix: String,
+    /// max_entry_bytes is the default maximum size of a single cached result. Default 1MB.
+    #[serde(default = "default_max_entry_bytes")]
+    pub max_entry_bytes: u32,
+    /// policies are checked in order, the first policy matching the query is used.
+    #[serde(default)]
+    pub policies: Vec<CachePolicy>,
+}
+
+const fn default_max_entry_bytes() -> u32 { 1024 * 1024 }
+const fn default_max_bytes() -> u64 { 64 * 1024 * 1024 }
+const fn default_shards() -> u32 { 16 }
+fn default_redis_address() -> String { "localhost:6379".to_string() }
+fn default_redis_key_prefix() -> String { "riverdb:".to_string() }
+
+/// A CachePolicy describes which queries may be cached and for how long.
+/// A policy matches a query by its normalized form (the query fingerprint) and/or a query tag.
+/// If both are specified, both must match.
+#[derive(Deserialize, Default)]
+pub struct CachePolicy {
+    /// query is the normalized SQL query (with $N placeholders) this policy applies to.
+    /// If it doesn't contain any $ placeholders, it's assumed to be an example query and it's normalized on load.
+    #[serde(default)]
+    pub query: String,
+    /// tag is a key=value pattern matched against the /* key=value */ tags in the query.
+    /// The value may be * to match any value, or end in * to match a prefix. e.g. cache=users*
+    #[serde(default)]
+    pub tag: String,
+    /// ttl_seconds is the number of seconds a cached result is fresh. Required.
+    pub ttl_seconds: u32,
+    /// max_entry_bytes is the maximum size of a result that can be cached. Defaults to the cache max_entry_bytes.
+    #[serde(default)]
+    pub max_entry_bytes: u32,
+    /// stale_while_revalidate_seconds is the number of seconds after a result expires that it can still be
+    /// returned while it's refreshed in the background. Default 0 (disabled).
+    /// Not yet implemented for queries, which refresh expired results before returning them.
+    #[serde(default)]
+    pub stale_while_revalidate_seconds: u32,
+    #[serde(skip)]
+    tag_key: String,
+    #[serde(skip)]
+    tag_value: String,
+    #[serde(skip)]
+    tag_is_prefix: bool,
+}
+
+impl CacheSettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self) -> Result<()> {
+        if self.max_entry_bytes == 0 {
+            self.max_entry_bytes = default_max_entry_bytes();
+        }
+        if self.max_bytes == 0 {
+            self.max_bytes = default_max_bytes();
+        }
+        if self.shards == 0 {
+            self.shards = default_shards();
+        }
+        self.shards = self.shards.next_power_of_two();
+        if self.max_bytes < self.max_entry_bytes as u64 {
+            return Err(Error::new("cache max_bytes cannot be < max_entry_bytes"));
+        }
+        if let CacheBackend::Redis = self.backend {
+            if self.redis_address.is_empty() {
+                return Err(Error::new("cache redis_address is required for the redis backend"));
+            }
+        }
+        for (i, policy) in self.policies.iter_mut().enumerate() {
+            policy.load(self.max_entry_bytes)
+                .map_err(|e| Error::new(format!("cache policies[{}]: {}", i, e)))?;
+        }
+        Ok(())
+    }
+
+    /// Return the first CachePolicy matching the query, if any.
+    /// Always returns None if the cache is not enabled.
+    pub fn policy_for(&self, query: &QueryMessage) -> Option<&CachePolicy> {
+        if !self.enabled {
+            return None;
+        }
+        self.policies.iter().find(|policy| policy.matches(query))
+    }
+}
+
+impl CachePolicy {
+    fn load(&mut self, default_max_entry_bytes: u32) -> Result<()> {
+        if self.query.is_empty() && self.tag.is_empty() {
+            return Err(Error::new("one of query or tag is required"));
+        }
+        if self.ttl_seconds == 0 {
+            return Err(Error::new("ttl_seconds must be > 0"));
+        }
+        if self.max_entry_bytes == 0 {
+            self.max_entry_bytes = default_max_entry_bytes;
+        }
+
+        if !self.query.is_empty() && !self.query.contains('$') {
+            let mut tags = Vec::new();
+            let query = QueryNormalizer::new_at(self.query.as_bytes(), 0).normalize(&mut tags)?;
+            if query.next.is_some() {
+                return Err(Error::new("query cannot contain multiple statements"));
+            }
+            self.query = query.normalized;
+        }
+
+        if !self.tag.is_empty() {
+            match self.tag.split_once('=') {
+                Some((key, value)) if !key.is_empty() && !value.is_empty() => {
+                    self.tag_key = key.trim().to_string();
+                    self.tag_is_prefix = value.ends_with('*');
+                    self.tag_value = value.trim_end_matches('*').to_string();
+                },
+                _ => {
+                    return Err(Error::new(format!("tag must be of the form key=value, got {}", &self.tag)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if this policy applies to query.
+    pub fn matches(&self, query: &QueryMessage) -> bool {
+        if !self.query.is_empty() && self.query != query.query().normalized() {
+            return false;
+        }
+        if !self.tag_key.is_empty() {
+            return match query.tag(&self.tag_key) {
+                Some(value) => {
+                    if self.tag_is_prefix {
+                        value.starts_with(self.tag_value.as_str())
+                    } else {
+                        value == self.tag_value
+                    }
+                },
+                None => false,
+            };
+        }
+        true
+    }
+}
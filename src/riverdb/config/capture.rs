@@ -0,0 +1,34 @@
+use serde::{Deserialize};
+
+use crate::riverdb::Result;
+
+
+/// Configuration for capturing the queries of clients to a file, which can be replayed against
+/// another database with pg::replay for benchmarking and regression testing.
+#[derive(Deserialize, Default)]
+pub struct CaptureSettings {
+    /// file is the path of the capture file, it's truncated on startup and only readable by its owner. Default empty (disabled).
+    #[serde(default)]
+    pub file: String,
+    /// max_file_bytes is the size at which capturing stops. 0 for unlimited. Default 1GB.
+    #[serde(default = "default_max_file_bytes")]
+    pub max_file_bytes: u64,
+    /// database limits capturing to the queries of clients connected to database. Default empty (all databases).
+    #[serde(default)]
+    pub database: String,
+}
+
+const fn default_max_file_bytes() -> u64 { 1024 * 1024 * 1024 }
+
+impl CaptureSettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns true if queries are captured.
+    pub fn is_enabled(&self) -> bool {
+        !self.file.is_empty()
+    }
+}
@@ -1,4 +1,5 @@
 use std::mem::MaybeUninit;
+use std::net::SocketAddr;
 use std::path::{PathBuf};
 use std::collections::hash_map::Entry;
 
@@ -6,7 +7,14 @@ use serde::{Deserialize};
 use serde_yaml::Value;
 use fnv::FnvHashMap;
 
-use crate::riverdb::config::postgres::PostgresCluster;
+use crate::riverdb::config::postgres::{PostgresCluster, Postgres};
+use crate::riverdb::config::cache::CacheSettings;
+use crate::riverdb::config::peers::PeerSettings;
+use crate::riverdb::config::memory_limit::MemoryLimitSettings;
+use crate::riverdb::config::audit::AuditSettings;
+use crate::riverdb::config::capture::CaptureSettings;
+use crate::riverdb::config::logging::LogSettings;
+use crate::riverdb::config::enums::IoEngine;
 use crate::riverdb::{Error, Result};
 use crate::riverdb::common::MIN_BUFFER_SPACE;
 
@@ -18,7 +26,7 @@ pub const SMALL_BUFFER_SIZE: u32 = (MIN_BUFFER_SPACE as u32) * 2;
 pub const CONNECT_TIMEOUT_SECONDS: u32 = 30;
 /// CHECK_TIMEOUTS_INTERVAL the number of seconds between checking for timed-out connections.
 pub const CHECK_TIMEOUTS_INTERVAL: u64 = 5 * 60;
-/// LISTEN_BACKLOG for the listening server socket.
+/// LISTEN_BACKLOG is the default accept backlog for listening server sockets.
 pub const LISTEN_BACKLOG: u32 = 1024;
 /// COARSE_CLOCK_GRANULARITY_SECONDS is the number of seconds between ticks of the coarse clock.
 /// It's updated to the current time after this many seconds.
@@ -32,6 +40,7 @@ pub type ConfigMap = FnvHashMap<String, Value>;
 #[derive(Deserialize, Default)]
 pub struct Settings {
     /// config_path is the path of the loaded config file
+    #[serde(skip)]
     pub config_path: PathBuf,
     /// app_name is used as the application name to identify connected sessions to the Postgres databases if not provided by the client
     #[serde(default = "default_app_name")]
@@ -42,6 +51,22 @@ pub struct Settings {
     /// https_port is the port to listen on for HTTPS and WebSocket connections: default 443
     #[serde(default = "default_https_port")]
     pub https_port: u16,
+    /// http_port is the port to listen on for the HTTP service with the /healthz, /readyz, /metrics, and /status endpoints.
+    /// Default 0, which disables it.
+    #[serde(default)]
+    pub http_port: u16,
+    /// http_host is the host the HTTP service listens on, defaults to 127.0.0.1. The /metrics and /status
+    /// endpoints are not authenticated, only listen on a public address if the network is trusted.
+    #[serde(default = "default_http_host")]
+    pub http_host: String,
+    /// http_tls serves the HTTP service over TLS with the tls_server_certificate and tls_server_key of the postgres cluster,
+    /// which requires that its client_tls is enabled. Default false.
+    #[serde(default)]
+    pub http_tls: bool,
+    /// http_query enables the POST /query endpoint of the HTTP service, which runs a SQL statement as the
+    /// Postgres user given with HTTP Basic authentication. Requires http_tls. Default false.
+    #[serde(default)]
+    pub http_query: bool,
     /// disable_keepalives disables use of TCP Keep Alives with long-running client-facing connections to detect and close broken connections. Default false.
     /// If you disable this, use client_idle_timeout_seconds to avoid exhausting server connections when clients disconnect without closing the connection.
     #[serde(default)]
@@ -50,6 +75,26 @@ pub struct Settings {
     /// this reduces lock contention in the kernel when calling accept. Default true.
     #[serde(default = "default_reuseport")]
     pub reuseport: bool,
+    /// reuseaddr sets SO_REUSEADDR on listening sockets so the server can restart while old connections are in TIME_WAIT. Default true.
+    #[serde(default = "default_reuseaddr")]
+    pub reuseaddr: bool,
+    /// ipv6_only sets IPV6_V6ONLY on sockets listening on IPv6 addresses (unix only), so "::" only accepts IPv6
+    /// connections and can be listed together with "0.0.0.0". Set it to false to accept IPv4 connections on "::" too. Default true.
+    #[serde(default = "default_ipv6_only")]
+    pub ipv6_only: bool,
+    /// listen_backlog is the maximum length of the queue of accepted connections waiting for the server to accept them.
+    /// The kernel may silently cap this (see net.core.somaxconn on linux.) Default 1024.
+    #[serde(default = "default_listen_backlog")]
+    pub listen_backlog: u32,
+    /// tcp_fastopen_queue enables TCP Fast Open on listening sockets (linux only) with the given maximum queue
+    /// of pending fast open requests. This saves a round-trip for clients that support it. Default 0 (disabled).
+    #[serde(default)]
+    pub tcp_fastopen_queue: u32,
+    /// io_engine selects how TCP sockets are read from and written to: readiness (epoll, portable) or io_uring
+    /// (linux only, falls back to readiness if the kernel doesn't support it.) Unix domain sockets always use readiness.
+    /// Default readiness.
+    #[serde(default)]
+    pub io_engine: IoEngine,
     /// num_workers is the number of worker threads. Default is the number of hardware threads (hyperthreads) for the host.
     #[serde(default = "default_num_workers")]
     pub num_workers: u32,
@@ -64,6 +109,30 @@ pub struct Settings {
     pub web_socket_idle_timeout_seconds: u32,
     /// postgres specific settings
     pub postgres: PostgresCluster,
+    /// clusters are additional Postgres clusters, each with its own servers, pools, and policies.
+    /// Each must listen on its own port, which lets applications that select an environment
+    /// by port (e.g. 5432 for production, 5433 for staging) share a single riverdb process.
+    /// Default empty.
+    #[serde(default)]
+    pub clusters: Vec<PostgresCluster>,
+    /// query result cache settings
+    #[serde(default)]
+    pub cache: CacheSettings,
+    /// cluster mode settings for sharing state with other riverdb instances
+    #[serde(default)]
+    pub peers: PeerSettings,
+    /// soft memory limit settings for shedding memory before hitting hard limits. Default disabled.
+    #[serde(default)]
+    pub memory_limit: MemoryLimitSettings,
+    /// audit log settings for recording DDL, SET ROLE, and COPY queries. Default disabled.
+    #[serde(default)]
+    pub audit: AuditSettings,
+    /// capture settings for recording the queries of clients to a file for pg::replay. Default disabled.
+    #[serde(default)]
+    pub capture: CaptureSettings,
+    /// log settings for the level, format, and destination of the log. Default info level text to stdout.
+    #[serde(default)]
+    pub log: LogSettings,
     /// plugin settings
     pub plugins: Vec<ConfigMap>,
     #[serde(skip)]
@@ -72,8 +141,12 @@ pub struct Settings {
 
 fn default_num_workers() -> u32 { num_cpus::get() as u32 }
 fn default_reuseport() -> bool { cfg!(unix) }
+const fn default_reuseaddr() -> bool { true }
+const fn default_ipv6_only() -> bool { true }
+const fn default_listen_backlog() -> u32 { LISTEN_BACKLOG }
 fn default_app_name() -> String { "riverdb".to_string() }
 fn default_host() -> String { "0.0.0.0".to_string() }
+fn default_http_host() -> String { "127.0.0.1".to_string() }
 const fn default_https_port() -> u16 { 443 }
 const fn default_recv_buffer_size() -> u32 { 32 * 1024 }
 const fn default_max_http_connections() -> u32 { 100000 }
@@ -116,6 +189,13 @@ impl Settings {
     /// Do not call this method after the server starts.
     pub fn load(&mut self, path: PathBuf) -> Result<()> {
         self.config_path = path;
+        // Settings constructed in code (see init_config) don't get the serde defaults
+        if self.num_workers == 0 {
+            self.num_workers = default_num_workers();
+        }
+        if self.app_name.is_empty() {
+            self.app_name = default_app_name();
+        }
         if self.recv_buffer_size < 4096 {
             self.recv_buffer_size = default_recv_buffer_size();
         }
@@ -126,6 +206,15 @@ impl Settings {
             return Err(Error::new(format!("recv_buffer_size cannot be < {} bytes", MIN_BUFFER_SPACE)));
         }
         self.recv_buffer_size = self.recv_buffer_size.next_power_of_two();
+        if self.listen_backlog == 0 {
+            self.listen_backlog = default_listen_backlog();
+        }
+        if self.max_http_connections == 0 {
+            self.max_http_connections = default_max_http_connections();
+        }
+        if self.io_engine == IoEngine::IoUring && !cfg!(target_os = "linux") {
+            return Err(Error::new("io_engine io_uring is only supported on linux"));
+        }
 
         let mut i = 0;
         for plugin in &mut self.plugins {
@@ -150,7 +239,65 @@ impl Settings {
             }
         }
 
-        self.postgres.load()
+        self.cache.load()?;
+        self.peers.load()?;
+        self.memory_limit.load()?;
+        self.audit.load()?;
+        self.capture.load()?;
+        self.log.load()?;
+        self.postgres.load()?;
+
+        for cluster in self.postgres_clusters() {
+            for host in &cluster.listen {
+                if listen_address(host, cluster.port).parse::<SocketAddr>().is_err() {
+                    return Err(Error::new(format!("invalid listen address {}, listen must be a list of IP addresses", host)));
+                }
+            }
+        }
+
+        if self.http_tls && self.postgres.tls_config.is_none() {
+            return Err(Error::new("http_tls requires client_tls to be enabled for the postgres cluster"));
+        }
+        if self.http_query && !self.http_tls {
+            return Err(Error::new("http_query requires http_tls, the HTTP Basic authentication password would be sent in clear text"));
+        }
+
+        let mut ports = vec![self.postgres.port];
+        for cluster in &mut self.clusters {
+            cluster.load()?;
+            if cluster.port == 0 {
+                return Err(Error::new("clusters must each specify a port"));
+            }
+            if ports.contains(&cluster.port) {
+                return Err(Error::new(format!("port {} is used by more than one cluster", cluster.port)));
+            }
+            ports.push(cluster.port);
+        }
+
+        // The Kerberos library reads the client keytab from the environment of the process (see gss_keytab)
+        let keytab = self.gss_keytab();
+        if self.postgres_servers().any(|server| !server.gss_keytab.is_empty() && server.gss_keytab != keytab) {
+            return Err(Error::new("all servers must use the same gss_keytab"));
+        }
+        Ok(())
+    }
+
+    /// Returns the configuration of every server in every Postgres cluster, including the replicas.
+    pub fn postgres_servers(&self) -> impl Iterator<Item=&Postgres> {
+        self.postgres_clusters()
+            .flat_map(|cluster| cluster.servers.iter())
+            .flat_map(|server| std::iter::once(server).chain(server.replicas.iter()))
+    }
+
+    /// Returns the gss_keytab of the servers that set one, or an empty string. They must all use the same
+    /// keytab, since it's set once for the process on startup (see gssapi::set_client_keytab.)
+    pub fn gss_keytab(&self) -> &str {
+        self.postgres_servers().map(|server| server.gss_keytab.as_str()).find(|keytab| !keytab.is_empty()).unwrap_or("")
+    }
+
+    /// Returns the configuration of all Postgres clusters, starting with the default cluster (postgres).
+    pub fn postgres_clusters(&self) -> impl Iterator<Item=&PostgresCluster> {
+        std::iter::once(&self.postgres).chain(self.clusters.iter())
     }
 
     /// Get the ConfigMap, if any, for the named plugin.
@@ -167,8 +314,36 @@ impl Settings {
         format!("{}:{}", self.host, self.https_port)
     }
 
+    /// Listen address for the HTTP service
+    pub fn http_listen_address(&self) -> String {
+        listen_address(&self.http_host, self.http_port)
+    }
+
     /// Listen address for the PostgreSQL server
     pub fn postgres_listen_address(&self) -> String {
-        format!("{}:{}", self.host, self.postgres.port)
+        self.cluster_listen_address(&self.postgres)
+    }
+
+    /// Listen address for the PostgreSQL server of the given cluster, the first if it has several
+    pub fn cluster_listen_address(&self, cluster: &PostgresCluster) -> String {
+        self.cluster_listen_addresses(cluster).swap_remove(0)
+    }
+
+    /// Listen addresses for the PostgreSQL server of the given cluster, one per host in its listen setting
+    pub fn cluster_listen_addresses(&self, cluster: &PostgresCluster) -> Vec<String> {
+        if cluster.listen.is_empty() {
+            vec![listen_address(&self.host, cluster.port)]
+        } else {
+            cluster.listen.iter().map(|host| listen_address(host, cluster.port)).collect()
+        }
+    }
+}
+
+/// Returns the host:port address to listen on, adding brackets around IPv6 addresses if required.
+fn listen_address(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
     }
 }
@@ -0,0 +1,45 @@
+use serde::{Deserialize};
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::config::enums::Jitter;
+
+
+/// Configuration for retrying failed attempts to connect to (and authenticate with) a database server
+/// when growing a pool, so that a network blip or a restart of Postgres doesn't fail the checkout.
+/// Only errors that may not happen again on another attempt are retried: network errors, and Postgres errors
+/// reporting a connection exception or that the server is starting up or shutting down.
+#[derive(Deserialize, Default)]
+pub struct ConnectRetrySettings {
+    /// retries is the number of times to retry connecting after a failed attempt. Default 0, which disables retries.
+    #[serde(default)]
+    pub retries: u32,
+    /// base_delay_ms is the delay before the first retry, it doubles with each following retry. Default 100.
+    #[serde(default = "default_base_delay_ms")]
+    pub base_delay_ms: u32,
+    /// max_delay_ms is the maximum delay between retries. Default 5000.
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u32,
+    /// jitter is how the delay is randomized, to avoid all pools retrying in lockstep. Default equal.
+    #[serde(default)]
+    pub jitter: Jitter,
+}
+
+const fn default_base_delay_ms() -> u32 { 100 }
+const fn default_max_delay_ms() -> u32 { 5000 }
+
+impl ConnectRetrySettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self) -> Result<()> {
+        if self.base_delay_ms == 0 {
+            self.base_delay_ms = default_base_delay_ms();
+        }
+        if self.max_delay_ms == 0 {
+            self.max_delay_ms = default_max_delay_ms();
+        }
+        if self.max_delay_ms < self.base_delay_ms {
+            return Err(Error::new(format!("connect_retry max_delay_ms {} cannot be less than base_delay_ms {}", self.max_delay_ms, self.base_delay_ms)));
+        }
+        Ok(())
+    }
+}
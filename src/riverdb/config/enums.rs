@@ -1,26 +1,343 @@
-use serde::{Deserialize};
+use serde::{Deserialize, Serialize};
 
 /// TlsMode is an enum of the supported TLS settings for the PostgreSQL connection.
 /// Used for both backend (to db server) and client connections (clients connected to this server.)
-#[derive(Deserialize, Copy, Clone)]
+#[derive(Deserialize, Serialize, Copy, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum TlsMode {
     /// Invalid, used to indicate value was not explicitly set
     Invalid,
     /// Disabled do not use TLS
     Disabled,
-    /// Prefer use TLS when the other side of the connection permits it, and verifies the issuing CA is trusted, and the hostname matches
+    /// Prefer use TLS when the other side of the connection permits it (otherwise connect without it),
+    /// and verifies the issuing CA is trusted, and the hostname matches
     Prefer,
     /// Required requires TLS and verifies the issuing CA is trusted, and the hostname matches
     Required,
+    /// VerifyCa requires TLS and verifies the issuing CA is trusted, but not the hostname (like libpq sslmode=verify-ca.)
+    /// Only supported for backend_tls.
+    #[serde(rename = "verify-ca")]
+    VerifyCa,
+    /// VerifyFull requires TLS and verifies the issuing CA is trusted, and the hostname matches (like libpq sslmode=verify-full.)
+    /// Only supported for backend_tls.
+    #[serde(rename = "verify-full")]
+    VerifyFull,
     /// DangerouslyUnverifiedCertificates requires TLS but does not verify the issuing CA or hostname.
     /// DO NOT USE in production! This only exists for facilitating testing/troubleshooting.
     DangerouslyUnverifiedCertificates,
 }
 
+impl TlsMode {
+    /// Returns true if the certificate of the server must be valid for its hostname.
+    pub fn verifies_hostname(self) -> bool {
+        matches!(self, TlsMode::Prefer | TlsMode::Required | TlsMode::VerifyFull)
+    }
+}
+
 impl Default for TlsMode {
     fn default() -> Self {
         TlsMode::Invalid
     }
 }
 
+
+/// TlsVersion is an enum of the TLS protocol versions.
+#[derive(Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsVersion {
+    /// Tls12 is TLS 1.2
+    Tls12,
+    /// Tls13 is TLS 1.3
+    Tls13,
+}
+
+impl Default for TlsVersion {
+    fn default() -> Self {
+        TlsVersion::Tls12
+    }
+}
+
+
+/// PoolMode is an enum of when a backend connection used by a client is returned to the pool.
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PoolMode {
+    /// Invalid, used to indicate value was not explicitly set
+    Invalid,
+    /// Session returns the connection when the client disconnects
+    Session,
+    /// Transaction returns the connection after each transaction, or query outside a transaction
+    Transaction,
+    /// Statement returns the connection after each query, transactions are not permitted
+    Statement,
+}
+
+impl Default for PoolMode {
+    fn default() -> Self {
+        PoolMode::Invalid
+    }
+}
+
+
+/// TagViolationAction is an enum of what to do with a query missing a required tag.
+#[derive(Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TagViolationAction {
+    /// Log logs a warning and runs the query
+    Log,
+    /// Reject returns an error to the client without running the query
+    Reject,
+}
+
+impl Default for TagViolationAction {
+    fn default() -> Self {
+        TagViolationAction::Log
+    }
+}
+
+/// TracePropagation is an enum of the ways to pass the trace tag of a query (see QueryTagSettings::trace_tags)
+/// to the database, so the database sessions can be correlated with application traces.
+#[derive(Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TracePropagation {
+    /// None doesn't pass the trace tag to the database
+    None,
+    /// ApplicationName appends tag=value to the application_name of the backend connection it checks out
+    ApplicationName,
+    /// SetLocal sets the trace_setting custom setting to the value before running the query,
+    /// with SET LOCAL in a transaction, otherwise with SET for the session
+    SetLocal,
+}
+
+impl Default for TracePropagation {
+    fn default() -> Self {
+        TracePropagation::None
+    }
+}
+
+
+/// ProtocolOptions is an enum of the ways to handle the _pq_ protocol options (e.g. _pq_.compression)
+/// clients can request in the startup message.
+#[derive(Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProtocolOptions {
+    /// Strip removes the options, so a protocol extension riverdb doesn't understand is never used.
+    /// The client is told they're unsupported with a NegotiateProtocolVersion message.
+    Strip,
+    /// Passthrough keeps the options in the client's connection parameters and requests them from
+    /// the database when opening a backend connection for that client
+    Passthrough,
+}
+
+impl Default for ProtocolOptions {
+    fn default() -> Self {
+        ProtocolOptions::Strip
+    }
+}
+
+
+/// ClientAuth is an enum of the password authentication methods riverdb offers clients.
+#[derive(Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    /// Scram uses SCRAM-SHA-256 (with channel binding over TLS) for the user configured for the database,
+    /// other users fall back to password
+    Scram,
+    /// Password asks for the clear text password over TLS, and for an MD5 hashed password otherwise
+    Password,
+}
+
+impl Default for ClientAuth {
+    fn default() -> Self {
+        ClientAuth::Password
+    }
+}
+
+
+/// AuthMethod is an enum of the ways an auth_rules rule can require a client to authenticate.
+#[derive(Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    /// Trust allows the client to connect without a password
+    Trust,
+    /// Password asks for the clear text password, use only over TLS
+    Password,
+    /// Md5 asks for an MD5 hashed password, only possible for the user configured for the database
+    Md5,
+    /// Scram uses SCRAM-SHA-256 (with channel binding over TLS), only possible for the user configured for the database
+    Scram,
+    /// Cert requires a TLS client certificate issued by tls_client_ca_certificate, with the user as its common name
+    /// or a DNS subject alternative name
+    Cert,
+    /// Jwt asks for a JSON Web Token as the clear text password, verified with the jwt settings, and sets the role
+    /// of the session from its claims. Clients without TLS are rejected, and the session can't change its role
+    Jwt,
+    /// Reject refuses the connection
+    Reject,
+}
+
+impl Default for AuthMethod {
+    fn default() -> Self {
+        AuthMethod::Reject
+    }
+}
+
+
+/// CacheBackend is an enum of the supported storage backends for the query result cache.
+#[derive(Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheBackend {
+    /// Memory stores results in a sharded, size bounded LRU cache in this process
+    Memory,
+    /// Redis stores results in a redis server, so they can be shared by multiple riverdb instances
+    Redis,
+}
+
+impl Default for CacheBackend {
+    fn default() -> Self {
+        CacheBackend::Memory
+    }
+}
+
+
+/// NotificationPolicy is an enum of the ways to handle a NotificationResponse received by a pooled
+/// connection, which happens when a previous client ran LISTEN and it wasn't undone by the server_reset_query.
+/// The notification is always dropped, because there's no client to deliver it to.
+#[derive(Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationPolicy {
+    /// Drop only drops the notification, the connection keeps listening.
+    Drop,
+    /// Unlisten drops the notification and runs UNLISTEN * before the connection is next used.
+    Unlisten,
+    /// Close drops the notification and closes the connection.
+    Close,
+}
+
+impl Default for NotificationPolicy {
+    fn default() -> Self {
+        NotificationPolicy::Unlisten
+    }
+}
+
+/// ReplicaSelection is an enum of the strategies for choosing the replica a read-only query is routed to.
+#[derive(Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicaSelection {
+    /// WeightedRandom chooses a random replica with probability proportional to its weight
+    WeightedRandom,
+    /// LeastLoaded chooses the replica with the fewest in-use connections relative to its weight
+    LeastLoaded,
+}
+
+impl Default for ReplicaSelection {
+    fn default() -> Self {
+        ReplicaSelection::WeightedRandom
+    }
+}
+
+/// ShardingStrategy is an enum of the ways a shard key is mapped to a shard, see the sharding settings.
+#[derive(Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardingStrategy {
+    /// Hash chooses the shard by the FNV-1a hash of the key modulo the number of shards
+    Hash,
+    /// Range chooses the shard with the largest min that's less than or equal to the (integer) key
+    Range,
+}
+
+impl Default for ShardingStrategy {
+    fn default() -> Self {
+        ShardingStrategy::Hash
+    }
+}
+
+/// Jitter is an enum of the ways a retry delay is randomized, see the connect_retry settings.
+#[derive(Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Jitter {
+    /// None waits for exactly the delay
+    None,
+    /// Equal waits for half the delay plus a random amount up to the other half
+    Equal,
+    /// Full waits for a random amount up to the delay
+    Full,
+}
+
+impl Default for Jitter {
+    fn default() -> Self {
+        Jitter::Equal
+    }
+}
+
+/// IsolationAction is an enum of what to do with a transaction requesting a higher isolation level than max_isolation.
+#[derive(Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IsolationAction {
+    /// Invalid, used to indicate value was not explicitly set
+    Invalid,
+    /// Reject returns an error to the client without running the transaction
+    Reject,
+    /// Downgrade runs the transaction at max_isolation instead, with a warning to the client
+    Downgrade,
+}
+
+impl Default for IsolationAction {
+    fn default() -> Self {
+        IsolationAction::Invalid
+    }
+}
+
+
+/// LogFormat is an enum of the formats of the log lines.
+#[derive(Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Text is a human readable line per event, with the span context and fields as key=value pairs
+    Text,
+    /// Json is a JSON object per line, for log aggregators
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+/// LogRotation is an enum of the intervals at which the log file is rotated.
+#[derive(Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    /// Never rotates the log file on a schedule, only when it reaches max_file_bytes (if set)
+    Never,
+    /// Hourly rotates the log file at the start of each hour (UTC)
+    Hourly,
+    /// Daily rotates the log file at midnight UTC
+    Daily,
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        LogRotation::Never
+    }
+}
+
+
+/// IoEngine is an enum of the ways sockets are read from and written to.
+#[derive(Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IoEngine {
+    /// Readiness waits for sockets to become readable or writable (epoll on linux) and then reads or writes them.
+    /// It's portable and the default.
+    Readiness,
+    /// IoUring submits the accepts, reads, writes, and closes of TCP sockets to an io_uring (linux only).
+    /// Falls back to Readiness if the kernel doesn't support io_uring.
+    IoUring,
+}
+
+impl Default for IoEngine {
+    fn default() -> Self {
+        IoEngine::Readiness
+    }
+}
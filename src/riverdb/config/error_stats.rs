@@ -0,0 +1,45 @@
+use serde::{Deserialize};
+
+use crate::riverdb::{Error, Result};
+
+
+/// Configuration for aggregating the errors returned by Postgres by SQLSTATE class.
+#[derive(Deserialize, Default)]
+pub struct ErrorStatsSettings {
+    /// alerts log a warning when the number of errors matching a SQLSTATE code or class exceeds
+    /// a threshold within a time window, e.g. a spike in serialization failures (40001)
+    /// or insufficient resources errors (53). Default none.
+    #[serde(default)]
+    pub alerts: Vec<ErrorAlert>,
+}
+
+/// An alert threshold for errors matching a SQLSTATE code or class.
+#[derive(Deserialize, Default, Clone)]
+pub struct ErrorAlert {
+    /// code is a five character SQLSTATE error code (e.g. 40001) or a two character class (e.g. 53.)
+    pub code: String,
+    /// max_errors is the number of matching errors permitted within window_seconds before alerting.
+    pub max_errors: u32,
+    /// window_seconds is the length of the time window for counting errors. Default 60.
+    #[serde(default = "default_window_seconds")]
+    pub window_seconds: u32,
+}
+
+const fn default_window_seconds() -> u32 { 60 }
+
+impl ErrorStatsSettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self) -> Result<()> {
+        for alert in &mut self.alerts {
+            alert.code.make_ascii_uppercase();
+            if (alert.code.len() != 2 && alert.code.len() != 5) || !alert.code.bytes().all(|c| c.is_ascii_alphanumeric()) {
+                return Err(Error::new(format!("error_stats alert code {:?} must be a SQLSTATE code or a two character class", alert.code)));
+            }
+            if alert.window_seconds == 0 {
+                alert.window_seconds = default_window_seconds();
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+use serde::{Deserialize};
+
+use crate::riverdb::Result;
+
+
+/// Configuration for periodically checking that the master and replicas of each node are reachable,
+/// removing unreachable servers from routing, and failing over to a replica when the master is down.
+#[derive(Deserialize, Default)]
+pub struct HealthCheckSettings {
+    /// enabled turns on health checking. Default false.
+    #[serde(default)]
+    pub enabled: bool,
+    /// check_ms is the number of milliseconds between health checks of each server. Default 1000.
+    #[serde(default = "default_check_ms")]
+    pub check_ms: u32,
+    /// timeout_ms is the number of milliseconds to wait for a server to answer a health check. Default 2000.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u32,
+    /// unhealthy_threshold is the number of consecutive failed health checks before a server is marked down.
+    /// A single successful health check marks it up again. Default 3.
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+    /// promote_replica promotes the healthy replica with the lowest failover_priority (with pg_promote())
+    /// when the master is marked down, and sends writes to it. Otherwise writes fail until the master
+    /// is back up, or a master_down plugin chooses the new master. Default false.
+    #[serde(default)]
+    pub promote_replica: bool,
+}
+
+const fn default_check_ms() -> u32 { 1000 }
+const fn default_timeout_ms() -> u32 { 2000 }
+const fn default_unhealthy_threshold() -> u32 { 3 }
+
+impl HealthCheckSettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self) -> Result<()> {
+        if self.check_ms == 0 {
+            self.check_ms = default_check_ms();
+        }
+        if self.timeout_ms == 0 {
+            self.timeout_ms = default_timeout_ms();
+        }
+        if self.unhealthy_threshold == 0 {
+            self.unhealthy_threshold = default_unhealthy_threshold();
+        }
+        Ok(())
+    }
+}
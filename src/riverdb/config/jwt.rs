@@ -0,0 +1,57 @@
+use serde::{Deserialize};
+use fnv::FnvHashMap;
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::pg::JwkSet;
+
+
+/// Configuration for verifying the JSON Web Tokens (JWT) of clients using the jwt authentication method
+/// (see auth_rules.) The client sends the token as its password, and its claims choose the Postgres role
+/// set with SET ROLE on the backend connections. This lets platforms that mint short-lived database tokens
+/// connect through riverdb without a password per user.
+#[derive(Deserialize, Default)]
+pub struct JwtSettings {
+    /// issuer is the required iss claim of the tokens. Required for the jwt method.
+    #[serde(default)]
+    pub issuer: String,
+    /// audience is the required aud claim of the tokens (one of them, if it's an array.) Default empty (not checked.)
+    #[serde(default)]
+    pub audience: String,
+    /// jwks is the JSON Web Key Set with the public keys that sign the tokens (RS256, RS384, RS512, PS256, PS384,
+    /// PS512, ES256, or ES384.) The value can be the inlined JSON, or a file path from which to load it.
+    /// A file is loaded again when a token is signed with a key it doesn't contain, to pick up rotated keys.
+    /// Required for the jwt method.
+    #[serde(default)]
+    pub jwks: String,
+    /// role_claim is the claim with the Postgres role of the client. Default role.
+    #[serde(default)]
+    pub role_claim: String,
+    /// roles map the values of role_claim to Postgres roles. If set, tokens with other values are rejected.
+    /// Default none (the value is the role.)
+    #[serde(default)]
+    pub roles: FnvHashMap<String, String>,
+    /// leeway_seconds is the allowed clock skew when checking the exp and nbf claims. Default 60.
+    #[serde(default = "default_leeway_seconds")]
+    pub leeway_seconds: u32,
+}
+
+const fn default_leeway_seconds() -> u32 { 60 }
+
+impl JwtSettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self) -> Result<()> {
+        if self.role_claim.is_empty() {
+            self.role_claim = "role".to_string();
+        }
+        if !self.jwks.is_empty() {
+            JwkSet::load(&self.jwks).map_err(|e| Error::new(format!("invalid jwt jwks: {}", e)))?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if tokens can be verified with these settings.
+    pub fn is_configured(&self) -> bool {
+        !self.jwks.is_empty() && !self.issuer.is_empty()
+    }
+}
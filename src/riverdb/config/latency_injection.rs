@@ -0,0 +1,59 @@
+use serde::{Deserialize};
+use tracing::warn;
+
+use crate::riverdb::{Error, Result};
+
+
+/// Configuration for adding artificial latency to queries, to test how applications behave when the
+/// database is slow or far away (e.g. "what if the database were 20ms away".) For staging, not production.
+#[derive(Deserialize, Default)]
+pub struct LatencyInjectionSettings {
+    /// rules delay the queries of matching clients before they're forwarded to the database.
+    /// The first matching rule applies. Default none.
+    #[serde(default)]
+    pub rules: Vec<LatencyRule>,
+}
+
+/// Artificial latency added to the queries of clients connected to a database as a user.
+#[derive(Deserialize, Default, Clone)]
+pub struct LatencyRule {
+    /// database the client connected to, empty matches any database. Default empty.
+    #[serde(default)]
+    pub database: String,
+    /// user the client connected as, empty matches any user. Default empty.
+    #[serde(default)]
+    pub user: String,
+    /// delay_ms is the number of milliseconds each query is delayed.
+    pub delay_ms: u32,
+    /// jitter_ms is the maximum number of additional milliseconds, chosen at random, each query is delayed. Default 0.
+    #[serde(default)]
+    pub jitter_ms: u32,
+}
+
+impl LatencyRule {
+    /// Returns true if this rule applies to clients connected to database as user.
+    pub fn matches(&self, database: &str, user: &str) -> bool {
+        (self.database.is_empty() || self.database == database) && (self.user.is_empty() || self.user == user)
+    }
+}
+
+impl LatencyInjectionSettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self) -> Result<()> {
+        for rule in &self.rules {
+            if rule.delay_ms == 0 && rule.jitter_ms == 0 {
+                return Err(Error::new("latency_injection rules require delay_ms or jitter_ms"));
+            }
+        }
+        if !self.rules.is_empty() {
+            warn!(rules=self.rules.len(), "latency_injection is enabled, queries will be artificially delayed");
+        }
+        Ok(())
+    }
+
+    /// Returns the first rule that applies to clients connected to database as user, if any.
+    pub fn rule_for(&self, database: &str, user: &str) -> Option<&LatencyRule> {
+        self.rules.iter().find(|rule| rule.matches(database, user))
+    }
+}
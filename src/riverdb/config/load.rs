@@ -1,4 +1,6 @@
 use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::AcqRel;
 use tracing::{info_span, info, debug};
 use std::env;
 use std::borrow::Cow;
@@ -6,11 +8,23 @@ use regex::{Regex, Captures};
 
 use crate::riverdb::{Error, Result};
 use crate::riverdb::config::config;
+use crate::riverdb::pg::protocol::gssapi;
 
 
+/// Set once the global settings are installed by load_config or init_config, which can only happen once
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns an error if the global settings were already installed, otherwise marks them installed.
+fn install_once() -> Result<()> {
+    if INSTALLED.swap(true, AcqRel) {
+        return Err(Error::new("the settings were already loaded, load_config or init_config can only be called once"));
+    }
+    Ok(())
+}
+
 /// Load configuration settings from riverdb.yaml
 /// Searching in order:
-/// 1) config_path passed as first command line argument
+/// 1) config_path passed as first command line argument (other than flags)
 /// 2) Current directory
 /// 3) Any parent directory of the current directory, up to root
 /// 4) ~/.config/riverdb/
@@ -25,16 +39,56 @@ pub fn load_config(config_name: &str) -> Result<&'static config::Settings> {
     info!(config_path = %config_path.to_string_lossy().into_owned(), "found config file");
     let raw_yaml = std::fs::read_to_string(&config_path)?;
     let yaml_text = replace_env_vars(&raw_yaml)?;
+    let settings: config::Settings = serde_yaml::from_str(&yaml_text)?;
 
-    let config = unsafe { &mut *config::SETTINGS.as_mut_ptr() };
-    *config = serde_yaml::from_str(&yaml_text)?;
+    install_once()?;
+    // Safety: install_once ensures this is the only write, before anything reads the settings
+    let config = unsafe {
+        let p = std::ptr::addr_of_mut!(config::SETTINGS) as *mut config::Settings;
+        p.write(settings);
+        &mut *p
+    };
     config.load(config_path)?;
+    gssapi::set_client_keytab(config.gss_keytab());
+    Ok(&*config)
+}
+
+/// Use settings constructed in code instead of loading riverdb.yaml, e.g. when embedding
+/// riverdb in an application as a connection pool (see pg::Pool.) Validates the settings and
+/// installs them as the settings returned by conf(). Call this once on startup, before starting other threads
+/// (see gssapi::set_client_keytab), instead of load_config. It returns an error if the settings were already installed.
+pub fn init_config(settings: config::Settings) -> Result<&'static config::Settings> {
+    install_once()?;
+    // Load in place, the server configs store pointers to their PostgresCluster.
+    // Safety: install_once ensures this is the only write, before anything reads the settings
+    let config = unsafe {
+        let p = std::ptr::addr_of_mut!(config::SETTINGS) as *mut config::Settings;
+        p.write(settings);
+        &mut *p
+    };
+    config.load(PathBuf::new())?;
+    gssapi::set_client_keytab(config.gss_keytab());
     Ok(&*config)
 }
 
+/// Re-read and validate the config file the server was started with (see Settings::config_path.)
+/// The new Settings are leaked, like the originals, because the pools created from them keep
+/// 'static references to them. This doesn't change the Settings returned by conf().
+pub fn reload_config() -> Result<&'static config::Settings> {
+    let config_path = config::conf().config_path.clone();
+    let _span = info_span!("reloading config file", config_path = %config_path.to_string_lossy().into_owned());
+    let raw_yaml = std::fs::read_to_string(&config_path)?;
+    let yaml_text = replace_env_vars(&raw_yaml)?;
+
+    // Load in place on the heap, the server configs store pointers to their PostgresCluster
+    let mut config: Box<config::Settings> = Box::new(serde_yaml::from_str(&yaml_text)?);
+    config.load(config_path)?;
+    Ok(Box::leak(config))
+}
+
 fn find_config_file(config_name: &str) -> Result<PathBuf> {
-    // Use the full path given as the first command line argument
-    if let Some(path) = env::args().skip(1).next() {
+    // Use the full path given as the first command line argument, ignoring flags like --supervise
+    if let Some(path) = env::args().skip(1).find(|arg| !arg.starts_with("--")) {
         debug!("using config_path passed on command line");
         return Ok(PathBuf::from(path));
     }
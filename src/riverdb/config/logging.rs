@@ -0,0 +1,96 @@
+use std::str::FromStr;
+
+use serde::{Deserialize};
+use fnv::FnvHashMap;
+use tracing::Level;
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::config::enums::{LogFormat, LogRotation};
+
+
+/// Configuration for the log: which events are logged, how they're formatted, and where they're written.
+#[derive(Deserialize)]
+pub struct LogSettings {
+    /// level is the maximum level of the events logged: off, error, warn, info, debug, or trace.
+    /// Release builds never log debug or trace events. Default info.
+    #[serde(default = "default_level")]
+    pub level: String,
+    /// modules overrides level for the events of modules and their submodules, e.g. riverdb::pg::backend: debug.
+    /// Default none.
+    #[serde(default)]
+    pub modules: FnvHashMap<String, String>,
+    /// format is text or json (a JSON object per line.) Default text.
+    #[serde(default)]
+    pub format: LogFormat,
+    /// file is the path of the log file, it's appended to. Default empty (stdout.)
+    #[serde(default)]
+    pub file: String,
+    /// max_file_bytes rotates the log file before it exceeds this size. Default 0 (no limit.)
+    #[serde(default)]
+    pub max_file_bytes: u64,
+    /// rotation is never, hourly, or daily, how often the log file is rotated regardless of size.
+    /// A rotated file is renamed with the UTC time of the rotation appended, e.g. riverdb.log.20210701T000000.000.
+    /// Default never.
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// max_files is the number of rotated log files kept, the oldest are deleted. 0 keeps them all. Default 7.
+    #[serde(default = "default_max_files")]
+    pub max_files: u32,
+}
+
+fn default_level() -> String { "info".to_string() }
+const fn default_max_files() -> u32 { 7 }
+
+// Not derived, so the defaults are the same when the log settings are omitted
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self{
+            level: default_level(),
+            modules: FnvHashMap::default(),
+            format: LogFormat::default(),
+            file: String::new(),
+            max_file_bytes: 0,
+            rotation: LogRotation::default(),
+            max_files: default_max_files(),
+        }
+    }
+}
+
+impl LogSettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self) -> Result<()> {
+        if self.level.is_empty() {
+            self.level = default_level();
+        }
+        check_level(&self.level)?;
+        for (module, level) in &self.modules {
+            if module.is_empty() || module.contains(|c: char| c == '=' || c == ',' || c.is_whitespace()) {
+                return Err(Error::new(format!("log modules has an invalid module name '{}'", module)));
+            }
+            check_level(level)?;
+        }
+        if self.file.is_empty() && (self.max_file_bytes != 0 || self.rotation != LogRotation::Never) {
+            return Err(Error::new("log rotation requires a log file"));
+        }
+        Ok(())
+    }
+
+    /// Returns the filter directives for level and modules, e.g. info,riverdb::pg::backend=debug.
+    pub fn filter(&self) -> String {
+        let mut modules: Vec<_> = self.modules.iter().collect();
+        modules.sort();
+        let mut filter = self.level.to_lowercase();
+        for (module, level) in modules {
+            filter.push_str(&format!(",{}={}", module, level.to_lowercase()));
+        }
+        filter
+    }
+}
+
+fn check_level(level: &str) -> Result<()> {
+    if level.eq_ignore_ascii_case("off") || Level::from_str(level).is_ok() {
+        return Ok(());
+    }
+    Err(Error::new(format!("invalid log level '{}', must be off, error, warn, info, debug, or trace", level)))
+}
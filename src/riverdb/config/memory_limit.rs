@@ -0,0 +1,55 @@
+use serde::{Deserialize};
+
+use crate::riverdb::{Error, Result};
+
+
+/// Configuration for the soft memory limit. When the process uses more memory than the limit,
+/// the result cache, idle pooled connections, and connection backlogs are shed together to bring
+/// it back under, before it runs into hard limits (e.g. a cgroup memory limit or the OOM killer.)
+#[derive(Deserialize, Default)]
+pub struct MemoryLimitSettings {
+    /// soft_limit_bytes is the resident memory size of the process above which memory is shed (linux only).
+    /// Default 0 (disabled).
+    #[serde(default)]
+    pub soft_limit_bytes: u64,
+    /// check_ms is the number of milliseconds between checks of the memory usage. Default 1000.
+    #[serde(default = "default_check_ms")]
+    pub check_ms: u32,
+    /// cache_shrink_percent is the percent of the in-memory result cache evicted on each check while over
+    /// the limit. No new results are cached while over the limit. Default 25.
+    #[serde(default = "default_cache_shrink_percent")]
+    pub cache_shrink_percent: u32,
+    /// keep_idle_connections is the number of idle connections kept in each pool while over the limit,
+    /// the rest are closed. Default 1.
+    #[serde(default = "default_keep_idle_connections")]
+    pub keep_idle_connections: u32,
+    /// max_backlog_bytes is the maximum size of the data waiting to be written to a slow client or database
+    /// connection while over the limit. Connections that would exceed it are closed. Default 1MB.
+    #[serde(default = "default_max_backlog_bytes")]
+    pub max_backlog_bytes: u32,
+}
+
+const fn default_check_ms() -> u32 { 1000 }
+const fn default_cache_shrink_percent() -> u32 { 25 }
+const fn default_keep_idle_connections() -> u32 { 1 }
+const fn default_max_backlog_bytes() -> u32 { 1024 * 1024 }
+
+impl MemoryLimitSettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self) -> Result<()> {
+        if self.check_ms == 0 {
+            self.check_ms = default_check_ms();
+        }
+        if self.max_backlog_bytes == 0 {
+            self.max_backlog_bytes = default_max_backlog_bytes();
+        }
+        if self.cache_shrink_percent > 100 {
+            return Err(Error::new("memory_limit cache_shrink_percent cannot be > 100"));
+        }
+        if self.soft_limit_bytes != 0 && !cfg!(target_os = "linux") {
+            return Err(Error::new("memory_limit soft_limit_bytes is only supported on linux"));
+        }
+        Ok(())
+    }
+}
@@ -1,9 +1,47 @@
 mod config;
 mod postgres;
+mod cache;
+mod peers;
+mod shard_map;
+mod sharding;
+mod error_stats;
+mod slow_replica;
+mod slow_query;
+mod health_check;
+mod connect_retry;
+mod latency_injection;
+mod rate_limit;
+mod query_tags;
+mod query_types;
+mod auth_rules;
+mod jwt;
+mod memory_limit;
+mod audit;
+mod capture;
+mod logging;
 mod enums;
 mod load;
 
 pub use config::*;
 pub use postgres::*;
+pub use cache::*;
+pub use peers::*;
+pub use shard_map::*;
+pub use sharding::*;
+pub use error_stats::*;
+pub use slow_replica::*;
+pub use slow_query::*;
+pub use health_check::*;
+pub use connect_retry::*;
+pub use latency_injection::*;
+pub use rate_limit::*;
+pub use query_tags::*;
+pub use query_types::*;
+pub use auth_rules::*;
+pub use jwt::*;
+pub use memory_limit::*;
+pub use audit::*;
+pub use capture::*;
+pub use logging::*;
 pub use enums::*;
-pub use load::load_config;
\ No newline at end of file
+pub use load::{load_config, reload_config, init_config};
\ No newline at end of file
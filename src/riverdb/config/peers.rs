@@ -0,0 +1,53 @@
+use serde::{Deserialize};
+
+use crate::riverdb::{Error, Result};
+
+
+/// Configuration for cluster mode, where multiple riverdb instances share state
+/// (cache invalidations and paused state, see the PAUSE and RESUME admin commands) through redis pub/sub.
+#[derive(Deserialize, Default)]
+pub struct PeerSettings {
+    /// enabled turns on cluster mode. Default false.
+    #[serde(default)]
+    pub enabled: bool,
+    /// node_id uniquely identifies this riverdb instance among its peers. Defaults to hostname:pid.
+    #[serde(default)]
+    pub node_id: String,
+    /// redis_address is the host:port of the redis server used to exchange messages with peers. Default localhost:6379.
+    #[serde(default = "default_redis_address")]
+    pub redis_address: String,
+    /// channel is the redis pub/sub channel used to exchange messages with peers. Default "riverdb".
+    #[serde(default = "default_channel")]
+    pub channel: String,
+}
+
+fn default_redis_address() -> String { "localhost:6379".to_string() }
+fn default_channel() -> String { "riverdb".to_string() }
+
+impl PeerSettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self) -> Result<()> {
+        if self.node_id.is_empty() {
+            self.node_id = format!("{}:{}", hostname(), std::process::id());
+        }
+        if self.node_id.contains('\0') {
+            return Err(Error::new("peers node_id cannot contain a null byte"));
+        }
+        if self.enabled && (self.redis_address.is_empty() || self.channel.is_empty()) {
+            return Err(Error::new("peers redis_address and channel are required in cluster mode"));
+        }
+        Ok(())
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // Safety: gethostname writes at most buf.len() bytes to buf
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if rc != 0 {
+        return "localhost".to_string();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
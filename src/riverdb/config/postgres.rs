@@ -7,9 +7,24 @@ use std::fs::File;
 use serde::{Deserialize};
 use rustls::{Certificate, PrivateKey};
 
-use crate::riverdb::config::enums::TlsMode;
+use crate::riverdb::config::enums::{TlsMode, TlsVersion, ReplicaSelection, NotificationPolicy, ProtocolOptions, PoolMode, ClientAuth, AuthMethod, IsolationAction, TracePropagation};
+use crate::riverdb::config::shard_map::ShardMapSettings;
+use crate::riverdb::config::sharding::ShardingSettings;
+use crate::riverdb::config::error_stats::ErrorStatsSettings;
+use crate::riverdb::config::slow_replica::SlowReplicaSettings;
+use crate::riverdb::config::slow_query::SlowQuerySettings;
+use crate::riverdb::config::health_check::HealthCheckSettings;
+use crate::riverdb::config::connect_retry::ConnectRetrySettings;
+use crate::riverdb::config::latency_injection::LatencyInjectionSettings;
+use crate::riverdb::config::rate_limit::RateLimitSettings;
+use crate::riverdb::config::query_tags::QueryTagSettings;
+use crate::riverdb::config::query_types::QueryTypeSettings;
+use crate::riverdb::config::auth_rules::AuthRuleSettings;
+use crate::riverdb::config::jwt::JwtSettings;
 use crate::riverdb::{Error, Result};
-use crate::riverdb::server::DangerousCertificateNonverifier;
+use crate::riverdb::server::{DangerousCertificateNonverifier, CertificateChainVerifier};
+use crate::riverdb::pg::protocol::{sasl, gssapi};
+use crate::riverdb::pg::IsolationLevel;
 
 
 /// Configuration for a Postgres cluster where each writable master server can have its own read-only replicas.
@@ -24,10 +39,26 @@ pub struct PostgresCluster {
     /// port to listen on for PostgreSQL connections: default 5432
     #[serde(default = "default_port")]
     pub port: u16,
+    /// listen is the list of hosts to listen on for PostgreSQL connections, e.g. ["0.0.0.0", "::"] for dual-stack.
+    /// IPv6 addresses may be given with or without brackets. Default empty, which listens on the global host setting.
+    #[serde(default)]
+    pub listen: Vec<String>,
+    /// unix_socket_dir is the directory in which to also listen on the unix domain socket .s.PGSQL.<port>
+    /// for PostgreSQL connections, like Postgres' unix_socket_directories. TLS is not offered on the unix socket.
+    /// Default empty, which disables it.
+    #[serde(default)]
+    pub unix_socket_dir: String,
+    /// accept_proxy_protocol requires every client connection to start with a PROXY protocol (version 1 or 2) header,
+    /// as sent by HAProxy (send-proxy) or AWS NLB (proxy protocol v2), and uses the client address from the header
+    /// for auth rules, logging, and the startup guard instead of the address of the load balancer.
+    /// Only enable this if all connections go through such a proxy, otherwise clients can claim any address. Default false.
+    #[serde(default)]
+    pub accept_proxy_protocol: bool,
     /// pinned_sessions prevents release of the backend db connection until the session ends. Default false.
     /// Enabling this means that every connection to riverdb that's issued a query is backed 1-to-1 by a
     /// connection to the database, which hurts performance. It's not recommended to change this setting.
     /// This will also prevent client_partition from being called after the first query in a session.
+    /// This sets the default pool_mode to session.
     #[serde(default)]
     pub pinned_sessions: bool,
     /// NOT IMPLEMENTED defer_begin = false requires that transactions are backed 1-to-1 with a backend db transaction.
@@ -43,24 +74,131 @@ pub struct PostgresCluster {
     /// and being able to offload more queries to the replica(s).
     #[serde(default)]
     pub defer_begin: bool,
+    /// unbuffered_begin = false holds BEGIN, and the SET, SET LOCAL, or SET TRANSACTION statements following it,
+    /// answering them right away, and sends them to the database with the next statement of the transaction.
+    /// That shortens transactions by a round-trip to the client, and lets the first statement choose the node
+    /// the transaction runs on (e.g. by its shard key.) If the held statements fail, the client receives the error
+    /// in response to that next statement. Default false, set it to true to send BEGIN to the database as received.
+    #[serde(default)]
+    pub unbuffered_begin: bool,
+    /// server_reset_query is run on a backend db connection before it's returned to the pool to clean
+    /// up any session state. Default "RESET ROLE; RESET ALL". Use "DISCARD ALL" to also drop temporary
+    /// tables, prepared statements, and advisory locks, or "" to disable. If the connection is returned
+    /// inside a transaction, it's always preceded by ROLLBACK.
+    #[serde(default = "default_server_reset_query")]
+    pub server_reset_query: String,
+    /// server_reset_query_always runs server_reset_query even if the connection only executed simple
+    /// reads (SELECT, SHOW, VALUES) outside of a transaction, which can't normally change session state.
+    /// Default false. Enable this if your SELECT queries call functions like set_config.
+    #[serde(default)]
+    pub server_reset_query_always: bool,
     /// max_connections to allow before rejecting new connections. Important to introduce back-pressure. Default 10,000.
     #[serde(default = "default_max_connections")]
     pub max_connections: u32,
     /// idle_timeout_seconds is the number of seconds a client connection can be idle before it is closed. Default 0 (no timeout).
     #[serde(default)]
     pub idle_timeout_seconds: u32,
+    /// drain_timeout_seconds is the number of seconds DRAIN SERVER waits for the sessions using a server to finish
+    /// before closing their connections, if the command doesn't specify a TIMEOUT. Default 60.
+    #[serde(default = "default_drain_timeout_seconds")]
+    pub drain_timeout_seconds: u32,
+    /// max_startup_packet_bytes is the maximum size of a message a client can send before it's authenticated. Default 10,000.
+    #[serde(default = "default_max_startup_packet_bytes")]
+    pub max_startup_packet_bytes: u32,
+    /// max_message_bytes is the maximum size of a message a client can send after it's authenticated, which bounds
+    /// the buffer messages are received into. Clients that send a larger message are disconnected. 0 for no limit. Default 0.
+    #[serde(default)]
+    pub max_message_bytes: u32,
+    /// max_backlog_bytes is the maximum size of the data waiting to be written to a slow client or database connection,
+    /// e.g. the result of a large query the client isn't reading fast enough. Connections that would exceed it are
+    /// disconnected with an error, instead of buffering without bound. See also memory_limit max_backlog_bytes,
+    /// which applies while over the soft memory limit. 0 for no limit. Default 0.
+    #[serde(default)]
+    pub max_backlog_bytes: u32,
+    /// startup_timeout_seconds is the number of seconds a client has to complete startup and authentication
+    /// before it's disconnected. This protects against slowloris clients that trickle in bytes to hold
+    /// connections open. Default 15. 0 is disabled.
+    #[serde(default = "default_startup_timeout_seconds")]
+    pub startup_timeout_seconds: u32,
+    /// ban_seconds temporarily bans the IP address of a client that exceeds max_startup_packet_bytes
+    /// or startup_timeout_seconds ban_after_violations times. Default 0 (disabled).
+    #[serde(default)]
+    pub ban_seconds: u32,
+    /// ban_after_violations is the number of startup violations, each within ban_seconds of the last,
+    /// after which the client IP address is banned. Default 3.
+    #[serde(default = "default_ban_after_violations")]
+    pub ban_after_violations: u32,
+    /// reject_old_protocol_silently closes connections from clients using the obsolete protocol version 2.0
+    /// without sending an error message explaining that protocol version 3.0 is required. Default false.
+    #[serde(default)]
+    pub reject_old_protocol_silently: bool,
+    /// protocol_options is strip or passthrough, what to do with the _pq_ protocol options (e.g. _pq_.compression)
+    /// clients request in the startup message. With passthrough, an option is only in effect for a session
+    /// if the backend connection it's using also negotiated it with the database. Those connections are opened
+    /// for the client and closed afterwards, they're never reused by other clients. Default strip.
+    #[serde(default)]
+    pub protocol_options: ProtocolOptions,
+    /// request_id_application_name appends the request_id tag of a query (see query_tags) to the application_name
+    /// of the backend connection it checks out, for correlating backend activity with requests. It's shorthand for
+    /// query_tags propagate: application_name with trace_tags: [request_id]. Default false.
+    #[serde(default)]
+    pub request_id_application_name: bool,
+    /// split_multi_statement_queries splits simple Query messages with several statements separated by ; into a
+    /// Query per statement, sent one at a time, so routing, caching, query tags, and logging apply to each statement.
+    /// The client still receives one response, which stops at the first error. Outside an explicit transaction the
+    /// statements then commit separately, instead of in one implicit transaction. Default false (sent as is.)
+    #[serde(default)]
+    pub split_multi_statement_queries: bool,
+    /// collapse_in_lists normalizes a list of literals in IN (...) to a single placeholder, so queries that differ
+    /// only in the length of the list have the same normalized form, e.g. for the slow query log. Default false.
+    #[serde(default)]
+    pub collapse_in_lists: bool,
+    /// preserve_identifier_case uppercases only Postgres key words when normalizing queries, so table and column
+    /// names keep the case they were written in. Cache policy queries with $N placeholders must be written the
+    /// same way to match. Default false (identifiers are uppercased too.)
+    #[serde(default)]
+    pub preserve_identifier_case: bool,
+    /// max_normalize_bytes is how much of the SQL of a query is normalized, so huge queries like batch inserts don't
+    /// use as much memory again to normalize. Larger queries are marked truncated, aren't cached, split, or tracked
+    /// as session settings, and are logged with the normalized start of the query. 0 for no limit. Default 1 MiB.
+    #[serde(default = "default_max_normalize_bytes")]
+    pub max_normalize_bytes: u32,
+    /// client_auth is scram or password, how clients authenticate to riverdb. SCRAM-SHA-256 is only possible
+    /// for the user configured for the database, or users whose auth_query returns a password or
+    /// SCRAM-SHA-256 verifier, since riverdb must know the password. Default password.
+    #[serde(default)]
+    pub client_auth: ClientAuth,
+    /// auth_query is run against the master database to look up the password of a user other than the configured user,
+    /// so any database user can connect. $1 is replaced with the quoted user name, and the second column of the result
+    /// is the password, md5 hash, or SCRAM-SHA-256 verifier of the user (e.g. SELECT usename, passwd FROM pg_shadow
+    /// WHERE usename=$1.) Default empty (disabled.)
+    #[serde(default)]
+    pub auth_query: String,
+    /// auth_rules choose the authentication method for clients by address, database, and user, like pg_hba.conf.
+    /// The first matching rule applies. Default none (client_auth applies.)
+    #[serde(default)]
+    pub auth_rules: AuthRuleSettings,
+    /// jwt configures how the JSON Web Tokens of clients using the jwt authentication method are verified (see auth_rules.)
+    #[serde(default)]
+    pub jwt: JwtSettings,
+    /// admin_users are the users allowed to run the riverdb admin commands, e.g. SHOW CLIENTS, RELOAD, or DRAIN SERVER.
+    /// Other clients receive an insufficient_privilege error. The riverdb_*() functions are available to all clients.
+    /// Clients authenticated with the jwt method are identified by the role from their token. Default none.
+    #[serde(default)]
+    pub admin_users: Vec<String>,
     /// client_tls TLS preference between clients and River DB, defaults to disabled
     #[serde(default)]
     pub client_tls: TlsMode,
     /// backend_tls TLS preference between River DB and PostgreSQL, defaults to disabled
     #[serde(default)]
     pub backend_tls: TlsMode,
-    /// tls_client_certificate is the client authentication certificate sent from River DB to Postgres
-    /// The value can be the inlined certificate, or a file path from which to load it.
+    /// tls_client_certificate is the client authentication certificate (chain) sent from River DB to Postgres
+    /// when backend_tls is enabled, for servers that require certificate authentication (see pg_hba.conf cert.)
+    /// Requires tls_client_key. The value can be the inlined PEM certificate, or a file path from which to load it.
     #[serde(default)]
     pub tls_client_certificate: String,
     /// tls_client_key is the client private key used with a TLS connection from River DB to Postgres
-    /// The value can be the inlined certificate, or a file path from which to load it.
+    /// The value can be the inlined PEM (PKCS#8 or RSA) key, or a file path from which to load it.
     #[serde(default)]
     pub tls_client_key: String,
     /// tls_root_certificate are additional certificates to add to the trusted certificate roots for validating the Postgres server certificate
@@ -71,18 +209,130 @@ pub struct PostgresCluster {
     /// The value can be the inlined certificate, or a file path from which to load it.
     #[serde(default)]
     pub tls_server_certificate: String,
+    /// tls_client_ca_certificate is the file path of the CA certificates that issue the client certificates
+    /// used for cert authentication (see auth_rules.) Clients using TLS may then present a certificate, which must be
+    /// signed by one of these CAs, and which identifies the user by its common name or a DNS subject alternative name.
+    #[serde(default)]
+    pub tls_client_ca_certificate: String,
     /// tls_server_key is the server private key used with a TLS connection from the clients to River DB
     /// The value can be the inlined key, or a file path from which to load it.
     #[serde(default)]
     pub tls_server_key: String,
+    /// tls_min_version is the minimum TLS protocol version of client_tls and backend_tls connections: tls12 or tls13. Default tls12.
+    #[serde(default)]
+    pub tls_min_version: TlsVersion,
+    /// tls_cipher_suites restricts the cipher suites of client_tls and backend_tls connections to these, in order of preference,
+    /// by their IANA names (e.g. TLS13_AES_256_GCM_SHA384 or TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384.)
+    /// Default empty (the rustls defaults.)
+    #[serde(default)]
+    pub tls_cipher_suites: Vec<String>,
+    /// tls_session_cache_size is the number of TLS sessions remembered for resumption: by River DB as the server of
+    /// client_tls connections, and as the client of backend_tls connections. 0 disables session id resumption. Default 256.
+    #[serde(default = "default_tls_session_cache_size")]
+    pub tls_session_cache_size: u32,
+    /// tls_session_tickets enables stateless TLS session resumption with session tickets, issued to clients
+    /// of client_tls connections, and requested from the database for backend_tls connections. Default true.
+    #[serde(default = "default_tls_session_tickets")]
+    pub tls_session_tickets: bool,
+    /// tls_alpn_protocols are the ALPN protocols, in order of preference, accepted from clients for client_tls connections,
+    /// and offered to the database for backend_tls connections (e.g. postgresql.) Default empty (no ALPN.)
+    #[serde(default)]
+    pub tls_alpn_protocols: Vec<String>,
+    /// tls_reload_check_seconds is how often to check tls_server_certificate and tls_server_key for changes
+    /// (e.g. a certificate renewed by Let's Encrypt), and reload them without a restart if they changed.
+    /// New client_tls connections use the new certificate, existing connections are unaffected.
+    /// 0 disables checking, they're still reloaded by the RELOAD admin command or SIGHUP. Default 60.
+    #[serde(default = "default_tls_reload_check_seconds")]
+    pub tls_reload_check_seconds: u32,
+    /// in_pool_notifications is what to do when a pooled connection receives a NotificationResponse
+    /// (because a previous client ran LISTEN): drop, unlisten, or close. The notification is always dropped and counted.
+    /// Any other unexpected message from the database (except NoticeResponse and ParameterStatus) closes the connection.
+    /// Default unlisten.
+    #[serde(default)]
+    pub in_pool_notifications: NotificationPolicy,
+    /// dropped_messages_grace_ms is how long a backend connection holds messages from the database that arrive
+    /// while no client is attached (e.g. racing with a client re-attaching in transaction pool_mode), waiting for
+    /// a client to be attached before dropping them. Dropped messages are counted and passed to the
+    /// backend_dropped_messages plugins. 0 drops them immediately. Default 0.
+    #[serde(default)]
+    pub dropped_messages_grace_ms: u32,
+    /// query_timeout_ms is the maximum time a client query can run on the database before riverdb cancels it
+    /// with a CancelRequest, and the client receives a QUERY_CANCELED (57014) error. Each query in a
+    /// pipeline is timed from when the database starts processing it. 0 is disabled. Takes effect on RELOAD. Default 0.
+    #[serde(default)]
+    pub query_timeout_ms: u32,
+    /// replica_selection is the strategy for choosing which replica receives a read-only query:
+    /// weighted_random or least_loaded. Both take into account the replica weight. Default weighted_random.
+    #[serde(default)]
+    pub replica_selection: ReplicaSelection,
+    /// replica_warmup_seconds is the number of seconds over which the share of queries routed to a replica added
+    /// while running (e.g. by a config reload) is ramped up to its full weight, so its cold cache isn't
+    /// immediately sent its full share of traffic. 0 disables the warm-up. Default 0.
+    #[serde(default)]
+    pub replica_warmup_seconds: u32,
+    /// max_replica_lag_ms is the replication lag in milliseconds above which a replica is removed from routing,
+    /// so queries fall back to the master or a replica that's caught up. 0 disables checking the lag. Default 0.
+    #[serde(default)]
+    pub max_replica_lag_ms: u32,
+    /// replica_lag_check_ms is the number of milliseconds between checking the replication lag of the replicas,
+    /// if max_replica_lag_ms is set. Default 1000.
+    #[serde(default = "default_replica_lag_check_ms")]
+    pub replica_lag_check_ms: u32,
+    /// shard_map routes queries directly to the worker nodes of a sharded (e.g. Citus) cluster. Default disabled.
+    #[serde(default)]
+    pub shard_map: ShardMapSettings,
+    /// sharding routes queries to the server storing the shard of their shard key. Default disabled.
+    #[serde(default)]
+    pub sharding: ShardingSettings,
+    /// error_stats configures alert thresholds for errors returned by Postgres. Default no alerts.
+    #[serde(default)]
+    pub error_stats: ErrorStatsSettings,
+    /// slow_replica temporarily removes replicas that are much slower than their peers from routing. Default disabled.
+    #[serde(default)]
+    pub slow_replica: SlowReplicaSettings,
+    /// slow_query aggregates the client queries slower than a threshold by normalized query, see SHOW STATS. Default disabled.
+    #[serde(default)]
+    pub slow_query: SlowQuerySettings,
+    /// health_check marks unreachable servers down and optionally fails over to a replica. Default disabled.
+    #[serde(default)]
+    pub health_check: HealthCheckSettings,
+    /// connect_retry retries failed connection attempts to the servers with exponential backoff. Default disabled.
+    #[serde(default)]
+    pub connect_retry: ConnectRetrySettings,
+    /// latency_injection adds artificial latency to the queries of matching clients, for testing in staging. Default disabled.
+    #[serde(default)]
+    pub latency_injection: LatencyInjectionSettings,
+    /// rate_limit limits the queries per second and concurrent queries of each user and database. Default unlimited.
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    /// query_tags validates the tags on queries, e.g. requiring a team tag on all queries from a user. Default none.
+    #[serde(default)]
+    pub query_tags: QueryTagSettings,
+    /// query_types restricts the types of queries clients may run, e.g. denying Drop and Truncate to the
+    /// application's user. Rejected queries get an insufficient_privilege error. Default none.
+    #[serde(default)]
+    pub query_types: QueryTypeSettings,
     #[serde(skip)]
     pub tls_config: Option<Arc<rustls::ServerConfig>>,
+    /// tls_server_end_point is the hash of tls_server_certificate used for SCRAM-SHA-256-PLUS channel binding
+    #[serde(skip)]
+    pub tls_server_end_point: Vec<u8>,
     #[serde(skip)]
     pub backend_tls_config: Option<Arc<rustls::ClientConfig>>,
 }
 
 const fn default_port() -> u16 { 5432 }
 const fn default_max_connections() -> u32 { 10000 }
+const fn default_drain_timeout_seconds() -> u32 { 60 }
+const fn default_replica_lag_check_ms() -> u32 { 1000 }
+const fn default_max_startup_packet_bytes() -> u32 { 10000 }
+const fn default_startup_timeout_seconds() -> u32 { 15 }
+const fn default_ban_after_violations() -> u32 { 3 }
+const fn default_max_normalize_bytes() -> u32 { 1024 * 1024 }
+const fn default_tls_session_cache_size() -> u32 { 256 }
+const fn default_tls_session_tickets() -> bool { true }
+const fn default_tls_reload_check_seconds() -> u32 { 60 }
+fn default_server_reset_query() -> String { "RESET ROLE; RESET ALL".to_string() }
 
 /// Configuration for a Postgres master and its replicas.
 #[derive(Deserialize, Default)]
@@ -93,7 +343,8 @@ pub struct Postgres {
     #[serde(default = "default_host")]
     pub host: String,
     /// user to connect with.
-    /// This should usually be a superuser, if the login user is different we'll call SET ROLE to the login user.
+    /// This should usually be a superuser, if the login user is different we'll call SET ROLE to the login user,
+    /// unless the login user has a user pool (see user_pools.)
     #[serde(default)]
     pub user: String,
     /// password if using password authentication
@@ -102,6 +353,16 @@ pub struct Postgres {
     /// tls_host is the hostname expected in the server's certificate, if different from host.
     #[serde(default)]
     pub tls_host: String,
+    /// gss_keytab is the path to the Kerberos keytab with the credentials used for GSSAPI authentication,
+    /// if the server requires it. Requires riverdb built with the gssapi feature. Replicas inherit it from
+    /// the master unless set. Default empty (use the default credential cache), or the value in default.
+    #[serde(default)]
+    pub gss_keytab: String,
+    /// gss_service_name is the Kerberos service name of the server for GSSAPI authentication
+    /// (krb_srvname in postgresql.conf.) Replicas inherit it from the master unless set.
+    /// Default postgres, or the value in default.
+    #[serde(default)]
+    pub gss_service_name: String,
     /// Port to connect to, defaults to 5432
     #[serde(default = "default_port")]
     pub port: u16,
@@ -110,74 +371,182 @@ pub struct Postgres {
     pub is_master: bool,
     /// true if queries can be routed to this database. Set to false for failover only databases.
     pub can_query: bool,
+    /// weight is the relative share of read-only queries routed to this replica, compared to the other
+    /// replicas. Use this to send more traffic to larger replicas. Ignored for the master. Default 1.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// failover_priority is the order in which replicas are promoted to master when the master is down
+    /// (see health_check.promote_replica), lowest first. 0 means never promote this replica. Default 0.
+    #[serde(default)]
+    pub failover_priority: u32,
+    /// replica_read_only makes the transactions of clients routed to the replicas of this master read only, by
+    /// setting SET SESSION CHARACTERISTICS AS TRANSACTION READ ONLY when a connection is checked out of a replica pool.
+    /// Accidental writes then fail fast with READ_ONLY_SQL_TRANSACTION (25006) instead of confusing replication errors.
+    /// Replicas inherit it from the master, and a promoted replica is no longer read only. Default false.
+    #[serde(default)]
+    pub replica_read_only: bool,
     /// max_concurrent_transactions is the maximum number of db connections with open transactions permitted, defaults to 80.
     #[serde(default = "default_max_concurrent_transactions")]
     pub max_concurrent_transactions: u32,
     /// max_connections is the total maximum number of db connections for one-off queries and transactions, defaults to 100.
     #[serde(default = "default_max_db_connections")]
     pub max_connections: u32,
-    /// idle_timeout_seconds is the number of seconds a client connection can be idle in the pool before it is closed. Default 30min. 0 is disabled.
+    /// idle_timeout_seconds is the number of seconds a server connection can be idle in the pool before it is closed. Default 30min. 0 is disabled.
     #[serde(default = "default_idle_timeout_seconds")]
     pub idle_timeout_seconds: u32,
+    /// server_lifetime_seconds is the number of seconds after which a server connection is closed once it's
+    /// no longer in use, to release the memory Postgres backends accumulate over time. Default 1 hour. 0 is disabled.
+    #[serde(default = "default_server_lifetime_seconds")]
+    pub server_lifetime_seconds: u32,
+    /// min_idle is the number of idle connections kept established in the pool, so bursts of traffic
+    /// (e.g. after startup) don't wait to connect and authenticate. Default 0 (connect on demand.)
+    #[serde(default)]
+    pub min_idle: u32,
+    /// connect_rate is the maximum number of connections per second established to keep min_idle idle connections. Default 10.
+    #[serde(default = "default_connect_rate")]
+    pub connect_rate: u32,
+    /// too_many_connections_retries is the number of times to retry connecting after the database
+    /// returns a too_many_connections error, backing off pool growth in between. Default 5.
+    #[serde(default = "default_too_many_connections_retries")]
+    pub too_many_connections_retries: u32,
+    /// too_many_connections_backoff_ms is the initial delay before retrying after a too_many_connections
+    /// error, it doubles (with jitter) on each subsequent error. Default 100.
+    #[serde(default = "default_too_many_connections_backoff_ms")]
+    pub too_many_connections_backoff_ms: u32,
+    /// pool_wait_timeout_ms is how long a client waits in line for a connection when the pool is at max_connections,
+    /// before it receives a TOO_MANY_CONNECTIONS (53300) error. Clients are given connections in the order they
+    /// started waiting. It also bounds the time spent backing off and retrying failed connection attempts
+    /// (see connect_retry and too_many_connections_backoff_ms.) 0 fails immediately. Default 5000.
+    #[serde(default = "default_pool_wait_timeout_ms")]
+    pub pool_wait_timeout_ms: u32,
+    /// max_pool_waiters is the maximum number of clients that can wait for a connection at once,
+    /// once reached further clients fail immediately. Default 1000.
+    #[serde(default = "default_max_pool_waiters")]
+    pub max_pool_waiters: u32,
+    /// pool_mode is session, transaction, or statement: when a backend connection used by a client is returned
+    /// to the pool. In transaction and statement modes, SET and RESET outside a transaction are remembered and
+    /// set again on each connection the client uses, but SET in a transaction only lasts until the connection
+    /// is returned, use SET LOCAL in a transaction instead. LISTEN in those modes subscribes the client through
+    /// a dedicated connection to the master. Clients that create session state (temporary tables, PREPARE or a named
+    /// prepared statement, cursors WITH HOLD, or session advisory locks) keep their connection until the session ends.
+    /// Default transaction, or session if pinned_sessions is set.
+    #[serde(default)]
+    pub pool_mode: PoolMode,
+    /// retry_reads transparently retries a client query on another connection if the connection to the server fails
+    /// (e.g. it's reset, or the server is shut down with admin_shutdown 57P01) before any of the result was sent
+    /// to the client. Only simple query protocol SELECT, SHOW, and VALUES queries outside a transaction are retried,
+    /// on a connection checked out for the query. Beware a SELECT calling functions with side effects runs twice.
+    /// Replicas inherit it from the master unless set. Default false, or the value in default.
+    #[serde(default)]
+    pub retry_reads: bool,
+    /// max_isolation is the highest transaction isolation level permitted on this server: read_uncommitted,
+    /// read_committed, repeatable_read, or serializable. Transactions requesting a higher level (with BEGIN or
+    /// SET TRANSACTION) are rejected or downgraded, see max_isolation_action. E.g. set repeatable_read for replicas,
+    /// hot standbys can't run serializable transactions. Replicas inherit it from the master unless set.
+    /// Default none (no limit), or the value in default.
+    #[serde(default)]
+    pub max_isolation: IsolationLevel,
+    /// max_isolation_action is reject to fail transactions requesting a higher isolation level than max_isolation,
+    /// or downgrade to run them at max_isolation instead, with a warning to the client. Replicas inherit it from
+    /// the master unless set. Default reject, or the value in default.
+    #[serde(default)]
+    pub max_isolation_action: IsolationAction,
+    /// user_pools are separate pools of connections established as other users (and optionally to other databases
+    /// on this server), used for clients connecting as those users instead of switching roles with SET ROLE.
+    /// Clients connecting as other users use the main pool. Replicas use the user_pools of the master unless
+    /// they specify their own. Default none.
+    #[serde(default)]
+    pub user_pools: Vec<UserPool>,
     /// replicas are other Postgres servers that host read-only replicas of this database
     pub replicas: Vec<Postgres>,
     #[serde(skip)]
     pub address: Option<SocketAddr>,
     #[serde(skip)]
     pub cluster: Option<&'static PostgresCluster>,
+    /// user_pool_configs are the configurations of the user_pools, a copy of this configuration
+    /// with the user, password, database, and max_connections of the user pool.
+    #[serde(skip)]
+    pub user_pool_configs: Vec<Postgres>,
+}
+
+/// The connection pool for clients connecting to a Postgres server as user to database.
+#[derive(Deserialize, Default, Clone)]
+pub struct UserPool {
+    /// user the clients connect as, the connections of the pool are established as this user. Required.
+    pub user: String,
+    /// password for user if using password authentication. Default empty.
+    #[serde(default)]
+    pub password: String,
+    /// database the clients connect to. Default the database of the server.
+    #[serde(default)]
+    pub database: String,
+    /// max_connections is the maximum number of connections in this pool. These don't count towards
+    /// the max_connections of the server's main pool. Default 10.
+    #[serde(default = "default_user_pool_max_connections")]
+    pub max_connections: u32,
 }
 
 fn default_host() -> String { "localhost".to_string() }
 const fn default_max_concurrent_transactions() -> u32 { 80 }
 const fn default_max_db_connections() -> u32 { 100 }
 const fn default_idle_timeout_seconds() -> u32 { 30 * 60 }
+const fn default_server_lifetime_seconds() -> u32 { 60 * 60 }
+const fn default_weight() -> u32 { 1 }
+const fn default_connect_rate() -> u32 { 10 }
+const fn default_too_many_connections_retries() -> u32 { 5 }
+const fn default_too_many_connections_backoff_ms() -> u32 { 100 }
+const fn default_user_pool_max_connections() -> u32 { 10 }
+const fn default_pool_wait_timeout_ms() -> u32 { 5000 }
+const fn default_max_pool_waiters() -> u32 { 1000 }
 
 impl PostgresCluster {
     /// Validate settings and configure defaults as necessary. Called on startup.
     /// Do not call this method after the server starts.
     pub fn load(&mut self) -> Result<()> {
+        if self.max_startup_packet_bytes == 0 {
+            self.max_startup_packet_bytes = default_max_startup_packet_bytes();
+        }
+        if self.ban_after_violations == 0 {
+            self.ban_after_violations = default_ban_after_violations();
+        }
+        self.shard_map.load(self.servers.len())?;
+        self.sharding.load(self.servers.len())?;
+        if self.sharding.enabled && self.shard_map.enabled {
+            return Err(Error::new("sharding and shard_map can't both be enabled"));
+        }
+        self.error_stats.load()?;
+        self.slow_replica.load()?;
+        self.slow_query.load()?;
+        self.health_check.load()?;
+        self.connect_retry.load()?;
+        self.latency_injection.load()?;
+        if self.request_id_application_name && self.query_tags.propagate == TracePropagation::None {
+            self.query_tags.propagate = TracePropagation::ApplicationName;
+            self.query_tags.trace_tags = vec!["request_id".to_string()];
+        }
+        self.query_tags.load()?;
+        self.query_types.load()?;
+        self.auth_rules.load()?;
+        if self.auth_rules.rules.iter().any(|rule| rule.method == AuthMethod::Cert) && self.tls_client_ca_certificate.is_empty() {
+            return Err(Error::new("auth_rules with the cert method require tls_client_ca_certificate"));
+        }
+        self.jwt.load()?;
+        if self.auth_rules.rules.iter().any(|rule| rule.method == AuthMethod::Jwt) && !self.jwt.is_configured() {
+            return Err(Error::new("auth_rules with the jwt method require jwt issuer and jwks"));
+        }
+
         match self.client_tls {
             TlsMode::Invalid => {
                 self.client_tls = TlsMode::Disabled;
             },
             TlsMode::Disabled => (),
+            TlsMode::VerifyCa | TlsMode::VerifyFull => {
+                return Err(Error::new("client_tls does not support verify-ca or verify-full, use tls_client_ca_certificate to verify client certificates"));
+            },
             _ => {
-                let b = rustls::server_config_builder_with_safe_defaults();
-                let b = if let TlsMode::DangerouslyUnverifiedCertificates = self.client_tls {
-                    b.with_client_cert_verifier(DangerousCertificateNonverifier::new())
-                } else {
-                    b.with_no_client_auth()
-                }; // TODO add client certificate verification
-
-                let server_certs = Path::new(self.tls_server_certificate.as_str());
-                let server_key = Path::new(self.tls_server_key.as_str());
-
-                if !server_certs.exists() {
-                    return Err(Error::new("tls_server_certificate does not exist"));
-                }
-
-                if !server_key.exists() {
-                    return Err(Error::new("tls_server_key does not exist"));
-                }
-
-                let mut r = BufReader::new(File::open(server_key)?);
-                let certs: Vec<Certificate> = rustls_pemfile::certs(&mut r)?
-                    .into_iter()
-                    .map(|cert| Certificate(cert))
-                    .collect();
-
-                if certs.is_empty() {
-                    return Err(Error::new("tls_server_certificate file does not contain any certificates"));
-                }
-
-                let mut r = BufReader::new(File::open(server_key)?);
-                let mut keys = rustls_pemfile::rsa_private_keys(&mut r)?;
-                if keys.is_empty() {
-                    return Err(Error::new("tls_server_key file does not contain any keys"));
-                }
-                let key = PrivateKey(keys.pop().unwrap());
-
-                self.tls_config = Some(Arc::new(b.with_single_cert(certs, key)?));
+                let (tls_config, end_point) = self.load_server_tls_config()?;
+                self.tls_config = Some(Arc::new(tls_config));
+                self.tls_server_end_point = end_point;
             }
         }
 
@@ -187,14 +556,23 @@ impl PostgresCluster {
             },
             TlsMode::Disabled => (),
             _ => {
-                let b = rustls::client_config_builder_with_safe_defaults();
-                let backend_config = if let TlsMode::DangerouslyUnverifiedCertificates = self.backend_tls {
-                    b.with_custom_certificate_verifier(DangerousCertificateNonverifier::new())
-                        .with_no_client_auth()
+                let client_identity = self.load_client_identity()?;
+                let (suites, versions) = self.tls_suites_and_versions()?;
+                let b = rustls::config_builder()
+                    .with_cipher_suites(&suites)
+                    .with_safe_default_kx_groups()
+                    .with_protocol_versions(versions)
+                    .for_client()
+                    .map_err(|e| Error::new(format!("invalid TLS settings: {}", e)))?;
+                let mut backend_config = if let TlsMode::DangerouslyUnverifiedCertificates = self.backend_tls {
+                    let b = b.with_custom_certificate_verifier(DangerousCertificateNonverifier::new());
+                    match client_identity {
+                        Some((certs, key)) => b.with_single_cert(certs, key)?,
+                        None => b.with_no_client_auth(),
+                    }
                 } else {
-                    let mut root_store = rustls::RootCertStore::empty();
-                    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0);
-
+                    // Verify the certificate chain with these roots, and its hostname, unless backend_tls is verify-ca
+                    let mut root_certs = Vec::new();
                     if !self.tls_root_certificate.is_empty() {
                         let root_cert_path = Path::new(self.tls_root_certificate.as_str());
                         if !root_cert_path.exists() {
@@ -202,18 +580,42 @@ impl PostgresCluster {
                         }
 
                         let mut r = BufReader::new(File::open(root_cert_path)?);
-                        let certs = rustls_pemfile::certs(&mut r)?;
-                        root_store.add_parsable_certificates(certs.as_slice());
+                        root_certs = rustls_pemfile::certs(&mut r)?;
                     }
 
-                    let b = b.with_root_certificates(root_store, &[]);
-                    b.with_no_client_auth() // TODO add client certificate if configured
+                    if self.backend_tls.verifies_hostname() {
+                        let mut root_store = rustls::RootCertStore::empty();
+                        root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0);
+                        root_store.add_parsable_certificates(root_certs.as_slice());
+                        let b = b.with_root_certificates(root_store, &[]);
+                        match client_identity {
+                            Some((certs, key)) => b.with_single_cert(certs, key)?,
+                            None => b.with_no_client_auth(),
+                        }
+                    } else {
+                        let b = b.with_custom_certificate_verifier(CertificateChainVerifier::new(root_certs));
+                        match client_identity {
+                            Some((certs, key)) => b.with_single_cert(certs, key)?,
+                            None => b.with_no_client_auth(),
+                        }
+                    }
                 };
 
+                backend_config.session_storage = if self.tls_session_cache_size == 0 {
+                    Arc::new(rustls::client::NoClientSessionStorage{})
+                } else {
+                    rustls::client::ClientSessionMemoryCache::new(self.tls_session_cache_size as usize)
+                };
+                backend_config.enable_tickets = self.tls_session_tickets;
+                backend_config.alpn_protocols = self.tls_alpn_protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
                 self.backend_tls_config = Some(Arc::new(backend_config));
             }
         }
 
+        if self.default.pool_mode == PoolMode::Invalid {
+            self.default.pool_mode = if self.pinned_sessions { PoolMode::Session } else { PoolMode::Transaction };
+        }
+
         let self_ptr = self as *mut PostgresCluster as *const PostgresCluster;
         for server in &mut self.servers {
             if let Err(e) = server.load(self_ptr, &self.default, true) {
@@ -223,6 +625,148 @@ impl PostgresCluster {
 
         Ok(())
     }
+
+    /// Build the client_tls ServerConfig from tls_server_certificate and tls_server_key, and the other TLS settings.
+    /// Returns it with the tls_server_end_point of the certificate. Called again to reload the certificate
+    /// and key when they change (see tls_reload_check_seconds.)
+    pub fn load_server_tls_config(&self) -> Result<(rustls::ServerConfig, Vec<u8>)> {
+        let (suites, versions) = self.tls_suites_and_versions()?;
+        let b = rustls::config_builder()
+            .with_cipher_suites(&suites)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(versions)
+            .for_server()
+            .map_err(|e| Error::new(format!("invalid TLS settings: {}", e)))?;
+        let b = if let TlsMode::DangerouslyUnverifiedCertificates = self.client_tls {
+            b.with_client_cert_verifier(DangerousCertificateNonverifier::new())
+        } else if !self.tls_client_ca_certificate.is_empty() {
+            // Client certificates are optional, but if presented must be issued by one of these CAs
+            let ca_certs = Path::new(self.tls_client_ca_certificate.as_str());
+            if !ca_certs.exists() {
+                return Err(Error::new("tls_client_ca_certificate does not exist"));
+            }
+            let mut r = BufReader::new(File::open(ca_certs)?);
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut r)? {
+                roots.add(&Certificate(cert))
+                    .map_err(|e| Error::new(format!("invalid certificate in tls_client_ca_certificate: {:?}", e)))?;
+            }
+            if roots.is_empty() {
+                return Err(Error::new("tls_client_ca_certificate file does not contain any certificates"));
+            }
+            b.with_client_cert_verifier(rustls::AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+        } else {
+            b.with_no_client_auth()
+        };
+
+        let server_certs = Path::new(self.tls_server_certificate.as_str());
+        let server_key = Path::new(self.tls_server_key.as_str());
+
+        if !server_certs.exists() {
+            return Err(Error::new("tls_server_certificate does not exist"));
+        }
+
+        if !server_key.exists() {
+            return Err(Error::new("tls_server_key does not exist"));
+        }
+
+        let mut r = BufReader::new(File::open(server_certs)?);
+        let certs: Vec<Certificate> = rustls_pemfile::certs(&mut r)?
+            .into_iter()
+            .map(|cert| Certificate(cert))
+            .collect();
+
+        if certs.is_empty() {
+            return Err(Error::new("tls_server_certificate file does not contain any certificates"));
+        }
+
+        // Empty if channel binding isn't possible with the certificate, then SCRAM-SHA-256-PLUS isn't offered
+        let end_point = sasl::tls_server_end_point(&certs[0].0).unwrap_or_default();
+
+        let key = read_private_key("tls_server_key", &std::fs::read(server_key)?)?;
+
+        let mut tls_config = b.with_single_cert(certs, key)?;
+        tls_config.session_storage = if self.tls_session_cache_size == 0 {
+            Arc::new(rustls::server::NoServerSessionStorage{})
+        } else {
+            rustls::server::ServerSessionMemoryCache::new(self.tls_session_cache_size as usize)
+        };
+        if self.tls_session_tickets {
+            tls_config.ticketer = rustls::Ticketer::new()?;
+        }
+        tls_config.alpn_protocols = self.tls_alpn_protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+        Ok((tls_config, end_point))
+    }
+
+    /// Returns the cipher suites named by tls_cipher_suites (or the defaults), and the protocol versions allowed by tls_min_version.
+    fn tls_suites_and_versions(&self) -> Result<(Vec<rustls::SupportedCipherSuite>, &'static [&'static rustls::SupportedProtocolVersion])> {
+        static TLS13_ONLY: &[&rustls::SupportedProtocolVersion] = &[&rustls::version::TLS13];
+        let versions = match self.tls_min_version {
+            TlsVersion::Tls12 => rustls::ALL_VERSIONS,
+            TlsVersion::Tls13 => TLS13_ONLY,
+        };
+        if self.tls_cipher_suites.is_empty() {
+            return Ok((rustls::DEFAULT_CIPHER_SUITES.to_vec(), versions));
+        }
+
+        let mut suites = Vec::with_capacity(self.tls_cipher_suites.len());
+        for name in &self.tls_cipher_suites {
+            let suite = rustls::ALL_CIPHER_SUITES.iter()
+                .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+                .ok_or_else(|| Error::new(format!("unknown cipher suite {} in tls_cipher_suites", name)))?;
+            suites.push(*suite);
+        }
+        Ok((suites, versions))
+    }
+
+    /// Load the tls_client_certificate chain and tls_client_key, if configured.
+    fn load_client_identity(&self) -> Result<Option<(Vec<Certificate>, PrivateKey)>> {
+        if self.tls_client_certificate.is_empty() && self.tls_client_key.is_empty() {
+            return Ok(None);
+        }
+        if self.tls_client_certificate.is_empty() {
+            return Err(Error::new("tls_client_key requires tls_client_certificate"));
+        }
+        if self.tls_client_key.is_empty() {
+            return Err(Error::new("tls_client_certificate requires tls_client_key"));
+        }
+
+        let pem = read_pem("tls_client_certificate", &self.tls_client_certificate)?;
+        let certs: Vec<Certificate> = rustls_pemfile::certs(&mut pem.as_slice())?
+            .into_iter()
+            .map(|cert| Certificate(cert))
+            .collect();
+        if certs.is_empty() {
+            return Err(Error::new("tls_client_certificate does not contain any certificates"));
+        }
+
+        let key = read_private_key("tls_client_key", &read_pem("tls_client_key", &self.tls_client_key)?)?;
+        Ok(Some((certs, key)))
+    }
+}
+
+/// Returns the inlined PEM value of the named setting, or the contents of the file it names.
+fn read_pem(name: &str, value: &str) -> Result<Vec<u8>> {
+    if value.contains("-----BEGIN") {
+        return Ok(value.as_bytes().to_vec());
+    }
+    let path = Path::new(value);
+    if !path.exists() {
+        return Err(Error::new(format!("{} does not exist", name)));
+    }
+    Ok(std::fs::read(path)?)
+}
+
+/// Returns the last PKCS#8 or, failing that, RSA private key in the PEM of the named setting.
+fn read_private_key(name: &str, pem: &[u8]) -> Result<PrivateKey> {
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut &pem[..])?;
+    if keys.is_empty() {
+        keys = rustls_pemfile::rsa_private_keys(&mut &pem[..])?;
+    }
+    match keys.pop() {
+        Some(key) => Ok(PrivateKey(key)),
+        None => Err(Error::new(format!("{} does not contain any keys", name))),
+    }
 }
 
 impl Postgres {
@@ -239,6 +783,23 @@ impl Postgres {
         if self.tls_host.is_empty() {
             self.tls_host = self.host.clone();
         }
+        if self.gss_keytab.is_empty() {
+            self.gss_keytab = defaults.gss_keytab.clone();
+        }
+        if !self.gss_keytab.is_empty() {
+            if cfg!(not(feature = "gssapi")) {
+                return Err(Error::new("gss_keytab requires riverdb to be built with the gssapi feature"));
+            }
+            if !Path::new(&self.gss_keytab).exists() {
+                return Err(Error::new("gss_keytab does not exist"));
+            }
+        }
+        if self.gss_service_name.is_empty() {
+            self.gss_service_name = defaults.gss_service_name.clone();
+            if self.gss_service_name.is_empty() {
+                self.gss_service_name = gssapi::DEFAULT_SERVICE_NAME.to_string();
+            }
+        }
         if self.user.is_empty() {
             self.user = defaults.user.clone();
         }
@@ -258,19 +819,133 @@ impl Postgres {
             }
         }
 
+        if self.pool_mode == PoolMode::Invalid {
+            self.pool_mode = defaults.pool_mode;
+        }
+        self.retry_reads |= defaults.retry_reads;
+        if self.max_isolation == IsolationLevel::None {
+            self.max_isolation = defaults.max_isolation;
+        }
+        if self.max_isolation_action == IsolationAction::Invalid {
+            self.max_isolation_action = defaults.max_isolation_action;
+            if self.max_isolation_action == IsolationAction::Invalid {
+                self.max_isolation_action = IsolationAction::Reject;
+            }
+        }
+
+        if self.weight == 0 {
+            self.weight = defaults.weight;
+            if self.weight == 0 {
+                self.weight = default_weight();
+            }
+        }
+        if self.min_idle == 0 {
+            self.min_idle = defaults.min_idle;
+        }
+        if self.connect_rate == 0 {
+            self.connect_rate = defaults.connect_rate;
+            if self.connect_rate == 0 {
+                self.connect_rate = default_connect_rate();
+            }
+        }
+        if self.min_idle > self.max_connections {
+            return Err(Error::new(format!("min_idle {} cannot be greater than max_connections {}", self.min_idle, self.max_connections)));
+        }
+        if self.too_many_connections_backoff_ms == 0 {
+            self.too_many_connections_backoff_ms = defaults.too_many_connections_backoff_ms;
+            if self.too_many_connections_backoff_ms == 0 {
+                self.too_many_connections_backoff_ms = default_too_many_connections_backoff_ms();
+            }
+        }
+
         self.address = Some(to_address(&self.host, self.port)?);
 
         // Safety: we're using a raw pointer here to get around a limitation in rusts borrow checker
         // the caller holds a &mut PostgresCluster, so having a &PostgresCluster here doesn't work
         // (even though we don't use it until after the caller returns.)
         self.cluster = Some(unsafe { &*cluster });
+
+        for (i, user_pool) in self.user_pools.iter_mut().enumerate() {
+            if user_pool.user.is_empty() {
+                return Err(Error::new(format!("user_pools entry missing user at index {}", i)));
+            }
+            if user_pool.database.is_empty() {
+                user_pool.database = self.database.clone();
+            }
+            if user_pool.max_connections == 0 {
+                user_pool.max_connections = default_user_pool_max_connections();
+            }
+        }
+        for (i, user_pool) in self.user_pools.iter().enumerate() {
+            if self.user_pools[..i].iter().any(|p| p.user == user_pool.user && p.database == user_pool.database) {
+                return Err(Error::new(format!("user_pools has more than one pool for {}@{}", user_pool.user, user_pool.database)));
+            }
+        }
+        self.user_pool_configs = self.user_pools.iter().map(|user_pool| self.for_user_pool(user_pool)).collect();
+
         for replica in &mut self.replicas {
+            if replica.user_pools.is_empty() {
+                replica.user_pools = self.user_pools.clone();
+            }
+            replica.replica_read_only = self.replica_read_only;
+            replica.retry_reads |= self.retry_reads;
+            if replica.max_isolation == IsolationLevel::None {
+                replica.max_isolation = self.max_isolation;
+            }
+            if replica.max_isolation_action == IsolationAction::Invalid {
+                replica.max_isolation_action = self.max_isolation_action;
+            }
+            if replica.gss_keytab.is_empty() {
+                replica.gss_keytab = self.gss_keytab.clone();
+            }
+            if replica.gss_service_name.is_empty() {
+                replica.gss_service_name = self.gss_service_name.clone();
+            }
             if let Err(e) = replica.load(cluster, defaults, false) {
                 return Err(e);
             }
         }
         Ok(())
     }
+
+    /// Returns a copy of this configuration for the given user pool, without replicas or user pools.
+    fn for_user_pool(&self, user_pool: &UserPool) -> Postgres {
+        Postgres {
+            database: user_pool.database.clone(),
+            host: self.host.clone(),
+            user: user_pool.user.clone(),
+            password: user_pool.password.clone(),
+            tls_host: self.tls_host.clone(),
+            gss_keytab: self.gss_keytab.clone(),
+            gss_service_name: self.gss_service_name.clone(),
+            port: self.port,
+            is_master: self.is_master,
+            can_query: self.can_query,
+            weight: self.weight,
+            failover_priority: self.failover_priority,
+            replica_read_only: self.replica_read_only,
+            max_concurrent_transactions: user_pool.max_connections,
+            max_connections: user_pool.max_connections,
+            idle_timeout_seconds: self.idle_timeout_seconds,
+            server_lifetime_seconds: self.server_lifetime_seconds,
+            // User pools are only for the clients connecting as that user, so they connect on demand
+            min_idle: 0,
+            connect_rate: self.connect_rate,
+            too_many_connections_retries: self.too_many_connections_retries,
+            too_many_connections_backoff_ms: self.too_many_connections_backoff_ms,
+            pool_wait_timeout_ms: self.pool_wait_timeout_ms,
+            max_pool_waiters: self.max_pool_waiters,
+            pool_mode: self.pool_mode,
+            retry_reads: self.retry_reads,
+            max_isolation: self.max_isolation,
+            max_isolation_action: self.max_isolation_action,
+            user_pools: vec![],
+            replicas: vec![],
+            address: self.address,
+            cluster: self.cluster,
+            user_pool_configs: vec![],
+        }
+    }
 }
 
 fn to_address(host: &str, port: u16) -> Result<SocketAddr> {
@@ -279,4 +954,17 @@ fn to_address(host: &str, port: u16) -> Result<SocketAddr> {
         .map_err(Error::from)?
         .next()
         .ok_or_else(|| Error::new(format!("DNS lookup failed for {}", host)))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_private_key() {
+        let pkcs8 = read_private_key("tls_server_key", include_bytes!("../../tests/testdata/test-ca/rsa/end.key")).unwrap();
+        let rsa = read_private_key("tls_server_key", include_bytes!("../../tests/testdata/test-ca/rsa/end.rsa")).unwrap();
+        assert_ne!(pkcs8.0, rsa.0);
+        assert!(read_private_key("tls_server_key", include_bytes!("../../tests/testdata/test-ca/rsa/end.cert")).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use serde::{Deserialize};
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::config::enums::{TagViolationAction, TracePropagation};
+
+
+/// Configuration for validating the tags (key=value pairs in a leading /* */ comment) on queries.
+#[derive(Deserialize, Default)]
+pub struct QueryTagSettings {
+    /// required lists the tags queries from matching clients must have, e.g. team or request_id,
+    /// for attributing database load. The first matching rule applies. Default none.
+    #[serde(default)]
+    pub required: Vec<RequiredTags>,
+    /// propagate is none, application_name, or set_local: how the trace tag of a query is passed to the database,
+    /// so DBAs can correlate pg_stat_activity rows with application traces. application_name appends it to the
+    /// application_name of the backend connection checked out for the query (or transaction.) set_local sets
+    /// trace_setting to its value before the query runs, which costs a round trip unless it's the first
+    /// statement of a transaction with a held BEGIN (see unbuffered_begin.) Default none.
+    #[serde(default)]
+    pub propagate: TracePropagation,
+    /// trace_tags are the names of the tags propagated, the first one present on a query is used.
+    /// Default [trace_id, request_id].
+    #[serde(default = "default_trace_tags")]
+    pub trace_tags: Vec<String>,
+    /// trace_setting is the custom setting (with a prefix, like all custom settings) set by propagate: set_local,
+    /// read it with current_setting('riverdb.trace_id', true). Default riverdb.trace_id.
+    #[serde(default = "default_trace_setting")]
+    pub trace_setting: String,
+}
+
+fn default_trace_tags() -> Vec<String> {
+    vec!["trace_id".to_string(), "request_id".to_string()]
+}
+
+fn default_trace_setting() -> String {
+    "riverdb.trace_id".to_string()
+}
+
+/// Tags required on every query from clients connected to a database as a user.
+#[derive(Deserialize, Default, Clone)]
+pub struct RequiredTags {
+    /// database the client connected to, empty matches any database. Default empty.
+    #[serde(default)]
+    pub database: String,
+    /// user the client connected as, empty matches any user. Default empty.
+    #[serde(default)]
+    pub user: String,
+    /// tags are the names of the required tags, compared case insensitively.
+    pub tags: Vec<String>,
+    /// action is log or reject, what to do with a query missing a required tag. Default log.
+    #[serde(default)]
+    pub action: TagViolationAction,
+}
+
+impl RequiredTags {
+    /// Returns true if this rule applies to clients connected to database as user.
+    pub fn matches(&self, database: &str, user: &str) -> bool {
+        (self.database.is_empty() || self.database == database) && (self.user.is_empty() || self.user == user)
+    }
+
+    /// Returns the required tags for which has_tag returns false.
+    pub fn missing<F: Fn(&str) -> bool>(&self, has_tag: F) -> Vec<&str> {
+        self.tags.iter().map(|tag| tag.as_str()).filter(|tag| !has_tag(tag)).collect()
+    }
+}
+
+impl QueryTagSettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self) -> Result<()> {
+        for rule in &self.required {
+            if rule.tags.is_empty() || rule.tags.iter().any(|tag| tag.is_empty()) {
+                return Err(Error::new("query_tags required rules must list one or more tag names"));
+            }
+        }
+        if self.trace_tags.is_empty() {
+            self.trace_tags = default_trace_tags();
+        }
+        if self.trace_setting.is_empty() {
+            self.trace_setting = default_trace_setting();
+        }
+        if self.trace_tags.iter().any(|tag| tag.is_empty()) {
+            return Err(Error::new("query_tags trace_tags can't be empty strings"));
+        }
+        let valid_setting = self.trace_setting.split('.').count() == 2 && self.trace_setting.split('.').all(|part| {
+            !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        });
+        if self.propagate == TracePropagation::SetLocal && !valid_setting {
+            return Err(Error::new(format!("query_tags trace_setting {} must be a custom setting like riverdb.trace_id", self.trace_setting)));
+        }
+        Ok(())
+    }
+
+    /// Returns the first of the trace_tags present on a query, and its value, if propagate is enabled.
+    pub fn trace_tag<'a, F: Fn(&str) -> Option<&'a str>>(&self, tag: F) -> Option<(&str, &'a str)> {
+        if self.propagate == TracePropagation::None {
+            return None;
+        }
+        self.trace_tags.iter().find_map(|name| tag(name).map(|value| (name.as_str(), value)))
+    }
+
+    /// Returns the first required tags rule that applies to clients connected to database as user, if any.
+    pub fn required_for(&self, database: &str, user: &str) -> Option<&RequiredTags> {
+        self.required.iter().find(|rule| rule.matches(database, user))
+    }
+}
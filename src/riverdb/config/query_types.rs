@@ -0,0 +1,82 @@
+use std::str::FromStr;
+
+use serde::{Deserialize};
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::pg::sql::QueryType;
+
+
+/// Configuration for restricting the types of queries (e.g. Drop, Truncate) clients may run.
+#[derive(Deserialize, Default)]
+pub struct QueryTypeSettings {
+    /// rules list the query types permitted or denied for clients connected to a database as a user.
+    /// The first matching rule applies, if none match all query types are permitted. Default none.
+    #[serde(default)]
+    pub rules: Vec<QueryTypeRule>,
+}
+
+/// The query types permitted for clients connected to a database as a user.
+#[derive(Deserialize, Default, Clone)]
+pub struct QueryTypeRule {
+    /// database the client connected to, empty matches any database. Default empty.
+    #[serde(default)]
+    pub database: String,
+    /// user the client connected as, empty matches any user. Default empty.
+    #[serde(default)]
+    pub user: String,
+    /// allow lists the only query types permitted, e.g. Select, Insert, Update, Delete (see QueryType),
+    /// compared case insensitively. Default empty (all types not denied are permitted.)
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// deny lists the query types that aren't permitted, e.g. Drop, Truncate, Copy, Alter. Default empty.
+    #[serde(default)]
+    pub deny: Vec<String>,
+    #[serde(skip)]
+    allowed: Vec<QueryType>,
+    #[serde(skip)]
+    denied: Vec<QueryType>,
+}
+
+impl QueryTypeRule {
+    /// Returns true if this rule applies to clients connected to database as user.
+    pub fn matches(&self, database: &str, user: &str) -> bool {
+        (self.database.is_empty() || self.database == database) && (self.user.is_empty() || self.user == user)
+    }
+
+    /// Returns true if this rule permits queries of type ty.
+    pub fn permits(&self, ty: QueryType) -> bool {
+        (self.allowed.is_empty() || self.allowed.contains(&ty)) && !self.denied.contains(&ty)
+    }
+
+    fn load(&mut self) -> Result<()> {
+        if self.allow.is_empty() && self.deny.is_empty() {
+            return Err(Error::new("query_types rules must allow or deny one or more query types"));
+        }
+        self.allowed = parse_query_types(&self.allow)?;
+        self.denied = parse_query_types(&self.deny)?;
+        Ok(())
+    }
+}
+
+impl QueryTypeSettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self) -> Result<()> {
+        for rule in &mut self.rules {
+            rule.load()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the first rule that applies to clients connected to database as user, if any.
+    pub fn rule_for(&self, database: &str, user: &str) -> Option<&QueryTypeRule> {
+        self.rules.iter().find(|rule| rule.matches(database, user))
+    }
+}
+
+fn parse_query_types(names: &[String]) -> Result<Vec<QueryType>> {
+    names.iter()
+        .map(|name| QueryType::from_str(name.trim())
+            .map_err(|_| Error::new(format!("query_types rule has unknown query type {}", name))))
+        .collect()
+}
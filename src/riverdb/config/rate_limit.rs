@@ -0,0 +1,30 @@
+use serde::{Deserialize};
+
+
+/// Limits on the rate and concurrency of client queries, to protect the database from a single
+/// runaway user or database (tenant.) Queries over a limit fail with CONFIGURATION_LIMIT_EXCEEDED (53400)
+/// and a hint saying when to retry. Each limit is per user or per database across all the clients
+/// of the cluster, and 0 disables it. Admin commands and cached results don't count towards the limits.
+#[derive(Deserialize, Default)]
+pub struct RateLimitSettings {
+    /// user_queries_per_second is the sustained rate of queries allowed for each user, with bursts of up to one second's worth. Default 0.
+    #[serde(default)]
+    pub user_queries_per_second: u32,
+    /// user_concurrent_queries is the number of queries each user can have running at once. Default 0.
+    #[serde(default)]
+    pub user_concurrent_queries: u32,
+    /// database_queries_per_second is the sustained rate of queries allowed for each database, with bursts of up to one second's worth. Default 0.
+    #[serde(default)]
+    pub database_queries_per_second: u32,
+    /// database_concurrent_queries is the number of queries each database can have running at once. Default 0.
+    #[serde(default)]
+    pub database_concurrent_queries: u32,
+}
+
+impl RateLimitSettings {
+    /// Returns true if any of the limits are set.
+    pub fn is_enabled(&self) -> bool {
+        self.user_queries_per_second != 0 || self.user_concurrent_queries != 0 ||
+            self.database_queries_per_second != 0 || self.database_concurrent_queries != 0
+    }
+}
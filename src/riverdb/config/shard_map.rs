@@ -0,0 +1,52 @@
+use serde::{Deserialize};
+
+use crate::riverdb::{Error, Result};
+
+
+/// The default query run against the coordinator to build the shard map for a Citus cluster.
+/// It returns one row per (table, worker node) where the table has shard placements.
+pub const CITUS_SHARD_MAP_QUERY: &str = "SELECT s.logicalrelid::regclass::text, n.nodename, n.nodeport \
+    FROM pg_dist_shard s \
+    JOIN pg_dist_placement p ON p.shardid = s.shardid \
+    JOIN pg_dist_node n ON n.groupid = p.groupid \
+    WHERE n.noderole = 'primary' \
+    GROUP BY 1, 2, 3";
+
+/// Configuration for routing queries directly to the worker nodes of a sharded (e.g. Citus) cluster.
+/// The first server in the cluster is the coordinator, other servers are the worker nodes.
+/// Queries that only touch tables stored on a single worker are sent to that worker,
+/// all other queries are sent to the coordinator.
+#[derive(Deserialize, Default)]
+pub struct ShardMapSettings {
+    /// enabled turns on shard map routing in client_partition. Default false.
+    #[serde(default)]
+    pub enabled: bool,
+    /// refresh_seconds is the number of seconds between refreshing the shard map from the coordinator. Default 60.
+    #[serde(default = "default_refresh_seconds")]
+    pub refresh_seconds: u32,
+    /// query is run on the coordinator to build the shard map. It must return rows of
+    /// (table, host, port). Worker host names are resolved with DNS and matched to the
+    /// configured servers by address. Defaults to a query of the Citus metadata tables.
+    #[serde(default = "default_query")]
+    pub query: String,
+}
+
+const fn default_refresh_seconds() -> u32 { 60 }
+fn default_query() -> String { CITUS_SHARD_MAP_QUERY.to_string() }
+
+impl ShardMapSettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self, num_servers: usize) -> Result<()> {
+        if self.refresh_seconds == 0 {
+            self.refresh_seconds = default_refresh_seconds();
+        }
+        if self.query.is_empty() {
+            self.query = default_query();
+        }
+        if self.enabled && num_servers < 2 {
+            return Err(Error::new("shard_map requires a coordinator and at least one worker server"));
+        }
+        Ok(())
+    }
+}
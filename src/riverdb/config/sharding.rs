@@ -0,0 +1,78 @@
+use serde::{Deserialize};
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::config::ShardingStrategy;
+
+
+/// Configuration for routing queries to the server (replication group) storing the shard of their shard key.
+/// The key is taken from a query tag (e.g. /* shard_key=42 */) or else from the literals compared to key_column,
+/// and mapped to a shard by the hash or range strategy. Queries with keys on more than one shard are rejected.
+#[derive(Deserialize, Default)]
+pub struct ShardingSettings {
+    /// enabled turns on shard key routing in client_partition. Default false.
+    #[serde(default)]
+    pub enabled: bool,
+    /// strategy maps a shard key to a shard, hash or range. Default hash.
+    #[serde(default)]
+    pub strategy: ShardingStrategy,
+    /// key_tag is the name of the query tag with the shard key. Default shard_key.
+    #[serde(default = "default_key_tag")]
+    pub key_tag: String,
+    /// key_column is the name of the column with the shard key, used for queries without key_tag.
+    /// The key is the literal (or bound parameter) compared with = or IN to the column, or inserted into it.
+    /// Default empty (only key_tag is used.)
+    #[serde(default)]
+    pub key_column: String,
+    /// require_key rejects queries without a shard key, otherwise they're routed by database as usual.
+    /// Executing an existing prepared statement has no SQL to take the key from, it's never rejected. Default false.
+    #[serde(default)]
+    pub require_key: bool,
+    /// shards are the shards, in order. Required if enabled.
+    #[serde(default)]
+    pub shards: Vec<ShardSettings>,
+}
+
+/// A shard, see ShardingSettings.
+#[derive(Deserialize, Default)]
+pub struct ShardSettings {
+    /// server is the index in servers of the server (replication group) storing the shard.
+    pub server: usize,
+    /// min is the smallest key stored on the shard for the range strategy, which stores the keys from min
+    /// up to the min of the next shard. Required for the range strategy, except on the first shard, which
+    /// stores all keys less than the min of the next shard.
+    #[serde(default)]
+    pub min: Option<i64>,
+}
+
+fn default_key_tag() -> String { "shard_key".to_string() }
+
+impl ShardingSettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self, num_servers: usize) -> Result<()> {
+        if self.key_tag.is_empty() {
+            self.key_tag = default_key_tag();
+        }
+        if !self.enabled {
+            return Ok(());
+        }
+        if self.shards.is_empty() {
+            return Err(Error::new("sharding requires at least one shard"));
+        }
+        if let Some(shard) = self.shards.iter().find(|shard| shard.server >= num_servers) {
+            return Err(Error::new(format!("sharding shard server {} does not exist, there are {} servers", shard.server, num_servers)));
+        }
+        if self.strategy == ShardingStrategy::Range {
+            let mut prev = i64::MIN;
+            for (i, shard) in self.shards.iter_mut().enumerate() {
+                match shard.min {
+                    None if i == 0 => shard.min = Some(i64::MIN),
+                    Some(min) if i == 0 || min > prev => prev = min,
+                    Some(min) => return Err(Error::new(format!("sharding shard min {} must be greater than the min of the previous shard", min))),
+                    None => return Err(Error::new("sharding with the range strategy requires a min on every shard after the first")),
+                }
+            }
+        }
+        Ok(())
+    }
+}
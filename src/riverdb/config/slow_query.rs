@@ -0,0 +1,38 @@
+use serde::{Deserialize};
+
+use crate::riverdb::Result;
+
+
+/// Configuration for the slow query log, which aggregates client queries that take longer than
+/// a threshold by their normalized query text (see QueryNormalizer.)
+#[derive(Deserialize, Default)]
+pub struct SlowQuerySettings {
+    /// enabled turns on the slow query log. Default false.
+    #[serde(default)]
+    pub enabled: bool,
+    /// threshold_ms is the latency at or above which a query is counted as slow. Default 1000.
+    #[serde(default = "default_threshold_ms")]
+    pub threshold_ms: u32,
+    /// max_queries is the maximum number of distinct normalized queries tracked, once reached
+    /// slow queries with new fingerprints are only logged. Default 1000.
+    #[serde(default = "default_max_queries")]
+    pub max_queries: u32,
+    /// log logs a warning for each slow query, with its normalized text, latency, and rows. Default true.
+    #[serde(default = "default_log")]
+    pub log: bool,
+}
+
+const fn default_threshold_ms() -> u32 { 1000 }
+const fn default_max_queries() -> u32 { 1000 }
+const fn default_log() -> bool { true }
+
+impl SlowQuerySettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self) -> Result<()> {
+        if self.max_queries == 0 {
+            self.max_queries = default_max_queries();
+        }
+        Ok(())
+    }
+}
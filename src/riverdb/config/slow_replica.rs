@@ -0,0 +1,55 @@
+use serde::{Deserialize};
+
+use crate::riverdb::{Error, Result};
+
+
+/// Configuration for detecting replicas that are much slower than their peers (e.g. because of
+/// a bad disk or a long GC pause) and temporarily removing them from query routing.
+#[derive(Deserialize, Default)]
+pub struct SlowReplicaSettings {
+    /// enabled turns on slow replica detection. Default false.
+    #[serde(default)]
+    pub enabled: bool,
+    /// check_seconds is the number of seconds between comparing replica latencies. Default 10.
+    #[serde(default = "default_check_seconds")]
+    pub check_seconds: u32,
+    /// slowdown_factor is how many times the p95 query latency of a replica must exceed the median p95
+    /// latency of the other replicas in the group for it to be quarantined. Default 3.
+    #[serde(default = "default_slowdown_factor")]
+    pub slowdown_factor: u32,
+    /// min_p95_ms is the p95 query latency below which a replica is never considered slow. Default 10.
+    #[serde(default = "default_min_p95_ms")]
+    pub min_p95_ms: u32,
+    /// min_samples is the number of recent queries required to compute a replica's p95 latency. Default 50.
+    #[serde(default = "default_min_samples")]
+    pub min_samples: u32,
+    /// quarantine_seconds is the number of seconds a slow replica is removed from routing. Default 60.
+    #[serde(default = "default_quarantine_seconds")]
+    pub quarantine_seconds: u32,
+}
+
+const fn default_check_seconds() -> u32 { 10 }
+const fn default_slowdown_factor() -> u32 { 3 }
+const fn default_min_p95_ms() -> u32 { 10 }
+const fn default_min_samples() -> u32 { 50 }
+const fn default_quarantine_seconds() -> u32 { 60 }
+
+impl SlowReplicaSettings {
+    /// Validate settings and configure defaults as necessary. Called on startup.
+    /// Do not call this method after the server starts.
+    pub fn load(&mut self) -> Result<()> {
+        if self.check_seconds == 0 {
+            self.check_seconds = default_check_seconds();
+        }
+        if self.min_samples == 0 {
+            self.min_samples = default_min_samples();
+        }
+        if self.quarantine_seconds == 0 {
+            self.quarantine_seconds = default_quarantine_seconds();
+        }
+        if self.enabled && self.slowdown_factor < 2 {
+            return Err(Error::new("slow_replica slowdown_factor must be at least 2"));
+        }
+        Ok(())
+    }
+}
@@ -1 +1,7 @@
-mod service;
\ No newline at end of file
+mod query;
+mod service;
+mod status;
+
+pub use service::HttpService;
+pub use query::{QueryRequest, QueryResponse, QueryError, run_query};
+pub use status::{Status, ClusterStatus, ClientStatus, ServerStatus, is_serving};
@@ -0,0 +1,306 @@
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, error, warn};
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::audit::{AuditLog, AuditQuery, audited_type};
+use crate::riverdb::common::ErrorKind;
+use crate::riverdb::config::{self, conf, AuthMethod, TagViolationAction};
+use crate::riverdb::pg::{BackendConn, ConnectionPool, PostgresCluster, PostgresService, TransactionType};
+use crate::riverdb::pg::protocol::{MessageBuilder, Tag};
+use crate::riverdb::pg::sql::{QueryMessage, QueryType};
+
+
+/// The JSON body of a POST /query request.
+#[derive(Deserialize)]
+pub struct QueryRequest {
+    pub database: String,
+    pub sql: String,
+    /// the values of the $1, $2, ... parameters in sql. Strings, numbers, and booleans are sent as text,
+    /// arrays and objects as JSON text. Default empty.
+    #[serde(default)]
+    pub params: Vec<Value>,
+}
+
+/// The JSON response of a successful POST /query request.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct QueryResponse {
+    /// the names of the columns in the result, empty if the statement doesn't return rows
+    pub columns: Vec<String>,
+    /// the rows of the result, booleans, integers, floats, and json(b) are converted to their JSON types,
+    /// everything else is a string
+    pub rows: Vec<Vec<Value>>,
+    /// the number of rows returned or affected
+    pub affected: i32,
+}
+
+/// Why a POST /query request failed, mapped to an HTTP status by the HttpService.
+#[derive(Debug)]
+pub enum QueryError {
+    /// the request body is invalid
+    BadRequest(String),
+    /// the credentials are missing or were rejected by the database
+    Unauthorized,
+    /// the auth_rules, query_types, or query_tags settings don't permit the request
+    Forbidden(String),
+    /// the request would exceed a rate_limit, with the hint for when to retry
+    TooManyRequests{message: String, hint: String},
+    /// no cluster has the requested database
+    NotFound(String),
+    /// the database returned an error, with its SQLSTATE code
+    Postgres{code: String, message: String},
+    /// riverdb couldn't run the query
+    Internal(Error),
+}
+
+impl From<Error> for QueryError {
+    fn from(e: Error) -> Self {
+        match e.kind() {
+            ErrorKind::PostgresError{source} => QueryError::Postgres{code: source.code().to_string(), message: source.message().to_string()},
+            _ => QueryError::Internal(e),
+        }
+    }
+}
+
+/// Run the statement in request as user, from the client ip, on the master of the first cluster of services with the requested database.
+/// user and password are checked by authenticating with the database (the result is cached, see PostgresCluster::authenticate.)
+/// Statements that change the transaction or session state are rejected, each request runs in its own implicit transaction.
+/// Like queries from Postgres clients, the request is subject to the auth_rules, query_tags, query_types,
+/// rate_limit, and audit settings of the cluster.
+pub async fn run_query(services: &[&'static PostgresService], user: &str, password: &str, ip: Option<IpAddr>, request: &QueryRequest) -> std::result::Result<QueryResponse, QueryError> {
+    let query = check_statement(&request.sql)?;
+    let database = request.database.as_str();
+    let (cluster, pool, role) = services.iter()
+        .find_map(|service| {
+            let cluster = service.cluster();
+            cluster.get_by_database(database).and_then(|node| node.master())
+                .and_then(|pool| pool.pool_for(user, database))
+                .map(|(pool, role)| (cluster, pool, role))
+        })
+        .ok_or_else(|| QueryError::NotFound(request.database.clone()))?;
+
+    check_auth_rule(cluster.config, ip, database, user)?;
+    if let Err(e) = cluster.authenticate(user, password, pool).await {
+        debug!(%e, user, database, "http query authentication failed");
+        return Err(QueryError::Unauthorized);
+    }
+    check_query(cluster.config, database, user, &query)?;
+
+    let audit_id = match AuditLog::singleton().zip(audited_type(&query)) {
+        Some((log, ty)) => {
+            match log.record_query(&AuditQuery{client_id: 0, ip, user, database, ty, sql: &request.sql}).await {
+                Ok(id) => Some((log, id)),
+                Err(e) => {
+                    error!(%e, "could not record http query in the audit log");
+                    return Err(QueryError::Internal(Error::new("query was not run because it could not be recorded in the audit log")));
+                },
+            }
+        },
+        None => None,
+    };
+    let result = admit_and_query(cluster, pool, database, user, role, request).await;
+    if let Some((log, id)) = audit_id {
+        let outcome = match &result {
+            Ok(_) => "success",
+            Err(QueryError::TooManyRequests{..}) => "rejected",
+            Err(_) => "error",
+        };
+        if let Err(e) = log.record_outcome(id, outcome) {
+            error!(%e, id, outcome, "could not record query outcome in the audit log");
+        }
+    }
+    result
+}
+
+/// Run the statement in request for user on pool with role (see ConnectionPool::pool_for), if it's admitted by the rate_limit settings of cluster.
+async fn admit_and_query(cluster: &'static PostgresCluster, pool: &'static ConnectionPool, database: &str, user: &str, role: &str, request: &QueryRequest) -> std::result::Result<QueryResponse, QueryError> {
+    let settings = &cluster.config.rate_limit;
+    if settings.is_enabled() {
+        if let Err(e) = cluster.rate_limiter.admit(settings, user, database, 1) {
+            warn!(%e, user, database, "http query rejected by rate_limit");
+            return Err(QueryError::TooManyRequests{message: e.to_string(), hint: e.hint()});
+        }
+    }
+
+    let params: Vec<Option<String>> = request.params.iter().map(param_text).collect();
+    let params: Vec<Option<&str>> = params.iter().map(|param| param.as_deref()).collect();
+
+    let result = match pool.get(&conf().app_name, role, TransactionType::None).await {
+        Ok(conn) => {
+            let result = match conn.load() {
+                Some(backend) => query_rows(backend, &request.sql, &params).await,
+                None => Err(Error::new(format!("could not connect {:?}", pool))),
+            };
+            BackendConn::return_to_pool(conn).await;
+            result
+        },
+        Err(e) => Err(e),
+    };
+    if settings.is_enabled() {
+        cluster.rate_limiter.complete(user, database, 1);
+    }
+    Ok(result?)
+}
+
+/// Run sql with params on backend and collect the result.
+async fn query_rows(backend: &BackendConn, sql: &str, params: &[Option<&str>]) -> Result<QueryResponse> {
+    let mut rows = backend.query_with_params(sql, params).await?;
+    let mut response = QueryResponse{columns: Vec::new(), rows: Vec::new(), affected: 0};
+    let mut type_oids = Vec::new();
+    while rows.next().await? {
+        if type_oids.is_empty() {
+            type_oids = (0..rows.fields().len()).filter_map(|i| rows.fields().get(i)).map(|field| field.type_oid()).collect();
+        }
+        let mut row = Vec::with_capacity(type_oids.len());
+        for (i, type_oid) in type_oids.iter().enumerate() {
+            row.push(json_value(*type_oid, rows.get_str(i)?, rows.is_null(i)?));
+        }
+        response.rows.push(row);
+    }
+    // The RowDescription is still there after the last row, or if there were no rows
+    for i in 0..rows.fields().len() {
+        let field = rows.fields().get(i).ok_or_else(|| Error::new("missing field description"))?;
+        response.columns.push(field.name()?.to_string());
+    }
+    response.affected = rows.affected();
+    Ok(response)
+}
+
+/// Returns an error if the first auth_rules rule matching ip, database, and user doesn't allow password authentication.
+/// The password is always checked by authenticate, trust rules don't waive it.
+fn check_auth_rule(config: &config::PostgresCluster, ip: Option<IpAddr>, database: &str, user: &str) -> std::result::Result<(), QueryError> {
+    match config.auth_rules.rule_for(ip, database, user).map(|rule| rule.method) {
+        None | Some(AuthMethod::Trust | AuthMethod::Password | AuthMethod::Md5 | AuthMethod::Scram) => Ok(()),
+        Some(method) => {
+            debug!(?method, ?ip, user, database, "http query rejected by auth_rules");
+            Err(QueryError::Forbidden(format!("auth_rules requires {:?} authentication for user \"{}\", database \"{}\"", method, user, database)))
+        },
+    }
+}
+
+/// Returns an error if query is missing tags required by a query_tags rule with action reject,
+/// or has a statement not permitted by the query_types rule for database and user.
+fn check_query(config: &config::PostgresCluster, database: &str, user: &str, query: &QueryMessage) -> std::result::Result<(), QueryError> {
+    if let Some(rule) = config.query_tags.required_for(database, user) {
+        let missing = rule.missing(|tag| query.tag(tag).is_some());
+        if !missing.is_empty() {
+            warn!(?missing, database, user, ?query, "http query is missing required tags");
+            if rule.action == TagViolationAction::Reject {
+                return Err(QueryError::Forbidden("query is missing tags required by the query_tags setting".to_string()));
+            }
+        }
+    }
+    if let Some(rule) = config.query_types.rule_for(database, user) {
+        if query.is_truncated() {
+            return Err(QueryError::Forbidden("query is larger than max_normalize_bytes, so it can't be checked against the query_types setting".to_string()));
+        }
+        if let Some(q) = query.statements().find(|q| !rule.permits(q.query_type())) {
+            warn!(ty=%q.query_type(), database, user, ?query, "http query type is not permitted");
+            return Err(QueryError::Forbidden(format!("{} queries are not permitted by the query_types setting", q.query_type())));
+        }
+    }
+    Ok(())
+}
+
+/// Reject statements that would leave the pooled connection in a transaction, or otherwise change
+/// the session state that later users of the connection would see. Returns the parsed query.
+fn check_statement(sql: &str) -> std::result::Result<QueryMessage, QueryError> {
+    let mut mb = MessageBuilder::new(Tag::QUERY);
+    mb.write_str(sql);
+    let query = QueryMessage::new(mb.finish()).map_err(|e| QueryError::BadRequest(e.to_string()))?;
+    if query.is_multi_query() {
+        return Err(QueryError::BadRequest("only a single statement is allowed".to_string()));
+    }
+    match query.query().query_type() {
+        QueryType::Begin | QueryType::Commit | QueryType::CommitPrepared | QueryType::Rollback |
+        QueryType::RollbackPrepared | QueryType::RollbackSavepoint | QueryType::Savepoint |
+        QueryType::ReleaseSavepoint | QueryType::PrepareTransaction | QueryType::SetSession |
+        QueryType::SetRole | QueryType::SetTransaction | QueryType::SetConstraints | QueryType::Reset |
+        QueryType::Listen | QueryType::Unlisten | QueryType::Prepare | QueryType::Cursor | QueryType::Copy => {
+            Err(QueryError::BadRequest(format!("{:?} statements are not allowed", query.query().query_type())))
+        },
+        _ => Ok(query),
+    }
+}
+
+/// Returns the text format of a JSON parameter value, or None for null.
+fn param_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Array(_) | Value::Object(_) => Some(value.to_string()),
+    }
+}
+
+/// Converts a text format result value of the Postgres type type_oid to JSON.
+fn json_value(type_oid: i32, value: &str, is_null: bool) -> Value {
+    if is_null {
+        return Value::Null;
+    }
+    let converted = match type_oid {
+        16 => Some(Value::Bool(value == "t")), // bool
+        20 | 21 | 23 | 26 => value.parse::<i64>().ok().map(Value::from), // int8, int2, int4, oid
+        700 | 701 => value.parse::<f64>().ok().and_then(|f| serde_json::Number::from_f64(f)).map(Value::Number), // float4, float8
+        114 | 3802 => serde_json::from_str(value).ok(), // json, jsonb
+        _ => None,
+    };
+    converted.unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_statement() {
+        assert!(check_statement("SELECT * FROM films WHERE id = $1").is_ok());
+        assert!(check_statement("insert into films (title) values ($1)").is_ok());
+        assert!(matches!(check_statement("BEGIN"), Err(QueryError::BadRequest(_))));
+        assert!(matches!(check_statement("SET search_path TO evil"), Err(QueryError::BadRequest(_))));
+        assert!(matches!(check_statement("SELECT 1; SELECT 2"), Err(QueryError::BadRequest(_))));
+    }
+
+    #[test]
+    fn test_check_policies() {
+        let mut config = config::PostgresCluster::default();
+        config.auth_rules = serde_yaml::from_str("rules: [{user: ops, method: cert}, {method: password}]").unwrap();
+        config.auth_rules.load().unwrap();
+        config.query_types = serde_yaml::from_str("rules: [{user: app, deny: [Drop, Truncate]}]").unwrap();
+        config.query_types.load().unwrap();
+        config.query_tags = serde_yaml::from_str("required: [{user: tagged, tags: [request_id], action: reject}]").unwrap();
+        config.query_tags.load().unwrap();
+
+        assert!(check_auth_rule(&config, None, "db", "app").is_ok());
+        assert!(matches!(check_auth_rule(&config, None, "db", "ops"), Err(QueryError::Forbidden(_))));
+
+        assert!(check_query(&config, "db", "app", &check_statement("SELECT 1").unwrap()).is_ok());
+        assert!(matches!(check_query(&config, "db", "app", &check_statement("DROP TABLE films").unwrap()), Err(QueryError::Forbidden(_))));
+        assert!(check_query(&config, "db", "other", &check_statement("DROP TABLE films").unwrap()).is_ok());
+        assert!(matches!(check_query(&config, "db", "tagged", &check_statement("SELECT 1").unwrap()), Err(QueryError::Forbidden(_))));
+        assert!(check_query(&config, "db", "tagged", &check_statement("/* request_id=1 */ SELECT 1").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_param_text() {
+        assert_eq!(param_text(&Value::Null), None);
+        assert_eq!(param_text(&serde_json::json!("it's")).as_deref(), Some("it's"));
+        assert_eq!(param_text(&serde_json::json!(42)).as_deref(), Some("42"));
+        assert_eq!(param_text(&serde_json::json!(true)).as_deref(), Some("true"));
+        assert_eq!(param_text(&serde_json::json!({"a": [1]})).as_deref(), Some(r#"{"a":[1]}"#));
+    }
+
+    #[test]
+    fn test_json_value() {
+        assert_eq!(json_value(16, "t", false), Value::Bool(true));
+        assert_eq!(json_value(20, "-9000000000", false), serde_json::json!(-9000000000i64));
+        assert_eq!(json_value(701, "1.5", false), serde_json::json!(1.5));
+        assert_eq!(json_value(701, "NaN", false), Value::String("NaN".to_string()));
+        assert_eq!(json_value(3802, r#"{"a": 1}"#, false), serde_json::json!({"a": 1}));
+        assert_eq!(json_value(1700, "12345678901234567890.5", false), Value::String("12345678901234567890.5".to_string()));
+        assert_eq!(json_value(25, "", true), Value::Null);
+    }
+}
@@ -1,2 +1,331 @@
-// TODO!
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
 
+use rustls::ServerConfig;
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+use tracing::{info, debug};
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::config::{conf, TlsMode};
+use crate::riverdb::server::{Listener, ListenerOptions, Transport};
+use crate::riverdb::pg::PostgresService;
+use crate::riverdb::http::status::{Status, is_serving};
+use crate::riverdb::http::query::{QueryRequest, QueryError, run_query};
+
+
+/// The maximum size of an HTTP request head, larger requests are rejected.
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+/// The maximum size of an HTTP request body, larger requests get a 413 response.
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+/// The time allowed to read the request and write the response before the connection is closed.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// HttpService serves the operational endpoints for monitoring riverdb and the Postgres services:
+///   GET /healthz - 200 if the process is up
+///   GET /readyz - 200 if the services are listening and at least one server is reachable, else 503
+///   GET /metrics - metrics in the Prometheus text format
+///   GET /status - a JSON dump of the pools, clients, and servers of each cluster
+///   POST /query - if http_query is enabled, run {"database": ..., "sql": ..., "params": [...]} as the user
+///                 given with HTTP Basic authentication and return the result as JSON, see run_query.
+///                 Requires TLS (http_tls), the password would be sent in clear text otherwise.
+/// Each connection handles a single request (Connection: close.)
+pub struct HttpService {
+    listener: Listener,
+    tls_config: Option<Arc<ServerConfig>>,
+    services: Vec<&'static PostgresService>,
+    /// the number of connections being handled, limited to max_http_connections
+    active: AtomicUsize,
+}
+
+/// The parts of an HTTP request the HttpService uses.
+#[derive(Debug, Eq, PartialEq)]
+struct Request {
+    method: String,
+    path: String,
+    /// the value of the Authorization header, if any
+    authorization: Option<String>,
+    /// the value of the Content-Length header, the body is only read if it's at most MAX_BODY_BYTES
+    content_length: usize,
+    body: Vec<u8>,
+}
+
+/// An HTTP response: the status line, content type, extra headers, and body.
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    /// additional header lines, each terminated by \r\n
+    headers: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: &str) -> Self {
+        Self{status, content_type: "text/plain", headers: "", body: format!("{}\n", body)}
+    }
+
+    fn json(status: &'static str, body: String) -> Self {
+        Self{status, content_type: "application/json", headers: "", body}
+    }
+}
+
+impl HttpService {
+    /// Create an HttpService listening on address, reporting on services.
+    /// If tls_config is given, connections must use HTTPS.
+    pub fn new(address: String, options: ListenerOptions, tls_config: Option<Arc<ServerConfig>>, services: Vec<&'static PostgresService>) -> Self {
+        Self{
+            listener: Listener::new(address, options).expect("could not create http listener"),
+            tls_config,
+            services,
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the socket address the service is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub async fn run(&'static self) {
+        info!(address = %self.listener.address.as_str(), tls = self.tls_config.is_some(), "starting HttpService");
+        while let Some(sock) = self.listener.accept().await {
+            if let Ok(addr) = sock.peer_addr() {
+                if self.services.iter().any(|service| service.cluster().startup_guard.is_banned(addr.ip())) {
+                    debug!(%addr, "closing http connection from banned ip address");
+                    continue;
+                }
+            }
+            if self.active.fetch_add(1, Relaxed) >= conf().max_http_connections as usize {
+                self.active.fetch_sub(1, Relaxed);
+                debug!("closing http connection, at max_http_connections");
+                continue;
+            }
+            tokio::spawn(async move {
+                match timeout(REQUEST_TIMEOUT, self.handle(sock)).await {
+                    Ok(Err(e)) => debug!(%e, "http request failed"),
+                    Err(_) => debug!("http request timed out"),
+                    Ok(Ok(())) => (),
+                }
+                self.active.fetch_sub(1, Relaxed);
+            });
+        }
+    }
+
+    async fn handle(&self, sock: TcpStream) -> Result<()> {
+        let ip = sock.peer_addr().ok().map(|addr| addr.ip());
+        let transport = Transport::new(sock);
+        if let Some(tls_config) = &self.tls_config {
+            transport.upgrade_server(tls_config.clone(), TlsMode::Required).await?;
+        }
+        let response = match read_request(&transport).await? {
+            Some(request) => self.respond(&request, ip).await,
+            None => Response::text("400 Bad Request", "bad request"),
+        };
+        let head = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+            response.status, response.content_type, response.body.len(), response.headers);
+        write_all(&transport, head.as_bytes()).await?;
+        write_all(&transport, response.body.as_bytes()).await?;
+        transport.close();
+        Ok(())
+    }
+
+    async fn respond(&self, request: &Request, ip: Option<IpAddr>) -> Response {
+        // Ignore any query string
+        let path = request.path.split('?').next().unwrap_or("");
+        if request.method == "POST" && path == "/query" && conf().http_query {
+            return self.query(request, ip).await;
+        }
+        if request.method != "GET" {
+            return Response::text("405 Method Not Allowed", "method not allowed");
+        }
+        match path {
+            "/healthz" => Response::text("200 OK", "ok"),
+            "/readyz" => {
+                if is_serving(&self.services) {
+                    Response::text("200 OK", "ready")
+                } else {
+                    Response::text("503 Service Unavailable", "not ready")
+                }
+            },
+            "/metrics" => Response{
+                status: "200 OK",
+                content_type: "text/plain; version=0.0.4",
+                headers: "",
+                body: Status::new(&self.services).to_prometheus(),
+            },
+            "/status" => Response::json("200 OK", Status::new(&self.services).to_json()),
+            _ => Response::text("404 Not Found", "not found"),
+        }
+    }
+
+    /// Handle POST /query from ip, errors are returned as JSON {"error": message, "code": sqlstate}.
+    async fn query(&self, request: &Request, ip: Option<IpAddr>) -> Response {
+        if self.tls_config.is_none() {
+            // Don't accept Basic authentication, the password would be sent in clear text
+            return error_response("403 Forbidden", "POST /query requires https, enable http_tls", None);
+        }
+        if request.body.len() < request.content_length {
+            return Response::text("413 Payload Too Large", "request body is too large");
+        }
+        let (user, password) = match request.authorization.as_deref().and_then(parse_basic_auth) {
+            Some(credentials) => credentials,
+            None => return unauthorized(),
+        };
+        let query: QueryRequest = match serde_json::from_slice(&request.body) {
+            Ok(query) => query,
+            Err(e) => return error_response("400 Bad Request", &e.to_string(), None),
+        };
+        match run_query(&self.services, &user, &password, ip, &query).await {
+            Ok(result) => Response::json("200 OK", serde_json::to_string(&result).expect("serializing query result failed")),
+            Err(QueryError::Unauthorized) => unauthorized(),
+            Err(QueryError::Forbidden(message)) => error_response("403 Forbidden", &message, None),
+            Err(QueryError::TooManyRequests{message, hint}) => {
+                Response::json("429 Too Many Requests", serde_json::json!({"error": message, "hint": hint}).to_string())
+            },
+            Err(QueryError::BadRequest(message)) => error_response("400 Bad Request", &message, None),
+            Err(QueryError::NotFound(database)) => error_response("404 Not Found", &format!("database {} not found", database), None),
+            Err(QueryError::Postgres{code, message}) => error_response("400 Bad Request", &message, Some(&code)),
+            Err(QueryError::Internal(e)) => {
+                debug!(%e, "http query failed");
+                error_response("500 Internal Server Error", &e.to_string(), None)
+            },
+        }
+    }
+}
+
+fn unauthorized() -> Response {
+    Response{
+        status: "401 Unauthorized",
+        content_type: "application/json",
+        headers: "WWW-Authenticate: Basic realm=\"riverdb\"\r\n",
+        body: serde_json::json!({"error": "authentication required"}).to_string(),
+    }
+}
+
+fn error_response(status: &'static str, message: &str, code: Option<&str>) -> Response {
+    Response::json(status, serde_json::json!({"error": message, "code": code}).to_string())
+}
+
+/// Returns the user and password from the value of a Basic Authorization header.
+fn parse_basic_auth(authorization: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = authorization.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64::decode(credentials.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Reads the request from transport. Returns None if the request head is malformed.
+/// The body is read only if it's at most MAX_BODY_BYTES.
+async fn read_request(transport: &Transport) -> Result<Option<Request>> {
+    let mut buf = vec![0; MAX_REQUEST_BYTES];
+    let mut len = 0;
+    let end = loop {
+        if let Some(end) = buf[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if len == buf.len() {
+            return Err(Error::new("http request is too large"));
+        }
+        len += read(transport, &mut buf[len..]).await?;
+    };
+    let mut request = match parse_request_head(&buf[..end]) {
+        Some(request) => request,
+        None => return Ok(None),
+    };
+    if request.content_length <= MAX_BODY_BYTES {
+        let mut body = buf[end+4..len].to_vec();
+        body.truncate(request.content_length);
+        let mut read_len = body.len();
+        body.resize(request.content_length, 0);
+        while read_len < body.len() {
+            read_len += read(transport, &mut body[read_len..]).await?;
+        }
+        request.body = body;
+    }
+    Ok(Some(request))
+}
+
+/// Waits for transport to be readable and reads into buf.
+async fn read(transport: &Transport, buf: &mut [u8]) -> Result<usize> {
+    if !transport.wants_read() {
+        transport.ready(Interest::READABLE).await?;
+    }
+    transport.try_read(buf)
+}
+
+/// Parses the method and path from the request line, and the headers we use from the request head.
+fn parse_request_head(head: &[u8]) -> Option<Request> {
+    let head = std::str::from_utf8(head).ok()?;
+    let mut lines = head.lines();
+    let mut parts = lines.next()?.split(' ');
+    let method = parts.next()?;
+    let path = parts.next()?;
+    if !parts.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    let mut request = Request{
+        method: method.to_string(),
+        path: path.to_string(),
+        authorization: None,
+        content_length: 0,
+        body: Vec::new(),
+    };
+    for line in lines {
+        let (name, value) = line.split_once(':')?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("authorization") {
+            request.authorization = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("content-length") {
+            request.content_length = value.parse().ok()?;
+        }
+    }
+    Some(request)
+}
+
+/// Writes all of buf to transport, waiting for it to become writable as necessary.
+async fn write_all(transport: &Transport, mut buf: &[u8]) -> Result<()> {
+    while !buf.is_empty() {
+        let n = transport.try_write(buf)?;
+        buf = &buf[n..];
+        if n == 0 {
+            transport.ready(Interest::WRITABLE).await?;
+        }
+    }
+    while !transport.try_flush()? {
+        transport.ready(Interest::WRITABLE).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request_head() {
+        let request = parse_request_head(b"GET /metrics HTTP/1.1\r\nHost: localhost").unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("GET", "/metrics"));
+        assert_eq!(parse_request_head(b"GET /readyz?verbose=1 HTTP/1.0").unwrap().path, "/readyz?verbose=1");
+        assert_eq!(parse_request_head(b"GET /metrics"), None);
+        assert_eq!(parse_request_head(b"GET /metrics SPDY/3"), None);
+
+        let request = parse_request_head(b"POST /query HTTP/1.1\r\nauthorization: Basic Zm9vOmI6cg==\r\nContent-Length:  42").unwrap();
+        assert_eq!(request.authorization.as_deref(), Some("Basic Zm9vOmI6cg=="));
+        assert_eq!(request.content_length, 42);
+        assert_eq!(parse_request_head(b"POST /query HTTP/1.1\r\nContent-Length: -1"), None);
+    }
+
+    #[test]
+    fn test_parse_basic_auth() {
+        assert_eq!(parse_basic_auth("Basic Zm9vOmI6cg=="), Some(("foo".to_string(), "b:r".to_string())));
+        assert_eq!(parse_basic_auth("Bearer Zm9vOmI6cg=="), None);
+        assert_eq!(parse_basic_auth("Basic Zm9v"), None);
+        assert_eq!(parse_basic_auth("Basic !!!"), None);
+    }
+}
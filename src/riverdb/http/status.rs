@@ -0,0 +1,298 @@
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use crate::riverdb::common::panic_count;
+use crate::riverdb::server::Connection as ServerConnection;
+use crate::riverdb::pg::{Connection, ConnectionPool, PostgresService, ClientState, WaitEvent, SlowQuerySummary, is_ready, wait_event_counts};
+
+
+/// A snapshot of the state of the server, returned as JSON by the /status endpoint
+/// and in the Prometheus text format by /metrics.
+#[derive(Serialize)]
+pub struct Status {
+    pub version: &'static str,
+    /// true if the services are listening and at least one server is reachable, see is_serving
+    pub ready: bool,
+    /// the number of panics caught in connection tasks, see catch_panic
+    pub panics: u64,
+    pub clusters: Vec<ClusterStatus>,
+}
+
+/// The state of a PostgresCluster and the clients connected to it in the Status.
+#[derive(Serialize)]
+pub struct ClusterStatus {
+    pub port: u16,
+    /// the number of client sessions waiting on each WaitEvent
+    pub wait_events: Vec<(WaitEvent, usize)>,
+    pub clients: Vec<ClientStatus>,
+    pub servers: Vec<ServerStatus>,
+    /// the slow queries by normalized query, see SHOW STATS
+    pub slow_queries: Vec<SlowQuerySummary>,
+}
+
+/// A client session in the Status.
+#[derive(Serialize)]
+pub struct ClientStatus {
+    pub id: u32,
+    pub user: String,
+    pub database: String,
+    pub client_addr: Option<String>,
+    pub state: String,
+    pub wait_event: WaitEvent,
+    pub idle_seconds: u32,
+    /// the bytes waiting to be sent to the client, see max_backlog_bytes
+    pub backlog_bytes: usize,
+}
+
+/// The connection pool of a master or replica in the Status.
+#[derive(Serialize)]
+pub struct ServerStatus {
+    pub database: String,
+    pub address: String,
+    pub is_master: bool,
+    pub healthy: bool,
+    pub draining: bool,
+    pub connections: usize,
+    pub pooled: usize,
+    pub in_use: usize,
+    pub active_transactions: i32,
+    pub queue_depth: usize,
+    pub waits: u64,
+    pub wait_timeouts: u64,
+    pub too_many_connections_errors: u64,
+    pub dropped_messages: u64,
+}
+
+impl Status {
+    /// Take a snapshot of the state of services and the clusters they serve.
+    pub fn new(services: &[&'static PostgresService]) -> Self {
+        let clusters = services.iter().map(|service| {
+            let mut clients = Vec::new();
+            service.connections().for_each(|client| {
+                let (user, database) = if let ClientState::StateInitial | ClientState::SSLHandshake = client.state() {
+                    (String::new(), String::new()) // connection_params isn't set yet
+                } else {
+                    let params = client.connection_params();
+                    (params.get("user").unwrap_or("").to_string(), params.get("database").unwrap_or("").to_string())
+                };
+                clients.push(ClientStatus{
+                    id: client.id(),
+                    user,
+                    database,
+                    client_addr: client.remote_ip().map(|ip| ip.to_string()),
+                    state: format!("{:?}", client.state()),
+                    wait_event: client.wait_event(),
+                    idle_seconds: client.idle_seconds(),
+                    backlog_bytes: client.backlog_bytes(),
+                });
+                false
+            });
+
+            let mut servers = Vec::new();
+            for node in service.cluster().nodes.iter() {
+                for pool in node.master().into_iter().chain(node.replicas().iter().cloned()) {
+                    servers.push(ServerStatus::new(pool));
+                }
+            }
+
+            ClusterStatus{
+                port: service.cluster().config.port,
+                wait_events: wait_event_counts(service.connections()).to_vec(),
+                clients,
+                servers,
+                slow_queries: service.cluster().slow_queries.snapshot(),
+            }
+        }).collect();
+
+        Self{
+            version: env!("CARGO_PKG_VERSION"),
+            ready: is_serving(services),
+            panics: panic_count(),
+            clusters,
+        }
+    }
+
+    /// Returns the status as JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("serializing status failed")
+    }
+
+    /// Returns the status as metrics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        metric(&mut out, "riverdb_ready", "gauge", "1 if riverdb is ready to serve queries, see /readyz");
+        let _ = writeln!(out, "riverdb_ready {}", self.ready as u8);
+
+        metric(&mut out, "riverdb_panics_total", "counter", "panics caught in connection tasks, each closes the affected connections");
+        let _ = writeln!(out, "riverdb_panics_total {}", self.panics);
+
+        metric(&mut out, "riverdb_clients", "gauge", "client sessions by what they are waiting on");
+        for cluster in &self.clusters {
+            for (event, count) in &cluster.wait_events {
+                let _ = writeln!(out, "riverdb_clients{{port=\"{}\",wait_event=\"{}\"}} {}", cluster.port, event, count);
+            }
+        }
+
+        metric(&mut out, "riverdb_client_backlog_bytes", "gauge", "bytes waiting to be sent to slow clients, see max_backlog_bytes");
+        for cluster in &self.clusters {
+            let backlog_bytes: usize = cluster.clients.iter().map(|client| client.backlog_bytes).sum();
+            let _ = writeln!(out, "riverdb_client_backlog_bytes{{port=\"{}\"}} {}", cluster.port, backlog_bytes);
+        }
+
+        metric(&mut out, "riverdb_slow_query_seconds", "summary", "latency of the slow queries by normalized query, see slow_query");
+        for cluster in &self.clusters {
+            for query in &cluster.slow_queries {
+                let labels = format!("port=\"{}\",query=\"{}\"", cluster.port, escape_label(&query.query));
+                for (quantile, latency) in [("0.5", query.p50), ("0.95", query.p95), ("0.99", query.p99)] {
+                    let _ = writeln!(out, "riverdb_slow_query_seconds{{{},quantile=\"{}\"}} {}", labels, quantile, latency.as_secs_f64());
+                }
+                let _ = writeln!(out, "riverdb_slow_query_seconds_sum{{{}}} {}", labels, query.total.as_secs_f64());
+                let _ = writeln!(out, "riverdb_slow_query_seconds_count{{{}}} {}", labels, query.count);
+            }
+        }
+
+        metric(&mut out, "riverdb_slow_query_rows_total", "counter", "rows returned or affected by the slow queries by normalized query");
+        for cluster in &self.clusters {
+            for query in &cluster.slow_queries {
+                let _ = writeln!(out, "riverdb_slow_query_rows_total{{port=\"{}\",query=\"{}\"}} {}", cluster.port, escape_label(&query.query), query.rows);
+            }
+        }
+
+        let gauges: [(&str, &str, fn(&ServerStatus) -> u64); 6] = [
+            ("riverdb_server_up", "1 if the server is not marked down by the health checks", |s| s.healthy as u64),
+            ("riverdb_pool_connections", "backend connections open to the server", |s| s.connections as u64),
+            ("riverdb_pool_idle_connections", "backend connections idle in the pool", |s| s.pooled as u64),
+            ("riverdb_pool_active_connections", "backend connections in use by clients", |s| s.in_use as u64),
+            ("riverdb_pool_active_transactions", "transactions in progress on the server", |s| s.active_transactions.max(0) as u64),
+            ("riverdb_pool_queue_depth", "callers waiting for a connection from the pool", |s| s.queue_depth as u64),
+        ];
+        let counters: [(&str, &str, fn(&ServerStatus) -> u64); 4] = [
+            ("riverdb_pool_waits_total", "times a caller waited for a connection from the pool", |s| s.waits),
+            ("riverdb_pool_wait_timeouts_total", "times a caller gave up waiting for a connection from the pool", |s| s.wait_timeouts),
+            ("riverdb_pool_too_many_connections_errors_total", "too many connections errors from the server", |s| s.too_many_connections_errors),
+            ("riverdb_pool_dropped_messages_total", "messages from the server dropped because no client was attached", |s| s.dropped_messages),
+        ];
+        for (kind, metrics) in [("gauge", &gauges[..]), ("counter", &counters[..])] {
+            for (name, help, value) in metrics {
+                metric(&mut out, name, kind, help);
+                for cluster in &self.clusters {
+                    for server in &cluster.servers {
+                        let _ = writeln!(out, "{}{{port=\"{}\",database=\"{}\",address=\"{}\",is_master=\"{}\"}} {}",
+                            name, cluster.port, escape_label(&server.database), escape_label(&server.address), server.is_master, value(server));
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+impl ServerStatus {
+    fn new(pool: &ConnectionPool) -> Self {
+        Self{
+            database: pool.config.database.clone(),
+            address: format!("{}:{}", pool.config.host, pool.config.port),
+            is_master: pool.config.is_master,
+            healthy: pool.is_healthy(),
+            draining: pool.is_draining(),
+            connections: pool.connections.len(),
+            pooled: pool.pooled(),
+            in_use: pool.in_use(),
+            active_transactions: pool.active_transactions(),
+            queue_depth: pool.queue_depth(),
+            waits: pool.waits(),
+            wait_timeouts: pool.wait_timeouts(),
+            too_many_connections_errors: pool.too_many_connections_errors(),
+            dropped_messages: pool.dropped_messages(),
+        }
+    }
+}
+
+/// Returns true if the services are listening for connections (see is_ready) and at least one of
+/// the masters or replicas of their clusters is reachable: connected to at least once (see is_reachable),
+/// and not marked down by the health checks or draining.
+pub fn is_serving(services: &[&'static PostgresService]) -> bool {
+    is_ready() && services.iter().any(|service| {
+        service.cluster().nodes.iter().any(|node| {
+            node.master().into_iter().chain(node.replicas().iter().cloned())
+                .any(|pool| pool.is_reachable() && pool.is_healthy() && !pool.is_draining())
+        })
+    })
+}
+
+/// Write the HELP and TYPE lines of a Prometheus metric.
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a Prometheus label value.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+
+    #[test]
+    fn test_to_prometheus() {
+        let status = Status{
+            version: "test",
+            ready: true,
+            panics: 2,
+            clusters: vec![ClusterStatus{
+                port: 5432,
+                wait_events: vec![(WaitEvent::ClientRead, 3)],
+                clients: vec![ClientStatus{
+                    id: 1,
+                    user: "user".to_string(),
+                    database: "db".to_string(),
+                    client_addr: None,
+                    state: "Ready".to_string(),
+                    wait_event: WaitEvent::ClientRead,
+                    idle_seconds: 0,
+                    backlog_bytes: 4096,
+                }],
+                servers: vec![ServerStatus{
+                    database: "db\"1".to_string(),
+                    address: "localhost:5433".to_string(),
+                    is_master: true,
+                    healthy: true,
+                    draining: false,
+                    connections: 4,
+                    pooled: 1,
+                    in_use: 3,
+                    active_transactions: 2,
+                    queue_depth: 0,
+                    waits: 7,
+                    wait_timeouts: 1,
+                    too_many_connections_errors: 0,
+                    dropped_messages: 0,
+                }],
+                slow_queries: vec![SlowQuerySummary{
+                    query: "SELECT * FROM t WHERE id = $1".to_string(),
+                    count: 3,
+                    rows: 5,
+                    total: Duration::from_millis(4500),
+                    p50: Duration::from_millis(1500),
+                    p95: Duration::from_millis(2000),
+                    p99: Duration::from_millis(2000),
+                }],
+            }],
+        };
+        let metrics = status.to_prometheus();
+        assert!(metrics.contains("riverdb_ready 1\n"));
+        assert!(metrics.contains("# TYPE riverdb_panics_total counter\nriverdb_panics_total 2\n"));
+        assert!(metrics.contains("riverdb_clients{port=\"5432\",wait_event=\"ClientRead\"} 3\n"));
+        assert!(metrics.contains("riverdb_client_backlog_bytes{port=\"5432\"} 4096\n"));
+        assert!(metrics.contains("# TYPE riverdb_pool_waits_total counter\n"));
+        assert!(metrics.contains("riverdb_slow_query_seconds{port=\"5432\",query=\"SELECT * FROM t WHERE id = $1\",quantile=\"0.95\"} 2\n"));
+        assert!(metrics.contains("riverdb_slow_query_seconds_sum{port=\"5432\",query=\"SELECT * FROM t WHERE id = $1\"} 4.5\n"));
+        assert!(metrics.contains("riverdb_slow_query_seconds_count{port=\"5432\",query=\"SELECT * FROM t WHERE id = $1\"} 3\n"));
+        assert!(metrics.contains("riverdb_slow_query_rows_total{port=\"5432\",query=\"SELECT * FROM t WHERE id = $1\"} 5\n"));
+        assert!(metrics.contains("riverdb_pool_connections{port=\"5432\",database=\"db\\\"1\",address=\"localhost:5433\",is_master=\"true\"} 4\n"));
+    }
+}
@@ -0,0 +1,459 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex, PoisonError, Weak};
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::Ordering::{Acquire, AcqRel};
+
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use serde_json::{Map, Value};
+use tracing::{Event, Subscriber};
+use tracing::field::{Field, Visit};
+use tracing_subscriber::{EnvFilter, FmtSubscriber, reload};
+use tracing_subscriber::fmt::{Formatter, FmtContext, FormatEvent, FormatFields, FormattedFields, MakeWriter};
+use tracing_subscriber::fmt::format::{DefaultFields, Format};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::config::{LogSettings, LogFormat, LogRotation};
+
+
+type FilterHandle = reload::Handle<EnvFilter, Formatter<DefaultFields, EventFormat, BoxMakeWriter>>;
+
+static FILTER_HANDLE: AtomicPtr<FilterHandle> = AtomicPtr::new(std::ptr::null_mut());
+
+/// The time format appended to the name of a rotated log file, e.g. riverdb.log.20210701T000000.000
+const ROTATED_SUFFIX: &str = ".%Y%m%dT%H%M%S%.3f";
+
+/// Install the global tracing subscriber configured by settings. The filter can be changed
+/// later with set_log_filter. Call this once, on startup.
+pub fn init_logging(settings: &LogSettings) -> Result<()> {
+    let filter = EnvFilter::try_new(settings.filter()).map_err(Error::new)?;
+    let writer = if settings.file.is_empty() {
+        BoxMakeWriter::new(io::stdout)
+    } else {
+        BoxMakeWriter::new(RotatingFile::open(settings)?)
+    };
+    let format = match settings.format {
+        LogFormat::Text => EventFormat::Text(Format::default().with_ansi(settings.file.is_empty())),
+        LogFormat::Json => EventFormat::Json,
+    };
+    let builder = FmtSubscriber::builder()
+        .with_writer(writer)
+        .event_format(format)
+        .with_env_filter(filter)
+        .with_filter_reloading();
+    let handle = Box::new(builder.reload_handle());
+    tracing::subscriber::set_global_default(builder.finish()).map_err(Error::new)?;
+
+    let p = Box::leak(handle) as *mut FilterHandle;
+    if FILTER_HANDLE.compare_exchange(std::ptr::null_mut(), p, AcqRel, Acquire).is_err() {
+        // Unreachable, set_global_default fails if called twice
+        unsafe { drop(Box::from_raw(p)) };
+    }
+    Ok(())
+}
+
+fn filter_handle() -> Result<&'static FilterHandle> {
+    let p = FILTER_HANDLE.load(Acquire);
+    if p.is_null() {
+        return Err(Error::new("logging was not configured with init_logging"));
+    }
+    Ok(unsafe { &*p })
+}
+
+/// Replace the log filter with directives, a level optionally followed by module=level overrides,
+/// e.g. info,riverdb::pg::backend=debug. See LogSettings::filter.
+pub fn set_log_filter(directives: &str) -> Result<()> {
+    let filter = EnvFilter::try_new(directives).map_err(Error::new)?;
+    filter_handle()?.reload(filter).map_err(Error::new)
+}
+
+/// Returns the current log filter directives.
+pub fn log_filter() -> Result<String> {
+    filter_handle()?.with_current(|filter| filter.to_string()).map_err(Error::new)
+}
+
+/// The format of the log lines, see LogFormat.
+pub enum EventFormat {
+    Text(Format),
+    Json,
+}
+
+impl<S, N> FormatEvent<S, N> for EventFormat
+    where S: Subscriber + for<'a> LookupSpan<'a>,
+          N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, writer: &mut dyn fmt::Write, event: &Event<'_>) -> fmt::Result {
+        match self {
+            EventFormat::Text(format) => format.format_event(ctx, writer, event),
+            EventFormat::Json => writeln!(writer, "{}", json_event(ctx, event, Utc::now())),
+        }
+    }
+}
+
+/// Returns the event as a JSON object with the timestamp, level, target, fields, and spans (outermost first.)
+fn json_event<S, N>(ctx: &FmtContext<'_, S, N>, event: &Event<'_>, now: DateTime<Utc>) -> Value
+    where S: Subscriber + for<'a> LookupSpan<'a>,
+          N: for<'a> FormatFields<'a> + 'static,
+{
+    let meta = event.metadata();
+    let mut fields = JsonFields(Map::new());
+    event.record(&mut fields);
+
+    let mut spans = Vec::new();
+    let _ = ctx.visit_spans::<(), _>(|span| {
+        let mut obj = Map::new();
+        obj.insert("name".to_string(), Value::from(span.name()));
+        if let Some(formatted) = span.extensions().get::<FormattedFields<N>>() {
+            if !formatted.fields.is_empty() {
+                obj.insert("fields".to_string(), Value::from(formatted.fields.as_str()));
+            }
+        }
+        spans.push(Value::Object(obj));
+        Ok(())
+    });
+    spans.reverse();
+
+    let mut obj = Map::new();
+    obj.insert("timestamp".to_string(), Value::from(now.to_rfc3339_opts(SecondsFormat::Micros, true)));
+    obj.insert("level".to_string(), Value::from(meta.level().to_string()));
+    obj.insert("target".to_string(), Value::from(meta.target()));
+    obj.insert("fields".to_string(), Value::Object(fields.0));
+    if !spans.is_empty() {
+        obj.insert("spans".to_string(), Value::Array(spans));
+    }
+    Value::Object(obj)
+}
+
+/// Records the fields of an event as JSON values.
+struct JsonFields(Map<String, Value>);
+
+impl Visit for JsonFields {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), Value::from(format!("{:?}", value)));
+    }
+}
+
+/// A log file that's rotated when it would exceed max_file_bytes, or on the rotation schedule,
+/// by renaming it with the time appended and opening a new file. See LogSettings.
+/// The rotation happens on its own thread, so writes aren't blocked on the rename, and a failed
+/// rotation doesn't fail writes, which continue to the current file.
+#[derive(Clone)]
+pub struct RotatingFile {
+    inner: Arc<Mutex<RotatingFileState>>,
+}
+
+struct RotatingFileState {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// the rotation period the file was opened in, see LogRotation
+    period: i64,
+    max_file_bytes: u64,
+    rotation: LogRotation,
+    max_files: u32,
+    /// set while the rotation thread is rotating the file
+    rotating: bool,
+    /// requests a rotation at the given time from the rotation thread, which exits when it's dropped
+    rotations: mpsc::Sender<DateTime<Utc>>,
+}
+
+impl RotatingFile {
+    /// Open (or create) the log file of settings for appending, and start its rotation thread.
+    pub fn open(settings: &LogSettings) -> Result<Self> {
+        let path = PathBuf::from(&settings.file);
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        let (rotations, receiver) = mpsc::channel();
+        let state = RotatingFileState{
+            path,
+            file,
+            size,
+            period: period(settings.rotation, Utc::now()),
+            max_file_bytes: settings.max_file_bytes,
+            rotation: settings.rotation,
+            max_files: settings.max_files,
+            rotating: false,
+            rotations,
+        };
+        let inner = Arc::new(Mutex::new(state));
+        let weak = Arc::downgrade(&inner);
+        std::thread::Builder::new()
+            .name("riverdb-log-rotation".to_string())
+            .spawn(move || run_rotations(weak, receiver))?;
+        Ok(Self{inner})
+    }
+}
+
+impl MakeWriter for RotatingFile {
+    type Writer = RotatingFile;
+
+    fn make_writer(&self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Utc::now();
+        if !state.rotating && state.rotation_due(buf.len() as u64, now) {
+            // The rotation thread only exits once the state is dropped
+            state.rotating = state.rotations.send(now).is_ok();
+        }
+        let n = state.file.write(buf)?;
+        state.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner).file.flush()
+    }
+}
+
+impl RotatingFileState {
+    /// Returns true if the file should be rotated because writing len bytes would exceed max_file_bytes
+    /// (unless the file is empty), or because now is in a later rotation period than the file was opened in.
+    fn rotation_due(&self, len: u64, now: DateTime<Utc>) -> bool {
+        let too_big = self.max_file_bytes != 0 && self.size != 0 && self.size + len > self.max_file_bytes;
+        too_big || period(self.rotation, now) != self.period
+    }
+
+    /// Start writing to the new file opened by rotate_file at now. If the rotation failed, writes continue
+    /// to the current file, and the rotation is tried again after another max_file_bytes or in the next period.
+    fn rotated(&mut self, new_file: io::Result<File>, now: DateTime<Utc>) -> io::Result<()> {
+        self.rotating = false;
+        self.size = 0;
+        self.period = period(self.rotation, now);
+        self.file = new_file?;
+        Ok(())
+    }
+}
+
+/// Rotate the log file for each time received, until the RotatingFile is dropped.
+/// Errors are printed to stderr, since they can't be logged to the file being rotated.
+fn run_rotations(state: Weak<Mutex<RotatingFileState>>, receiver: mpsc::Receiver<DateTime<Utc>>) {
+    for now in receiver {
+        let (path, max_files) = match state.upgrade() {
+            Some(state) => {
+                let state = state.lock().unwrap_or_else(PoisonError::into_inner);
+                (state.path.clone(), state.max_files)
+            },
+            None => return,
+        };
+        // Lines written while this renames the file go to the end of the rotated file
+        let new_file = rotate_file(&path, now);
+        let result = match state.upgrade() {
+            Some(state) => state.lock().unwrap_or_else(PoisonError::into_inner).rotated(new_file, now),
+            None => return,
+        };
+        if let Err(e) = result.and_then(|_| remove_old_files(&path, max_files)) {
+            eprintln!("could not rotate the log file {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Rename the log file at path with the time now appended, and open a new file at path.
+fn rotate_file(path: &Path, now: DateTime<Utc>) -> io::Result<File> {
+    let mut rotated = path.to_path_buf().into_os_string();
+    rotated.push(now.format(ROTATED_SUFFIX).to_string());
+    fs::rename(path, &rotated)?;
+    open_append(path)
+}
+
+/// Delete the oldest files rotated from the log file at path, keeping max_files of them.
+/// Only files named like the rotated files, with the time of the rotation appended, are considered.
+fn remove_old_files(path: &Path, max_files: u32) -> io::Result<()> {
+    if max_files == 0 {
+        return Ok(());
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Ok(()),
+    };
+    let mut rotated = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let entry_name = entry?.file_name().to_string_lossy().into_owned();
+        let is_rotated = entry_name.strip_prefix(name.as_str())
+            .map_or(false, |suffix| NaiveDateTime::parse_from_str(suffix, ROTATED_SUFFIX).is_ok());
+        if is_rotated {
+            rotated.push(entry_name);
+        }
+    }
+    // The names end with the time of the rotation, so they sort oldest first
+    rotated.sort();
+    let excess = rotated.len().saturating_sub(max_files as usize);
+    for name in &rotated[..excess] {
+        fs::remove_file(dir.join(name))?;
+    }
+    Ok(())
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Returns the number of the rotation period now is in, which changes at the start of each period.
+fn period(rotation: LogRotation, now: DateTime<Utc>) -> i64 {
+    match rotation {
+        LogRotation::Never => 0,
+        LogRotation::Hourly => now.timestamp() / 3600,
+        LogRotation::Daily => now.timestamp() / 86400,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+    use tracing::info;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("riverdb-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn files(dir: &PathBuf) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        names.sort();
+        names
+    }
+
+    /// Rotate the file of state at now if writing len bytes to it is due for a rotation, like the rotation thread.
+    fn rotate_if_due(state: &mut RotatingFileState, len: u64, now: DateTime<Utc>) {
+        if state.rotation_due(len, now) {
+            let new_file = rotate_file(&state.path, now);
+            state.rotated(new_file, now).unwrap();
+            remove_old_files(&state.path, state.max_files).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = temp_dir("rotate-size");
+        let settings = LogSettings{
+            file: dir.join("riverdb.log").to_string_lossy().into_owned(),
+            max_file_bytes: 10,
+            max_files: 2,
+            ..Default::default()
+        };
+        // Only rotated files are removed
+        fs::write(dir.join("riverdb.log.bak"), "keep").unwrap();
+        let file = RotatingFile::open(&settings).unwrap();
+        let mut state = file.inner.lock().unwrap();
+        for i in 0..4 {
+            rotate_if_due(&mut state, 6, Utc.ymd(2021, 7, 1).and_hms(0, 0, i));
+            state.file.write_all(b"12345\n").unwrap();
+            state.size += 6;
+        }
+        let names = files(&dir);
+        assert_eq!(names, vec!["riverdb.log", "riverdb.log.20210701T000002.000", "riverdb.log.20210701T000003.000", "riverdb.log.bak"]);
+        assert_eq!(fs::read_to_string(dir.join("riverdb.log")).unwrap(), "12345\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_daily() {
+        let dir = temp_dir("rotate-daily");
+        let settings = LogSettings{
+            file: dir.join("riverdb.log").to_string_lossy().into_owned(),
+            rotation: LogRotation::Daily,
+            ..Default::default()
+        };
+        let file = RotatingFile::open(&settings).unwrap();
+        let mut state = file.inner.lock().unwrap();
+        state.period = period(LogRotation::Daily, Utc.ymd(2021, 7, 1).and_hms(12, 0, 0));
+        rotate_if_due(&mut state, 100, Utc.ymd(2021, 7, 1).and_hms(23, 59, 59));
+        assert_eq!(files(&dir), vec!["riverdb.log"]);
+        rotate_if_due(&mut state, 100, Utc.ymd(2021, 7, 2).and_hms(0, 0, 0));
+        assert_eq!(files(&dir), vec!["riverdb.log", "riverdb.log.20210702T000000.000"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_failed() {
+        let dir = temp_dir("rotate-failed");
+        let settings = LogSettings{
+            file: dir.join("riverdb.log").to_string_lossy().into_owned(),
+            max_file_bytes: 10,
+            ..Default::default()
+        };
+        let mut file = RotatingFile::open(&settings).unwrap();
+        // The rename fails when the file is gone, the writes continue to the open file
+        fs::remove_file(dir.join("riverdb.log")).unwrap();
+        for _ in 0..3 {
+            file.write_all(b"1234567890\n").unwrap();
+        }
+        let now = Utc::now();
+        let mut state = file.inner.lock().unwrap();
+        let new_file = rotate_file(&state.path, now);
+        assert!(state.rotated(new_file, now).is_err());
+        assert!(!state.rotating);
+        assert_eq!(state.size, 0);
+        state.file.write_all(b"ok\n").unwrap();
+        drop(state);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_json_format() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let writer = lines.clone();
+        let subscriber = FmtSubscriber::builder()
+            .with_writer(move || SharedBuf(writer.clone()))
+            .event_format(EventFormat::Json)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("client", id = 42).entered();
+            info!(rows = 3, ok = true, "query done");
+        });
+        let line = String::from_utf8(lines.lock().unwrap().clone()).unwrap();
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["level"], "INFO");
+        assert_eq!(value["target"], "riverdb::riverdb::logging::tests");
+        assert_eq!(value["fields"]["message"], "query done");
+        assert_eq!(value["fields"]["rows"], 3);
+        assert_eq!(value["fields"]["ok"], true);
+        assert_eq!(value["spans"][0]["name"], "client");
+        assert_eq!(value["spans"][0]["fields"], "id=42");
+    }
+
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
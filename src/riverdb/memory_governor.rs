@@ -0,0 +1,98 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+
+use tokio::time::{interval, Duration};
+use tracing::{debug, info, warn};
+
+use crate::riverdb::config::{conf, MemoryLimitSettings};
+use crate::riverdb::cache::{ResultCache, CacheStorage};
+use crate::riverdb::pg::PostgresCluster;
+
+
+/// Set while the resident memory size of the process exceeds the soft memory limit
+static UNDER_MEMORY_PRESSURE: AtomicBool = AtomicBool::new(false);
+
+/// Returns true if the process is over the soft memory limit (see MemoryLimitSettings.)
+/// Subsystems should avoid growing their memory usage while this is true.
+pub fn under_memory_pressure() -> bool {
+    UNDER_MEMORY_PRESSURE.load(Relaxed)
+}
+
+/// Check the memory usage of the process every check_ms until the process exits. While it's over
+/// the soft limit, shed memory from the result cache, the connection pools, and the connection backlogs.
+pub async fn run_memory_governor(settings: &'static MemoryLimitSettings) {
+    let mut interval = interval(Duration::from_millis(settings.check_ms as u64));
+    loop {
+        interval.tick().await;
+        let rss = match resident_memory_bytes() {
+            Some(rss) => rss,
+            None => {
+                warn!("can't read the resident memory size of the process, the soft memory limit is disabled");
+                return;
+            }
+        };
+
+        let over_limit = rss > settings.soft_limit_bytes;
+        let was_over_limit = UNDER_MEMORY_PRESSURE.swap(over_limit, Relaxed);
+        if over_limit {
+            if !was_over_limit {
+                warn!(rss, soft_limit_bytes=settings.soft_limit_bytes, "memory usage exceeds the soft limit, shedding memory");
+            }
+            shed_memory(settings);
+        } else if was_over_limit {
+            info!(rss, soft_limit_bytes=settings.soft_limit_bytes, "memory usage is back under the soft limit");
+        }
+    }
+}
+
+/// Evict part of the in-memory result cache and close idle pooled connections.
+/// Backlogs are limited as they grow, see Connection::write_or_buffer.
+fn shed_memory(settings: &MemoryLimitSettings) {
+    let mut evicted = 0;
+    if conf().cache.enabled {
+        let cache = ResultCache::singleton();
+        if let CacheStorage::Memory(storage) = cache.storage() {
+            let bytes = storage.bytes();
+            evicted = cache.shrink(bytes - bytes * settings.cache_shrink_percent as u64 / 100);
+        }
+    }
+
+    let mut closed = 0;
+    for cluster in PostgresCluster::all() {
+        for node in cluster.nodes.iter() {
+            for pool in node.master().into_iter().chain(node.replicas().iter().cloned()) {
+                closed += pool.close_idle(settings.keep_idle_connections as usize);
+                for user_pool in pool.user_pools() {
+                    closed += user_pool.close_idle(settings.keep_idle_connections as usize);
+                }
+            }
+        }
+    }
+    debug!(evicted, closed, "shed memory");
+}
+
+/// Returns the resident memory size of the process in bytes, if it can be determined.
+fn resident_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    parse_vm_rss(&status)
+}
+
+/// Parses the VmRSS line of /proc/self/status, which is in kB, and returns it in bytes.
+fn parse_vm_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb = line["VmRSS:".len()..].trim().strip_suffix("kB")?.trim().parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_vm_rss() {
+        let status = "Name:\triverdb\nVmHWM:\t   20480 kB\nVmRSS:\t   10240 kB\nRssAnon:\t    4096 kB\n";
+        assert_eq!(parse_vm_rss(status), Some(10240 * 1024));
+        assert_eq!(parse_vm_rss("Name:\triverdb\n"), None);
+        assert_eq!(parse_vm_rss("VmRSS:\tlots\n"), None);
+    }
+}
@@ -4,8 +4,15 @@ pub mod worker;
 pub mod pg;
 pub mod server;
 pub mod http;
+pub mod cache;
+pub mod peers;
+pub mod memory_governor;
+pub mod audit;
+pub mod logging;
+#[cfg(unix)]
+pub mod supervisor;
 #[macro_use]
 pub mod plugins;
 
 pub use common::{Error, Result};
-pub use plugins::{Plugin, configure};
+pub use plugins::{Plugin, configure, configure_plugins, start_plugins, shutdown_plugins};
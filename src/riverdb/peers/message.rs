@@ -0,0 +1,65 @@
+use crate::riverdb::{Error, Result};
+
+
+/// A message exchanged between riverdb peers in cluster mode.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PeerMessage {
+    /// Remove the key from the result cache
+    InvalidateCache(String),
+    /// Remove the cached results of queries that read any of these tables from the result cache
+    InvalidateTables(Vec<String>),
+    /// Remove the cached results of queries that read any table, after a write to unknown tables
+    InvalidateAllTables,
+    /// Stop routing new queries to the backend databases until Resume
+    Pause,
+    /// Resume routing queries after Pause
+    Resume,
+}
+
+impl PeerMessage {
+    /// Encode the message with the sending node_id for publishing.
+    /// The format is node_id, kind, and arguments separated by null bytes,
+    /// which can't occur in Postgres query text or identifiers.
+    pub fn encode(&self, node_id: &str) -> Vec<u8> {
+        let mut parts = vec![node_id];
+        match self {
+            PeerMessage::InvalidateCache(key) => {
+                parts.push("invalidate");
+                parts.push(key);
+            },
+            PeerMessage::InvalidateTables(tables) => {
+                parts.push("invalidate_tables");
+                parts.extend(tables.iter().map(|table| table.as_str()));
+            },
+            PeerMessage::InvalidateAllTables => parts.push("invalidate_all_tables"),
+            PeerMessage::Pause => parts.push("pause"),
+            PeerMessage::Resume => parts.push("resume"),
+        }
+        parts.join("\0").into_bytes()
+    }
+
+    /// Decode a message created with encode, returning the sending node_id and the message.
+    pub fn decode(buf: &[u8]) -> Result<(&str, Self)> {
+        let s = std::str::from_utf8(buf)?;
+        let mut parts = s.split('\0');
+        let node_id = parts.next().unwrap_or("");
+        let kind = parts.next().unwrap_or("");
+        let msg = match kind {
+            "invalidate" => {
+                PeerMessage::InvalidateCache(next_part(&mut parts)?.to_string())
+            },
+            "invalidate_tables" => {
+                PeerMessage::InvalidateTables(parts.map(|table| table.to_string()).collect())
+            },
+            "invalidate_all_tables" => PeerMessage::InvalidateAllTables,
+            "pause" => PeerMessage::Pause,
+            "resume" => PeerMessage::Resume,
+            _ => return Err(Error::new(format!("unknown peer message kind '{}'", kind))),
+        };
+        Ok((node_id, msg))
+    }
+}
+
+fn next_part<'a, I: Iterator<Item=&'a str>>(parts: &mut I) -> Result<&'a str> {
+    parts.next().ok_or_else(|| Error::new("peer message is missing an argument"))
+}
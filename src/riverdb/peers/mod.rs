@@ -0,0 +1,29 @@
+mod message;
+mod peers;
+
+pub use self::message::PeerMessage;
+pub use self::peers::Peers;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_message_encoding() {
+        let msgs = vec![
+            PeerMessage::InvalidateCache("SELECT * FROM USERS WHERE ID = $1".to_string()),
+            PeerMessage::InvalidateTables(vec!["users".to_string(), "orders".to_string()]),
+            PeerMessage::InvalidateAllTables,
+            PeerMessage::Pause,
+            PeerMessage::Resume,
+        ];
+        for msg in msgs {
+            let buf = msg.encode("node1");
+            let (node_id, decoded) = PeerMessage::decode(&buf).unwrap();
+            assert_eq!(node_id, "node1");
+            assert_eq!(decoded, msg);
+        }
+        assert!(PeerMessage::decode(b"node1\0bogus").is_err());
+        assert!(PeerMessage::decode(b"node1\0invalidate").is_err());
+    }
+}
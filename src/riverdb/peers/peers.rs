@@ -0,0 +1,151 @@
+use std::sync::atomic::{AtomicBool, AtomicPtr};
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed};
+
+use tokio::time::{sleep, Duration};
+use tracing::{debug, info, warn};
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::config::{self, PeerSettings};
+use crate::riverdb::cache::{RedisClient, Reply, ResultCache, write_command, read_reply};
+use crate::riverdb::peers::PeerMessage;
+
+
+/// Maximum number of seconds to wait between attempts to reconnect to the redis pub/sub channel.
+const MAX_RECONNECT_DELAY_SECONDS: u64 = 30;
+/// Number of milliseconds between checks of the paused state while waiting for it to be resumed.
+const PAUSED_POLL_INTERVAL_MS: u64 = 50;
+
+/// Peers shares state between riverdb instances in cluster mode so a fleet of proxies behaves coherently
+/// (e.g. during failovers and cache invalidation.) Messages are exchanged over a redis pub/sub channel.
+pub struct Peers {
+    pub config: &'static PeerSettings,
+    client: RedisClient,
+    paused: AtomicBool,
+}
+
+impl Peers {
+    /// Create a new Peers from the passed configuration.
+    pub fn new(config: &'static PeerSettings) -> Self {
+        Self {
+            config,
+            client: RedisClient::new(&config.redis_address),
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    /// Return the global Peers instance.
+    pub fn singleton() -> &'static Self {
+        static SINGLETON_PEERS: AtomicPtr<Peers> = AtomicPtr::new(std::ptr::null_mut());
+        unsafe {
+            let mut p = SINGLETON_PEERS.load(Acquire);
+            if p.is_null() {
+                let mut peers = Box::new(Peers::new(&config::conf().peers));
+                p = peers.as_mut() as _;
+                match SINGLETON_PEERS.compare_exchange(std::ptr::null_mut(), p, AcqRel, Acquire) {
+                    Ok(_) => {
+                        Box::leak(peers);
+                    },
+                    Err(current) => {
+                        p = current;
+                    },
+                }
+            }
+            &*p
+        }
+    }
+
+    /// Returns true if routing of new queries is paused cluster-wide.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Relaxed)
+    }
+
+    /// Wait until routing is resumed, if it's paused.
+    pub async fn wait_while_paused(&self) {
+        while self.is_paused() {
+            sleep(Duration::from_millis(PAUSED_POLL_INTERVAL_MS)).await;
+        }
+    }
+
+    /// Apply msg locally and publish it to the other peers (if cluster mode is enabled.)
+    pub async fn broadcast(&self, msg: PeerMessage) -> Result<()> {
+        let payload = msg.encode(&self.config.node_id);
+        self.handle(msg).await;
+        if self.config.enabled {
+            self.client.publish(&self.config.channel, &payload).await?;
+        }
+        Ok(())
+    }
+
+    /// Apply the message to the local state
+    async fn handle(&self, msg: PeerMessage) {
+        match msg {
+            PeerMessage::InvalidateCache(key) => {
+                if config::conf().cache.enabled {
+                    ResultCache::singleton().remove(&key).await;
+                }
+            },
+            PeerMessage::InvalidateTables(tables) => {
+                if config::conf().cache.enabled {
+                    ResultCache::singleton().invalidate_tables(&tables).await;
+                }
+            },
+            PeerMessage::InvalidateAllTables => {
+                if config::conf().cache.enabled {
+                    ResultCache::singleton().invalidate_all_tables().await;
+                }
+            },
+            PeerMessage::Pause => {
+                info!("pausing new queries");
+                self.paused.store(true, Relaxed);
+            },
+            PeerMessage::Resume => {
+                info!("resuming queries");
+                self.paused.store(false, Relaxed);
+            },
+        }
+    }
+
+    /// Subscribe to the peers channel and apply messages from other peers until the process exits.
+    /// Reconnects with exponential backoff if the connection to redis fails.
+    pub async fn run(&'static self) {
+        let mut delay = 1;
+        loop {
+            if let Err(e) = self.subscribe(&mut delay).await {
+                warn!(?e, delay, "peers subscription failed, reconnecting");
+            }
+            sleep(Duration::from_secs(delay)).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY_SECONDS);
+        }
+    }
+
+    async fn subscribe(&self, delay: &mut u64) -> Result<()> {
+        let mut conn = self.client.connect().await?;
+        write_command(&mut conn, &[b"SUBSCRIBE", self.config.channel.as_bytes()]).await?;
+        info!(channel=%self.config.channel, node_id=%self.config.node_id, "subscribed to peers channel");
+        *delay = 1;
+
+        loop {
+            let payload = match read_reply(&mut conn).await? {
+                Reply::Array(mut items) if items.len() == 3 => {
+                    match (items.pop(), items.first()) {
+                        (Some(Reply::Bulk(payload)), Some(Reply::Bulk(kind))) if kind.as_ref() == b"message" => payload,
+                        _ => continue, // subscribe confirmation
+                    }
+                },
+                _ => return Err(Error::new("unexpected reply from redis on peers channel")),
+            };
+
+            match PeerMessage::decode(&payload) {
+                Ok((node_id, msg)) => {
+                    if node_id != self.config.node_id {
+                        debug!(node_id, ?msg, "received peer message");
+                        self.handle(msg).await;
+                    }
+                },
+                Err(e) => {
+                    warn!(?e, "invalid peer message");
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,550 @@
+use tokio::time::{sleep, Instant, Duration};
+use tracing::{info, warn};
+
+use crate::riverdb::Result;
+use crate::riverdb::pg::{ClientConn, ClientState, Connection, ConnectionPool, Reloader, wait_event_counts, dump_state};
+use crate::riverdb::pg::protocol::{Messages, MessageBuilder, MessageErrorBuilder, ErrorSeverity, ErrorFieldTag, Tag, error_codes, COMPRESSION_OPTION};
+use crate::riverdb::server::Connection as ServerConnection;
+use crate::riverdb::common::coarse_monotonic_now;
+use crate::riverdb::pg::sql::QueryMessage;
+use crate::riverdb::plugins::{event_listeners, set_listener_enabled};
+use crate::riverdb::logging::{log_filter, set_log_filter};
+use crate::riverdb::peers::{Peers, PeerMessage};
+
+
+/// The type oid of the Postgres text type
+const TEXT_OID: i32 = 25;
+/// The length of the longest normalized admin command
+const MAX_COMMAND_LEN: usize = 32;
+
+/// Commands answered locally by riverdb instead of being sent to Postgres.
+/// These are only recognized in simple Query messages, and use names that aren't valid in Postgres (or functions it doesn't have.)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum AdminCommand {
+    /// SHOW ERRORS lists the errors returned by Postgres by database, user, and SQLSTATE class.
+    ShowErrors,
+    /// SHOW STATS lists the slow queries (see the slow_query setting) by normalized query, the most frequent first,
+    /// with the number of slow executions, the rows they returned or affected, and their latency percentiles.
+    ShowStats,
+    /// RESET STATS forgets the slow queries listed by SHOW STATS, e.g. after a deploy changed the queries.
+    ResetStats,
+    /// SHOW CLIENTS lists the client sessions of the service, with the protocol compression they
+    /// requested and the compression in effect (see the protocol_options setting), the number
+    /// of savepoints in the transaction they're in, the bytes waiting to be sent to them (see max_backlog_bytes),
+    /// and the TLS version and cipher suite negotiated with them (see client_tls.)
+    ShowClients,
+    /// SHOW ACTIVITY lists the client sessions of the service with what each is waiting on (see WaitEvent)
+    /// and its most recent query.
+    ShowActivity,
+    /// SHOW WAIT EVENTS lists the number of client sessions of the service waiting on each WaitEvent.
+    ShowWaitEvents,
+    /// SHOW REPLICAS lists the replicas of the cluster with their weight, warm-up progress (see replica_warmup_seconds),
+    /// whether they're currently excluded from routing, and their replication lag (see max_replica_lag_ms.)
+    ShowReplicas,
+    /// RELOAD re-reads the config file and applies the changes that don't require a restart.
+    Reload,
+    /// DUMP STATE writes a diagnostic snapshot of the connections and pools of the service to the log,
+    /// like sending the process SIGUSR1 (see dump_state.)
+    DumpState,
+    /// DRAIN SERVER 'host:port' [TIMEOUT seconds] stops new checkouts from the pools for that server,
+    /// waits up to the timeout for the sessions using it to finish, and then closes its connections.
+    /// The timeout defaults to the cluster's drain_timeout_seconds.
+    DrainServer{server: String, timeout_seconds: Option<u32>},
+    /// SHOW PLUGINS lists the plugin listeners of each event, in the order they're invoked, and whether they're enabled.
+    ShowPlugins,
+    /// ENABLE|DISABLE PLUGIN 'name' [ON 'event'] enables or disables the listeners of the named plugin
+    /// for the event, or for all events. Disabled listeners are skipped when dispatching the event.
+    SetPluginEnabled{plugin: String, event: Option<String>, enabled: bool},
+    /// SHOW LOG LEVEL returns the current log filter, e.g. info,riverdb::pg::backend=debug.
+    ShowLogLevel,
+    /// SET LOG LEVEL 'filter' replaces the log filter until the next restart, see the log settings.
+    SetLogLevel(String),
+    /// PAUSE holds new queries that need a backend connection until RESUME, e.g. during a failover.
+    /// In cluster mode it's published to the peers, which pause as well (see the peers settings.)
+    Pause,
+    /// RESUME lets the queries held by PAUSE continue, on this riverdb and its peers in cluster mode.
+    Resume,
+    /// SELECT riverdb_*() calls one of the RiverdbFunctions, which return information about
+    /// the proxy and the session for application developers.
+    Select(RiverdbFunction),
+}
+
+/// Functions answered locally with metadata about riverdb and the client's session.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RiverdbFunction {
+    /// riverdb_version() returns the riverdb version.
+    Version,
+    /// riverdb_uptime() returns the number of seconds riverdb has been running.
+    Uptime,
+    /// riverdb_session_id() returns the id of the client connection.
+    SessionId,
+    /// riverdb_last_route() returns the server (master or replica) the last query was routed to.
+    LastRoute,
+    /// riverdb_stats() returns all of the above as a single row.
+    Stats,
+}
+
+impl RiverdbFunction {
+    /// Returns the RiverdbFunction for the normalized function call, or None if it's not one.
+    pub fn parse(call: &str) -> Option<Self> {
+        match call {
+            "RIVERDB_VERSION()" => Some(RiverdbFunction::Version),
+            "RIVERDB_UPTIME()" => Some(RiverdbFunction::Uptime),
+            "RIVERDB_SESSION_ID()" => Some(RiverdbFunction::SessionId),
+            "RIVERDB_LAST_ROUTE()" => Some(RiverdbFunction::LastRoute),
+            "RIVERDB_STATS()" => Some(RiverdbFunction::Stats),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the function, used as the column name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RiverdbFunction::Version => "riverdb_version",
+            RiverdbFunction::Uptime => "riverdb_uptime",
+            RiverdbFunction::SessionId => "riverdb_session_id",
+            RiverdbFunction::LastRoute => "riverdb_last_route",
+            RiverdbFunction::Stats => "riverdb_stats",
+        }
+    }
+
+    /// Returns the value of the function for client. Stats isn't a single value, see AdminCommand::run.
+    fn value(&self, client: &ClientConn) -> String {
+        match self {
+            RiverdbFunction::Version => env!("CARGO_PKG_VERSION").to_string(),
+            RiverdbFunction::Uptime => coarse_monotonic_now().to_string(),
+            RiverdbFunction::SessionId => client.id().to_string(),
+            RiverdbFunction::LastRoute => match client.pool() {
+                Some(pool) => format!("{} {}:{}",
+                    if pool.config.is_master { "master" } else { "replica" },
+                    pool.config.host, pool.config.port),
+                None => "none".to_string(),
+            },
+            RiverdbFunction::Stats => unreachable!(),
+        }
+    }
+}
+
+impl AdminCommand {
+    /// Returns the AdminCommand for query, or None if it's not an admin command.
+    pub fn parse(query: &QueryMessage) -> Option<Self> {
+        if !query.is_simple_query() || query.is_multi_query() {
+            return None;
+        }
+        let q = query.query();
+        let param = |i: usize| q.params().get(i).map(|p| q.param(p));
+        let mut normalized = q.normalized().trim_end_matches(|c| c == ';' || c == ' ');
+        // Only some of the words are key words, which are uppercase even if the normalizer preserves
+        // identifier case. Admin commands are short, so don't allocate for longer queries.
+        let upper;
+        if normalized.len() <= MAX_COMMAND_LEN && normalized.bytes().any(|b| b.is_ascii_lowercase()) {
+            upper = normalized.to_ascii_uppercase();
+            normalized = upper.as_str();
+        }
+        match normalized {
+            "SHOW ERRORS" => Some(AdminCommand::ShowErrors),
+            "SHOW STATS" => Some(AdminCommand::ShowStats),
+            "RESET STATS" => Some(AdminCommand::ResetStats),
+            "SHOW CLIENTS" => Some(AdminCommand::ShowClients),
+            "SHOW ACTIVITY" => Some(AdminCommand::ShowActivity),
+            "SHOW WAIT EVENTS" => Some(AdminCommand::ShowWaitEvents),
+            "SHOW REPLICAS" => Some(AdminCommand::ShowReplicas),
+            "SHOW PLUGINS" => Some(AdminCommand::ShowPlugins),
+            "RELOAD" => Some(AdminCommand::Reload),
+            "DUMP STATE" => Some(AdminCommand::DumpState),
+            "PAUSE" => Some(AdminCommand::Pause),
+            "RESUME" => Some(AdminCommand::Resume),
+            "SHOW LOG LEVEL" => Some(AdminCommand::ShowLogLevel),
+            "SET LOG LEVEL $1" => Some(AdminCommand::SetLogLevel(string_literal(param(0)?)?)),
+            s if s.starts_with("SELECT ") => RiverdbFunction::parse(&s[7..]).map(AdminCommand::Select),
+            "DRAIN SERVER $1" => Some(AdminCommand::DrainServer{
+                server: string_literal(param(0)?)?,
+                timeout_seconds: None,
+            }),
+            "DRAIN SERVER $1 TIMEOUT $2" => Some(AdminCommand::DrainServer{
+                server: string_literal(param(0)?)?,
+                timeout_seconds: Some(param(1)?.parse().ok()?),
+            }),
+            "ENABLE PLUGIN $1" | "DISABLE PLUGIN $1" => Some(AdminCommand::SetPluginEnabled{
+                plugin: string_literal(param(0)?)?,
+                event: None,
+                enabled: normalized.starts_with("ENABLE"),
+            }),
+            "ENABLE PLUGIN $1 ON $2" | "DISABLE PLUGIN $1 ON $2" => Some(AdminCommand::SetPluginEnabled{
+                plugin: string_literal(param(0)?)?,
+                event: Some(string_literal(param(1)?)?),
+                enabled: normalized.starts_with("ENABLE"),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns true if only the admin_users may run the command. The RiverdbFunctions only return
+    /// information about the client's own session, so any client may call them.
+    pub fn requires_admin(&self) -> bool {
+        !matches!(self, AdminCommand::Select(_))
+    }
+
+    /// Run the command, sending the result to the client.
+    pub async fn run(self, client: &ClientConn) -> Result<()> {
+        let msgs = match self {
+            AdminCommand::ShowErrors => {
+                let rows = match client.cluster() {
+                    Some(cluster) => cluster.error_stats.snapshot().into_iter()
+                        .map(|(key, count)| vec![key.database, key.user, key.class, count.to_string()])
+                        .collect(),
+                    None => Vec::new(),
+                };
+                rows_result(&["database", "user", "class", "errors"], &rows, "SHOW", client.state())
+            },
+            AdminCommand::ShowStats => {
+                let millis = |d: Duration| format!("{:.3}", d.as_secs_f64() * 1000.0);
+                let rows = match client.cluster() {
+                    Some(cluster) => cluster.slow_queries.snapshot().into_iter()
+                        .map(|q| vec![q.query, q.count.to_string(), q.rows.to_string(), millis(q.p50), millis(q.p95), millis(q.p99)])
+                        .collect(),
+                    None => Vec::new(),
+                };
+                rows_result(&["query", "count", "rows", "p50_ms", "p95_ms", "p99_ms"], &rows, "SHOW", client.state())
+            },
+            AdminCommand::ResetStats => {
+                if let Some(cluster) = client.cluster() {
+                    cluster.slow_queries.clear();
+                }
+                command_result("RESET", client.state())
+            },
+            AdminCommand::ShowClients => {
+                let mut rows = Vec::new();
+                client.connections().for_each(|c| {
+                    if let ClientState::StateInitial | ClientState::SSLHandshake = c.state() {
+                        return false; // connection_params isn't set yet
+                    }
+                    let params = c.connection_params();
+                    let requested = c.protocol_options().get(COMPRESSION_OPTION).unwrap_or("").to_string();
+                    rows.push(vec![
+                        c.id().to_string(),
+                        params.get("user").unwrap_or("").to_string(),
+                        params.get("database").unwrap_or("").to_string(),
+                        format!("{:?}", c.state()),
+                        requested,
+                        c.compression().unwrap_or_else(|| "none".to_string()),
+                        c.backend().map_or(0, |backend| backend.savepoint_depth()).to_string(),
+                        c.backlog_bytes().to_string(),
+                        c.transport().tls_version().unwrap_or_else(|| "none".to_string()),
+                        c.transport().tls_cipher_suite().unwrap_or_else(|| "none".to_string()),
+                    ]);
+                    false
+                });
+                rows_result(&["id", "user", "database", "state", "requested_compression", "compression", "savepoints", "backlog_bytes", "tls_version", "tls_cipher"], &rows, "SHOW", client.state())
+            },
+            AdminCommand::ShowActivity => {
+                let mut rows = Vec::new();
+                client.connections().for_each(|c| {
+                    if let ClientState::StateInitial | ClientState::SSLHandshake = c.state() {
+                        return false; // connection_params isn't set yet
+                    }
+                    let params = c.connection_params();
+                    rows.push(vec![
+                        c.id().to_string(),
+                        params.get("user").unwrap_or("").to_string(),
+                        params.get("database").unwrap_or("").to_string(),
+                        c.remote_ip().map_or(String::new(), |ip| ip.to_string()),
+                        format!("{:?}", c.state()),
+                        c.wait_event().to_string(),
+                        c.last_query().map_or(String::new(), |query| query.normalized().to_string()),
+                    ]);
+                    false
+                });
+                rows_result(&["id", "user", "database", "client_addr", "state", "wait_event", "query"], &rows, "SHOW", client.state())
+            },
+            AdminCommand::ShowWaitEvents => {
+                let rows: Vec<_> = wait_event_counts(client.connections()).iter()
+                    .map(|(event, count)| vec![event.to_string(), count.to_string()])
+                    .collect();
+                rows_result(&["wait_event", "sessions"], &rows, "SHOW", client.state())
+            },
+            AdminCommand::ShowReplicas => {
+                let mut rows = Vec::new();
+                for node in client.cluster().map_or(&[][..], |cluster| cluster.nodes.as_slice()) {
+                    for replica in node.replicas() {
+                        rows.push(vec![
+                            node.config.database.clone(),
+                            format!("{}:{}", replica.config.host, replica.config.port),
+                            replica.config.weight.to_string(),
+                            format!("{:.2}", replica.warmup_factor()),
+                            replica.is_quarantined().to_string(),
+                            replica.is_draining().to_string(),
+                            replica.is_healthy().to_string(),
+                            replica.in_use().to_string(),
+                            replica.replication_lag().map_or("unknown".to_string(), |lag| lag.as_millis().to_string()),
+                        ]);
+                    }
+                }
+                rows_result(&["database", "server", "weight", "warmup", "quarantined", "draining", "healthy", "in_use", "lag_ms"], &rows, "SHOW", client.state())
+            },
+            AdminCommand::ShowPlugins => {
+                let rows: Vec<_> = event_listeners().into_iter()
+                    .map(|(event, plugin, enabled)| vec![event.to_string(), plugin.to_string(), enabled.to_string()])
+                    .collect();
+                rows_result(&["event", "plugin", "enabled"], &rows, "SHOW", client.state())
+            },
+            AdminCommand::Select(RiverdbFunction::Stats) => {
+                let functions = [RiverdbFunction::Version, RiverdbFunction::Uptime, RiverdbFunction::SessionId, RiverdbFunction::LastRoute];
+                let columns: Vec<_> = functions.iter().map(|f| &f.name()["riverdb_".len()..]).collect();
+                let row = functions.iter().map(|f| f.value(client)).collect();
+                rows_result(&columns, &[row], "SELECT 1", client.state())
+            },
+            AdminCommand::Select(function) => {
+                rows_result(&[function.name()], &[vec![function.value(client)]], "SELECT 1", client.state())
+            },
+            AdminCommand::Reload => {
+                match Reloader::singleton().reload() {
+                    Ok(_) => command_result("RELOAD", client.state()),
+                    // The config is unchanged, the session can continue
+                    Err(e) => error_result(error_codes::CONFIG_FILE_ERROR, &format!("RELOAD failed: {}", e), client.state()),
+                }
+            },
+            AdminCommand::DumpState => {
+                match client.cluster() {
+                    Some(cluster) => {
+                        dump_state(client.connections(), cluster);
+                        command_result("DUMP", client.state())
+                    },
+                    None => error_result(error_codes::OBJECT_NOT_IN_PREREQUISITE_STATE, "DUMP STATE requires a cluster", client.state()),
+                }
+            },
+            AdminCommand::DrainServer{server, timeout_seconds} => {
+                let cluster = match client.cluster() {
+                    Some(cluster) => cluster,
+                    None => return client.send(error_result(error_codes::OBJECT_NOT_IN_PREREQUISITE_STATE, "DRAIN SERVER requires a cluster", client.state())).await.map(|_| ()),
+                };
+                let pools = cluster.pools_by_address(&server);
+                if pools.is_empty() {
+                    let msg = format!("DRAIN SERVER unknown server {}", server);
+                    return client.send(error_result(error_codes::UNDEFINED_OBJECT, &msg, client.state())).await.map(|_| ());
+                }
+                let timeout = Duration::from_secs(timeout_seconds.unwrap_or(cluster.config.drain_timeout_seconds) as u64);
+                let mut rows = Vec::with_capacity(pools.len());
+                for pool in pools {
+                    let (waited, closed) = drain_pool(client, pool, &server, timeout).await?;
+                    rows.push(vec![server.clone(), waited.as_secs().to_string(), closed.to_string()]);
+                }
+                rows_result(&["server", "waited_seconds", "closed_connections"], &rows, "DRAIN", client.state())
+            },
+            AdminCommand::SetPluginEnabled{plugin, event, enabled} => {
+                let command = if enabled { "ENABLE" } else { "DISABLE" };
+                let changed = set_listener_enabled(&plugin, event.as_deref(), enabled);
+                if changed == 0 {
+                    let msg = format!("{} PLUGIN no listeners for plugin {} on event {}", command, plugin, event.as_deref().unwrap_or("*"));
+                    return client.send(error_result(error_codes::UNDEFINED_OBJECT, &msg, client.state())).await.map(|_| ());
+                }
+                warn!(%plugin, ?event, enabled, changed, "changed plugin listeners");
+                command_result(command, client.state())
+            },
+            AdminCommand::ShowLogLevel => {
+                match log_filter() {
+                    Ok(filter) => rows_result(&["log_level"], &[vec![filter]], "SHOW", client.state()),
+                    Err(e) => error_result(error_codes::OBJECT_NOT_IN_PREREQUISITE_STATE, &format!("SHOW LOG LEVEL failed: {}", e), client.state()),
+                }
+            },
+            AdminCommand::SetLogLevel(filter) => {
+                match set_log_filter(&filter) {
+                    Ok(()) => {
+                        warn!(%filter, "changed log level");
+                        command_result("SET", client.state())
+                    },
+                    Err(e) => error_result(error_codes::INVALID_PARAMETER_VALUE, &format!("SET LOG LEVEL failed: {}", e), client.state()),
+                }
+            },
+            AdminCommand::Pause | AdminCommand::Resume => {
+                let (command, msg) = if self == AdminCommand::Pause {
+                    ("PAUSE", PeerMessage::Pause)
+                } else {
+                    ("RESUME", PeerMessage::Resume)
+                };
+                match Peers::singleton().broadcast(msg).await {
+                    Ok(()) => command_result(command, client.state()),
+                    // It's already applied to this riverdb
+                    Err(e) => error_result(error_codes::CONNECTION_FAILURE, &format!("{} failed to notify peers: {}", command, e), client.state()),
+                }
+            },
+        };
+        client.send(msgs).await?;
+        Ok(())
+    }
+}
+
+/// Drain pool, sending the client a notice each time the number of connections in use changes.
+/// Returns how long it waited for the sessions using the pool, and the number of connections
+/// that were still in use and closed at the timeout.
+async fn drain_pool(client: &ClientConn, pool: &ConnectionPool, server: &str, timeout: Duration) -> Result<(Duration, usize)> {
+    info!(?pool, ?timeout, "draining server");
+    pool.drain();
+
+    let start = Instant::now();
+    let mut last_remaining = 0;
+    loop {
+        let remaining = pool.connections.len();
+        if remaining == 0 {
+            return Ok((start.elapsed(), 0));
+        }
+        if start.elapsed() >= timeout {
+            warn!(?pool, remaining, "drain timed out, closing connections still in use");
+            pool.close_all();
+            return Ok((start.elapsed(), remaining));
+        }
+        if remaining != last_remaining {
+            let msg = format!("draining {}: waiting on {} connections", server, remaining);
+            client.send(MessageErrorBuilder::new(ErrorSeverity::Notice, error_codes::SUCCESSFUL_COMPLETION, &msg).finish()).await?;
+            last_remaining = remaining;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Returns the value of a single quoted SQL string literal, or None if it isn't one.
+fn string_literal(s: &str) -> Option<String> {
+    let inner = s.strip_prefix('\'')?.strip_suffix('\'')?;
+    Some(inner.replace("''", "'"))
+}
+
+/// Build a complete query result of text columns, ending with CommandComplete and ReadyForQuery.
+pub fn rows_result(columns: &[&str], rows: &[Vec<String>], command: &str, state: ClientState) -> Messages {
+    let mut mb = MessageBuilder::new(Tag::ROW_DESCRIPTION);
+    mb.write_i16(columns.len() as i16);
+    for column in columns {
+        mb.write_str(column);
+        mb.write_i32(0); // table oid
+        mb.write_i16(0); // column attribute number
+        mb.write_i32(TEXT_OID);
+        mb.write_i16(-1); // variable length type
+        mb.write_i32(-1); // type modifier
+        mb.write_i16(0); // text format
+    }
+    for row in rows {
+        mb.add_new(Tag::DATA_ROW);
+        mb.write_i16(row.len() as i16);
+        for value in row {
+            mb.write_i32(value.len() as i32);
+            mb.write_bytes(value.as_bytes());
+        }
+    }
+    mb.add_new(Tag::COMMAND_COMPLETE);
+    finish_command(mb, command, state)
+}
+
+/// Build the result of a command that returns no rows: CommandComplete and ReadyForQuery.
+pub fn command_result(command: &str, state: ClientState) -> Messages {
+    finish_command(MessageBuilder::new(Tag::COMMAND_COMPLETE), command, state)
+}
+
+/// Build the result of a query that failed: ErrorResponse and ReadyForQuery.
+pub fn error_result(code: &str, msg: &str, state: ClientState) -> Messages {
+    let mut mb = MessageBuilder::new(Tag::READY_FOR_QUERY);
+    mb.write_byte(ready_status(state));
+    MessageErrorBuilder::new(ErrorSeverity::Error, code, msg).finish().append(mb.finish())
+}
+
+/// Like error_result, but the error includes a hint (e.g. when to retry.)
+pub fn error_result_with_hint(code: &str, msg: &str, hint: &str, state: ClientState) -> Messages {
+    let mut mb = MessageBuilder::new(Tag::READY_FOR_QUERY);
+    mb.write_byte(ready_status(state));
+    let mut eb = MessageErrorBuilder::new(ErrorSeverity::Error, code, msg);
+    eb.write_field(ErrorFieldTag::MESSAGE_HINT, hint);
+    eb.finish().append(mb.finish())
+}
+
+/// Returns the transaction status byte sent in ReadyForQuery for state.
+fn ready_status(state: ClientState) -> u8 {
+    match state {
+        ClientState::Transaction => b'T',
+        ClientState::FailedTransaction => b'E',
+        _ => b'I',
+    }
+}
+
+/// Write the command tag to the CommandComplete message started in mb, followed by ReadyForQuery.
+fn finish_command(mut mb: MessageBuilder, command: &str, state: ClientState) -> Messages {
+    mb.write_str(command);
+    mb.add_new(Tag::READY_FOR_QUERY);
+    mb.write_byte(ready_status(state));
+    mb.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riverdb::pg::protocol::RowDescription;
+
+    fn query(sql: &str) -> QueryMessage {
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str(sql);
+        QueryMessage::new(mb.finish()).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(AdminCommand::parse(&query("show errors")), Some(AdminCommand::ShowErrors));
+        assert_eq!(AdminCommand::parse(&query("SHOW  Errors;")), Some(AdminCommand::ShowErrors));
+        assert_eq!(AdminCommand::parse(&query("show clients")), Some(AdminCommand::ShowClients));
+        assert_eq!(AdminCommand::parse(&query("show stats")), Some(AdminCommand::ShowStats));
+        assert_eq!(AdminCommand::parse(&query("reset stats;")), Some(AdminCommand::ResetStats));
+        assert_eq!(AdminCommand::parse(&query("show activity;")), Some(AdminCommand::ShowActivity));
+        assert_eq!(AdminCommand::parse(&query("SHOW WAIT EVENTS")), Some(AdminCommand::ShowWaitEvents));
+        assert_eq!(AdminCommand::parse(&query("show replicas;")), Some(AdminCommand::ShowReplicas));
+        assert_eq!(AdminCommand::parse(&query("reload;")), Some(AdminCommand::Reload));
+        assert_eq!(AdminCommand::parse(&query("dump state;")), Some(AdminCommand::DumpState));
+        assert_eq!(AdminCommand::parse(&query("pause")), Some(AdminCommand::Pause));
+        assert_eq!(AdminCommand::parse(&query("RESUME;")), Some(AdminCommand::Resume));
+        assert_eq!(AdminCommand::parse(&query("drain server 'db1:5432'")),
+                   Some(AdminCommand::DrainServer{server: "db1:5432".to_string(), timeout_seconds: None}));
+        assert_eq!(AdminCommand::parse(&query("DRAIN SERVER '10.0.0.1:5432' TIMEOUT 30;")),
+                   Some(AdminCommand::DrainServer{server: "10.0.0.1:5432".to_string(), timeout_seconds: Some(30)}));
+        assert_eq!(AdminCommand::parse(&query("drain server 'db1:5432' timeout soon")), None);
+        assert_eq!(AdminCommand::parse(&query("show plugins")), Some(AdminCommand::ShowPlugins));
+        assert_eq!(AdminCommand::parse(&query("disable plugin 'AuditPlugin';")),
+                   Some(AdminCommand::SetPluginEnabled{plugin: "AuditPlugin".to_string(), event: None, enabled: false}));
+        assert_eq!(AdminCommand::parse(&query("ENABLE PLUGIN 'AuditPlugin' ON 'client_connected'")),
+                   Some(AdminCommand::SetPluginEnabled{plugin: "AuditPlugin".to_string(), event: Some("client_connected".to_string()), enabled: true}));
+        assert_eq!(AdminCommand::parse(&query("disable plugin AuditPlugin")), None);
+        assert_eq!(AdminCommand::parse(&query("show log level")), Some(AdminCommand::ShowLogLevel));
+        assert_eq!(AdminCommand::parse(&query("SET LOG LEVEL 'info,riverdb::pg=debug';")),
+                   Some(AdminCommand::SetLogLevel("info,riverdb::pg=debug".to_string())));
+        assert_eq!(AdminCommand::parse(&query("set log level debug")), None);
+        assert_eq!(AdminCommand::parse(&query("select riverdb_version();")), Some(AdminCommand::Select(RiverdbFunction::Version)));
+        assert_eq!(AdminCommand::parse(&query("SELECT riverdb_stats()")), Some(AdminCommand::Select(RiverdbFunction::Stats)));
+        assert_eq!(AdminCommand::parse(&query("select riverdb_nope()")), None);
+        assert_eq!(AdminCommand::parse(&query("select 1")), None);
+        assert_eq!(AdminCommand::parse(&query("show search_path")), None);
+        assert_eq!(AdminCommand::parse(&query("show errors; select 1")), None);
+    }
+
+    #[test]
+    fn test_requires_admin() {
+        assert!(AdminCommand::ShowClients.requires_admin());
+        assert!(AdminCommand::Reload.requires_admin());
+        assert!(AdminCommand::SetLogLevel("debug".to_string()).requires_admin());
+        assert!(!AdminCommand::Select(RiverdbFunction::Version).requires_admin());
+    }
+
+    #[test]
+    fn test_rows_result() {
+        let rows = vec![vec!["db".to_string(), "1".to_string()]];
+        let msgs = rows_result(&["name", "count"], &rows, "SHOW", ClientState::Transaction);
+        let tags: Vec<Tag> = msgs.iter(0).map(|msg| msg.tag()).collect();
+        assert_eq!(tags, vec![Tag::ROW_DESCRIPTION, Tag::DATA_ROW, Tag::COMMAND_COMPLETE, Tag::READY_FOR_QUERY]);
+
+        let desc = RowDescription::new(msgs.clone()).unwrap();
+        assert_eq!(desc.len(), 2);
+        let field = desc.get(1).unwrap();
+        assert_eq!(field.name().unwrap(), "count");
+        assert_eq!(field.type_oid(), TEXT_OID);
+    }
+
+    #[test]
+    fn test_error_result() {
+        let msgs = error_result(error_codes::FEATURE_NOT_SUPPORTED, "nope", ClientState::Ready);
+        let tags: Vec<Tag> = msgs.iter(0).map(|msg| msg.tag()).collect();
+        assert_eq!(tags, vec![Tag::ERROR_RESPONSE, Tag::READY_FOR_QUERY]);
+    }
+}
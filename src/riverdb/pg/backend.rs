@@ -5,28 +5,33 @@ use std::collections::VecDeque;
 use std::fmt::{Debug, Formatter};
 use std::net::SocketAddr;
 use std::pin::Pin;
-use std::cell::UnsafeCell;
 use std::convert::TryFrom;
+use std::future::Future;
 
 use chrono::{Local, DateTime};
 use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
 use tokio::io::Interest;
-use tokio::sync::Notify;
-use tracing::{error, warn, debug, instrument};
+use tokio::sync::{Notify, Mutex as AsyncMutex};
+use tokio::time::{Instant, Duration, timeout_at};
+use tracing::{error, warn, info, debug, instrument};
 use bytes::Bytes;
 
 use crate::{define_event, query};
 use crate::riverdb::{config, Error, Result};
-use crate::riverdb::config::TlsMode;
-use crate::riverdb::pg::{BackendConnState, ClientConn, Connection, ConnectionPool, Rows, parse_messages};
+use crate::riverdb::config::{TlsMode, ProtocolOptions, NotificationPolicy};
+use crate::riverdb::pg::{BackendConnState, ClientConn, Connection, ConnectionPool, CheckoutTimings, Rows, CancelTarget, Savepoints, send_cancel_request};
+use crate::riverdb::pg::rows::parse_affected_rows;
+use crate::riverdb::pg::sql::Query;
 use crate::riverdb::server::{Transport, Connection as ServerConnection, Connections};
 use crate::riverdb::server;
-use crate::riverdb::pg::connection::{Backlog, RefcountAndFlags};
-use crate::riverdb::pg::backend_state::{BackendState, StateEnum};
-use crate::riverdb::common::{SpscQueue, AtomicRef, coarse_monotonic_now, change_lifetime, AtomicRefCounted, Ark};
+use crate::riverdb::pg::connection::{Backlog, RefcountAndFlags, ReceiveHandle, ReceiveSlot, flush_backlog};
+use crate::riverdb::pg::backend_state::{BackendState, CopyState, StateEnum};
+use crate::riverdb::common::{SpscQueue, AtomicRef, AtomicCell, coarse_monotonic_now, change_lifetime, AtomicRefCounted, Ark, Extensions};
 use crate::riverdb::pg::protocol::{
     ServerParams, Messages, MessageBuilder, MessageParser, Tag, SSL_ALLOWED, PROTOCOL_VERSION,
-    AuthType, PostgresError, hash_md5_password, Message, sasl,
+    AuthType, PostgresError, hash_md5_password, Message, sasl, gssapi, error_codes,
 };
 
 
@@ -34,6 +39,8 @@ const MAX_PENDING_REQUESTS: u32 = 32;
 const CLIENT_REQUEST: u64 = 1;
 const BACKEND_REQUEST: u64 = 2;
 const REQUEST_TYPE_MASK: u64 = 3;
+/// how long session_idle waits for the client to read the rest of its result before disconnecting it
+const FLUSH_CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// An SPSC queue of pending result messages (each Messages entry may contain one or more messages)
 pub type MessageQueue = SpscQueue<Messages, 32>;
@@ -42,25 +49,73 @@ pub type MessageQueue = SpscQueue<Messages, 32>;
 /// All methods are thread-safe unless otherwise documented.
 pub struct BackendConn {
     stream: Transport,
-    parser: UnsafeCell<MessageParser>,
+    receiver: ReceiveSlot,
     /// id is set once and then read-only. Starts as 0.
     id: AtomicU32,
     /// added_to_pool is a course-grained monotonic clock that is 0, or records when this was returned to the pool
     added_to_pool: AtomicU32,
     refcount_and_flags: RefcountAndFlags,
     for_transaction: AtomicBool,
+    session_modified: AtomicBool,
+    /// reset_setting is a setting changed outside a transaction, which reset RESETs even if there's no server_reset_query
+    reset_setting: Mutex<Option<String>>,
+    /// read_only is set when the session default was made read only by check_health_and_set_role
+    read_only: AtomicBool,
+    /// unlisten is set when a NotificationResponse is received while pooled, see the in_pool_notifications setting
+    unlisten: AtomicBool,
+    /// notification_listener is set for the dedicated connection of the pool's NotificationHub
+    notification_listener: AtomicBool,
+    timings: Mutex<CheckoutTimings>,
+    /// the SASL authentication state machine while authenticating with SCRAM-SHA-256
+    scram: Mutex<Option<sasl::ScramSha256>>,
+    /// the GSSAPI security context while authenticating with Kerberos
+    gss: Mutex<Option<gssapi::Gss>>,
     state: BackendConnState,
+    /// the savepoints of the current transaction, see track_savepoints
+    savepoints: Mutex<Savepoints>,
+    /// the COPY sub-protocol state, updated by forward
+    copy_state: AtomicCell<CopyState>,
+    /// set when the database starts a COPY FROM STDIN, until the client sends CopyDone or CopyFail, see is_ignored_sync
+    client_copy_in: AtomicBool,
+    /// the number of Syncs the client sent since its last Execute or Query, see is_ignored_sync
+    syncs_since_execute: AtomicU32,
+    /// set when the client's next Sync won't be answered with a ReadyForQuery, see is_ignored_sync
+    skip_copy_sync: AtomicBool,
     client: Ark<ClientConn>,
+    /// notified when a client is attached, see forward_without_client
+    client_attached: Notify,
     send_backlog: Backlog,
     pool: AtomicRef<'static, ConnectionPool>,
     pending_requests: AtomicU64, // a bitfield identifying client and backend (iterator) requests
+    /// microseconds after started when the oldest pending request was sent (or the previous one completed), or 0
+    request_started: AtomicU64,
+    /// rows returned or affected by the client request being forwarded, from the CommandComplete messages so far
+    request_rows: AtomicU64,
+    /// the results at the start of the next client request of statements added to it by riverdb, see skip_results
+    skipped_results: AtomicU32,
+    /// set if the client request being forwarded returned an ErrorResponse in the messages so far
+    request_failed: AtomicBool,
+    /// set once any of the result of the client request being forwarded was sent to the client
+    request_forwarded: AtomicBool,
+    /// the messages of the client request being forwarded, if it can be retried on another connection (see retry_reads)
+    retry_request: Mutex<Option<Messages>>,
+    /// the request_started of the last request cancelled for exceeding the query_timeout_ms, or 0
+    timed_out_request: AtomicU64,
+    /// held while sending a CancelRequest for the query_timeout_ms, so reset waits for it (see cancel_if_timed_out)
+    cancelling: AsyncMutex<()>,
+    /// the reference point for request_started
+    started: Instant,
     iterator_messages: MessageQueue, // messages queued for Rows iterators
     iterators: SpscQueue<usize, 16>, // rust doesn't allow a pointer type here (*const Notify is not Send, despite Send being implemented for SPSC)
     server_params: Mutex<ServerParams>,
+    /// the _pq_ protocol options requested in the startup message and not rejected by the server
+    protocol_options: Mutex<ServerParams>,
     pid: AtomicI32,
     secret: AtomicI32,
     #[allow(unused)]
     created_at: DateTime<Local>,
+    /// per-connection state attached by plugins, kept while the connection is pooled
+    extensions: Extensions,
     connections: &'static Connections<BackendConn>,
 }
 
@@ -77,38 +132,17 @@ impl BackendConn {
         // XXX: This code is very similar to ClientConn::run_inner.
         // If you change this, you probably need to change that too.
 
+        let mut rx = self.take_receive_handle()?;
         loop {
-            // Safety: we only access self.stream from this thread
-            // Safety: we only access self.stream from this thread
-            let msgs = unsafe { self.recv().await? };
+            let msgs = rx.recv(self, self.client()).await?;
             backend_messages::run(self, msgs).await?;
         }
     }
 
-    /// Return the message parse instance being used by run().
-    /// Safety: this is unsound unless from inside run or a method called by run.
-    unsafe fn parser(&self) -> &mut MessageParser {
-        &mut *self.parser.get()
-    }
-
-    /// recv parses some Messages from the stream.
-    /// Safety: recv can only be called from the run thread, only from inside
-    /// methods called directly or indirectly by self.run(). Marked as unsafe
-    /// because the programmer must enforce that constraint.
-    #[inline]
-    pub async unsafe fn recv(&self) -> Result<Messages> {
-        let parser = self.parser();
-        parse_messages(parser, self, self.client(), false).await
-    }
-
-    /// recv_one parses a single Message from the stream.
-    /// Safety: recv_one can only be called from the run thread, only from inside
-    /// methods called directly or indirectly by self.run(). Marked as unsafe
-    /// because the programmer must enforce that constraint.
-    #[inline]
-    pub async unsafe fn recv_one(&self) -> Result<Messages> {
-        let parser = self.parser();
-        parse_messages(parser, self, self.client(), true).await
+    /// Take the ReceiveHandle used to receive Messages from the database. This is done by run(),
+    /// and temporarily while authenticating. Returns an error if the handle was already taken.
+    pub fn take_receive_handle(&self) -> Result<ReceiveHandle> {
+        self.receiver.take()
     }
 
     /// Send Messages to the connected database
@@ -131,11 +165,15 @@ impl BackendConn {
         while !msgs.is_empty() {
             if pending == 0 {
                 // We don't have any requests in-flight, just forward the messages
+                for msg in msgs.iter(0) {
+                    self.track_copy_state(msg.tag());
+                }
                 return if let Some(client) = client {
+                    #[cfg(debug_assertions)]
+                    client.passthrough_checks().forwarded(msgs.as_slice());
                     client.send(msgs).await
                 } else {
-                    warn!(?msgs, "dropping messages without client");
-                    return Ok(0);
+                    self.forward_without_client(msgs).await
                 };
             }
 
@@ -144,8 +182,26 @@ impl BackendConn {
             let mut wake = false;
             let mut pop = false;
             let request_type = pending & REQUEST_TYPE_MASK;
+            // rows returned or affected by the client request, from its CommandComplete messages
+            let mut rows = 0;
+            let mut failed = false;
+            let mut completed = false;
             for msg in msgs.iter(0) {
+                self.track_copy_state(msg.tag());
                 match msg.tag() {
+                    Tag::COMMAND_COMPLETE if request_type == CLIENT_REQUEST => {
+                        rows += parse_affected_rows(&msg).unwrap_or(0) as u64;
+                    },
+                    Tag::ERROR_RESPONSE if request_type == CLIENT_REQUEST => {
+                        if msg.offset() == 0 && self.can_retry_request() {
+                            let e = PostgresError::new(msgs.split_message(&msg))?;
+                            if is_shutdown_error(&e) {
+                                // Fail the connection without forwarding the error, so the request is retried
+                                return Err(Error::from(e));
+                            }
+                        }
+                        failed = true;
+                    },
                     Tag::ROW_DESCRIPTION => {
                         debug!("forward ROW_DESCRIPTION");
                         // If this is a backend request, this is a new rows result, wake the iterator
@@ -167,6 +223,11 @@ impl BackendConn {
                             },
                         }
 
+                        completed = true;
+                        let rows = self.request_rows.swap(0, Relaxed) + rows;
+                        let failed = self.request_failed.swap(false, Relaxed) || failed;
+                        self.request_completed(pending != 0, request_type == CLIENT_REQUEST, client, rows, failed);
+
                         offset = msg.offset() + msg.len() as usize;
                         // If we didn't notify the iterator above to consume it's messages, now's the last chance
                         pop = request_type == BACKEND_REQUEST;
@@ -176,14 +237,31 @@ impl BackendConn {
                     _ => (),
                 }
             }
+            if !completed {
+                // The request continues in the next messages
+                self.request_rows.fetch_add(rows, Relaxed);
+                if failed {
+                    self.request_failed.store(true, Relaxed);
+                }
+            }
 
             debug!("split to {} out of {} for {}", offset, msgs.len(), if request_type == CLIENT_REQUEST {"client request"} else {"backend request"});
             let out = msgs.split_to(offset);
             if request_type == CLIENT_REQUEST {
+                let out = self.strip_skipped_results(out);
+                // Once any of the result was sent to the client, the request can't be retried
+                self.request_forwarded.store(!completed, Relaxed);
+                if completed {
+                    self.retry_request.lock().unwrap().take();
+                }
                 if let Some(client) = client {
+                    #[cfg(debug_assertions)]
+                    client.passthrough_checks().forwarded(out.as_slice());
+                    client.track_prepared_statements(&out);
+                    client.capture_result(&out).await;
                     sent += client.send(out).await?;
                 } else {
-                    warn!(msgs=?out, "dropping messages without client");
+                    sent += self.forward_without_client(out).await?;
                 }
             } else {
                 debug_assert_eq!(request_type, BACKEND_REQUEST);
@@ -212,6 +290,186 @@ impl BackendConn {
         Ok(sent)
     }
 
+    /// Update the CopyState for a message with tag received from the database.
+    fn track_copy_state(&self, tag: Tag) {
+        self.copy_state.store(self.copy_state.load().next(tag));
+        match tag {
+            Tag::COPY_IN_RESPONSE | Tag::COPY_BOTH_RESPONSE => self.client_copy_in.store(true, Relaxed),
+            // The COPY ended without CopyDone or CopyFail, e.g. it failed in a simple Query
+            Tag::READY_FOR_QUERY => self.client_copy_in.store(false, Relaxed),
+            _ => (),
+        }
+    }
+
+    /// Returns true if the database will ignore a Sync the client sends now, instead of answering it with a ReadyForQuery.
+    /// The database ignores Syncs during COPY FROM STDIN, which ends, in the order the client sends messages, with CopyDone
+    /// or CopyFail. Clients using the extended query protocol usually send a Sync right after the Execute that starts
+    /// the COPY. It's counted as the request, since it's sent before the COPY starts, so the Sync after CopyDone isn't.
+    /// Because this doesn't depend on when the COPY completes, a Sync sent after the CommandComplete isn't counted twice.
+    fn is_ignored_sync(&self) -> bool {
+        self.client_copy_in.load(Relaxed) || self.skip_copy_sync.swap(false, Relaxed)
+    }
+
+    /// Drop the results of count statements added by riverdb to the start of the client request about to be sent,
+    /// e.g. the BEGIN held until the next statement (see unbuffered_begin.) These are the first count ParseComplete,
+    /// BindComplete, and CommandComplete messages of its result. If one of them fails, the ErrorResponse is forwarded.
+    /// Must only be called when there are no pending requests.
+    pub fn skip_results(&self, count: u32) {
+        self.skipped_results.store(count, Relaxed);
+    }
+
+    /// Returns msgs, part of the result of a client request, without the results counted by skip_results.
+    fn strip_skipped_results(&self, msgs: Messages) -> Messages {
+        let mut skip = self.skipped_results.load(Relaxed);
+        if skip == 0 {
+            return msgs;
+        }
+        let mut out = Messages::default();
+        let mut start = 0;
+        for msg in msgs.iter(0) {
+            match msg.tag() {
+                _ if skip == 0 => break,
+                Tag::PARSE_COMPLETE | Tag::BIND_COMPLETE | Tag::COMMAND_COMPLETE => {
+                    out = out.append(msgs.slice(start, msg.offset()));
+                    start = msg.offset() + msg.len() as usize;
+                    skip -= 1;
+                },
+                // The database skips the rest of the request after an error
+                Tag::ERROR_RESPONSE | Tag::READY_FOR_QUERY => skip = 0,
+                _ => (),
+            }
+        }
+        self.skipped_results.store(skip, Relaxed);
+        out.append(msgs.slice(start, msgs.len() as usize))
+    }
+
+    /// Remember the messages of the client request about to be sent, so it can be retried on another
+    /// connection if this one fails before any of the result is sent to the client (see retry_reads.)
+    /// Must only be called when there are no pending requests.
+    pub fn set_retry_request(&self, msgs: Messages) {
+        self.request_forwarded.store(false, Relaxed);
+        *self.retry_request.lock().unwrap() = Some(msgs);
+    }
+
+    /// Returns true if the only pending request is a client request that can be retried,
+    /// and none of its result has been sent to the client.
+    fn can_retry_request(&self) -> bool {
+        self.pending_requests.load(Relaxed) == CLIENT_REQUEST
+            && !self.request_forwarded.load(Relaxed)
+            && self.retry_request.lock().unwrap().is_some()
+    }
+
+    /// Retry the client request on another connection, if it can be retried (see set_retry_request.)
+    /// Called after run fails. Returns true if the request was sent on another connection.
+    /// The future is boxed because it's awaited by the task running the connection, which is
+    /// spawned when checking out a connection, which this may do.
+    pub fn retry_failed_request(&self) -> Pin<Box<dyn Future<Output=bool> + Send + '_>> {
+        Box::pin(async move {
+            if !self.can_retry_request() {
+                return false;
+            }
+            let msgs = match self.retry_request.lock().unwrap().take() {
+                Some(msgs) => msgs,
+                None => return false,
+            };
+            let client_ark = self.client.clone();
+            let client = match client_ark.load() {
+                Some(client) => client,
+                None => return false,
+            };
+            match client.retry_query(self, msgs).await {
+                Ok(()) => {
+                    info!(client=client.id(), "retried query on another connection after the connection failed");
+                    self.client.take();
+                    true
+                },
+                Err(e) => {
+                    warn!(?e, client=client.id(), "could not retry query on another connection");
+                    false
+                },
+            }
+        })
+    }
+
+    /// Called after run panicked. The connection may have been left in any state, so log the panic,
+    /// close it, and send the attached client (if any) an error and close its session too.
+    /// The task that ran the connection then removes it from the pool.
+    pub async fn panicked(&self, panic: String) {
+        let client_ark = self.client.take();
+        let client = client_ark.load();
+        error!(%panic, id = self.id(), state = ?self.state(), pool = ?self.pool(), client_id = ?client.map(|client| client.id()),
+            "backend connection panicked");
+        self.close();
+        if let Some(client) = client {
+            if !client.is_closed() {
+                let err_msg = Messages::new_error(error_codes::SYSTEM_ERROR, "riverdb internal error, closing the connection");
+                let _ = client.send(err_msg).await;
+                client.close();
+            }
+        }
+    }
+
+    /// Called by forward for messages that arrive while no client is attached. Holds them for up to
+    /// the dropped_messages_grace_ms waiting for a client to be attached and sends them to it,
+    /// otherwise counts them and runs the backend_dropped_messages plugins.
+    async fn forward_without_client(&self, msgs: Messages) -> Result<usize> {
+        let pool = self.pool();
+        let grace_ms = pool.and_then(|pool| pool.config.cluster).map_or(0, |cluster| cluster.dropped_messages_grace_ms);
+        if grace_ms != 0 {
+            let deadline = Instant::now() + Duration::from_millis(grace_ms as u64);
+            loop {
+                // Create the future before checking the client, so set_client can't be missed in between
+                let attached = self.client_attached.notified();
+                if let Some(client) = self.client() {
+                    if let Some(pool) = pool {
+                        pool.record_late_messages(msgs.count() as u32);
+                    }
+                    return client.send(msgs).await;
+                }
+                if timeout_at(deadline, attached).await.is_err() {
+                    break;
+                }
+            }
+        }
+        if let Some(pool) = pool {
+            pool.record_dropped_messages(msgs.count() as u32, msgs.len());
+        }
+        backend_dropped_messages::run(self, &msgs).await?;
+        Ok(0)
+    }
+
+    /// Called by the backend_dropped_messages plugins when messages from the database are dropped
+    /// because no client is attached to receive them. Logs a warning by default.
+    #[instrument]
+    pub async fn backend_dropped_messages(&self, _: &mut backend_dropped_messages::Event, msgs: &Messages) -> Result<()> {
+        warn!(?msgs, "dropping messages without client");
+        Ok(())
+    }
+
+    /// Record the latency of a completed request with the pool, if it was a client request,
+    /// and with the slow query log of the client's cluster if it exceeded the slow_query threshold_ms.
+    /// If more requests are pending, the next one is timed from now, since the database processes them in order.
+    fn request_completed(&self, more_pending: bool, is_client_request: bool, client: Option<&ClientConn>, rows: u64, failed: bool) {
+        let now = self.micros_since_started();
+        let started = self.request_started.swap(if more_pending { now } else { 0 }, Relaxed);
+        if !is_client_request {
+            return;
+        }
+        let latency = if started != 0 { Some(Duration::from_micros(now.saturating_sub(started))) } else { None };
+        if let (Some(pool), Some(latency)) = (self.pool(), latency) {
+            pool.latency().record(latency);
+        }
+        if let Some(client) = client {
+            client.query_completed(failed);
+            client.record_query_latency(latency, rows);
+        }
+    }
+
+    /// Returns the microseconds since this connection was created, never 0.
+    fn micros_since_started(&self) -> u64 {
+        self.started.elapsed().as_micros() as u64 + 1
+    }
+
     /// Test authentication with these credentials against the target database.
     /// For test purposes or for checking credentials or database health.
     pub async fn test_auth<'a, 'b: 'a, 'c: 'a>(&'a self, user: &'b str, password: &'c str, pool: &'static ConnectionPool) -> Result<()> {
@@ -231,13 +489,21 @@ impl BackendConn {
 
     /// Services the connections asynchronously until state is reached.
     /// Like run() but stops once the desired BackendState has been attained.
+    /// Must be called before run(), which takes ownership of the ReceiveHandle.
     async fn run_until_state(&self, state: BackendState) -> Result<()> {
+        let mut rx = self.take_receive_handle()?;
+        let mut result = Ok(());
         while self.state() != state {
-            // Safety: This method is not called concurrently with run()
-            let msgs = unsafe { self.recv().await? };
-            backend_messages::run(self, msgs).await?;
+            result = match rx.recv(self, self.client()).await {
+                Ok(msgs) => backend_messages::run(self, msgs).await,
+                Err(e) => Err(e),
+            };
+            if result.is_err() {
+                break;
+            }
         }
-        Ok(())
+        self.receiver.put(rx);
+        result
     }
 
     /// Complete the TLS connection if necessary and send the startup message
@@ -262,10 +528,19 @@ impl BackendConn {
         }
 
         let cluster = pool.config.cluster.unwrap();
+        // Request the protocol options of the client this connection is being opened for, see set_protocol_options
+        if cluster.protocol_options == ProtocolOptions::Passthrough {
+            for (key, value) in self.protocol_options.lock().unwrap().iter() {
+                params.add(key.clone(), value.clone());
+            }
+        }
+
         match cluster.backend_tls {
             TlsMode::Disabled | TlsMode::Invalid => (),
             _ => {
+                let start = Instant::now();
                 self.ssl_handshake(pool, cluster).await?;
+                self.timings.lock().unwrap().tls_time = start.elapsed();
             }
         }
 
@@ -288,9 +563,10 @@ impl BackendConn {
                 let tls_config = cluster.backend_tls_config.clone().unwrap();
                 self.stream.upgrade_client(tls_config, cluster.backend_tls, pool.config.tls_host.as_str()).await
             } else if let TlsMode::Prefer = cluster.backend_tls {
-                Err(Error::new(format!("{} does not support TLS", pool.config.address.as_ref().unwrap())))
-            } else {
+                // Fall back to plaintext
                 Ok(())
+            } else {
+                Err(Error::new(format!("{} does not support TLS", pool.config.address.as_ref().unwrap())))
             }
         } else {
             unreachable!(); // readable, but not a single byte could be read? Not possible.
@@ -300,6 +576,15 @@ impl BackendConn {
     /// Called when a backend connection is idle
     /// (no queries pending, no transaction in progress, session clean.)
     pub async fn session_idle(&self, client: &ClientConn) -> Result<()> {
+        // Once released, nothing else flushes the backlog of the client (its task may only be
+        // waiting to read), so finish writing the result first. A client that doesn't read it is disconnected.
+        match timeout_at(Instant::now() + FLUSH_CLIENT_TIMEOUT, flush_backlog(client)).await {
+            Ok(result) => result?,
+            Err(_) => {
+                warn!(client=client.id(), "client didn't read the result within {:?}, closing the connection", FLUSH_CLIENT_TIMEOUT);
+                client.close();
+            },
+        }
         let conn = client.session_idle().await?;
         Self::return_to_pool(conn).await;
         Ok(())
@@ -313,34 +598,87 @@ impl BackendConn {
         }
     }
 
-    /// Reset the connection prior to returning it to the pool
+    /// Reset the connection prior to returning it to the pool by running the cluster's
+    /// server_reset_query. This is skipped if the connection only executed simple reads,
+    /// unless server_reset_query_always is set. Open transactions are always rolled back,
+    /// and a setting passed to reset_on_return is always RESET.
     pub async fn reset(&self) -> Result<()> {
-        // TODO(optimization) track how SET was used and if there's nothing to reset, no need to call RESET ALL
-
-        let reset = if self.state().is_transaction() {
-            query!("ROLLBACK; RESET ROLE; RESET ALL",)
-        } else {
-            query!("RESET ROLE; RESET ALL",)
+        // Wait for a CancelRequest for the query_timeout_ms, so it can't cancel the next client's query
+        drop(self.cancelling.lock().await);
+        // Don't carry the COPY state over to the next client
+        self.client_copy_in.store(false, Relaxed);
+        self.skip_copy_sync.store(false, Relaxed);
+        self.skipped_results.store(0, Relaxed);
+
+        let cluster = self.pool.load().and_then(|pool| pool.config.cluster);
+        let (reset_query, always) = match cluster {
+            Some(cluster) => (cluster.server_reset_query.as_str(), cluster.server_reset_query_always),
+            None => ("RESET ROLE; RESET ALL", true),
         };
 
-        self.execute(reset).await?;
+        let in_transaction = self.state().is_transaction();
+        let savepoints = std::mem::take(&mut *self.savepoints.lock().unwrap());
+        let session_modified = self.session_modified.swap(false, Relaxed);
+        let run_reset = !reset_query.is_empty() && (always || session_modified || in_transaction);
+        let reset_setting = self.reset_setting.lock().unwrap().take();
+        if !in_transaction && !run_reset && reset_setting.is_none() {
+            return Ok(());
+        }
+
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        if in_transaction {
+            // ROLLBACK the whole transaction, ROLLBACK TO SAVEPOINT would leave it open
+            debug!(state=?self.state(), savepoints=savepoints.depth(), "rolling back the transaction left open by the client");
+            mb.write_bytes(b"ROLLBACK; ");
+        }
+        if let Some(setting) = &reset_setting {
+            // The server_reset_query may not be RESET ALL, or may be empty
+            mb.write_bytes(b"RESET ");
+            mb.write_bytes(setting.as_bytes());
+            if run_reset {
+                mb.write_bytes(b"; ");
+            }
+        }
+        if run_reset {
+            mb.write_bytes(reset_query.as_bytes());
+        }
+        mb.write_byte(0);
+
+        self.execute(mb.finish()).await?;
         Ok(())
     }
 
     /// Checks that the database connection is healthy and sets the role and application.
-    pub async fn check_health_and_set_role(&self, application_name: &str, role: &str) -> Result<()> {
+    /// If read_only, transactions default to read only until the next check (see config.replica_read_only.)
+    pub async fn check_health_and_set_role(&self, application_name: &str, role: &str, read_only: bool) -> Result<()> {
         if self.state() == BackendState::InPool {
             self.transition(BackendState::Ready)?;
             self.added_to_pool.store(0, Relaxed);
         }
 
+        // Stop listening for notifications requested by a previous client (see in_pool_notifications)
+        if self.unlisten.swap(false, Relaxed) {
+            let mut mb = MessageBuilder::new(Tag::QUERY);
+            mb.write_str("UNLISTEN *");
+            self.execute(mb.finish()).await?;
+        }
+
         // Safety: I don't know why this is required here. Rust bug?
         let role: &'static str = unsafe { change_lifetime(role) };
         let application_name: &'static str = unsafe { change_lifetime(application_name) };
+        // Set it every time when read_only, because the server_reset_query may have reset it,
+        // and undo it if the pool is no longer read only (the replica was promoted to master.)
+        let characteristics = match (read_only, self.read_only.swap(read_only, Relaxed)) {
+            (true, _) => "; SET SESSION CHARACTERISTICS AS TRANSACTION READ ONLY",
+            (false, true) => "; SET SESSION CHARACTERISTICS AS TRANSACTION READ WRITE",
+            (false, false) => "",
+        };
         let check = if role.is_empty() {
-            query!("SET application_name TO {}", application_name)
+            let sql = format!("SET application_name TO {{}}{}", characteristics);
+            query!(sql.as_str(), application_name)
         } else {
-            query!("SET ROLE {}; SET application_name TO {}", role, application_name)
+            let sql = format!("SET ROLE {{}}; SET application_name TO {{}}{}", characteristics);
+            query!(sql.as_str(), role, application_name)
         };
 
         self.execute(check).await?;
@@ -349,11 +687,14 @@ impl BackendConn {
 
     /// Issue a query and return a Rows iterator over the results. You must call Rows::next()
     /// until it returns false or Rows::finish() to consume the entire result, even if you
-    /// don't intend to use it.
+    /// don't intend to use it. The query is either a single Query message, or the extended
+    /// query protocol messages for a single statement ending in one Sync (see query_with_params.)
     #[must_use = "you must call Rows::next() until it returns false or Rows::finish() to consume the entire result"]
     pub async fn query<'a>(&'a self, escaped_query: Messages) -> Result<Pin<Box<Rows<'a>>>> {
-        if escaped_query.count() != 1 {
-            return Err(Error::new("query expects exactly one Message"));
+        let requests = escaped_query.iter(0).filter(|msg| msg.tag() == Tag::QUERY || msg.tag() == Tag::SYNC).count();
+        let is_simple = escaped_query.first().map_or(false, |msg| msg.tag() == Tag::QUERY);
+        if requests != 1 || (is_simple && escaped_query.count() != 1) {
+            return Err(Error::new("query expects exactly one Query message or extended query messages ending in Sync"));
         }
         let rows = Box::pin(Rows::new(self));
         let notifier = rows.as_ref().notifier() as usize;
@@ -362,6 +703,14 @@ impl BackendConn {
         Ok(rows)
     }
 
+    /// Issue a query with parameters using the extended query protocol, and return a Rows iterator
+    /// over the results, see query. The parameters are sent in text format, None is NULL.
+    /// The parameters are never interpolated into sql, so they don't need to be escaped.
+    #[must_use = "you must call Rows::next() until it returns false or Rows::finish() to consume the entire result"]
+    pub async fn query_with_params<'a>(&'a self, sql: &str, params: &[Option<&str>]) -> Result<Pin<Box<Rows<'a>>>> {
+        self.query(extended_query(sql, params)?).await
+    }
+
     /// Issue a command and wait for the result. If this is awaited with other query/execute
     /// futures then it will pipeline the queries. Returns the number of affected rows.
     pub async fn execute(&self, escaped_query: Messages) -> Result<i32> {
@@ -379,6 +728,27 @@ impl BackendConn {
         self.state.transition(self, new_state)
     }
 
+    /// Returns the number of seconds since the connection was established.
+    pub fn age_seconds(&self) -> u32 {
+        self.started.elapsed().as_secs() as u32
+    }
+
+    /// Returns the ConnectionPool this connection belongs to, if any.
+    pub fn pool(&self) -> Option<&'static ConnectionPool> {
+        self.pool.load()
+    }
+
+    /// Returns the state attached to this connection by plugins, see Extensions.
+    /// Unlike the extensions of a ClientConn, these outlive the client session using the connection.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Returns the BackendKeyData (process id and secret key) sent by the database, used for cancelling queries.
+    pub fn backend_key(&self) -> (i32, i32) {
+        (self.pid.load(Relaxed), self.secret.load(Relaxed))
+    }
+
     /// Returns the associated ClientConn, if any.
     pub fn client(&self) -> Option<&ClientConn> {
         self.client.load()
@@ -387,6 +757,7 @@ impl BackendConn {
     /// Sets the associated ClientConn.
     pub fn set_client(&self, client: Ark<ClientConn>) {
         self.client.store(client);
+        self.client_attached.notify_waiters();
     }
 
     /// Returns true if this connection was created for use in a transaction.
@@ -401,6 +772,44 @@ impl BackendConn {
         self.for_transaction.store(value, Relaxed)
     }
 
+    /// Returns true if this is the dedicated connection used to LISTEN on behalf of clients, see NotificationHub.
+    pub fn is_notification_listener(&self) -> bool {
+        self.notification_listener.load(Relaxed)
+    }
+
+    /// Marks this as the dedicated connection used to LISTEN on behalf of clients.
+    pub(crate) fn set_notification_listener(&self, value: bool) {
+        self.notification_listener.store(value, Relaxed)
+    }
+
+    /// Returns the timings from the last time this connection was checked out of the pool.
+    pub fn checkout_timings(&self) -> CheckoutTimings {
+        *self.timings.lock().unwrap()
+    }
+
+    /// Sets the timings for checking out this connection from the pool.
+    pub(crate) fn set_checkout_timings(&self, timings: CheckoutTimings) {
+        *self.timings.lock().unwrap() = timings;
+    }
+
+    /// Returns true if a query that may have changed the session state was sent on this connection
+    /// since it was last reset.
+    pub fn session_modified(&self) -> bool {
+        self.session_modified.load(Relaxed)
+    }
+
+    /// Marks this connection as having (possibly) changed session state, so it must be reset
+    /// with the server_reset_query before it's returned to the pool.
+    pub fn set_session_modified(&self) {
+        self.session_modified.store(true, Relaxed)
+    }
+
+    /// Marks setting as changed with SET for the session, so it's RESET before this connection is
+    /// returned to the pool, whether or not there's a server_reset_query.
+    pub fn reset_on_return(&self, setting: &str) {
+        *self.reset_setting.lock().unwrap() = Some(setting.to_string());
+    }
+
     /// Returns true if this connection is assigned to the pool (inactive).
     pub fn in_pool(&self) -> bool {
         if let BackendState::InPool = self.state() {
@@ -411,6 +820,38 @@ impl BackendConn {
         }
     }
 
+    /// Update the savepoints of the current transaction from the client query about to be sent.
+    pub fn track_savepoints(&self, query: &Query) {
+        self.savepoints.lock().unwrap().apply(query);
+    }
+
+    /// Returns the number of active savepoints in the current transaction.
+    pub fn savepoint_depth(&self) -> usize {
+        self.savepoints.lock().unwrap().depth()
+    }
+
+    /// Transition to the Ready, Transaction, or FailedTransaction state from the status of the last
+    /// ReadyForQuery in msgs, if any. The savepoints are forgotten when the transaction ends.
+    fn update_transaction_status(&self, msgs: &Messages) -> Result<()> {
+        let state = self.state();
+        if state != BackendState::Ready && !state.is_transaction() {
+            return Ok(());
+        }
+        let status = match msgs.iter(0).filter(|msg| msg.tag() == Tag::READY_FOR_QUERY).last() {
+            Some(msg) => msg.reader().read_byte(),
+            None => return Ok(()),
+        };
+        let new_state = match status {
+            b'T' => BackendState::Transaction,
+            b'E' => BackendState::FailedTransaction,
+            _ => {
+                self.savepoints.lock().unwrap().clear();
+                BackendState::Ready
+            },
+        };
+        self.transition(new_state)
+    }
+
     /// Set the connection state to InPool and update the added_to_pool timestamp.
     pub fn set_in_pool(&self) -> bool {
         // See ConnectionPool::put, which calls reset() before this.
@@ -428,11 +869,73 @@ impl BackendConn {
         self.server_params.lock().unwrap()
     }
 
+    /// Return the _pq_ protocol options negotiated with the server.
+    pub fn protocol_options(&self) -> MutexGuard<'_, ServerParams> {
+        self.protocol_options.lock().unwrap()
+    }
+
+    /// Set the _pq_ protocol options to request when authenticating the connection, see ConnectionPool::get_reporting_waits.
+    pub(crate) fn set_protocol_options(&self, options: ServerParams) {
+        *self.protocol_options.lock().unwrap() = options;
+    }
+
     /// Return the number of pending requests (queries).
     pub fn pending_requests(&self) -> u32 {
         self.pending_requests.load(Relaxed).count_ones()
     }
 
+    /// Returns the raw pending requests bitfield, two bits per request identifying client and backend requests.
+    pub fn pending_requests_bitfield(&self) -> u64 {
+        self.pending_requests.load(Relaxed)
+    }
+
+    /// Returns how long the database has been processing the oldest pending request, if it's a client request.
+    /// Pipelined requests are processed in order, so each is timed from when the previous one completed.
+    pub fn client_request_elapsed(&self) -> Option<Duration> {
+        let started = self.request_started.load(Relaxed);
+        if started == 0 || self.pending_requests.load(Relaxed) & REQUEST_TYPE_MASK != CLIENT_REQUEST {
+            return None;
+        }
+        Some(Duration::from_micros(self.micros_since_started().saturating_sub(started)))
+    }
+
+    /// Cancels the client request being processed by the database if it has been running longer than
+    /// query_timeout, by sending a CancelRequest with the backend key data. The database then fails the query
+    /// with QUERY_CANCELED (57014), which is forwarded to the client after a warning explaining why.
+    /// Each request is only cancelled once. Returns true if a CancelRequest was sent.
+    /// A CancelRequest cancels whatever the database is running when it arrives, so this connection isn't
+    /// reset and returned to the pool until it was delivered, and it's not sent if the request completed meanwhile.
+    pub async fn cancel_if_timed_out(&self, query_timeout: Duration) -> Result<bool> {
+        let _cancelling = self.cancelling.lock().await;
+        match self.client_request_elapsed() {
+            Some(elapsed) if elapsed >= query_timeout => (),
+            _ => return Ok(false),
+        }
+        let started = self.request_started.load(Relaxed);
+        if self.timed_out_request.swap(started, Relaxed) == started {
+            return Ok(false);
+        }
+        let address = match self.pool().and_then(|pool| pool.config.address) {
+            Some(address) => address,
+            None => return Ok(false),
+        };
+        let (pid, secret) = self.backend_key();
+        if let Some(client) = self.client() {
+            let msg = format!("cancelling query that exceeded the query_timeout_ms of {}", query_timeout.as_millis());
+            client.send(Messages::new_warning(error_codes::QUERY_CANCELED, &msg)).await?;
+        }
+        if self.request_started.load(Relaxed) != started || self.client_request_elapsed().is_none() {
+            return Ok(false);
+        }
+        send_cancel_request(&CancelTarget{address, pid, secret}).await?;
+        Ok(true)
+    }
+
+    /// Returns the current CopyState, if a COPY is in progress.
+    pub fn copy_state(&self) -> CopyState {
+        self.copy_state.load()
+    }
+
     /// Pop and return some Messages from the result queue, "blocking" if
     pub(crate) async fn iterator_messages(&self) -> Messages {
         self.iterator_messages.pop().await
@@ -497,8 +1000,12 @@ impl BackendConn {
                     break;
                 },
                 BackendState::InPool => {
+                    let pool = self.pool.load();
                     let mut params = self.server_params.lock().unwrap();
                     for msg in msgs.iter(0) {
+                        if let Some(pool) = pool {
+                            pool.record_in_pool_message(msg.tag());
+                        }
                         match msg.tag() {
                             Tag::PARAMETER_STATUS => {
                                 let mut r = msg.reader();
@@ -509,15 +1016,41 @@ impl BackendConn {
                             Tag::ERROR_RESPONSE => {
                                 return Err(Error::from(PostgresError::new(msgs.split_message(&msg))?));
                             },
+                            Tag::NOTIFICATION_RESPONSE => {
+                                // There's no client to deliver it to, a previous client must have run LISTEN
+                                let policy = pool.and_then(|pool| pool.config.cluster)
+                                    .map_or(NotificationPolicy::default(), |cluster| cluster.in_pool_notifications);
+                                debug!(?msg, ?policy, "dropping notification for pooled connection");
+                                match policy {
+                                    NotificationPolicy::Drop => (),
+                                    NotificationPolicy::Unlisten => self.unlisten.store(true, Relaxed),
+                                    NotificationPolicy::Close => {
+                                        return Err(Error::new("closing pooled connection that received a notification"));
+                                    },
+                                }
+                            },
+                            Tag::NOTICE_RESPONSE => {
+                                info!(?msg, "notice received by pooled connection");
+                            },
                             _ => {
-                                // Else ignore the message
-                                error!(?msg, "ignoring unexpected message");
+                                // We don't know what state the connection is in, don't reuse it
+                                return Err(Error::new(format!("unexpected message for pooled connection: {:?}", msg)));
                             },
                         }
                     }
                     break;
                 },
                 _ => {
+                    if self.is_notification_listener() {
+                        if let Some(pool) = self.pool.load() {
+                            msgs = pool.notifications().dispatch(pool, msgs)?;
+                            if msgs.is_empty() {
+                                break;
+                            }
+                        }
+                    }
+                    self.dispatch_errors(&msgs).await?;
+                    self.update_transaction_status(&msgs)?;
                     // Forward the message to the client, if there is one
                     // Safety: this is safe to call from the run() thread, and backend_messages is called by run().
                     self.forward(msgs).await?;
@@ -529,6 +1062,36 @@ impl BackendConn {
         Ok(())
     }
 
+    /// Runs the backend_error plugins for each ErrorResponse in msgs.
+    async fn dispatch_errors(&self, msgs: &Messages) -> Result<()> {
+        for msg in msgs.iter(0) {
+            if msg.tag() == Tag::ERROR_RESPONSE {
+                let error = PostgresError::new(msgs.split_message(&msg))?;
+                let client = self.client();
+                let query = client.and_then(|client| client.last_query());
+                backend_error::run(self, client, &error, query.as_deref()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Called by the backend_error plugins when an ErrorResponse is received from the database
+    /// while processing a query. Counts the error in the cluster ErrorStats by default,
+    /// the error is still forwarded to the client.
+    #[instrument]
+    pub async fn backend_error(&self, _: &mut backend_error::Event, client: Option<&ClientConn>, error: &PostgresError, _query: Option<&Query>) -> Result<()> {
+        debug!(code=error.code(), message=error.message(), "backend error");
+        if let Some(client) = client {
+            if let Some(cluster) = client.cluster() {
+                let params = client.connection_params();
+                let database = params.get("database").unwrap_or("");
+                let user = params.get("user").unwrap_or("");
+                cluster.error_stats.record(database, user, error.code());
+            }
+        }
+        Ok(())
+    }
+
     /// Called by the backend_authenticate plugins to authenticate with the database based on
     /// the auth challenge received in msgs.
     #[instrument]
@@ -555,7 +1118,8 @@ impl BackendConn {
 
                 match auth_type {
                     AuthType::Ok => {
-                        // Success!
+                        // Success! The GSSAPI security context isn't used after authentication
+                        self.gss.lock().unwrap().take();
                         self.transition(BackendState::Startup)
                     },
                     AuthType::ClearText => {
@@ -586,9 +1150,32 @@ impl BackendConn {
                     AuthType::SASL => {
                         self.sasl_auth(msg, user, password).await
                     },
-                    _ => Err(Error::new(format!("unsupported authentication scheme (use SASL, MD5, or plaintext over SSL) {}", auth_type)))
+                    AuthType::SASLContinue => self.sasl_continue(msgs).await,
+                    AuthType::SASLFinal => self.sasl_final(msgs),
+                    AuthType::GSS => self.gss_auth().await,
+                    AuthType::GSSContinue => self.gss_continue(msg).await,
+                    _ => Err(Error::new(format!("unsupported authentication scheme (use SASL, MD5, GSSAPI, or plaintext over SSL) {}", auth_type)))
                 }
             },
+            Tag::NEGOTIATE_PROTOCOL_VERSION => {
+                // The server doesn't support some of the requested protocol options, authentication continues
+                let mut r = msg.reader();
+                let _minor_version = r.read_i32();
+                let count = r.read_i32();
+                r.error()?;
+                let mut rejected = Vec::with_capacity(count.max(0) as usize);
+                for _ in 0..count {
+                    rejected.push(r.read_str()?.to_string());
+                }
+                debug!(?rejected, "server rejected protocol options");
+                let mut options = self.protocol_options.lock().unwrap();
+                let mut accepted = ServerParams::new();
+                for (key, value) in options.iter().filter(|(key, _)| !rejected.contains(key)) {
+                    accepted.add(key.clone(), value.clone());
+                }
+                *options = accepted;
+                Ok(())
+            },
             Tag::ERROR_RESPONSE => {
                 Err(Error::from(PostgresError::new(msgs)?))
             },
@@ -596,7 +1183,9 @@ impl BackendConn {
         }
     }
 
-    /// Handles the SASL authentication flow from start to end (sends and receives messages).
+    /// Starts the SASL authentication flow by sending the SASLInitialResponse message.
+    /// The flow continues in backend_authenticate with sasl_continue and sasl_final as
+    /// the server responds.
     pub async fn sasl_auth(&self, msg: Message<'_>, _user: String, password: String) -> Result<()> {
         let mut have_scram_256 = false;
         let mut have_scram_256_plus = false;
@@ -617,14 +1206,12 @@ impl BackendConn {
             }
         }
 
-        // TODO support channel binding for better security when possible
-        let tls_endpoint = vec![];
-
+        // Bind the authentication to the TLS connection, so it can't be relayed by a man in the middle.
+        // Postgres only offers SCRAM-SHA-256-PLUS over TLS, so the server certificate is known.
         let (channel_binding, mechanism) = if have_scram_256_plus {
-            if tls_endpoint.is_empty() {
-                (sasl::ChannelBinding::unsupported(), sasl::SCRAM_SHA_256)
-            } else {
-                (sasl::ChannelBinding::tls_server_end_point(tls_endpoint), sasl::SCRAM_SHA_256_PLUS)
+            match self.stream.tls_server_end_point() {
+                Some(end_point) => (sasl::ChannelBinding::tls_server_end_point(end_point), sasl::SCRAM_SHA_256_PLUS),
+                None => (sasl::ChannelBinding::unsupported(), sasl::SCRAM_SHA_256),
             }
         } else if have_scram_256 {
             (sasl::ChannelBinding::unrequested(), sasl::SCRAM_SHA_256)
@@ -632,7 +1219,7 @@ impl BackendConn {
             return Err(Error::new("unsupported SASL mechanism"));
         };
 
-        let mut scram = sasl::ScramSha256::new(password.as_bytes(), channel_binding);
+        let scram = sasl::ScramSha256::new(password.as_bytes(), channel_binding);
         let sasl_initial = {
             let message_data = scram.message();
             let mut mb = MessageBuilder::new(Tag::PASSWORD_MESSAGE);
@@ -641,23 +1228,62 @@ impl BackendConn {
             mb.write_bytes(message_data);
             mb.finish()
         };
+        *self.scram.lock().unwrap() = Some(scram);
         self.send(sasl_initial).await?;
+        Ok(())
+    }
 
-        // Safety: this is called indirectly from inside run()
-        let msgs = unsafe { self.recv_one() }.await?;
-        scram.update_from_message(msgs)?;
-
+    /// Handles the AuthenticationSASLContinue message by sending the SASLResponse message.
+    async fn sasl_continue(&self, msgs: Messages) -> Result<()> {
         let sasl_continue = {
+            let mut scram = self.scram.lock().unwrap();
+            let scram = scram.as_mut().ok_or_else(|| Error::new("unexpected SASLContinue message"))?;
+            scram.update_from_message(msgs)?;
             let mut mb = MessageBuilder::new(Tag::PASSWORD_MESSAGE);
             mb.write_bytes(scram.message());
             mb.finish()
         };
         self.send(sasl_continue).await?;
+        Ok(())
+    }
 
-        let mut msgs = unsafe { self.recv_one() }.await?;
-        let final_msg = msgs.split_first();
-        scram.update_from_message(final_msg)?;
+    /// Handles the AuthenticationSASLFinal message by verifying the server signature.
+    fn sasl_final(&self, msgs: Messages) -> Result<()> {
+        let mut scram = self.scram.lock().unwrap().take()
+            .ok_or_else(|| Error::new("unexpected SASLFinal message"))?;
+        scram.update_from_message(msgs)
+    }
 
+    /// Starts GSSAPI authentication by sending the initial token in a GSSResponse message.
+    /// The token exchange continues in backend_authenticate with gss_continue, until the server sends AuthenticationOk.
+    async fn gss_auth(&self) -> Result<()> {
+        let config = self.pool.load().ok_or_else(|| Error::new("GSSAPI authentication requires a pool"))?.config;
+        let (service, host) = (config.gss_service_name.clone(), config.host.clone());
+        // Acquiring the credentials and the service ticket may contact the KDC, don't block the runtime
+        let (gss, token) = tokio::task::spawn_blocking(move || gssapi::Gss::new(&service, &host))
+            .await
+            .map_err(Error::new)??;
+        *self.gss.lock().unwrap() = Some(gss);
+        let mut mb = MessageBuilder::new(Tag::PASSWORD_MESSAGE);
+        mb.write_bytes(&token);
+        self.send(mb.finish()).await?;
+        Ok(())
+    }
+
+    /// Handles the AuthenticationGSSContinue message, sending a GSSResponse message if there's a token to reply with.
+    async fn gss_continue(&self, msg: Message<'_>) -> Result<()> {
+        let token = {
+            let mut r = msg.reader();
+            r.advance(4)?; // skip auth_type
+            let mut gss = self.gss.lock().unwrap();
+            let gss = gss.as_mut().ok_or_else(|| Error::new("unexpected GSSContinue message"))?;
+            gss.update(r.read_to_end())?
+        };
+        if let Some(token) = token {
+            let mut mb = MessageBuilder::new(Tag::PASSWORD_MESSAGE);
+            mb.write_bytes(&token);
+            self.send(mb.finish()).await?;
+        }
         Ok(())
     }
 
@@ -669,7 +1295,27 @@ impl BackendConn {
         }
         for msg in msgs.iter(0) {
             match msg.tag() {
-                Tag::QUERY => { // TODO what other tags expect a response?
+                // There will be one ReadyForQuery after COPY FROM STDIN ends for the query that started it
+                Tag::SYNC if from_client && self.is_ignored_sync() => {
+                    if let Some(client) = self.client() {
+                        client.sync_ignored();
+                    }
+                },
+                Tag::COPY_DONE | Tag::COPY_FAIL if from_client => {
+                    if self.client_copy_in.swap(false, Relaxed) {
+                        self.skip_copy_sync.store(self.syncs_since_execute.load(Relaxed) != 0, Relaxed);
+                    }
+                },
+                Tag::EXECUTE if from_client => self.syncs_since_execute.store(0, Relaxed),
+                // Each of these is answered with a ReadyForQuery
+                Tag::QUERY | Tag::SYNC | Tag::FUNCTION_CALL => {
+                    if from_client {
+                        if msg.tag() == Tag::SYNC {
+                            self.syncs_since_execute.fetch_add(1, Relaxed);
+                        } else {
+                            self.syncs_since_execute.store(0, Relaxed);
+                        }
+                    }
                     let request_flag = if from_client {
                         CLIENT_REQUEST
                     } else {
@@ -683,7 +1329,12 @@ impl BackendConn {
                         }
                         let val = pending | (request_flag << (pending_count*2));
                         match self.pending_requests.compare_exchange_weak(pending, val, Release, Relaxed) {
-                            Ok(_) => break,
+                            Ok(_) => {
+                                if pending_count == 0 {
+                                    self.request_started.store(self.micros_since_started(), Relaxed);
+                                }
+                                break;
+                            },
                             Err(val) => pending = val,
                         }
                     }
@@ -695,6 +1346,13 @@ impl BackendConn {
     }
 }
 
+/// Returns true if e reports that the server is shutting down or restarting, so the connection is closing
+/// and another connection (possibly after the restart) may succeed.
+fn is_shutdown_error(e: &PostgresError) -> bool {
+    let code = e.code();
+    code == error_codes::ADMIN_SHUTDOWN || code == error_codes::CRASH_SHUTDOWN || code == error_codes::CANNOT_CONNECT_NOW
+}
+
 impl AtomicRefCounted for BackendConn {
     fn refcount(&self) -> u32 {
         self.refcount_and_flags.refcount()
@@ -714,29 +1372,65 @@ impl AtomicRefCounted for BackendConn {
     }
 }
 
-impl server::Connection for BackendConn {
-    fn new(stream: TcpStream, connections: &'static Connections<Self>) -> Self {
+impl BackendConn {
+    fn with_transport(stream: Transport, connections: &'static Connections<Self>) -> Self {
         BackendConn {
-            stream: Transport::new(stream),
-            parser: UnsafeCell::new(MessageParser::new()),
+            stream,
+            receiver: ReceiveSlot::new(ReceiveHandle::new(MessageParser::new())),
             id: Default::default(),
             added_to_pool: Default::default(),
             refcount_and_flags: RefcountAndFlags::new(),
             for_transaction: Default::default(),
+            session_modified: Default::default(),
+            reset_setting: Mutex::new(None),
+            read_only: Default::default(),
+            unlisten: Default::default(),
+            notification_listener: Default::default(),
+            timings: Mutex::new(CheckoutTimings::default()),
+            scram: Mutex::new(None),
+            gss: Mutex::new(None),
             state: Default::default(),
             client: Ark::default(),
+            client_attached: Notify::new(),
             send_backlog: Mutex::new(Default::default()),
             pool: AtomicRef::default(),
             pending_requests: AtomicU64::new(0),
+            request_started: AtomicU64::new(0),
+            timed_out_request: AtomicU64::new(0),
+            cancelling: AsyncMutex::new(()),
+            request_rows: AtomicU64::new(0),
+            skipped_results: AtomicU32::new(0),
+            request_failed: AtomicBool::new(false),
+            request_forwarded: AtomicBool::new(false),
+            retry_request: Mutex::new(None),
+            savepoints: Mutex::new(Savepoints::default()),
+            started: Instant::now(),
+            copy_state: AtomicCell::default(),
+            client_copy_in: AtomicBool::new(false),
+            syncs_since_execute: AtomicU32::new(0),
+            skip_copy_sync: AtomicBool::new(false),
             iterator_messages: MessageQueue::new(),
             iterators: SpscQueue::new(),
             server_params: Mutex::new(ServerParams::default()),
+            protocol_options: Mutex::new(ServerParams::default()),
             pid: AtomicI32::new(0),
             secret: AtomicI32::new(0),
             created_at: Local::now(),
+            extensions: Extensions::new(),
             connections,
         }
     }
+}
+
+impl server::Connection for BackendConn {
+    fn new(stream: TcpStream, connections: &'static Connections<Self>) -> Self {
+        Self::with_transport(Transport::new(stream), connections)
+    }
+
+    #[cfg(unix)]
+    fn new_unix(stream: UnixStream, connections: &'static Connections<Self>) -> Self {
+        Self::with_transport(Transport::new_unix(stream), connections)
+    }
 
     fn id(&self) -> u32 {
         self.id.load(Relaxed)
@@ -768,6 +1462,10 @@ impl Connection for BackendConn {
         &self.send_backlog
     }
 
+    fn max_backlog_bytes(&self) -> u32 {
+        self.pool().and_then(|pool| pool.config.cluster).map_or(0, |cluster| cluster.max_backlog_bytes)
+    }
+
     fn transport(&self) -> &Transport {
         &self.stream
     }
@@ -798,9 +1496,41 @@ impl Debug for BackendConn {
     }
 }
 
-// Safety: we use an UnsafeCell, but access is controlled safely, see recv method for details.
-unsafe impl Send for BackendConn {}
-unsafe impl Sync for BackendConn {}
+/// Returns the extended query protocol messages (Parse, Bind, Describe, Execute, Sync) to run sql
+/// as an unnamed statement with the text format params, where None is NULL. Results are in text format.
+pub fn extended_query(sql: &str, params: &[Option<&str>]) -> Result<Messages> {
+    if params.len() > i16::MAX as usize {
+        return Err(Error::new(format!("too many query parameters: {}", params.len())));
+    }
+    let mut mb = MessageBuilder::new(Tag::PARSE);
+    mb.write_str(""); // unnamed statement
+    mb.write_str(sql);
+    mb.write_i16(0); // let the server infer the parameter types
+    mb.add_new(Tag::BIND);
+    mb.write_str(""); // unnamed portal
+    mb.write_str("");
+    mb.write_i16(0); // all parameters are text format
+    mb.write_i16(params.len() as i16);
+    for param in params {
+        match param {
+            Some(value) => {
+                mb.write_i32(value.len() as i32);
+                mb.write_bytes(value.as_bytes());
+            },
+            None => mb.write_i32(-1),
+        }
+    }
+    mb.write_i16(0); // all results are text format
+    mb.add_new(Tag::DESCRIBE);
+    mb.write_byte(b'P');
+    mb.write_str("");
+    mb.add_new(Tag::EXECUTE);
+    mb.write_str("");
+    mb.write_i32(0); // no row limit
+    mb.add_new(Tag::SYNC);
+    Ok(mb.finish())
+}
+
 
 
 define_event! {
@@ -839,6 +1569,17 @@ define_event! {
 }
 
 
+define_event! {
+    /// backend_dropped_messages is called when messages received from Postgres are dropped because
+    /// no client is attached to the backend connection to forward them to (after the dropped_messages_grace_ms.)
+    ///     backend: &BackendConn : the event source handling the backend connection
+    ///     msgs: &protocol.Messages : the dropped message(s)
+    /// BackendConn::backend_dropped_messages is called by default and logs a warning.
+    /// If it returns an error, the backend connection is closed.
+    backend_dropped_messages,
+    (backend: &'a BackendConn, msgs: &'a Messages) -> Result<()>
+}
+
 define_event! {
     /// backend_authenticate is called with each message(s) received from Postgres while in the Authentication state
     ///     backend: &BackendConn : the event source handling the backend connection
@@ -848,4 +1589,18 @@ define_event! {
     /// return an error.
     backend_authenticate,
     (backend: &'a BackendConn, msgs: Messages) -> Result<()>
-}
\ No newline at end of file
+}
+
+define_event! {
+    /// backend_error is called for each ErrorResponse received from Postgres while processing a query,
+    /// before it's forwarded to the client.
+    ///     backend: &BackendConn : the event source handling the backend connection
+    ///     client: Option<&ClientConn> : the associated client session (if any)
+    ///     error: &PostgresError : the parsed error, with the SQLSTATE code, message, table, constraint, etc.
+    ///     query: Option<&Query> : the most recent query sent by the client (if any)
+    /// BackendConn::backend_error is called by default and does nothing.
+    /// Use it to implement custom alerting, retry, or metrics on specific SQLSTATEs.
+    /// If it returns an error, the associated session is terminated.
+    backend_error,
+    (backend: &'a BackendConn, client: Option<&'a ClientConn>, error: &'a PostgresError, query: Option<&'a Query>) -> Result<()>
+}
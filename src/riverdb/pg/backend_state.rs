@@ -116,17 +116,19 @@ impl BackendConnState {
             Tag::COPY_IN_RESPONSE,
             Tag::COPY_OUT_RESPONSE,
             Tag::COPY_BOTH_RESPONSE,
+            Tag::COPY_DATA,
+            Tag::COPY_DONE,
             Tag::PORTAL,
         ];
 
         const ALLOWED_TAGS: [&'static [Tag]; 10] = [
             &[], // no valid tags in StateInitial
             &[], // no valid tags in SSLHandshake
-            &[Tag::AUTHENTICATION_OK], // Authentication
+            &[Tag::AUTHENTICATION_OK, Tag::NEGOTIATE_PROTOCOL_VERSION], // Authentication
             &[Tag::AUTHENTICATION_OK, Tag::BACKEND_KEY_DATA, Tag::READY_FOR_QUERY], // Startup
             RESPONSE_TAGS, // Ready
             RESPONSE_TAGS, // Transaction
-            &[], // FailedTransaction
+            RESPONSE_TAGS, // FailedTransaction
             &[], // Listen (only ASYNC_TAGS)
             &[], // InPool (only ASYNC_TAGS)
             &[], // no valid tags in Closed
@@ -157,8 +159,10 @@ impl BackendConnState {
                     transmute::<_, u16>(BackendState::InPool) |
                     transmute::<_, u16>(BackendState::Transaction) |
                     transmute::<_, u16>(BackendState::FailedTransaction), // Ready
-                transmute::<_, u16>(BackendState::Ready), // Transaction
-                transmute::<_, u16>(BackendState::Transaction), // FailedTransaction
+                // ROLLBACK TO SAVEPOINT recovers a failed transaction
+                transmute::<_, u16>(BackendState::Ready) | transmute::<_, u16>(BackendState::FailedTransaction), // Transaction
+                // A multi-statement query starting with BEGIN can fail with a single ReadyForQuery
+                transmute::<_, u16>(BackendState::Ready) | transmute::<_, u16>(BackendState::Transaction), // FailedTransaction
                 transmute::<_, u16>(BackendState::Ready), // Listen
                 transmute::<_, u16>(BackendState::Ready), // InPool
             ]
@@ -188,6 +192,46 @@ impl Debug for BackendConnState {
     }
 }
 
+/// The COPY sub-protocol state of a BackendConn, tracked separately from BackendState
+/// because COPY happens within the Ready or Transaction states.
+#[derive(Display, Debug, Clone, Copy, Eq, PartialEq)]
+#[repr(u8)]
+pub enum CopyState {
+    /// Not copying
+    None,
+    /// COPY FROM STDIN, the client sends CopyData messages to the database
+    CopyIn,
+    /// COPY TO STDOUT, the database sends CopyData messages to the client
+    CopyOut,
+    /// Both directions, only used for streaming replication
+    CopyBoth,
+}
+
+impl CopyState {
+    /// Returns the CopyState after the database sends a message with the given tag.
+    pub fn next(self, tag: Tag) -> Self {
+        match tag {
+            Tag::COPY_IN_RESPONSE => CopyState::CopyIn,
+            Tag::COPY_OUT_RESPONSE => CopyState::CopyOut,
+            Tag::COPY_BOTH_RESPONSE => CopyState::CopyBoth,
+            // COPY ends with CommandComplete if it succeeded, or ErrorResponse if it failed (e.g. after CopyFail)
+            Tag::COMMAND_COMPLETE | Tag::ERROR_RESPONSE | Tag::READY_FOR_QUERY => CopyState::None,
+            _ => self,
+        }
+    }
+
+    /// Returns true if the client is sending data to the database.
+    pub fn is_copy_in(self) -> bool {
+        self == CopyState::CopyIn || self == CopyState::CopyBoth
+    }
+}
+
+impl Default for CopyState {
+    fn default() -> Self {
+        CopyState::None
+    }
+}
+
 /// Transition the state if allowed, otherwise return an error.
 #[instrument]
 pub fn checked_state_transition<T: Debug, S: Copy + Debug + Eq + StateEnum>(subject: &T, allowed_transitions: &[u16], state: S, new_state: S) -> Result<()>
@@ -208,9 +252,29 @@ pub fn checked_state_transition<T: Debug, S: Copy + Debug + Eq + StateEnum>(subj
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn test_backend_state_transition() {
         // TODO
         //BackendConn::new_unix()
     }
+
+    #[test]
+    fn test_copy_state() {
+        let mut state = CopyState::default();
+        for tag in [Tag::ROW_DESCRIPTION, Tag::COPY_IN_RESPONSE, Tag::NOTICE_RESPONSE] {
+            state = state.next(tag);
+        }
+        assert_eq!(state, CopyState::CopyIn);
+        assert!(state.is_copy_in());
+        assert_eq!(state.next(Tag::COMMAND_COMPLETE), CopyState::None);
+        assert_eq!(state.next(Tag::ERROR_RESPONSE), CopyState::None);
+
+        state = CopyState::None.next(Tag::COPY_OUT_RESPONSE);
+        assert_eq!(state, CopyState::CopyOut);
+        assert!(!state.is_copy_in());
+        assert_eq!(state.next(Tag::COPY_DATA).next(Tag::COPY_DONE), CopyState::CopyOut);
+        assert_eq!(state.next(Tag::READY_FOR_QUERY), CopyState::None);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,109 @@
+use std::net::SocketAddr;
+use std::sync::{Mutex, PoisonError};
+
+use fnv::FnvHashMap;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{timeout, Duration};
+use tokio::net::TcpStream;
+use tracing::{debug};
+
+use crate::riverdb::Result;
+use crate::riverdb::pg::protocol::{Messages, MessageBuilder, Tag, CANCEL_REQUEST};
+
+/// How long send_cancel_request waits for Postgres to close the connection after the CancelRequest.
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(5);
+
+
+/// The Postgres server and backend key data needed to cancel the query running on a backend connection.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct CancelTarget {
+    pub address: SocketAddr,
+    pub pid: i32,
+    pub secret: i32,
+}
+
+/// CancelMap maps the backend key data riverdb sends to each client (the client id and salt)
+/// to the backend key data of the Postgres connection the client is currently using.
+/// A CancelRequest from the client is relayed to that Postgres server with the real key data.
+pub struct CancelMap {
+    targets: Mutex<FnvHashMap<(u32, i32), CancelTarget>>,
+}
+
+impl CancelMap {
+    /// Create a new, empty CancelMap.
+    pub fn new() -> Self {
+        Self {
+            targets: Mutex::new(FnvHashMap::default()),
+        }
+    }
+
+    /// Register target as the backend connection used by the client with the given id and salt.
+    pub fn register(&self, client_id: u32, salt: i32, target: CancelTarget) {
+        self.targets.lock().unwrap_or_else(PoisonError::into_inner).insert((client_id, salt), target);
+    }
+
+    /// Remove the backend connection registered for the client with the given id and salt.
+    pub fn unregister(&self, client_id: u32, salt: i32) {
+        self.targets.lock().unwrap_or_else(PoisonError::into_inner).remove(&(client_id, salt));
+    }
+
+    /// Returns the CancelTarget for the client with the given id and salt, if any.
+    pub fn get(&self, client_id: u32, salt: i32) -> Option<CancelTarget> {
+        self.targets.lock().unwrap_or_else(PoisonError::into_inner).get(&(client_id, salt)).copied()
+    }
+
+    /// Relay a CancelRequest for the client with the given id and salt to the Postgres server
+    /// it's currently using. Does nothing if the client isn't using a backend connection,
+    /// or the key data doesn't match (the same as Postgres, the requester gets no indication.)
+    pub async fn cancel(&self, client_id: u32, salt: i32) -> Result<()> {
+        match self.get(client_id, salt) {
+            Some(target) => send_cancel_request(&target).await,
+            None => {
+                debug!(client_id, "ignoring CancelRequest for unknown client or inactive session");
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Open a new connection to the target Postgres server and send it a CancelRequest.
+pub async fn send_cancel_request(target: &CancelTarget) -> Result<()> {
+    let mut stream = TcpStream::connect(target.address).await?;
+    stream.write_all(cancel_request(target.pid, target.secret).as_slice()).await?;
+    stream.shutdown().await?;
+    // Postgres closes the connection once it has signalled the backend, like libpq, wait for that
+    let mut buf = [0; 1];
+    let _ = timeout(CANCEL_TIMEOUT, stream.read(&mut buf)).await;
+    Ok(())
+}
+
+/// Build a CancelRequest message for the backend key data pid and secret.
+fn cancel_request(pid: i32, secret: i32) -> Messages {
+    let mut mb = MessageBuilder::new(Tag::UNTAGGED);
+    mb.write_i32(CANCEL_REQUEST);
+    mb.write_i32(pid);
+    mb.write_i32(secret);
+    mb.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register() {
+        let map = CancelMap::new();
+        let target = CancelTarget{address: "127.0.0.1:5432".parse().unwrap(), pid: 42, secret: -7};
+        map.register(1, 1234, target);
+        assert_eq!(map.get(1, 1234), Some(target));
+        assert_eq!(map.get(1, 4321), None);
+        map.unregister(1, 1234);
+        assert_eq!(map.get(1, 1234), None);
+    }
+
+    #[test]
+    fn test_cancel_request() {
+        let msgs = cancel_request(42, -7);
+        assert_eq!(msgs.as_slice(), &[0, 0, 0, 16, 4, 210, 22, 46, 0, 0, 0, 42, 255, 255, 255, 249]);
+    }
+}
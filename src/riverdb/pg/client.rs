@@ -1,59 +1,145 @@
 use std::cell::UnsafeCell;
-use std::sync::atomic::{AtomicU32};
+use std::sync::atomic::{AtomicU32, AtomicBool};
 use std::sync::atomic::Ordering::{Relaxed};
 use std::fmt::{Debug, Formatter};
-use std::sync::{Mutex};
-use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::borrow::Cow;
 
-use bytes::Bytes;
+use bytes::{Bytes, Buf};
 use tokio::net::TcpStream;
-use tracing::{error, warn, debug, instrument};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::Notify;
+use tokio::time::{timeout, timeout_at, sleep, Instant, Duration};
+use tracing::{error, warn, debug, instrument, Span};
 
 use crate::define_event;
 use crate::riverdb::{Error, Result};
 use crate::riverdb::worker::{Worker};
 use crate::riverdb::pg::protocol::{
     Messages, ServerParams, Tag, MessageParser,
-    PROTOCOL_VERSION, SSL_REQUEST, AuthType, MessageBuilder,
-    error_codes, SSL_ALLOWED, SSL_NOT_ALLOWED
+    PROTOCOL_VERSION, PROTOCOL_VERSION_2, SSL_REQUEST, CANCEL_REQUEST, GSSENC_REQUEST, AuthType, MessageBuilder,
+    error_codes, SSL_ALLOWED, SSL_NOT_ALLOWED, COMPRESSION_OPTION, Message, sasl, Credentials
 };
-use crate::riverdb::pg::{ClientConnState, BackendConn, Connection, TransactionType};
-use crate::riverdb::server::{Transport, Connections, Connection as ServerConnection};
-use crate::riverdb::pg::{PostgresCluster, ConnectionPool, parse_messages};
-use crate::riverdb::pg::connection::{Backlog, RefcountAndFlags};
+use crate::riverdb::pg::{ClientConnState, BackendConn, BackendState, Connection, TransactionType, TransactionOptions, IsolationLevel, WaitEvent, SessionSettings, SettingChange};
+use crate::riverdb::server::{Transport, Connections, Connection as ServerConnection, ProxyHeader, certificate_names};
+use crate::riverdb::pg::{PostgresCluster, ServerTls, ConnectionPool, PendingQueries, StartupGuard, AdminCommand, CancelTarget, error_result, error_result_with_hint, command_result, parse_channel, route_shard};
+use crate::riverdb::pg::connection::{Backlog, RefcountAndFlags, ReceiveHandle, ReceiveSlot, read_and_flush_backlog};
 use crate::riverdb::pg::client_state::ClientState;
-use crate::riverdb::pg::sql::{QueryMessage, QueryType};
+use crate::riverdb::pg::sql::{Query, QueryMessage, QueryType, NormalizeOptions, quote_str};
 use crate::riverdb::pg::PostgresReplicationGroup;
-use crate::riverdb::common::{AtomicCell, AtomicRef, Ark, AtomicRefCounted, ErrorKind};
-use crate::riverdb::config::{conf, TlsMode};
+#[cfg(debug_assertions)]
+use crate::riverdb::pg::PassthroughChecks;
+use crate::riverdb::common::{AtomicCell, AtomicRef, Ark, AtomicRefCounted, ErrorKind, Extensions, catch_panic};
+use crate::riverdb::config::{self, conf, TlsMode, ProtocolOptions, PoolMode, TagViolationAction, ClientAuth, AuthMethod, IsolationAction, TracePropagation};
+use crate::riverdb::peers::{Peers, PeerMessage};
+use crate::riverdb::cache::{ResultCache, ResultCapture, cache_key, cache_tables, unix_now};
+use crate::riverdb::audit::{AuditLog, AuditQuery, PendingAudits, audited_type};
+use crate::riverdb::pg::replay::QueryCapture;
 
 
+/// The query tag holding the id of the request (e.g. HTTP request) that issued the query
+const REQUEST_ID_TAG: &str = "request_id";
+
+/// The maximum length in bytes of an application_name, longer names are truncated by Postgres (NAMEDATALEN - 1)
+const MAX_APPLICATION_NAME_LEN: usize = 63;
+
+/// How often to check the client wasn't closed while waiting for a statement of a split query to complete
+const SPLIT_QUERY_POLL: Duration = Duration::from_secs(1);
+
+/// The error sent to clients that attempt to connect with protocol version 2.0
+const OLD_PROTOCOL_ERROR: &str = "FATAL:  unsupported frontend protocol 2.0: riverdb requires protocol 3.0 (PostgreSQL 7.4 or later client libraries)\n";
+
 pub struct ClientConn {
     /// stream is a possibly uninitialized Transport, may check if client_id != 0 first
     stream: Transport,
-    parser: UnsafeCell<MessageParser>,
+    receiver: ReceiveSlot,
     /// id is set once and then read-only. Starts as 0.
     id: AtomicU32,
     /// last-active is a course-grained monotonic clock that is advanced when data is received from the client
     last_active: AtomicU32,
     auth_type: AtomicCell<AuthType>,
+    /// the server side of the SASL authentication state machine while authenticating with SCRAM-SHA-256
+    scram: Mutex<Option<sasl::ScramSha256Server>>,
+    /// the stored credentials of the user, if known, while authenticating with a password or md5
+    credentials: Mutex<Option<Credentials>>,
+    /// the tls_server_end_point of the certificate presented to the client in the TLS handshake, for SCRAM channel binding
+    tls_server_end_point: Mutex<Vec<u8>>,
     refcount_and_flags: RefcountAndFlags,
     state: ClientConnState,
     tx_type: AtomicCell<TransactionType>,
+    /// the isolation level requested for the current transaction, see enforce_max_isolation
+    tx_isolation: AtomicCell<IsolationLevel>,
+    /// the default isolation level of new transactions set by the client, see SettingChange::default_isolation
+    session_isolation: AtomicCell<IsolationLevel>,
+    /// what the session is waiting on, see wait_event()
+    wait_event: AtomicCell<WaitEvent>,
     backend: Ark<BackendConn>,
     send_backlog: Backlog,
+    /// extended query protocol messages received without the terminating Sync or Flush
+    extended_messages: Mutex<Messages>,
+    /// the most recent query sent to the backend
+    last_query: Mutex<Option<Arc<Query>>>,
+    /// the queries sent to the backend that it hasn't completed yet, see record_query_latency
+    pending_queries: Mutex<PendingQueries>,
+    /// the result of a cacheable query being captured for the result cache
+    result_capture: Mutex<Option<ResultCapture>>,
+    /// tables written since the client was last idle, their cached results are invalidated again when the transaction ends
+    written_tables: Mutex<Vec<String>>,
+    /// set when a statement that writes unknown tables ran since the client was last idle, see invalidated_tables
+    wrote_unknown_tables: AtomicBool,
+    /// the tables written by each prepared statement the client prepared with Parse (empty for reads), see invalidated_tables
+    prepared_tables: Mutex<HashMap<String, Vec<String>>>,
+    /// the queries admitted by the cluster's rate_limiter that haven't completed yet
+    running_queries: AtomicU32,
+    /// the session parameters set by the client, set again on each backend connection in transaction or statement pool_mode
+    session_settings: Mutex<SessionSettings>,
+    /// the change made by the SET or RESET statement sent to the backend, recorded in session_settings if it succeeds
+    pending_setting: Mutex<Option<SettingChange>>,
+    /// the SQL of the BEGIN and SET statements held until the next statement of the transaction, see unbuffered_begin
+    buffered_begin: Mutex<Vec<String>>,
+    /// the audited queries waiting for the database to complete them, see AuditLog
+    pending_audits: Mutex<PendingAudits>,
+    /// set when the client created session state (see Query::creates_session_state), the backend is kept until the session ends
+    pinned: AtomicBool,
+    /// for each Parse (true if it prepares a named statement) and each Query or Sync sent to the backend
+    /// (None) whose result hasn't arrived yet, see track_prepared_statements
+    pending_parses: Mutex<VecDeque<Option<bool>>>,
+    /// the state of the statement of a split multi-statement query being run, see split_multi_statement_queries
+    split_query: Mutex<SplitQuery>,
+    /// notified when the statement of a split query completes
+    split_done: Notify,
     cluster: AtomicRef<'static, PostgresCluster>,
     replication_group: AtomicRef<'static, PostgresReplicationGroup>, // the last PostgresReplicationGroup used
+    /// the PostgresReplicationGroup the current transaction is bound to, see transaction_group()
+    tx_group: AtomicRef<'static, PostgresReplicationGroup>,
     pool: AtomicRef<'static, ConnectionPool>, // the last ConnectionPool used
+    listen_pool: AtomicRef<'static, ConnectionPool>, // the pool whose NotificationHub has our LISTEN channels
     connect_params: UnsafeCell<ServerParams>,
+    /// the Postgres role granted by the JSON Web Token of the client, see jwt_authenticate
+    jwt_role: Mutex<Option<String>>,
+    /// the _pq_ protocol options requested in the startup message
+    protocol_options: Mutex<ServerParams>,
     salt: i32,
+    /// remote_ip is the IP address of the client, if known. With accept_proxy_protocol this is replaced
+    /// by the source address in the PROXY protocol header, see read_proxy_header.
+    remote_ip: Mutex<Option<IpAddr>>,
+    /// per-connection state attached by plugins
+    extensions: Extensions,
+    #[cfg(debug_assertions)]
+    passthrough: PassthroughChecks,
     connections: &'static Connections<ClientConn>,
 }
 
 impl ClientConn {
     #[instrument]
     pub async fn run(&self) -> Result<()> {
-        let e = self.run_inner().await.expect_err("client run exited without error");
+        let e = match catch_panic(self.run_inner()).await {
+            Ok(result) => result.expect_err("client run exited without error"),
+            Err(panic) => return Err(self.panicked(panic).await),
+        };
         if let ErrorKind::ClosedError = e.kind() {
             // This is expected, don't pollute the logs by logging this
         } else {
@@ -66,39 +152,224 @@ impl ClientConn {
         Err(e)
     }
 
+    /// Called when run panicked. The session may have been left in any state, so log the panic with
+    /// the session context, send the client an error, and close both the session and its backend
+    /// connection, instead of returning the backend to the pool. Returns the error for run.
+    async fn panicked(&self, panic: String) -> Error {
+        let (user, database) = if let ClientState::StateInitial | ClientState::SSLHandshake = self.state() {
+            ("", "") // connection_params isn't set yet
+        } else {
+            let params = self.connection_params();
+            (params.get("user").unwrap_or(""), params.get("database").unwrap_or(""))
+        };
+        let backend = self.backend.take();
+        error!(%panic, id = self.id(), state = ?self.state(), client_addr = ?self.remote_ip(), user, database,
+            backend_id = ?backend.load().map(|backend| backend.id()), "client connection panicked");
+        if let Some(backend) = backend.load() {
+            // This causes its run task to exit, and the pool discards it instead of reusing it
+            backend.close();
+        }
+        BackendConn::return_to_pool(backend).await;
+        if !self.is_closed() {
+            let err_msg = Messages::new_error(error_codes::SYSTEM_ERROR, "riverdb internal error, closing the connection");
+            let _ = self.send(err_msg).await;
+            self.close();
+        }
+        Error::new(format!("client connection panicked: {}", panic))
+    }
+
     async fn run_inner(&self) -> Result<()> {
         // XXX: This code is very similar to BackendConn::run.
         // If you change this, you probably need to change that too.
 
+        let mut rx = self.take_receive_handle()?;
+        self.run_startup(&mut rx).await?;
+
         loop {
-            // Safety: we only access self.stream from this thread
-            let msgs = unsafe { self.recv().await? };
+            self.set_wait_event(WaitEvent::ClientRead);
+            let msgs = rx.recv(self, self.backend()).await?;
+            self.set_wait_event(WaitEvent::None);
+            client_messages::run(self, msgs).await?;
+        }
+    }
+
+    /// Process messages until the client is authenticated, enforcing the max_startup_packet_bytes
+    /// and startup_timeout_seconds limits. Violations are recorded with the StartupGuard.
+    async fn run_startup(&self, rx: &mut ReceiveHandle) -> Result<()> {
+        let config = self.cluster_config();
+        rx.parser().set_max_message_len(config.max_startup_packet_bytes);
+        let startup_timeout = config.startup_timeout_seconds;
+        let deadline = if startup_timeout != 0 {
+            Some(Instant::now() + Duration::from_secs(startup_timeout as u64))
+        } else {
+            None
+        };
+
+        if config.accept_proxy_protocol {
+            self.set_wait_event(WaitEvent::ClientRead);
+            let read = self.read_proxy_header(rx);
+            match deadline {
+                Some(deadline) => {
+                    match timeout_at(deadline, read).await {
+                        Ok(result) => result,
+                        Err(_) => {
+                            self.startup_guard().timed_out(self.remote_ip());
+                            return Err(Error::new(format!("client did not send a PROXY protocol header within {} seconds", startup_timeout)));
+                        },
+                    }
+                },
+                None => read.await,
+            }?;
+            if let Some(ip) = self.remote_ip() {
+                if self.startup_guard().is_banned(ip) {
+                    debug!(%ip, "closing connection from banned ip address");
+                    return Err(Error::closed());
+                }
+            }
+        }
+
+        while self.is_starting_up() {
+            self.set_wait_event(WaitEvent::ClientRead);
+            let recv = rx.recv(self, self.backend());
+            let result = match deadline {
+                Some(deadline) => {
+                    match timeout_at(deadline, recv).await {
+                        Ok(result) => result,
+                        Err(_) => {
+                            self.startup_guard().timed_out(self.remote_ip());
+                            return Err(Error::new(format!("client did not complete startup within {} seconds", startup_timeout)));
+                        },
+                    }
+                },
+                None => recv.await,
+            };
+            let msgs = match result {
+                Ok(msgs) => msgs,
+                Err(e) => {
+                    if let ErrorKind::MessageTooLarge{..} = e.kind() {
+                        self.startup_guard().oversized_packet(self.remote_ip());
+                    }
+                    return Err(e);
+                },
+            };
             client_messages::run(self, msgs).await?;
         }
+
+        rx.parser().set_max_message_len(self.cluster_config().max_message_bytes);
+        Ok(())
     }
 
-    unsafe fn parser(&self) -> &mut MessageParser {
-        &mut *self.parser.get()
+    /// Reads the PROXY protocol header sent by a load balancer before the startup packet, and replaces
+    /// remote_ip with the address of the client from the header. The rest of the data read stays in
+    /// the MessageParser buffer.
+    async fn read_proxy_header(&self, rx: &mut ReceiveHandle) -> Result<()> {
+        loop {
+            let buf = rx.parser().bytes_mut();
+            if let Some(header) = ProxyHeader::parse(buf)? {
+                buf.advance(header.len);
+                if let Some(source) = header.source {
+                    debug!(proxy = ?self.remote_ip(), client = %source, "received PROXY protocol header");
+                    *self.remote_ip.lock().unwrap() = Some(source.ip());
+                }
+                return Ok(());
+            }
+            if buf.len() == buf.capacity() {
+                buf.reserve(buf.len());
+            }
+            read_and_flush_backlog(self, buf, None::<&BackendConn>).await?;
+        }
     }
 
-    /// recv parses some Messages from the stream.
-    /// Safety: recv can only be called from the run thread, only from inside
-    /// methods called directly or indirectly by self.run(). Marked as unsafe
-    /// because the programmer must enforce that constraint.
-    #[inline]
-    pub async unsafe fn recv(&self) -> Result<Messages> {
-        let parser = self.parser();
-        parse_messages(parser, self, self.backend(), false).await
+    /// Returns the IP address of the client, if known.
+    pub fn remote_ip(&self) -> Option<IpAddr> {
+        *self.remote_ip.lock().unwrap()
     }
 
-    /// recv_one parses a single Message from the stream.
-    /// Safety: recv_one can only be called from the run thread, only from inside
-    /// methods called directly or indirectly by self.run(). Marked as unsafe
-    /// because the programmer must enforce that constraint.
-    #[inline]
-    pub async unsafe fn recv_one(&self) -> Result<Messages> {
-        let parser = self.parser();
-        parse_messages(parser, self, self.backend(), true).await
+    /// Returns the type of the current transaction, or TransactionType::None if not in a transaction.
+    pub fn tx_type(&self) -> TransactionType {
+        self.tx_type.load()
+    }
+
+    /// Returns what the session is currently waiting on. A session waiting for the client to send
+    /// a message while it has queries pending on its backend connection is waiting on the database.
+    pub fn wait_event(&self) -> WaitEvent {
+        match self.wait_event.load() {
+            WaitEvent::ClientRead if self.backend().map_or(false, |backend| backend.pending_requests() != 0) => {
+                WaitEvent::BackendResponse
+            },
+            event => event,
+        }
+    }
+
+    /// Records what the session is about to wait on, or WaitEvent::None when it stops waiting.
+    pub(crate) fn set_wait_event(&self, event: WaitEvent) {
+        self.wait_event.store(event);
+    }
+
+    /// Returns true if the client began a transaction that hasn't ended yet. This is tracked from the queries
+    /// sent by the client (see update_tx_type), so unlike state() it's current for pipelined queries.
+    pub fn in_transaction(&self) -> bool {
+        self.tx_type.load() != TransactionType::None
+    }
+
+    /// Returns the PostgresReplicationGroup the current transaction was routed to, if any. All queries
+    /// in the transaction are routed there, queries the sharding settings route elsewhere are rejected.
+    pub fn transaction_group(&self) -> Option<&'static PostgresReplicationGroup> {
+        self.tx_group.load()
+    }
+
+    /// Update tx_type from BEGIN, SET TRANSACTION, COMMIT, and ROLLBACK queries.
+    /// This must happen before the backend is chosen, so BEGIN READ ONLY can be routed to a replica.
+    fn update_tx_type(&self, query: &QueryMessage) {
+        let q = query.query();
+        match q.query_type() {
+            // BEGIN in a transaction is ignored by Postgres, with a warning
+            QueryType::Begin if !self.in_transaction() => {
+                self.tx_type.store(TransactionType::parse_from_query(q.normalized()));
+                let isolation = match q.isolation_level() {
+                    IsolationLevel::None => self.session_isolation.load(),
+                    isolation => isolation,
+                };
+                self.tx_isolation.store(isolation);
+                self.tx_group.store(None);
+            },
+            // These end the transaction even if it failed, the ReadyForQuery status also ends it (see client_send_messages)
+            QueryType::Commit | QueryType::Rollback | QueryType::PrepareTransaction if !query.is_multi_query() => {
+                self.end_transaction();
+            },
+            QueryType::SetTransaction => {
+                let tx_type = self.tx_type.load();
+                if tx_type != TransactionType::None {
+                    let opts = TransactionOptions::parse(q.normalized());
+                    self.tx_type.store(tx_type.with_options(&opts));
+                    if opts.isolation != IsolationLevel::None {
+                        self.tx_isolation.store(opts.isolation);
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// Clear the transaction tracking, queries are routed independently again.
+    fn end_transaction(&self) {
+        self.tx_type.store(TransactionType::None);
+        self.tx_isolation.store(IsolationLevel::None);
+        self.tx_group.store(None);
+    }
+
+    /// Returns true if the client has not yet completed startup and authentication.
+    fn is_starting_up(&self) -> bool {
+        match self.state() {
+            ClientState::StateInitial | ClientState::SSLHandshake | ClientState::Authentication => true,
+            _ => false,
+        }
+    }
+
+    /// Take the ReceiveHandle used to receive Messages from the client. This is done once by run(),
+    /// it returns an error if the handle was already taken.
+    pub fn take_receive_handle(&self) -> Result<ReceiveHandle> {
+        self.receiver.take()
     }
 
     #[inline]
@@ -122,6 +393,12 @@ impl ClientConn {
 
     /// Sets the associated BackendConn. Panics if called on a BackendConn.
     pub fn set_backend(&self, backend: Ark<BackendConn>) {
+        if let (Some(cluster), Some(b)) = (self.cluster(), backend.load()) {
+            let (pid, secret) = b.backend_key();
+            if let Some(address) = b.pool().and_then(|pool| pool.config.address) {
+                cluster.cancel_map.register(self.id.load(Relaxed), self.salt, CancelTarget{address, pid, secret});
+            }
+        }
         self.backend.store(backend);
     }
 
@@ -133,181 +410,1359 @@ impl ClientConn {
         self.cluster.store(cluster);
     }
 
-    pub fn replication_group(&self) -> Option<&'static PostgresReplicationGroup> {
-        self.replication_group.load()
+    /// Returns the checks used to verify forwarded messages aren't corrupted (debug builds only.)
+    #[cfg(debug_assertions)]
+    pub fn passthrough_checks(&self) -> &PassthroughChecks {
+        &self.passthrough
+    }
+
+    /// Returns the config of the associated cluster, or of the default cluster if not set.
+    pub fn cluster_config(&self) -> &'static config::PostgresCluster {
+        self.cluster().map_or(&conf().postgres, |cluster| cluster.config)
+    }
+
+    /// Returns the StartupGuard of the cluster this client connected to, or of the default cluster.
+    fn startup_guard(&self) -> &'static StartupGuard {
+        &self.cluster().unwrap_or_else(PostgresCluster::singleton).startup_guard
+    }
+
+    pub fn replication_group(&self) -> Option<&'static PostgresReplicationGroup> {
+        self.replication_group.load()
+    }
+
+    pub fn set_replication_group(&self, replication_group: Option<&'static PostgresReplicationGroup>) {
+        self.replication_group.store(replication_group);
+    }
+
+    pub fn pool(&self) -> Option<&'static ConnectionPool> {
+        self.pool.load()
+    }
+
+    /// Returns the state attached to this client session by plugins, see Extensions.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn set_pool(&self, pool: Option<&'static ConnectionPool>) {
+        self.pool.store(pool);
+    }
+
+    pub fn connection_params(&self) -> &ServerParams {
+        match self.state.get() {
+            ClientState::StateInitial | ClientState::SSLHandshake => {
+                panic!("can only access connection_params once in the Authentication or later states");
+            },
+            _ => (),
+        }
+        // Safety: we don't allow accessing params (we panic) if ClientState < ClientState::Authentication
+        unsafe {
+            &*self.connect_params.get()
+        }
+    }
+
+    /// Returns the pool_mode of the pool of the current backend connection, or the last pool used,
+    /// or the default for the cluster if the client hasn't used a pool yet.
+    pub fn pool_mode(&self) -> PoolMode {
+        match self.backend().and_then(|backend| backend.pool()).or_else(|| self.pool()) {
+            Some(pool) => pool.config.pool_mode,
+            None => self.cluster_config().default.pool_mode,
+        }
+    }
+
+    /// Returns the options for normalizing the queries of this client, from the cluster config.
+    fn normalize_options(&self) -> NormalizeOptions {
+        let config = self.cluster_config();
+        NormalizeOptions{
+            collapse_in_lists: config.collapse_in_lists,
+            preserve_identifier_case: config.preserve_identifier_case,
+            max_bytes: config.max_normalize_bytes,
+        }
+    }
+
+    /// Returns true if the backend connection is kept until the session ends, because the client created
+    /// session state like a temporary table or prepared statement (see Query::creates_session_state.)
+    pub fn is_pinned(&self) -> bool {
+        self.pinned.load(Relaxed)
+    }
+
+    /// Remember the Parse messages of query as it's sent to the database, so the backend can be pinned
+    /// once one that prepares a named statement completes (see track_prepared_statements.)
+    fn track_parses(&self, query: &QueryMessage) {
+        if self.pool_mode() == PoolMode::Session || self.is_pinned() {
+            return;
+        }
+        let mut pending = self.pending_parses.lock().unwrap();
+        for msg in query.messages().iter(0) {
+            match msg.tag() {
+                Tag::PARSE => pending.push_back(Some(msg.reader().read_str().map_or(false, |name| !name.is_empty()))),
+                Tag::QUERY | Tag::SYNC => pending.push_back(None),
+                _ => (),
+            }
+        }
+    }
+
+    /// Called with the messages returned by the database for each of this client's queries, before they're sent
+    /// to the client. A named prepared statement only exists on the backend connection it was prepared on, so the
+    /// backend is pinned once the ParseComplete for one arrives. A Parse that fails, or that's skipped after an
+    /// earlier error in its group, doesn't pin it.
+    pub(crate) fn track_prepared_statements(&self, msgs: &Messages) {
+        let mut pending = self.pending_parses.lock().unwrap();
+        if pending.is_empty() {
+            return;
+        }
+        for msg in msgs.iter(0) {
+            match msg.tag() {
+                Tag::PARSE_COMPLETE => {
+                    if let Some(Some(named)) = pending.front().cloned() {
+                        pending.pop_front();
+                        if named && !self.pinned.swap(true, Relaxed) {
+                            debug!(client=self.id(), "pinning the backend connection to the session for a named prepared statement");
+                        }
+                    }
+                },
+                Tag::ERROR_RESPONSE => {
+                    // The rest of the group up to the Sync is skipped
+                    while matches!(pending.front(), Some(Some(_))) {
+                        pending.pop_front();
+                    }
+                },
+                Tag::READY_FOR_QUERY => {
+                    while let Some(Some(_)) = pending.pop_front() {}
+                },
+                _ => (),
+            }
+        }
+    }
+
+    /// Returns the _pq_ protocol options the client requested in the startup message.
+    pub fn protocol_options(&self) -> ServerParams {
+        self.protocol_options.lock().unwrap().clone()
+    }
+
+    /// Returns the protocol compression in effect for this session: the _pq_.compression option,
+    /// if protocol_options is passthrough and the current backend connection negotiated the same value.
+    pub fn compression(&self) -> Option<String> {
+        if self.cluster_config().protocol_options != ProtocolOptions::Passthrough {
+            return None;
+        }
+        let requested = self.protocol_options.lock().unwrap().get(COMPRESSION_OPTION)?.to_string();
+        let backend = self.backend()?;
+        if backend.protocol_options().get(COMPRESSION_OPTION) == Some(requested.as_str()) {
+            Some(requested)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the client connections of the service this client connected to.
+    pub fn connections(&self) -> &'static Connections<ClientConn> {
+        self.connections
+    }
+
+    /// For each Query or FunctionCall Message, or group of extended query protocol messages
+    /// ending in Sync or Flush, constructs a QueryMessage and runs client_query.
+    /// Which forwards the Query or Message to the backend via backend.send.
+    /// Extended query protocol messages are held until the Sync or Flush arrives,
+    /// so the group is routed to the backend as a unit.
+    /// If backend is None, runs client_connect_backend to acquire a backend connection.
+    /// Panics unless in Ready, Transaction, or FailedTransaction states.
+    #[instrument]
+    pub async fn forward(&self, msgs: Messages) -> Result<()> {
+        // The offset in msgs of the first extended query protocol message in the current group
+        let mut group_start = None;
+        // The offset in msgs of the first message in the current run of COPY FROM STDIN messages
+        let mut copy_start = None;
+        for msg in msgs.iter(0) {
+            let tag = msg.tag();
+            if tag == Tag::COPY_DATA || tag == Tag::COPY_DONE || tag == Tag::COPY_FAIL {
+                copy_start.get_or_insert(msg.offset());
+                continue;
+            }
+            if let Some(start) = copy_start.take() {
+                self.forward_copy(msgs.slice(start, msg.offset())).await?;
+            }
+            match tag {
+                Tag::QUERY | Tag::FUNCTION_CALL => {
+                    // TODO can we still issue a bulk send here if Query is unaltered?
+                    let query = QueryMessage::new_with_options(msgs.split_message(&msg), self.normalize_options())?;
+                    if query.is_multi_query() && query.is_simple_query() && !query.query().is_truncated()
+                        && self.cluster_config().split_multi_statement_queries {
+                        self.split_query(query).await?;
+                    } else {
+                        client_query::run(self, query).await?;
+                    }
+                },
+                Tag::PARSE | Tag::BIND | Tag::DESCRIBE | Tag::EXECUTE | Tag::CLOSE => {
+                    group_start.get_or_insert(msg.offset());
+                },
+                Tag::SYNC | Tag::FLUSH => {
+                    let start = group_start.take().unwrap_or(msg.offset());
+                    let group = self.take_extended_messages()
+                        .append(msgs.slice(start, msg.offset() + msg.len() as usize));
+                    let query = QueryMessage::new_with_options(group, self.normalize_options())?;
+                    client_query::run(self, query).await?;
+                },
+                Tag::TERMINATE => {
+                    // This code is slightly different from close() in that it doesn't spawn a new task
+                    self.transition(ClientState::Closed)?;
+                    // This must come after state transition, so release_backend always releases it
+                    let backend = self.release_backend();
+                    if backend.is_some() {
+                        BackendConn::return_to_pool(backend).await;
+                    }
+                    self.stream.close();
+                    break;
+                },
+                _ => {
+                    return Err(Error::new(format!("unexpected client message {} for state {:?}", msg.tag(), self.state())));
+                }
+            }
+        }
+        if let Some(start) = copy_start {
+            self.forward_copy(msgs.slice(start, msgs.len() as usize)).await?;
+        }
+        if let Some(start) = group_start {
+            // Hold the incomplete group until the Sync or Flush arrives
+            let rest = msgs.slice(start, msgs.len() as usize);
+            let mut extended = self.extended_messages.lock().unwrap();
+            *extended = std::mem::take(&mut *extended).append(rest);
+        }
+        Ok(())
+    }
+
+    /// Send CopyData, CopyDone, or CopyFail messages directly to the backend. These are streamed
+    /// through as they arrive, so large bulk loads aren't buffered in memory.
+    async fn forward_copy(&self, msgs: Messages) -> Result<()> {
+        match self.backend() {
+            Some(backend) => {
+                if !backend.copy_state().is_copy_in() {
+                    // This can happen if the COPY failed, the database discards the messages
+                    debug!(?msgs, copy_state=?backend.copy_state(), "COPY messages received outside of COPY FROM STDIN");
+                }
+                backend.send(msgs).await?;
+                Ok(())
+            },
+            None => Err(Error::new("received COPY messages without a backend connection")),
+        }
+    }
+
+    /// Take the held extended query protocol messages received without the terminating Sync or Flush.
+    fn take_extended_messages(&self) -> Messages {
+        std::mem::take(&mut *self.extended_messages.lock().unwrap())
+    }
+
+    pub async fn session_idle(&self) -> Result<Ark<BackendConn>> {
+        if self.state() == ClientState::Closed {
+            Ok(Ark::default())
+        } else {
+            client_idle::run(self).await
+        }
+    }
+
+    #[instrument]
+    pub fn release_backend(&self) -> Ark<BackendConn> {
+        match self.state.get() {
+            ClientState::Ready | ClientState::Closed => {
+                if let Some(cluster) = self.cluster() {
+                    cluster.cancel_map.unregister(self.id.load(Relaxed), self.salt);
+                }
+                return self.backend.take();
+            },
+            _ => (),
+        }
+        Ark::default()
+    }
+
+    /// Sends a COMMAND_COMPLETE message. Command should usually be a single word that identifies the completed SQL command.
+    /// For an INSERT command, the tag is INSERT 0 rows, where rows is the number of rows inserted.
+    /// For a DELETE command, the tag is DELETE rows where rows is the number of rows deleted.
+    /// For an UPDATE command, the tag is UPDATE rows where rows is the number of rows updated.
+    /// For a SELECT or CREATE TABLE AS command, the tag is SELECT rows where rows is the number of rows retrieved.
+    /// For a MOVE command, the tag is MOVE rows where rows is the number of rows the cursor's position has been changed by.
+    /// For a FETCH command, the tag is FETCH rows where rows is the number of rows that have been retrieved from the cursor.
+    /// For a COPY command, the tag is COPY rows where rows is the number of rows copied.
+    async fn send_command_successful(&self, command: &str, tx_status: char) -> Result<usize> {
+        let mut mb = MessageBuilder::new(Tag::COMMAND_COMPLETE);
+        mb.write_str(command);
+        mb.add_new(Tag::READY_FOR_QUERY);
+        mb.write_byte(tx_status as u8);
+        self.send(mb.finish()).await
+    }
+
+    #[instrument]
+    async fn startup(&self, msgs: Messages) -> Result<()> {
+        if msgs.count() != 1 {
+            return Err(Error::new("startup expects exactly one Message"));
+        }
+
+        let msg = msgs.first().unwrap(); // see msgs.count() condition above
+        assert_eq!(msg.tag(), Tag::UNTAGGED); // was previously checked by msg_is_allowed
+        let protocol_version = msg.reader().read_i32();
+        match protocol_version {
+            // Accept any minor version of protocol 3, newer clients fall back to 3.0 after NegotiateProtocolVersion
+            _ if protocol_version >> 16 == PROTOCOL_VERSION >> 16 => {
+                let mut params= ServerParams::from_startup_message(&msg)?;
+                let options = params.take_protocol_options();
+                let mut unsupported = Vec::new();
+                if options.len() != 0 {
+                    match self.cluster_config().protocol_options {
+                        ProtocolOptions::Strip => {
+                            debug!(?options, "stripping protocol options");
+                            unsupported.extend(options.iter().map(|(key, _)| key.as_str()));
+                        },
+                        ProtocolOptions::Passthrough => {
+                            for (key, value) in options.iter() {
+                                params.add(key.clone(), value.clone());
+                            }
+                        },
+                    }
+                }
+                let minor_version = protocol_version & 0xffff;
+                if minor_version != 0 || !unsupported.is_empty() {
+                    debug!(minor_version, ?unsupported, "negotiating protocol version 3.0");
+                    self.send(Messages::new_negotiate_protocol_version(0, &unsupported)).await?;
+                }
+                if options.len() != 0 {
+                    *self.protocol_options.lock().unwrap() = options;
+                }
+                let cluster = client_connected::run(self, params).await?;
+                self.set_cluster(Some(cluster));
+                Ok(())
+            },
+            SSL_REQUEST => self.ssl_handshake().await,
+            GSSENC_REQUEST => {
+                // We don't support GSSAPI encryption, decline so the client falls back to SSL or plain
+                debug!("declining GSSAPI encryption request");
+                let n = self.write_or_buffer(Bytes::from_static(&[SSL_NOT_ALLOWED]))?;
+                debug_assert_eq!(n, 1);
+                Ok(())
+            },
+            CANCEL_REQUEST => {
+                let mut r = msg.reader();
+                r.read_i32(); // skip the request code
+                let (client_id, salt) = (r.read_i32() as u32, r.read_i32());
+                if let Some(cluster) = self.cluster() {
+                    cluster.cancel_map.cancel(client_id, salt).await?;
+                }
+                // Postgres closes the connection without a response, and so do we
+                Err(Error::closed())
+            },
+            PROTOCOL_VERSION_2 => self.reject_old_protocol(),
+            _ => Err(Error::new(format!("{:?}: unsupported protocol {}", self, protocol_version)))
+        }
+    }
+
+    /// Reject a client using protocol version 2.0 with an error message it can display, unless
+    /// reject_old_protocol_silently is set. The message must use the protocol 2.0 format
+    /// (no length or fields, just the text) or old clients can't parse it.
+    fn reject_old_protocol(&self) -> Result<()> {
+        self.startup_guard().old_protocol();
+        debug!(remote_ip=?self.remote_ip(), "rejecting client using protocol version 2.0");
+        if !self.cluster_config().reject_old_protocol_silently {
+            let mut buf = Vec::with_capacity(OLD_PROTOCOL_ERROR.len() + 2);
+            buf.push(Tag::ERROR_RESPONSE.as_u8());
+            buf.extend_from_slice(OLD_PROTOCOL_ERROR.as_bytes());
+            buf.push(0);
+            self.write_or_buffer(Bytes::from(buf))?;
+        }
+        // Return ClosedError so run() doesn't send a protocol 3.0 ErrorResponse or log this
+        Err(Error::closed())
+    }
+
+    #[instrument]
+    async fn ssl_handshake(&self) -> Result<()> {
+        let tls_mode = self.cluster_config().client_tls;
+        // TLS is always declined on a unix socket, it's local-only
+        match tls_mode {
+            _ if !self.stream.can_use_tls() => {
+                let n = self.write_or_buffer(Bytes::from_static(&[SSL_NOT_ALLOWED]))?;
+                debug_assert_eq!(n, 1);
+                Ok(())
+            },
+            TlsMode::Disabled | TlsMode::Invalid => {
+                let n = self.write_or_buffer(Bytes::from_static(&[SSL_NOT_ALLOWED]))?;
+                debug_assert_eq!(n, 1);
+                Ok(())
+            },
+            _ => {
+                let n = self.write_or_buffer(Bytes::from_static(&[SSL_ALLOWED]))?;
+                debug_assert_eq!(n, 1);
+                self.transition(ClientState::SSLHandshake)?;
+                // The certificate may be reloaded, use the current one for the whole handshake
+                let server_tls = match self.cluster() {
+                    Some(cluster) => cluster.server_tls(),
+                    None => ServerTls::from_config(self.cluster_config()),
+                }.unwrap();
+                *self.tls_server_end_point.lock().unwrap() = server_tls.end_point.clone();
+                self.set_wait_event(WaitEvent::TlsHandshake);
+                let result = self.stream.upgrade_server(server_tls.config.clone(), tls_mode).await;
+                self.set_wait_event(WaitEvent::None);
+                result
+            }
+        }
+    }
+
+    #[instrument(fields(request_id))]
+    pub async fn client_query(&self, _: &mut client_query::Event, mut query: QueryMessage) -> Result<()> {
+        if let Some(request_id) = query.tag(REQUEST_ID_TAG) {
+            Span::current().record("request_id", &request_id);
+        }
+
+        let backend = self.backend();
+        if let Some(command) = AdminCommand::parse(&query) {
+            // Don't answer ahead of pipelined queries still waiting on the backend
+            if backend.map_or(true, |backend| backend.pending_requests() == 0) {
+                if command.requires_admin() && !self.is_admin() {
+                    warn!(?command, client=self.id(), "admin command rejected, the user isn't in admin_users");
+                    let msg = "permission denied for riverdb admin command, the user must be in admin_users";
+                    return self.send(error_result(error_codes::INSUFFICIENT_PRIVILEGE, msg, self.state())).await.map(|_| ());
+                }
+                return command.run(self).await;
+            }
+        }
+
+        if !self.check_required_tags(&query) {
+            let msg = "query is missing tags required by the query_tags setting";
+            return self.send(error_result(error_codes::INSUFFICIENT_PRIVILEGE, msg, self.state())).await.map(|_| ());
+        }
+
+        if let Some((code, msg)) = self.denied_query(&query, backend.is_some()) {
+            return self.send(error_result(code, &msg, self.state())).await.map(|_| ());
+        }
+
+        // Only queries with SQL can be rewritten, not executing an existing prepared statement,
+        // or a truncated query, which would lose everything after max_normalize_bytes
+        if !query.query().normalized().is_empty() && !query.is_truncated() {
+            if let Some(sql) = client_rewrite_query::run(self, &query).await? {
+                debug!(%sql, "rewrote query");
+                query = query.with_sql(&sql)?;
+                // A rewrite can't get around the policies, the rewritten query must pass them too
+                if let Some((code, msg)) = self.denied_query(&query, backend.is_some()) {
+                    return self.send(error_result(code, &msg, self.state())).await.map(|_| ());
+                }
+            }
+        }
+
+        let pool_mode = self.pool_mode();
+        let setting = if pool_mode != PoolMode::Session { self.tracked_setting(&query, backend) } else { None };
+        if pool_mode != PoolMode::Session && !self.is_pinned() && query.query().creates_session_state() {
+            debug!(?query, "pinning the backend connection to the session");
+            self.pinned.store(true, Relaxed);
+        }
+        match query.query().query_type() {
+            QueryType::Begin if pool_mode == PoolMode::Statement => {
+                let msg = "transactions are not permitted in statement pool_mode";
+                return self.send(error_result(error_codes::FEATURE_NOT_SUPPORTED, msg, self.state())).await.map(|_| ());
+            },
+            QueryType::Listen | QueryType::Unlisten if pool_mode != PoolMode::Session && query.is_simple_query() && !query.is_multi_query() => {
+                // The backend connection goes back to the pool, LISTEN on the pool's dedicated connection instead
+                return self.listen_query(&query).await;
+            },
+            QueryType::SetSession | QueryType::SetRole if pool_mode != PoolMode::Session && setting.is_none() => {
+                // The server_reset_query undoes this when the backend connection is returned to the pool
+                let msg = "SET ROLE, or SET in a transaction or with other statements, only lasts until the connection returns to the pool in transaction or statement pool_mode";
+                self.send(Messages::new_warning(error_codes::WARNING, msg)).await?;
+            },
+            _ => (),
+        }
+        self.update_tx_type(&query);
+
+        if let Some(command) = self.buffer_begin(&query) {
+            return self.send(command_result(command, ClientState::Transaction)).await.map(|_| ());
+        }
+
+        let audit_id = match self.audit_query(&query).await {
+            Ok(id) => id,
+            Err(e) => {
+                error!(%e, client=self.id(), "could not record query in the audit log");
+                let msg = "query was not run because it could not be recorded in the audit log";
+                return self.send(error_result(error_codes::IO_ERROR, msg, self.state())).await.map(|_| ());
+            },
+        };
+
+        if !self.admit_query(&query).await? {
+            self.audit_outcome(audit_id, "rejected");
+            return Ok(());
+        }
+
+        // The capture starts when the query is sent to the database, see start_result_capture
+        let mut capture = None;
+        if conf().cache.enabled {
+            self.remember_prepared_tables(&query);
+            if query.is_simple_read() {
+                if self.cached_query_result(&query, backend, &mut capture).await? {
+                    // The cached result completes the admitted (and audited) query without the database
+                    self.capture_query(query.messages());
+                    self.audit_sent(&query, audit_id);
+                    self.query_completed(false);
+                    return Ok(());
+                }
+            } else {
+                match self.invalidated_tables(&query) {
+                    Some(tables) => {
+                        if !tables.is_empty() {
+                            self.written_tables.lock().unwrap().extend(tables.iter().cloned());
+                            self.invalidate_cached_results(tables).await;
+                        }
+                    },
+                    None => {
+                        self.wrote_unknown_tables.store(true, Relaxed);
+                        self.invalidate_all_cached_results().await;
+                    },
+                }
+            }
+        }
+
+        if let Some(delay) = self.injected_latency() {
+            sleep(delay).await;
+        }
+
+        if backend.is_none() {
+            // Hold new queries that need a backend while paused, see the PAUSE admin command
+            Peers::singleton().wait_while_paused().await;
+            let cluster = self.cluster.load().expect("missing cluster");
+            let params = self.connection_params();
+            let user = params.get("user").expect("missing user");
+            let database = params.get("database").expect("missing database");
+            let mut application_name = Cow::Borrowed(params.get("application_name").unwrap_or("riverdb"));
+            let query_tags = &self.cluster_config().query_tags;
+            if query_tags.propagate == TracePropagation::ApplicationName {
+                if let Some((tag, value)) = query_tags.trace_tag(|name| query.tag(name)) {
+                    application_name = Cow::Owned(application_name_with_tag(&application_name, tag, value));
+                }
+            }
+            let tx_type = self.tx_type.load();
+            let backend_ark = client_connect_backend::run(self, cluster, &application_name, user, database, tx_type, &mut query).await?;
+            self.replay_session_settings(&backend_ark).await?;
+            if !self.enforce_max_isolation(&backend_ark, &mut query, audit_id).await? {
+                self.set_backend(backend_ark);
+                return Ok(());
+            }
+            self.propagate_trace(&backend_ark, &query).await?;
+            if !query.is_simple_read() {
+                backend_ark.set_session_modified();
+            }
+            backend_ark.track_savepoints(query.query());
+            *self.pending_setting.lock().unwrap() = setting;
+            self.start_result_capture(capture);
+            self.track_parses(&query);
+            self.audit_sent(&query, audit_id);
+            let retry = tx_type == TransactionType::None && query.is_simple_query() && query.is_simple_read()
+                && backend_ark.pool().map_or(false, |pool| pool.config.retry_reads);
+            let msgs = self.with_buffered_begin(&backend_ark, self.record_last_query(query))?;
+            self.capture_query(&msgs);
+            if retry {
+                backend_ark.set_retry_request(msgs.clone());
+            }
+            // Set the backend before sending, so it's set if the backend fails or completes the query right away
+            self.set_backend(backend_ark.clone());
+            backend_ark.send(msgs).await?;
+        } else {
+            let backend = backend.unwrap();
+            if !self.enforce_max_isolation(backend, &mut query, audit_id).await? {
+                return Ok(());
+            }
+            self.propagate_trace(backend, &query).await?;
+            if !query.is_simple_read() {
+                backend.set_session_modified();
+            }
+            backend.track_savepoints(query.query());
+            *self.pending_setting.lock().unwrap() = setting;
+            self.start_result_capture(capture);
+            self.track_parses(&query);
+            self.audit_sent(&query, audit_id);
+            let msgs = self.with_buffered_begin(backend, self.record_last_query(query))?;
+            self.capture_query(&msgs);
+            backend.send(msgs).await?;
+        }
+        Ok(())
+    }
+
+    /// Retry the query msgs on a new backend connection from the same pool, because the failed backend
+    /// connection it was sent to closed before returning any of the result (see retry_reads.)
+    pub(crate) async fn retry_query(&self, failed: &BackendConn, msgs: Messages) -> Result<()> {
+        if !self.backend().map_or(false, |backend| std::ptr::eq(backend, failed)) {
+            return Err(Error::new("client is no longer using the failed backend connection"));
+        }
+        let pool = failed.pool().ok_or_else(|| Error::new("failed backend connection has no pool"))?;
+        let params = self.connection_params();
+        let user = self.effective_user();
+        let user: &str = &user;
+        let application_name = params.get("application_name").unwrap_or("riverdb");
+        // Connections from a user pool are already established as user, so there's no role to set
+        let role = if pool.config.user == user { "" } else { user };
+        let backend_ark = pool.get(application_name, role, TransactionType::None).await?;
+        let backend = backend_ark.load().ok_or_else(|| Error::new(format!("could not get a connection from {:?}", pool)))?;
+        backend.set_client(Ark::from(self));
+        self.set_backend(backend_ark.clone());
+        self.replay_session_settings(backend).await?;
+        backend.send(msgs).await?;
+        Ok(())
+    }
+
+    /// Runs each statement of the multi-statement query as a separate query, one at a time. The ReadyForQuery
+    /// of each statement but the last is held back, so the client sees one response, which stops at the first error.
+    async fn split_query(&self, query: QueryMessage) -> Result<()> {
+        let statements = query.split()?;
+        let last = statements.len() - 1;
+        for (i, statement) in statements.into_iter().enumerate() {
+            if i == last {
+                return client_query::run(self, statement).await;
+            }
+            *self.split_query.lock().unwrap() = SplitQuery{holding: true, held: None, failed: false};
+            client_query::run(self, statement).await?;
+            while timeout(SPLIT_QUERY_POLL, self.split_done.notified()).await.is_err() {
+                if self.is_closed() {
+                    return Err(Error::closed());
+                }
+            }
+            let split = std::mem::take(&mut *self.split_query.lock().unwrap());
+            if split.failed {
+                // Postgres skips the remaining statements after an error, and so do we
+                if let Some(ready) = split.held {
+                    self.write_or_buffer(ready.into_bytes())?;
+                }
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Holds back the ReadyForQuery at the end of msgs while running a statement of a split query, see split_query.
+    /// Returns the messages to send to the client.
+    fn hold_ready_for_query(&self, msgs: Messages) -> Messages {
+        let mut split = self.split_query.lock().unwrap();
+        if !split.holding {
+            return msgs;
+        }
+        let mut ready = None;
+        for msg in msgs.iter(0) {
+            match msg.tag() {
+                Tag::ERROR_RESPONSE => split.failed = true,
+                Tag::READY_FOR_QUERY => ready = Some(msg.offset()),
+                _ => (),
+            }
+        }
+        match ready {
+            Some(offset) => {
+                split.holding = false;
+                split.held = Some(msgs.slice(offset, msgs.len() as usize));
+                self.split_done.notify_one();
+                msgs.slice(0, offset)
+            },
+            None => msgs,
+        }
+    }
+
+    /// Returns the change to the session parameters made by query, if it's a SET or RESET statement on its own,
+    /// outside a transaction, and no other queries are pending on the backend (so the next result is its result.)
+    fn tracked_setting(&self, query: &QueryMessage, backend: Option<&BackendConn>) -> Option<SettingChange> {
+        if !query.is_simple_query() || query.is_multi_query() || query.query().is_truncated() || self.tx_type() != TransactionType::None {
+            return None;
+        }
+        if backend.map_or(false, |backend| backend.pending_requests() != 0) {
+            return None;
+        }
+        SettingChange::parse(query.query())
+    }
+
+    /// Sets the session parameters the client set earlier on the newly checked out backend connection,
+    /// since the server_reset_query undid them when the previous connection returned to the pool.
+    /// If that fails (e.g. the server has a different configuration) they're forgotten and the client is warned.
+    /// On a read only pool (see replica_read_only) transactions are made read only again afterwards,
+    /// so replaying SET SESSION CHARACTERISTICS or default_transaction_read_only can't override it.
+    async fn replay_session_settings(&self, backend: &BackendConn) -> Result<()> {
+        let sql = {
+            let settings = self.session_settings.lock().unwrap();
+            if settings.is_empty() {
+                return Ok(());
+            }
+            let mut sql = settings.replay_sql();
+            if backend.pool().map_or(false, |pool| pool.is_read_only()) {
+                sql.push_str("; SET SESSION CHARACTERISTICS AS TRANSACTION READ ONLY");
+            }
+            sql
+        };
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str(&sql);
+        backend.set_session_modified();
+        if let Err(e) = backend.execute(mb.finish()).await {
+            warn!(%e, %sql, "could not set the session parameters again, forgetting them");
+            *self.session_settings.lock().unwrap() = SessionSettings::new();
+            self.session_isolation.store(IsolationLevel::None);
+            let msg = format!("could not restore the session parameters set earlier: {}", e);
+            self.send(Messages::new_warning(error_codes::WARNING, &msg)).await?;
+        }
+        Ok(())
+    }
+
+    /// Hold BEGIN, and the SET statements following it, to send them to the database with the next statement
+    /// of the transaction (see unbuffered_begin.) Returns the command tag to answer the query with, if it's held.
+    fn buffer_begin(&self, query: &QueryMessage) -> Option<&'static str> {
+        if self.cluster_config().unbuffered_begin || !query.is_simple_query() || query.is_multi_query() {
+            return None;
+        }
+        let mut buffered = self.buffered_begin.lock().unwrap();
+        let command = match query.query().query_type() {
+            // Not in a transaction already, or after pipelined queries that haven't completed
+            QueryType::Begin if buffered.is_empty() && self.state() == ClientState::Ready
+                && self.backend().map_or(true, |backend| backend.pending_requests() == 0) => "BEGIN",
+            QueryType::SetSession | QueryType::SetLocal | QueryType::SetTransaction if !buffered.is_empty() => "SET",
+            _ => return None,
+        };
+        buffered.push(query.sql()?.to_string());
+        Some(command)
+    }
+
+    /// Add the statements held by buffer_begin to msgs, the statement following them, so they're sent to the
+    /// backend in the same write. They're prepended to the SQL of a simple Query, otherwise each is sent with Parse,
+    /// Bind, and Execute before msgs. Either way the database skips the statement if they fail, and the client
+    /// receives their error instead. Their results are dropped, see BackendConn::skip_results.
+    fn with_buffered_begin(&self, backend: &BackendConn, msgs: Messages) -> Result<Messages> {
+        let buffered = std::mem::take(&mut *self.buffered_begin.lock().unwrap());
+        if buffered.is_empty() {
+            return Ok(msgs);
+        }
+        if let Some(msg) = msgs.first().filter(|msg| msg.tag() == Tag::QUERY) {
+            // Each statement is ended by a newline, in case it ends with a -- comment
+            let sql = format!("{}\n; {}", buffered.join("\n; "), msg.reader().read_str()?);
+            let mut mb = MessageBuilder::new(Tag::QUERY);
+            mb.write_str(&sql);
+            backend.skip_results(buffered.len() as u32);
+            return Ok(mb.finish());
+        }
+        let mut mb = MessageBuilder::new(Tag::PARSE);
+        for (i, sql) in buffered.iter().enumerate() {
+            if i != 0 {
+                mb.add_new(Tag::PARSE);
+            }
+            // The unnamed statement and portal were already destroyed by the client's BEGIN Query
+            mb.write_str("");
+            mb.write_str(sql);
+            mb.write_i16(0); // no parameters
+            mb.add_new(Tag::BIND);
+            mb.write_str("");
+            mb.write_str("");
+            mb.write_i16(0); // no parameter formats
+            mb.write_i16(0); // no parameters
+            mb.write_i16(0); // no result formats
+            mb.add_new(Tag::EXECUTE);
+            mb.write_str("");
+            mb.write_i32(0); // no row limit
+        }
+        backend.skip_results(3 * buffered.len() as u32);
+        Ok(mb.finish().append(msgs))
+    }
+
+    /// Set the trace_setting of the query_tags settings to the trace tag of query before it runs, with SET LOCAL
+    /// in a transaction (added to the held BEGIN, if any) and otherwise with SET. See QueryTagSettings::propagate.
+    /// If that fails, the query runs anyway.
+    async fn propagate_trace(&self, backend: &BackendConn, query: &QueryMessage) -> Result<()> {
+        let query_tags = &self.cluster_config().query_tags;
+        if query_tags.propagate != TracePropagation::SetLocal {
+            return Ok(());
+        }
+        let value = match query_tags.trace_tag(|name| query.tag(name)) {
+            Some((_, value)) => quote_str(value),
+            None => return Ok(()),
+        };
+        {
+            let mut buffered = self.buffered_begin.lock().unwrap();
+            if !buffered.is_empty() {
+                buffered.push(format!("SET LOCAL {} TO {}", query_tags.trace_setting, value));
+                return Ok(());
+            }
+        }
+        // Don't run it ahead of the results of pipelined queries, or in a failed transaction
+        let local = match backend.state() {
+            _ if backend.pending_requests() != 0 => return Ok(()),
+            BackendState::Transaction => true,
+            BackendState::FailedTransaction => return Ok(()),
+            _ => false,
+        };
+        let sql = format!("SET {}{} TO {}", if local { "LOCAL " } else { "" }, query_tags.trace_setting, value);
+        if !local {
+            // Undo it when the connection is returned to the pool, even without a server_reset_query
+            backend.reset_on_return(&query_tags.trace_setting);
+        }
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str(&sql);
+        if let Err(e) = backend.execute(mb.finish()).await {
+            warn!(%e, %sql, "could not set the trace setting");
+        }
+        Ok(())
+    }
+
+    /// Release the rate_limiter admission and record the audit outcome of query, which won't be sent to the backend.
+    fn abandon_query(&self, query: &QueryMessage, audit_id: Option<u64>, outcome: &str) {
+        let requests = query.request_count();
+        if self.running_queries.fetch_update(Relaxed, Relaxed, |n| n.checked_sub(requests)).is_ok() {
+            self.release_running_queries(requests);
+        }
+        self.audit_outcome(audit_id, outcome);
+    }
+
+    /// Apply the max_isolation of the backend's pool to the current transaction, if it requested a higher
+    /// isolation level, or began with a higher default set by the client (see session_isolation.) With max_isolation_action downgrade, the BEGIN or SET TRANSACTION is rewritten to use
+    /// max_isolation (or a SET TRANSACTION is added to the held BEGIN) and the client is warned. Otherwise the
+    /// client receives an error instead of the result of query, and this returns false. Queries in the rejected
+    /// transaction are rejected until it ends.
+    async fn enforce_max_isolation(&self, backend: &BackendConn, query: &mut QueryMessage, audit_id: Option<u64>) -> Result<bool> {
+        let requested = self.tx_isolation.load();
+        let pool = match backend.pool() {
+            Some(pool) if pool.config.max_isolation != IsolationLevel::None && requested > pool.config.max_isolation => pool,
+            _ => return Ok(true),
+        };
+        let max = pool.config.max_isolation;
+        let ty = query.query().query_type();
+        // A BEGIN without an isolation level gets the session default, which is higher than max
+        let rewrite = ty == QueryType::Begin || (ty == QueryType::SetTransaction && query.query().isolation_level() > max);
+        // A multi-statement query can't be rewritten without dropping the other statements
+        if pool.config.max_isolation_action == IsolationAction::Downgrade && !(rewrite && query.is_multi_query()) {
+            if rewrite {
+                let mut opts = TransactionOptions::parse(query.query().normalized());
+                opts.isolation = max;
+                let sql = opts.to_sql(if ty == QueryType::Begin { "BEGIN" } else { "SET TRANSACTION" });
+                debug!(%sql, "downgraded transaction isolation level");
+                *query = query.with_sql(&sql)?;
+            } else {
+                let mut buffered = self.buffered_begin.lock().unwrap();
+                if !buffered.is_empty() {
+                    buffered.push(format!("SET TRANSACTION ISOLATION LEVEL {}", max.as_sql()));
+                }
+            }
+            self.tx_isolation.store(max);
+            let msg = format!("isolation level {} is not permitted on this server, using {} instead", requested.as_sql(), max.as_sql());
+            self.send(Messages::new_warning(error_codes::WARNING, &msg)).await?;
+            return Ok(true);
+        }
+
+        debug!(?requested, ?max, "rejected transaction isolation level");
+        self.buffered_begin.lock().unwrap().clear();
+        // If BEGIN itself fails, the client isn't in a transaction
+        let state = if ty == QueryType::Begin && !query.is_multi_query() {
+            self.end_transaction();
+            ClientState::Ready
+        } else {
+            ClientState::FailedTransaction
+        };
+        self.abandon_query(query, audit_id, "rejected");
+        let msg = format!("isolation level {} is not permitted on this server, the maximum is {}", requested.as_sql(), max.as_sql());
+        self.send(error_result(error_codes::FEATURE_NOT_SUPPORTED, &msg, state)).await?;
+        Ok(false)
+    }
+
+    /// Handles LISTEN and UNLISTEN in transaction and statement pool_mode by subscribing this client
+    /// to the channel in the master pool's NotificationHub. Takes effect immediately, even in a transaction.
+    async fn listen_query(&self, query: &QueryMessage) -> Result<()> {
+        let database = self.connection_params().get("database").unwrap_or("").to_string();
+        let user = self.effective_user();
+        let pool = self.cluster().and_then(|cluster| cluster.get_by_database(&database)).and_then(|group| group.master())
+            .and_then(|pool| pool.pool_for(&user, &database));
+        let pool = match pool {
+            Some((pool, _)) => pool,
+            None => {
+                let msg = format!("no database server for {} to LISTEN on", database);
+                return self.send(error_result(error_codes::CONNECTION_FAILURE, &msg, self.state())).await.map(|_| ());
+            },
+        };
+        let channel = parse_channel(query.query().normalized());
+        let command = if query.query().query_type() == QueryType::Listen {
+            match channel {
+                Some(channel) => {
+                    self.listen_pool.store(Some(pool));
+                    pool.notifications().listen(pool, self, &channel).await?;
+                },
+                None => {
+                    let msg = "LISTEN requires a channel name";
+                    return self.send(error_result(error_codes::SYNTAX_ERROR, msg, self.state())).await.map(|_| ());
+                },
+            }
+            "LISTEN"
+        } else {
+            pool.notifications().unlisten(pool, self, channel.as_deref()).await?;
+            "UNLISTEN"
+        };
+        self.send(command_result(command, self.state())).await?;
+        Ok(())
+    }
+
+    /// If a cache policy applies to the query, send the cached result and return true if there's a fresh one.
+    /// Otherwise set capture to capture the result from the database to cache it, and return false.
+    /// Stale results are refreshed by the query that finds them, they're not yet served while refreshing.
+    async fn cached_query_result(&self, query: &QueryMessage, backend: Option<&BackendConn>, capture: &mut Option<ResultCapture>) -> Result<bool> {
+        // Only single simple queries outside of a transaction, and not pipelined after other queries,
+        // so the next ReadyForQuery from the database ends this query's result.
+        if !query.is_simple_query() || query.is_multi_query() || query.query().is_truncated() || self.state() != ClientState::Ready {
+            return Ok(false);
+        }
+        if backend.map_or(false, |backend| backend.pending_requests() != 0) {
+            return Ok(false);
+        }
+        let policy = match conf().cache.policy_for(query) {
+            Some(policy) => policy,
+            None => return Ok(false),
+        };
+        let settings = self.session_settings.lock().unwrap().replay_sql();
+        let key = cache_key(
+            self.cluster_config().port,
+            self.connection_params().get("database").unwrap_or(""),
+            &self.effective_user(),
+            &settings,
+            query.query());
+
+        let cache = ResultCache::singleton();
+        if let Some(entry) = cache.get(&key).await {
+            if entry.is_fresh(unix_now()) {
+                debug!(%key, "sending cached query result");
+                self.send(entry.msgs).await?;
+                return Ok(true);
+            }
+        }
+
+        let tables = cache_tables(query.statements());
+        *capture = Some(ResultCapture::new(key, tables, policy, cache.invalidations()));
+        Ok(false)
+    }
+
+    /// Start capturing the result of the query about to be sent to the database, if cached_query_result
+    /// returned a capture for it. It's only started once nothing can stop the query being sent, otherwise
+    /// the capture would be left waiting and store the result of a later query under this query's key.
+    fn start_result_capture(&self, capture: Option<ResultCapture>) {
+        if let Some(capture) = capture {
+            let mut current = self.result_capture.lock().unwrap();
+            if current.is_none() {
+                *current = Some(capture);
+            }
+        }
+    }
+
+    /// Called with the messages returned by the database for each of this client's queries,
+    /// before they're sent to the client. Stores the result of a cacheable query in the result cache
+    /// once it's complete, and invalidates the cached results for the tables written by the client
+    /// again when its transaction ends, in case they were cached by other clients before it committed.
+    pub(crate) async fn capture_result(&self, msgs: &Messages) {
+        if !conf().cache.enabled {
+            return;
+        }
+        let capture = {
+            let mut capture = self.result_capture.lock().unwrap();
+            let complete = capture.as_mut().map_or(false, |result| result.add(msgs));
+            if complete { capture.take() } else { None }
+        };
+        if let Some(capture) = capture {
+            let key = capture.key().to_string();
+            let cached = capture.finish(ResultCache::singleton()).await;
+            debug!(%key, cached, "captured query result");
+        }
+
+        let is_idle = msgs.iter(0).any(|msg| msg.tag() == Tag::READY_FOR_QUERY && msg.body().first() == Some(&b'I'));
+        if is_idle {
+            let tables = std::mem::take(&mut *self.written_tables.lock().unwrap());
+            if self.wrote_unknown_tables.swap(false, Relaxed) {
+                self.invalidate_all_cached_results().await;
+            } else if !tables.is_empty() {
+                self.invalidate_cached_results(tables).await;
+            }
+        }
+    }
+
+    /// Remember the tables written by each statement query prepares with Parse, and forget the statements it closes,
+    /// so executing them later with only Bind and Execute invalidates the right cached results (see invalidated_tables.)
+    fn remember_prepared_tables(&self, query: &QueryMessage) {
+        if query.is_simple_query() {
+            return;
+        }
+        let mut prepared = self.prepared_tables.lock().unwrap();
+        for (name, statement) in query.parsed_statements() {
+            let tables = if statement.is_simple_read() { Vec::new() } else { cache_tables(std::iter::once(statement)) };
+            prepared.insert(name.to_string(), tables);
+        }
+        for name in query.closed_statements() {
+            prepared.remove(name);
+        }
+    }
+
+    /// Returns the tables whose cached results are invalidated by query, which isn't a simple read.
+    /// For the extended query protocol, these are the tables written by the prepared statements it executes with Bind.
+    /// Returns None if it executes a statement the client didn't prepare with Parse (e.g. one created with PREPARE),
+    /// the tables it writes are unknown.
+    fn invalidated_tables(&self, query: &QueryMessage) -> Option<Vec<String>> {
+        if query.is_simple_query() {
+            return Some(cache_tables(query.statements()));
+        }
+        let prepared = self.prepared_tables.lock().unwrap();
+        let mut tables = Vec::new();
+        for name in query.bound_statements() {
+            tables.extend(prepared.get(name)?.iter().cloned());
+        }
+        Some(tables)
+    }
+
+    /// Remove the cached results of queries reading any of tables, from this and the peer riverdb instances.
+    async fn invalidate_cached_results(&self, mut tables: Vec<String>) {
+        tables.sort_unstable();
+        tables.dedup();
+        if conf().peers.enabled {
+            if let Err(e) = Peers::singleton().broadcast(PeerMessage::InvalidateTables(tables)).await {
+                warn!(%e, "error publishing cache invalidation to peers");
+            }
+        } else {
+            ResultCache::singleton().invalidate_tables(&tables).await;
+        }
+    }
+
+    /// Remove the cached results of queries reading any table, from this and the peer riverdb instances.
+    async fn invalidate_all_cached_results(&self) {
+        if conf().peers.enabled {
+            if let Err(e) = Peers::singleton().broadcast(PeerMessage::InvalidateAllTables).await {
+                warn!(%e, "error publishing cache invalidation to peers");
+            }
+        } else {
+            ResultCache::singleton().invalidate_all_tables().await;
+        }
+    }
+
+    /// Check the query has the tags required by the first matching query_tags rule.
+    /// Logs a warning if any are missing, and returns false if the query should be rejected.
+    fn check_required_tags(&self, query: &QueryMessage) -> bool {
+        let query_tags = &self.cluster_config().query_tags;
+        if query_tags.required.is_empty() {
+            return true;
+        }
+        let params = self.connection_params();
+        let (database, user) = (params.get("database").unwrap_or(""), params.get("user").unwrap_or(""));
+        let rule = match query_tags.required_for(database, user) {
+            Some(rule) => rule,
+            None => return true,
+        };
+        let missing = rule.missing(|tag| query.tag(tag).is_some());
+        if missing.is_empty() {
+            return true;
+        }
+        warn!(?missing, database, user, ?query, "query is missing required tags");
+        rule.action != TagViolationAction::Reject
+    }
+
+    /// Returns the error code and message to reject query with, if it's not permitted by the query_types setting,
+    /// changes the role of a session authenticated with a JSON Web Token, or can't be routed (see check_route.)
+    fn denied_query(&self, query: &QueryMessage, has_backend: bool) -> Option<(&'static str, String)> {
+        if let Some(msg) = self.denied_query_type(query) {
+            return Some((error_codes::INSUFFICIENT_PRIVILEGE, msg));
+        }
+        if self.jwt_role().is_some() && changes_role(query) {
+            let msg = "clients authenticated with a JSON Web Token can't change the role granted by the token";
+            return Some((error_codes::INSUFFICIENT_PRIVILEGE, msg.to_string()));
+        }
+        if let Err(e) = self.check_route(query, has_backend) {
+            return Some((error_codes::FEATURE_NOT_SUPPORTED, e.to_string()));
+        }
+        None
+    }
+
+    /// Returns the error message if a statement in query is not permitted by the first matching query_types rule.
+    /// A truncated query is denied, the statements after max_normalize_bytes can't be checked.
+    fn denied_query_type(&self, query: &QueryMessage) -> Option<String> {
+        let query_types = &self.cluster_config().query_types;
+        // Executing an existing prepared statement has no SQL, it was checked when it was prepared
+        if query_types.rules.is_empty() || query.query().normalized().is_empty() {
+            return None;
+        }
+        let params = self.connection_params();
+        let (database, user) = (params.get("database").unwrap_or(""), params.get("user").unwrap_or(""));
+        let rule = query_types.rule_for(database, user)?;
+        if query.is_truncated() {
+            warn!(database, user, ?query, "query is too large to check its query types");
+            return Some("query is larger than max_normalize_bytes, so it can't be checked against the query_types setting".to_string());
+        }
+        let q = query.statements().find(|q| !rule.permits(q.query_type()))?;
+        warn!(ty=%q.query_type(), database, user, ?query, "query type is not permitted");
+        Some(format!("{} queries are not permitted by the query_types setting", q.query_type()))
+    }
+
+    /// Returns an error if the query can't be routed by the sharding settings: it has keys on more than one shard,
+    /// or an invalid key, or it's routed to a node other than the one the current transaction is bound to
+    /// (see transaction_group), or of the backend connection the client is using.
+    fn check_route(&self, query: &QueryMessage, has_backend: bool) -> Result<()> {
+        let cluster = match self.cluster() {
+            Some(cluster) => cluster,
+            None => return Ok(()),
+        };
+        let node = match route_node(cluster, query)? {
+            Some(node) => node,
+            None => return Ok(()),
+        };
+        let bound = self.transaction_group().or_else(|| if has_backend { self.replication_group() } else { None });
+        if let Some(group) = bound {
+            if cluster.nodes.get(node).map_or(false, |n| std::ptr::eq(n, group)) {
+                return Ok(());
+            }
+            // The coordinator of a sharded cluster can run queries for any worker node
+            if cluster.config.shard_map.enabled {
+                let database = self.connection_params().get("database").unwrap_or("");
+                if cluster.get_by_database(database).map_or(false, |n| std::ptr::eq(n, group)) {
+                    return Ok(());
+                }
+            }
+            return Err(Error::new(if self.in_transaction() {
+                "query is routed to a different database node than the current transaction, which can't switch nodes"
+            } else {
+                "query has a shard key on a different shard than the current session"
+            }));
+        }
+        Ok(())
+    }
+
+    /// Check the query against the rate_limit settings of the cluster. If it would exceed a limit, send the client
+    /// a CONFIGURATION_LIMIT_EXCEEDED error with a hint for when to retry, and return false.
+    /// Admitted queries count as running until query_completed is called for each of them.
+    async fn admit_query(&self, query: &QueryMessage) -> Result<bool> {
+        let cluster = match self.cluster() {
+            Some(cluster) => cluster,
+            None => return Ok(true),
+        };
+        let settings = &cluster.config.rate_limit;
+        let requests = query.request_count();
+        if !settings.is_enabled() || requests == 0 {
+            return Ok(true);
+        }
+        let user = self.effective_user();
+        let database = self.connection_params().get("database").unwrap_or("");
+        match cluster.rate_limiter.admit(settings, &user, database, requests) {
+            Ok(()) => {
+                self.running_queries.fetch_add(requests, Relaxed);
+                Ok(true)
+            },
+            Err(e) => {
+                warn!(%e, client=self.id(), "query rejected by rate_limit");
+                let msgs = error_result_with_hint(error_codes::CONFIGURATION_LIMIT_EXCEEDED, &e.to_string(), &e.hint(), self.state());
+                self.send(msgs).await?;
+                Ok(false)
+            },
+        }
+    }
+
+    /// Called when the database completes a query sent by this client, ends it in the cluster's rate_limiter.
+    /// If it was a tracked SET or RESET statement, and it didn't fail, the change is recorded in session_settings.
+    pub(crate) fn query_completed(&self, failed: bool) {
+        if let Some(change) = self.pending_setting.lock().unwrap().take() {
+            if !failed {
+                if let Some(isolation) = change.default_isolation() {
+                    self.session_isolation.store(isolation);
+                }
+                self.session_settings.lock().unwrap().apply(change);
+            }
+        }
+        if self.running_queries.fetch_update(Relaxed, Relaxed, |n| n.checked_sub(1)).is_ok() {
+            self.release_running_queries(1);
+        }
+        if AuditLog::singleton().is_some() {
+            let mut pending = self.pending_audits.lock().unwrap();
+            pending.completed();
+            while let Some(id) = pending.pop_completed() {
+                self.audit_outcome(Some(id), if failed { "error" } else { "success" });
+            }
+        }
     }
 
-    pub fn set_replication_group(&self, replication_group: Option<&'static PostgresReplicationGroup>) {
-        self.replication_group.store(replication_group);
+    /// Record the query msgs in the capture file as they're sent to the database, if capturing queries
+    /// is enabled (see the capture settings.) Queries rejected before they're sent aren't recorded.
+    fn capture_query(&self, msgs: &Messages) {
+        if let Some(capture) = QueryCapture::singleton() {
+            if capture.wants_database(self.connection_params().get("database").unwrap_or("")) {
+                capture.record(self.id(), msgs);
+            }
+        }
     }
 
-    pub fn pool(&self) -> Option<&'static ConnectionPool> {
-        self.pool.load()
+    /// Record the query in the audit log, if it's audited (see audited_type), before it's sent to the database.
+    /// Returns the id of the record, or an error if it couldn't be recorded, in which case the query isn't run.
+    async fn audit_query(&self, query: &QueryMessage) -> Result<Option<u64>> {
+        let log = match AuditLog::singleton() {
+            Some(log) => log,
+            None => return Ok(None),
+        };
+        let ty = match audited_type(query) {
+            Some(ty) => ty,
+            None => return Ok(None),
+        };
+        let user = self.effective_user();
+        log.record_query(&AuditQuery{
+            client_id: self.id(),
+            ip: self.remote_ip(),
+            user: &user,
+            database: self.connection_params().get("database").unwrap_or(""),
+            ty,
+            sql: query.sql().unwrap_or(""),
+        }).await.map(Some)
     }
 
-    pub fn set_pool(&self, pool: Option<&'static ConnectionPool>) {
-        self.pool.store(pool);
+    /// Track the requests of query sent to the database, to record the outcome of audited queries in query_completed.
+    fn audit_sent(&self, query: &QueryMessage, id: Option<u64>) {
+        if AuditLog::singleton().is_some() {
+            self.pending_audits.lock().unwrap().sent(query.request_count(), id);
+        }
     }
 
-    pub fn connection_params(&self) -> &ServerParams {
-        match self.state.get() {
-            ClientState::StateInitial | ClientState::SSLHandshake => {
-                panic!("can only access connection_params once in the Authentication or later states");
-            },
-            _ => (),
+    /// Called when the backend connection doesn't count a Sync sent by this client as a request, because the database
+    /// ignores it (see BackendConn::is_ignored_sync), so the requests of later queries are still matched correctly.
+    pub(crate) fn sync_ignored(&self) {
+        self.pending_queries.lock().unwrap().ignored();
+        if AuditLog::singleton().is_some() {
+            self.pending_audits.lock().unwrap().ignored();
         }
-        // Safety: we don't allow accessing params (we panic) if ClientState < ClientState::Authentication
-        unsafe {
-            &*self.connect_params.get()
+    }
+
+    /// Called when the backend connection failed and the query in progress wasn't retried on another connection.
+    /// The database won't complete the queries sent on it, so record the outcome of the audited ones.
+    pub(crate) fn backend_failed(&self) {
+        self.pending_queries.lock().unwrap().abandon();
+        if AuditLog::singleton().is_some() {
+            let abandoned = self.pending_audits.lock().unwrap().abandon();
+            for id in abandoned {
+                self.audit_outcome(Some(id), "error");
+            }
         }
     }
 
-    /// For each Message in msgs, constructs a Query object and runs client_query.
-    /// Which forwards the Query or Message to the backend via backend.send.
-    /// If backend is None, runs client_connect_backend to acquire a backend connection.
-    /// Panics unless in Ready, Transaction, or FailedTransaction states.
-    #[instrument]
-    pub async fn forward(&self, msgs: Messages) -> Result<()> {
-        for msg in msgs.iter(0) {
-            match msg.tag() {
-                Tag::QUERY => {
-                    // TODO can we still issue a bulk send here if Query is unaltered?
-                    let query = QueryMessage::new(msgs.split_message(&msg))?;
-                    client_query::run(self, query).await?;
-                },
-                Tag::TERMINATE => {
-                    // This code is slightly different from close() in that it doesn't spawn a new task
-                    self.transition(ClientState::Closed)?;
-                    // This must come after state transition, so release_backend always releases it
-                    let backend = self.release_backend();
-                    if backend.is_some() {
-                        BackendConn::return_to_pool(backend).await;
-                    }
-                    self.stream.close();
-                    break;
-                },
-                _ => {
-                    todo!();
-                }
+    /// Record the outcome of the audited query with the record id, if any.
+    fn audit_outcome(&self, id: Option<u64>, outcome: &str) {
+        if let (Some(id), Some(log)) = (id, AuditLog::singleton()) {
+            if let Err(e) = log.record_outcome(id, outcome) {
+                error!(%e, id, outcome, "could not record query outcome in the audit log");
             }
         }
-        Ok(())
     }
 
-    pub async fn session_idle(&self) -> Result<Ark<BackendConn>> {
-        if self.state() == ClientState::Closed {
-            Ok(Ark::default())
-        } else {
-            client_idle::run(self).await
+    /// End n running queries in the cluster's rate_limiter.
+    fn release_running_queries(&self, n: u32) {
+        if let Some(cluster) = self.cluster() {
+            cluster.rate_limiter.complete(&self.effective_user(), self.connection_params().get("database").unwrap_or(""), n);
         }
     }
 
-    #[instrument]
-    pub fn release_backend(&self) -> Ark<BackendConn> {
-        match self.state.get() {
-            ClientState::Ready | ClientState::Closed => {
-                return self.backend.take();
-            },
-            _ => (),
+    /// Returns the artificial delay to add to the next query, if a latency_injection rule applies to this client.
+    fn injected_latency(&self) -> Option<Duration> {
+        let rules = &self.cluster_config().latency_injection;
+        if rules.rules.is_empty() {
+            return None;
         }
-        Ark::default()
+        let params = self.connection_params();
+        let rule = rules.rule_for(params.get("database").unwrap_or(""), params.get("user").unwrap_or(""))?;
+        let jitter = if rule.jitter_ms > 0 { Worker::get().uniform_rand32(rule.jitter_ms + 1) } else { 0 };
+        Some(Duration::from_millis((rule.delay_ms + jitter) as u64))
     }
 
-    /// Sends a COMMAND_COMPLETE message. Command should usually be a single word that identifies the completed SQL command.
-    /// For an INSERT command, the tag is INSERT 0 rows, where rows is the number of rows inserted.
-    /// For a DELETE command, the tag is DELETE rows where rows is the number of rows deleted.
-    /// For an UPDATE command, the tag is UPDATE rows where rows is the number of rows updated.
-    /// For a SELECT or CREATE TABLE AS command, the tag is SELECT rows where rows is the number of rows retrieved.
-    /// For a MOVE command, the tag is MOVE rows where rows is the number of rows the cursor's position has been changed by.
-    /// For a FETCH command, the tag is FETCH rows where rows is the number of rows that have been retrieved from the cursor.
-    /// For a COPY command, the tag is COPY rows where rows is the number of rows copied.
-    async fn send_command_successful(&self, command: &str, tx_status: char) -> Result<usize> {
-        let mut mb = MessageBuilder::new(Tag::COMMAND_COMPLETE);
-        mb.write_str(command);
-        mb.add_new(Tag::READY_FOR_QUERY);
-        mb.write_byte(tx_status as u8);
-        self.send(mb.finish()).await
+    /// Save the parsed query as the last query sent by this client, track it until the database
+    /// completes it (see record_query_latency), and return its Messages.
+    fn record_last_query(&self, query: QueryMessage) -> Messages {
+        let requests = query.request_count();
+        let (msgs, query) = query.into_parts();
+        let query = Arc::new(query);
+        self.pending_queries.lock().unwrap().sent(requests, query.clone());
+        *self.last_query.lock().unwrap() = Some(query);
+        msgs
     }
 
-    #[instrument]
-    async fn startup(&self, msgs: Messages) -> Result<()> {
-        if msgs.count() != 1 {
-            return Err(Error::new("startup expects exactly one Message"));
+    /// Called when the database completes a client request, with its latency if it was timed.
+    /// Records it in the slow query log of the cluster, if it's enabled and the query it completed
+    /// exceeded the threshold_ms. Pipelined queries are matched to their requests by PendingQueries.
+    pub(crate) fn record_query_latency(&self, latency: Option<Duration>, rows: u64) {
+        let query = self.pending_queries.lock().unwrap().completed();
+        let (query, latency, cluster) = match (query, latency, self.cluster()) {
+            (Some(query), Some(latency), Some(cluster)) => (query, latency, cluster),
+            _ => return,
+        };
+        let settings = &cluster.config.slow_query;
+        if !settings.enabled || latency < Duration::from_millis(settings.threshold_ms as u64) {
+            return;
         }
-
-        let msg = msgs.first().unwrap(); // see msgs.count() condition above
-        assert_eq!(msg.tag(), Tag::UNTAGGED); // was previously checked by msg_is_allowed
-        let protocol_version = msg.reader().read_i32();
-        match protocol_version {
-            PROTOCOL_VERSION => {
-                let params= ServerParams::from_startup_message(&msg)?;
-                let cluster = client_connected::run(self, params).await?;
-                self.set_cluster(Some(cluster));
-                Ok(())
-            },
-            SSL_REQUEST => self.ssl_handshake().await,
-            _ => Err(Error::new(format!("{:?}: unsupported protocol {}", self, protocol_version)))
+        cluster.slow_queries.record(query.normalized(), latency, rows);
+        if settings.log {
+            warn!(query=query.normalized(), latency_ms=latency.as_millis() as u64, rows, client=self.id(), "slow query");
         }
     }
 
-    #[instrument]
-    async fn ssl_handshake(&self) -> Result<()> {
-        let tls_mode = conf().postgres.client_tls;
-        match tls_mode {
-            TlsMode::Disabled | TlsMode::Invalid => {
-                let n = self.write_or_buffer(Bytes::from_static(&[SSL_NOT_ALLOWED]))?;
-                debug_assert_eq!(n, 1);
-                Ok(())
-            },
-            _ => {
-                let n = self.write_or_buffer(Bytes::from_static(&[SSL_ALLOWED]))?;
-                debug_assert_eq!(n, 1);
-                self.transition(ClientState::SSLHandshake)?;
-                let tls_config = conf().postgres.tls_config.clone().unwrap();
-                self.stream.upgrade_server(tls_config, tls_mode).await
-            }
+    /// Returns the Postgres role granted by the JSON Web Token the client authenticated with (see AuthMethod::Jwt), if any.
+    /// It's used instead of the user the client connected as for its backend connections.
+    pub fn jwt_role(&self) -> Option<String> {
+        self.jwt_role.lock().unwrap().clone()
+    }
+
+    /// Returns the user the client's queries run as: the role granted by its JSON Web Token (see jwt_role),
+    /// or else the user it connected as. The cached results, rate_limit, and audit log are keyed by it.
+    pub fn effective_user(&self) -> Cow<'_, str> {
+        match self.jwt_role() {
+            Some(role) => Cow::Owned(role),
+            None => Cow::Borrowed(self.connection_params().get("user").unwrap_or("")),
         }
     }
 
-    #[instrument]
-    pub async fn client_query(&self, _: &mut client_query::Event, mut query: QueryMessage) -> Result<()> {
-        let backend = self.backend();
+    /// Returns true if the client may run the admin commands, see admin_users.
+    fn is_admin(&self) -> bool {
+        let user = self.effective_user();
+        self.cluster_config().admin_users.iter().any(|admin| *admin == user)
+    }
 
-        if backend.is_none() {
-            let cluster = self.cluster.load().expect("missing cluster");
-            let params = self.connection_params();
-            let user = params.get("user").expect("missing user");
-            let database = params.get("database").expect("missing database");
-            let application_name = params.get("application_name").unwrap_or("riverdb");
-            let tx_type = self.tx_type.load();
-            let backend_ark = client_connect_backend::run(self, cluster, application_name, user, database, tx_type, &mut query).await?;
-            backend_ark.send(query.into_messages()).await?;
-            self.set_backend(backend_ark);
-        } else {
-            backend.unwrap().send(query.into_messages()).await?;
-        }
-        Ok(())
+    /// Returns the most recent query sent to the backend by this client, if any.
+    /// When queries are pipelined, this may be later than the query being processed by the backend.
+    pub fn last_query(&self) -> Option<Arc<Query>> {
+        self.last_query.lock().unwrap().clone()
     }
 
-    #[instrument]
+    #[instrument(fields(wait_time_us, connect_time_us, tls_time_us, auth_time_us))]
     pub async fn client_connect_backend<'a>(&'a self, _: &'a mut client_connect_backend::Event, cluster: &'static PostgresCluster, application_name: &'a str, user: &'a str, database: &'a str, tx_type: TransactionType, query: &'a mut QueryMessage) -> Result<Ark<BackendConn>> {
         let mut error_code = error_codes::CANNOT_CONNECT_NOW;
-        let group = client_partition::run(self, cluster, application_name, user, database, tx_type, query).await?;
+        let group = match self.transaction_group() {
+            // A transaction stays on the node it was routed to, see check_route
+            Some(group) => Some(group),
+            None => client_partition::run(self, cluster, application_name, user, database, tx_type, query).await?,
+        };
         if let Some(group) = group {
+            if self.in_transaction() {
+                self.tx_group.store(Some(group));
+            }
             self.set_replication_group(Some(group));
             let pool = if !group.has_query_replica() || tx_type != TransactionType::ReadOnly {
                 group.master()
             } else {
                 client_route_query::run(self, group, tx_type, query).await?
             };
-            if let Some(pool) = pool {
+            // Fail fast with a retriable error (CANNOT_CONNECT_NOW) if the server is marked down by the health checks
+            if let Some(pool) = pool.filter(|pool| pool.is_healthy()) {
+                let jwt_role = self.jwt_role();
+                let user = jwt_role.as_deref().unwrap_or(user);
+                // Connections from a user pool are already established as user, so there's no role to set
+                let (pool, role) = match pool.pool_for(user, database) {
+                    Some(pool) => pool,
+                    None => {
+                        let error_msg = format!("no user pool for user \"{}\" to database \"{}\"", user, database);
+                        self.send(Messages::new_error(error_codes::INVALID_AUTHORIZATION_SPECIFICATION, &error_msg)).await?;
+                        return Err(Error::new(error_msg));
+                    },
+                };
                 self.set_pool(Some(pool));
-                let backend = pool.get(application_name, user, tx_type).await?;
+                let protocol_options = match cluster.config.protocol_options {
+                    ProtocolOptions::Passthrough => self.protocol_options(),
+                    ProtocolOptions::Strip => ServerParams::new(),
+                };
+                let backend = pool.get_reporting_waits(application_name, role, tx_type, &protocol_options, &|event| self.set_wait_event(event)).await;
+                self.set_wait_event(WaitEvent::None);
+                let backend = backend?;
                 if let Some(backend_ref) = backend.load() {
+                    let timings = backend_ref.checkout_timings();
+                    timings.record(&Span::current());
+                    debug!(?timings, total=?timings.total(), "checked out backend connection");
                     let client = Ark::from(self);
                     backend_ref.set_client(client);
                     return Ok(backend);
                 }
+                if pool.is_full() {
+                    // Timed out waiting in line for a connection (see pool_wait_timeout_ms)
+                    let error_msg = "timed out waiting for a database connection, too many clients";
+                    self.send(Messages::new_error(error_codes::TOO_MANY_CONNECTIONS, error_msg)).await?;
+                    return Err(Error::new(error_msg));
+                }
                 error_code = error_codes::CONFIGURATION_LIMIT_EXCEEDED;
             }
         }
@@ -318,33 +1773,118 @@ impl ClientConn {
     }
 
     #[instrument]
-    pub async fn client_partition<'a>(&'a self, _: &'a mut client_partition::Event, cluster: &'static PostgresCluster, _application_name: &'a str, _user: &'a str, database: &'a str, _tx_type: TransactionType, _query: &'a mut QueryMessage) -> Result<Option<&'static PostgresReplicationGroup>> {
+    pub async fn client_partition<'a>(&'a self, _: &'a mut client_partition::Event, cluster: &'static PostgresCluster, _application_name: &'a str, _user: &'a str, database: &'a str, _tx_type: TransactionType, query: &'a mut QueryMessage) -> Result<Option<&'static PostgresReplicationGroup>> {
+        // Queries with a shard key go to the server storing its shard, see check_route for the errors
+        if let Some(node) = route_node(cluster, query)? {
+            return Ok(cluster.nodes.get(node));
+        }
         Ok(cluster.get_by_database(database))
     }
 
+    /// Returns the replacement SQL for query, if any. By default queries aren't rewritten.
+    #[instrument]
+    pub async fn client_rewrite_query<'a>(&'a self, _: &'a mut client_rewrite_query::Event, _query: &'a QueryMessage) -> Result<Option<String>> {
+        Ok(None)
+    }
+
     #[instrument]
     pub async fn client_route_query<'a>(&'a self, _: &'a mut client_route_query::Event, group: &'static PostgresReplicationGroup, _tx_type: TransactionType, _query: &'a mut QueryMessage) -> Result<Option<&'static ConnectionPool>> {
-        Ok(group.master())
+        Ok(group.select_replica().or_else(|| group.master()))
     }
 
     #[instrument]
     pub async fn client_auth_challenge(&self, _: &mut client_auth_challenge::Event, params: ServerParams) -> Result<AuthType> {
-        let auth_type = if self.is_tls() {
-            AuthType::ClearText
-        } else {
-            AuthType::MD5
-        };
-
         // Safety: we don't allow accessing params (we panic) if ClientState < ClientState::Authentication
         unsafe {
             *self.connect_params.get() = params
         };
         self.transition(ClientState::Authentication)?;
 
+        let params = self.connection_params();
+        // user and database exist, see ServerParams::from_startup_message
+        let user = params.get("user").expect("missing user");
+        let database = params.get("database").expect("missing database");
+        let cluster = self.cluster.load().expect("expected db_cluster to be set");
+        let config = self.cluster_config();
+        let rule_method = config.auth_rules.rule_for(self.remote_ip(), database, user).map(|rule| rule.method);
+
+        // MD5 and SCRAM-SHA-256 require knowing the password (or the stored hash or verifier) of the user
+        let credentials = match rule_method {
+            Some(AuthMethod::Trust | AuthMethod::Cert | AuthMethod::Jwt | AuthMethod::Reject) => None,
+            _ => client_check_credentials::run(self, cluster, user, database).await?,
+        };
+        let supports_scram = credentials.as_ref().map_or(false, |c| c.supports_scram());
+        let supports_md5 = credentials.as_ref().map_or(true, |c| c.supports_md5());
+
+        let method = match rule_method {
+            Some(method) => method,
+            None => match config.client_auth {
+                ClientAuth::Scram if supports_scram => AuthMethod::Scram,
+                // Only SCRAM-SHA-256 can verify a password without sending it in clear text over an insecure connection
+                _ if supports_scram && !supports_md5 && !self.is_tls() => AuthMethod::Scram,
+                _ if self.is_tls() => AuthMethod::Password,
+                _ => AuthMethod::Md5,
+            },
+        };
+
+        let auth_type = match method {
+            AuthMethod::Trust => AuthType::Ok,
+            AuthMethod::Jwt if !self.is_tls() => {
+                // The token is sent as a clear text password, it could be replayed by anyone who sees it
+                let error_msg = format!("JWT authentication requires a TLS connection for user \"{}\"", user);
+                self.send(Messages::new_error(error_codes::INVALID_AUTHORIZATION_SPECIFICATION, &error_msg)).await?;
+                return Err(Error::new(error_msg));
+            },
+            AuthMethod::Password | AuthMethod::Jwt => AuthType::ClearText,
+            AuthMethod::Md5 => AuthType::MD5,
+            AuthMethod::Scram => {
+                let tls_server_end_point = if self.is_tls() {
+                    self.tls_server_end_point.lock().unwrap().clone()
+                } else {
+                    vec![]
+                };
+                match credentials.as_ref().and_then(|c| c.scram_server(tls_server_end_point)) {
+                    Some(scram) => *self.scram.lock().unwrap() = Some(scram),
+                    None => {
+                        let error_msg = format!("SCRAM authentication requires the password or SCRAM verifier of the user: {}@{}", user, database);
+                        self.send(Messages::new_error(error_codes::INVALID_AUTHORIZATION_SPECIFICATION, &error_msg)).await?;
+                        return Err(Error::new(error_msg));
+                    },
+                }
+                AuthType::SASL
+            },
+            AuthMethod::Cert if self.has_certificate_for(user) => AuthType::Ok,
+            AuthMethod::Cert => {
+                let error_msg = format!("certificate authentication failed for user \"{}\"", user);
+                self.send(Messages::new_error(error_codes::INVALID_AUTHORIZATION_SPECIFICATION, &error_msg)).await?;
+                return Err(Error::new(error_msg));
+            },
+            AuthMethod::Reject => {
+                let error_msg = format!("auth_rules rejects connection for host \"{}\", user \"{}\", database \"{}\"",
+                    self.remote_ip().map_or(String::from("unknown"), |ip| ip.to_string()), user, database);
+                self.send(Messages::new_error(error_codes::INVALID_AUTHORIZATION_SPECIFICATION, &error_msg)).await?;
+                return Err(Error::new(error_msg));
+            },
+        };
+        debug!(?method, %auth_type, ?credentials, user, database, "authenticating client");
+
+        if auth_type == AuthType::Ok {
+            client_complete_startup::run(self, cluster).await?;
+            return Ok(auth_type);
+        }
+        *self.credentials.lock().unwrap() = credentials;
+
         let mut mb = MessageBuilder::new(Tag::AUTHENTICATION_OK);
         mb.write_i32(auth_type.as_i32());
-        if let AuthType::MD5 = auth_type {
-            mb.write_i32(self.salt);
+        match auth_type {
+            AuthType::MD5 => mb.write_i32(self.salt),
+            AuthType::SASL => {
+                for mechanism in self.scram.lock().unwrap().as_ref().unwrap().mechanisms() {
+                    mb.write_str(mechanism);
+                }
+                mb.write_byte(0);
+            },
+            _ => (),
         }
         self.send(mb.finish()).await?;
 
@@ -367,22 +1907,32 @@ impl ClientConn {
                 let user = params.get("user").expect("missing user");
                 let database = params.get("database").expect("missing database");
 
+                if let AuthType::SASL | AuthType::SASLContinue = auth_type {
+                    return self.sasl_authenticate(cluster, auth_type, msg, user).await;
+                }
+                let rule_method = self.cluster_config().auth_rules.rule_for(self.remote_ip(), database, user).map(|rule| rule.method);
+                if rule_method == Some(AuthMethod::Jwt) {
+                    return self.jwt_authenticate(cluster, msg, user).await;
+                }
+
                 let group = cluster.get_by_database(database);
                 if let Some(group) = group {
-                    let pool = group.master();
-                    if let Some(pool) = pool {
-                        let password = if auth_type == AuthType::ClearText {
-                            msg.reader().read_str()?
-                        } else if user == pool.config.user {
-                            pool.config.password.as_str()
-                        } else {
-                            // TODO confirm this is the right error code
-                            let error_msg = format!("unless the user is the configured user, only clear text authentication is supported: {}@{}", user, database);
-                            self.send(Messages::new_error(error_codes::INVALID_AUTHORIZATION_SPECIFICATION, &error_msg)).await?;
-                            return Err(Error::new(error_msg))
+                    let pool = group.master().and_then(|pool| pool.pool_for(user, database));
+                    if let Some((pool, _)) = pool {
+                        let credentials = self.credentials.lock().unwrap().take();
+                        let authenticated = match (auth_type, credentials) {
+                            (AuthType::ClearText, Some(credentials)) => credentials.verify_password(user, msg.reader().read_str()?),
+                            (AuthType::MD5, Some(credentials)) => credentials.verify_md5(user, msg.reader().read_str()?, self.salt),
+                            (AuthType::ClearText, None) => cluster.authenticate(user, msg.reader().read_str()?, pool).await?,
+                            _ => {
+                                // TODO confirm this is the right error code
+                                let error_msg = format!("unless the user is the configured user or auth_query is set, only clear text authentication is supported: {}@{}", user, database);
+                                self.send(Messages::new_error(error_codes::INVALID_AUTHORIZATION_SPECIFICATION, &error_msg)).await?;
+                                return Err(Error::new(error_msg))
+                            },
                         };
 
-                        return if cluster.authenticate(user, password, pool).await? {
+                        return if authenticated {
                             client_complete_startup::run(self, cluster).await
                         } else {
                             let error_msg = format!("password authentication failed for user \"{}\"", user);
@@ -402,6 +1952,102 @@ impl ClientConn {
         }
     }
 
+    #[instrument]
+    pub async fn client_check_credentials<'a>(&'a self, _: &'a mut client_check_credentials::Event, cluster: &'static PostgresCluster, user: &'a str, database: &'a str) -> Result<Option<Credentials>> {
+        let pool = match cluster.get_by_database(database).and_then(|group| group.master()).and_then(|pool| pool.pool_for(user, database)) {
+            Some((pool, _)) => pool,
+            None => return Ok(None),
+        };
+        if pool.config.user == user {
+            return Ok(Some(Credentials::Password(pool.config.password.clone())));
+        }
+        if cluster.config.auth_query.is_empty() {
+            return Ok(None);
+        }
+        // If the lookup fails, fall back to checking clear text passwords against the database.
+        // Boxed because the future (which connects to the database) is too deeply nested otherwise.
+        match Box::pin(cluster.lookup_credentials(user, pool)).await {
+            Ok(credentials) => Ok(credentials),
+            Err(e) => {
+                warn!(?e, user, "auth_query failed");
+                Ok(None)
+            },
+        }
+    }
+
+    /// Returns true if the client presented a TLS certificate, verified against tls_client_ca_certificate,
+    /// with a common name or DNS subject alternative name matching user.
+    fn has_certificate_for(&self, user: &str) -> bool {
+        let config = self.cluster_config();
+        // Only verified certificates count, the DangerouslyUnverifiedCertificates verifier accepts any certificate
+        if !self.is_tls() || config.tls_client_ca_certificate.is_empty() || matches!(config.client_tls, TlsMode::DangerouslyUnverifiedCertificates) {
+            return false;
+        }
+        match self.stream.peer_certificate().and_then(|cert| certificate_names(&cert.0)) {
+            Some(names) => names.iter().any(|name| name == user),
+            None => false,
+        }
+    }
+
+    /// Handles the SASLInitialResponse and SASLResponse messages of the SCRAM-SHA-256 authentication flow
+    /// started by client_auth_challenge, replying with AuthenticationSASLContinue and AuthenticationSASLFinal.
+    async fn sasl_authenticate(&self, cluster: &'static PostgresCluster, auth_type: AuthType, msg: Message<'_>, user: &str) -> Result<()> {
+        let result = {
+            let mut scram = self.scram.lock().unwrap();
+            let scram = scram.as_mut().ok_or_else(|| Error::new("unexpected SASL message"))?;
+            let mut r = msg.reader();
+            if auth_type == AuthType::SASL {
+                let mechanism = r.read_str()?;
+                let _len = r.read_i32();
+                r.error()?;
+                scram.update(mechanism, r.read_to_end()).map(|data| (AuthType::SASLContinue, data))
+            } else {
+                scram.finish(r.read_to_end()).map(|data| (AuthType::SASLFinal, data))
+            }
+        };
+
+        let (next_auth_type, data) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                debug!(?e, "SCRAM authentication failed");
+                let error_msg = format!("password authentication failed for user \"{}\"", user);
+                self.send(Messages::new_error(error_codes::INVALID_PASSWORD, &error_msg)).await?;
+                return Err(Error::new(error_msg));
+            }
+        };
+
+        let mut mb = MessageBuilder::new(Tag::AUTHENTICATION_OK);
+        mb.write_i32(next_auth_type.as_i32());
+        mb.write_bytes(data.as_bytes());
+        self.send(mb.finish()).await?;
+
+        if next_auth_type == AuthType::SASLFinal {
+            *self.scram.lock().unwrap() = None;
+            client_complete_startup::run(self, cluster).await
+        } else {
+            self.auth_type.store(next_auth_type);
+            Ok(())
+        }
+    }
+
+    /// Verifies the JSON Web Token sent as the password of the client. The role from its claims is used
+    /// instead of the user for the backend connections: it's set with SET ROLE, or chooses its user pool.
+    async fn jwt_authenticate(&self, cluster: &'static PostgresCluster, msg: Message<'_>, user: &str) -> Result<()> {
+        match cluster.jwt.verify(msg.reader().read_str()?) {
+            Ok(role) => {
+                debug!(user, %role, "JWT authentication succeeded");
+                *self.jwt_role.lock().unwrap() = Some(role);
+                client_complete_startup::run(self, cluster).await
+            },
+            Err(e) => {
+                debug!(?e, "JWT authentication failed");
+                let error_msg = format!("JWT authentication failed for user \"{}\"", user);
+                self.send(Messages::new_error(error_codes::INVALID_PASSWORD, &error_msg)).await?;
+                Err(Error::new(error_msg))
+            },
+        }
+    }
+
     #[instrument]
     pub async fn client_complete_startup(&self, _: &mut client_complete_startup::Event, cluster: &PostgresCluster) -> Result<()> {
         let startup_params = cluster.get_startup_params();
@@ -471,22 +2117,45 @@ impl ClientConn {
         for msg in msgs.iter(0) {
             if msg.tag() == Tag::READY_FOR_QUERY {
                 match msg.reader().read_byte() as char {
-                    'I' => self.transition(ClientState::Ready),
+                    'I' => {
+                        self.end_transaction();
+                        self.transition(ClientState::Ready)
+                    },
                     'T' => self.transition(ClientState::Transaction),
                     'E' => self.transition(ClientState::FailedTransaction),
                     _ => Ok(()),
                 }?;
             }
         }
+        let msgs = self.hold_ready_for_query(msgs);
+        if msgs.is_empty() {
+            return Ok(0);
+        }
+        #[cfg(debug_assertions)]
+        self.passthrough.verify(msgs.as_slice());
         self.write_or_buffer(msgs.into_bytes())
     }
 
     #[instrument]
     pub async fn client_idle(&self, _: &mut client_idle::Event) -> Result<Ark<BackendConn>> {
+        if (self.pool_mode() == PoolMode::Session || self.is_pinned()) && self.state() != ClientState::Closed {
+            return Ok(Ark::default());
+        }
         Ok(self.release_backend())
     }
 }
 
+/// The state of the statement being run by ClientConn::split_query.
+#[derive(Default)]
+struct SplitQuery {
+    /// set until the ReadyForQuery of the statement is received
+    holding: bool,
+    /// the ReadyForQuery held back from the client
+    held: Option<Messages>,
+    /// set if the statement returned an error
+    failed: bool,
+}
+
 impl AtomicRefCounted for ClientConn {
     fn refcount(&self) -> u32 {
         self.refcount_and_flags.refcount()
@@ -506,27 +2175,72 @@ impl AtomicRefCounted for ClientConn {
     }
 }
 
-impl ServerConnection for ClientConn {
-    fn new(stream: TcpStream, connections: &'static Connections<Self>) -> Self {
+impl ClientConn {
+    fn with_transport(stream: Transport, remote_ip: Option<IpAddr>, connections: &'static Connections<Self>) -> Self {
+        let mut parser = MessageParser::new();
+        parser.set_max_message_len(conf().postgres.max_startup_packet_bytes);
         ClientConn {
-            stream: Transport::new(stream),
-            parser: UnsafeCell::new(MessageParser::new()),
+            stream,
+            receiver: ReceiveSlot::new(ReceiveHandle::new(parser)),
             id: Default::default(),
             last_active: Default::default(),
             auth_type: AtomicCell::default(),
+            scram: Mutex::new(None),
+            credentials: Mutex::new(None),
+            tls_server_end_point: Mutex::new(Vec::new()),
             refcount_and_flags: RefcountAndFlags::new(),
             state: Default::default(),
             tx_type: AtomicCell::default(),
+            tx_isolation: AtomicCell::default(),
+            session_isolation: AtomicCell::default(),
+            wait_event: AtomicCell::default(),
             backend: Ark::default(),
             send_backlog: Mutex::new(VecDeque::new()),
+            extended_messages: Mutex::new(Messages::default()),
+            last_query: Mutex::new(None),
+            pending_queries: Mutex::new(PendingQueries::default()),
+            result_capture: Mutex::new(None),
+            written_tables: Mutex::new(Vec::new()),
+            wrote_unknown_tables: AtomicBool::new(false),
+            prepared_tables: Mutex::new(HashMap::new()),
+            running_queries: AtomicU32::new(0),
+            session_settings: Mutex::new(SessionSettings::new()),
+            pending_setting: Mutex::new(None),
+            buffered_begin: Mutex::new(Vec::new()),
+            pending_audits: Mutex::new(PendingAudits::default()),
+            pinned: AtomicBool::new(false),
+            pending_parses: Mutex::new(VecDeque::new()),
+            split_query: Mutex::new(SplitQuery::default()),
+            split_done: Notify::new(),
             cluster: AtomicRef::default(),
             replication_group: AtomicRef::default(),
+            tx_group: AtomicRef::default(),
             pool: AtomicRef::default(),
+            listen_pool: AtomicRef::default(),
             connect_params: UnsafeCell::new(ServerParams::new()),
+            jwt_role: Mutex::new(None),
+            protocol_options: Mutex::new(ServerParams::new()),
             salt: Worker::get().rand32() as i32,
+            remote_ip: Mutex::new(remote_ip),
+            extensions: Extensions::new(),
+            #[cfg(debug_assertions)]
+            passthrough: PassthroughChecks::new(),
             connections,
         }
     }
+}
+
+impl ServerConnection for ClientConn {
+    fn new(stream: TcpStream, connections: &'static Connections<Self>) -> Self {
+        let remote_ip = stream.peer_addr().ok().map(|addr| addr.ip());
+        Self::with_transport(Transport::new(stream), remote_ip, connections)
+    }
+
+    #[cfg(unix)]
+    fn new_unix(stream: UnixStream, connections: &'static Connections<Self>) -> Self {
+        // Unix socket clients are local, but don't have an IP address for the auth_rules and bans
+        Self::with_transport(Transport::new_unix(stream), None, connections)
+    }
 
     fn id(&self) -> u32 {
         self.id.load(Relaxed)
@@ -543,6 +2257,16 @@ impl ServerConnection for ClientConn {
     fn close(&self) {
         self.transition(ClientState::Closed).unwrap(); // does not fail
 
+        if let Some(pool) = self.listen_pool.load() {
+            pool.notifications().remove_client(pool, self);
+        }
+
+        // The results of running queries won't be forwarded to this client anymore
+        let running = self.running_queries.swap(0, Relaxed);
+        if running != 0 {
+            self.release_running_queries(running);
+        }
+
         // This must come after state transition, so release_backend always releases it
         let backend = self.release_backend();
         if backend.is_some() {
@@ -568,6 +2292,10 @@ impl Connection for ClientConn {
         &self.send_backlog
     }
 
+    fn max_backlog_bytes(&self) -> u32 {
+        self.cluster_config().max_backlog_bytes
+    }
+
     fn transport(&self) -> &Transport {
         &self.stream
     }
@@ -598,16 +2326,66 @@ impl Debug for ClientConn {
     }
 }
 
-// Safety: we use an UnsafeCell, but access is controlled safely, see connection_params and recv method for details.
+// Safety: we use an UnsafeCell, but access is controlled safely, see connection_params for details.
 unsafe impl Send for ClientConn {}
 unsafe impl Sync for ClientConn {}
 
+/// Returns true if any statement in query changes the role of the session: SET [SESSION | LOCAL] ROLE,
+/// SET SESSION AUTHORIZATION, RESET ROLE, RESET SESSION AUTHORIZATION, RESET ALL, or DISCARD ALL.
+/// Also true if the query is truncated, the statements after max_normalize_bytes can't be checked.
+fn changes_role(query: &QueryMessage) -> bool {
+    query.is_truncated() || query.statements().any(|q| {
+        let sql = q.normalized().to_ascii_uppercase();
+        if let Some(set) = sql.strip_prefix("SET ") {
+            let set = set.strip_prefix("SESSION ").or_else(|| set.strip_prefix("LOCAL ")).unwrap_or(set);
+            set.starts_with("ROLE ") || set.starts_with("AUTHORIZATION ") || set.starts_with("SESSION AUTHORIZATION ")
+        } else if let Some(reset) = sql.strip_prefix("RESET ") {
+            matches!(reset, "ROLE" | "ALL" | "SESSION AUTHORIZATION")
+        } else {
+            sql == "DISCARD ALL"
+        }
+    })
+}
+
+/// Returns the index of the cluster node the query is routed to by the sharding or shard_map settings, if any.
+fn route_node(cluster: &PostgresCluster, query: &QueryMessage) -> Result<Option<usize>> {
+    if cluster.config.sharding.enabled {
+        return route_shard(&cluster.config.sharding, query);
+    }
+    if cluster.config.shard_map.enabled {
+        // Route single-shard queries directly to the worker node, everything else goes to the coordinator
+        return Ok(cluster.shard_map.route(query.query()));
+    }
+    Ok(None)
+}
+
+/// Returns the application_name with the tag=value appended, truncating the application_name
+/// if necessary so that the tag is not cut off by Postgres.
+fn application_name_with_tag(application_name: &str, tag: &str, value: &str) -> String {
+    let suffix = format!(" {}={}", tag, value);
+    let mut end = MAX_APPLICATION_NAME_LEN.saturating_sub(suffix.len()).min(application_name.len());
+    while !application_name.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut name = format!("{}{}", &application_name[..end], suffix);
+    // If the tag alone is too long, keep as much of it as fits
+    if name.len() > MAX_APPLICATION_NAME_LEN {
+        let mut end = MAX_APPLICATION_NAME_LEN;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+    }
+    name
+}
+
 
 define_event! {
     /// client_connected is called when a new client session is being established.
     ///     client: &ClientConn : the event source handling the client connection
     ///     params: &ServerParams : key-value pairs passed by the connected client in the startup message (including database and user)
     /// Returns the database cluster where the BackendConn will later be established (usually pool.get_cluster()).
+    /// By default that's the cluster for the port the client connected to, as set by PostgresService.
     /// ClientConn::client_connected is called by default and sends the authentication challenge in response.
     /// If it returns an error, the associated session is terminated.
     client_connected,
@@ -633,6 +2411,20 @@ define_event! {
     (client: &'a ClientConn, query: QueryMessage) -> Result<()>
 }
 
+define_event! {
+    /// client_rewrite_query is called for each query from the client with SQL, before it's checked,
+    /// routed, and forwarded to the database (admin commands are handled before this.)
+    ///     client: &ClientConn : the event source handling the client connection
+    ///     query: &QueryMessage : the parsed query, with its normalized form, params, and tags
+    /// Returns the SQL to forward instead, or None to keep the query. The replacement is re-packaged
+    /// into a Query message, or for the extended protocol into the Parse message (the parameter count must
+    /// not change.) Query::to_sql, splice_params, quote_str, and tags_comment help build the replacement
+    /// without the risk of SQL injection. By default, ClientConn::client_rewrite_query returns None.
+    /// If it returns an error, the associated session is terminated.
+    client_rewrite_query,
+    (client: &'a ClientConn, query: &'a QueryMessage) -> Result<Option<String>>
+}
+
 define_event! {
     /// client_send_message is called to send a Message to the connected client.
     ///     client: &ClientConn : the event source handling the client connection
@@ -649,6 +2441,23 @@ define_event! {
     (client: &'a ClientConn, params: ServerParams) -> Result<AuthType>
 }
 
+define_event! {
+    /// client_check_credentials is called before sending the authentication challenge to look up the stored
+    /// credentials of the user, which are required for md5 and SCRAM-SHA-256 authentication.
+    ///     client: &ClientConn : the event source handling the client connection
+    ///     cluster: &'static PostgresCluster : the Postgres cluster this connection belongs to.
+    ///     user: &str : the user the client is connecting as
+    ///     database: &str : the database the client is connecting to
+    /// Returns the password, md5 hash, or SCRAM-SHA-256 verifier of the user, or None if unknown. Plugins can
+    /// implement this to authenticate clients against an external source (e.g. LDAP.) By default,
+    /// ClientConn::client_check_credentials returns the password for the user configured for the database,
+    /// or the result of the configured auth_query for other users. If None, clients can only authenticate
+    /// with a clear text password, which is checked by connecting to the database.
+    /// If it returns an error, the associated session is terminated.
+    client_check_credentials,
+    (client: &'a ClientConn, cluster: &'static PostgresCluster, user: &'a str, database: &'a str) -> Result<Option<Credentials>>
+}
+
 define_event! {
     /// TODO
     client_authenticate,
@@ -697,7 +2506,16 @@ define_event! {
 }
 
 define_event! {
-    /// TODO
+    /// client_route_query is called to choose the replica for a read-only query when the
+    /// replication group has queryable replicas.
+    ///     client: &ClientConn : the event source handling the client connection
+    ///     group: &PostgresReplicationGroup : the replication group chosen by client_partition
+    ///     tx_type: TransactionType : the type of the transaction, if any
+    ///     query: &mut QueryMessage : the query to route
+    /// Returns the ConnectionPool to use, or None if no database is available. By default,
+    /// ClientConn::client_route_query chooses a replica with the configured replica_selection
+    /// strategy (taking into account replica weights), skipping replicas that lag more than
+    /// max_replica_lag_ms behind, and falling back to the master.
     client_route_query,
     (
         client: &'a ClientConn,
@@ -711,10 +2529,50 @@ define_event! {
     /// client_idle is called when the connection is ready for a query, and not waiting for a response,
     /// and is not inside a transaction.
     ///     client: &ClientConn : the event source handling the client connection
-    /// Optionally dissociates and returns the BackendConn. By default, if there is a BackendConn,
-    /// ClientConn::client_idle will remove it from this session and return it. The caller
+    /// Optionally dissociates and returns the BackendConn. By default, if there is a BackendConn and the
+    /// pool_mode isn't session, ClientConn::client_idle will remove it from this session and return it. The caller
     /// then typically returns that BackendConn to the connection pool.
     /// If it returns an error, the associated session is terminated.
     client_idle,
     (client: &'a ClientConn) -> Result<Ark<BackendConn>>
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(sql: &str) -> QueryMessage {
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str(sql);
+        QueryMessage::new(mb.finish()).unwrap()
+    }
+
+    #[test]
+    fn test_changes_role() {
+        for sql in ["set role admin", "SET SESSION ROLE admin", "set local role admin", "SET ROLE TO DEFAULT",
+            "set session authorization admin", "RESET ROLE", "reset session authorization", "RESET ALL",
+            "discard all", "SELECT 1; SET ROLE admin"] {
+            assert!(changes_role(&query(sql)), "{}", sql);
+        }
+        for sql in ["SELECT 1", "SET search_path TO app", "RESET search_path", "DISCARD PLANS", "SET roles.x TO 1"] {
+            assert!(!changes_role(&query(sql)), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_application_name_with_tag() {
+        assert_eq!(application_name_with_tag("web", "request_id", "abc-123"), "web request_id=abc-123");
+
+        let name = application_name_with_tag(&"x".repeat(100), "request_id", "abc-123");
+        assert_eq!(name.len(), MAX_APPLICATION_NAME_LEN);
+        assert!(name.ends_with(" request_id=abc-123"));
+
+        let name = application_name_with_tag("web", "request_id", &"1".repeat(100));
+        assert_eq!(name.len(), MAX_APPLICATION_NAME_LEN);
+        assert!(name.starts_with(" request_id=111"));
+
+        let name = application_name_with_tag(&"é".repeat(40), "request_id", "abc-123");
+        assert!(name.len() <= MAX_APPLICATION_NAME_LEN);
+        assert!(name.ends_with(" request_id=abc-123"));
+    }
+}
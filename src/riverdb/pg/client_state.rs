@@ -80,7 +80,7 @@ impl ClientConnState {
             return true;
         }
 
-        // Tags expected from server in Ready or Transaction states
+        // Tags expected from the client in Ready, Transaction, or FailedTransaction states
         const REQUEST_TAGS: &'static [Tag] = &[
             Tag::QUERY,
             Tag::BIND,
@@ -91,6 +91,10 @@ impl ClientConnState {
             Tag::DESCRIBE,
             Tag::FLUSH,
             Tag::SYNC,
+            // sent by the client during COPY FROM STDIN
+            Tag::COPY_DATA,
+            Tag::COPY_DONE,
+            Tag::COPY_FAIL,
         ];
 
         const ALLOWED_TAGS: [&'static [Tag]; 8] = [
@@ -99,7 +103,7 @@ impl ClientConnState {
             &[Tag::PASSWORD_MESSAGE, Tag::AUTHENTICATION_OK, Tag::ERROR_RESPONSE], // Authentication
             REQUEST_TAGS, // Ready
             REQUEST_TAGS, // Transaction
-            &[], // FailedTransaction
+            REQUEST_TAGS, // FailedTransaction
             &[], // Listen
             &[], // no valid tags in Closed
         ];
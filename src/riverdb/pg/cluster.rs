@@ -1,32 +1,76 @@
 use std::fmt::{Debug, Formatter};
 use std::cell::UnsafeCell;
-use std::sync::RwLock;
-use std::sync::atomic::AtomicPtr;
-use std::sync::atomic::Ordering::{AcqRel, Acquire};
+use std::sync::{Arc, RwLock, PoisonError};
+use std::path::Path;
+use std::time::SystemTime;
+use std::sync::atomic::{AtomicPtr, AtomicU32};
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed};
 
 
+use bytes::BytesMut;
 use fnv::FnvHashSet;
+use tokio::time::{interval, sleep, Duration};
+use tracing::{info, warn};
 use crypto::sha2::Sha256;
 use crypto::digest::Digest;
 
-use crate::riverdb::{Result};
+use crate::riverdb::{Error, Result};
 use crate::riverdb::config;
-use crate::riverdb::pg::{PostgresReplicationGroup, ConnectionPool, BackendConn};
+use crate::riverdb::pg::{PostgresReplicationGroup, ConnectionPool, BackendConn, ShardMap, ErrorStats, SlowQueryStats, RateLimiter, CancelMap, TransactionType, JwtVerifier, StartupGuard};
 use crate::riverdb::pg::group::merge_server_params;
-use crate::riverdb::pg::protocol::ServerParams;
+use crate::riverdb::pg::protocol::{ServerParams, MessageBuilder, Tag, Credentials};
+use crate::riverdb::pg::sql::escape_str;
+use crate::riverdb::common::Ark;
+use crate::riverdb::server::Connection;
 
 
+/// The TLS config for client_tls connections to a cluster, with the tls_server_end_point of its certificate
+/// for SCRAM-SHA-256-PLUS channel binding. Replaced as a whole when the certificate is reloaded.
+pub struct ServerTls {
+    pub config: Arc<rustls::ServerConfig>,
+    pub end_point: Vec<u8>,
+    /// the cluster config with the tls_server_certificate and tls_server_key this was loaded from
+    pub settings: &'static config::PostgresCluster,
+}
+
+impl ServerTls {
+    /// Returns the ServerTls loaded with the cluster config, or None if client_tls is disabled.
+    pub fn from_config(config: &'static config::PostgresCluster) -> Option<Arc<Self>> {
+        config.tls_config.as_ref().map(|tls_config| Arc::new(Self{
+            config: tls_config.clone(),
+            end_point: config.tls_server_end_point.clone(),
+            settings: config,
+        }))
+    }
+}
+
 /// A Cluster represents a collection of nodes which store all database partitions.
 /// Each node itself may be a replication group with a single master and multiple read-only replicas.
-/// By default there is only one global singleton Cluster. If you need multiple
-/// clusters, you can run multiple riverdb processes. It's also possible to
-/// have multiple Clusters managed in a single process by using custom plugins.
+/// By default there is only one global singleton Cluster. Additional clusters, each with
+/// its own listen port, can be configured with the clusters setting (see PostgresCluster::all.)
+/// It's also possible to have multiple Clusters managed in a single process by using custom plugins.
 pub struct PostgresCluster {
     /// The configuration for this cluster of replication groups.
     pub config: &'static config::PostgresCluster,
     /// The nodes of the cluster (each node is a replication group which may consist of multiple servers.)
     pub nodes: Vec<PostgresReplicationGroup>,
+    /// The shard map used to route queries to worker nodes if config.shard_map is enabled.
+    pub shard_map: ShardMap,
+    /// The errors returned by Postgres, counted by SQLSTATE class per database and user.
+    pub error_stats: ErrorStats,
+    /// The client queries slower than the slow_query threshold_ms, aggregated by normalized query.
+    pub slow_queries: SlowQueryStats,
+    /// The rate and running queries of each user and database, limited by the rate_limit settings.
+    pub rate_limiter: RateLimiter,
+    /// Maps the backend key data sent to clients to the backend connection they're using, for CancelRequest.
+    pub cancel_map: CancelMap,
+    /// Verifies the tokens of clients using the jwt authentication method.
+    pub jwt: JwtVerifier,
+    /// Bans client ip addresses that repeatedly violate this cluster's startup limits.
+    pub startup_guard: StartupGuard,
     startup_params: UnsafeCell<ServerParams>,
+    server_tls: RwLock<Option<Arc<ServerTls>>>,
+    query_timeout_ms: AtomicU32, // config.query_timeout_ms, updated by RELOAD
     auth_cache: RwLock<FnvHashSet<[u8; 32]>>, // keyed by sha256(user+database+password)
 }
 
@@ -37,7 +81,16 @@ impl PostgresCluster {
         Self{
             config,
             nodes,
+            shard_map: ShardMap::new(),
+            error_stats: ErrorStats::new(&config.error_stats.alerts),
+            slow_queries: SlowQueryStats::new(config.slow_query.max_queries),
+            rate_limiter: RateLimiter::new(),
+            cancel_map: CancelMap::new(),
+            jwt: JwtVerifier::new(&config.jwt),
+            startup_guard: StartupGuard::new(config),
             startup_params: UnsafeCell::new(ServerParams::default()),
+            server_tls: RwLock::new(ServerTls::from_config(config)),
+            query_timeout_ms: AtomicU32::new(config.query_timeout_ms),
             auth_cache: RwLock::new(FnvHashSet::default()),
         }
     }
@@ -66,16 +119,59 @@ impl PostgresCluster {
         }
     }
 
-    /// Returns a reference to the PostgresReplicationGroup of the first partition with a matching database
+    /// Return all the configured PostgresCluster instances, starting with the singleton,
+    /// followed by one for each of the additional clusters in config.
+    pub fn all() -> &'static [&'static Self] {
+        static ALL_CLUSTERS: AtomicPtr<Vec<&'static PostgresCluster>> = AtomicPtr::new(std::ptr::null_mut());
+        unsafe {
+            let mut p = ALL_CLUSTERS.load(Acquire);
+            if p.is_null() {
+                let mut clusters = Box::new(Vec::with_capacity(1 + config::conf().clusters.len()));
+                clusters.push(Self::singleton());
+                for cluster_config in config::conf().clusters.iter() {
+                    clusters.push(&*Box::leak(Box::new(PostgresCluster::new(cluster_config))));
+                }
+                p = clusters.as_mut() as _;
+                match ALL_CLUSTERS.compare_exchange(std::ptr::null_mut(), p, AcqRel, Acquire) {
+                    Ok(_) => {
+                        Box::leak(clusters);
+                    },
+                    Err(current) => {
+                        // Another thread won the race, the clusters we created are leaked, but this is rare
+                        p = current;
+                    },
+                }
+            }
+            (&*p).as_slice()
+        }
+    }
+
+    /// Returns a reference to the PostgresReplicationGroup of the first partition with a matching database,
+    /// the database of its servers or of one of their user_pools.
     pub fn get_by_database(&'static self, database: &str) -> Option<&'static PostgresReplicationGroup> {
         for node in self.nodes.iter() {
-            if node.config.database == database {
+            if node.config.database == database || node.config.user_pools.iter().any(|pool| pool.database == database) {
                 return Some(node);
             }
         }
         None
     }
 
+    /// Returns the pools, masters or replicas, for the server at address, given as host:port.
+    /// The host can be the configured host name or the IP address it resolved to.
+    pub fn pools_by_address(&self, address: &str) -> Vec<&'static ConnectionPool> {
+        let matches = |pool: &ConnectionPool| {
+            format!("{}:{}", pool.config.host, pool.config.port) == address
+                || pool.config.address.map_or(false, |addr| addr.to_string() == address)
+        };
+        let mut pools = Vec::new();
+        for node in self.nodes.iter() {
+            pools.extend(node.master().into_iter().filter(|pool| matches(pool)));
+            pools.extend(node.replicas().iter().cloned().filter(|pool| matches(pool)));
+        }
+        pools
+    }
+
     /// Test a connection to each node in the cluster.
     pub async fn test_connection(&self) -> Result<()> {
         let mut params = futures::future::try_join_all(
@@ -96,6 +192,231 @@ impl PostgresCluster {
         Ok(())
     }
 
+    /// Rebuild the shard map by running the configured shard_map query on the coordinator (the first node.)
+    /// Worker host names are resolved with DNS and matched to the configured nodes by address.
+    /// Placements on unknown nodes are ignored.
+    pub async fn refresh_shard_map(&'static self) -> Result<()> {
+        let coordinator = self.nodes.first()
+            .and_then(|node| node.master())
+            .ok_or_else(|| Error::new("shard_map requires a coordinator"))?;
+        let conn = coordinator.get("riverdb", "", TransactionType::None).await?;
+        let placements = match conn.load() {
+            Some(backend) => self.query_shard_placements(backend).await,
+            None => Err(Error::new(format!("could not connect {:?}", coordinator))),
+        };
+        // Return it on every path, so a failed refresh doesn't lose the connection
+        BackendConn::return_to_pool(conn).await;
+        let placements = placements?;
+
+        let mut nodes_by_host: Vec<((String, u16), Option<usize>)> = Vec::new();
+        let mut resolved = Vec::with_capacity(placements.len());
+        for (table, host, port) in placements.iter() {
+            let key = (host.clone(), *port);
+            let node = match nodes_by_host.iter().find(|(k, _)| *k == key) {
+                Some((_, node)) => *node,
+                None => {
+                    let node = self.resolve_node(host, *port).await;
+                    if node.is_none() {
+                        warn!(%host, port, "shard map references a node that isn't configured");
+                    }
+                    nodes_by_host.push((key, node));
+                    node
+                },
+            };
+            if let Some(node) = node {
+                resolved.push((table.as_str(), node));
+            }
+        }
+
+        self.shard_map.replace(resolved);
+        info!(tables=self.shard_map.len(), "refreshed shard map");
+        Ok(())
+    }
+
+    /// Run the shard_map query on backend, returning the (table, host, port) of each row.
+    async fn query_shard_placements(&self, backend: &BackendConn) -> Result<Vec<(String, String, u16)>> {
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str(&self.config.shard_map.query);
+        let mut rows = backend.query(mb.finish()).await?;
+        let mut placements = Vec::new();
+        while rows.next().await? {
+            let table = rows.get_str(0)?.to_string();
+            let host = rows.get_str(1)?.to_string();
+            let port = rows.get_i32(2)?.unwrap_or(5432) as u16;
+            placements.push((table, host, port));
+        }
+        Ok(placements)
+    }
+
+    /// Returns the index of the node with a master at host:port, comparing by resolved address.
+    async fn resolve_node(&self, host: &str, port: u16) -> Option<usize> {
+        let addrs: Vec<_> = tokio::net::lookup_host((host, port)).await.ok()?.collect();
+        self.nodes.iter().position(|node| {
+            node.config.address.map_or(false, |addr| addrs.contains(&addr))
+        })
+    }
+
+    /// Check for slow replicas in each node every slow_replica.check_seconds until the process exits.
+    pub async fn run_slow_replica_checks(&'static self) {
+        let settings = &self.config.slow_replica;
+        let mut interval = interval(Duration::from_secs(settings.check_seconds as u64));
+        loop {
+            interval.tick().await;
+            for node in self.nodes.iter() {
+                if let Err(e) = node.check_slow_replicas(settings).await {
+                    warn!(?e, ?node, "error checking for slow replicas");
+                }
+            }
+        }
+    }
+
+    /// Returns the query_timeout_ms as a Duration, which is zero if query timeouts are disabled.
+    pub fn query_timeout(&self) -> Duration {
+        Duration::from_millis(self.query_timeout_ms.load(Relaxed) as u64)
+    }
+
+    /// Replace the query_timeout_ms, e.g. when the config is reloaded. 0 disables query timeouts.
+    pub fn set_query_timeout_ms(&self, query_timeout_ms: u32) {
+        self.query_timeout_ms.store(query_timeout_ms, Relaxed);
+    }
+
+    /// Cancel client queries that have been running longer than the query_timeout_ms, until the process exits.
+    /// The query_timeout_ms is read again on every check, so it can be changed or enabled by RELOAD.
+    pub async fn run_query_timeouts(&'static self) {
+        loop {
+            let query_timeout = self.query_timeout();
+            if query_timeout == Duration::from_millis(0) {
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+            // Check often enough that queries aren't allowed to run much longer than the timeout
+            sleep((query_timeout / 4).clamp(Duration::from_millis(10), Duration::from_secs(1))).await;
+            for node in self.nodes.iter() {
+                for pool in node.master().into_iter().chain(node.replicas().iter().cloned()) {
+                    for pool in std::iter::once(pool).chain(pool.user_pools().iter().cloned()) {
+                        let mut running = Vec::new();
+                        pool.connections.for_each(|conn| {
+                            if conn.client_request_elapsed().map_or(false, |elapsed| elapsed >= query_timeout) {
+                                running.push(Ark::from(conn));
+                            }
+                            false
+                        });
+                        for conn in running {
+                            match conn.cancel_if_timed_out(query_timeout).await {
+                                Ok(true) => info!(?pool, backend=conn.id(), "cancelled query that exceeded the query_timeout_ms"),
+                                Ok(false) => (),
+                                Err(e) => warn!(?e, ?pool, "error cancelling query that exceeded the query_timeout_ms"),
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Refresh the shard map every shard_map.refresh_seconds until the process exits.
+    pub async fn run_shard_map_refresh(&'static self) {
+        let mut interval = interval(Duration::from_secs(self.config.shard_map.refresh_seconds as u64));
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.refresh_shard_map().await {
+                warn!(?e, "error refreshing shard map");
+            }
+        }
+    }
+
+    /// Returns the TLS config for new client_tls connections, or None if client_tls is disabled.
+    pub fn server_tls(&self) -> Option<Arc<ServerTls>> {
+        self.server_tls.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Replace the TLS config for new client_tls connections, e.g. with a renewed certificate.
+    /// Existing connections keep using the config they were established with.
+    pub fn set_server_tls(&self, server_tls: Arc<ServerTls>) {
+        *self.server_tls.write().unwrap_or_else(PoisonError::into_inner) = Some(server_tls);
+    }
+
+    /// Check tls_server_certificate and tls_server_key for changes every tls_reload_check_seconds, until the process exits,
+    /// and reload them if they changed. Waits until the files are unchanged for one check, so a certificate and key
+    /// that are replaced one after the other are loaded together. After a RELOAD, checks the files of the reloaded config.
+    pub async fn run_tls_reload_checks(&'static self) {
+        let modified = |settings: &config::PostgresCluster| [&settings.tls_server_certificate, &settings.tls_server_key]
+            .map(|path| Path::new(path).metadata().and_then(|m| m.modified()).ok());
+        let mut settings = self.config;
+        let mut loaded: [Option<SystemTime>; 2] = modified(settings);
+        let mut last = loaded;
+        let mut interval = interval(Duration::from_secs(self.config.tls_reload_check_seconds as u64));
+        loop {
+            interval.tick().await;
+            // RELOAD loaded the certificate and key of the new config, which may be other files
+            if let Some(server_tls) = self.server_tls() {
+                if !std::ptr::eq(server_tls.settings, settings) {
+                    settings = server_tls.settings;
+                    loaded = modified(settings);
+                    last = loaded;
+                }
+            }
+            let current = modified(settings);
+            if current == last && current != loaded {
+                match settings.load_server_tls_config() {
+                    Ok((config, end_point)) => {
+                        let mut server_tls = self.server_tls.write().unwrap_or_else(PoisonError::into_inner);
+                        // Unless a RELOAD replaced the config since the check above
+                        if server_tls.as_ref().map_or(false, |tls| std::ptr::eq(tls.settings, settings)) {
+                            *server_tls = Some(Arc::new(ServerTls{config: Arc::new(config), end_point, settings}));
+                            info!(port=self.config.port, "reloaded tls_server_certificate and tls_server_key");
+                        }
+                    },
+                    Err(e) => warn!(?e, port=self.config.port, "could not reload tls_server_certificate and tls_server_key, keeping the current certificate"),
+                }
+                // Don't retry until they change again
+                loaded = current;
+            }
+            last = current;
+        }
+    }
+
+    /// Spawn the configured background tasks of the cluster and its pools on the current tokio runtime.
+    /// Called once on startup, whether or not the cluster has a PostgresService listening for clients.
+    pub fn spawn_background_tasks(&'static self) {
+        // Reload the client_tls certificate when it's renewed
+        if self.config.tls_reload_check_seconds != 0 && self.server_tls().is_some() {
+            tokio::spawn(self.run_tls_reload_checks());
+        }
+        // Keep the shard map for routing to worker nodes up to date
+        if self.config.shard_map.enabled {
+            tokio::spawn(self.run_shard_map_refresh());
+        }
+        // Quarantine replicas that are much slower than their peers
+        if self.config.slow_replica.enabled {
+            tokio::spawn(self.run_slow_replica_checks());
+        }
+        // Cancel client queries that run longer than the query_timeout_ms, which RELOAD may enable later
+        tokio::spawn(self.run_query_timeouts());
+        // Recycle expired connections and keep min_idle connections established in each pool
+        for node in self.nodes.iter() {
+            for pool in node.master().into_iter().chain(node.replicas().iter().cloned()) {
+                for pool in std::iter::once(pool).chain(pool.user_pools().iter().cloned()) {
+                    if pool.needs_maintainer() {
+                        tokio::spawn(pool.run_maintainer());
+                    }
+                }
+            }
+        }
+        // Mark unreachable servers down and fail over when the master is down
+        if self.config.health_check.enabled {
+            for node in self.nodes.iter() {
+                tokio::spawn(node.run_health_checks(&self.config.health_check));
+            }
+        }
+        // Remove replicas from routing while they lag too far behind the master
+        if self.config.max_replica_lag_ms != 0 {
+            for node in self.nodes.iter() {
+                tokio::spawn(node.run_replica_lag_checks());
+            }
+        }
+    }
+
     /// Get the common/shared ServerParams for the cluster.
     pub fn get_startup_params(&self) -> &ServerParams {
         // Safety: this is not called until after it's initialized (prior to starting the server)
@@ -106,13 +427,45 @@ impl PostgresCluster {
     /// Returns if the authentication was successful (or if cached, returns the cache result.)
     pub async fn authenticate<'a, 'b: 'a, 'c: 'a>(&'a self, user: &'b str, password: &'c str, pool: &'static ConnectionPool) -> Result<bool> {
         let key = hash_sha256(user, password, &pool.config.database);
-        if !self.auth_cache.read().unwrap().contains(&key[..]) {
+        if !self.auth_cache.read().unwrap_or_else(PoisonError::into_inner).contains(&key[..]) {
             let backend = BackendConn::connect(pool.config.address.as_ref().unwrap(), pool.connections).await?;
             backend.test_auth(user, password, pool).await?;
-            self.auth_cache.write().unwrap().insert(key);
+            self.auth_cache.write().unwrap_or_else(PoisonError::into_inner).insert(key);
         }
         Ok(true)
     }
+
+    /// Look up the stored credentials of user by running the configured auth_query on pool.
+    /// Returns None if the query returns no rows or an empty password.
+    pub async fn lookup_credentials(&self, user: &str, pool: &'static ConnectionPool) -> Result<Option<Credentials>> {
+        let mut quoted_user = BytesMut::with_capacity(user.len() + 2);
+        escape_str(&mut quoted_user, user);
+        let query = self.config.auth_query.replace("$1", std::str::from_utf8(&quoted_user)?);
+
+        let conn = pool.get("riverdb", "", TransactionType::None).await?;
+        let credentials = match conn.load() {
+            Some(backend) => query_credentials(backend, &query).await,
+            None => Err(Error::new(format!("could not connect {:?}", pool))),
+        };
+        // Return it on every path, so a failed lookup doesn't lose the connection
+        BackendConn::return_to_pool(conn).await;
+        credentials
+    }
+}
+
+/// Run the auth_query on backend, returning the credentials of the first row with a password.
+async fn query_credentials(backend: &BackendConn, query: &str) -> Result<Option<Credentials>> {
+    let mut mb = MessageBuilder::new(Tag::QUERY);
+    mb.write_str(query);
+    let mut rows = backend.query(mb.finish()).await?;
+    let mut credentials = None;
+    while rows.next().await? {
+        let password = rows.get_str(1)?;
+        if credentials.is_none() && !password.is_empty() {
+            credentials = Some(Credentials::parse(password));
+        }
+    }
+    Ok(credentials)
 }
 
 /// hashes a (user, database, password) tuple with sha256
@@ -128,7 +481,7 @@ fn hash_sha256(user: &str, password: &str, database: &str) -> [u8; 32] {
 
 impl Debug for PostgresCluster {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("PostgresCluster(num_partitions={})", self.config.servers.len()))
+        f.write_fmt(format_args!("PostgresCluster(port={}, num_partitions={})", self.config.port, self.config.servers.len()))
     }
 }
 
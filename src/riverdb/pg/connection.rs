@@ -2,19 +2,26 @@ use std::sync::atomic::AtomicU8;
 use std::sync::atomic::Ordering::{Relaxed};
 use std::sync::{Mutex, MutexGuard};
 use std::collections::VecDeque;
+use std::io::IoSlice;
 
 use tokio::io::{Interest, Ready};
 use bytes::{Bytes, BytesMut, Buf};
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::riverdb::server;
 use crate::riverdb::server::Transport;
 use crate::riverdb::{Error, Result};
+use crate::riverdb::config::conf;
+use crate::riverdb::memory_governor::under_memory_pressure;
 use crate::riverdb::common::{bytes_to_slice_mut, unsplit_bytes, bytes_are_contiguous};
 use crate::riverdb::pg::protocol::{Tag, Messages, MessageParser};
 
 pub type Backlog = Mutex<VecDeque<Bytes>>;
 
+/// The maximum number of backlog chunks written with a single try_write_vectored call.
+/// Well under IOV_MAX (1024 on Linux), and enough that the system call overhead is amortized.
+const MAX_WRITE_CHUNKS: usize = 64;
+
 pub struct RefcountAndFlags(AtomicU8);
 
 impl RefcountAndFlags {
@@ -61,6 +68,8 @@ pub trait Connection: server::Connection {
     fn set_has_backlog(&self, value: bool);
     /// Returns a reference to the backlog, wrapped in a Mutex.
     fn backlog(&self) -> &Mutex<VecDeque<Bytes>>;
+    /// Returns the maximum number of bytes the backlog can hold, or 0 for no limit (see max_backlog_bytes.)
+    fn max_backlog_bytes(&self) -> u32;
     /// Returns a reference to the underlying Transport.
     fn transport(&self) -> &Transport;
     fn is_closed(&self) -> bool;
@@ -72,6 +81,14 @@ pub trait Connection: server::Connection {
         self.transport().is_tls()
     }
 
+    /// Returns the number of bytes in the backlog, waiting to be written to the connection.
+    fn backlog_bytes(&self) -> usize {
+        match self.backlog().lock() {
+            Ok(backlog) => backlog.iter().map(|b| b.remaining()).sum(),
+            Err(_) => 0,
+        }
+    }
+
     /// Writes all the bytes in buf to sender without blocking or buffers it
     /// (without copying) to send later. Takes ownership of buf in all cases.
     /// Returns the number of bytes actually written (not buffered.)
@@ -93,6 +110,31 @@ pub trait Connection: server::Connection {
             }
         }
         // Else we have data buffered pending because the socket is not ready for writing, add buf to the end.
+        // Don't buffer empty chunks, write_backlog would stop at them since they can't be written.
+        if !buf.has_remaining() {
+            return Ok(bytes_written);
+        }
+
+        // Don't let slow connections accumulate large backlogs, especially while over the soft memory limit
+        let max_backlog_bytes = self.max_backlog_bytes();
+        let under_pressure = under_memory_pressure();
+        if max_backlog_bytes != 0 || under_pressure {
+            let backlog_bytes = backlog.iter().map(|b| b.remaining()).sum::<usize>() + buf.remaining();
+            let error = if max_backlog_bytes != 0 && backlog_bytes > max_backlog_bytes as usize {
+                Some(format!("backlog of {} bytes exceeds max_backlog_bytes of {}", backlog_bytes, max_backlog_bytes))
+            } else if under_pressure && backlog_bytes > conf().memory_limit.max_backlog_bytes as usize {
+                Some(format!("backlog of {} bytes exceeds memory_limit max_backlog_bytes while over the soft memory limit", backlog_bytes))
+            } else {
+                None
+            };
+            if let Some(msg) = error {
+                drop(backlog);
+                warn!(connection = ?self, "{}, closing the connection", &msg);
+                // Disconnect the slow connection, the data it hasn't read yet is discarded
+                self.close();
+                return Err(Error::new(msg));
+            }
+        }
 
         // MessageParser often produces a run of contiguous messages, and recombining them here will mean fewer syscalls to write().
         if !backlog.is_empty() && bytes_are_contiguous(&buf, backlog.back().unwrap()) {
@@ -126,29 +168,42 @@ pub trait Connection: server::Connection {
     }
 
     /// With the given locked backlog, write as much data from it to the connection as possible.
+    /// Writes up to MAX_WRITE_CHUNKS chunks at a time with try_write_vectored, so a backlog of many
+    /// small messages (e.g. DataRows) takes a single system call (or TLS session write) per batch.
     fn write_backlog(&self, mut backlog: MutexGuard<VecDeque<Bytes>>) -> Result<usize> {
         let mut write_bytes = 0;
-        loop {
-            // If !backend.is_tls() && backlog.len() > 1 we may want to use try_write_vectored
-            // However, that's not worth the effort yet, and it should be completely pointless once we're
-            // using io_uring through mio. I'm betting on the latter eventually making it unnecessary.
-            if let Some(bytes) = backlog.front_mut() {
-                let n = self.transport().try_write(bytes.chunk())?;
-                write_bytes += n;
+        while !backlog.is_empty() {
+            let mut n = {
+                let mut slices = [IoSlice::new(&[]); MAX_WRITE_CHUNKS];
+                let mut count = 0;
+                for (slice, bytes) in slices.iter_mut().zip(backlog.iter()) {
+                    *slice = IoSlice::new(bytes.chunk());
+                    count += 1;
+                }
+                self.transport().try_write_vectored(&slices[..count])?
+            };
+            if n == 0 {
+                break;
+            }
+            write_bytes += n;
+
+            // Remove the chunks that were completely written, and advance past the written part of the last one
+            while let Some(bytes) = backlog.front_mut() {
+                if n < bytes.remaining() {
+                    bytes.advance(n);
+                    break;
+                }
+                n -= bytes.remaining();
+                backlog.pop_front();
                 if n == 0 {
                     break;
-                } else if n < bytes.remaining() {
-                    bytes.advance(n);
-                } else {
-                    // n == bytes.remaining()
-                    backlog.pop_front();
                 }
-            } else {
-                // Relaxed because the mutex release below is a global barrier
-                self.set_has_backlog(false);
-                break;
             }
         }
+        if backlog.is_empty() {
+            // Relaxed because the mutex release below is a global barrier
+            self.set_has_backlog(false);
+        }
         Ok(write_bytes)
     }
 
@@ -165,8 +220,8 @@ pub trait Connection: server::Connection {
     }
 }
 
-/// Reads from transport and optionally flushes pending data for sender
-/// these two steps are combined in a single task to reduce synchronization and scheduling overhead.
+/// Reads from connection and flushes the pending data (backlog) of connection and sender, if any.
+/// These two steps are combined in a single task to reduce synchronization and scheduling overhead.
 /// This is a free-standing function and not part of the Connection trait because traits don't
 /// support async functions yet, and the async_trait crate boxes the returned future.
 pub(crate) async fn read_and_flush_backlog<R: Connection, W: Connection>(
@@ -178,26 +233,28 @@ pub(crate) async fn read_and_flush_backlog<R: Connection, W: Connection>(
         return Ok((0, 0));
     }
 
-    // Check if we need to write data to maybe_send_transport
-    let interest = Interest::READABLE;
-    let flush = sender.is_some() && sender.unwrap().has_backlog();
-    if flush {
-        interest.add(Interest::WRITABLE);
-    } else if let Some(sender) = sender {
-        // If sender.is_tls(), then it may have data buffered internally too
-        if sender.transport().wants_write() {
-            interest.add(Interest::WRITABLE);
-        }
-    }
+    // Flush the backlog of connection as well as sender, connection's peer may have stopped
+    // flushing it (e.g. a backend returned to the pool before its client read the whole result.)
+    let interest = if connection.has_backlog() {
+        Interest::READABLE.add(Interest::WRITABLE)
+    } else {
+        Interest::READABLE
+    };
+    let flush_sender = sender.map_or(false, |sender| sender.has_backlog());
 
     // Note that once something is ready, it stays ready (this method returns instantly)
     // until it's reset by encountering a WouldBlock error. From mio examples, this
     // seems to apply even if we've never attempted to read or write on the socket.
-    let ready = if connection.transport().wants_read() {
+    let (ready, sender_writable) = if connection.transport().wants_read() {
         // We already have buffered plaintext data waiting on our TLS session, just read it
-        Ready::READABLE
+        (Ready::READABLE, false)
+    } else if flush_sender {
+        tokio::select! {
+            ready = connection.transport().ready(interest) => (ready?, false),
+            ready = sender.unwrap().transport().ready(Interest::WRITABLE) => (tokio::io::Ready::EMPTY, ready?.is_writable()),
+        }
     } else {
-        connection.transport().ready(interest).await.map_err(Error::from)?
+        (connection.transport().ready(interest).await?, false)
     };
 
     let read_bytes = if ready.is_readable() {
@@ -206,19 +263,35 @@ pub(crate) async fn read_and_flush_backlog<R: Connection, W: Connection>(
         0
     };
 
-    let write_bytes = if sender.is_some() && ready.is_writable() {
-        sender.unwrap().try_write_backlog()?
-    } else {
-        0
-    };
+    let mut write_bytes = 0;
+    if ready.is_writable() {
+        write_bytes += connection.try_write_backlog()?;
+    }
+    if sender_writable {
+        write_bytes += sender.unwrap().try_write_backlog()?;
+    }
 
     return Ok((read_bytes, write_bytes))
 }
 
+/// Waits until the backlog of connection has been completely written.
+/// Used when nothing else is going to flush it, e.g. when its peer is about to detach from it.
+pub(crate) async fn flush_backlog<C: Connection>(connection: &C) -> Result<()> {
+    while connection.has_backlog() {
+        connection.transport().ready(Interest::WRITABLE).await?;
+        connection.try_write_backlog()?;
+    }
+    Ok(())
+}
+
 /// Using the given MessageParser to accumulate and parse messages, reads bytes from receiver,
 /// writes any pending backlog data to sender (if not None) and returns the parsed Messages.
 /// Reads at least one Message, or returns an Error.
 pub async fn parse_messages<R: Connection, W: Connection>(parser: &mut MessageParser, receiver: &R, sender: Option<&W>, first_only: bool) -> Result<Messages> {
+    // There may be complete messages left in the buffer from the last call (e.g. after a PROXY protocol header)
+    if let Some(result) = parser.next(first_only) {
+        return result;
+    }
     loop {
         read_and_flush_backlog(
             receiver,
@@ -247,3 +320,69 @@ pub async fn parse_messages<R: Connection, W: Connection>(parser: &mut MessagePa
         }
     }
 }
+
+/// ReceiveHandle owns the MessageParser used to receive Messages from a connection.
+/// There is exactly one per connection, and receiving requires a &mut ReceiveHandle,
+/// so only the task that owns the handle (the one running the connection) can receive.
+/// That makes concurrent use of the parser impossible at compile time, instead of relying
+/// on unsafe "only call this from run()" invariants.
+pub struct ReceiveHandle {
+    parser: MessageParser,
+}
+
+impl ReceiveHandle {
+    pub fn new(parser: MessageParser) -> Self {
+        Self { parser }
+    }
+
+    /// Returns the MessageParser owned by this handle.
+    pub fn parser(&mut self) -> &mut MessageParser {
+        &mut self.parser
+    }
+
+    /// recv parses some Messages from receiver, writing any pending backlog data to sender.
+    #[inline]
+    pub async fn recv<R: Connection, W: Connection>(&mut self, receiver: &R, sender: Option<&W>) -> Result<Messages> {
+        parse_messages(&mut self.parser, receiver, sender, false).await
+    }
+
+    /// recv_one parses a single Message from receiver, writing any pending backlog data to sender.
+    #[inline]
+    pub async fn recv_one<R: Connection, W: Connection>(&mut self, receiver: &R, sender: Option<&W>) -> Result<Messages> {
+        parse_messages(&mut self.parser, receiver, sender, true).await
+    }
+}
+
+/// ReceiveSlot holds the ReceiveHandle of a connection until it's taken by the task that runs it.
+pub struct ReceiveSlot(Mutex<Option<ReceiveHandle>>);
+
+impl ReceiveSlot {
+    pub fn new(handle: ReceiveHandle) -> Self {
+        Self(Mutex::new(Some(handle)))
+    }
+
+    /// Take the ReceiveHandle. Returns an error if it was already taken (and not put back.)
+    pub fn take(&self) -> Result<ReceiveHandle> {
+        self.0.lock().unwrap().take().ok_or_else(|| Error::new("connection is already being run by another task"))
+    }
+
+    /// Put back a ReceiveHandle previously taken from this slot.
+    pub fn put(&self, handle: ReceiveHandle) {
+        let prev = self.0.lock().unwrap().replace(handle);
+        debug_assert!(prev.is_none());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receive_slot() {
+        let slot = ReceiveSlot::new(ReceiveHandle::new(MessageParser::new()));
+        let rx = slot.take().expect("handle");
+        assert!(slot.take().is_err());
+        slot.put(rx);
+        assert!(slot.take().is_ok());
+    }
+}
@@ -0,0 +1,248 @@
+use std::fs;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::{Acquire, Release};
+
+use crypto::sha2::Sha256;
+use crypto::digest::Digest;
+use serde::Serialize;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+use tracing::{info, info_span, warn};
+
+use crate::riverdb::config::{conf, PoolMode, TlsMode};
+use crate::riverdb::server::{Connections, Connection as ServerConnection};
+use crate::riverdb::pg::{ClientConn, BackendConn, Connection, ConnectionPool, PostgresCluster, PostgresService, wait_event_counts};
+use crate::riverdb::plugins::event_listeners;
+#[cfg(unix)]
+use crate::riverdb::supervisor::notify_ready;
+
+
+/// Set once the services are listening, see set_ready.
+static READY: AtomicBool = AtomicBool::new(false);
+static READY_NOTIFY: Notify = Notify::const_new();
+
+/// A summary of the server configuration, logged as a single JSON line on startup by log_startup.
+#[derive(Serialize)]
+pub struct StartupSummary<'a> {
+    pub version: &'static str,
+    pub config_path: String,
+    pub listen: Vec<&'a str>,
+    pub clusters: Vec<ClusterSummary<'a>>,
+    /// the plugins listening for events, in the order they were first registered
+    pub plugins: Vec<&'static str>,
+}
+
+/// The configuration of a PostgresCluster in the StartupSummary.
+#[derive(Serialize)]
+pub struct ClusterSummary<'a> {
+    pub port: u16,
+    pub client_tls: TlsMode,
+    pub backend_tls: TlsMode,
+    pub servers: Vec<ServerSummary<'a>>,
+}
+
+/// The configuration of a master or replica in the StartupSummary.
+#[derive(Serialize)]
+pub struct ServerSummary<'a> {
+    pub database: &'a str,
+    pub address: String,
+    pub is_master: bool,
+    pub pool_mode: PoolMode,
+    pub max_connections: u32,
+    pub min_idle: u32,
+}
+
+impl<'a> StartupSummary<'a> {
+    /// Summarize the configuration of services and the clusters they serve.
+    pub fn new(services: &'a [&'static PostgresService]) -> Self {
+        let mut plugins = Vec::new();
+        for (_, plugin, _) in event_listeners() {
+            if !plugins.contains(&plugin) {
+                plugins.push(plugin);
+            }
+        }
+
+        let clusters = PostgresCluster::all().iter().map(|cluster| {
+            let mut servers = Vec::new();
+            for node in cluster.nodes.iter() {
+                for pool in node.master().into_iter().chain(node.replicas().iter().cloned()) {
+                    servers.push(ServerSummary{
+                        database: &pool.config.database,
+                        address: format!("{}:{}", pool.config.host, pool.config.port),
+                        is_master: pool.config.is_master,
+                        pool_mode: pool.config.pool_mode,
+                        max_connections: pool.config.max_connections,
+                        min_idle: pool.config.min_idle,
+                    });
+                }
+            }
+            ClusterSummary{
+                port: cluster.config.port,
+                client_tls: cluster.config.client_tls,
+                backend_tls: cluster.config.backend_tls,
+                servers,
+            }
+        }).collect();
+
+        Self{
+            version: env!("CARGO_PKG_VERSION"),
+            config_path: conf().config_path.display().to_string(),
+            listen: services.iter().flat_map(|service| service.addresses()).collect(),
+            clusters,
+            plugins,
+        }
+    }
+
+    /// Returns the summary as a single line of JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("serializing startup summary failed")
+    }
+}
+
+/// Log the StartupSummary of services as one JSON line, then log the "ready" event and wake
+/// any tasks waiting in wait_ready, and the supervisor (see notify_ready.) Call once the services are listening for connections.
+pub fn log_startup(services: &[&'static PostgresService]) {
+    info!(summary=%StartupSummary::new(services).to_json(), "startup summary");
+    info!(event="ready", "ready to accept connections");
+    READY.store(true, Release);
+    READY_NOTIFY.notify_waiters();
+    #[cfg(unix)]
+    notify_ready();
+}
+
+/// Returns true once the services are listening for connections, see log_startup.
+pub fn is_ready() -> bool {
+    READY.load(Acquire)
+}
+
+/// Wait until the services are listening for connections, see log_startup.
+/// Use this instead of sleeping before connecting, e.g. in integration tests.
+pub async fn wait_ready() {
+    loop {
+        let notified = READY_NOTIFY.notified();
+        if is_ready() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+
+/// Write a diagnostic snapshot of the server state to the log: every client and backend
+/// connection with its state, the occupancy of each pool, and a digest of the config file.
+/// This is useful for debugging stuck sessions without attaching a debugger.
+/// It's written on SIGUSR1 (see dump_state_on_signal) and by the DUMP STATE admin command.
+pub fn dump_state(clients: &Connections<ClientConn>, cluster: &PostgresCluster) {
+    let _span = info_span!("state dump", ?cluster).entered();
+
+    info!(config_path=%conf().config_path.display(), config_sha256=%config_digest(), "config");
+
+    info!(count=clients.len(), "client connections");
+    for (event, count) in wait_event_counts(clients) {
+        info!(%event, count, "clients by wait event");
+    }
+    clients.for_each(|client| {
+        info!(
+            id=client.id(),
+            state=?client.state(),
+            wait_event=%client.wait_event(),
+            tx_type=%client.tx_type(),
+            backend=client.backend().map_or(0, |backend| backend.id()),
+            backlog=client.backlog().lock().unwrap().len(),
+            idle_seconds=client.idle_seconds(),
+            "client");
+        false
+    });
+
+    for node in cluster.nodes.iter() {
+        if let Some(master) = node.master() {
+            dump_pool(master, true);
+        }
+        for replica in node.replicas() {
+            dump_pool(replica, false);
+        }
+    }
+}
+
+fn dump_pool(pool: &ConnectionPool, is_master: bool) {
+    info!(
+        pool=?pool,
+        is_master,
+        connections=pool.connections.len(),
+        pooled=pool.pooled(),
+        in_use=pool.in_use(),
+        active_transactions=pool.active_transactions(),
+        too_many_connections_errors=pool.too_many_connections_errors(),
+        in_pool_notifications=pool.in_pool_notifications(),
+        in_pool_notices=pool.in_pool_notices(),
+        in_pool_unexpected_messages=pool.in_pool_unexpected_messages(),
+        dropped_messages=pool.dropped_messages(),
+        dropped_bytes=pool.dropped_bytes(),
+        late_messages=pool.late_messages(),
+        queue_depth=pool.queue_depth(),
+        waits=pool.waits(),
+        wait_timeouts=pool.wait_timeouts(),
+        total_wait_ms=pool.total_wait_time().as_millis() as u64,
+        warmup=?pool.warmup_factor(),
+        "pool");
+    pool.connections.for_each(|backend: &BackendConn| {
+        info!(
+            id=backend.id(),
+            state=?backend.state(),
+            client=backend.client().map_or(0, |client| client.id()),
+            pending_requests=%format_args!("{:#b}", backend.pending_requests_bitfield()),
+            backlog=backend.backlog().lock().unwrap().len(),
+            in_pool=backend.in_pool(),
+            "backend");
+        false
+    });
+}
+
+/// Returns the hex encoded sha256 of the config file, or "unknown" if it can't be read.
+fn config_digest() -> String {
+    match fs::read(&conf().config_path) {
+        Ok(contents) => {
+            let mut hasher = Sha256::new();
+            hasher.input(&contents);
+            hasher.result_str()
+        },
+        Err(_) => "unknown".to_string(),
+    }
+}
+
+/// Dump the server state of each service to the log with dump_state each time the process receives SIGUSR1.
+#[cfg(unix)]
+pub async fn dump_state_on_signal(services: Vec<&'static PostgresService>) {
+    let mut sigusr1 = match signal(SignalKind::user_defined1()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!(?e, "could not install SIGUSR1 handler, state dumps are disabled");
+            return;
+        },
+    };
+    while sigusr1.recv().await.is_some() {
+        for service in services.iter() {
+            dump_state(service.connections(), service.cluster());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_log_startup() {
+        let json = StartupSummary::new(&[]).to_json();
+        assert!(!json.contains('\n'));
+        let summary: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(summary["version"], env!("CARGO_PKG_VERSION"));
+        assert!(summary["clusters"].is_array());
+
+        let waiter = tokio::spawn(wait_ready());
+        log_startup(&[]);
+        waiter.await.unwrap();
+        assert!(is_ready());
+    }
+}
@@ -0,0 +1,147 @@
+use std::pin::Pin;
+
+use tracing::warn;
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::config::{self, conf, init_config};
+use crate::riverdb::pg::{PostgresCluster, ConnectionPool, BackendConn, TransactionType, Rows};
+use crate::riverdb::pg::protocol::{MessageBuilder, Tag};
+use crate::riverdb::common::Ark;
+
+
+/// Pool is the supported API for embedding riverdb in a Rust application purely as a connection pool.
+/// The cluster is configured in code rather than riverdb.yaml, and no PostgresService is started,
+/// so there is no listener for clients. Connections are acquired with get or acquire and
+/// queried with BackendConn::query, or query and execute on the returned PooledConn.
+///
+/// The riverdb pools expect to run on worker threads, so create the tokio runtime with
+/// init_runtime(conf()) (or call init_workers with at least as many workers as runtime threads.)
+#[derive(Copy, Clone)]
+pub struct Pool {
+    cluster: &'static PostgresCluster,
+}
+
+impl Pool {
+    /// Create a Pool for the default cluster (postgres) of settings constructed in code.
+    /// This installs settings as the global settings returned by conf() (see init_config),
+    /// so it can only be called once, on startup, and not together with load_config.
+    /// Returns an error if the settings are invalid, or were already installed.
+    pub fn new(settings: config::Settings) -> Result<Self> {
+        let settings = init_config(settings)?;
+        Ok(Self::from_cluster(&settings.postgres))
+    }
+
+    /// Create a Pool for the cluster configuration constructed in code, using the current global
+    /// settings. The configuration is validated, and is leaked, like the cluster created from it.
+    pub fn with_config(config: config::PostgresCluster) -> Result<Self> {
+        // Load in place on the heap, the server configs store pointers to their PostgresCluster
+        let mut config = Box::new(config);
+        config.load()?;
+        Ok(Self::from_cluster(Box::leak(config)))
+    }
+
+    fn from_cluster(config: &'static config::PostgresCluster) -> Self {
+        Self{cluster: Box::leak(Box::new(PostgresCluster::new(config)))}
+    }
+
+    /// Returns the PostgresCluster managed by this Pool.
+    pub fn cluster(&self) -> &'static PostgresCluster {
+        self.cluster
+    }
+
+    /// Test a connection to each node of the cluster and spawn the background tasks
+    /// (e.g. the pool maintainers and health checks) on the current tokio runtime.
+    /// Call this once before using the Pool.
+    pub async fn start(&self) -> Result<()> {
+        self.cluster.test_connection().await?;
+        self.cluster.spawn_background_tasks();
+        Ok(())
+    }
+
+    /// Returns the ConnectionPool for the node at index node in the cluster's servers, the master,
+    /// or a replica chosen by the replica_selection setting if allow_replica is true and one is queryable.
+    pub fn pool(&self, node: usize, allow_replica: bool) -> Result<&'static ConnectionPool> {
+        let group = self.cluster.nodes.get(node)
+            .ok_or_else(|| Error::new(format!("node {} is out of range, the cluster has {} nodes", node, self.cluster.nodes.len())))?;
+        let replica = if allow_replica { group.select_replica() } else { None };
+        replica.or_else(|| group.master()).ok_or_else(|| Error::new(format!("node {} has no master", node)))
+    }
+
+    /// Acquire a connection to the master of the first node. Shorthand for acquire(0, false, TransactionType::None).
+    pub async fn get(&self) -> Result<PooledConn> {
+        self.acquire(0, false, TransactionType::None).await
+    }
+
+    /// Acquire a connection from pool(node, allow_replica) for a transaction of tx_type.
+    /// Returns an error if the pool is at its max_concurrent_transactions, or if it can't connect.
+    pub async fn acquire(&self, node: usize, allow_replica: bool, tx_type: TransactionType) -> Result<PooledConn> {
+        let pool = self.pool(node, allow_replica)?;
+        let conn = pool.get(&conf().app_name, "", tx_type).await?;
+        if conn.is_none() {
+            return Err(Error::new(format!("could not get a connection from {:?}", pool)));
+        }
+        Ok(PooledConn{conn})
+    }
+
+    /// Run sql, which may contain multiple statements, with the simple query protocol on a connection
+    /// to the master of the first node. Returns the number of rows affected by the last statement.
+    pub async fn execute(&self, sql: &str) -> Result<i32> {
+        let conn = self.get().await?;
+        let result = conn.execute(sql).await;
+        conn.release().await;
+        result
+    }
+}
+
+/// A connection checked out of a Pool. Return it to the pool with release when done.
+/// If it's dropped instead, it's returned to the pool by a spawned task.
+pub struct PooledConn {
+    conn: Ark<BackendConn>,
+}
+
+impl PooledConn {
+    /// Returns the BackendConn, for access to its full API.
+    pub fn backend(&self) -> &BackendConn {
+        // conn is only None after release
+        self.conn.load().unwrap()
+    }
+
+    /// Run sql with the extended query protocol and the text format params, where None is NULL.
+    /// Iterate over the result with Rows::next until it returns false, or call Rows::finish.
+    pub async fn query(&self, sql: &str, params: &[Option<&str>]) -> Result<Pin<Box<Rows<'_>>>> {
+        self.backend().query_with_params(sql, params).await
+    }
+
+    /// Run sql, which may contain multiple statements, with the simple query protocol.
+    /// Returns the number of rows affected by the last statement, or the first error.
+    pub async fn execute(&self, sql: &str) -> Result<i32> {
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str(sql);
+        let mut rows = self.backend().query(mb.finish()).await?;
+        rows.complete().await
+    }
+
+    /// Return the connection to its pool.
+    pub async fn release(self) {
+        BackendConn::return_to_pool(self.take()).await;
+    }
+
+    fn take(mut self) -> Ark<BackendConn> {
+        std::mem::take(&mut self.conn)
+    }
+}
+
+impl Drop for PooledConn {
+    fn drop(&mut self) {
+        if self.conn.is_none() {
+            return;
+        }
+        let conn = std::mem::take(&mut self.conn);
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(BackendConn::return_to_pool(conn));
+            },
+            Err(_) => warn!("PooledConn dropped outside of a tokio runtime, closing the connection"),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::{Mutex, PoisonError};
+
+use fnv::FnvHashMap;
+use tracing::{warn};
+
+use crate::riverdb::config::ErrorAlert;
+use crate::riverdb::common::coarse_monotonic_now;
+
+
+/// The key errors are aggregated by: the client database, user, and the SQLSTATE class
+/// (the first two characters of the error code.)
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct ErrorStatsKey {
+    pub database: String,
+    pub user: String,
+    pub class: String,
+}
+
+struct AlertWindow {
+    alert: ErrorAlert,
+    /// coarse_monotonic_now() when the current window started
+    started: u32,
+    /// number of matching errors in the current window
+    count: u32,
+    /// number of windows where the count exceeded alert.max_errors
+    fired: u64,
+}
+
+/// ErrorStats counts the errors returned by Postgres by SQLSTATE class, per database and user,
+/// and logs a warning when the configured alert thresholds are exceeded.
+pub struct ErrorStats {
+    counts: Mutex<FnvHashMap<ErrorStatsKey, u64>>,
+    alerts: Mutex<Vec<AlertWindow>>,
+}
+
+impl ErrorStats {
+    /// Create a new ErrorStats with the given alert thresholds.
+    pub fn new(alerts: &[ErrorAlert]) -> Self {
+        Self {
+            counts: Mutex::new(FnvHashMap::default()),
+            alerts: Mutex::new(alerts.iter().map(|alert| AlertWindow{
+                alert: alert.clone(),
+                started: 0,
+                count: 0,
+                fired: 0,
+            }).collect()),
+        }
+    }
+
+    /// Record an error with the given SQLSTATE code for database and user.
+    pub fn record(&self, database: &str, user: &str, code: &str) {
+        self.record_at(database, user, code, coarse_monotonic_now());
+    }
+
+    fn record_at(&self, database: &str, user: &str, code: &str, now: u32) {
+        let class = code.get(..2).unwrap_or(code);
+        {
+            let mut counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+            let key = ErrorStatsKey{database: database.to_string(), user: user.to_string(), class: class.to_string()};
+            *counts.entry(key).or_insert(0) += 1;
+        }
+
+        let mut alerts = self.alerts.lock().unwrap_or_else(PoisonError::into_inner);
+        for window in alerts.iter_mut() {
+            if !code.starts_with(window.alert.code.as_str()) {
+                continue;
+            }
+            if window.started + window.alert.window_seconds <= now || window.count == 0 {
+                window.started = now;
+                window.count = 0;
+            }
+            window.count += 1;
+            if window.count == window.alert.max_errors + 1 {
+                window.fired += 1;
+                warn!(code=%window.alert.code, errors=window.count, window_seconds=window.alert.window_seconds, database, user, "error rate exceeded alert threshold");
+            }
+        }
+    }
+
+    /// Returns the error counts, sorted by key.
+    pub fn snapshot(&self) -> Vec<(ErrorStatsKey, u64)> {
+        let mut counts: Vec<_> = self.counts.lock().unwrap_or_else(PoisonError::into_inner).iter()
+            .map(|(key, count)| (key.clone(), *count))
+            .collect();
+        counts.sort_unstable();
+        counts
+    }
+
+    /// Returns the number of times each configured alert has fired, in the order they were configured.
+    pub fn alerts_fired(&self) -> Vec<(String, u64)> {
+        self.alerts.lock().unwrap_or_else(PoisonError::into_inner).iter()
+            .map(|window| (window.alert.code.clone(), window.fired))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riverdb::pg::protocol::error_codes;
+
+    fn key(database: &str, user: &str, class: &str) -> ErrorStatsKey {
+        ErrorStatsKey{database: database.to_string(), user: user.to_string(), class: class.to_string()}
+    }
+
+    #[test]
+    fn test_counts_by_class() {
+        let stats = ErrorStats::new(&[]);
+        stats.record("db", "alice", error_codes::SERIALIZATION_FAILURE);
+        stats.record("db", "alice", error_codes::DEADLOCK_DETECTED);
+        stats.record("db", "bob", error_codes::UNIQUE_VIOLATION);
+        stats.record("other", "alice", error_codes::UNIQUE_VIOLATION);
+
+        assert_eq!(stats.snapshot(), vec![
+            (key("db", "alice", "40"), 2),
+            (key("db", "bob", "23"), 1),
+            (key("other", "alice", "23"), 1),
+        ]);
+    }
+
+    #[test]
+    fn test_alert_threshold() {
+        let stats = ErrorStats::new(&[
+            ErrorAlert{code: error_codes::SERIALIZATION_FAILURE.to_string(), max_errors: 2, window_seconds: 10},
+            ErrorAlert{code: "53".to_string(), max_errors: 0, window_seconds: 10},
+        ]);
+        for now in [100, 101, 102, 103] {
+            stats.record_at("db", "alice", error_codes::SERIALIZATION_FAILURE, now);
+        }
+        // Only counted once per window
+        assert_eq!(stats.alerts_fired(), vec![("40001".to_string(), 1), ("53".to_string(), 0)]);
+
+        // A new window, below the threshold
+        stats.record_at("db", "alice", error_codes::SERIALIZATION_FAILURE, 110);
+        stats.record_at("db", "alice", error_codes::DEADLOCK_DETECTED, 111);
+        stats.record_at("db", "alice", error_codes::SERIALIZATION_FAILURE, 112);
+        assert_eq!(stats.alerts_fired()[0].1, 1);
+
+        stats.record_at("db", "alice", error_codes::OUT_OF_MEMORY, 120);
+        assert_eq!(stats.alerts_fired()[1].1, 1);
+    }
+}
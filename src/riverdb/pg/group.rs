@@ -3,13 +3,17 @@ use std::sync::atomic::AtomicU32;
 use std::sync::atomic::Ordering::Relaxed;
 use std::str::FromStr;
 
-use tracing::{warn};
+use tokio::time::{interval, timeout, Duration};
+use tracing::{debug, info, warn, instrument};
 
-use crate::riverdb::config;
+use crate::define_event;
+use crate::riverdb::config::{self, ReplicaSelection, SlowReplicaSettings, HealthCheckSettings};
+use crate::riverdb::worker::Worker;
 use crate::riverdb::{Result, Error};
-use crate::riverdb::pg::{ConnectionPool, TransactionType};
+use crate::riverdb::pg::{BackendConn, ConnectionPool, TransactionType};
+use crate::riverdb::server::Connection as ServerConnection;
 use crate::riverdb::common::{AtomicRef, Version};
-use crate::riverdb::pg::protocol::ServerParams;
+use crate::riverdb::pg::protocol::{ServerParams, MessageBuilder, Tag};
 
 
 /// Represents a Postgres master (writable) database plus optional replicas.
@@ -17,18 +21,18 @@ pub struct PostgresReplicationGroup {
     /// The configuration for this replication group.
     pub config: &'static config::Postgres,
     master: AtomicRef<'static, ConnectionPool>,
-    replicas: Vec<&'static ConnectionPool>,
+    replicas: AtomicRef<'static, Vec<&'static ConnectionPool>>,
     next_replica: AtomicU32,
 }
 
 impl PostgresReplicationGroup {
     /// Create a new replication group with the given configuration.
     pub fn new(config: &'static config::Postgres) -> Self {
-        let replicas = config.replicas.iter().map(|c| &*Box::leak(Box::new(ConnectionPool::new(c)))).collect();
+        let replicas: Vec<_> = config.replicas.iter().map(|c| &*Box::leak(Box::new(ConnectionPool::new(c)))).collect();
         Self{
             config,
             master: AtomicRef::new(Some(Box::leak(Box::new(ConnectionPool::new(config))))),
-            replicas,
+            replicas: AtomicRef::new(Some(Box::leak(Box::new(replicas)))),
             next_replica: AtomicU32::new(0),
         }
     }
@@ -38,25 +42,210 @@ impl PostgresReplicationGroup {
         self.master.load()
     }
 
+    /// Return the replicas of the group.
+    pub fn replicas(&self) -> &'static [&'static ConnectionPool] {
+        self.replicas.load().map_or(&[], |replicas| replicas.as_slice())
+    }
+
+    /// Make replica the master of the group, removing it from the replicas. Used for failover.
+    /// The previous master is no longer used, so it can't accept writes alongside the new master.
+    pub fn set_master(&self, replica: &'static ConnectionPool) {
+        let replicas = self.replicas().iter().cloned().filter(|db| !std::ptr::eq(*db, replica)).collect();
+        self.set_replicas(replicas);
+        replica.set_read_only(false);
+        self.master.store(Some(replica));
+    }
+
+    /// Replace the replicas of the group, used when reloading the config. The previous list
+    /// is leaked rather than freed, because other threads may still be iterating over it.
+    pub fn set_replicas(&self, replicas: Vec<&'static ConnectionPool>) {
+        self.replicas.store(Some(Box::leak(Box::new(replicas))));
+    }
+
     /// Returns true if there is a replica that we can query (see config.can_query).
     pub fn has_query_replica(&self) -> bool {
-        self.replicas.iter().cloned().find(|db| db.config.can_query).is_some()
+        self.replicas().iter().cloned().find(|db| db.config.can_query).is_some()
     }
 
     /// Return the ConnectionPool for the next one of the replicas (if any) or the master.
     pub fn round_robin(&self, allow_replica: bool) -> &'static ConnectionPool {
-        if !allow_replica || !self.has_query_replica() {
+        // Load the replicas once, set_replicas may replace them with an empty list at any time
+        let replicas = self.replicas();
+        if !allow_replica || !replicas.iter().any(|db| db.config.can_query) {
             return self.master.load().unwrap();
         }
 
         // This can produce the same replica occasionally under load, that's fine.
-        let cur = self.next_replica.load(Relaxed);
+        let cur = self.next_replica.load(Relaxed) as usize % replicas.len();
         let mut next = cur + 1;
-        if next == self.replicas.len() as u32 {
+        if next == replicas.len() {
             next = 0;
         }
-        self.next_replica.store(next, Relaxed);
-        self.replicas.get(cur as usize).unwrap()
+        self.next_replica.store(next as u32, Relaxed);
+        replicas[cur]
+    }
+
+    /// Return the ConnectionPool of a queryable replica chosen with the cluster's replica_selection
+    /// strategy, taking into account the replica weights. Returns None if there are no queryable replicas.
+    pub fn select_replica(&self) -> Option<&'static ConnectionPool> {
+        let selection = self.config.cluster.map(|c| c.replica_selection).unwrap_or_default();
+        match selection {
+            ReplicaSelection::WeightedRandom => self.weighted_random(),
+            ReplicaSelection::LeastLoaded => self.least_loaded(),
+        }
+    }
+
+    /// Return a random queryable replica chosen with probability proportional to its weight.
+    /// Replicas that are warming up (see ConnectionPool::start_warmup) have their weight reduced.
+    pub fn weighted_random(&self) -> Option<&'static ConnectionPool> {
+        // The routing weights change over time while warming up, so only compute them once
+        let replicas: Vec<_> = self.query_replicas().map(|db| (db, db.routing_weight())).collect();
+        let total = replicas.iter().fold(0u32, |total, &(_, weight)| total.saturating_add(weight));
+        if total == 0 {
+            return None;
+        }
+        let r = Worker::get().uniform_rand32(total);
+        let i = weighted_index(replicas.iter().map(|&(_, weight)| weight), r)?;
+        Some(replicas[i].0)
+    }
+
+    /// Return the queryable replica with the fewest in-use connections relative to its weight.
+    /// Replicas that are warming up (see ConnectionPool::start_warmup) have their weight reduced.
+    pub fn least_loaded(&self) -> Option<&'static ConnectionPool> {
+        let loads: Vec<_> = self.query_replicas().map(|db| (db.in_use(), db.routing_weight())).collect();
+        if loads.is_empty() {
+            return None;
+        }
+        // Start at a random replica so ties are spread evenly
+        let start = Worker::get().uniform_rand32(loads.len() as u32) as usize;
+        let i = least_loaded_index(&loads, start)?;
+        self.query_replicas().nth(i)
+    }
+
+    fn query_replicas(&self) -> impl Iterator<Item=&'static ConnectionPool> + '_ {
+        let max_lag_ms = self.config.cluster.map_or(0, |c| c.max_replica_lag_ms);
+        self.replicas().iter().cloned()
+            .filter(move |db| db.config.can_query && db.is_healthy() && !db.is_quarantined() && !db.is_draining() && !db.is_lagging(max_lag_ms))
+    }
+
+    /// Measure the replication lag of each replica every replica_lag_check_ms until the process exits.
+    /// Replicas lagging more than max_replica_lag_ms, or whose lag can't be measured, are skipped by select_replica.
+    pub async fn run_replica_lag_checks(&'static self) {
+        let cluster = self.config.cluster.expect("expected cluster to be set");
+        let mut interval = interval(Duration::from_millis(cluster.replica_lag_check_ms as u64));
+        loop {
+            interval.tick().await;
+            for replica in self.replicas() {
+                let lag = match Box::pin(query_replication_lag(replica)).await {
+                    Ok(lag) => lag,
+                    Err(e) => {
+                        warn!(?e, ?replica, "error checking replication lag");
+                        None
+                    },
+                };
+                let was_lagging = replica.is_lagging(cluster.max_replica_lag_ms);
+                replica.set_replication_lag(lag);
+                let lagging = replica.is_lagging(cluster.max_replica_lag_ms);
+                if lagging && !was_lagging {
+                    warn!(?replica, ?lag, max_replica_lag_ms=cluster.max_replica_lag_ms, "replica is lagging, removing it from routing");
+                } else if was_lagging && !lagging {
+                    warn!(?replica, ?lag, "replica caught up, returning it to routing");
+                } else {
+                    debug!(?replica, ?lag, "checked replication lag");
+                }
+            }
+        }
+    }
+
+    /// Returns the queryable replicas with a p95 query latency more than settings.slowdown_factor
+    /// times the median p95 latency of the other replicas, with their p95 and the peers median p95.
+    pub fn slow_replicas(&self, settings: &SlowReplicaSettings) -> Vec<(&'static ConnectionPool, Duration, Duration)> {
+        let replicas: Vec<_> = self.query_replicas().collect();
+        let p95s: Vec<_> = replicas.iter()
+            .map(|db| db.latency().p95(settings.min_samples as usize).map(|d| d.as_micros() as u64))
+            .collect();
+        let min_p95 = settings.min_p95_ms as u64 * 1000;
+        slow_indexes(&p95s, settings.slowdown_factor as u64, min_p95).into_iter()
+            .map(|(i, peers_p95)| (replicas[i], Duration::from_micros(p95s[i].unwrap()), Duration::from_micros(peers_p95)))
+            .collect()
+    }
+
+    /// Run the replica_slow plugins for each of the slow_replicas.
+    pub async fn check_slow_replicas(&self, settings: &SlowReplicaSettings) -> Result<()> {
+        for (replica, p95, peers_p95) in self.slow_replicas(settings) {
+            replica_slow::run(self, replica, p95, peers_p95).await?;
+        }
+        Ok(())
+    }
+
+    /// Called by the replica_slow plugins when replica is much slower than its peers.
+    /// By default this quarantines the replica for slow_replica.quarantine_seconds.
+    #[instrument]
+    pub async fn replica_slow(&self, _: &mut replica_slow::Event, replica: &'static ConnectionPool, p95: Duration, peers_p95: Duration) -> Result<()> {
+        let quarantine_seconds = self.config.cluster.map_or(0, |c| c.slow_replica.quarantine_seconds);
+        warn!(?replica, ?p95, ?peers_p95, quarantine_seconds, "quarantining slow replica");
+        replica.quarantine(Duration::from_secs(quarantine_seconds as u64));
+        Ok(())
+    }
+
+    /// Health check the master and replicas every health_check.check_ms until the process exits.
+    /// Servers that fail health_check.unhealthy_threshold consecutive checks are marked down, and their
+    /// connections are closed, failing the queries in progress. When the master is marked down the
+    /// master_down plugins are run, which may choose a new master.
+    pub async fn run_health_checks(&'static self, settings: &'static HealthCheckSettings) {
+        let mut interval = interval(Duration::from_millis(settings.check_ms as u64));
+        loop {
+            interval.tick().await;
+            let master = self.master();
+            for pool in master.into_iter().chain(self.replicas().iter().cloned()) {
+                let result = match timeout(Duration::from_millis(settings.timeout_ms as u64), Box::pin(health_check(pool))).await {
+                    Ok(result) => result,
+                    Err(_) => Err(Error::new("health check timed out")),
+                };
+                if let Err(e) = &result {
+                    debug!(?e, ?pool, "health check failed");
+                }
+                match pool.record_health_check(result.is_ok(), settings.unhealthy_threshold) {
+                    Some(true) => info!(?pool, "server is up"),
+                    Some(false) => {
+                        warn!(?pool, "server is down, closing its connections");
+                        pool.close_all();
+                        for user_pool in pool.user_pools() {
+                            user_pool.close_all();
+                        }
+                        if master.map_or(false, |master| std::ptr::eq(master, pool)) {
+                            match master_down::run(self, pool).await {
+                                Ok(Some(new_master)) => {
+                                    warn!(?pool, ?new_master, "failing over to new master");
+                                    self.set_master(new_master);
+                                },
+                                Ok(None) => warn!(?pool, "master is down and there is no new master, writes will fail until it's back up"),
+                                Err(e) => warn!(?e, ?pool, "error choosing a new master"),
+                            }
+                        }
+                    },
+                    None => (),
+                }
+            }
+        }
+    }
+
+    /// Called by the master_down plugins when the master of the group is marked down by the health checks.
+    /// If health_check.promote_replica is set, this promotes the healthy replica with the lowest
+    /// failover_priority and returns it. Otherwise it returns None and the master stays the same.
+    #[instrument]
+    pub async fn master_down(&self, _: &mut master_down::Event, master: &'static ConnectionPool) -> Result<Option<&'static ConnectionPool>> {
+        if !self.config.cluster.map_or(false, |c| c.health_check.promote_replica) {
+            return Ok(None);
+        }
+        let candidate = self.replicas().iter().cloned()
+            .filter(|db| db.config.failover_priority != 0 && db.is_healthy() && !db.is_draining())
+            .min_by_key(|db| db.config.failover_priority);
+        if let Some(replica) = candidate {
+            warn!(?replica, "promoting replica to master");
+            query_promote(replica).await?;
+        }
+        Ok(candidate)
     }
 
     /// Test connecting to the master and each replica. Returns the ServerParams from the master
@@ -69,7 +258,7 @@ impl PostgresReplicationGroup {
         }
         let mut master_params = conn.params().clone();
 
-        for replica in &self.replicas {
+        for replica in self.replicas() {
             let conn = replica.get("riverdb", "", TransactionType::None).await?;
             if conn.is_none() {
                 return Err(Error::new(format!("could not connect {:?}", replica)));
@@ -87,6 +276,137 @@ impl Debug for PostgresReplicationGroup {
     }
 }
 
+/// The replication lag in milliseconds: 0 if the server isn't in recovery or the replica has replayed all the WAL
+/// it received, otherwise the time since the last replayed transaction was committed on the master.
+/// It's NULL (unknown) if the WAL receiver isn't streaming from the master, since the replica then isn't receiving
+/// the WAL it would be behind on, or if no transaction has been replayed yet.
+const REPLICATION_LAG_QUERY: &str = "SELECT CASE WHEN NOT pg_is_in_recovery() THEN 0 \
+    WHEN NOT EXISTS (SELECT 1 FROM pg_stat_wal_receiver WHERE status = 'streaming') THEN NULL \
+    WHEN pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0 \
+    ELSE (EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()) * 1000)::bigint END";
+
+/// Query the replication lag of replica. Returns None if it's unknown, see REPLICATION_LAG_QUERY.
+async fn query_replication_lag(replica: &'static ConnectionPool) -> Result<Option<Duration>> {
+    let conn = replica.get("riverdb", "", TransactionType::None).await?;
+    let lag_ms = match conn.load() {
+        Some(backend) => run_replication_lag(backend).await,
+        None => Err(Error::new(format!("could not connect {:?}", replica))),
+    };
+    // Return it on every path, so the connection isn't lost when the query fails
+    BackendConn::return_to_pool(conn).await;
+    Ok(lag_ms?.map(|lag_ms| Duration::from_millis(lag_ms.max(0) as u64)))
+}
+
+/// Runs the REPLICATION_LAG_QUERY on backend, returns the lag in milliseconds, or None if it returned NULL.
+async fn run_replication_lag(backend: &BackendConn) -> Result<Option<i64>> {
+    let mut mb = MessageBuilder::new(Tag::QUERY);
+    mb.write_str(REPLICATION_LAG_QUERY);
+    let mut rows = backend.query(mb.finish()).await?;
+    let mut lag_ms = None;
+    let mut returned = false;
+    while rows.next().await? {
+        returned = true;
+        if !rows.is_null(0)? {
+            // Simple queries return values in text format
+            lag_ms = Some(rows.get_str(0)?.parse::<i64>().map_err(|e| Error::new(format!("invalid replication lag: {}", e)))?);
+        }
+    }
+    if !returned {
+        return Err(Error::new("replication lag query returned no rows"));
+    }
+    Ok(lag_ms)
+}
+
+/// Check that the server of pool is up by connecting and authenticating a dedicated connection, which is then closed.
+/// It doesn't wait in the pool's queue or retry connecting (see connect_retry), so a busy pool or retries
+/// can't delay noticing that the server is down.
+async fn health_check(pool: &'static ConnectionPool) -> Result<()> {
+    let address = pool.config.address.as_ref().ok_or_else(|| Error::new(format!("{:?} has no address", pool)))?;
+    let backend = BackendConn::connect(address, pool.connections).await?;
+    let result = backend.authenticate(pool).await;
+    backend.close();
+    result
+}
+
+/// Promote replica to a master with pg_promote(), which waits up to 60 seconds for the promotion to finish.
+async fn query_promote(replica: &'static ConnectionPool) -> Result<()> {
+    let conn = replica.get("riverdb", "", TransactionType::None).await?;
+    let result = match conn.load() {
+        Some(backend) => run_promote(backend).await,
+        None => Err(Error::new(format!("could not connect {:?}", replica))),
+    };
+    // Return it on every path, so the connection isn't lost when the query fails
+    BackendConn::return_to_pool(conn).await;
+    if !result? {
+        return Err(Error::new(format!("pg_promote() failed on {:?}", replica)));
+    }
+    Ok(())
+}
+
+/// Runs pg_promote() on backend, returns true if it succeeded.
+async fn run_promote(backend: &BackendConn) -> Result<bool> {
+    let mut mb = MessageBuilder::new(Tag::QUERY);
+    mb.write_str("SELECT pg_promote()");
+    let mut rows = backend.query(mb.finish()).await?;
+    let mut promoted = false;
+    while rows.next().await? {
+        promoted = rows.get_str(0)? == "t";
+    }
+    Ok(promoted)
+}
+
+/// Returns the index of the weight in which r falls when the weights are laid end to end.
+/// r must be < the sum of the weights.
+fn weighted_index<I: Iterator<Item=u32>>(weights: I, mut r: u32) -> Option<usize> {
+    for (i, weight) in weights.enumerate() {
+        if r < weight {
+            return Some(i);
+        }
+        r -= weight;
+    }
+    None
+}
+
+/// Returns the index of the (in_use, weight) pair with the lowest in_use / weight ratio,
+/// checking from start and wrapping around, so the first lowest after start wins ties.
+fn least_loaded_index(loads: &[(usize, u32)], start: usize) -> Option<usize> {
+    let mut best: Option<usize> = None;
+    for n in 0..loads.len() {
+        let i = (start + n) % loads.len();
+        let (in_use, weight) = loads[i];
+        best = match best {
+            // in_use / weight < best_in_use / best_weight, without division
+            Some(b) if (in_use as u64) * (loads[b].1 as u64) >= (loads[b].0 as u64) * (weight as u64) => Some(b),
+            _ => Some(i),
+        };
+    }
+    best
+}
+
+/// Returns the indexes of the p95 latencies that are at least min_p95 and more than factor times
+/// the median of the other latencies, paired with that median. Latencies that are None are ignored.
+fn slow_indexes(p95s: &[Option<u64>], factor: u64, min_p95: u64) -> Vec<(usize, u64)> {
+    let mut slow = Vec::new();
+    for (i, p95) in p95s.iter().enumerate() {
+        let p95 = match p95 {
+            Some(p95) if *p95 >= min_p95 => *p95,
+            _ => continue,
+        };
+        let mut peers: Vec<u64> = p95s.iter().enumerate()
+            .filter_map(|(j, p)| if j != i { *p } else { None })
+            .collect();
+        if peers.is_empty() {
+            continue;
+        }
+        peers.sort_unstable();
+        let median = peers[peers.len() / 2];
+        if p95 > median.saturating_mul(factor) {
+            slow.push((i, median));
+        }
+    }
+    slow
+}
+
 /// Merge the second ServerParams into the first.
 /// server_version will be the minimum server_version seen.
 /// Otherwise if both have the same paramter, the first value (master) will be kept.
@@ -111,4 +431,69 @@ pub(crate) fn merge_server_params(master: &mut ServerParams, server: &ServerPara
             warn!("server has server param {}={}, but master has no value for that parameter. Clients will see the master's params.", key, val);
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_weighted_index() {
+        let weights = [1, 3, 0, 2];
+        let expected = [0, 1, 1, 1, 3, 3];
+        for (r, &i) in expected.iter().enumerate() {
+            assert_eq!(weighted_index(weights.iter().cloned(), r as u32), Some(i));
+        }
+        assert_eq!(weighted_index(weights.iter().cloned(), 6), None);
+    }
+
+    #[test]
+    fn test_least_loaded_index() {
+        assert_eq!(least_loaded_index(&[], 0), None);
+        // 4/2 vs 3/1 vs 5/4
+        assert_eq!(least_loaded_index(&[(4, 2), (3, 1), (5, 4)], 0), Some(2));
+        // ties go to the first from start
+        assert_eq!(least_loaded_index(&[(2, 1), (4, 2), (2, 1)], 0), Some(0));
+        assert_eq!(least_loaded_index(&[(2, 1), (4, 2), (2, 1)], 1), Some(1));
+        assert_eq!(least_loaded_index(&[(2, 1), (4, 2), (2, 1)], 2), Some(2));
+    }
+
+    #[test]
+    fn test_slow_indexes() {
+        assert_eq!(slow_indexes(&[], 3, 0), vec![]);
+        assert_eq!(slow_indexes(&[Some(100)], 3, 0), vec![]);
+        assert_eq!(slow_indexes(&[Some(100), Some(301)], 3, 0), vec![(1, 100)]);
+        assert_eq!(slow_indexes(&[Some(100), Some(300)], 3, 0), vec![]);
+        // Below the minimum
+        assert_eq!(slow_indexes(&[Some(100), Some(301)], 3, 1000), vec![]);
+        // Replicas without enough samples are ignored
+        assert_eq!(slow_indexes(&[None, Some(5000), Some(1000), Some(1200)], 3, 0), vec![(1, 1200)]);
+        assert_eq!(slow_indexes(&[None, Some(5000)], 3, 0), vec![]);
+    }
+}
+
+define_event! {
+    /// replica_slow is called when the p95 query latency of a replica is more than slow_replica.slowdown_factor
+    /// times the median p95 latency of the other replicas in the replication group.
+    ///     group: &PostgresReplicationGroup : the event source, the replication group of the replica
+    ///     replica: &'static ConnectionPool : the slow replica
+    ///     p95: Duration : the p95 latency of recent queries to the replica
+    ///     peers_p95: Duration : the median p95 latency of the other replicas
+    /// PostgresReplicationGroup::replica_slow is called by default and quarantines the replica
+    /// (removes it from routing) for slow_replica.quarantine_seconds.
+    /// Use it to alert on grey failures, or to implement a different policy.
+    replica_slow,
+    (group: &'a PostgresReplicationGroup, replica: &'static ConnectionPool, p95: Duration, peers_p95: Duration) -> Result<()>
+}
+
+define_event! {
+    /// master_down is called when the master of a replication group is marked down by the health checks
+    /// (see health_check.) Its connections have already been closed.
+    ///     group: &PostgresReplicationGroup : the event source, the replication group of the master
+    ///     master: &'static ConnectionPool : the master that's down
+    /// Returns the ConnectionPool of the new master, which must be one of the group's replicas, or None to keep
+    /// the current master. PostgresReplicationGroup::master_down is called by default and promotes a replica
+    /// if health_check.promote_replica is set. Use it to follow an external source of the cluster topology
+    /// (e.g. Patroni or a cloud provider API) instead.
+    master_down,
+    (group: &'a PostgresReplicationGroup, master: &'static ConnectionPool) -> Result<Option<&'static ConnectionPool>>
+}
@@ -0,0 +1,109 @@
+use std::collections::VecDeque;
+use std::hash::Hasher;
+use std::sync::{Mutex, PoisonError};
+
+use fnv::FnvHasher;
+use tracing::error;
+
+
+/// The maximum number of unverified forwarded buffers remembered per client. Buffers that are
+/// replaced or dropped by plugins are never verified, so we must not remember them forever.
+const MAX_PENDING_CHECKS: usize = 64;
+
+struct PassthroughCheck {
+    ptr: usize,
+    len: usize,
+    checksum: u64,
+}
+
+/// PassthroughChecks validates (in debug builds only) that the bytes forwarded from a backend
+/// to a client are written to the client exactly as they were received from the backend.
+/// BackendConn::forward records a checksum of each response it forwards, and the default
+/// client_send_messages handler verifies it just before writing. This catches buffer
+/// management bugs in the unsafe split/unsplit code that would otherwise silently
+/// corrupt query results.
+///
+/// Buffers are matched by identity (address and length) so messages that are replaced by
+/// plugins in client_send_messages aren't checked, only the original buffer is.
+pub struct PassthroughChecks {
+    pending: Mutex<VecDeque<PassthroughCheck>>,
+}
+
+impl PassthroughChecks {
+    pub fn new() -> Self {
+        Self {
+            pending: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Record the checksum of the bytes of messages forwarded from the backend.
+    pub fn forwarded(&self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        if pending.len() == MAX_PENDING_CHECKS {
+            pending.pop_front();
+        }
+        pending.push_back(PassthroughCheck{
+            ptr: bytes.as_ptr() as usize,
+            len: bytes.len(),
+            checksum: checksum(bytes),
+        });
+    }
+
+    /// Verify the bytes of messages about to be written to the client. If bytes is a buffer that
+    /// was previously forwarded, and its contents changed since, this logs an error and panics.
+    /// Any older forwarded buffers are discarded, they were replaced or dropped.
+    pub fn verify(&self, bytes: &[u8]) {
+        let ptr = bytes.as_ptr() as usize;
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let pos = match pending.iter().position(|check| check.ptr == ptr && check.len == bytes.len()) {
+            Some(pos) => pos,
+            None => return,
+        };
+        let check = pending.drain(..=pos).last().unwrap();
+        drop(pending);
+
+        if check.checksum != checksum(bytes) {
+            error!(len=bytes.len(), "forwarded messages were corrupted before being sent to the client");
+            panic!("forwarded messages were corrupted before being sent to the client");
+        }
+    }
+}
+
+/// Returns the FNV-1a hash of bytes.
+pub fn checksum(bytes: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_unchanged() {
+        let checks = PassthroughChecks::new();
+        let replaced = b"replaced by a plugin".to_vec();
+        let buf = b"forwarded".to_vec();
+        checks.forwarded(&replaced);
+        checks.forwarded(&buf);
+        checks.verify(b"generated by riverdb");
+        assert_eq!(checks.pending.lock().unwrap().len(), 2);
+        checks.verify(&buf);
+        assert!(checks.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "corrupted")]
+    fn test_verify_corrupted() {
+        let checks = PassthroughChecks::new();
+        let mut buf = b"forwarded".to_vec();
+        checks.forwarded(&buf);
+        // Simulate a buffer management bug overwriting the forwarded bytes
+        buf[0] = b'F';
+        checks.verify(&buf);
+    }
+}
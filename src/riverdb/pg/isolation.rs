@@ -1,9 +1,11 @@
 use std::fmt::Debug;
 
+use serde::Deserialize;
 use strum::Display;
 
-/// An enum of SQL transaction isolation modes
-#[derive(Display, Debug, Copy, Clone)]
+/// An enum of SQL transaction isolation modes, ordered from weakest to strongest
+#[derive(Display, Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "snake_case")]
 #[repr(u8)]
 pub enum IsolationLevel {
     None,
@@ -13,9 +15,21 @@ pub enum IsolationLevel {
     Serializable,
 }
 
+impl IsolationLevel {
+    /// Returns the isolation level as written in SQL, e.g. REPEATABLE READ. Empty for IsolationLevel::None.
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            IsolationLevel::None => "",
+            IsolationLevel::ReadUncommitted => "READ UNCOMMITTED",
+            IsolationLevel::ReadCommitted => "READ COMMITTED",
+            IsolationLevel::RepeatableRead => "REPEATABLE READ",
+            IsolationLevel::Serializable => "SERIALIZABLE",
+        }
+    }
+}
+
 impl Default for IsolationLevel {
     fn default() -> Self {
         IsolationLevel::None
     }
 }
-
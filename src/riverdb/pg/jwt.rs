@@ -0,0 +1,275 @@
+//! Verification of the JSON Web Tokens (JWT) of clients using the jwt authentication method.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::config::JwtSettings;
+
+/// A JSON Web Key, with only the members needed to verify signatures.
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: String,
+    #[serde(default)]
+    alg: String,
+    #[serde(default, rename = "use")]
+    key_use: String,
+    #[serde(default)]
+    n: String,
+    #[serde(default)]
+    e: String,
+    #[serde(default)]
+    crv: String,
+    #[serde(default)]
+    x: String,
+    #[serde(default)]
+    y: String,
+}
+
+#[derive(Deserialize)]
+struct JwkSetJson {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+enum KeyMaterial {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    /// An uncompressed P-256 point (0x04 || x || y)
+    P256(Vec<u8>),
+    /// An uncompressed P-384 point (0x04 || x || y)
+    P384(Vec<u8>),
+}
+
+struct PublicKey {
+    kid: String,
+    alg: String,
+    material: KeyMaterial,
+}
+
+impl PublicKey {
+    /// Returns true if the signature of message is valid for this key and the alg of the token.
+    fn verify(&self, alg: &str, message: &[u8], sig: &[u8]) -> bool {
+        if !self.alg.is_empty() && self.alg != alg {
+            return false;
+        }
+        match &self.material {
+            KeyMaterial::Rsa{n, e} => {
+                let params = match alg {
+                    "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+                    "RS384" => &signature::RSA_PKCS1_2048_8192_SHA384,
+                    "RS512" => &signature::RSA_PKCS1_2048_8192_SHA512,
+                    "PS256" => &signature::RSA_PSS_2048_8192_SHA256,
+                    "PS384" => &signature::RSA_PSS_2048_8192_SHA384,
+                    "PS512" => &signature::RSA_PSS_2048_8192_SHA512,
+                    _ => return false,
+                };
+                RsaPublicKeyComponents{n, e}.verify(params, message, sig).is_ok()
+            },
+            KeyMaterial::P256(point) if alg == "ES256" => {
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point).verify(message, sig).is_ok()
+            },
+            KeyMaterial::P384(point) if alg == "ES384" => {
+                UnparsedPublicKey::new(&signature::ECDSA_P384_SHA384_FIXED, point).verify(message, sig).is_ok()
+            },
+            _ => false,
+        }
+    }
+}
+
+fn decode_base64url(s: &str) -> Result<Vec<u8>> {
+    base64::decode_config(s, base64::URL_SAFE_NO_PAD).map_err(|_| Error::new("invalid base64url encoding"))
+}
+
+/// The public keys of a JSON Web Key Set (JWKS) that can verify token signatures (RSA, and EC P-256 and P-384 keys.)
+#[derive(Default)]
+pub struct JwkSet {
+    keys: Vec<PublicKey>,
+}
+
+impl JwkSet {
+    /// Load the JWKS from value, which is either the inlined JSON, or a file path from which to load it.
+    pub fn load(value: &str) -> Result<Self> {
+        if value.trim_start().starts_with('{') {
+            return Self::parse(value.as_bytes());
+        }
+        let path = Path::new(value);
+        if !path.exists() {
+            return Err(Error::new(format!("{} does not exist", value)));
+        }
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    /// Parse the JWKS from its JSON. Keys for encryption, or of unsupported types, are skipped.
+    pub fn parse(json: &[u8]) -> Result<Self> {
+        let set: JwkSetJson = serde_json::from_slice(json).map_err(|e| Error::new(format!("invalid JSON: {}", e)))?;
+        let mut keys = Vec::new();
+        for jwk in set.keys {
+            if !jwk.key_use.is_empty() && jwk.key_use != "sig" {
+                continue;
+            }
+            let material = match (jwk.kty.as_str(), jwk.crv.as_str()) {
+                ("RSA", _) => KeyMaterial::Rsa{n: decode_base64url(&jwk.n)?, e: decode_base64url(&jwk.e)?},
+                ("EC", "P-256") | ("EC", "P-384") => {
+                    let mut point = vec![4];
+                    point.extend(decode_base64url(&jwk.x)?);
+                    point.extend(decode_base64url(&jwk.y)?);
+                    if jwk.crv == "P-256" { KeyMaterial::P256(point) } else { KeyMaterial::P384(point) }
+                },
+                _ => continue,
+            };
+            keys.push(PublicKey{kid: jwk.kid, alg: jwk.alg, material});
+        }
+        if keys.is_empty() {
+            return Err(Error::new("no RSA or EC (P-256 or P-384) signing keys"));
+        }
+        Ok(Self{keys})
+    }
+
+    /// Returns true if this contains a key with the given key id.
+    fn has_key(&self, kid: &str) -> bool {
+        self.keys.iter().any(|key| key.kid == kid)
+    }
+
+    /// Returns true if the signature of message is valid for one of the keys, restricted to the key with kid if set.
+    fn verify(&self, alg: &str, kid: Option<&str>, message: &[u8], sig: &[u8]) -> bool {
+        self.keys.iter()
+            .filter(|key| kid.is_none() || kid == Some(key.kid.as_str()))
+            .any(|key| key.verify(alg, message, sig))
+    }
+}
+
+/// JwtVerifier verifies the tokens sent by clients as their password, and maps their claims to a Postgres role.
+pub struct JwtVerifier {
+    settings: &'static JwtSettings,
+    keys: RwLock<Arc<JwkSet>>,
+    /// the modification time of the jwks file when it was loaded, None if it's not a file
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl JwtVerifier {
+    pub fn new(settings: &'static JwtSettings) -> Self {
+        let keys = if settings.jwks.is_empty() {
+            JwkSet::default()
+        } else {
+            // Already validated by JwtSettings::load
+            JwkSet::load(&settings.jwks).unwrap_or_default()
+        };
+        Self{
+            settings,
+            keys: RwLock::new(Arc::new(keys)),
+            modified: Mutex::new(Self::jwks_modified(settings)),
+        }
+    }
+
+    fn jwks_modified(settings: &JwtSettings) -> Option<SystemTime> {
+        if settings.jwks.trim_start().starts_with('{') {
+            return None;
+        }
+        std::fs::metadata(&settings.jwks).and_then(|m| m.modified()).ok()
+    }
+
+    /// Load the jwks file again if it changed since it was last loaded. Returns the (possibly new) keys.
+    fn reload_keys(&self) -> Arc<JwkSet> {
+        let mut modified = self.modified.lock().unwrap_or_else(PoisonError::into_inner);
+        let current = Self::jwks_modified(self.settings);
+        if current.is_some() && current != *modified {
+            match JwkSet::load(&self.settings.jwks) {
+                Ok(keys) => {
+                    info!(jwks = %self.settings.jwks, "reloaded jwt jwks");
+                    *self.keys.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(keys);
+                    *modified = current;
+                },
+                Err(e) => warn!(%e, jwks = %self.settings.jwks, "could not reload jwt jwks"),
+            }
+        }
+        self.keys.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Verifies the signature and claims of token, and returns the Postgres role it grants.
+    pub fn verify(&self, token: &str) -> Result<String> {
+        if !self.settings.is_configured() {
+            return Err(Error::new("jwt authentication requires the jwt issuer and jwks settings"));
+        }
+        let mut parts = token.split('.');
+        let (header_b64, claims_b64, sig_b64) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(header), Some(claims), Some(sig), None) => (header, claims, sig),
+            _ => return Err(Error::new("token is not a JWT")),
+        };
+        let header: Header = serde_json::from_slice(&decode_base64url(header_b64)?)
+            .map_err(|_| Error::new("invalid JWT header"))?;
+        let sig = decode_base64url(sig_b64)?;
+        let message = &token.as_bytes()[..header_b64.len() + 1 + claims_b64.len()];
+
+        let mut keys = self.keys.read().unwrap_or_else(PoisonError::into_inner).clone();
+        if let Some(kid) = header.kid.as_deref() {
+            if !keys.has_key(kid) {
+                // The signing keys may have been rotated
+                keys = self.reload_keys();
+                if !keys.has_key(kid) {
+                    return Err(Error::new(format!("unknown JWT key id {}", kid)));
+                }
+            }
+        }
+        if !keys.verify(&header.alg, header.kid.as_deref(), message, &sig) {
+            return Err(Error::new(format!("invalid JWT signature (alg {})", header.alg)));
+        }
+
+        let claims: Value = serde_json::from_slice(&decode_base64url(claims_b64)?)
+            .map_err(|_| Error::new("invalid JWT claims"))?;
+        self.check_claims(&claims, SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64())
+    }
+
+    /// Checks the registered claims of a token with a valid signature at time now (seconds since the epoch),
+    /// and returns the Postgres role of role_claim.
+    fn check_claims(&self, claims: &Value, now: f64) -> Result<String> {
+        let leeway = self.settings.leeway_seconds as f64;
+        match claims.get("exp").and_then(Value::as_f64) {
+            Some(exp) if now > exp + leeway => return Err(Error::new("JWT has expired")),
+            Some(_) => (),
+            None => return Err(Error::new("JWT has no exp claim")),
+        }
+        if let Some(nbf) = claims.get("nbf").and_then(Value::as_f64) {
+            if now + leeway < nbf {
+                return Err(Error::new("JWT is not valid yet"));
+            }
+        }
+        if claims.get("iss").and_then(Value::as_str) != Some(self.settings.issuer.as_str()) {
+            return Err(Error::new("JWT has the wrong issuer"));
+        }
+        if !self.settings.audience.is_empty() {
+            let audience = self.settings.audience.as_str();
+            let matches = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !matches {
+                return Err(Error::new("JWT has the wrong audience"));
+            }
+        }
+
+        let value = claims.get(self.settings.role_claim.as_str()).and_then(Value::as_str).unwrap_or("");
+        if value.is_empty() {
+            return Err(Error::new(format!("JWT has no {} claim", self.settings.role_claim)));
+        }
+        if self.settings.roles.is_empty() {
+            return Ok(value.to_string());
+        }
+        self.settings.roles.get(value).cloned()
+            .ok_or_else(|| Error::new(format!("JWT {} claim {} is not mapped to a role", self.settings.role_claim, value)))
+    }
+}
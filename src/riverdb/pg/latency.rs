@@ -0,0 +1,90 @@
+use std::sync::{Mutex, PoisonError};
+
+use tokio::time::Duration;
+
+
+/// The number of recent query latencies remembered per server.
+const MAX_SAMPLES: usize = 512;
+
+struct Samples {
+    /// latencies in microseconds, a ring buffer once full
+    latencies: Vec<u32>,
+    next: usize,
+}
+
+/// LatencyTracker records the latency of recent queries to a database server
+/// for computing percentiles.
+pub struct LatencyTracker {
+    samples: Mutex<Samples>,
+}
+
+impl LatencyTracker {
+    /// Create a new, empty LatencyTracker.
+    pub fn new() -> Self {
+        Self {
+            samples: Mutex::new(Samples{latencies: Vec::new(), next: 0}),
+        }
+    }
+
+    /// Record the latency of a completed query.
+    pub fn record(&self, latency: Duration) {
+        let micros = latency.as_micros().min(u32::MAX as u128) as u32;
+        let mut samples = self.samples.lock().unwrap_or_else(PoisonError::into_inner);
+        if samples.latencies.len() < MAX_SAMPLES {
+            samples.latencies.push(micros);
+        } else {
+            let i = samples.next;
+            samples.latencies[i] = micros;
+            samples.next = (i + 1) % MAX_SAMPLES;
+        }
+    }
+
+    /// Returns the number of recorded latencies, up to the maximum remembered.
+    pub fn len(&self) -> usize {
+        self.samples.lock().unwrap_or_else(PoisonError::into_inner).latencies.len()
+    }
+
+    /// Forget all recorded latencies.
+    pub fn clear(&self) {
+        let mut samples = self.samples.lock().unwrap_or_else(PoisonError::into_inner);
+        samples.latencies.clear();
+        samples.next = 0;
+    }
+
+    /// Returns the 95th percentile of the recorded latencies, or None if there are fewer than min_samples.
+    pub fn p95(&self, min_samples: usize) -> Option<Duration> {
+        let mut latencies = self.samples.lock().unwrap_or_else(PoisonError::into_inner).latencies.clone();
+        if latencies.is_empty() || latencies.len() < min_samples {
+            return None;
+        }
+        let i = (latencies.len() * 95 / 100).min(latencies.len() - 1);
+        let (_, p95, _) = latencies.select_nth_unstable(i);
+        Some(Duration::from_micros(*p95 as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_p95() {
+        let tracker = LatencyTracker::new();
+        assert_eq!(tracker.p95(1), None);
+        for ms in (1..=100).rev() {
+            tracker.record(Duration::from_millis(ms));
+        }
+        assert_eq!(tracker.p95(101), None);
+        assert_eq!(tracker.p95(100), Some(Duration::from_millis(96)));
+
+        // Old samples are overwritten
+        for _ in 0..MAX_SAMPLES {
+            tracker.record(Duration::from_millis(1));
+        }
+        assert_eq!(tracker.len(), MAX_SAMPLES);
+        assert_eq!(tracker.p95(1), Some(Duration::from_millis(1)));
+
+        tracker.clear();
+        assert_eq!(tracker.len(), 0);
+    }
+}
@@ -1,6 +1,7 @@
 mod client;
 pub mod sql;
 pub mod protocol;
+pub mod replay;
 mod client_state;
 mod service;
 mod connection;
@@ -8,20 +9,63 @@ mod backend;
 mod backend_state;
 mod isolation;
 mod pool;
+mod notifications;
 mod cluster;
 mod group;
 mod transaction;
 mod rows;
+mod startup_guard;
+mod shard_map;
+mod sharding;
+mod embedded;
+mod error_stats;
+mod slow_queries;
+mod rate_limiter;
+mod session_settings;
+mod query_guard;
+mod admin;
+mod cancel;
+mod latency;
+mod reload;
+mod diagnostics;
+mod jwt;
+mod wait_event;
+#[cfg(debug_assertions)]
+mod integrity;
 
 pub use self::service::PostgresService;
 pub use self::client_state::{ClientConnState, ClientState};
-pub use self::backend_state::{BackendConnState, BackendState};
-pub use self::connection::{Connection, parse_messages};
+pub use self::backend_state::{BackendConnState, BackendState, CopyState};
+pub use self::connection::{Connection, ReceiveHandle, parse_messages};
 pub use self::client::*;
 pub use self::backend::*;
-pub use self::cluster::PostgresCluster;
-pub use self::group::PostgresReplicationGroup;
-pub use self::pool::ConnectionPool;
+pub use self::cluster::{PostgresCluster, ServerTls};
+pub use self::group::{PostgresReplicationGroup, replica_slow, master_down};
+pub use self::pool::{ConnectionPool, CheckoutTimings};
+pub(crate) use self::pool::{backoff_delay_ms, jittered_delay_ms};
+pub use self::notifications::{NotificationHub, parse_channel};
 pub use self::isolation::IsolationLevel;
-pub use self::transaction::TransactionType;
-pub use self::rows::Rows;
\ No newline at end of file
+pub use self::transaction::{TransactionType, TransactionOptions, Savepoints};
+pub use self::rows::Rows;
+pub use self::startup_guard::{StartupGuard, StartupStatsSnapshot};
+pub use self::shard_map::{ShardMap, referenced_tables};
+pub use self::sharding::{route_shard, shard_for_key, shard_keys};
+pub use self::embedded::{Pool, PooledConn};
+pub use self::error_stats::{ErrorStats, ErrorStatsKey};
+pub use self::slow_queries::{SlowQueryStats, SlowQuerySummary, PendingQueries};
+pub use self::rate_limiter::{RateLimiter, RateLimitExceeded, Limit};
+pub use self::session_settings::{SessionSettings, SettingChange};
+pub use self::query_guard::{QueryGuard, QueryGuardSettings, QueryGuardRule, GuardAction, register_query_guard};
+pub use self::admin::{AdminCommand, RiverdbFunction, rows_result, command_result, error_result, error_result_with_hint};
+pub use self::cancel::{CancelMap, CancelTarget, send_cancel_request};
+pub use self::latency::LatencyTracker;
+pub use self::wait_event::{WaitEvent, wait_event_counts};
+pub use self::reload::{Reloader, ReloadSummary};
+pub use self::jwt::{JwkSet, JwtVerifier};
+pub use self::diagnostics::{dump_state, log_startup, is_ready, wait_ready, StartupSummary, ClusterSummary, ServerSummary};
+#[cfg(debug_assertions)]
+pub use self::integrity::PassthroughChecks;
+#[cfg(unix)]
+pub use self::diagnostics::dump_state_on_signal;
+#[cfg(unix)]
+pub use self::reload::reload_on_signal;
\ No newline at end of file
@@ -0,0 +1,229 @@
+use std::sync::{Mutex, PoisonError};
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::atomic::Ordering::Relaxed;
+
+use fnv::FnvHashMap;
+use tracing::{debug, warn};
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::common::Ark;
+use crate::riverdb::pg::{BackendConn, ClientConn, Connection, ConnectionPool};
+use crate::riverdb::pg::protocol::{Messages, MessageBuilder, Tag};
+
+
+/// NotificationHub implements LISTEN/NOTIFY for clients in transaction or statement pool_mode,
+/// where the backend connection that ran LISTEN goes back to the pool after the query.
+/// A single dedicated connection per pool stays subscribed to the union of the channels
+/// its clients are listening on, and NotificationResponse messages received on it are
+/// sent to every client listening on that channel.
+pub struct NotificationHub {
+    /// channel name => the clients listening on it
+    channels: Mutex<FnvHashMap<String, Vec<Ark<ClientConn>>>>,
+    /// the dedicated connection, created when the first client runs LISTEN
+    listener: tokio::sync::Mutex<Ark<BackendConn>>,
+    /// set when the dedicated connection closed, it must be re-established before it's used again
+    listener_closed: AtomicBool,
+    /// count of the notifications received on the dedicated connection
+    received: AtomicU64,
+    /// count of the notifications sent to clients
+    delivered: AtomicU64,
+}
+
+impl NotificationHub {
+    pub fn new() -> Self {
+        Self{
+            channels: Mutex::new(FnvHashMap::default()),
+            listener: tokio::sync::Mutex::new(Ark::default()),
+            listener_closed: AtomicBool::new(false),
+            received: AtomicU64::new(0),
+            delivered: AtomicU64::new(0),
+        }
+    }
+
+    /// Subscribe client to channel. The dedicated connection runs LISTEN if this is the first client listening on it.
+    pub async fn listen(&self, pool: &'static ConnectionPool, client: &ClientConn, channel: &str) -> Result<()> {
+        let first = {
+            let mut channels = self.channels.lock().unwrap_or_else(PoisonError::into_inner);
+            let clients = channels.entry(channel.to_string()).or_default();
+            if clients.iter().any(|c| std::ptr::eq(c.as_ptr(), client)) {
+                return Ok(());
+            }
+            clients.push(Ark::from(client));
+            clients.len() == 1
+        };
+        if first {
+            self.execute(pool, "LISTEN", channel).await?;
+        }
+        Ok(())
+    }
+
+    /// Unsubscribe client from channel, or from all channels if channel is None.
+    /// The dedicated connection runs UNLISTEN for channels that no longer have any clients.
+    pub async fn unlisten(&self, pool: &'static ConnectionPool, client: &ClientConn, channel: Option<&str>) -> Result<()> {
+        for channel in self.remove(client, channel) {
+            self.execute(pool, "UNLISTEN", &channel).await?;
+        }
+        Ok(())
+    }
+
+    /// Unsubscribe client from all channels when it disconnects. Channels left without clients
+    /// are unlistened in the background.
+    pub fn remove_client(&'static self, pool: &'static ConnectionPool, client: &ClientConn) {
+        let empty = self.remove(client, None);
+        if !empty.is_empty() {
+            tokio::spawn(async move {
+                for channel in empty {
+                    if let Err(e) = self.execute(pool, "UNLISTEN", &channel).await {
+                        warn!(%e, %channel, "failed to unlisten channel without clients");
+                    }
+                }
+            });
+        }
+    }
+
+    /// Removes client from channel (or all channels) and returns the channels that are left without clients.
+    fn remove(&self, client: &ClientConn, channel: Option<&str>) -> Vec<String> {
+        let mut channels = self.channels.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut empty = Vec::new();
+        for (name, clients) in channels.iter_mut() {
+            if channel.map_or(true, |channel| channel == name) {
+                clients.retain(|c| !std::ptr::eq(c.as_ptr(), client));
+                if clients.is_empty() {
+                    empty.push(name.clone());
+                }
+            }
+        }
+        for name in &empty {
+            channels.remove(name);
+        }
+        empty
+    }
+
+    /// Returns the names of the channels with at least one client listening.
+    pub fn channels(&self) -> Vec<String> {
+        self.channels.lock().unwrap_or_else(PoisonError::into_inner).keys().cloned().collect()
+    }
+
+    /// Returns the number of notifications received on the dedicated connection.
+    pub fn received(&self) -> u64 {
+        self.received.load(Relaxed)
+    }
+
+    /// Returns the number of notifications sent to clients.
+    pub fn delivered(&self) -> u64 {
+        self.delivered.load(Relaxed)
+    }
+
+    /// Run LISTEN or UNLISTEN for channel on the dedicated connection, (re-)establishing it if required.
+    async fn execute(&self, pool: &'static ConnectionPool, command: &str, channel: &str) -> Result<()> {
+        let mut listener = self.listener.lock().await;
+        if listener.is_none() || self.listener_closed.swap(false, Relaxed) {
+            let conn = pool.new_dedicated_connection().await?;
+            if conn.is_none() {
+                return Err(Error::new("can't open a connection for LISTEN, the pool is full"));
+            }
+            conn.set_notification_listener(true);
+            // Subscribe a new connection to all the channels, it may be replacing one that closed
+            for name in self.channels() {
+                if name != channel {
+                    conn.execute(listen_query("LISTEN", &name)).await?;
+                }
+            }
+            *listener = conn;
+        }
+        debug!(command, channel, "notification listener");
+        listener.execute(listen_query(command, channel)).await?;
+        Ok(())
+    }
+
+    /// Called when the dedicated connection closes. If there are still clients listening,
+    /// a new connection is established and subscribed to their channels.
+    pub(crate) fn listener_closed(&'static self, pool: &'static ConnectionPool) {
+        self.listener_closed.store(true, Relaxed);
+        let channels = self.channels();
+        if let Some(channel) = channels.first().cloned() {
+            warn!(?channels, "notification listener connection closed, reconnecting");
+            tokio::spawn(async move {
+                if let Err(e) = self.execute(pool, "LISTEN", &channel).await {
+                    warn!(%e, "failed to re-establish notification listener connection");
+                }
+            });
+        }
+    }
+
+    /// Sends the NotificationResponse messages in msgs to the clients listening on their channel,
+    /// and returns the remaining messages. Clients that can't be sent to are unsubscribed, and channels
+    /// left without clients are unlistened in the background (see remove_client.) This is called by the
+    /// dedicated connection's read loop, so it writes to each client without waiting: what the client's
+    /// socket doesn't accept is added to its backlog, within the max_backlog_bytes.
+    pub(crate) fn dispatch(&'static self, pool: &'static ConnectionPool, msgs: Messages) -> Result<Messages> {
+        if !msgs.iter(0).any(|msg| msg.tag() == Tag::NOTIFICATION_RESPONSE) {
+            return Ok(msgs);
+        }
+        let mut rest = Messages::default();
+        for msg in msgs.iter(0) {
+            if msg.tag() != Tag::NOTIFICATION_RESPONSE {
+                rest = rest.append(msgs.split_message(&msg));
+                continue;
+            }
+            self.received.fetch_add(1, Relaxed);
+            let mut r = msg.reader();
+            let _pid = r.read_i32();
+            let channel = r.read_str()?;
+            let clients = self.channels.lock().unwrap_or_else(PoisonError::into_inner).get(channel).cloned().unwrap_or_default();
+            if clients.is_empty() {
+                debug!(channel, "dropping notification for channel without clients");
+            }
+            for client in clients {
+                if let Err(e) = client.write_or_buffer(msgs.split_message(&msg).into_bytes()) {
+                    debug!(%e, channel, "failed to send notification, unsubscribing client");
+                    self.remove_client(pool, &client);
+                } else {
+                    self.delivered.fetch_add(1, Relaxed);
+                }
+            }
+        }
+        Ok(rest)
+    }
+}
+
+/// Returns the LISTEN or UNLISTEN query for channel, quoted so it's not case-folded.
+fn listen_query(command: &str, channel: &str) -> Messages {
+    let mut mb = MessageBuilder::new(Tag::QUERY);
+    mb.write_str(&format!("{} \"{}\"", command, channel.replace('"', "\"\"")));
+    mb.finish()
+}
+
+/// Parses the channel name from a normalized LISTEN or UNLISTEN query. Unquoted names are
+/// folded to lowercase like Postgres does. Returns None for UNLISTEN * or if the name is missing.
+pub fn parse_channel(normalized: &str) -> Option<String> {
+    let name = normalized.trim().trim_end_matches(';').trim_end().splitn(2, char::is_whitespace).nth(1)?.trim();
+    if name.is_empty() || name == "*" {
+        None
+    } else if name.len() >= 2 && name.starts_with('"') && name.ends_with('"') {
+        Some(name[1..name.len()-1].replace("\"\"", "\""))
+    } else {
+        Some(name.to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_channel() {
+        assert_eq!(parse_channel("LISTEN ORDERS").as_deref(), Some("orders"));
+        assert_eq!(parse_channel("UNLISTEN ORDERS;").as_deref(), Some("orders"));
+        assert_eq!(parse_channel("LISTEN \"New\"\"Orders\"").as_deref(), Some("New\"Orders"));
+        assert_eq!(parse_channel("UNLISTEN *"), None);
+        assert_eq!(parse_channel("UNLISTEN"), None);
+    }
+
+    #[test]
+    fn test_listen_query() {
+        let msgs = listen_query("LISTEN", "a\"b");
+        let msg = msgs.first().unwrap();
+        assert_eq!(msg.reader().read_str().unwrap(), "LISTEN \"a\"\"b\"");
+    }
+}
@@ -1,21 +1,69 @@
-use std::sync::atomic::{AtomicI32};
+use std::sync::atomic::{AtomicI32, AtomicU32, AtomicU64, AtomicBool};
 use std::sync::atomic::Ordering::{Relaxed};
+use std::cmp::min;
 
-use std::sync::{Mutex};
+use std::sync::{Mutex, PoisonError};
 use std::fmt::{Debug, Formatter};
+use std::collections::VecDeque;
 
 use tokio::net::TcpStream;
-use tracing::{warn};
+use tokio::sync::{Notify, oneshot};
+use tokio::time::{interval, sleep, timeout, timeout_at, Instant, Duration};
+use tracing::{debug, warn, Span};
 
-use crate::riverdb::{Result};
+use crate::riverdb::{Error, Result};
 use crate::riverdb::server::{Connections, Connection};
-use crate::riverdb::pg::{BackendConn, IsolationLevel, TransactionType};
+use crate::riverdb::pg::{BackendConn, Connection as _, IsolationLevel, TransactionType, LatencyTracker, NotificationHub, WaitEvent};
+use crate::riverdb::pg::protocol::{error_codes, ServerParams, Tag};
+use crate::riverdb::worker::Worker;
+use crate::riverdb::memory_governor::under_memory_pressure;
 
-use crate::riverdb::config::{Postgres};
-use crate::riverdb::common::{Version, AtomicCell, change_lifetime, ErrorKind, Ark};
+use crate::riverdb::config::{Postgres, Jitter};
+use crate::riverdb::common::{Version, AtomicCell, change_lifetime, ErrorKind, Ark, catch_panic};
 
 
 
+/// The time spent checking out a backend connection from the pool, broken down by phase.
+/// connect_time, tls_time, and auth_time are zero if an existing pooled connection was used.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct CheckoutTimings {
+    /// time spent waiting for a connection, including while pool growth is backed off and health checks
+    pub wait_time: Duration,
+    /// time spent establishing the TCP connection to the database
+    pub connect_time: Duration,
+    /// time spent on the TLS handshake with the database
+    pub tls_time: Duration,
+    /// time spent authenticating with the database
+    pub auth_time: Duration,
+}
+
+impl CheckoutTimings {
+    /// Returns the total time spent checking out the connection.
+    pub fn total(&self) -> Duration {
+        self.wait_time + self.connect_time + self.tls_time + self.auth_time
+    }
+
+    /// Record the timings (in microseconds) on the span, which must have declared
+    /// wait_time_us, connect_time_us, tls_time_us, and auth_time_us fields.
+    pub fn record(&self, span: &Span) {
+        span.record("wait_time_us", &(self.wait_time.as_micros() as u64));
+        span.record("connect_time_us", &(self.connect_time.as_micros() as u64));
+        span.record("tls_time_us", &(self.tls_time.as_micros() as u64));
+        span.record("auth_time_us", &(self.auth_time.as_micros() as u64));
+    }
+}
+
+/// Returns the fraction of the warm-up of duration_ms that has elapsed, or 1 if there is no warm-up.
+fn warmup_factor(elapsed_ms: u64, duration_ms: u64) -> f64 {
+    if elapsed_ms >= duration_ms {
+        1.0
+    } else {
+        elapsed_ms as f64 / duration_ms as f64
+    }
+}
+
+/// Maximum number of milliseconds to back off pool growth after a too_many_connections error.
+const MAX_TOO_MANY_CONNECTIONS_BACKOFF_MS: u64 = 10000;
 
 // We just use a Mutex and Vec here to implement the pool.
 // if contention is light, this is optimal. We hold the lock for very short
@@ -34,11 +82,61 @@ pub struct ConnectionPool {
     pub config: &'static Postgres,
     pub(crate) connections: &'static Connections<BackendConn>,
     active_transactions: AtomicI32,
-    max_transactions: i32,
+    max_transactions: AtomicI32,
     default_isolation_level: AtomicCell<IsolationLevel>,
     #[allow(unused)]
     server_version: AtomicCell<Version>,
     pooled_connections: Mutex<Vec<Ark<BackendConn>>>,
+    /// notified when a connection is returned to the pool
+    returned: Notify,
+    /// callers of get waiting in FIFO order while the pool is at max_connections. A returned connection is
+    /// handed to the first waiter, an empty Ark tells it a connection closed and it can try to establish one.
+    /// Lock order is waiters then pooled_connections.
+    waiters: Mutex<VecDeque<oneshot::Sender<Ark<BackendConn>>>>,
+    /// counts of the callers that waited for a connection, and of those that timed out or were turned away
+    waits: AtomicU64,
+    wait_timeouts: AtomicU64,
+    /// total microseconds spent waiting in the queue
+    wait_time_us: AtomicU64,
+    /// created is the reference point for backoff_until
+    created: Instant,
+    /// backoff_until is the number of milliseconds after created until which the pool won't grow
+    backoff_until: AtomicU64,
+    /// too_many_connections counts the too_many_connections errors received while growing the pool
+    too_many_connections: AtomicU64,
+    /// counts of the NotificationResponse, NoticeResponse, and other unexpected messages received by pooled connections
+    in_pool_notifications: AtomicU64,
+    in_pool_notices: AtomicU64,
+    in_pool_unexpected: AtomicU64,
+    /// counts of the messages (and bytes) dropped because no client was attached, and those delivered late
+    /// to a client attached within the dropped_messages_grace_ms
+    dropped_messages: AtomicU64,
+    dropped_bytes: AtomicU64,
+    late_messages: AtomicU64,
+    /// the LISTEN channels of clients in transaction or statement pool_mode
+    notifications: NotificationHub,
+    /// latency of recent client queries, used to detect slow replicas
+    latency: LatencyTracker,
+    /// quarantined_until is the number of milliseconds after created until which the pool is removed from routing
+    quarantined_until: AtomicU64,
+    /// draining is set when the database is being removed, connections are closed instead of returned to the pool
+    draining: AtomicBool,
+    /// warmup_started is the number of milliseconds after created when the warm-up began
+    warmup_started: AtomicU64,
+    /// warmup_millis is the duration of the warm-up, 0 if the pool was never warmed up
+    warmup_millis: AtomicU64,
+    /// replication_lag_ms is the last measured replication lag of a replica, u64::MAX if it couldn't be measured
+    replication_lag_ms: AtomicU64,
+    /// healthy is cleared when the server fails health_check.unhealthy_threshold consecutive health checks
+    healthy: AtomicBool,
+    /// reachable is set once a connection to the server was established or a health check succeeded
+    reachable: AtomicBool,
+    /// health_check_failures is the number of consecutive failed health checks
+    health_check_failures: AtomicU32,
+    /// user_pools are the pools for connections established as other users (see config.user_pools)
+    user_pools: Vec<&'static ConnectionPool>,
+    /// read_only is set for the replicas of a master with replica_read_only, and cleared when promoted to master
+    read_only: AtomicBool,
 }
 
 impl ConnectionPool {
@@ -47,36 +145,475 @@ impl ConnectionPool {
             config,
             connections: Connections::new(config.max_connections, 0), // we don't use the Connections level timeout
             active_transactions: Default::default(),
-            max_transactions: config.max_concurrent_transactions as i32,
+            max_transactions: AtomicI32::new(config.max_concurrent_transactions as i32),
             default_isolation_level: AtomicCell::<IsolationLevel>::default(),
             server_version: Default::default(),
             pooled_connections: Mutex::new(Vec::new()),
+            returned: Notify::new(),
+            waiters: Mutex::new(VecDeque::new()),
+            waits: AtomicU64::new(0),
+            wait_timeouts: AtomicU64::new(0),
+            wait_time_us: AtomicU64::new(0),
+            created: Instant::now(),
+            backoff_until: AtomicU64::new(0),
+            too_many_connections: AtomicU64::new(0),
+            in_pool_notifications: AtomicU64::new(0),
+            in_pool_notices: AtomicU64::new(0),
+            in_pool_unexpected: AtomicU64::new(0),
+            dropped_messages: AtomicU64::new(0),
+            dropped_bytes: AtomicU64::new(0),
+            late_messages: AtomicU64::new(0),
+            notifications: NotificationHub::new(),
+            latency: LatencyTracker::new(),
+            quarantined_until: AtomicU64::new(0),
+            draining: AtomicBool::new(false),
+            warmup_started: AtomicU64::new(0),
+            warmup_millis: AtomicU64::new(0),
+            replication_lag_ms: AtomicU64::new(0),
+            healthy: AtomicBool::new(true),
+            reachable: AtomicBool::new(false),
+            health_check_failures: AtomicU32::new(0),
+            user_pools: config.user_pool_configs.iter().map(|c| &*Box::leak(Box::new(ConnectionPool::new(c)))).collect(),
+            read_only: AtomicBool::new(!config.is_master && config.replica_read_only),
+        }
+    }
+
+    /// Returns the pool for connections established as user to database, if one is configured (see config.user_pools.)
+    /// Otherwise connections come from this pool and switch to the user's role with SET ROLE.
+    pub fn user_pool(&self, user: &str, database: &str) -> Option<&'static ConnectionPool> {
+        self.user_pools.iter().cloned().find(|pool| pool.config.user == user && pool.config.database == database)
+    }
+
+    /// Returns the pool for clients connecting as user to database, and the role to set on its connections:
+    /// the user pool for user and database if one is configured (with no role to set), otherwise this pool
+    /// with user as the role. Returns None if database is only served by the user pools of other users.
+    pub fn pool_for<'a>(&'static self, user: &'a str, database: &str) -> Option<(&'static ConnectionPool, &'a str)> {
+        match self.user_pool(user, database) {
+            Some(user_pool) => Some((user_pool, "")),
+            None if self.config.database == database || self.user_pools.iter().all(|pool| pool.config.database != database) => Some((self, user)),
+            None => None,
+        }
+    }
+
+    /// Returns the pools for connections established as other users (see config.user_pools.)
+    pub fn user_pools(&self) -> &[&'static ConnectionPool] {
+        &self.user_pools
+    }
+
+    /// Returns the number of idle connections in the pool.
+    pub fn pooled(&self) -> usize {
+        self.pooled_connections.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    /// Returns the number of connections currently used for transactions.
+    pub fn active_transactions(&self) -> i32 {
+        self.active_transactions.load(Relaxed)
+    }
+
+    /// Returns the number of connections checked out of the pool.
+    pub fn in_use(&self) -> usize {
+        let pooled = self.pooled_connections.lock().unwrap_or_else(PoisonError::into_inner).len();
+        self.connections.len().saturating_sub(pooled)
+    }
+
+    /// Returns the number of too_many_connections errors received from the database while growing the pool.
+    pub fn too_many_connections_errors(&self) -> u64 {
+        self.too_many_connections.load(Relaxed)
+    }
+
+    /// Count a message with the given tag received by a pooled connection.
+    /// ParameterStatus and ErrorResponse messages are expected and not counted.
+    pub(crate) fn record_in_pool_message(&self, tag: Tag) {
+        let counter = match tag {
+            Tag::PARAMETER_STATUS | Tag::ERROR_RESPONSE => return,
+            Tag::NOTIFICATION_RESPONSE => &self.in_pool_notifications,
+            Tag::NOTICE_RESPONSE => &self.in_pool_notices,
+            _ => &self.in_pool_unexpected,
+        };
+        counter.fetch_add(1, Relaxed);
+    }
+
+    /// Returns the number of notifications dropped because they were received by pooled connections.
+    pub fn in_pool_notifications(&self) -> u64 {
+        self.in_pool_notifications.load(Relaxed)
+    }
+
+    /// Returns the number of notices received by pooled connections.
+    pub fn in_pool_notices(&self) -> u64 {
+        self.in_pool_notices.load(Relaxed)
+    }
+
+    /// Returns the number of other unexpected messages received by pooled connections, each of which closed the connection.
+    pub fn in_pool_unexpected_messages(&self) -> u64 {
+        self.in_pool_unexpected.load(Relaxed)
+    }
+
+    /// Counts messages dropped by a backend connection because no client was attached.
+    pub(crate) fn record_dropped_messages(&self, count: u32, bytes: u32) {
+        self.dropped_messages.fetch_add(count as u64, Relaxed);
+        self.dropped_bytes.fetch_add(bytes as u64, Relaxed);
+    }
+
+    /// Counts messages held by a backend connection until a client was attached.
+    pub(crate) fn record_late_messages(&self, count: u32) {
+        self.late_messages.fetch_add(count as u64, Relaxed);
+    }
+
+    /// Returns the number of messages dropped because no client was attached to the backend connection.
+    pub fn dropped_messages(&self) -> u64 {
+        self.dropped_messages.load(Relaxed)
+    }
+
+    /// Returns the total size in bytes of the messages dropped because no client was attached.
+    pub fn dropped_bytes(&self) -> u64 {
+        self.dropped_bytes.load(Relaxed)
+    }
+
+    /// Returns the number of messages delivered to a client attached within the dropped_messages_grace_ms.
+    pub fn late_messages(&self) -> u64 {
+        self.late_messages.load(Relaxed)
+    }
+
+    /// Returns the NotificationHub for clients that LISTEN in transaction or statement pool_mode.
+    pub fn notifications(&self) -> &NotificationHub {
+        &self.notifications
+    }
+
+    /// Change the limits on total connections and connections used for transactions.
+    /// max_connections can't be increased beyond the value the pool was created with,
+    /// returns the max_connections that was applied.
+    pub fn set_limits(&self, max_connections: u32, max_transactions: u32) -> u32 {
+        self.max_transactions.store(max_transactions as i32, Relaxed);
+        self.connections.set_max_connections(max_connections)
+    }
+
+    /// Stop handing out connections from this pool, and close its idle connections. Connections
+    /// currently in use are closed when they're returned, so sessions using them are not interrupted.
+    pub fn drain(&self) {
+        for pool in self.user_pools.iter() {
+            pool.drain();
+        }
+        self.draining.store(true, Relaxed);
+        let pooled = std::mem::take(&mut *self.pooled_connections.lock().unwrap_or_else(PoisonError::into_inner));
+        for conn in pooled {
+            conn.close();
+        }
+        // Wake any tasks waiting on a connection so they see the pool is draining
+        self.returned.notify_waiters();
+        for waiter in self.waiters.lock().unwrap_or_else(PoisonError::into_inner).drain(..) {
+            let _ = waiter.send(Ark::default());
         }
     }
+
+    /// Returns the number of callers currently waiting for a connection because the pool is at max_connections.
+    pub fn queue_depth(&self) -> usize {
+        self.waiters.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    /// Returns the number of callers that have waited for a connection because the pool was at max_connections.
+    pub fn waits(&self) -> u64 {
+        self.waits.load(Relaxed)
+    }
+
+    /// Returns the number of callers that timed out waiting for a connection, or found the queue full.
+    pub fn wait_timeouts(&self) -> u64 {
+        self.wait_timeouts.load(Relaxed)
+    }
+
+    /// Returns the total time callers have spent waiting for a connection because the pool was at max_connections.
+    pub fn total_wait_time(&self) -> Duration {
+        Duration::from_micros(self.wait_time_us.load(Relaxed))
+    }
+
+    /// Returns true if the pool has reached max_connections.
+    pub fn is_full(&self) -> bool {
+        self.connections.is_full()
+    }
+
+    /// Close idle connections in the pool, keeping at most keep of the most recently returned.
+    /// Returns the number of connections closed.
+    pub fn close_idle(&self, keep: usize) -> usize {
+        let closed: Vec<_> = {
+            let mut pooled = self.pooled_connections.lock().unwrap_or_else(PoisonError::into_inner);
+            // Connections are taken from the end, so the least recently used are at the start
+            let n = pooled.len().saturating_sub(keep);
+            pooled.drain(..n).collect()
+        };
+        for conn in closed.iter() {
+            conn.close();
+        }
+        closed.len()
+    }
+
+    /// Close the idle connections in the pool that exceeded config.idle_timeout_seconds or
+    /// config.server_lifetime_seconds. Returns the number of connections closed.
+    pub fn close_expired(&self) -> usize {
+        let expired: Vec<_> = {
+            let mut pooled = self.pooled_connections.lock().unwrap_or_else(PoisonError::into_inner);
+            let (expired, keep) = pooled.drain(..).partition(|conn| self.is_expired(conn));
+            *pooled = keep;
+            expired
+        };
+        for conn in expired.iter() {
+            conn.close();
+        }
+        expired.len()
+    }
+
+    fn is_expired(&self, conn: &BackendConn) -> bool {
+        is_expired(conn.idle_seconds(), conn.age_seconds(), self.config.idle_timeout_seconds, self.config.server_lifetime_seconds)
+    }
+
+    /// Returns true if the pool needs run_maintainer to recycle or pre-establish connections.
+    pub fn needs_maintainer(&self) -> bool {
+        self.config.min_idle != 0 || self.config.idle_timeout_seconds != 0 || self.config.server_lifetime_seconds != 0
+    }
+
+    /// Close the idle connections that exceeded their idle timeout or lifetime (see close_expired),
+    /// and keep config.min_idle idle connections in the pool, establishing at most config.connect_rate
+    /// connections per second, until the pool is drained. Returns immediately if not needs_maintainer().
+    pub async fn run_maintainer(&'static self) {
+        if !self.needs_maintainer() {
+            return;
+        }
+        let mut interval = interval(Duration::from_millis(1000 / self.config.connect_rate.max(1) as u64));
+        loop {
+            interval.tick().await;
+            if self.is_draining() {
+                return;
+            }
+            let closed = self.close_expired();
+            if closed != 0 {
+                debug!(pool=?self, closed, "closed expired idle connections");
+            }
+            // Don't grow the pool while it's not needed, or while that could make things worse
+            if self.pooled() >= self.config.min_idle as usize || !self.is_healthy()
+                || under_memory_pressure() || self.backoff_remaining().is_some() {
+                continue;
+            }
+            match self.new_connection(&ServerParams::new()).await {
+                Ok(conn) if conn.is_some() => {
+                    debug!(pool=?self, "established idle connection");
+                    self.put(conn).await;
+                },
+                Ok(_) => (), // the pool is full
+                Err(e) if is_too_many_connections(&e) => self.too_many_connections_backoff(1),
+                Err(e) => warn!(?e, pool=?self, "error establishing idle connection"),
+            }
+        }
+    }
+
+    /// Close all connections of the pool, including those in use by client sessions.
+    pub fn close_all(&self) {
+        for pool in self.user_pools.iter() {
+            pool.close_all();
+        }
+        self.connections.for_each(|conn| {
+            conn.close();
+            false
+        });
+    }
+
+    /// Returns true if the pool is being drained, see drain().
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Relaxed)
+    }
+
+    /// Returns true if transactions on connections from this pool are made read only (see config.replica_read_only.)
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Relaxed)
+    }
+
+    /// Set whether transactions on connections from this pool and its user pools are made read only.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Relaxed);
+        for user_pool in &self.user_pools {
+            user_pool.set_read_only(read_only);
+        }
+    }
+
+    /// Returns false if the server is marked down by the health checks (see health_check.)
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Relaxed)
+    }
+
+    /// Returns true once a connection to the server was established or a health check succeeded.
+    /// Unlike is_healthy, which assumes the server is up until the health checks say otherwise,
+    /// this is false for a server that was never reached.
+    pub fn is_reachable(&self) -> bool {
+        self.reachable.load(Relaxed)
+    }
+
+    /// Record the result of a health check. The server is marked down after unhealthy_threshold
+    /// consecutive failures, and up again after a success. Returns the new health if it changed.
+    pub fn record_health_check(&self, ok: bool, unhealthy_threshold: u32) -> Option<bool> {
+        let healthy = if ok {
+            self.health_check_failures.store(0, Relaxed);
+            self.reachable.store(true, Relaxed);
+            true
+        } else {
+            self.health_check_failures.fetch_add(1, Relaxed) + 1 < unhealthy_threshold
+        };
+        if self.healthy.swap(healthy, Relaxed) != healthy {
+            Some(healthy)
+        } else {
+            None
+        }
+    }
+
+    /// Ramp up the share of queries routed to this database from nothing to its full weight over duration,
+    /// so a newly added replica with a cold cache isn't immediately sent its full share of traffic.
+    pub fn start_warmup(&self, duration: Duration) {
+        self.warmup_started.store(self.created.elapsed().as_millis() as u64, Relaxed);
+        self.warmup_millis.store(duration.as_millis() as u64, Relaxed);
+    }
+
+    /// Returns the fraction of its weight (between 0 and 1) currently used to route queries to this database.
+    /// This is 1 unless the pool is warming up, see start_warmup.
+    pub fn warmup_factor(&self) -> f64 {
+        let elapsed = (self.created.elapsed().as_millis() as u64).saturating_sub(self.warmup_started.load(Relaxed));
+        warmup_factor(elapsed, self.warmup_millis.load(Relaxed))
+    }
+
+    /// Returns the weight used to route queries to this database, which is config.weight scaled by
+    /// the warmup_factor. It's in thousandths, so it's only comparable to the routing_weight of other pools.
+    pub fn routing_weight(&self) -> u32 {
+        let permille = (self.warmup_factor() * 1000.0) as u32;
+        // Give it a small share of queries even at the start of the warm-up
+        self.config.weight.saturating_mul(permille.max(1))
+    }
+
+    /// Returns the latency of recent client queries to this database.
+    pub fn latency(&self) -> &LatencyTracker {
+        &self.latency
+    }
+
+    /// Returns true if this database was found to be much slower than its peers
+    /// and is temporarily removed from query routing.
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantined_until.load(Relaxed) > self.created.elapsed().as_millis() as u64
+    }
+
+    /// Remove this database from query routing for duration. The recorded latencies are cleared,
+    /// so it's judged on fresh samples once the quarantine ends.
+    pub fn quarantine(&self, duration: Duration) {
+        let until = self.created.elapsed().as_millis() as u64 + duration.as_millis() as u64;
+        self.quarantined_until.store(until, Relaxed);
+        self.latency.clear();
+    }
+
+    /// Returns the last measured replication lag of this replica, or None if it couldn't be measured.
+    /// This is zero for the master, or if the lag isn't checked (see max_replica_lag_ms.)
+    pub fn replication_lag(&self) -> Option<Duration> {
+        match self.replication_lag_ms.load(Relaxed) {
+            u64::MAX => None,
+            lag_ms => Some(Duration::from_millis(lag_ms)),
+        }
+    }
+
+    /// Record the replication lag of this replica, None if it couldn't be measured.
+    pub fn set_replication_lag(&self, lag: Option<Duration>) {
+        self.replication_lag_ms.store(lag.map_or(u64::MAX, |lag| lag.as_millis() as u64), Relaxed);
+    }
+
+    /// Returns true if the replication lag of this replica exceeds max_lag_ms, or couldn't be measured.
+    /// Always false if max_lag_ms is 0.
+    pub fn is_lagging(&self, max_lag_ms: u32) -> bool {
+        is_lag_exceeded(self.replication_lag_ms.load(Relaxed), max_lag_ms)
+    }
     
     pub async fn get(&self, application_name: &str, role: &str, tx_type: TransactionType) -> Result<Ark<BackendConn>> {
+        self.get_reporting_waits(application_name, role, tx_type, &ServerParams::new(), &|_| ()).await
+    }
+
+    /// Like get, but calls wait_event with what the checkout is about to wait on: a PoolSlot when the pool
+    /// is at max_connections, BackendConnect while establishing a new connection, or BackendResponse for the
+    /// health check and SET ROLE. See ClientConn::wait_event.
+    /// If protocol_options (see the protocol_options setting) isn't empty, a new connection requesting them
+    /// is established instead of using a pooled one, and put closes it instead of returning it to the pool, since
+    /// they're negotiated at startup and can't be reset for the next client. Once the pool is full, the connection
+    /// may be one returned to the pool without them.
+    pub async fn get_reporting_waits(&self, application_name: &str, role: &str, tx_type: TransactionType, protocol_options: &ServerParams, wait_event: &(dyn Fn(WaitEvent) + Sync)) -> Result<Ark<BackendConn>> {
         // Safety: self is 'static, but if we mark it as such the compiler barfs.
         // See: https://github.com/rust-lang/rust/issues/87632 **sigh**
         let static_self: &'static Self = unsafe { change_lifetime(self) };
 
-        if tx_type != TransactionType::None && self.active_transactions.fetch_add(1, Relaxed) > self.max_transactions {
-            let prev = self.active_transactions.fetch_add(-1, Relaxed);
-            debug_assert!(prev > 0);
+        if self.is_draining() {
+            return Err(Error::new(format!("{:?} is draining", self)));
+        }
+        // Counted until the connection is returned with put, or undone if get doesn't return a connection
+        let transaction = TransactionSlot::new(&self.active_transactions, tx_type != TransactionType::None);
+        if transaction.others() > self.max_transactions.load(Relaxed) {
             return Ok(Ark::default());
         }
 
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(self.config.pool_wait_timeout_ms as u64);
+        let mut too_many_connections_attempts = 0;
+        let mut connect_retries = 0;
         loop {
+            // It may start draining while waiting
+            if self.is_draining() {
+                return Err(Error::new(format!("{:?} is draining", self)));
+            }
             let mut created = false;
-            let pooled_conn = self.pooled_connections.lock().unwrap().pop();
+            let pooled_conn = if protocol_options.len() == 0 {
+                self.pooled_connections.lock().unwrap_or_else(PoisonError::into_inner).pop()
+            } else {
+                None
+            };
             let conn = if let Some(conn) = pooled_conn {
                 conn
             } else {
-                let conn = static_self.new_connection().await?;
+                if let Some(wait) = self.backoff_remaining() {
+                    // Don't try to grow the pool while backing off, queue the checkout
+                    // until a connection is returned to the pool or the backoff expires.
+                    let wait = match remaining_delay(wait, Instant::now(), deadline) {
+                        Some(wait) => wait,
+                        None => {
+                            self.wait_timeouts.fetch_add(1, Relaxed);
+                            return Err(Error::new(format!("{:?} timed out waiting for a connection while backing off", self)));
+                        },
+                    };
+                    wait_event(WaitEvent::PoolSlot);
+                    let _ = timeout(wait, self.returned.notified()).await;
+                    continue;
+                }
+                wait_event(WaitEvent::BackendConnect);
+                let conn = match static_self.new_connection(protocol_options).await {
+                    Ok(conn) => conn,
+                    Err(e) if is_too_many_connections(&e) => {
+                        too_many_connections_attempts += 1;
+                        self.too_many_connections_backoff(too_many_connections_attempts);
+                        if too_many_connections_attempts > self.config.too_many_connections_retries {
+                            return Err(e);
+                        }
+                        continue;
+                    },
+                    Err(e) if is_retryable_connect_error(&e) && connect_retries < self.connect_retries() => {
+                        connect_retries += 1;
+                        let delay = match remaining_delay(self.connect_retry_delay(connect_retries), Instant::now(), deadline) {
+                            Some(delay) => delay,
+                            None => return Err(e),
+                        };
+                        warn!(pool=?self, ?e, attempts=connect_retries, delay_ms=delay.as_millis() as u64, "error connecting to the database, retrying");
+                        sleep(delay).await;
+                        continue;
+                    },
+                    Err(e) => return Err(e),
+                };
                 if conn.is_none() {
-                    return Ok(Ark::default());
+                    // The pool is full, wait in line for a connection to be returned
+                    wait_event(WaitEvent::PoolSlot);
+                    match self.wait_for_connection(deadline).await {
+                        Some(conn) if conn.is_some() => conn,
+                        Some(_) => continue, // a connection closed, try to establish a new one
+                        None => return Ok(Ark::default()),
+                    }
+                } else {
+                    created = true;
+                    conn
                 }
-                created = true;
-                conn
             };
 
             // Remember if it was created for a transaction so we can decrement active_transactions later
@@ -84,7 +621,8 @@ impl ConnectionPool {
 
             // Set the role for the connection, which also checks that it's healthy.
             // If this fails, and the connection came from the pool, we try with another connection.
-            return if let Err(e) = conn.check_health_and_set_role(application_name, role).await {
+            wait_event(WaitEvent::BackendResponse);
+            return if let Err(e) = conn.check_health_and_set_role(application_name, role, self.is_read_only()).await {
                 // If this connection came from the pool, and failed the health check
                 // Record how long it was idle in the pool.
                 warn!(?e, idle_seconds=conn.idle_seconds(), role, "connection failed health check / set role");
@@ -97,15 +635,127 @@ impl ConnectionPool {
                 // Just return the error.
                 Err(e)
             } else {
+                let mut timings = if created { conn.checkout_timings() } else { CheckoutTimings::default() };
+                timings.wait_time = start.elapsed().saturating_sub(timings.connect_time + timings.tls_time + timings.auth_time);
+                conn.set_checkout_timings(timings);
+                transaction.disarm();
                 Ok(conn)
             }
         }
     }
 
-    async fn new_connection(&'static self) -> Result<Ark<BackendConn>> {
+    /// Waits until deadline in FIFO order for a connection to be returned to the pool. Returns the connection,
+    /// an empty Ark if a connection closed instead (so there may be room to establish a new one), or None
+    /// if the deadline passed or max_pool_waiters callers are already waiting.
+    async fn wait_for_connection(&self, deadline: Instant) -> Option<Ark<BackendConn>> {
+        let mut rx = {
+            let mut waiters = self.waiters.lock().unwrap_or_else(PoisonError::into_inner);
+            // A connection may have been returned since we last checked
+            if let Some(conn) = self.pooled_connections.lock().unwrap_or_else(PoisonError::into_inner).pop() {
+                return Some(conn);
+            }
+            if Instant::now() >= deadline || waiters.len() >= self.config.max_pool_waiters as usize {
+                self.wait_timeouts.fetch_add(1, Relaxed);
+                return None;
+            }
+            let (tx, rx) = oneshot::channel();
+            waiters.push_back(tx);
+            rx
+        };
+        self.waits.fetch_add(1, Relaxed);
+        let start = Instant::now();
+        let result = timeout_at(deadline, &mut rx).await;
+        self.wait_time_us.fetch_add(start.elapsed().as_micros() as u64, Relaxed);
+        match result {
+            Ok(Ok(conn)) => Some(conn),
+            Ok(Err(_)) => Some(Ark::default()),
+            Err(_) => {
+                // Our sender is dropped (and skipped by put) when the queue is next used. But put or
+                // connection_closed may have sent to it just as we timed out, don't lose what they sent.
+                rx.close();
+                match rx.try_recv() {
+                    Ok(conn) if conn.is_some() => return Some(conn),
+                    Ok(_) => self.connection_closed(),
+                    Err(_) => (),
+                }
+                self.wait_timeouts.fetch_add(1, Relaxed);
+                warn!(pool=?self, wait_ms=self.config.pool_wait_timeout_ms, "timed out waiting for a connection");
+                None
+            },
+        }
+    }
+
+    /// Tell the first waiter a connection closed, so it can try to establish a new one.
+    fn connection_closed(&self) {
+        let mut waiters = self.waiters.lock().unwrap_or_else(PoisonError::into_inner);
+        while let Some(waiter) = waiters.pop_front() {
+            if waiter.send(Ark::default()).is_ok() {
+                return;
+            }
+        }
+    }
+
+    /// Returns the remaining time to back off growing the pool, if any.
+    fn backoff_remaining(&self) -> Option<Duration> {
+        let backoff_until = self.backoff_until.load(Relaxed);
+        let now = self.created.elapsed().as_millis() as u64;
+        if backoff_until > now {
+            Some(Duration::from_millis(backoff_until - now))
+        } else {
+            None
+        }
+    }
+
+    /// Record a too_many_connections error and back off growing the pool with exponential backoff and jitter.
+    fn too_many_connections_backoff(&self, attempts: u32) {
+        self.too_many_connections.fetch_add(1, Relaxed);
+        let base_ms = self.config.too_many_connections_backoff_ms as u64;
+        let delay_ms = min(base_ms << min(attempts - 1, 16), MAX_TOO_MANY_CONNECTIONS_BACKOFF_MS);
+        // Equal jitter: wait at least half the delay, plus a random amount up to the other half
+        let jittered_ms = delay_ms / 2 + Worker::get().uniform_rand32((delay_ms / 2 + 1) as u32) as u64;
+        let until = self.created.elapsed().as_millis() as u64 + jittered_ms;
+        self.backoff_until.fetch_max(until, Relaxed);
+        warn!(pool=?self, attempts, backoff_ms=jittered_ms, "database has too many connections, backing off pool growth");
+    }
+
+    /// Returns the number of times to retry a failed attempt to connect (see connect_retry.retries.)
+    fn connect_retries(&self) -> u32 {
+        self.config.cluster.map_or(0, |c| c.connect_retry.retries)
+    }
+
+    /// Returns the delay before retry number attempts (starting at 1) of a failed connect,
+    /// with exponential backoff and jitter (see the connect_retry settings.)
+    fn connect_retry_delay(&self, attempts: u32) -> Duration {
+        let settings = &self.config.cluster.unwrap().connect_retry;
+        let delay_ms = backoff_delay_ms(settings.base_delay_ms as u64, settings.max_delay_ms as u64, attempts);
+        Duration::from_millis(jittered_delay_ms(delay_ms, settings.jitter, Worker::get().rand32()))
+    }
+
+    /// Creates a new authenticated connection that's never added to the pool, e.g. for LISTEN.
+    /// It still counts against max_connections, returns None if the pool is full.
+    pub(crate) async fn new_dedicated_connection(&'static self) -> Result<Ark<BackendConn>> {
+        self.new_connection(&ServerParams::new()).await
+    }
+
+    /// Establish and authenticate a new connection requesting the protocol_options, see get_reporting_waits.
+    async fn new_connection(&'static self, protocol_options: &ServerParams) -> Result<Ark<BackendConn>> {
+        let start = Instant::now();
         let conn = self.connect().await?;
+        if conn.is_none() {
+            return Ok(conn);
+        }
+        if protocol_options.len() != 0 {
+            conn.set_protocol_options(protocol_options.clone());
+        }
+        let connect_time = start.elapsed();
         // Authenticate the new connection (afterwards state is Ready)
+        let start = Instant::now();
         conn.authenticate(self).await?;
+        self.reachable.store(true, Relaxed);
+        let mut timings = conn.checkout_timings(); // tls_time is set by authenticate
+        timings.connect_time = connect_time;
+        timings.auth_time = start.elapsed().saturating_sub(timings.tls_time);
+        conn.set_checkout_timings(timings);
         // Clone the Ark so we can return it (closure below moves conn)
         let result = conn.clone();
 
@@ -113,15 +763,35 @@ impl ConnectionPool {
         // Which can happen asynchronously, and need to be handled (if only by dropping them)
         // even if the connection is idle in the pool.
         tokio::spawn(async move {
-            if let Err(e) = conn.run().await {
-                self.connections.increment_errors();
-                if let ErrorKind::ClosedError = e.kind() {
-                    // This is expected, don't pollute the logs by logging this
-                } else {
-                    warn!(?e, "backend connection run failed");
-                }
+            match catch_panic(conn.run()).await {
+                Ok(Err(e)) => {
+                    self.connections.increment_errors();
+                    // Nothing reads from the connection anymore, so put discards it if its client returns it
+                    conn.close();
+                    if let ErrorKind::ClosedError = e.kind() {
+                        // This is expected, don't pollute the logs by logging this
+                    } else {
+                        warn!(?e, "backend connection run failed");
+                    }
+                    // If the client's query can be retried on another connection, do so (see retry_reads)
+                    if !conn.retry_failed_request().await {
+                        if let Some(client) = conn.client() {
+                            client.backend_failed();
+                        }
+                    }
+                },
+                Err(panic) => {
+                    self.connections.increment_errors();
+                    conn.panicked(panic).await;
+                },
+                Ok(Ok(())) => (),
+            }
+            if conn.is_notification_listener() {
+                self.notifications.listener_closed(self);
             }
             self.remove(&conn);
+            drop(conn);
+            self.connection_closed();
         });
 
         let isolation = self.default_isolation_level.load();
@@ -148,6 +818,14 @@ impl ConnectionPool {
             debug_assert!(prev > 0);
         }
 
+        // A closed connection (e.g. after a panic, see BackendConn::panicked) is discarded here,
+        // as is one that negotiated a client's protocol options (see get_reporting_waits)
+        if self.is_draining() || conn.transport().is_closed() || conn.protocol_options().len() != 0
+            || (self.config.server_lifetime_seconds != 0 && conn.age_seconds() >= self.config.server_lifetime_seconds) {
+            conn.close();
+            return
+        }
+
         if let Err(e) = conn.reset().await {
             conn.close();
             warn!(?e, "error resetting connection");
@@ -159,7 +837,17 @@ impl ConnectionPool {
             return
         }
 
-        self.pooled_connections.lock().unwrap().push(conn);
+        // Hand the connection to the longest waiting caller of get, if any
+        let mut waiters = self.waiters.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut conn = conn;
+        while let Some(waiter) = waiters.pop_front() {
+            match waiter.send(conn) {
+                Ok(()) => return,
+                Err(returned) => conn = returned, // the waiter timed out
+            }
+        }
+        self.pooled_connections.lock().unwrap_or_else(PoisonError::into_inner).push(conn);
+        self.returned.notify_one();
     }
 
     fn remove(&'static self, conn: &Ark<BackendConn>) {
@@ -167,7 +855,7 @@ impl ConnectionPool {
             return
         }
 
-        let mut pool = self.pooled_connections.lock().unwrap();
+        let mut pool = self.pooled_connections.lock().unwrap_or_else(PoisonError::into_inner);
         // rposition should be slightly better than position here, as we remove needs to slide the
         // tail elements down, which will now be in cache after the search with rposition.
         if let Some(i) = pool.iter().rposition(|a| Ark::ptr_eq(a,conn)) {
@@ -176,6 +864,101 @@ impl ConnectionPool {
     }
 }
 
+/// Returns true if a connection idle for idle_seconds and established age_seconds ago exceeded the
+/// idle_timeout or lifetime (in seconds, 0 is disabled.)
+fn is_expired(idle_seconds: u32, age_seconds: u32, idle_timeout: u32, lifetime: u32) -> bool {
+    (idle_timeout != 0 && idle_seconds >= idle_timeout) || (lifetime != 0 && age_seconds >= lifetime)
+}
+
+/// Returns true if the replication lag lag_ms (u64::MAX if unknown) exceeds max_lag_ms (0 is disabled.)
+fn is_lag_exceeded(lag_ms: u64, max_lag_ms: u32) -> bool {
+    max_lag_ms != 0 && lag_ms > max_lag_ms as u64
+}
+
+/// Returns the delay before retry number attempts (starting at 1): base_ms doubled for each
+/// previous attempt, up to max_ms.
+pub(crate) fn backoff_delay_ms(base_ms: u64, max_ms: u64, attempts: u32) -> u64 {
+    min(base_ms.saturating_mul(1 << min(attempts.saturating_sub(1), 32)), max_ms)
+}
+
+/// Randomize delay_ms with the given jitter and random number.
+pub(crate) fn jittered_delay_ms(delay_ms: u64, jitter: Jitter, rand: u32) -> u64 {
+    match jitter {
+        Jitter::None => delay_ms,
+        Jitter::Equal => delay_ms / 2 + rand as u64 % (delay_ms / 2 + 1),
+        Jitter::Full => rand as u64 % (delay_ms + 1),
+    }
+}
+
+/// Counts a checkout for a transaction in active_transactions, and undoes that when dropped, unless it's disarmed
+/// because the connection was handed out (put undoes it when the connection is returned, see created_for_transaction.)
+struct TransactionSlot<'a> {
+    active_transactions: Option<&'a AtomicI32>,
+    others: i32,
+}
+
+impl<'a> TransactionSlot<'a> {
+    fn new(active_transactions: &'a AtomicI32, for_transaction: bool) -> Self {
+        if for_transaction {
+            let others = active_transactions.fetch_add(1, Relaxed);
+            Self{active_transactions: Some(active_transactions), others}
+        } else {
+            Self{active_transactions: None, others: 0}
+        }
+    }
+
+    /// Returns the number of other active transactions when this one was counted, or 0 if it's not for a transaction.
+    fn others(&self) -> i32 {
+        self.others
+    }
+
+    fn disarm(mut self) {
+        self.active_transactions = None;
+    }
+}
+
+impl Drop for TransactionSlot<'_> {
+    fn drop(&mut self) {
+        if let Some(active_transactions) = self.active_transactions {
+            let prev = active_transactions.fetch_add(-1, Relaxed);
+            debug_assert!(prev > 0);
+        }
+    }
+}
+
+/// Returns delay shortened to end at deadline, or None if the deadline has passed.
+fn remaining_delay(delay: Duration, now: Instant, deadline: Instant) -> Option<Duration> {
+    if now >= deadline {
+        None
+    } else {
+        Some(min(delay, deadline - now))
+    }
+}
+
+/// Returns true if e is an error connecting to the database that may not happen again on another attempt:
+/// a network error, or a Postgres error reporting a connection exception or that the server is starting up
+/// or shutting down. Authentication failures and too_many_connections (see too_many_connections_retries) aren't.
+fn is_retryable_connect_error(e: &Error) -> bool {
+    match e.kind() {
+        ErrorKind::Io{..} | ErrorKind::ClosedError => true,
+        ErrorKind::PostgresError{source} => {
+            let code = source.code();
+            code.starts_with("08") || code == error_codes::CANNOT_CONNECT_NOW
+                || code == error_codes::ADMIN_SHUTDOWN || code == error_codes::CRASH_SHUTDOWN
+        },
+        _ => false,
+    }
+}
+
+/// Returns true if e is a too_many_connections error from the database.
+fn is_too_many_connections(e: &Error) -> bool {
+    if let ErrorKind::PostgresError{source} = e.kind() {
+        source.code() == error_codes::TOO_MANY_CONNECTIONS
+    } else {
+        false
+    }
+}
+
 // Safety: although ConnectionPool contains a reference, it's a shared thread-safe 'static reference.
 // It is safe to send and share a ConnectionPool between threads.
 unsafe impl Send for ConnectionPool {}
@@ -185,4 +968,106 @@ impl Debug for ConnectionPool {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!("pg::ConnectionPool({})", self.config.address.as_ref().unwrap()))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riverdb::pg::protocol::{Messages, PostgresError};
+
+    #[test]
+    fn test_is_expired() {
+        assert!(!is_expired(10, 100, 0, 0));
+        assert!(!is_expired(10, 100, 60, 3600));
+        assert!(is_expired(60, 100, 60, 3600));
+        assert!(is_expired(10, 3600, 60, 3600));
+        assert!(!is_expired(60, 3600, 0, 0));
+    }
+
+    #[test]
+    fn test_warmup_factor() {
+        assert_eq!(warmup_factor(0, 0), 1.0);
+        assert_eq!(warmup_factor(5000, 0), 1.0);
+        assert_eq!(warmup_factor(0, 60000), 0.0);
+        assert_eq!(warmup_factor(15000, 60000), 0.25);
+        assert_eq!(warmup_factor(60000, 60000), 1.0);
+        assert_eq!(warmup_factor(90000, 60000), 1.0);
+    }
+
+    #[test]
+    fn test_is_lag_exceeded() {
+        assert!(!is_lag_exceeded(0, 1000));
+        assert!(!is_lag_exceeded(1000, 1000));
+        assert!(is_lag_exceeded(1001, 1000));
+        assert!(is_lag_exceeded(u64::MAX, 1000));
+        assert!(!is_lag_exceeded(u64::MAX, 0));
+        assert!(!is_lag_exceeded(60000, 0));
+    }
+
+    #[test]
+    fn test_backoff_delay_ms() {
+        assert_eq!(backoff_delay_ms(100, 5000, 1), 100);
+        assert_eq!(backoff_delay_ms(100, 5000, 2), 200);
+        assert_eq!(backoff_delay_ms(100, 5000, 5), 1600);
+        assert_eq!(backoff_delay_ms(100, 5000, 6), 3200);
+        assert_eq!(backoff_delay_ms(100, 5000, 7), 5000);
+        assert_eq!(backoff_delay_ms(100, 5000, 1000), 5000);
+    }
+
+    #[test]
+    fn test_jittered_delay_ms() {
+        assert_eq!(jittered_delay_ms(1000, Jitter::None, 12345), 1000);
+        for rand in [0, 1, 499, 500, 501, 12345, u32::MAX] {
+            let equal = jittered_delay_ms(1000, Jitter::Equal, rand);
+            assert!(equal >= 500 && equal <= 1000);
+            assert!(jittered_delay_ms(1000, Jitter::Full, rand) <= 1000);
+        }
+        assert_eq!(jittered_delay_ms(1000, Jitter::Full, 0), 0);
+        assert_eq!(jittered_delay_ms(0, Jitter::Equal, 7), 0);
+    }
+
+    #[test]
+    fn test_transaction_slot() {
+        let active_transactions = AtomicI32::new(0);
+        let slot = TransactionSlot::new(&active_transactions, true);
+        assert_eq!(slot.others(), 0);
+        drop(slot);
+        assert_eq!(active_transactions.load(Relaxed), 0);
+
+        let slot = TransactionSlot::new(&active_transactions, true);
+        slot.disarm();
+        assert_eq!(active_transactions.load(Relaxed), 1);
+
+        let slot = TransactionSlot::new(&active_transactions, true);
+        assert_eq!(slot.others(), 1);
+        drop(slot);
+        let slot = TransactionSlot::new(&active_transactions, false);
+        assert_eq!(slot.others(), 0);
+        drop(slot);
+        assert_eq!(active_transactions.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn test_remaining_delay() {
+        let now = Instant::now();
+        let deadline = now + Duration::from_millis(500);
+        assert_eq!(remaining_delay(Duration::from_millis(100), now, deadline), Some(Duration::from_millis(100)));
+        assert_eq!(remaining_delay(Duration::from_millis(1000), now, deadline), Some(Duration::from_millis(500)));
+        assert_eq!(remaining_delay(Duration::from_millis(100), deadline, deadline), None);
+        assert_eq!(remaining_delay(Duration::from_millis(100), deadline + Duration::from_millis(1), deadline), None);
+    }
+
+    #[test]
+    fn test_is_retryable_connect_error() {
+        let io_error = Error::from(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+        assert!(is_retryable_connect_error(&io_error));
+        assert!(is_retryable_connect_error(&Error::closed()));
+        let pg_error = |code| Error::from(PostgresError::new(Messages::new_error(code, "error")).unwrap());
+        assert!(is_retryable_connect_error(&pg_error(error_codes::CANNOT_CONNECT_NOW)));
+        assert!(is_retryable_connect_error(&pg_error(error_codes::ADMIN_SHUTDOWN)));
+        assert!(is_retryable_connect_error(&pg_error(error_codes::CONNECTION_FAILURE)));
+        assert!(!is_retryable_connect_error(&pg_error(error_codes::INVALID_PASSWORD)));
+        assert!(!is_retryable_connect_error(&pg_error(error_codes::TOO_MANY_CONNECTIONS)));
+        assert!(!is_retryable_connect_error(&Error::new("invalid config")));
+    }
+}
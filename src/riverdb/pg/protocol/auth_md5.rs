@@ -11,9 +11,21 @@ pub fn hash_md5_password(user: &str, password: &str, salt: i32) -> String {
     hasher.input_str(user);
     let mut pwd_hash = [0; 16];
     hasher.result(&mut pwd_hash);
-    hasher.reset();
-    hasher.input_str(&hex::encode(&pwd_hash[..]));
+    salt_md5_hash(&hex::encode(&pwd_hash[..]), salt)
+}
+
+/// Construct the same String as hash_md5_password given the md5 password hash of the user
+/// as stored by PostgreSQL in pg_authid ("md5" followed by the hex-encoded MD5 digest of the
+/// password and user), which allows verifying a client's response without knowing the password.
+pub fn hash_md5_stored_password(stored: &str, salt: i32) -> String {
+    salt_md5_hash(stored.strip_prefix("md5").unwrap_or(stored), salt)
+}
+
+fn salt_md5_hash(hex_hash: &str, salt: i32) -> String {
+    let mut hasher = Md5::new();
+    hasher.input_str(hex_hash);
     hasher.input(&salt.to_be_bytes()[..]);
+    let mut pwd_hash = [0; 16];
     hasher.result(&mut pwd_hash);
 
     let mut result = String::with_capacity(32+3);
@@ -37,4 +49,12 @@ mod tests {
             "md562af4dd09bbb41884907a838a3233294"
         );
     }
+    #[test]
+    fn test_hash_md5_stored_password() {
+        // md5("foobar" + "username")
+        assert_eq!(
+            hash_md5_stored_password("md548bcb74dee348b76267719ca552ad77b", 0xa26892c4u32 as i32),
+            "md57b4e445f6041af0d6d962d0cbd830f18"
+        );
+    }
 }
\ No newline at end of file
@@ -0,0 +1,107 @@
+use std::fmt;
+
+use crate::riverdb::pg::protocol::{hash_md5_password, hash_md5_stored_password};
+use crate::riverdb::pg::protocol::sasl::{ScramVerifier, ScramSha256Server};
+
+
+/// The stored credentials of a user, used to verify the password a client authenticates with.
+#[derive(Clone)]
+pub enum Credentials {
+    /// The plaintext password
+    Password(String),
+    /// The md5 password hash, as stored in pg_authid ("md5" followed by 32 hex digits)
+    Md5(String),
+    /// The SCRAM-SHA-256 verifier, as stored in pg_authid
+    Scram(ScramVerifier),
+}
+
+impl Credentials {
+    /// Parses a password as stored in pg_authid, which may be an md5 hash, a SCRAM-SHA-256 verifier,
+    /// or a plaintext password.
+    pub fn parse(stored: &str) -> Credentials {
+        if let Some(verifier) = ScramVerifier::parse(stored) {
+            Credentials::Scram(verifier)
+        } else if stored.len() == 35 && stored.starts_with("md5") && stored[3..].bytes().all(|b| b.is_ascii_hexdigit()) {
+            Credentials::Md5(stored.to_ascii_lowercase())
+        } else {
+            Credentials::Password(stored.to_string())
+        }
+    }
+
+    /// Returns true if the plaintext password sent by the client for user matches these credentials.
+    pub fn verify_password(&self, user: &str, password: &str) -> bool {
+        match self {
+            Credentials::Password(expected) => expected == password,
+            Credentials::Md5(stored) => hash_md5_password(user, password, 0) == hash_md5_stored_password(stored, 0),
+            Credentials::Scram(verifier) => verifier.verify_password(password.as_bytes()),
+        }
+    }
+
+    /// Returns true if the md5 response sent by the client for user matches these credentials.
+    /// Always false for SCRAM-SHA-256 credentials, which can't be used for md5 authentication.
+    pub fn verify_md5(&self, user: &str, response: &str, salt: i32) -> bool {
+        match self {
+            Credentials::Password(password) => hash_md5_password(user, password, salt) == response,
+            Credentials::Md5(stored) => hash_md5_stored_password(stored, salt) == response,
+            Credentials::Scram(_) => false,
+        }
+    }
+
+    /// Returns true if these credentials can be used for SCRAM-SHA-256 authentication.
+    pub fn supports_scram(&self) -> bool {
+        !matches!(self, Credentials::Md5(_))
+    }
+
+    /// Returns true if these credentials can be used for md5 authentication.
+    pub fn supports_md5(&self) -> bool {
+        !matches!(self, Credentials::Scram(_))
+    }
+
+    /// Returns a SCRAM-SHA-256 server for authenticating a client with these credentials,
+    /// or None if that's not possible (md5 hashes can't be used for SCRAM authentication.)
+    pub fn scram_server(&self, tls_server_end_point: Vec<u8>) -> Option<ScramSha256Server> {
+        match self {
+            Credentials::Password(password) => Some(ScramSha256Server::new(password.as_bytes(), tls_server_end_point)),
+            Credentials::Md5(_) => None,
+            Credentials::Scram(verifier) => Some(ScramSha256Server::with_verifier(verifier.clone(), tls_server_end_point)),
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't leak secrets into the logs
+        f.write_str(match self {
+            Credentials::Password(_) => "Password(***)",
+            Credentials::Md5(_) => "Md5(***)",
+            Credentials::Scram(_) => "Scram(***)",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credentials() {
+        let md5 = Credentials::parse("md548bcb74dee348b76267719ca552ad77b");
+        assert!(matches!(md5, Credentials::Md5(_)));
+        assert!(md5.verify_password("username", "foobar"));
+        assert!(!md5.verify_password("username", "foobaz"));
+        assert!(md5.verify_md5("username", "md57b4e445f6041af0d6d962d0cbd830f18", 0xa26892c4u32 as i32));
+        assert!(md5.scram_server(vec![]).is_none());
+
+        let scram = Credentials::parse("SCRAM-SHA-256$4096:fs3IXBy7U7+IvVjZ$tmgbRn9qfDg3ip++wAxsFIk0Zl9PF0NDB5npDjVeECM=:bSU5hgS4vu9S/BceyyM0+b0RxdaRtdH5LRjmCPcHxhg=");
+        assert!(matches!(scram, Credentials::Scram(_)));
+        assert!(scram.verify_password("username", "foobar"));
+        assert!(!scram.verify_md5("username", "md57b4e445f6041af0d6d962d0cbd830f18", 0xa26892c4u32 as i32));
+        assert!(scram.scram_server(vec![]).is_some());
+
+        let password = Credentials::parse("foobar");
+        assert!(matches!(password, Credentials::Password(_)));
+        assert!(password.verify_password("username", "foobar"));
+        assert!(password.verify_md5("username", "md57b4e445f6041af0d6d962d0cbd830f18", 0xa26892c4u32 as i32));
+        assert_eq!(format!("{:?}", password), "Password(***)");
+    }
+}
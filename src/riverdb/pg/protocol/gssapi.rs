@@ -0,0 +1,105 @@
+//! GSSAPI (Kerberos) authentication support, when built with the gssapi feature.
+
+use crate::riverdb::{Error, Result};
+
+#[cfg(feature = "gssapi")]
+mod imp {
+    use libgssapi::context::{ClientCtx, CtxFlags, SecurityContext};
+    use libgssapi::credential::{Cred, CredUsage};
+    use libgssapi::name::Name;
+    use libgssapi::oid::{OidSet, GSS_MECH_KRB5, GSS_NT_HOSTBASED_SERVICE};
+
+    use crate::riverdb::{Error, Result};
+
+    fn gss_error(e: libgssapi::error::Error) -> Error {
+        Error::new(format!("gssapi error: {}", e))
+    }
+
+    pub struct GssClient {
+        ctx: ClientCtx,
+    }
+
+    impl GssClient {
+        pub fn new(service: &str, host: &str) -> Result<Self> {
+            let mut mechs = OidSet::new().map_err(gss_error)?;
+            mechs.add(&GSS_MECH_KRB5).map_err(gss_error)?;
+            let cred = Cred::acquire(None, None, CredUsage::Initiate, Some(&mechs)).map_err(gss_error)?;
+            let target = Name::new(format!("{}@{}", service, host).as_bytes(), Some(&GSS_NT_HOSTBASED_SERVICE))
+                .and_then(|name| name.canonicalize(Some(&GSS_MECH_KRB5)))
+                .map_err(gss_error)?;
+            Ok(Self {
+                ctx: ClientCtx::new(cred, target, CtxFlags::GSS_C_MUTUAL_FLAG, Some(&GSS_MECH_KRB5)),
+            })
+        }
+
+        pub fn step(&mut self, token: Option<&[u8]>) -> Result<Option<Vec<u8>>> {
+            Ok(self.ctx.step(token).map_err(gss_error)?.map(|buf| buf.to_vec()))
+        }
+
+        pub fn is_complete(&self) -> bool {
+            self.ctx.is_complete()
+        }
+    }
+
+    // Safety: a security context can be used from any thread, it's only used by one at a time (behind a Mutex.)
+    unsafe impl Send for GssClient {}
+}
+
+#[cfg(not(feature = "gssapi"))]
+mod imp {
+    use crate::riverdb::{Error, Result};
+
+    pub struct GssClient {}
+
+    impl GssClient {
+        pub fn new(_service: &str, _host: &str) -> Result<Self> {
+            Err(Error::new("GSSAPI authentication requires riverdb to be built with the gssapi feature"))
+        }
+
+        pub fn step(&mut self, _token: Option<&[u8]>) -> Result<Option<Vec<u8>>> {
+            unreachable!()
+        }
+
+        pub fn is_complete(&self) -> bool {
+            unreachable!()
+        }
+    }
+}
+
+/// The Kerberos service name of PostgreSQL servers, unless they're configured with a different krb_srvname.
+pub const DEFAULT_SERVICE_NAME: &str = "postgres";
+
+/// Use the client keytab (gss_keytab) to acquire the credentials for GSSAPI authentication, if not empty.
+/// The Kerberos library reads it from the KRB5_CLIENT_KTNAME environment variable, which is global to the process,
+/// so this is called once on startup by load_config or init_config, before any other threads are started.
+pub fn set_client_keytab(keytab: &str) {
+    if !keytab.is_empty() {
+        std::env::set_var("KRB5_CLIENT_KTNAME", keytab);
+    }
+}
+
+/// Gss is the client side of the GSSAPI security context used to authenticate with the server.
+/// Authentication completes when the server sends AuthenticationOk, after exchanging tokens
+/// in GSSResponse and AuthenticationGSSContinue messages.
+pub struct Gss {
+    client: imp::GssClient,
+}
+
+impl Gss {
+    /// Acquires the credentials from the default credential cache, or the client keytab (see set_client_keytab),
+    /// to authenticate with service on host. Returns the Gss state and the initial token to send to the server.
+    pub fn new(service: &str, host: &str) -> Result<(Self, Vec<u8>)> {
+        let mut client = imp::GssClient::new(service, host)?;
+        let token = client.step(None)?.unwrap_or_default();
+        Ok((Self{client}, token))
+    }
+
+    /// Processes the token from the server in an AuthenticationGSSContinue message.
+    /// Returns the token to send back to the server, if any.
+    pub fn update(&mut self, token: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.client.is_complete() {
+            return Err(Error::new("unexpected GSSContinue message, authentication is already complete"));
+        }
+        Ok(self.client.step(Some(token))?.filter(|token| !token.is_empty()))
+    }
+}
@@ -55,6 +55,7 @@ impl Display for Header {
 /// A parser for incrementally parsing Postgres messages from a BytesMut buffer
 pub struct MessageParser {
     data: BytesMut,
+    max_message_len: u32,
 }
 
 impl MessageParser {
@@ -62,9 +63,16 @@ impl MessageParser {
     pub fn new() -> Self {
         Self {
             data: BytesMut::with_capacity(conf().recv_buffer_size as usize),
+            max_message_len: 0,
         }
     }
 
+    /// Set the maximum length of a message frame, larger messages result in a MessageTooLarge error.
+    /// 0 is unlimited, which is the default.
+    pub fn set_max_message_len(&mut self, max_message_len: u32) {
+        self.max_message_len = max_message_len;
+    }
+
     /// Returns the next byte in the buffer (or None) if empty. Does not advance the read position.
     pub fn peek(&mut self) -> Option<u8> {
         self.data.first().cloned()
@@ -90,6 +98,9 @@ impl MessageParser {
                 },
                 Ok(None) => { break; },
                 Ok(Some(hdr)) => {
+                    if self.max_message_len != 0 && hdr.len() > self.max_message_len {
+                        return Some(Err(Error::message_too_large(hdr.len(), self.max_message_len)));
+                    }
                     let msg_end = pos + hdr.len() as usize;
                     if msg_end <= self.data.len() {
                         // We have the full message. Start after this message and loop again.
@@ -183,6 +194,17 @@ mod tests {
         assert_eq!(msgs.len(), 5);
     }
 
+    #[test]
+    fn test_parse_message_too_large() {
+        let mut parser = MessageParser::new();
+        parser.set_max_message_len(8);
+        parser.bytes_mut().put_slice(&[0u8,0,0,9]);
+        assert!(parser.next(true).is_none());
+        parser.bytes_mut().put_u8(0);
+        let err = parser.next(true).expect("expected an error").unwrap_err();
+        assert_eq!(err.to_string(), "message length 9 exceeds the maximum of 8");
+    }
+
     #[test]
     fn test_parse_multiple_messages() {
         // TODO
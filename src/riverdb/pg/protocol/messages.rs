@@ -2,11 +2,11 @@ use std::fmt;
 use std::fmt::{Display, Formatter, Debug, Write};
 
 
-use bytes::{Bytes, Buf};
+use bytes::{Bytes, BytesMut, Buf, BufMut};
 use tracing::{error};
 
 
-use crate::riverdb::pg::protocol::{Message, MessageErrorBuilder, ErrorSeverity};
+use crate::riverdb::pg::protocol::{Message, MessageBuilder, MessageErrorBuilder, ErrorSeverity, Tag};
 use crate::riverdb::pg::protocol::message_parser::{Header, MIN_MESSAGE_LEN};
 use crate::riverdb::common::unsplit_bytes;
 
@@ -44,6 +44,18 @@ impl Messages {
         mb.finish()
     }
 
+    /// Return a new Message of type Tag::NEGOTIATE_PROTOCOL_VERSION with the newest minor version of protocol 3
+    /// supported, and the protocol options requested by the client that aren't supported.
+    pub fn new_negotiate_protocol_version(minor_version: i32, unsupported_options: &[&str]) -> Self {
+        let mut mb = MessageBuilder::new(Tag::NEGOTIATE_PROTOCOL_VERSION);
+        mb.write_i32(minor_version);
+        mb.write_i32(unsupported_options.len() as i32);
+        for option in unsupported_options {
+            mb.write_str(option);
+        }
+        mb.finish()
+    }
+
     /// Returns true if Message was initialized with an empty buffer
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
@@ -154,6 +166,26 @@ impl Messages {
         }
     }
 
+    /// Returns the messages in [start, end) as a new Messages object. Zero-copy.
+    /// Panics if the range is out of bounds.
+    pub fn slice(&self, start: usize, end: usize) -> Self {
+        Self::new(self.0.slice(start..end))
+    }
+
+    /// Returns a Messages object containing the messages in self followed by those in other.
+    /// This is zero-copy if either is empty, otherwise it copies both into a new buffer.
+    pub fn append(self, other: Self) -> Self {
+        if self.is_empty() {
+            return other;
+        } else if other.is_empty() {
+            return self;
+        }
+        let mut buf = BytesMut::with_capacity(self.0.len() + other.0.len());
+        buf.put_slice(self.as_slice());
+        buf.put_slice(other.as_slice());
+        Self::new(buf.freeze())
+    }
+
     /// If other follows directly after self in memory, this merges other into self and returns self.
     /// Otherwise returns both self and other unchanged.
     /// Safety: see note on unsplit_bytes for when this may be undefined beahvior.
@@ -232,3 +264,20 @@ impl<'a> Iterator for MessageIter<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_negotiate_protocol_version() {
+        let msgs = Messages::new_negotiate_protocol_version(0, &["_pq_.compression", "_pq_.other"]);
+        let msg = msgs.first().unwrap();
+        assert_eq!(msg.tag(), Tag::NEGOTIATE_PROTOCOL_VERSION);
+        let mut r = msg.reader();
+        assert_eq!(r.read_i32(), 0);
+        assert_eq!(r.read_i32(), 2);
+        assert_eq!(r.read_str().unwrap(), "_pq_.compression");
+        assert_eq!(r.read_str().unwrap(), "_pq_.other");
+    }
+}
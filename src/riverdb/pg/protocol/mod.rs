@@ -10,9 +10,11 @@ mod message_error_builder;
 mod server_params;
 mod auth_type;
 mod auth_md5;
+mod credentials;
 mod row_description;
 mod messages;
 pub mod sasl;
+pub mod gssapi;
 
 pub use self::tag::*;
 pub use self::message::Message;
@@ -23,7 +25,8 @@ pub use self::message_builder::MessageBuilder;
 pub use self::message_error_builder::MessageErrorBuilder;
 pub use self::errors::{ErrorFieldTag, ErrorSeverity};
 pub use self::message_error::PostgresError;
-pub use self::server_params::ServerParams;
+pub use self::server_params::{ServerParams, PROTOCOL_OPTION_PREFIX, COMPRESSION_OPTION};
 pub use self::auth_type::AuthType;
-pub use self::auth_md5::hash_md5_password;
+pub use self::auth_md5::{hash_md5_password, hash_md5_stored_password};
+pub use self::credentials::Credentials;
 pub use self::row_description::{RowDescription, FieldDescription};
\ No newline at end of file
@@ -10,14 +10,19 @@ use std::str;
 use rand::{self, Rng};
 use crypto::digest::Digest;
 use crypto::hmac::{Hmac};
-use crypto::sha2::{Sha256};
+use crypto::sha2::{Sha224, Sha256, Sha384, Sha512};
 use crypto::mac::{Mac, MacResult};
 
 use crate::riverdb::{Error, Result};
 use crate::riverdb::pg::protocol::{Tag, PostgresError, AuthType, Messages};
-use std::convert::TryFrom;
+use std::convert::{TryFrom, TryInto};
 
 const NONCE_LENGTH: usize = 24;
+const SALT_LENGTH: usize = 16;
+const ITERATION_COUNT: u32 = 4096;
+
+/// The only channel binding type supported (and the only one supported by PostgreSQL.)
+const TLS_SERVER_END_POINT: &str = "tls-server-end-point";
 
 /// The identifier of the SCRAM-SHA-256 SASL authentication mechanism.
 pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
@@ -63,6 +68,129 @@ pub(crate) fn hi(str: &[u8], salt: &[u8], i: u32) -> [u8; 32] {
     result
 }
 
+/// Returns a random printable nonce (excluding ',') of NONCE_LENGTH characters.
+fn random_nonce() -> String {
+    // rand 0.5's ThreadRng is cryptographically secure
+    let mut rng = rand::thread_rng();
+    (0..NONCE_LENGTH)
+        .map(|_| {
+            let mut v = rng.gen_range(0x21u8..0x7e);
+            if v == 0x2c {
+                v = 0x7e
+            }
+            v as char
+        })
+        .collect::<String>()
+}
+
+/// Returns the tls-server-end-point channel binding data for the DER encoded certificate:
+/// the hash of the certificate, using the hash function of its signature algorithm,
+/// or SHA-256 if that is MD5 or SHA-1 (RFC 5929.) Returns None if the certificate can't be parsed,
+/// or its signature algorithm doesn't use a single hash function (e.g. Ed25519), like PostgreSQL
+/// channel binding isn't possible then.
+pub fn tls_server_end_point(cert: &[u8]) -> Option<Vec<u8>> {
+    let mut hash: Box<dyn Digest> = match signature_hash(cert)? {
+        SignatureHash::Sha224 => Box::new(Sha224::new()),
+        SignatureHash::Sha256 => Box::new(Sha256::new()),
+        SignatureHash::Sha384 => Box::new(Sha384::new()),
+        SignatureHash::Sha512 => Box::new(Sha512::new()),
+    };
+    hash.input(cert);
+    let mut result = vec![0u8; hash.output_bytes()];
+    hash.result(&mut result);
+    Some(result)
+}
+
+/// The hash function used for tls-server-end-point, see signature_hash.
+#[derive(Debug, Eq, PartialEq)]
+enum SignatureHash {
+    Sha224,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+const DER_SEQUENCE: u8 = 0x30;
+const DER_OID: u8 = 0x06;
+/// the [0] EXPLICIT tag of hashAlgorithm in RSASSA-PSS-params
+const DER_CONTEXT_0: u8 = 0xa0;
+
+/// The object identifier (the DER contents) of RSASSA-PSS, whose parameters specify the hash function.
+const RSASSA_PSS_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0a];
+/// The object identifier of SHA-1, the default hash function of RSASSA-PSS.
+const SHA1_OID: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+
+/// Returns the hash function to use for tls-server-end-point with the DER encoded certificate,
+/// from the signatureAlgorithm of the certificate. SHA-256 replaces MD5 and SHA-1.
+fn signature_hash(cert: &[u8]) -> Option<SignatureHash> {
+    // Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm AlgorithmIdentifier, signatureValue }
+    // AlgorithmIdentifier ::= SEQUENCE { algorithm OBJECT IDENTIFIER, parameters ANY OPTIONAL }
+    let cert = der_expect(cert, DER_SEQUENCE)?.0;
+    let (_, _tbs_certificate, rest) = der_read(cert)?;
+    let algorithm = der_expect(rest, DER_SEQUENCE)?.0;
+    let (oid, parameters) = der_expect(algorithm, DER_OID)?;
+    let oid = if oid == RSASSA_PSS_OID {
+        // RSASSA-PSS-params ::= SEQUENCE { hashAlgorithm [0] EXPLICIT AlgorithmIdentifier DEFAULT sha1, ... }
+        let parameters = der_expect(parameters, DER_SEQUENCE)?.0;
+        match der_expect(parameters, DER_CONTEXT_0) {
+            Some((hash_algorithm, _)) => der_expect(der_expect(hash_algorithm, DER_SEQUENCE)?.0, DER_OID)?.0,
+            None => SHA1_OID,
+        }
+    } else {
+        oid
+    };
+    match oid {
+        // md5WithRSAEncryption, sha1WithRSAEncryption, sha256WithRSAEncryption
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x04 | 0x05 | 0x0b] => Some(SignatureHash::Sha256),
+        // ecdsa-with-SHA1, ecdsa-with-SHA256
+        [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x01] | [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02] => Some(SignatureHash::Sha256),
+        // SHA-1, SHA-256 (the hash functions of RSASSA-PSS)
+        [0x2b, 0x0e, 0x03, 0x02, 0x1a] | [0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01] => Some(SignatureHash::Sha256),
+        // sha224WithRSAEncryption, ecdsa-with-SHA224, SHA-224
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0e] | [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x01] |
+        [0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x04] => Some(SignatureHash::Sha224),
+        // sha384WithRSAEncryption, ecdsa-with-SHA384, SHA-384
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c] | [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03] |
+        [0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02] => Some(SignatureHash::Sha384),
+        // sha512WithRSAEncryption, ecdsa-with-SHA512, SHA-512
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d] | [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x04] |
+        [0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03] => Some(SignatureHash::Sha512),
+        // Ed25519 and Ed448 don't use a separate hash function, nor does anything else we don't know
+        _ => None,
+    }
+}
+
+/// Reads the DER encoded value at the start of der, which must have the given tag.
+/// Returns its contents and the bytes after it.
+fn der_expect(der: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    match der_read(der)? {
+        (t, contents, rest) if t == tag => Some((contents, rest)),
+        _ => None,
+    }
+}
+
+/// Reads the DER encoded value (tag, length, contents) at the start of der.
+/// Returns its tag, its contents, and the bytes after it. Only single byte tags are supported.
+fn der_read(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        // The long form: the low bits are the number of length bytes that follow
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n].iter().fold(0usize, |len, &b| (len << 8) | b as usize);
+        (len, &rest[n..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
 enum ChannelBindingInner {
     Unrequested,
     Unsupported,
@@ -141,19 +269,7 @@ pub struct ScramSha256 {
 impl ScramSha256 {
     /// Constructs a new instance which will use the provided password for authentication.
     pub fn new(password: &[u8], channel_binding: ChannelBinding) -> ScramSha256 {
-        // rand 0.5's ThreadRng is cryptographically secure
-        let mut rng = rand::thread_rng();
-        let nonce = (0..NONCE_LENGTH)
-            .map(|_| {
-                let mut v = rng.gen_range(0x21u8..0x7e);
-                if v == 0x2c {
-                    v = 0x7e
-                }
-                v as char
-            })
-            .collect::<String>();
-
-        ScramSha256::new_inner(password, channel_binding, nonce)
+        ScramSha256::new_inner(password, channel_binding, random_nonce())
     }
 
     fn new_inner(password: &[u8], channel_binding: ChannelBinding, nonce: String) -> ScramSha256 {
@@ -319,6 +435,263 @@ impl ScramSha256 {
     }
 }
 
+/// The SCRAM-SHA-256 verifier for a password, in the format PostgreSQL stores it in pg_authid:
+/// SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>, with the last three base64 encoded.
+#[derive(Clone, Eq, PartialEq)]
+pub struct ScramVerifier {
+    iterations: u32,
+    salt: Vec<u8>,
+    stored_key: [u8; 32],
+    server_key: [u8; 32],
+}
+
+impl ScramVerifier {
+    /// Computes the verifier for password with the given salt and number of iterations.
+    pub fn from_password(password: &[u8], salt: Vec<u8>, iterations: u32) -> ScramVerifier {
+        let salted_password = hi(&normalize(password), &salt, iterations);
+
+        let mut hmac = Hmac::new(Sha256::new(), &salted_password);
+        hmac.input(b"Client Key");
+        let mut client_key = [0u8; 32];
+        hmac.raw_result(&mut client_key[..]);
+
+        let mut hash = Sha256::new();
+        hash.input(&client_key[..]);
+        let mut stored_key = [0u8; 32];
+        hash.result(&mut stored_key[..]);
+
+        let mut hmac = Hmac::new(Sha256::new(), &salted_password);
+        hmac.input(b"Server Key");
+        let mut server_key = [0u8; 32];
+        hmac.raw_result(&mut server_key[..]);
+
+        ScramVerifier {
+            iterations,
+            salt,
+            stored_key,
+            server_key,
+        }
+    }
+
+    /// Computes the verifier for password with a random salt.
+    pub fn new(password: &[u8]) -> ScramVerifier {
+        let mut salt = vec![0u8; SALT_LENGTH];
+        rand::thread_rng().fill(&mut salt[..]);
+        ScramVerifier::from_password(password, salt, ITERATION_COUNT)
+    }
+
+    /// Parses a verifier in the PostgreSQL format, returns None if s isn't one.
+    pub fn parse(s: &str) -> Option<ScramVerifier> {
+        let rest = s.strip_prefix(SCRAM_SHA_256)?.strip_prefix('$')?;
+        let (iterations_salt, keys) = rest.split_once('$')?;
+        let (iterations, salt) = iterations_salt.split_once(':')?;
+        let (stored_key, server_key) = keys.split_once(':')?;
+        let key = |k: &str| -> Option<[u8; 32]> {
+            let bytes = base64::decode(k).ok()?;
+            bytes.as_slice().try_into().ok()
+        };
+        Some(ScramVerifier {
+            iterations: iterations.parse().ok().filter(|&i| i > 0)?,
+            salt: base64::decode(salt).ok()?,
+            stored_key: key(stored_key)?,
+            server_key: key(server_key)?,
+        })
+    }
+
+    /// Returns true if password matches this verifier.
+    pub fn verify_password(&self, password: &[u8]) -> bool {
+        let other = ScramVerifier::from_password(password, self.salt.clone(), self.iterations);
+        MacResult::new(&other.stored_key[..]) == MacResult::new(&self.stored_key[..])
+            && MacResult::new(&other.server_key[..]) == MacResult::new(&self.server_key[..])
+    }
+}
+
+impl std::fmt::Display for ScramVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}${}:{}${}:{}", SCRAM_SHA_256, self.iterations, base64::encode(&self.salt),
+               base64::encode(&self.stored_key), base64::encode(&self.server_key))
+    }
+}
+
+enum ServerState {
+    Update {
+        nonce: String,
+    },
+    Finish {
+        nonce: String,
+        cbind_input: Vec<u8>,
+        client_first_bare: String,
+        server_first: String,
+    },
+    Done,
+}
+
+/// A type which handles the server side of the SCRAM-SHA-256/SCRAM-SHA-256-PLUS authentication
+/// process, for authenticating clients with a known password or ScramVerifier.
+///
+/// The mechanisms returned by `mechanisms()` should be sent to the client in an `AuthenticationSASL`
+/// message. The client replies with a `SASLInitialResponse` message, the mechanism and data of which
+/// should be passed to the `update()` method, and the result sent to the client in an
+/// `AuthenticationSASLContinue` message.
+///
+/// The client replies with a `SASLResponse` message. Its contents should be passed to the `finish()` method.
+/// Authentication has only succeeded if this returns `Ok`, in which case the result should be sent
+/// to the client in an `AuthenticationSASLFinal` message.
+pub struct ScramSha256Server {
+    verifier: ScramVerifier,
+    /// the tls-server-end-point channel binding data, empty if channel binding is not possible
+    tls_server_end_point: Vec<u8>,
+    state: ServerState,
+}
+
+impl ScramSha256Server {
+    /// Constructs a new instance which will authenticate clients that know the password.
+    /// tls_server_end_point is the channel binding data (see tls_server_end_point()) for the TLS
+    /// connection with the client, or empty if not using TLS.
+    pub fn new(password: &[u8], tls_server_end_point: Vec<u8>) -> ScramSha256Server {
+        ScramSha256Server::with_verifier(ScramVerifier::new(password), tls_server_end_point)
+    }
+
+    /// Constructs a new instance which will authenticate clients that know the password of the verifier.
+    pub fn with_verifier(verifier: ScramVerifier, tls_server_end_point: Vec<u8>) -> ScramSha256Server {
+        ScramSha256Server::new_inner(verifier, tls_server_end_point, random_nonce())
+    }
+
+    fn new_inner(verifier: ScramVerifier, tls_server_end_point: Vec<u8>, nonce: String) -> ScramSha256Server {
+        ScramSha256Server {
+            verifier,
+            tls_server_end_point,
+            state: ServerState::Update {
+                nonce,
+            },
+        }
+    }
+
+    /// Returns the SASL mechanisms to offer the client, in order of preference.
+    pub fn mechanisms(&self) -> &'static [&'static str] {
+        if self.tls_server_end_point.is_empty() {
+            &[SCRAM_SHA_256]
+        } else {
+            &[SCRAM_SHA_256_PLUS, SCRAM_SHA_256]
+        }
+    }
+
+    /// Processes the client-first-message sent with the mechanism chosen by the client in the
+    /// `SASLInitialResponse` message, and returns the server-first-message.
+    pub fn update(&mut self, mechanism: &str, message: &[u8]) -> io::Result<String> {
+        let server_nonce = match mem::replace(&mut self.state, ServerState::Done) {
+            ServerState::Update {
+                nonce,
+            } => nonce,
+            _ => return Err(io::Error::new(io::ErrorKind::Other, "invalid SCRAM state")),
+        };
+
+        let message =
+            str::from_utf8(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let parsed = Parser::new(message).client_first_message()?;
+
+        // Only accept the channel binding the client asked for if it matches the mechanism.
+        // A client that supports channel binding but thinks the server doesn't ('y') when we offered
+        // SCRAM-SHA-256-PLUS could be the victim of a downgrade attack (RFC 5802 section 6.)
+        let mut cbind_data: &[u8] = &[];
+        match (mechanism, parsed.cbind_flag) {
+            (SCRAM_SHA_256, 'n') => (),
+            (SCRAM_SHA_256, 'y') if self.tls_server_end_point.is_empty() => (),
+            (SCRAM_SHA_256, 'y') => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "SCRAM channel binding negotiation error"));
+            },
+            (SCRAM_SHA_256_PLUS, 'p') if !self.tls_server_end_point.is_empty() => {
+                if parsed.cbind_name != TLS_SERVER_END_POINT {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("unsupported SCRAM channel binding type {}", parsed.cbind_name)));
+                }
+                cbind_data = &self.tls_server_end_point;
+            },
+            (SCRAM_SHA_256, _) | (SCRAM_SHA_256_PLUS, _) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "SCRAM channel binding negotiation error"));
+            },
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("unsupported SASL mechanism {}", mechanism)));
+            },
+        }
+
+        let mut cbind_input = Vec::with_capacity(parsed.gs2_header.len() + cbind_data.len());
+        cbind_input.extend(parsed.gs2_header.as_bytes());
+        cbind_input.extend(cbind_data);
+
+        let nonce = format!("{}{}", parsed.nonce, server_nonce);
+        let server_first = format!("r={},s={},i={}", nonce, base64::encode(&self.verifier.salt), self.verifier.iterations);
+
+        self.state = ServerState::Finish {
+            nonce,
+            cbind_input,
+            client_first_bare: parsed.bare.to_string(),
+            server_first: server_first.clone(),
+        };
+        Ok(server_first)
+    }
+
+    /// Verifies the client-final-message sent in the `SASLResponse` message and returns the
+    /// server-final-message. Authentication has only succeeded if this method returns `Ok`.
+    pub fn finish(&mut self, message: &[u8]) -> io::Result<String> {
+        let (nonce, cbind_input, client_first_bare, server_first) =
+            match mem::replace(&mut self.state, ServerState::Done) {
+                ServerState::Finish {
+                    nonce,
+                    cbind_input,
+                    client_first_bare,
+                    server_first,
+                } => (nonce, cbind_input, client_first_bare, server_first),
+                _ => return Err(io::Error::new(io::ErrorKind::Other, "invalid SCRAM state")),
+            };
+
+        let message =
+            str::from_utf8(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let parsed = Parser::new(message).client_final_message()?;
+
+        match base64::decode(parsed.cbind_input) {
+            Ok(input) if input == cbind_input => (),
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "SCRAM channel binding check failed")),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+        }
+
+        if parsed.nonce != nonce {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid nonce"));
+        }
+
+        let mut proof = match base64::decode(parsed.proof) {
+            Ok(proof) if proof.len() == 32 => proof,
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid SCRAM client proof")),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+        };
+
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, parsed.without_proof);
+
+        // ClientKey = ClientProof XOR HMAC(StoredKey, AuthMessage), verify that H(ClientKey) == StoredKey
+        let mut hmac = Hmac::new(Sha256::new(), &self.verifier.stored_key[..]);
+        hmac.input(auth_message.as_bytes());
+        let client_signature = hmac.result();
+        for (proof, signature) in (&mut proof[..]).iter_mut().zip(client_signature.code()) {
+            *proof ^= signature;
+        }
+
+        let mut hash = Sha256::new();
+        hash.input(&proof[..]);
+        let mut proof_key = [0u8; 32];
+        hash.result(&mut proof_key[..]);
+        if MacResult::new(&proof_key[..]) != MacResult::new(&self.verifier.stored_key[..]) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "SCRAM verification error"));
+        }
+
+        let mut hmac = Hmac::new(Sha256::new(), &self.verifier.server_key[..]);
+        hmac.input(auth_message.as_bytes());
+        Ok(format!("v={}", base64::encode(hmac.result().code())))
+    }
+}
+
 struct Parser<'a> {
     s: &'a str,
     it: iter::Peekable<str::CharIndices<'a>>,
@@ -426,6 +799,84 @@ impl<'a> Parser<'a> {
         })
     }
 
+    fn offset(&mut self) -> usize {
+        match self.it.peek() {
+            Some(&(i, _)) => i,
+            None => self.s.len(),
+        }
+    }
+
+    fn gs2_cbind_flag(&mut self) -> io::Result<(char, &'a str)> {
+        match self.it.next() {
+            Some((_, 'p')) => {
+                self.eat('=')?;
+                let name = self.take_while(|c| matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-'))?;
+                Ok(('p', name))
+            },
+            Some((_, c)) if c == 'n' || c == 'y' => Ok((c, "")),
+            Some((i, c)) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unexpected character at byte {}: expected channel binding flag but got `{}`", i, c),
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "unexpected EOF",
+            )),
+        }
+    }
+
+    fn client_first_message(&mut self) -> io::Result<ClientFirstMessage<'a>> {
+        let (cbind_flag, cbind_name) = self.gs2_cbind_flag()?;
+        self.eat(',')?;
+        // the authzid is ignored, like the username
+        self.take_while(|c| c != ',')?;
+        self.eat(',')?;
+        let gs2_header = &self.s[..self.offset()];
+        let bare = &self.s[self.offset()..];
+        self.eat('n')?;
+        self.eat('=')?;
+        self.take_while(|c| c != ',')?;
+        self.eat(',')?;
+        let nonce = self.nonce()?;
+        // any extensions are ignored
+
+        Ok(ClientFirstMessage {
+            cbind_flag,
+            cbind_name,
+            gs2_header,
+            bare,
+            nonce,
+        })
+    }
+
+    fn client_final_message(&mut self) -> io::Result<ClientFinalMessage<'a>> {
+        self.eat('c')?;
+        self.eat('=')?;
+        let cbind_input = self.base64()?;
+        self.eat(',')?;
+        let nonce = self.nonce()?;
+        // skip over any extensions to the proof, which must be last
+        let without_proof = loop {
+            let end = self.offset();
+            self.eat(',')?;
+            if let Some(&(_, 'p')) = self.it.peek() {
+                break &self.s[..end];
+            }
+            self.take_while(|c| c != ',')?;
+        };
+        self.eat('p')?;
+        self.eat('=')?;
+        let proof = self.base64()?;
+        self.eof()?;
+
+        Ok(ClientFinalMessage {
+            cbind_input,
+            nonce,
+            without_proof,
+            proof,
+        })
+    }
+
     fn value(&mut self) -> io::Result<&'a str> {
         self.take_while(|c| matches!(c, '\0' | '=' | ','))
     }
@@ -468,6 +919,21 @@ enum ServerFinalMessage<'a> {
     Verifier(&'a str),
 }
 
+struct ClientFirstMessage<'a> {
+    cbind_flag: char,
+    cbind_name: &'a str,
+    gs2_header: &'a str,
+    bare: &'a str,
+    nonce: &'a str,
+}
+
+struct ClientFinalMessage<'a> {
+    cbind_input: &'a str,
+    nonce: &'a str,
+    without_proof: &'a str,
+    proof: &'a str,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -508,4 +974,128 @@ mod test {
 
         scram.finish(server_final.as_bytes()).unwrap();
     }
+
+    // the same recorded exchange from the server side
+    #[test]
+    fn server_exchange() {
+        let client_first = "n,,n=,r=9IZ2O01zb9IgiIZ1WJ/zgpJB";
+        let server_first =
+            "r=9IZ2O01zb9IgiIZ1WJ/zgpJBjx/oIRLs02gGSHcw1KEty3eY,s=fs3IXBy7U7+IvVjZ,i\
+             =4096";
+        let client_final =
+            "c=biws,r=9IZ2O01zb9IgiIZ1WJ/zgpJBjx/oIRLs02gGSHcw1KEty3eY,p=AmNKosjJzS3\
+             1NTlQYNs5BTeQjdHdk7lOflDo5re2an8=";
+        let server_final = "v=U+ppxD5XUKtradnv8e2MkeupiA8FU87Sg8CXzXHDAzw=";
+
+        let verifier = ScramVerifier::from_password(b"foobar", base64::decode("fs3IXBy7U7+IvVjZ").unwrap(), 4096);
+        let mut scram = ScramSha256Server::new_inner(verifier, vec![], "jx/oIRLs02gGSHcw1KEty3eY".to_string());
+        assert_eq!(scram.mechanisms(), &[SCRAM_SHA_256]);
+        assert_eq!(scram.update(SCRAM_SHA_256, client_first.as_bytes()).unwrap(), server_first);
+        assert_eq!(scram.finish(client_final.as_bytes()).unwrap(), server_final);
+    }
+
+    #[test]
+    fn server_rejects_wrong_password() {
+        let mut server = ScramSha256Server::new(b"foobar", vec![]);
+        let mut client = ScramSha256::new(b"foobaz", ChannelBinding::unrequested());
+        let server_first = server.update(SCRAM_SHA_256, client.message()).unwrap();
+        client.update(server_first.as_bytes()).unwrap();
+        assert!(server.finish(client.message()).is_err());
+    }
+
+    /// Returns a DER encoded value with tag and contents.
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut der = vec![tag];
+        if contents.len() < 0x80 {
+            der.push(contents.len() as u8);
+        } else {
+            der.extend_from_slice(&[0x82, (contents.len() >> 8) as u8, contents.len() as u8]);
+        }
+        der.extend_from_slice(contents);
+        der
+    }
+
+    /// Returns a certificate with the signatureAlgorithm algorithm (the DER OID contents) and parameters.
+    fn cert(algorithm: &[u8], parameters: &[u8]) -> Vec<u8> {
+        let tbs_certificate = der(DER_SEQUENCE, &[0x5a; 200]);
+        let signature_algorithm = der(DER_SEQUENCE, &[der(DER_OID, algorithm), parameters.to_vec()].concat());
+        let signature = der(0x03, &[0x00, 1, 2, 3]);
+        der(DER_SEQUENCE, &[tbs_certificate, signature_algorithm, signature].concat())
+    }
+
+    #[test]
+    fn test_signature_hash() {
+        const NULL: &[u8] = &[0x05, 0x00];
+        let sha256_rsa = [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+        let sha1_rsa = [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x05];
+        let sha512_rsa = [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+        let sha384_ecdsa = [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+        let sha384 = [0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02];
+        let ed25519 = [0x2b, 0x65, 0x70];
+        assert_eq!(signature_hash(&cert(&sha256_rsa, NULL)), Some(SignatureHash::Sha256));
+        assert_eq!(signature_hash(&cert(&sha1_rsa, NULL)), Some(SignatureHash::Sha256));
+        assert_eq!(signature_hash(&cert(&sha512_rsa, NULL)), Some(SignatureHash::Sha512));
+        assert_eq!(signature_hash(&cert(&sha384_ecdsa, &[])), Some(SignatureHash::Sha384));
+        assert_eq!(signature_hash(&cert(&ed25519, &[])), None);
+
+        // RSASSA-PSS takes the hash function from its parameters, SHA-1 by default
+        let hash_algorithm = der(DER_CONTEXT_0, &der(DER_SEQUENCE, &[der(DER_OID, &sha384), NULL.to_vec()].concat()));
+        let pss_params = der(DER_SEQUENCE, &hash_algorithm);
+        assert_eq!(signature_hash(&cert(RSASSA_PSS_OID, &pss_params)), Some(SignatureHash::Sha384));
+        assert_eq!(signature_hash(&cert(RSASSA_PSS_OID, &der(DER_SEQUENCE, &[]))), Some(SignatureHash::Sha256));
+
+        // An OID that only appears in the tbsCertificate doesn't count
+        let mut tricky = cert(&sha256_rsa, NULL);
+        tricky[10..19].copy_from_slice(&sha512_rsa);
+        assert_eq!(signature_hash(&tricky), Some(SignatureHash::Sha256));
+
+        assert_eq!(tls_server_end_point(b"not really a certificate"), None);
+        assert_eq!(tls_server_end_point(&cert(&sha512_rsa, NULL)).map(|end_point| end_point.len()), Some(64));
+        assert_eq!(tls_server_end_point(&cert(&ed25519, &[])), None);
+    }
+
+    #[test]
+    fn server_channel_binding() {
+        let end_point = tls_server_end_point(&cert(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b], &[0x05, 0x00])).unwrap();
+        let mut server = ScramSha256Server::new(b"foobar", end_point.clone());
+        assert_eq!(server.mechanisms(), &[SCRAM_SHA_256_PLUS, SCRAM_SHA_256]);
+        let mut client = ScramSha256::new(b"foobar", ChannelBinding::tls_server_end_point(end_point));
+        let server_first = server.update(SCRAM_SHA_256_PLUS, client.message()).unwrap();
+        client.update(server_first.as_bytes()).unwrap();
+        let server_final = server.finish(client.message()).unwrap();
+        client.finish(server_final.as_bytes()).unwrap();
+
+        // a client that thinks the server doesn't support channel binding is rejected
+        let mut server = ScramSha256Server::new(b"foobar", vec![1, 2, 3]);
+        let client = ScramSha256::new(b"foobar", ChannelBinding::unrequested());
+        assert!(server.update(SCRAM_SHA_256, client.message()).is_err());
+
+        // channel binding data that doesn't match the server's is rejected
+        let mut server = ScramSha256Server::new(b"foobar", vec![1, 2, 3]);
+        let mut client = ScramSha256::new(b"foobar", ChannelBinding::tls_server_end_point(vec![1, 2, 4]));
+        let server_first = server.update(SCRAM_SHA_256_PLUS, client.message()).unwrap();
+        client.update(server_first.as_bytes()).unwrap();
+        assert!(server.finish(client.message()).is_err());
+    }
+
+    #[test]
+    fn verifier_parse() {
+        let stored = "SCRAM-SHA-256$4096:fs3IXBy7U7+IvVjZ$tmgbRn9qfDg3ip++wAxsFIk0Zl9PF0NDB5npDjVeECM=:bSU5hgS4vu9S/BceyyM0+b0RxdaRtdH5LRjmCPcHxhg=";
+        let verifier = ScramVerifier::parse(stored).unwrap();
+        assert!(verifier == ScramVerifier::from_password(b"foobar", base64::decode("fs3IXBy7U7+IvVjZ").unwrap(), 4096));
+        assert_eq!(verifier.to_string(), stored);
+        assert!(verifier.verify_password(b"foobar"));
+        assert!(!verifier.verify_password(b"foobaz"));
+
+        let mut server = ScramSha256Server::with_verifier(verifier, vec![]);
+        let mut client = ScramSha256::new(b"foobar", ChannelBinding::unrequested());
+        let server_first = server.update(SCRAM_SHA_256, client.message()).unwrap();
+        client.update(server_first.as_bytes()).unwrap();
+        let server_final = server.finish(client.message()).unwrap();
+        client.finish(server_final.as_bytes()).unwrap();
+
+        assert!(ScramVerifier::parse("md548bcb74dee348b76267719ca552ad77b").is_none());
+        assert!(ScramVerifier::parse("SCRAM-SHA-256$0:fs3IXBy7U7+IvVjZ$tmgbRn9qfDg3ip++wAxsFIk0Zl9PF0NDB5npDjVeECM=:bSU5hgS4vu9S/BceyyM0+b0RxdaRtdH5LRjmCPcHxhg=").is_none());
+        assert!(ScramVerifier::parse("SCRAM-SHA-256$4096:fs3IXBy7U7+IvVjZ$tmgbRn9qfDg3ip++wAxsFIk0Zl9PF0NDB5npDjVeECM=").is_none());
+    }
 }
@@ -5,6 +5,11 @@ use crate::riverdb::{Error, Result};
 use crate::riverdb::pg::protocol::{MessageReader, Message, Tag};
 
 
+/// Startup parameters with this prefix are protocol options rather than run-time parameters.
+pub const PROTOCOL_OPTION_PREFIX: &str = "_pq_.";
+/// The protocol option requesting compression of the connection, supported by some Postgres forks.
+pub const COMPRESSION_OPTION: &str = "_pq_.compression";
+
 /// A collection of server parameters as sent in the startup message on connect
 pub struct ServerParams {
     params: Vec<(String, String)>,
@@ -80,6 +85,14 @@ impl ServerParams {
         None
     }
 
+    /// Remove and return the _pq_ protocol options (e.g. _pq_.compression), see PROTOCOL_OPTION_PREFIX.
+    pub fn take_protocol_options(&mut self) -> ServerParams {
+        let (options, params) = std::mem::take(&mut self.params).into_iter()
+            .partition(|(key, _)| key.starts_with(PROTOCOL_OPTION_PREFIX));
+        self.params = params;
+        Self{params: options}
+    }
+
     /// Return the number of parameters
     pub fn len(&self) -> usize {
         self.params.len()
@@ -122,4 +135,24 @@ impl Debug for ServerParams {
         }
         f.write_char('}')
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_protocol_options() {
+        let mut params = ServerParams::new();
+        params.add("user".to_string(), "bob".to_string());
+        params.add(COMPRESSION_OPTION.to_string(), "zstd".to_string());
+        params.add("database".to_string(), "test".to_string());
+
+        let options = params.take_protocol_options();
+        assert_eq!(options.len(), 1);
+        assert_eq!(options.get(COMPRESSION_OPTION), Some("zstd"));
+        assert_eq!(params.len(), 2);
+        assert_eq!(params.get(COMPRESSION_OPTION), None);
+        assert_eq!(params.get("database"), Some("test"));
+    }
+}
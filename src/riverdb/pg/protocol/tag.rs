@@ -5,7 +5,12 @@ use crate::riverdb::{Error, Result};
 pub const SSL_ALLOWED: u8 = 'S' as u8;
 pub const SSL_NOT_ALLOWED: u8 = 'N' as u8;
 pub const SSL_REQUEST: i32 = 80877103;
+pub const CANCEL_REQUEST: i32 = 80877102;
+/// Requests GSSAPI encryption, sent before SSLRequest by clients with gssencmode=prefer. Always declined.
+pub const GSSENC_REQUEST: i32 = 80877104;
 pub const PROTOCOL_VERSION: i32 = 196608;
+/// The obsolete protocol version 2.0 used by clients prior to PostgreSQL 7.4. Not supported.
+pub const PROTOCOL_VERSION_2: i32 = 131072;
 
 /// Tag defines the Postgres protocol message type tag bytes
 /// It includes constants that define the known protocol tag bytes.
@@ -0,0 +1,322 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::sync::{RwLock, PoisonError};
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+
+use fnv::FnvHashSet;
+use serde::{Deserialize};
+use serde_yaml::Value;
+use tracing::{info, warn};
+
+use crate::riverdb::{Error, Result, Plugin};
+use crate::riverdb::config::{Settings, ConfigMap};
+use crate::riverdb::pg::{ClientConn, client_query, error_result};
+use crate::riverdb::pg::protocol::error_codes;
+use crate::riverdb::pg::sql::{QueryMessage};
+use crate::riverdb::server::Connection;
+
+
+/// The name of the QueryGuard plugin in the plugins section of the config file
+pub const QUERY_GUARD: &str = "QueryGuard";
+
+/// What QueryGuard does with a query that breaks a rule.
+#[derive(Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GuardAction {
+    /// Reject the query with an error, it's not sent to the database
+    Reject,
+    /// Log a warning and run the query
+    Log,
+}
+
+impl Default for GuardAction {
+    fn default() -> Self {
+        GuardAction::Reject
+    }
+}
+
+/// The checks QueryGuard makes of the queries of clients connected to a database as a user.
+#[derive(Deserialize, Default, Clone, Debug)]
+pub struct QueryGuardRule {
+    /// database the client is connected to, empty matches any database. Default empty.
+    #[serde(default)]
+    pub database: String,
+    /// user the client is connected as, empty matches any user. Default empty.
+    #[serde(default)]
+    pub user: String,
+    /// action is reject or log. Default reject.
+    #[serde(default)]
+    pub action: GuardAction,
+    /// multi_statement flags messages with several statements separated by ; Default false.
+    #[serde(default)]
+    pub multi_statement: bool,
+    /// comment_after_literal flags statements with a comment (other than query tags) after a literal,
+    /// the usual shape of SQL injection through a string parameter. Default false.
+    #[serde(default)]
+    pub comment_after_literal: bool,
+    /// allowlist flags statements whose normalized form isn't in the allowlist, and queries too large
+    /// to normalize completely (see max_normalize_bytes.) Default false.
+    #[serde(default)]
+    pub allowlist: bool,
+}
+
+impl QueryGuardRule {
+    /// Returns true if this rule applies to clients connected to database as user.
+    pub fn matches(&self, database: &str, user: &str) -> bool {
+        (self.database.is_empty() || self.database == database) && (self.user.is_empty() || self.user == user)
+    }
+}
+
+/// Settings for the QueryGuard plugin, from its entry in the plugins section of the config file.
+#[derive(Deserialize, Default, Clone, Debug)]
+pub struct QueryGuardSettings {
+    /// rules are the checks for each database and user. The first matching rule applies,
+    /// if none match the queries aren't checked. Default none.
+    #[serde(default)]
+    pub rules: Vec<QueryGuardRule>,
+    /// learn adds the normalized form of statements to the allowlist instead of flagging them,
+    /// to build the allowlist by running the application for a while. Default false.
+    #[serde(default)]
+    pub learn: bool,
+    /// max_allowlist_statements is the most statements learn adds to the allowlist, so an application
+    /// that generates many distinct statements can't grow it without bound. 0 is no limit. Default 10000.
+    #[serde(default = "default_max_allowlist_statements")]
+    pub max_allowlist_statements: u32,
+    /// allowlist_file is the path of the allowlist, one normalized statement per line. It's loaded on startup,
+    /// and written on shutdown if learn added to it. Default empty (the allowlist only lasts until shutdown.)
+    #[serde(default)]
+    pub allowlist_file: String,
+    /// order of the plugin, set by Settings::load
+    #[serde(default)]
+    pub order: i32,
+}
+
+const fn default_max_allowlist_statements() -> u32 { 10000 }
+
+impl QueryGuardSettings {
+    /// Parse the settings from the plugin's ConfigMap.
+    pub fn from_config(config: &ConfigMap) -> Result<Self> {
+        let map = config.iter().map(|(k, v)| (Value::String(k.clone()), v.clone())).collect();
+        serde_yaml::from_value(Value::Mapping(map))
+            .map_err(|e| Error::new(format!("invalid {} plugin settings: {}", QUERY_GUARD, e)))
+    }
+}
+
+/// QueryGuard is a built-in plugin that checks the queries of clients against the rules for their
+/// database and user, and rejects (or logs) suspicious queries: multi-statement messages, comments
+/// after literals, or statements not in a learned allowlist of normalized queries.
+/// It's enabled by adding a QueryGuard entry to the plugins section of the config file.
+pub struct QueryGuard {
+    settings: QueryGuardSettings,
+    /// the normalized form of the allowed statements
+    allowlist: RwLock<FnvHashSet<String>>,
+    /// true if learn added statements to the allowlist since it was loaded
+    learned: AtomicBool,
+    /// true once learn found the allowlist full, so that's only logged once
+    allowlist_full: AtomicBool,
+}
+
+impl QueryGuard {
+    pub fn new(settings: QueryGuardSettings) -> Self {
+        Self{
+            settings,
+            allowlist: RwLock::new(FnvHashSet::default()),
+            learned: AtomicBool::new(false),
+            allowlist_full: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the rule for clients connected to database as user, if any.
+    pub fn rule_for(&self, database: &str, user: &str) -> Option<&QueryGuardRule> {
+        self.settings.rules.iter().find(|rule| rule.matches(database, user))
+    }
+
+    /// Returns the reason query breaks rule, or None if it's allowed. Every statement is checked, including
+    /// those of each Parse message of the extended protocol. In learn mode this adds the statements not in the
+    /// allowlist to it, until it has max_allowlist_statements.
+    pub fn check(&self, rule: &QueryGuardRule, query: &QueryMessage) -> Option<&'static str> {
+        if rule.multi_statement && query.is_multi_query() {
+            return Some("multiple statements in one query");
+        }
+        if rule.comment_after_literal && query.statements().any(|s| s.comment_after_literal) {
+            return Some("comment after a literal");
+        }
+        if rule.allowlist {
+            if query.is_truncated() && !self.settings.learn {
+                return Some("query too large to check against the allowlist");
+            }
+            // Executing an existing prepared statement has no SQL, it was checked when it was prepared
+            for s in query.statements().filter(|s| !s.normalized().is_empty()) {
+                if self.allowlist.read().unwrap_or_else(PoisonError::into_inner).contains(s.normalized()) {
+                    continue;
+                }
+                if !self.settings.learn {
+                    return Some("query not in the allowlist");
+                }
+                if !s.is_truncated() {
+                    self.learn(s.normalized());
+                }
+            }
+        }
+        None
+    }
+
+    /// Adds the normalized statement to the allowlist in learn mode, unless it's full.
+    fn learn(&self, normalized: &str) {
+        let mut allowlist = self.allowlist.write().unwrap_or_else(PoisonError::into_inner);
+        let max = self.settings.max_allowlist_statements as usize;
+        if max != 0 && allowlist.len() >= max {
+            if !self.allowlist_full.swap(true, Relaxed) {
+                warn!(max_allowlist_statements=max, "{} allowlist is full, not learning more statements", QUERY_GUARD);
+            }
+            return;
+        }
+        allowlist.insert(normalized.to_string());
+        self.learned.store(true, Relaxed);
+    }
+
+    /// Adds the normalized statement to the allowlist.
+    pub fn allow(&self, normalized: &str) {
+        self.allowlist.write().unwrap_or_else(PoisonError::into_inner).insert(normalized.to_string());
+    }
+
+    /// Returns true if there's a rule that may apply to the client, used to skip the plugin for other clients.
+    fn wants_client(&self, client: &ClientConn) -> bool {
+        let params = client.connection_params();
+        self.rule_for(params.get("database").unwrap_or(""), params.get("user").unwrap_or("")).is_some()
+    }
+
+    pub async fn client_query(&self, ev: &mut client_query::Event, client: &ClientConn, query: QueryMessage) -> Result<()> {
+        let params = client.connection_params();
+        let (database, user) = (params.get("database").unwrap_or(""), params.get("user").unwrap_or(""));
+        if let Some(rule) = self.rule_for(database, user) {
+            if let Some(reason) = self.check(rule, &query) {
+                warn!(client=client.id(), database, user, query=query.query().normalized(), action=?rule.action, "{} flagged query: {}", QUERY_GUARD, reason);
+                if rule.action == GuardAction::Reject {
+                    let msg = format!("query rejected by {}: {}", QUERY_GUARD, reason);
+                    return client.send(error_result(error_codes::INSUFFICIENT_PRIVILEGE, &msg, client.state())).await.map(|_| ());
+                }
+            }
+        }
+        ev.next(client, query).await
+    }
+}
+
+impl Plugin for QueryGuard {
+    fn order(&self) -> i32 {
+        self.settings.order
+    }
+
+    fn configure(&self, _settings: &'static Settings) -> Result<()> {
+        let path = &self.settings.allowlist_file;
+        if path.is_empty() {
+            return Ok(());
+        }
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            // It's created on shutdown when learning
+            Err(e) if e.kind() == ErrorKind::NotFound && self.settings.learn => String::new(),
+            Err(e) => return Err(Error::new(format!("could not read {} allowlist_file {}: {}", QUERY_GUARD, path, e))),
+        };
+        let mut allowlist = self.allowlist.write().unwrap_or_else(PoisonError::into_inner);
+        allowlist.extend(contents.lines().map(str::trim).filter(|line| !line.is_empty()).map(str::to_string));
+        info!(path=%path, statements=allowlist.len(), "loaded {} allowlist", QUERY_GUARD);
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        let path = &self.settings.allowlist_file;
+        if path.is_empty() || !self.learned.load(Relaxed) {
+            return;
+        }
+        let mut statements: Vec<String> = self.allowlist.read().unwrap_or_else(PoisonError::into_inner).iter()
+            .filter(|s| !s.contains('\n'))
+            .cloned()
+            .collect();
+        statements.sort_unstable();
+        statements.push(String::new()); // end with a newline
+        if let Err(e) = fs::write(path, statements.join("\n")) {
+            warn!(path=%path, %e, "could not write {} allowlist", QUERY_GUARD);
+        }
+    }
+}
+
+/// Registers the QueryGuard plugin if it's in the plugins section of settings.
+/// Call before configuring the plugins, see init_plugins.
+pub fn register_query_guard(settings: &'static Settings) -> Result<()> {
+    let config = match settings.get_plugin_config(QUERY_GUARD) {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+    let guard: &'static QueryGuard = Box::leak(Box::new(QueryGuard::new(QueryGuardSettings::from_config(config)?)));
+    crate::event_listener!(guard, QueryGuard:client_query<'a>(query: QueryMessage) -> Result<()>, if wants_client);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riverdb::pg::protocol::{MessageBuilder, Tag};
+
+    fn query(sql: &str) -> QueryMessage {
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str(sql);
+        QueryMessage::new(mb.finish()).unwrap()
+    }
+
+    fn guard(yaml: &str) -> QueryGuard {
+        let config: ConfigMap = serde_yaml::from_str(yaml).unwrap();
+        QueryGuard::new(QueryGuardSettings::from_config(&config).unwrap())
+    }
+
+    #[test]
+    fn test_rules() {
+        let guard = guard(r#"
+name: QueryGuard
+rules:
+  - user: admin
+    action: log
+  - database: app
+    multi_statement: true
+    comment_after_literal: true
+"#);
+        assert_eq!(guard.rule_for("app", "admin").unwrap().action, GuardAction::Log);
+        assert!(guard.rule_for("other", "web").is_none());
+
+        let rule = guard.rule_for("app", "web").unwrap();
+        assert_eq!(rule.action, GuardAction::Reject);
+        assert_eq!(guard.check(rule, &query("select * from users where name = 'x'")), None);
+        assert_eq!(guard.check(rule, &query("select * from users where name = 'x'; drop table users")), Some("multiple statements in one query"));
+        assert_eq!(guard.check(rule, &query("select * from users where name = 'x' -- ' and password = 'y'")), Some("comment after a literal"));
+        assert_eq!(guard.check(rule, &query("select * from users where name = 'x' /* ' and password = 'y' */")), Some("comment after a literal"));
+        assert_eq!(guard.check(rule, &query("-- users\nselect * from users where name = 'x'")), None);
+        assert_eq!(guard.check(rule, &query("select * from users where name = 'x' /* request_id='abc' */")), None);
+        assert_eq!(guard.check(rule, &query("select * from users where name = 'x' /*controller='index',framework='django'*/")), None);
+        // A tag doesn't exempt a comment with other text
+        assert_eq!(guard.check(rule, &query("select * from users where name = 'x' /* ' and a=b or password = 'y' */")), Some("comment after a literal"));
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let guard = guard("rules:\n  - allowlist: true\n");
+        let rule = guard.rule_for("app", "web").unwrap().clone();
+        assert_eq!(guard.check(&rule, &query("select * from users where id = 1")), Some("query not in the allowlist"));
+        guard.allow("SELECT * FROM USERS WHERE ID = $1");
+        assert_eq!(guard.check(&rule, &query("select * from users where id = 2")), None);
+        assert_eq!(guard.check(&rule, &query("select * from users where id = 2; delete from users")), Some("query not in the allowlist"));
+
+        let learning = self::guard("learn: true\nrules:\n  - allowlist: true\n");
+        let rule = learning.rule_for("app", "web").unwrap().clone();
+        assert_eq!(learning.check(&rule, &query("select * from users where id = 1; delete from users")), None);
+        assert!(learning.learned.load(Relaxed));
+        let allowlist = learning.allowlist.read().unwrap();
+        assert!(allowlist.contains("SELECT * FROM USERS WHERE ID = $1"));
+        assert!(allowlist.contains("DELETE FROM USERS"));
+        drop(allowlist);
+
+        let full = self::guard("learn: true\nmax_allowlist_statements: 1\nrules:\n  - allowlist: true\n");
+        let rule = full.rule_for("app", "web").unwrap().clone();
+        assert_eq!(full.check(&rule, &query("select 1; select 2, 3")), None);
+        assert_eq!(full.allowlist.read().unwrap().len(), 1);
+    }
+}
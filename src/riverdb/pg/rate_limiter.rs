@@ -0,0 +1,198 @@
+use std::fmt::{Display, Formatter};
+use std::sync::{Mutex, PoisonError};
+
+use fnv::FnvHashMap;
+use tokio::time::{Duration, Instant};
+
+use crate::riverdb::config::RateLimitSettings;
+
+
+/// Forget idle users and databases once more than this many are tracked.
+const MAX_IDLE_ENTRIES: usize = 1024;
+
+/// The queries of one user or database: a token bucket for the rate, and the number running.
+struct Usage {
+    /// queries that can be started now without exceeding the rate, up to one second's worth
+    tokens: f64,
+    refilled: Instant,
+    running: u32,
+}
+
+impl Usage {
+    fn new(now: Instant) -> Self {
+        Self{tokens: f64::MAX, refilled: now, running: 0}
+    }
+
+    /// Add the tokens accrued since the last refill at per_second, up to per_second.
+    fn refill(&mut self, per_second: u32, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second as f64).min(per_second as f64);
+        self.refilled = now;
+    }
+
+    /// Returns the limit exceeded by starting n more queries, if any.
+    fn check(&self, n: u32, per_second: u32, concurrent: u32) -> Option<Limit> {
+        if concurrent != 0 && self.running + n > concurrent {
+            return Some(Limit::Concurrent(concurrent));
+        }
+        if per_second != 0 && self.tokens < n as f64 {
+            let retry_after = Duration::from_secs_f64((n as f64 - self.tokens) / per_second as f64);
+            return Some(Limit::PerSecond(per_second, retry_after));
+        }
+        None
+    }
+
+    fn is_idle(&self, per_second: u32) -> bool {
+        self.running == 0 && self.tokens >= per_second as f64
+    }
+}
+
+/// A limit in RateLimitSettings.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Limit {
+    /// queries per second, and how long until the query would be allowed
+    PerSecond(u32, Duration),
+    /// concurrent queries
+    Concurrent(u32),
+}
+
+/// The error returned by RateLimiter::admit when a query would exceed a limit.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RateLimitExceeded {
+    /// "user" or "database"
+    pub scope: &'static str,
+    /// the name of the user or database
+    pub name: String,
+    pub limit: Limit,
+}
+
+impl RateLimitExceeded {
+    /// Returns a hint for when the client can retry the query.
+    pub fn hint(&self) -> String {
+        match self.limit {
+            Limit::PerSecond(_, retry_after) => format!("retry after {}ms", retry_after.as_millis().max(1)),
+            Limit::Concurrent(_) => format!("retry after one of the running queries of the {} completes", self.scope),
+        }
+    }
+}
+
+impl Display for RateLimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.limit {
+            Limit::PerSecond(n, _) => write!(f, "{} {} exceeded the rate limit of {} queries per second", self.scope, self.name, n),
+            Limit::Concurrent(n) => write!(f, "{} {} exceeded the limit of {} concurrent queries", self.scope, self.name, n),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Usages {
+    users: FnvHashMap<String, Usage>,
+    databases: FnvHashMap<String, Usage>,
+}
+
+/// RateLimiter enforces the rate_limit settings of a cluster on the queries of its clients,
+/// tracking the rate and running queries of each user and database.
+pub struct RateLimiter {
+    usages: Mutex<Usages>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self{usages: Mutex::new(Usages::default())}
+    }
+
+    /// Start n queries from user on database, if that doesn't exceed the limits in settings.
+    /// Each started query must be ended by calling complete.
+    pub fn admit(&self, settings: &RateLimitSettings, user: &str, database: &str, n: u32) -> Result<(), RateLimitExceeded> {
+        self.admit_at(settings, user, database, n, Instant::now())
+    }
+
+    fn admit_at(&self, settings: &RateLimitSettings, user: &str, database: &str, n: u32, now: Instant) -> Result<(), RateLimitExceeded> {
+        let mut guard = self.usages.lock().unwrap_or_else(PoisonError::into_inner);
+        let usages = &mut *guard;
+        if usages.users.len() + usages.databases.len() > MAX_IDLE_ENTRIES {
+            usages.users.retain(|_, usage| { usage.refill(settings.user_queries_per_second, now); !usage.is_idle(settings.user_queries_per_second) });
+            usages.databases.retain(|_, usage| { usage.refill(settings.database_queries_per_second, now); !usage.is_idle(settings.database_queries_per_second) });
+        }
+
+        let user_usage = usages.users.entry(user.to_string()).or_insert_with(|| Usage::new(now));
+        user_usage.refill(settings.user_queries_per_second, now);
+        if let Some(limit) = user_usage.check(n, settings.user_queries_per_second, settings.user_concurrent_queries) {
+            return Err(RateLimitExceeded{scope: "user", name: user.to_string(), limit});
+        }
+        let database_usage = usages.databases.entry(database.to_string()).or_insert_with(|| Usage::new(now));
+        database_usage.refill(settings.database_queries_per_second, now);
+        if let Some(limit) = database_usage.check(n, settings.database_queries_per_second, settings.database_concurrent_queries) {
+            return Err(RateLimitExceeded{scope: "database", name: database.to_string(), limit});
+        }
+
+        for usage in [usages.users.get_mut(user).unwrap(), usages.databases.get_mut(database).unwrap()] {
+            usage.tokens -= n as f64;
+            usage.running += n;
+        }
+        Ok(())
+    }
+
+    /// End n queries started by admit.
+    pub fn complete(&self, user: &str, database: &str, n: u32) {
+        let mut usages = self.usages.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(usage) = usages.users.get_mut(user) {
+            usage.running = usage.running.saturating_sub(n);
+        }
+        if let Some(usage) = usages.databases.get_mut(database) {
+            usage.running = usage.running.saturating_sub(n);
+        }
+    }
+
+    /// Returns the number of running queries of user and database.
+    pub fn running(&self, user: &str, database: &str) -> (u32, u32) {
+        let usages = self.usages.lock().unwrap_or_else(PoisonError::into_inner);
+        (usages.users.get(user).map_or(0, |usage| usage.running), usages.databases.get(database).map_or(0, |usage| usage.running))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queries_per_second() {
+        let settings = RateLimitSettings{user_queries_per_second: 10, database_queries_per_second: 15, ..Default::default()};
+        let limiter = RateLimiter::new();
+        let start = Instant::now();
+        assert!(limiter.admit_at(&settings, "alice", "db", 10, start).is_ok());
+        let e = limiter.admit_at(&settings, "alice", "db", 1, start).unwrap_err();
+        assert_eq!(e.scope, "user");
+        assert_eq!(e.limit, Limit::PerSecond(10, Duration::from_millis(100)));
+        assert_eq!(e.hint(), "retry after 100ms");
+
+        // Another user of the same database hits the database limit
+        assert!(limiter.admit_at(&settings, "bob", "db", 5, start).is_ok());
+        let e = limiter.admit_at(&settings, "bob", "db", 1, start).unwrap_err();
+        assert_eq!((e.scope, e.name.as_str()), ("database", "db"));
+
+        // Tokens are refilled over time
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.admit_at(&settings, "alice", "db", 5, later).is_ok());
+        assert!(limiter.admit_at(&settings, "alice", "db", 1, later).is_err());
+    }
+
+    #[test]
+    fn test_concurrent_queries() {
+        let settings = RateLimitSettings{user_concurrent_queries: 2, ..Default::default()};
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        assert!(limiter.admit_at(&settings, "alice", "db", 2, now).is_ok());
+        let e = limiter.admit_at(&settings, "alice", "db", 1, now).unwrap_err();
+        assert_eq!(e.limit, Limit::Concurrent(2));
+        assert_eq!(e.to_string(), "user alice exceeded the limit of 2 concurrent queries");
+        assert!(limiter.admit_at(&settings, "bob", "db", 2, now).is_ok());
+        assert_eq!(limiter.running("alice", "db"), (2, 4));
+
+        limiter.complete("alice", "db", 1);
+        assert!(limiter.admit_at(&settings, "alice", "db", 1, now).is_ok());
+        limiter.complete("alice", "db", 5);
+        assert_eq!(limiter.running("alice", "db"), (0, 0));
+    }
+}
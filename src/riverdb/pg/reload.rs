@@ -0,0 +1,273 @@
+use std::net::SocketAddr;
+use std::sync::{Mutex, PoisonError};
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::Ordering::{AcqRel, Acquire};
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::Duration;
+use tracing::{info, warn, error};
+
+use crate::riverdb::Result;
+use crate::riverdb::config::{self, reload_config};
+use crate::riverdb::pg::{ConnectionPool, PostgresCluster, PostgresReplicationGroup, PostgresService, ServerTls};
+
+
+/// The changes applied by Reloader::reload.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ReloadSummary {
+    /// The number of existing pools with updated limits.
+    pub pools_updated: usize,
+    /// The number of new replica pools created.
+    pub replicas_added: usize,
+    /// The number of replica pools that were removed and are draining.
+    pub replicas_removed: usize,
+    /// The number of clusters with a reloaded client_tls certificate and settings.
+    pub tls_reloaded: usize,
+    /// Changes in the new config that can't be applied without restarting the server.
+    pub restart_required: Vec<String>,
+}
+
+/// Reloader re-reads the config file while the server is running and applies the changes that
+/// can be made without a restart: connection limits and timeouts, adding or removing replicas,
+/// and the client_tls certificate and settings (for new connections.)
+/// Other changes (e.g. listen ports, masters, or enabling client_tls) are logged and otherwise ignored.
+pub struct Reloader {
+    services: Mutex<Vec<&'static PostgresService>>,
+    reloading: Mutex<()>,
+}
+
+impl Reloader {
+    fn new() -> Self {
+        Self{
+            services: Mutex::new(Vec::new()),
+            reloading: Mutex::new(()),
+        }
+    }
+
+    /// Return the global Reloader instance.
+    pub fn singleton() -> &'static Self {
+        static SINGLETON_RELOADER: AtomicPtr<Reloader> = AtomicPtr::new(std::ptr::null_mut());
+        unsafe {
+            let mut p = SINGLETON_RELOADER.load(Acquire);
+            if p.is_null() {
+                let mut reloader = Box::new(Reloader::new());
+                p = reloader.as_mut() as _;
+                match SINGLETON_RELOADER.compare_exchange(std::ptr::null_mut(), p, AcqRel, Acquire) {
+                    Ok(_) => {
+                        Box::leak(reloader);
+                    },
+                    Err(current) => {
+                        p = current;
+                    },
+                }
+            }
+            &*p
+        }
+    }
+
+    /// Set the running services, so their client connection limits can be updated on reload.
+    pub fn set_services(&self, services: Vec<&'static PostgresService>) {
+        *self.services.lock().unwrap_or_else(PoisonError::into_inner) = services;
+    }
+
+    /// Re-read the config file and apply the changes to the running clusters.
+    /// If the config file can't be loaded, the error is returned and nothing is changed.
+    /// Must be called from within the tokio runtime.
+    pub fn reload(&self) -> Result<ReloadSummary> {
+        let _guard = self.reloading.lock().unwrap_or_else(PoisonError::into_inner);
+        let settings = reload_config()?;
+
+        let mut summary = ReloadSummary::default();
+        let cluster_configs: Vec<_> = std::iter::once(&settings.postgres).chain(settings.clusters.iter()).collect();
+        for cluster in PostgresCluster::all() {
+            match cluster_configs.iter().find(|c| c.port == cluster.config.port) {
+                Some(cluster_config) => self.reload_cluster(cluster, cluster_config, &mut summary),
+                None => summary.restart_required.push(format!("cluster on port {} was removed", cluster.config.port)),
+            }
+        }
+        for cluster_config in cluster_configs.iter() {
+            if !PostgresCluster::all().iter().any(|c| c.config.port == cluster_config.port) {
+                summary.restart_required.push(format!("cluster on port {} was added", cluster_config.port));
+            }
+        }
+
+        for change in summary.restart_required.iter() {
+            warn!(%change, "config change requires a restart to take effect");
+        }
+        info!(?summary, "reloaded config");
+        Ok(summary)
+    }
+
+    fn reload_cluster(&self, cluster: &'static PostgresCluster, config: &'static config::PostgresCluster, summary: &mut ReloadSummary) {
+        for service in self.services.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+            if std::ptr::eq(service.cluster(), cluster) {
+                let connections = service.connections();
+                let applied = connections.set_max_connections(config.max_connections);
+                if applied != config.max_connections {
+                    summary.restart_required.push(format!("max_connections of cluster on port {} can't exceed {} without a restart", config.port, applied));
+                }
+                connections.set_timeout_seconds(config.idle_timeout_seconds);
+            }
+        }
+
+        cluster.set_query_timeout_ms(config.query_timeout_ms);
+        if cluster.config.listen != config.listen || cluster.config.unix_socket_dir != config.unix_socket_dir {
+            summary.restart_required.push(format!("listen addresses for cluster on port {} changed", config.port));
+        }
+        // The reloaded config has loaded tls_server_certificate and tls_server_key again, use them for new connections
+        match (cluster.server_tls(), ServerTls::from_config(config)) {
+            (Some(_), Some(server_tls)) => {
+                cluster.set_server_tls(server_tls);
+                summary.tls_reloaded += 1;
+            },
+            (None, None) => (),
+            _ => summary.restart_required.push(format!("client_tls for cluster on port {} changed", config.port)),
+        }
+        if cluster.nodes.len() != config.servers.len() {
+            summary.restart_required.push(format!("number of servers for cluster on port {} changed", config.port));
+        }
+        for (node, node_config) in cluster.nodes.iter().zip(config.servers.iter()) {
+            if node.config.address != node_config.address {
+                summary.restart_required.push(format!("master of {:?} changed", node));
+                continue;
+            }
+            if node.config.gss_keytab != node_config.gss_keytab {
+                summary.restart_required.push(format!("gss_keytab of {:?} changed", node));
+            }
+            if let Some(master) = node.master() {
+                set_limits(master, node_config.max_connections, node_config.max_concurrent_transactions, summary);
+                reload_user_pools(master, node_config, summary);
+            }
+            reload_replicas(node, node_config, summary);
+        }
+    }
+}
+
+/// Update the limits of the replicas of node that are still in config, create pools for the
+/// added replicas, and drain the pools of the replicas that were removed.
+fn reload_replicas(node: &PostgresReplicationGroup, config: &'static config::Postgres, summary: &mut ReloadSummary) {
+    let replicas = node.replicas();
+    let old: Vec<_> = replicas.iter().map(|db| db.config.address).collect();
+    let new: Vec<_> = config.replicas.iter().map(|c| c.address).collect();
+    let diff = diff_addresses(&old, &new);
+    let mut updated = Vec::with_capacity(new.len());
+    for &(i, j) in diff.kept.iter() {
+        set_limits(replicas[i], config.replicas[j].max_connections, config.replicas[j].max_concurrent_transactions, summary);
+        reload_user_pools(replicas[i], &config.replicas[j], summary);
+        updated.push(replicas[i]);
+    }
+    for &j in diff.added.iter() {
+        let pool: &'static ConnectionPool = Box::leak(Box::new(ConnectionPool::new(&config.replicas[j])));
+        let warmup_seconds = config.cluster.map_or(0, |c| c.replica_warmup_seconds);
+        if warmup_seconds != 0 {
+            pool.start_warmup(Duration::from_secs(warmup_seconds as u64));
+        }
+        for pool in std::iter::once(pool).chain(pool.user_pools().iter().cloned()) {
+            if pool.needs_maintainer() {
+                tokio::spawn(pool.run_maintainer());
+            }
+        }
+        info!(?pool, ?node, warmup_seconds, "adding replica");
+        summary.replicas_added += 1;
+        updated.push(pool);
+    }
+    if diff.added.is_empty() && diff.removed.is_empty() {
+        return;
+    }
+    node.set_replicas(updated);
+    for &i in diff.removed.iter() {
+        info!(pool=?replicas[i], ?node, "removing replica");
+        replicas[i].drain();
+        summary.replicas_removed += 1;
+    }
+}
+
+/// Update the limits of the user pools of pool. Adding or removing user pools requires a restart.
+fn reload_user_pools(pool: &ConnectionPool, config: &'static config::Postgres, summary: &mut ReloadSummary) {
+    let user_pools = pool.user_pools();
+    let unchanged = user_pools.len() == config.user_pools.len()
+        && config.user_pools.iter().all(|c| pool.user_pool(&c.user, &c.database).is_some());
+    if !unchanged {
+        summary.restart_required.push(format!("user_pools of {:?} changed", pool));
+        return;
+    }
+    for user_pool in user_pools {
+        if let Some(c) = config.user_pools.iter().find(|c| c.user == user_pool.config.user && c.database == user_pool.config.database) {
+            set_limits(user_pool, c.max_connections, c.max_connections, summary);
+        }
+    }
+}
+
+/// Update the limits of pool, recording in summary if max_connections can't be increased that far without a restart.
+fn set_limits(pool: &ConnectionPool, max_connections: u32, max_transactions: u32, summary: &mut ReloadSummary) {
+    let applied = pool.set_limits(max_connections, max_transactions);
+    if applied != max_connections {
+        summary.restart_required.push(format!("max_connections of {:?} can't exceed {} without a restart", pool, applied));
+    }
+    summary.pools_updated += 1;
+}
+
+/// The result of diff_addresses.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct AddressDiff {
+    /// Pairs of (old index, new index) for the addresses in both.
+    kept: Vec<(usize, usize)>,
+    /// Indexes into new of the addresses only in new.
+    added: Vec<usize>,
+    /// Indexes into old of the addresses only in old.
+    removed: Vec<usize>,
+}
+
+/// Compare the server addresses in old with those in new.
+fn diff_addresses(old: &[Option<SocketAddr>], new: &[Option<SocketAddr>]) -> AddressDiff {
+    let mut diff = AddressDiff::default();
+    for (i, addr) in old.iter().enumerate() {
+        match new.iter().position(|a| a == addr) {
+            Some(j) => diff.kept.push((i, j)),
+            None => diff.removed.push(i),
+        }
+    }
+    for (j, addr) in new.iter().enumerate() {
+        if !old.contains(addr) {
+            diff.added.push(j);
+        }
+    }
+    diff
+}
+
+/// Reload the config with Reloader::reload each time the process receives SIGHUP.
+#[cfg(unix)]
+pub async fn reload_on_signal() {
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!(?e, "could not install SIGHUP handler, config reloading is disabled");
+            return;
+        },
+    };
+    while sighup.recv().await.is_some() {
+        if let Err(e) = Reloader::singleton().reload() {
+            error!(?e, "could not reload config, keeping the current config");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(ports: &[u16]) -> Vec<Option<SocketAddr>> {
+        ports.iter().map(|port| Some(SocketAddr::from(([127, 0, 0, 1], *port)))).collect()
+    }
+
+    #[test]
+    fn test_diff_addresses() {
+        assert_eq!(diff_addresses(&[], &[]), AddressDiff::default());
+
+        let diff = diff_addresses(&addrs(&[5432, 5433, 5434]), &addrs(&[5434, 5435, 5432]));
+        assert_eq!(diff.kept, vec![(0, 2), (2, 0)]);
+        assert_eq!(diff.added, vec![1]);
+        assert_eq!(diff.removed, vec![1]);
+    }
+}
@@ -0,0 +1,506 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64};
+use std::sync::atomic::Ordering::{Acquire, AcqRel, Relaxed};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use fnv::FnvHashMap;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, info, warn};
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::config::{conf, CaptureSettings};
+use crate::riverdb::pg::{BackendConn, ConnectionPool, TransactionType};
+use crate::riverdb::pg::protocol::Messages;
+use crate::riverdb::pg::sql::{QueryMessage, QueryType};
+
+
+/// The magic bytes at the start of a capture file, the trailing 1 is the version of the format.
+/// They're followed by a record per query message: the time it was received in microseconds since
+/// the unix epoch (u64), the id of the client session (u32), and the length of the messages (u32),
+/// all big endian, followed by the messages (a Query message, or extended query protocol messages
+/// ending in Sync or Flush) as sent by the client.
+pub const CAPTURE_MAGIC: &[u8; 8] = b"RVDBCAP1";
+/// The length of the header of each record: timestamp, session id, and length of the messages
+const RECORD_HEADER_LEN: usize = 16;
+/// The application_name of the backend connections used to replay queries
+const REPLAY_APPLICATION_NAME: &str = "riverdb-replay";
+
+/// A query message read from a capture file.
+pub struct CapturedQuery {
+    /// the time the query was received, in microseconds since the unix epoch
+    pub timestamp_us: u64,
+    /// the id of the client session that sent the query
+    pub session: u32,
+    /// the query messages, as sent by the client
+    pub msgs: Messages,
+}
+
+impl CapturedQuery {
+    /// Returns the time the query was received.
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_micros(self.timestamp_us)
+    }
+}
+
+/// CaptureWriter writes query messages to a capture file, see CAPTURE_MAGIC for the format.
+pub struct CaptureWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Create a new CaptureWriter, writing the CAPTURE_MAGIC header to writer.
+    pub fn new(mut writer: W) -> Result<Self> {
+        writer.write_all(CAPTURE_MAGIC)?;
+        Ok(Self{writer})
+    }
+
+    /// Write a record of msgs received at timestamp_us (see CapturedQuery) from session. Returns the bytes written.
+    pub fn write(&mut self, timestamp_us: u64, session: u32, msgs: &[u8]) -> Result<usize> {
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + msgs.len());
+        record.extend_from_slice(&timestamp_us.to_be_bytes());
+        record.extend_from_slice(&session.to_be_bytes());
+        record.extend_from_slice(&(msgs.len() as u32).to_be_bytes());
+        record.extend_from_slice(msgs);
+        self.writer.write_all(&record)?;
+        Ok(record.len())
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.writer.flush()?)
+    }
+}
+
+/// CaptureReader iterates over the query messages in a capture file.
+pub struct CaptureReader<R: Read> {
+    reader: R,
+}
+
+impl CaptureReader<BufReader<File>> {
+    /// Open the capture file at path for reading.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path.as_ref())
+            .map_err(|e| Error::new(format!("could not open capture file {}: {}", path.as_ref().display(), e)))?;
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Create a new CaptureReader, reading and checking the CAPTURE_MAGIC header from reader.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0; CAPTURE_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != CAPTURE_MAGIC {
+            return Err(Error::new("not a riverdb capture file"));
+        }
+        Ok(Self{reader})
+    }
+
+    /// Read the next record, returns None at the end of the file.
+    fn read_record(&mut self) -> Result<Option<CapturedQuery>> {
+        let mut header = [0; RECORD_HEADER_LEN];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let timestamp_us = u64::from_be_bytes(header[..8].try_into().unwrap());
+        let session = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        let len = u32::from_be_bytes(header[12..].try_into().unwrap()) as usize;
+        let mut buf = vec![0; len];
+        self.reader.read_exact(&mut buf)
+            .map_err(|e| Error::new(format!("truncated capture file record: {}", e)))?;
+        if !is_valid_messages(&buf) {
+            return Err(Error::new("invalid messages in capture file record"));
+        }
+        Ok(Some(CapturedQuery{timestamp_us, session, msgs: Messages::new(Bytes::from(buf))}))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<CapturedQuery>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// Returns true if buf is a non-empty sequence of complete tagged messages.
+fn is_valid_messages(mut buf: &[u8]) -> bool {
+    if buf.is_empty() {
+        return false;
+    }
+    while !buf.is_empty() {
+        if buf.len() < 5 {
+            return false;
+        }
+        let len = i32::from_be_bytes(buf[1..5].try_into().unwrap());
+        if len < 4 || len as usize + 1 > buf.len() {
+            return false;
+        }
+        buf = &buf[len as usize + 1..];
+    }
+    true
+}
+
+/// QueryCapture records the query messages of clients to the capture file in the capture settings.
+/// The file is created (or truncated) when the first query is recorded, readable only by its owner,
+/// since the queries may contain sensitive data. It's written with a buffer by a dedicated thread
+/// (see CaptureFileWriter), so recording a query never blocks the client.
+pub struct QueryCapture {
+    path: String,
+    max_file_bytes: u64,
+    database: String,
+    /// the size of the file once the records sent to the writer are written, bounds the records waiting to be written
+    size: AtomicU64,
+    /// true once the file is full or can't be written, nothing more is recorded
+    stopped: Arc<AtomicBool>,
+    commands: mpsc::UnboundedSender<CaptureCommand>,
+}
+
+/// A command for the CaptureFileWriter.
+enum CaptureCommand {
+    /// write a record of the messages received at timestamp_us from session
+    Record{timestamp_us: u64, session: u32, msgs: Messages},
+    /// flush the records so far to the file, and reply when done
+    Flush(oneshot::Sender<()>),
+}
+
+/// CaptureFileWriter writes the records of a QueryCapture to the capture file on its own thread.
+struct CaptureFileWriter {
+    path: String,
+    stopped: Arc<AtomicBool>,
+    writer: Option<CaptureWriter<BufWriter<File>>>,
+}
+
+impl QueryCapture {
+    /// Create the QueryCapture and start its writer thread, which exits when the QueryCapture is dropped.
+    pub fn new(settings: &CaptureSettings) -> Self {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let writer = CaptureFileWriter{path: settings.file.clone(), stopped: stopped.clone(), writer: None};
+        std::thread::Builder::new()
+            .name("riverdb-capture".to_string())
+            .spawn(move || writer.run(receiver))
+            .expect("could not start the capture file writer thread");
+        Self{
+            path: settings.file.clone(),
+            max_file_bytes: settings.max_file_bytes,
+            database: settings.database.clone(),
+            size: AtomicU64::new(CAPTURE_MAGIC.len() as u64),
+            stopped,
+            commands,
+        }
+    }
+
+    /// Returns the query capture for the configured capture settings, or None if capturing is disabled.
+    pub fn singleton() -> Option<&'static Self> {
+        static SINGLETON_QUERY_CAPTURE: AtomicPtr<QueryCapture> = AtomicPtr::new(std::ptr::null_mut());
+        let settings = &conf().capture;
+        if !settings.is_enabled() {
+            return None;
+        }
+        unsafe {
+            let mut p = SINGLETON_QUERY_CAPTURE.load(Acquire);
+            if p.is_null() {
+                let mut capture = Box::new(QueryCapture::new(settings));
+                p = capture.as_mut() as _;
+                match SINGLETON_QUERY_CAPTURE.compare_exchange(std::ptr::null_mut(), p, AcqRel, Acquire) {
+                    Ok(_) => {
+                        std::mem::forget(capture);
+                    },
+                    Err(current) => {
+                        p = current;
+                    },
+                }
+            }
+            Some(&*p)
+        }
+    }
+
+    /// Returns true if queries of clients connected to database are recorded.
+    pub fn wants_database(&self, database: &str) -> bool {
+        !self.stopped.load(Relaxed) && (self.database.is_empty() || self.database == database)
+    }
+
+    /// Record the query msgs sent by the client session, now. Errors writing the file are logged
+    /// and stop the capture, they don't affect the client.
+    pub fn record(&self, session: u32, msgs: &Messages) {
+        if self.stopped.load(Relaxed) {
+            return;
+        }
+        let record_len = (RECORD_HEADER_LEN + msgs.len() as usize) as u64;
+        if self.max_file_bytes != 0 && self.size.fetch_add(record_len, Relaxed) + record_len > self.max_file_bytes {
+            if !self.stopped.swap(true, Relaxed) {
+                info!(path=%self.path, max_file_bytes=self.max_file_bytes, "capture file is full, stopped capturing queries");
+            }
+            return;
+        }
+        let timestamp_us = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+        // The writer only stops when this is dropped
+        let _ = self.commands.send(CaptureCommand::Record{timestamp_us, session, msgs: msgs.clone()});
+    }
+
+    /// Waits until the queries recorded so far are written to the file.
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.commands.send(CaptureCommand::Flush(done)).is_ok() {
+            let _ = flushed.await;
+        }
+    }
+}
+
+impl CaptureFileWriter {
+    /// Execute the commands received until the QueryCapture is dropped. The buffer is flushed
+    /// whenever there are no more records waiting, so the file is only behind while clients are busy.
+    fn run(mut self, mut receiver: mpsc::UnboundedReceiver<CaptureCommand>) {
+        while let Some(command) = receiver.blocking_recv() {
+            let mut command = Some(command);
+            while let Some(c) = command.take() {
+                match c {
+                    CaptureCommand::Record{timestamp_us, session, msgs} => {
+                        if !self.stopped.load(Relaxed) {
+                            if let Err(e) = self.write(timestamp_us, session, msgs.as_slice()) {
+                                self.stop(e);
+                            }
+                        }
+                    },
+                    CaptureCommand::Flush(done) => {
+                        self.flush();
+                        let _ = done.send(());
+                    },
+                }
+                command = receiver.try_recv().ok();
+            }
+            self.flush();
+        }
+        self.flush();
+    }
+
+    fn write(&mut self, timestamp_us: u64, session: u32, msgs: &[u8]) -> Result<()> {
+        if self.writer.is_none() {
+            let mut options = OpenOptions::new();
+            options.create(true).write(true).truncate(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            let file = options.open(&self.path)
+                .map_err(|e| Error::new(format!("could not open capture file {}: {}", self.path, e)))?;
+            // mode only applies if the file is created, restrict an existing file too
+            #[cfg(unix)]
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+            self.writer = Some(CaptureWriter::new(BufWriter::new(file))?);
+        }
+        self.writer.as_mut().unwrap().write(timestamp_us, session, msgs)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        if let Some(writer) = self.writer.as_mut() {
+            if let Err(e) = writer.flush() {
+                self.stop(e);
+            }
+        }
+    }
+
+    /// Stop capturing after an error writing the file.
+    fn stop(&mut self, e: Error) {
+        self.writer = None;
+        if !self.stopped.swap(true, Relaxed) {
+            warn!(path=%self.path, %e, "could not write capture file, stopped capturing queries");
+        }
+    }
+}
+
+/// The results of replay.
+#[derive(Default, Debug, Clone)]
+pub struct ReplayStats {
+    /// the number of client sessions replayed, each on its own backend connection
+    pub sessions: u64,
+    /// the number of queries sent to the database
+    pub queries: u64,
+    /// the number of queries that returned an error
+    pub errors: u64,
+    /// the number of queries that can't be replayed and were skipped: COPY, and messages
+    /// that aren't exactly one request (e.g. extended query protocol messages ending in Flush)
+    pub skipped: u64,
+    /// the time taken to replay the queries
+    pub elapsed: Duration,
+}
+
+impl ReplayStats {
+    fn add(&mut self, other: &ReplayStats) {
+        self.sessions += other.sessions;
+        self.queries += other.queries;
+        self.errors += other.errors;
+        self.skipped += other.skipped;
+    }
+}
+
+/// Returns true if the query messages can be replayed with BackendConn::query.
+pub fn is_replayable(msgs: &Messages) -> bool {
+    let query = match QueryMessage::new(msgs.clone()) {
+        Ok(query) => query,
+        Err(_) => return false,
+    };
+    if query.request_count() != 1 || (query.is_simple_query() && msgs.count() != 1) {
+        return false;
+    }
+    // COPY would wait for, or send, CopyData messages that aren't part of the capture
+    let mut statement = Some(query.query());
+    while let Some(q) = statement {
+        if q.query_type() == QueryType::Copy {
+            return false;
+        }
+        statement = q.next.as_deref();
+    }
+    true
+}
+
+/// Replay the captured queries through pool. The queries of each client session are run in order on a
+/// backend connection of their own, concurrently with the other sessions. Queries are sent at the time
+/// they were captured relative to the first query, divided by speed: 1.0 is the original speed, 2.0 is
+/// twice as fast, and 0.0 sends each query as soon as the previous one of its session completes.
+/// Returns an error if a session can't get a backend connection, errors returned by queries are only counted.
+pub async fn replay<I>(pool: &'static ConnectionPool, queries: I, speed: f64) -> Result<ReplayStats>
+    where I: IntoIterator<Item=CapturedQuery>
+{
+    let mut sessions: FnvHashMap<u32, Vec<CapturedQuery>> = FnvHashMap::default();
+    let mut first_us = u64::MAX;
+    for query in queries {
+        first_us = first_us.min(query.timestamp_us);
+        sessions.entry(query.session).or_default().push(query);
+    }
+
+    let start = Instant::now();
+    let tasks: Vec<_> = sessions.into_values()
+        .map(|queries| tokio::spawn(replay_session(pool, queries, first_us, start, speed)))
+        .collect();
+    let mut stats = ReplayStats::default();
+    let mut result = Ok(());
+    for task in tasks {
+        match task.await {
+            Ok(Ok(session_stats)) => stats.add(&session_stats),
+            Ok(Err(e)) => result = Err(e),
+            Err(e) => result = Err(Error::new(format!("replay session panicked: {}", e))),
+        }
+    }
+    result?;
+    stats.elapsed = start.elapsed();
+    info!(?stats, "replayed captured queries");
+    Ok(stats)
+}
+
+/// Replay the queries of one client session, see replay.
+async fn replay_session(pool: &'static ConnectionPool, queries: Vec<CapturedQuery>, first_us: u64, start: Instant, speed: f64) -> Result<ReplayStats> {
+    let conn = pool.get(REPLAY_APPLICATION_NAME, "", TransactionType::None).await?;
+    let result = match conn.load() {
+        Some(backend) => replay_queries(backend, queries, first_us, start, speed).await,
+        None => Err(Error::new(format!("could not connect {:?}", pool))),
+    };
+    // Return it on every path, so a failed session doesn't lose the connection
+    BackendConn::return_to_pool(conn).await;
+    result
+}
+
+/// Replay the queries of one client session on backend, see replay.
+async fn replay_queries(backend: &BackendConn, queries: Vec<CapturedQuery>, first_us: u64, start: Instant, speed: f64) -> Result<ReplayStats> {
+    let mut stats = ReplayStats{sessions: 1, ..Default::default()};
+    for query in queries {
+        if speed > 0.0 {
+            let offset = Duration::from_micros(query.timestamp_us - first_us).div_f64(speed);
+            sleep_until(start + offset).await;
+        }
+        if !is_replayable(&query.msgs) {
+            stats.skipped += 1;
+            continue;
+        }
+        stats.queries += 1;
+        let mut rows = backend.query(query.msgs).await?;
+        if let Err(e) = rows.complete().await {
+            debug!(%e, session=query.session, "replayed query failed");
+            stats.errors += 1;
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riverdb::pg::protocol::{MessageBuilder, Tag};
+
+    fn query(sql: &str) -> QueryMessage {
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str(sql);
+        QueryMessage::new(mb.finish()).unwrap()
+    }
+
+    #[test]
+    fn test_capture_round_trip() {
+        let mut writer = CaptureWriter::new(Vec::new()).unwrap();
+        let select = query("SELECT 1");
+        let insert = query("INSERT INTO t VALUES (1)");
+        writer.write(1_000_000, 7, select.messages().as_slice()).unwrap();
+        writer.write(1_500_000, 8, insert.messages().as_slice()).unwrap();
+        let buf = writer.writer;
+
+        let captured: Vec<CapturedQuery> = CaptureReader::new(buf.as_slice()).unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(captured.len(), 2);
+        assert_eq!(captured[0].session, 7);
+        assert_eq!(captured[0].time(), UNIX_EPOCH + Duration::from_secs(1));
+        assert_eq!(captured[0].msgs.as_slice(), select.messages().as_slice());
+        assert_eq!(captured[1].session, 8);
+        assert_eq!(captured[1].timestamp_us, 1_500_000);
+        assert_eq!(captured[1].msgs.as_slice(), insert.messages().as_slice());
+
+        assert!(CaptureReader::new(&b"NOTACAPTURE"[..]).is_err());
+        let truncated = CaptureReader::new(&buf[..buf.len() - 1]).unwrap().collect::<Result<Vec<_>>>();
+        assert!(truncated.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_query_capture() {
+        let path = std::env::temp_dir().join(format!("riverdb-capture-{}.bin", std::process::id()));
+        let settings = CaptureSettings{
+            file: path.to_str().unwrap().to_string(),
+            max_file_bytes: 100,
+            database: "app".to_string(),
+        };
+        let capture = QueryCapture::new(&settings);
+        assert!(capture.wants_database("app"));
+        assert!(!capture.wants_database("other"));
+        let select = query("SELECT * FROM users WHERE id = 1");
+        capture.record(3, select.messages());
+        capture.record(3, select.messages());
+        assert!(!capture.wants_database("app"));
+        capture.flush().await;
+        #[cfg(unix)]
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let captured: Vec<CapturedQuery> = CaptureReader::open(&path).unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(captured.len(), 1);
+        assert_eq!(captured[0].session, 3);
+        assert_eq!(captured[0].msgs.as_slice(), select.messages().as_slice());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_is_replayable() {
+        assert!(is_replayable(query("SELECT 1; SELECT 2").messages()));
+        assert!(!is_replayable(query("COPY t FROM STDIN").messages()));
+
+        let mut mb = MessageBuilder::new(Tag::PARSE);
+        mb.write_str("");
+        mb.write_str("SELECT 1");
+        mb.write_i16(0);
+        mb.add_new(Tag::FLUSH);
+        assert!(!is_replayable(&mb.finish()));
+    }
+}
@@ -18,6 +18,7 @@ pub struct Rows<'a> {
     fields: RowDescription,
     msgs: Messages, // messages to be processed next
     raw: Vec<&'static [u8]>, // these point into cur, they're not static
+    nulls: Vec<bool>, // true where the field in raw is null rather than empty
     cur_pos: i32, // the offset of the current message being processed in msgs
     affected: i32,
 }
@@ -30,6 +31,7 @@ impl<'a> Rows<'a> {
             fields: RowDescription::default(),
             msgs: Messages::default(),
             raw: Vec::new(),
+            nulls: Vec::new(),
             cur_pos: -1,
             affected: -1,
         }
@@ -59,6 +61,11 @@ impl<'a> Rows<'a> {
         unsafe { change_lifetime(self.raw.as_slice()) }
     }
 
+    /// Returns true if field i of the current row is null. get_raw returns an empty slice for both null and empty fields.
+    pub fn is_null(&self, i: usize) -> Result<bool> {
+        self.nulls.get(i).cloned().ok_or_else(|| Error::new(FIELD_INDEX_OUT_OF_RANGE))
+    }
+
     #[inline]
     pub fn get_bytes(&self, i: usize) -> Result<&[u8]> {
         self.raw.get(i).cloned().ok_or_else(|| Error::new(FIELD_INDEX_OUT_OF_RANGE))
@@ -136,6 +143,7 @@ impl<'a> Rows<'a> {
         assert!(self.affected < 0); // already iterated to completion
         self.raw = Vec::new();
         loop {
+            let mut error = None;
             for msg in self.msgs.iter(self.cur_pos as usize) {
                 match msg.tag() {
                     Tag::COMMAND_COMPLETE => {
@@ -143,8 +151,9 @@ impl<'a> Rows<'a> {
                         return Ok(self.affected);
                     },
                     Tag::ERROR_RESPONSE => {
-                        let e = PostgresError::new(self.msgs.split_message(&msg))?;
-                        return Err(Error::from(e));
+                        self.cur_pos = (msg.offset() as u32 + msg.len()) as i32;
+                        error = Some(PostgresError::new(self.msgs.split_message(&msg)));
+                        break;
                     },
                     Tag::NOTICE_RESPONSE => {
                         let e = PostgresError::new(self.msgs.split_message(&msg))?;
@@ -153,6 +162,10 @@ impl<'a> Rows<'a> {
                     _ => (),
                 }
             }
+            if let Some(e) = error {
+                self.skip_to_ready_for_query().await;
+                return Err(Error::from(e?));
+            }
             self.msgs = self.backend.iterator_messages().await;
             self.cur_pos = 0; // reset this, since msgs changed
         }
@@ -169,17 +182,20 @@ impl<'a> Rows<'a> {
 
         assert!(self.affected < 0); // already iterated to completion
         loop {
+            let mut error = None;
             for msg in self.msgs.iter(self.cur_pos as usize) {
                 // Don't process this message again on the next call to next().
                 self.cur_pos = (msg.offset() as u32 + msg.len()) as i32;
                 match msg.tag() {
                     Tag::DATA_ROW => {
                         self.raw.clear();
+                        self.nulls.clear();
                         let mut r = msg.reader();
                         let num_fields = r.read_i16() as usize;
                         let bytes = msg.as_slice();
                         for _ in 0..num_fields {
                             let len = r.read_i32();
+                            self.nulls.push(len < 0);
                             if len <= 0 {
                                 self.raw.push(&[]); // null
                             } else {
@@ -204,9 +220,11 @@ impl<'a> Rows<'a> {
                         self.raw = Vec::new();
                         return Ok(false);
                     },
+                    // Responses to the extended query protocol messages, see BackendConn::query
+                    Tag::PARSE_COMPLETE | Tag::BIND_COMPLETE | Tag::NO_DATA => (),
                     Tag::ERROR_RESPONSE => {
-                        let e = PostgresError::new(self.msgs.split_message(&msg))?;
-                        return Err(Error::from(e));
+                        error = Some(PostgresError::new(self.msgs.split_message(&msg)));
+                        break;
                     },
                     Tag::NOTICE_RESPONSE => {
                         let e = PostgresError::new(self.msgs.split_message(&msg))?;
@@ -217,6 +235,63 @@ impl<'a> Rows<'a> {
                     }
                 }
             }
+            if let Some(e) = error {
+                self.skip_to_ready_for_query().await;
+                return Err(Error::from(e?));
+            }
+            self.msgs = self.backend.iterator_messages().await;
+            self.cur_pos = 0; // reset this, since msgs changed
+        }
+    }
+
+    /// Consume the entire result up to and including the ReadyForQuery, which may include the results
+    /// of several statements (e.g. a Query message with multiple statements.) Returns the number of rows
+    /// affected by the last statement, or the first error. Rows are discarded.
+    pub async fn complete(&mut self) -> Result<i32> {
+        if self.affected >= 0 {
+            return Ok(self.affected);
+        }
+
+        self.wait_for_notify().await;
+
+        self.raw = Vec::new();
+        let mut affected = 0;
+        let mut error = None;
+        loop {
+            for msg in self.msgs.iter(self.cur_pos as usize) {
+                self.cur_pos = (msg.offset() as u32 + msg.len()) as i32;
+                match msg.tag() {
+                    Tag::COMMAND_COMPLETE => affected = parse_affected_rows(&msg).unwrap_or(0),
+                    Tag::ERROR_RESPONSE if error.is_none() => {
+                        error = Some(PostgresError::new(self.msgs.split_message(&msg)));
+                    },
+                    Tag::READY_FOR_QUERY => {
+                        self.affected = affected;
+                        return match error {
+                            Some(e) => Err(Error::from(e?)),
+                            None => Ok(affected),
+                        };
+                    },
+                    _ => (),
+                }
+            }
+            self.msgs = self.backend.iterator_messages().await;
+            self.cur_pos = 0; // reset this, since msgs changed
+        }
+    }
+
+    /// Consume the rest of the result up to and including the ReadyForQuery after an ErrorResponse,
+    /// so it isn't mistaken for the result of the next query. The Rows is then complete.
+    async fn skip_to_ready_for_query(&mut self) {
+        self.raw = Vec::new();
+        loop {
+            for msg in self.msgs.iter(self.cur_pos as usize) {
+                self.cur_pos = (msg.offset() as u32 + msg.len()) as i32;
+                if msg.tag() == Tag::READY_FOR_QUERY {
+                    self.affected = 0;
+                    return;
+                }
+            }
             self.msgs = self.backend.iterator_messages().await;
             self.cur_pos = 0; // reset this, since msgs changed
         }
@@ -229,7 +304,7 @@ impl<'a> Drop for Rows<'a> {
     }
 }
 
-fn parse_affected_rows(msg: &Message<'_>) -> Result<i32> {
+pub(crate) fn parse_affected_rows(msg: &Message<'_>) -> Result<i32> {
     let mut r = msg.reader();
     // For all command tags that have a row count, it's the last part of the tag after a space
     let cmd_tag = r.read_str()?;
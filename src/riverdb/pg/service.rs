@@ -1,29 +1,149 @@
-use tracing::{info};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
 
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tracing::{info, debug, error};
+
+use crate::riverdb::Result;
 use crate::riverdb::worker::Worker;
-use crate::riverdb::server::{Connections, Listener};
-use crate::riverdb::pg::ClientConn;
+use crate::riverdb::server::{Connections, Listener, ListenerOptions};
+use crate::riverdb::pg::{ClientConn, PostgresCluster};
 
+/// PostgresService accepts client connections on one or more listen addresses for a single PostgresCluster.
 pub struct PostgresService {
-    listener: Listener,
-    connections: &'static Connections<ClientConn>
+    /// the listening sockets, possibly several per address with reuseport
+    listeners: Vec<Listener>,
+    /// listens on the .s.PGSQL.<port> socket in the cluster's unix_socket_dir, if set
+    #[cfg(unix)]
+    unix_listener: Option<(PathBuf, UnixListener)>,
+    connections: &'static Connections<ClientConn>,
+    cluster: &'static PostgresCluster,
 }
 
 impl PostgresService {
-    pub fn new(address: String, max_connections: u32, timeout_seconds: u32, reuseport: bool) -> Self{
+    pub fn new(address: String, cluster: &'static PostgresCluster, options: ListenerOptions) -> Self{
+        Self::with_addresses(vec![address], 1, cluster, options)
+    }
+
+    /// Create a service listening on each of addresses, with listeners_per_address sockets bound to each.
+    /// More than one listener per address requires options.reuseport.
+    pub fn with_addresses(addresses: Vec<String>, listeners_per_address: u32, cluster: &'static PostgresCluster, options: ListenerOptions) -> Self{
+        let mut listeners = Vec::new();
+        for address in addresses {
+            for _ in 0..listeners_per_address.max(1) {
+                listeners.push(Listener::new(address.clone(), options)
+                    .unwrap_or_else(|e| panic!("could not create listener on {}: {}", address, e)));
+            }
+        }
         Self{
-            listener: Listener::new(address, reuseport).expect("could not create listener"),
-            connections: Connections::new(max_connections, timeout_seconds),
+            listeners,
+            #[cfg(unix)]
+            unix_listener: if cluster.config.unix_socket_dir.is_empty() {
+                None
+            } else {
+                Some(bind_unix_socket(&cluster.config.unix_socket_dir, cluster.config.port).expect("could not create unix socket listener"))
+            },
+            connections: Connections::new(cluster.config.max_connections, cluster.config.idle_timeout_seconds),
+            cluster,
+        }
+    }
+
+    /// Returns the addresses the service listens on.
+    pub fn addresses(&self) -> Vec<&str> {
+        let mut addresses: Vec<&str> = Vec::new();
+        for listener in &self.listeners {
+            if !addresses.contains(&listener.address.as_str()) {
+                addresses.push(listener.address.as_str());
+            }
         }
+        addresses
+    }
+
+    /// Returns the socket address the (first) listener is bound to, which includes the port chosen by the OS
+    /// if it was created with port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    /// Returns the number of listening sockets, see run_listener.
+    pub fn listener_count(&self) -> usize {
+        self.listeners.len()
+    }
+
+    /// Returns the path of the unix domain socket the service listens on, if any.
+    #[cfg(unix)]
+    pub fn unix_socket_path(&self) -> Option<&Path> {
+        self.unix_listener.as_ref().map(|(path, _)| path.as_path())
+    }
+
+    /// Returns the PostgresCluster clients of this service connect to.
+    pub fn cluster(&self) -> &'static PostgresCluster {
+        self.cluster
+    }
+
+    /// Returns the client connections for this service.
+    pub fn connections(&self) -> &'static Connections<ClientConn> {
+        self.connections
     }
 
+    /// Accept client connections on all the listeners (including the unix socket) until they're closed.
     pub async fn run(&self) {
-        info!(adress = %self.listener.address.as_str(), "starting PostgresService on worker thread {}", Worker::get().id);
+        let tcp = futures::future::join_all((0..self.listeners.len()).map(|i| self.run_listener(i)));
+        #[cfg(unix)]
+        tokio::join!(tcp, self.run_unix());
+        #[cfg(not(unix))]
+        tcp.await;
+    }
+
+    /// Accept client connections on the i-th listener. Spawn this once per listener to spread
+    /// the accept loops across worker threads.
+    pub async fn run_listener(&self, i: usize) {
+        let listener = &self.listeners[i];
+        info!(adress = %listener.address.as_str(), cluster = ?self.cluster, "starting PostgresService on worker thread {}", Worker::get().id);
         // Use an explicit handle here rather than looking it up in thread local storage each time
         let tokio = tokio::runtime::Handle::current();
-        while let Some(sock) = self.listener.accept().await {
+        let guard = &self.cluster.startup_guard;
+        while let Some(sock) = listener.accept().await {
+            if let Ok(addr) = sock.peer_addr() {
+                if guard.is_banned(addr.ip()) {
+                    debug!(%addr, "closing connection from banned ip address");
+                    continue;
+                }
+            }
             let conn = self.connections.add(sock);
-            if conn.is_some() {
+            if let Some(client) = conn.load() {
+                client.set_cluster(Some(self.cluster));
+                tokio.spawn(async move {
+                    // We already handled this error, including logging it, in run()
+                    let _ = conn.run().await;
+                });
+            }
+            // Else drop the connection, we're at capacity
+        }
+    }
+
+    /// Accept client connections on the unix domain socket, if there is one.
+    #[cfg(unix)]
+    pub async fn run_unix(&self) {
+        let (path, listener) = match self.unix_listener.as_ref() {
+            Some(unix_listener) => unix_listener,
+            None => return,
+        };
+        info!(path = %path.display(), cluster = ?self.cluster, "listening on unix socket");
+        let tokio = tokio::runtime::Handle::current();
+        loop {
+            let sock = match listener.accept().await {
+                Ok((sock, _)) => sock,
+                Err(e) => {
+                    error!(%e, path = %path.display(), "unix socket accept error");
+                    continue;
+                },
+            };
+            let conn = self.connections.add_unix(sock);
+            if let Some(client) = conn.load() {
+                client.set_cluster(Some(self.cluster));
                 tokio.spawn(async move {
                     // We already handled this error, including logging it, in run()
                     let _ = conn.run().await;
@@ -32,4 +152,16 @@ impl PostgresService {
             // Else drop the connection, we're at capacity
         }
     }
-}
\ No newline at end of file
+}
+
+/// Bind the standard .s.PGSQL.<port> socket in dir, replacing a stale socket file left by a previous process.
+#[cfg(unix)]
+fn bind_unix_socket(dir: &str, port: u16) -> Result<(PathBuf, UnixListener)> {
+    let path = Path::new(dir).join(format!(".s.PGSQL.{}", port));
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
+    }
+    let listener = UnixListener::bind(&path)?;
+    Ok((path, listener))
+}
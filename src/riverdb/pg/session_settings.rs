@@ -0,0 +1,178 @@
+use crate::riverdb::pg::sql::{Query, QueryType};
+use crate::riverdb::pg::{IsolationLevel, TransactionOptions};
+
+
+/// A change to a session parameter made by a SET or RESET statement.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SettingChange {
+    /// SET name TO value, with the SQL to set it again
+    Set{name: String, sql: String},
+    /// RESET name, or SET name TO DEFAULT
+    Reset{name: String},
+    /// RESET ALL
+    ResetAll,
+}
+
+impl SettingChange {
+    /// Returns the change to the session made by query, if it's a SET (not SET LOCAL, SET ROLE,
+    /// or SET TRANSACTION) or RESET statement. Returns None for other queries.
+    pub fn parse(query: &Query) -> Option<Self> {
+        let normalized = query.normalized().trim_end_matches(|c: char| c == ';' || c.is_whitespace());
+        match query.query_type() {
+            QueryType::SetSession => {
+                let rest = normalized.strip_prefix("SET")?.trim_start();
+                let rest = rest.strip_prefix("SESSION ").unwrap_or(rest).trim_start();
+                let (name, value) = split_name(rest)?;
+                let value = value.trim_start();
+                let value = value.strip_prefix('=').or_else(|| value.strip_prefix("TO ")).unwrap_or(value).trim();
+                if value == "DEFAULT" {
+                    Some(Self::Reset{name})
+                } else {
+                    Some(Self::Set{name, sql: query.to_sql()})
+                }
+            },
+            QueryType::Reset => {
+                let rest = normalized.strip_prefix("RESET")?.trim_start();
+                let (name, _) = split_name(rest)?;
+                if name == "ALL" {
+                    Some(Self::ResetAll)
+                } else {
+                    Some(Self::Reset{name})
+                }
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns the default isolation level of new transactions after this change, if it sets it with
+    /// SET default_transaction_isolation or SET SESSION CHARACTERISTICS AS TRANSACTION ISOLATION LEVEL.
+    /// Returns IsolationLevel::None if it resets it, or None if it doesn't change it.
+    pub fn default_isolation(&self) -> Option<IsolationLevel> {
+        match self {
+            Self::Set{name, sql} if name == "DEFAULT_TRANSACTION_ISOLATION" => {
+                let value = sql.find(|c: char| c == '=' || c == '\'')
+                    .or_else(|| sql.find(" TO ").map(|i| i + 3))
+                    .map_or("", |i| &sql[i..]);
+                let value = value.trim_matches(|c: char| c == '=' || c == '\'' || c == '"' || c.is_whitespace());
+                Some(TransactionOptions::parse(&format!("ISOLATION LEVEL {}", value.to_ascii_uppercase())).isolation)
+            },
+            Self::Set{name, sql} if name == "CHARACTERISTICS" => {
+                match TransactionOptions::parse(sql).isolation {
+                    IsolationLevel::None => None,
+                    isolation => Some(isolation),
+                }
+            },
+            Self::Reset{name} if name == "DEFAULT_TRANSACTION_ISOLATION" => Some(IsolationLevel::None),
+            Self::ResetAll => Some(IsolationLevel::None),
+            _ => None,
+        }
+    }
+}
+
+/// Splits the parameter name from the start of s, returns None if there is no name.
+/// The name is uppercased, since it may keep its case in the normalized query.
+fn split_name(s: &str) -> Option<(String, &str)> {
+    let end = s.find(|c: char| c.is_whitespace() || c == '=').unwrap_or(s.len());
+    if end == 0 {
+        None
+    } else {
+        Some((s[..end].to_ascii_uppercase(), &s[end..]))
+    }
+}
+
+/// SessionSettings are the session parameters a client changed with SET, which are set again on each
+/// backend connection the client uses in transaction or statement pool_mode, because the server_reset_query
+/// undoes them when a connection returns to the pool.
+#[derive(Debug, Default)]
+pub struct SessionSettings {
+    /// the SQL of the last SET statement for each parameter, in the order they were first set
+    settings: Vec<(String, String)>,
+}
+
+impl SessionSettings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns true if no parameters are set.
+    pub fn is_empty(&self) -> bool {
+        self.settings.is_empty()
+    }
+
+    /// Record a change made by a successful SET or RESET statement.
+    pub fn apply(&mut self, change: SettingChange) {
+        match change {
+            SettingChange::Set{name, sql} => {
+                match self.settings.iter_mut().find(|(n, _)| *n == name) {
+                    Some(setting) => setting.1 = sql,
+                    None => self.settings.push((name, sql)),
+                }
+            },
+            SettingChange::Reset{name} => self.settings.retain(|(n, _)| *n != name),
+            SettingChange::ResetAll => self.settings.clear(),
+        }
+    }
+
+    /// Returns the SQL statements to set all the parameters again, separated by ;
+    pub fn replay_sql(&self) -> String {
+        self.settings.iter().map(|(_, sql)| sql.as_str()).collect::<Vec<_>>().join("; ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riverdb::pg::protocol::{MessageBuilder, Tag};
+    use crate::riverdb::pg::sql::QueryMessage;
+
+    fn change(sql: &str) -> Option<SettingChange> {
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str(sql);
+        SettingChange::parse(QueryMessage::new(mb.finish()).unwrap().query())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(change("set search_path to myschema, public"), Some(SettingChange::Set{name: "SEARCH_PATH".to_string(), sql: "SET SEARCH_PATH TO MYSCHEMA, PUBLIC".to_string()}));
+        assert_eq!(change("SET SESSION statement_timeout = 5000"), Some(SettingChange::Set{name: "STATEMENT_TIMEOUT".to_string(), sql: "SET SESSION STATEMENT_TIMEOUT = 5000".to_string()}));
+        assert_eq!(change("SET statement_timeout TO DEFAULT"), Some(SettingChange::Reset{name: "STATEMENT_TIMEOUT".to_string()}));
+        assert_eq!(change("reset search_path;"), Some(SettingChange::Reset{name: "SEARCH_PATH".to_string()}));
+        assert_eq!(change("RESET ALL"), Some(SettingChange::ResetAll));
+        assert_eq!(change("SET LOCAL search_path TO myschema"), None);
+        assert_eq!(change("SET ROLE admin"), None);
+        assert_eq!(change("SELECT 1"), None);
+    }
+
+    #[test]
+    fn test_default_isolation() {
+        let tests = &[
+            ("SET default_transaction_isolation = 'serializable'", Some(IsolationLevel::Serializable)),
+            ("set session default_transaction_isolation to 'repeatable read'", Some(IsolationLevel::RepeatableRead)),
+            ("SET default_transaction_isolation TO \"read committed\"", Some(IsolationLevel::ReadCommitted)),
+            ("SET SESSION CHARACTERISTICS AS TRANSACTION ISOLATION LEVEL SERIALIZABLE", Some(IsolationLevel::Serializable)),
+            ("set session characteristics as transaction read only, isolation level repeatable read", Some(IsolationLevel::RepeatableRead)),
+            ("SET SESSION CHARACTERISTICS AS TRANSACTION READ ONLY", None),
+            ("SET default_transaction_isolation TO DEFAULT", Some(IsolationLevel::None)),
+            ("RESET default_transaction_isolation", Some(IsolationLevel::None)),
+            ("RESET ALL", Some(IsolationLevel::None)),
+            ("SET search_path TO a", None),
+        ];
+        for &(sql, isolation) in tests {
+            assert_eq!(change(sql).unwrap().default_isolation(), isolation, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_session_settings() {
+        let mut settings = SessionSettings::new();
+        assert!(settings.is_empty());
+        settings.apply(change("SET search_path TO a").unwrap());
+        settings.apply(change("SET application_name = 'it''s'").unwrap());
+        settings.apply(change("SET search_path TO b").unwrap());
+        assert_eq!(settings.replay_sql(), "SET SEARCH_PATH TO B; SET APPLICATION_NAME = 'it''s'");
+        settings.apply(change("RESET search_path").unwrap());
+        assert_eq!(settings.replay_sql(), "SET APPLICATION_NAME = 'it''s'");
+        settings.apply(change("RESET ALL").unwrap());
+        assert!(settings.is_empty());
+    }
+}
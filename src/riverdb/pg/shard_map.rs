@@ -0,0 +1,182 @@
+use std::sync::{RwLock, PoisonError};
+
+use fnv::FnvHashMap;
+
+use crate::riverdb::pg::sql::Query;
+
+
+/// ShardMap maps table names to the index of the cluster node (replication group) storing them.
+/// It's used to route queries that only touch tables stored on a single worker node of a
+/// sharded cluster (e.g. Citus) directly to that worker, bypassing the coordinator.
+pub struct ShardMap {
+    /// uppercase table name => Some(node index) if the table is stored on a single node, else None
+    tables: RwLock<FnvHashMap<String, Option<usize>>>,
+}
+
+impl ShardMap {
+    /// Create a new, empty ShardMap.
+    pub fn new() -> Self {
+        Self {
+            tables: RwLock::new(FnvHashMap::default()),
+        }
+    }
+
+    /// Replace the contents of the map with the given (table, node index) placements.
+    /// A table with placements on more than one node is marked as multi-shard.
+    pub fn replace<'a, I: IntoIterator<Item=(&'a str, usize)>>(&self, placements: I) {
+        let mut tables = FnvHashMap::default();
+        for (table, node) in placements {
+            tables.entry(table.to_ascii_uppercase())
+                .and_modify(|cur: &mut Option<usize>| {
+                    if *cur != Some(node) {
+                        *cur = None;
+                    }
+                })
+                .or_insert(Some(node));
+        }
+        *self.tables.write().unwrap_or_else(PoisonError::into_inner) = tables;
+    }
+
+    /// Returns the number of tables in the map.
+    pub fn len(&self) -> usize {
+        self.tables.read().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    /// Returns the index of the single node that stores all the tables referenced in query,
+    /// or None if the query touches multiple nodes, unknown tables, or no tables at all
+    /// (in which case it should be sent to the coordinator.)
+    pub fn route(&self, query: &Query) -> Option<usize> {
+        let tables = self.tables.read().unwrap_or_else(PoisonError::into_inner);
+        if tables.is_empty() {
+            return None;
+        }
+
+        let mut result = None;
+        let mut q = Some(query);
+        while let Some(cur) = q {
+            for table in referenced_tables(cur.normalized()) {
+                // Table names keep their case if the normalizer preserves identifier case
+                let table = table.to_ascii_uppercase();
+                let node = match tables.get(&table) {
+                    Some(node) => *node,
+                    None => {
+                        // Try again without the schema prefix
+                        let (_, name) = table.rsplit_once('.')?;
+                        *tables.get(name)?
+                    },
+                };
+                match (node, result) {
+                    (None, _) => return None,
+                    (Some(n), Some(prev)) if n != prev => return None,
+                    (Some(n), _) => result = Some(n),
+                }
+            }
+            q = cur.next.as_deref();
+        }
+        result
+    }
+}
+
+/// Returns the table names referenced in a normalized query, that is identifiers following
+/// FROM, JOIN, UPDATE, INTO, USING, or TABLE, and comma separated tables in a FROM or TRUNCATE list.
+/// This is a heuristic, it doesn't understand CTE names or functions in FROM, which just
+/// won't be found in the shard map (and the query will be sent to the coordinator.)
+pub fn referenced_tables(normalized: &str) -> Vec<&str> {
+    let mut tables = Vec::new();
+    let mut expect_table = false;
+    let mut in_from_list = false;
+    for token in normalized.split_ascii_whitespace() {
+        if expect_table {
+            if token == "ONLY" || token == "LATERAL" || token == "TABLE" {
+                continue;
+            }
+            expect_table = false;
+            if token.starts_with('(') {
+                // A subquery, its tables will be found when we reach its FROM
+                in_from_list = false;
+                continue;
+            }
+            let table = token.trim_end_matches(|c| c == ',' || c == ';' || c == ')');
+            let table = table.split('(').next().unwrap_or(table);
+            if !table.is_empty() {
+                tables.push(table);
+            }
+            // A comma inside a function call's arguments doesn't continue the FROM list
+            expect_table = in_from_list && token.ends_with(',') && !token.contains('(');
+            continue;
+        }
+
+        match token {
+            "FROM" | "TRUNCATE" => {
+                expect_table = true;
+                in_from_list = true;
+            },
+            "JOIN" | "UPDATE" | "INTO" | "USING" | "TABLE" => {
+                expect_table = true;
+                in_from_list = false;
+            },
+            _ => {
+                if in_from_list {
+                    if token.ends_with(',') {
+                        // The end of a table alias in a FROM list
+                        expect_table = true;
+                    } else if token.ends_with(')') || token.ends_with(';') || token == "WHERE" || token == "ON" {
+                        in_from_list = false;
+                    }
+                }
+            },
+        }
+    }
+    tables
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riverdb::pg::sql::QueryNormalizer;
+
+    fn route(map: &ShardMap, sql: &str) -> Option<usize> {
+        let mut tags = Vec::new();
+        let query = QueryNormalizer::new_at(sql.as_bytes(), 0).normalize(&mut tags).expect("valid query");
+        map.route(&query)
+    }
+
+    #[test]
+    fn test_route() {
+        let map = ShardMap::new();
+        assert_eq!(route(&map, "select * from users"), None);
+
+        map.replace(vec![("users", 1), ("orders", 1), ("events", 2), ("accounts", 1), ("accounts", 2)]);
+        assert_eq!(map.len(), 4);
+        assert_eq!(route(&map, "select * from users where id = 1"), Some(1));
+        assert_eq!(route(&map, "select * from public.users u join orders o on o.user_id = u.id"), Some(1));
+        assert_eq!(route(&map, "select * from users; select * from orders"), Some(1));
+        assert_eq!(route(&map, "insert into events (id) values (1)"), Some(2));
+        assert_eq!(route(&map, "select * from users join events on true"), None);
+        assert_eq!(route(&map, "select * from accounts"), None);
+        assert_eq!(route(&map, "select * from unknown"), None);
+        assert_eq!(route(&map, "select 1"), None);
+    }
+
+    #[test]
+    fn test_referenced_tables() {
+        let tests: &[(&str, &[&str])] = &[
+            ("SELECT $1", &[]),
+            ("SELECT * FROM USERS WHERE ID = $1", &["USERS"]),
+            ("SELECT * FROM PUBLIC.USERS U JOIN ORDERS O ON O.USER_ID = U.ID", &["PUBLIC.USERS", "ORDERS"]),
+            ("SELECT * FROM USERS U, ORDERS O WHERE O.USER_ID = U.ID", &["USERS", "ORDERS"]),
+            ("SELECT * FROM USERS, ORDERS", &["USERS", "ORDERS"]),
+            ("SELECT * FROM (SELECT ID FROM USERS) AS T", &["USERS"]),
+            ("INSERT INTO ORDERS (ID) VALUES ($1)", &["ORDERS"]),
+            ("UPDATE ONLY ORDERS SET TOTAL = $1", &["ORDERS"]),
+            ("DELETE FROM ORDERS WHERE ID = $1", &["ORDERS"]),
+            ("DELETE FROM ORDERS USING USERS WHERE ORDERS.USER_ID = USERS.ID", &["ORDERS", "USERS"]),
+            ("TRUNCATE TABLE ORDERS, ITEMS RESTART IDENTITY", &["ORDERS", "ITEMS"]),
+            ("SELECT * FROM GENERATE_SERIES($1, $2)", &["GENERATE_SERIES"]),
+        ];
+
+        for &(query, expected) in tests {
+            assert_eq!(referenced_tables(query), expected, "{}", query);
+        }
+    }
+}
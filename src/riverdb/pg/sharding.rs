@@ -0,0 +1,405 @@
+use std::hash::Hasher;
+
+use fnv::FnvHasher;
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::config::{ShardingSettings, ShardingStrategy};
+use crate::riverdb::pg::sql::{Query, QueryMessage, QueryParam, LiteralType};
+
+
+/// Returns the index of the server (replication group) storing the shard of the query's shard key,
+/// or None if the query has no shard key (and require_key isn't set.) Returns an error if the query
+/// has keys on more than one shard (including in different Bind messages of a pipeline), or an invalid key,
+/// or it's truncated without a key tag.
+pub fn route_shard(settings: &ShardingSettings, query: &QueryMessage) -> Result<Option<usize>> {
+    let keys = match query.tag(&settings.key_tag) {
+        Some(key) => vec![key.trim().to_string()],
+        // Keys on other shards could be past the truncation, don't guess
+        None if query.is_truncated() => return Err(Error::new(format!(
+            "query is larger than max_normalize_bytes, add a {} tag to route it", settings.key_tag))),
+        None => shard_keys(query, &settings.key_column)?,
+    };
+    let mut server = None;
+    for key in &keys {
+        let s = settings.shards[shard_for_key(settings, key)?].server;
+        match server {
+            Some(prev) if prev != s => return Err(Error::new("query has shard keys on more than one shard, cross-shard queries are not supported")),
+            _ => server = Some(s),
+        }
+    }
+    // Executing an existing prepared statement has no SQL to take the key from
+    if server.is_none() && settings.require_key && !query.query().normalized().is_empty() {
+        return Err(Error::new(format!("query has no shard key, add a {} tag or compare {} to a literal", settings.key_tag, settings.key_column)));
+    }
+    Ok(server)
+}
+
+/// Returns the index in settings.shards of the shard storing key.
+pub fn shard_for_key(settings: &ShardingSettings, key: &str) -> Result<usize> {
+    match settings.strategy {
+        ShardingStrategy::Hash => {
+            let mut hasher = FnvHasher::default();
+            hasher.write(key.as_bytes());
+            Ok((hasher.finish() % settings.shards.len() as u64) as usize)
+        },
+        ShardingStrategy::Range => {
+            let key: i64 = key.parse().map_err(|_| Error::new(format!("shard key {} is not an integer", key)))?;
+            // The first shard's min is i64::MIN, see ShardingSettings::load
+            let i = settings.shards.partition_point(|shard| shard.min.unwrap_or(i64::MIN) <= key);
+            Ok(i.saturating_sub(1))
+        },
+    }
+}
+
+/// Returns the shard keys of the statements in query: the values compared with = or IN to column,
+/// or inserted into it with INSERT ... VALUES, in every Bind message if the values are bound parameters.
+/// Values that aren't literals or bound parameters are skipped. Returns an error for keys that can't be
+/// read reliably: binary format bound parameters, and escape or unicode string literals.
+pub fn shard_keys(query: &QueryMessage, column: &str) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    if column.is_empty() {
+        return Ok(keys);
+    }
+    // Placeholders in a Parse message without literals refer to the bound parameters
+    let bound = !query.is_simple_query() && query.query().params().is_empty();
+    let mut statement = Some(query.query());
+    while let Some(q) = statement {
+        for n in key_placeholders(q.normalized(), column) {
+            if bound {
+                let values = query.bound_params(n - 1)
+                    .map_err(|e| Error::new(format!("can't read shard key {}: {}", column, e)))?;
+                keys.extend(values.into_iter().map(str::to_string));
+            } else if let Some(param) = q.params().get(n - 1) {
+                if param.ty == LiteralType::List {
+                    for item in &param.items {
+                        keys.extend(literal_key(q, item, column)?);
+                    }
+                } else {
+                    keys.extend(literal_key(q, param, column)?);
+                }
+            }
+        }
+        statement = q.next.as_deref();
+    }
+    Ok(keys)
+}
+
+/// Returns the value of a string or numeric literal as a shard key, without quotes, or None for other literals.
+/// Returns an error for escape and unicode strings, which would have to be unescaped to get the key.
+fn literal_key(query: &Query, param: &QueryParam, column: &str) -> Result<Option<String>> {
+    match param.ty {
+        LiteralType::String => {
+            let value = query.param(param);
+            Ok(Some(value[1..value.len() - 1].replace("''", "'")))
+        },
+        LiteralType::Integer | LiteralType::Numeric => Ok(Some(query.param_literal(param))),
+        LiteralType::EscapeString | LiteralType::UnicodeString => Err(Error::new(format!(
+            "shard key {} can't be an escape or unicode string, use a standard string literal", column))),
+        _ => Ok(None),
+    }
+}
+
+/// A token of a normalized query
+#[derive(Eq, PartialEq, Debug)]
+enum Token<'a> {
+    /// an identifier, the last part if qualified, without quotes
+    Ident(&'a str),
+    /// a $N placeholder
+    Placeholder(usize),
+    /// an operator, e.g. = or <=
+    Operator(&'a str),
+    /// ( ) , ; or another character
+    Punct(char),
+}
+
+fn tokenize(normalized: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let bytes = normalized.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let start = i;
+        i += 1;
+        if c.is_ascii_whitespace() {
+            continue;
+        } else if c == b'$' {
+            while i < bytes.len() && bytes[i].is_ascii_digit() {
+                i += 1;
+            }
+            match normalized[start + 1..i].parse() {
+                Ok(n) => tokens.push(Token::Placeholder(n)),
+                Err(_) => tokens.push(Token::Punct('$')),
+            }
+        } else if c == b'"' || c.is_ascii_alphabetic() || c == b'_' || c >= 0x80 {
+            // An identifier, possibly qualified: schema.table."Column"
+            let mut ident;
+            i = start;
+            loop {
+                let part_start = i;
+                if i < bytes.len() && bytes[i] == b'"' {
+                    // A quoted identifier, "" is an escaped quote
+                    i += 1;
+                    while i < bytes.len() {
+                        if bytes[i] == b'"' {
+                            if bytes.get(i + 1) != Some(&b'"') {
+                                break;
+                            }
+                            i += 1;
+                        }
+                        i += 1;
+                    }
+                    ident = &normalized[part_start + 1..i];
+                    i = (i + 1).min(bytes.len());
+                } else {
+                    while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_' || bytes[i] == b'$' || bytes[i] >= 0x80) {
+                        i += 1;
+                    }
+                    ident = &normalized[part_start..i];
+                }
+                if i < bytes.len() && bytes[i] == b'.' {
+                    i += 1;
+                } else {
+                    break;
+                }
+            }
+            tokens.push(Token::Ident(ident));
+        } else if b"+-*/<>=~!@#%^&|`?".contains(&c) {
+            while i < bytes.len() && b"+-*/<>=~!@#%^&|`?".contains(&bytes[i]) {
+                i += 1;
+            }
+            tokens.push(Token::Operator(&normalized[start..i]));
+        } else if c.is_ascii() {
+            tokens.push(Token::Punct(c as char));
+        }
+    }
+    tokens
+}
+
+/// Returns the numbers of the $N placeholders compared with = or IN to column, or inserted into it.
+fn key_placeholders(normalized: &str, column: &str) -> Vec<usize> {
+    let tokens = tokenize(normalized);
+    let is_column = |t: &Token| matches!(t, Token::Ident(name) if name.eq_ignore_ascii_case(column));
+    let mut placeholders = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if !is_column(token) {
+            continue;
+        }
+        match (tokens.get(i + 1), tokens.get(i + 2)) {
+            (Some(Token::Operator("=")), Some(&Token::Placeholder(n))) => placeholders.push(n),
+            (Some(Token::Ident(kw)), Some(Token::Punct('('))) if kw.eq_ignore_ascii_case("IN") => {
+                for t in &tokens[i + 3..] {
+                    match *t {
+                        Token::Placeholder(n) => placeholders.push(n),
+                        Token::Punct(',') => (),
+                        _ => break,
+                    }
+                }
+            },
+            _ => (),
+        }
+        if i >= 2 {
+            if let (&Token::Placeholder(n), Token::Operator("=")) = (&tokens[i - 2], &tokens[i - 1]) {
+                placeholders.push(n);
+            }
+        }
+    }
+    placeholders.extend(insert_placeholders(&tokens, is_column));
+    placeholders
+}
+
+/// Returns the placeholders inserted into the column for INSERT INTO table (columns) VALUES (...), (...)
+fn insert_placeholders<F: Fn(&Token) -> bool>(tokens: &[Token], is_column: F) -> Vec<usize> {
+    let mut placeholders = Vec::new();
+    let insert = tokens.windows(4).position(|w| {
+        matches!(w, [Token::Ident(insert), Token::Ident(into), Token::Ident(_), Token::Punct('(')]
+            if insert.eq_ignore_ascii_case("INSERT") && into.eq_ignore_ascii_case("INTO"))
+    });
+    let mut i = match insert {
+        Some(i) => i + 4,
+        None => return placeholders,
+    };
+    let mut position = None;
+    let mut column = 0;
+    while let Some(t) = tokens.get(i) {
+        i += 1;
+        match t {
+            Token::Punct(')') => break,
+            Token::Punct(',') => column += 1,
+            t if is_column(t) => position = Some(column),
+            _ => (),
+        }
+    }
+    let position = match position {
+        Some(position) => position,
+        None => return placeholders,
+    };
+    match tokens.get(i) {
+        Some(Token::Ident(values)) if values.eq_ignore_ascii_case("VALUES") => i += 1,
+        _ => return placeholders,
+    }
+    // Each row of values, the column's value is a placeholder if it's the only token in its position
+    let (mut depth, mut column, mut value) = (0, 0, Vec::new());
+    while let Some(t) = tokens.get(i) {
+        i += 1;
+        match t {
+            Token::Punct('(') => {
+                depth += 1;
+                if depth == 1 {
+                    column = 0;
+                    value.clear();
+                    continue;
+                }
+            },
+            Token::Punct(')') | Token::Punct(',') if depth == 1 => {
+                if column == position {
+                    if let [&Token::Placeholder(n)] = value[..] {
+                        placeholders.push(n);
+                    }
+                }
+                if *t == Token::Punct(')') {
+                    depth = 0;
+                }
+                column += 1;
+                value.clear();
+                continue;
+            },
+            Token::Punct(')') => depth -= 1,
+            Token::Punct(',') if depth == 0 => continue,
+            _ if depth == 0 => break,
+            _ => (),
+        }
+        value.push(t);
+    }
+    placeholders
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riverdb::config::ShardSettings;
+    use crate::riverdb::pg::protocol::{MessageBuilder, Tag};
+    use crate::riverdb::pg::sql::NormalizeOptions;
+
+    fn query(sql: &str) -> QueryMessage {
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str(sql);
+        QueryMessage::new(mb.finish()).unwrap()
+    }
+
+    fn settings(strategy: ShardingStrategy, shards: &[(usize, Option<i64>)]) -> ShardingSettings {
+        let mut settings = ShardingSettings{
+            enabled: true,
+            strategy,
+            key_column: "tenant_id".to_string(),
+            shards: shards.iter().map(|&(server, min)| ShardSettings{server, min}).collect(),
+            ..Default::default()
+        };
+        settings.load(4).unwrap();
+        settings
+    }
+
+    #[test]
+    fn test_shard_keys() {
+        let tests: &[(&str, &[&str])] = &[
+            ("select * from orders where tenant_id = 42", &["42"]),
+            ("select * from orders o where o.TENANT_ID = 'acme' and id = 7", &["acme"]),
+            ("select * from orders where 'it''s' = tenant_id", &["it's"]),
+            ("select * from orders where tenant_id in (1, 2, -3)", &["1", "2", "-3"]),
+            ("select * from orders where tenant_id >= 42", &[]),
+            ("select * from orders where other_tenant_id = 42", &[]),
+            ("insert into orders (id, \"tenant_id\", total) values (1, 5, 10.0), (2, 6, abs(-1))", &["5", "6"]),
+            ("insert into orders (id, tenant_id) values (1, 5 + 1)", &[]),
+            ("update orders set total = 0 where tenant_id = 3; delete from orders where tenant_id = 4", &["3", "4"]),
+            ("select 1", &[]),
+        ];
+        for &(sql, expected) in tests {
+            assert_eq!(shard_keys(&query(sql), "tenant_id").unwrap(), expected, "{}", sql);
+        }
+
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str("select * from orders where tenant_id in (1, 2)");
+        let collapsed = QueryMessage::new_with_options(mb.finish(), NormalizeOptions{collapse_in_lists: true, ..Default::default()}).unwrap();
+        assert_eq!(shard_keys(&collapsed, "tenant_id").unwrap(), &["1", "2"]);
+        assert!(shard_keys(&query("select * from orders where tenant_id = E'acme'"), "tenant_id").unwrap_err().to_string().contains("escape"));
+        assert!(shard_keys(&query("select * from orders where tenant_id in (1, U&'2')"), "tenant_id").is_err());
+    }
+
+    /// Writes a Bind and Execute message for the unnamed statement with the parameters id and tenant_id, in binary format if binary
+    fn add_bind(mb: &mut MessageBuilder, id: &[u8], tenant_id: &[u8], binary: bool) {
+        mb.add_new(Tag::BIND);
+        mb.write_str("");
+        mb.write_str("");
+        mb.write_i16(1);
+        mb.write_i16(binary as i16);
+        mb.write_i16(2);
+        mb.write_i32(id.len() as i32);
+        mb.write_bytes(id);
+        mb.write_i32(tenant_id.len() as i32);
+        mb.write_bytes(tenant_id);
+        mb.write_i16(0);
+        mb.add_new(Tag::EXECUTE);
+        mb.write_str("");
+        mb.write_i32(0);
+    }
+
+    fn bound_query(binds: &[(&[u8], &[u8], bool)]) -> QueryMessage {
+        let mut mb = MessageBuilder::new(Tag::PARSE);
+        mb.write_str("");
+        mb.write_str("select * from orders where id = $1 and tenant_id = $2");
+        mb.write_i16(0);
+        for &(id, tenant_id, binary) in binds {
+            add_bind(&mut mb, id, tenant_id, binary);
+        }
+        mb.add_new(Tag::SYNC);
+        QueryMessage::new(mb.finish()).unwrap()
+    }
+
+    #[test]
+    fn test_bound_shard_keys() {
+        let query = bound_query(&[(b"7", b"42", false)]);
+        assert_eq!(query.bound_params(0).unwrap(), &["7"]);
+        assert!(query.bound_params(2).unwrap().is_empty());
+        assert_eq!(shard_keys(&query, "tenant_id").unwrap(), &["42"]);
+
+        let query = bound_query(&[(b"7", b"42", false), (b"8", b"5000", false)]);
+        assert_eq!(shard_keys(&query, "tenant_id").unwrap(), &["42", "5000"]);
+        let settings = settings(ShardingStrategy::Range, &[(1, None), (2, Some(100)), (3, Some(1000))]);
+        assert!(route_shard(&settings, &query).unwrap_err().to_string().contains("more than one shard"));
+        assert_eq!(route_shard(&settings, &bound_query(&[(b"7", b"1", false), (b"8", b"99", false)])).unwrap(), Some(1));
+
+        let query = bound_query(&[(b"7", b"42", false), (b"\0\0\0\x08", b"\0\0\0\0\0\0\x13\x88", true)]);
+        assert!(shard_keys(&query, "tenant_id").unwrap_err().to_string().contains("binary format"));
+        assert!(route_shard(&settings, &query).is_err());
+    }
+
+    #[test]
+    fn test_route_shard() {
+        let settings = settings(ShardingStrategy::Range, &[(1, None), (2, Some(100)), (3, Some(1000))]);
+        assert_eq!(route_shard(&settings, &query("select * from orders where tenant_id = -5")).unwrap(), Some(1));
+        assert_eq!(route_shard(&settings, &query("select * from orders where tenant_id = 100")).unwrap(), Some(2));
+        assert_eq!(route_shard(&settings, &query("select * from orders where tenant_id in (100, 999)")).unwrap(), Some(2));
+        assert_eq!(route_shard(&settings, &query("select * from orders where tenant_id = 5000")).unwrap(), Some(3));
+        assert_eq!(route_shard(&settings, &query("/* shard_key=1000 */ select * from orders where tenant_id = 1")).unwrap(), Some(3));
+        assert_eq!(route_shard(&settings, &query("select * from orders")).unwrap(), None);
+        assert!(route_shard(&settings, &query("select * from orders where tenant_id in (1, 100)")).unwrap_err().to_string().contains("more than one shard"));
+        assert!(route_shard(&settings, &query("select * from orders where tenant_id = 'acme'")).unwrap_err().to_string().contains("not an integer"));
+
+        let mut settings = self::settings(ShardingStrategy::Hash, &[(0, None), (1, None), (2, None)]);
+        let shard = route_shard(&settings, &query("select * from orders where tenant_id = 'acme'")).unwrap();
+        assert!(shard.is_some());
+        assert_eq!(route_shard(&settings, &query("/* shard_key=acme */ select * from orders")).unwrap(), shard);
+        settings.require_key = true;
+        assert!(route_shard(&settings, &query("select * from orders")).unwrap_err().to_string().contains("no shard key"));
+
+        // The keys of a truncated query may be past the truncation, only the key tag can route it
+        let truncated = |sql: &str| {
+            let mut mb = MessageBuilder::new(Tag::QUERY);
+            mb.write_str(sql);
+            QueryMessage::new_with_options(mb.finish(), NormalizeOptions{max_bytes: 48, ..Default::default()}).unwrap()
+        };
+        let sql = "insert into orders (tenant_id, total) values ('acme', 1), ('other', 2)";
+        assert!(truncated(sql).is_truncated());
+        assert!(route_shard(&settings, &truncated(sql)).unwrap_err().to_string().contains("max_normalize_bytes"));
+        assert_eq!(route_shard(&settings, &truncated(&format!("/* shard_key=acme */ {}", sql))).unwrap(), shard);
+    }
+}
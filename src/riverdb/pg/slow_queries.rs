@@ -0,0 +1,228 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use fnv::FnvHashMap;
+use serde::Serialize;
+use tokio::time::Duration;
+
+use crate::riverdb::pg::sql::Query;
+
+
+/// The number of recent latencies remembered per normalized query for computing percentiles.
+const MAX_SAMPLES: usize = 256;
+
+struct SlowQuery {
+    count: u64,
+    rows: u64,
+    /// the sum of all latencies in microseconds
+    total_micros: u64,
+    /// latencies in microseconds, a ring buffer once full
+    latencies: Vec<u32>,
+    next: usize,
+}
+
+/// The aggregated statistics for the slow executions of one normalized query.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct SlowQuerySummary {
+    /// the normalized query text, the fingerprint the executions are aggregated by
+    pub query: String,
+    /// the number of slow executions
+    pub count: u64,
+    /// the total number of rows returned or affected by the slow executions
+    pub rows: u64,
+    /// the sum of the latencies of the slow executions
+    pub total: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+/// SlowQueryStats aggregates the client queries slower than the slow_query threshold_ms
+/// by their normalized query text, see the slow_query setting, SHOW STATS, and /metrics.
+pub struct SlowQueryStats {
+    queries: Mutex<FnvHashMap<String, SlowQuery>>,
+    max_queries: usize,
+}
+
+impl SlowQueryStats {
+    /// Create a new SlowQueryStats tracking at most max_queries distinct normalized queries.
+    pub fn new(max_queries: u32) -> Self {
+        Self {
+            queries: Mutex::new(FnvHashMap::default()),
+            max_queries: max_queries as usize,
+        }
+    }
+
+    /// Record a slow execution of the normalized query. Returns false if it wasn't recorded
+    /// because max_queries other queries are already tracked.
+    pub fn record(&self, normalized: &str, latency: Duration, rows: u64) -> bool {
+        let micros = latency.as_micros().min(u32::MAX as u128) as u32;
+        let mut queries = self.queries.lock().unwrap_or_else(PoisonError::into_inner);
+        let query = match queries.get_mut(normalized) {
+            Some(query) => query,
+            None => {
+                if queries.len() >= self.max_queries {
+                    return false;
+                }
+                queries.entry(normalized.to_string()).or_insert_with(|| SlowQuery{count: 0, rows: 0, total_micros: 0, latencies: Vec::new(), next: 0})
+            },
+        };
+        query.count += 1;
+        query.rows += rows;
+        query.total_micros += micros as u64;
+        if query.latencies.len() < MAX_SAMPLES {
+            query.latencies.push(micros);
+        } else {
+            let i = query.next;
+            query.latencies[i] = micros;
+            query.next = (i + 1) % MAX_SAMPLES;
+        }
+        true
+    }
+
+    /// Returns the statistics of each normalized query, the most frequent first.
+    pub fn snapshot(&self) -> Vec<SlowQuerySummary> {
+        let mut summaries: Vec<_> = self.queries.lock().unwrap_or_else(PoisonError::into_inner).iter().map(|(normalized, query)| {
+            let mut latencies = query.latencies.clone();
+            latencies.sort_unstable();
+            SlowQuerySummary{
+                query: normalized.clone(),
+                count: query.count,
+                rows: query.rows,
+                total: Duration::from_micros(query.total_micros),
+                p50: percentile(&latencies, 50),
+                p95: percentile(&latencies, 95),
+                p99: percentile(&latencies, 99),
+            }
+        }).collect();
+        summaries.sort_unstable_by(|a, b| b.count.cmp(&a.count).then_with(|| a.query.cmp(&b.query)));
+        summaries
+    }
+
+    /// Forget all recorded queries, see RESET STATS.
+    pub fn clear(&self) {
+        self.queries.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+}
+
+/// PendingQueries tracks the queries a client sent to the database until the requests they end with complete.
+/// The database completes pipelined requests in order, so this attributes the latency of each completed request
+/// to the query it belongs to, rather than the most recent query sent.
+#[derive(Default)]
+pub struct PendingQueries {
+    /// the number of requests sent
+    sent: u64,
+    /// the number of requests completed
+    completed: u64,
+    /// the request that completes each query, and the query
+    pending: VecDeque<(u64, Arc<Query>)>,
+}
+
+impl PendingQueries {
+    /// Called when a query with the given number of requests is sent to the database.
+    pub fn sent(&mut self, requests: u32, query: Arc<Query>) {
+        self.sent += requests as u64;
+        // Extended query protocol messages ending in Flush complete with the next Sync
+        let request = if requests == 0 { self.sent + 1 } else { self.sent };
+        self.pending.push_back((request, query));
+    }
+
+    /// Called when a Sync sent isn't a request, because the database ignores it during COPY FROM STDIN.
+    pub fn ignored(&mut self) {
+        self.sent = self.sent.saturating_sub(1);
+    }
+
+    /// Called when the database completes a request. Returns the query completed by it, if any.
+    /// If it completes several queries sent without a Sync between them, that's the last of them.
+    pub fn completed(&mut self) -> Option<Arc<Query>> {
+        self.completed += 1;
+        let mut completed = None;
+        while let Some(&(request, _)) = self.pending.front() {
+            if request > self.completed {
+                break;
+            }
+            completed = self.pending.pop_front().map(|(_, query)| query);
+        }
+        completed
+    }
+
+    /// Called when the database connection failed, the requests sent but not completed never will be.
+    pub fn abandon(&mut self) {
+        self.completed = self.sent;
+        self.pending.clear();
+    }
+}
+
+/// Returns the pct percentile of the sorted latencies (in microseconds.)
+fn percentile(sorted: &[u32], pct: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let i = (sorted.len() * pct / 100).min(sorted.len() - 1);
+    Duration::from_micros(sorted[i] as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riverdb::pg::protocol::{MessageBuilder, Tag};
+    use crate::riverdb::pg::sql::QueryMessage;
+
+    fn query(sql: &str) -> Arc<Query> {
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str(sql);
+        Arc::new(QueryMessage::new(mb.finish()).unwrap().into_parts().1)
+    }
+
+    #[test]
+    fn test_record() {
+        let stats = SlowQueryStats::new(2);
+        for ms in 1..=100 {
+            assert!(stats.record("SELECT * FROM T WHERE ID = $1", Duration::from_millis(ms), 2));
+        }
+        assert!(stats.record("UPDATE T SET X = $1", Duration::from_millis(5), 1));
+        assert!(!stats.record("DELETE FROM T", Duration::from_millis(5), 1));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].query, "SELECT * FROM T WHERE ID = $1");
+        assert_eq!(snapshot[0].count, 100);
+        assert_eq!(snapshot[0].rows, 200);
+        assert_eq!(snapshot[0].total, Duration::from_millis(5050));
+        assert_eq!(snapshot[0].p50, Duration::from_millis(51));
+        assert_eq!(snapshot[0].p95, Duration::from_millis(96));
+        assert_eq!(snapshot[0].p99, Duration::from_millis(100));
+        assert_eq!(snapshot[1].p99, Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_clear() {
+        let stats = SlowQueryStats::new(1);
+        assert!(stats.record("SELECT 1", Duration::from_millis(5), 1));
+        stats.clear();
+        assert!(stats.snapshot().is_empty());
+        assert!(stats.record("SELECT 2", Duration::from_millis(5), 1));
+    }
+
+    #[test]
+    fn test_pending_queries() {
+        let mut pending = PendingQueries::default();
+        pending.sent(1, query("SELECT 1"));
+        pending.sent(0, query("SELECT 2"));
+        pending.sent(1, query("SELECT 3"));
+        pending.sent(1, query("SELECT 4"));
+        assert_eq!(pending.completed().unwrap().normalized(), "SELECT $1");
+        let q = pending.completed().unwrap();
+        assert_eq!(q.params().len(), 1);
+        assert_eq!(q.param(&q.params()[0]), "3");
+        let q = pending.completed().unwrap();
+        assert_eq!(q.param(&q.params()[0]), "4");
+        assert!(pending.completed().is_none());
+
+        pending.sent(1, query("SELECT 5"));
+        pending.abandon();
+        pending.sent(1, query("SELECT 6"));
+        let q = pending.completed().unwrap();
+        assert_eq!(q.param(&q.params()[0]), "6");
+    }
+}
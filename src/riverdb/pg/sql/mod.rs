@@ -3,7 +3,11 @@ mod query_type;
 #[macro_use]
 mod escape;
 mod normalize;
+mod rewrite;
 
 pub use queries::*;
 pub use query_type::QueryType;
-pub use escape::*;
\ No newline at end of file
+pub use escape::*;
+pub use rewrite::{quote_str, splice_params, tags_comment};
+pub(crate) use normalize::QueryNormalizer;
+pub use normalize::{NormalizeOptions, is_keyword};
\ No newline at end of file
@@ -21,6 +21,84 @@ const REQUIRED_IF_OPERATOR_ENDS_IN_PLUS_OR_MINUS: &'static str = "~!@#%^&|`?";
 // OTHER_OPERATOR_CHARS = ALL_OPERATORS - REQUIRED_IF_OPERATOR_ENDS_IN_PLUS_OR_MINUS
 const OTHER_OPERATOR_CHARS: &'static str = "+-*<>/=";
 
+// Type names made of several words, which can't be parsed as a single identifier
+const MULTI_WORD_TYPES: &[&'static str] = &[
+    "TIMESTAMP WITHOUT TIME ZONE", "TIMESTAMP WITH TIME ZONE", "TIME WITHOUT TIME ZONE", "TIME WITH TIME ZONE",
+    "DOUBLE PRECISION", "CHARACTER VARYING", "BIT VARYING",
+];
+// Types that can precede a string literal as a cast: type 'string'. Since we can't tell keywords
+// from type names in the normalized query, only these are recognized (case-insensitively.)
+const STRING_PREFIX_TYPES: &[&'static str] = &[
+    "DATE", "TIME", "TIMESTAMP", "TIMESTAMPTZ", "TIMETZ", "INTERVAL", "JSON", "JSONB", "UUID", "INET", "CIDR",
+    "MACADDR", "NUMERIC", "DECIMAL", "INT", "INTEGER", "INT2", "INT4", "INT8", "BIGINT", "SMALLINT", "REAL",
+    "FLOAT4", "FLOAT8", "BOOLEAN", "BOOL", "TEXT", "VARCHAR", "CHAR", "BYTEA", "MONEY", "XML", "POINT",
+    "TSQUERY", "TSVECTOR",
+];
+// The key words of PostgreSQL (see Appendix C of the manual), sorted for binary search. Both reserved and
+// unreserved key words are included, since we can't tell from the context which are used as identifiers.
+const KEYWORDS: &[&'static str] = &[
+    "ABORT", "ABSENT", "ABSOLUTE", "ACCESS", "ACTION", "ADD", "ADMIN", "AFTER", "AGGREGATE", "ALL", "ALSO", "ALTER",
+    "ALWAYS", "ANALYSE", "ANALYZE", "AND", "ANY", "ARRAY", "AS", "ASC", "ASENSITIVE", "ASSERTION", "ASSIGNMENT",
+    "ASYMMETRIC", "AT", "ATOMIC", "ATTACH", "ATTRIBUTE", "AUTHORIZATION", "BACKWARD", "BEFORE", "BEGIN", "BETWEEN",
+    "BIGINT", "BINARY", "BIT", "BOOLEAN", "BOTH", "BREADTH", "BY", "CACHE", "CALL", "CALLED", "CASCADE", "CASCADED",
+    "CASE", "CAST", "CATALOG", "CHAIN", "CHAR", "CHARACTER", "CHARACTERISTICS", "CHECK", "CHECKPOINT", "CLASS", "CLOSE",
+    "CLUSTER", "COALESCE", "COLLATE", "COLLATION", "COLUMN", "COLUMNS", "COMMENT", "COMMENTS", "COMMIT", "COMMITTED",
+    "COMPRESSION", "CONCURRENTLY", "CONFIGURATION", "CONFLICT", "CONNECTION", "CONSTRAINT", "CONSTRAINTS", "CONTENT",
+    "CONTINUE", "CONVERSION", "COPY", "COST", "CREATE", "CROSS", "CSV", "CUBE", "CURRENT", "CURRENT_CATALOG",
+    "CURRENT_DATE", "CURRENT_ROLE", "CURRENT_SCHEMA", "CURRENT_TIME", "CURRENT_TIMESTAMP", "CURRENT_USER", "CURSOR",
+    "CYCLE", "DATA", "DATABASE", "DAY", "DEALLOCATE", "DEC", "DECIMAL", "DECLARE", "DEFAULT", "DEFAULTS", "DEFERRABLE",
+    "DEFERRED", "DEFINER", "DELETE", "DELIMITER", "DELIMITERS", "DEPENDS", "DEPTH", "DESC", "DETACH", "DICTIONARY",
+    "DISABLE", "DISCARD", "DISTINCT", "DO", "DOCUMENT", "DOMAIN", "DOUBLE", "DROP", "EACH", "ELSE", "ENABLE",
+    "ENCODING", "ENCRYPTED", "END", "ENUM", "ESCAPE", "EVENT", "EXCEPT", "EXCLUDE", "EXCLUDING", "EXCLUSIVE", "EXECUTE",
+    "EXISTS", "EXPLAIN", "EXPRESSION", "EXTENSION", "EXTERNAL", "EXTRACT", "FALSE", "FAMILY", "FETCH", "FILTER",
+    "FINALIZE", "FIRST", "FLOAT", "FOLLOWING", "FOR", "FORCE", "FOREIGN", "FORMAT", "FORWARD", "FREEZE", "FROM", "FULL",
+    "FUNCTION", "FUNCTIONS", "GENERATED", "GLOBAL", "GRANT", "GRANTED", "GREATEST", "GROUP", "GROUPING", "GROUPS",
+    "HANDLER", "HAVING", "HEADER", "HOLD", "HOUR", "IDENTITY", "IF", "ILIKE", "IMMEDIATE", "IMMUTABLE", "IMPLICIT",
+    "IMPORT", "IN", "INCLUDE", "INCLUDING", "INCREMENT", "INDENT", "INDEX", "INDEXES", "INHERIT", "INHERITS",
+    "INITIALLY", "INLINE", "INNER", "INOUT", "INPUT", "INSENSITIVE", "INSERT", "INSTEAD", "INT", "INTEGER", "INTERSECT",
+    "INTERVAL", "INTO", "INVOKER", "IS", "ISNULL", "ISOLATION", "JOIN", "JSON", "JSON_ARRAY", "JSON_ARRAYAGG",
+    "JSON_OBJECT", "JSON_OBJECTAGG", "JSON_SCALAR", "JSON_SERIALIZE", "KEY", "KEYS", "LABEL", "LANGUAGE", "LARGE",
+    "LAST", "LATERAL", "LEADING", "LEAKPROOF", "LEAST", "LEFT", "LEVEL", "LIKE", "LIMIT", "LISTEN", "LOAD", "LOCAL",
+    "LOCALTIME", "LOCALTIMESTAMP", "LOCATION", "LOCK", "LOCKED", "LOGGED", "MAPPING", "MATCH", "MATCHED",
+    "MATERIALIZED", "MAXVALUE", "MERGE", "METHOD", "MINUTE", "MINVALUE", "MODE", "MONTH", "MOVE", "NAME", "NAMES",
+    "NATIONAL", "NATURAL", "NCHAR", "NEW", "NEXT", "NFC", "NFD", "NFKC", "NFKD", "NO", "NONE", "NORMALIZE",
+    "NORMALIZED", "NOT", "NOTHING", "NOTIFY", "NOTNULL", "NOWAIT", "NULL", "NULLIF", "NULLS", "NUMERIC", "OBJECT", "OF",
+    "OFF", "OFFSET", "OIDS", "OLD", "ON", "ONLY", "OPERATOR", "OPTION", "OPTIONS", "OR", "ORDER", "ORDINALITY",
+    "OTHERS", "OUT", "OUTER", "OVER", "OVERLAPS", "OVERLAY", "OVERRIDING", "OWNED", "OWNER", "PARALLEL", "PARAMETER",
+    "PARSER", "PARTIAL", "PARTITION", "PASSING", "PASSWORD", "PLACING", "PLANS", "POLICY", "POSITION", "PRECEDING",
+    "PRECISION", "PREPARE", "PREPARED", "PRESERVE", "PRIMARY", "PRIOR", "PRIVILEGES", "PROCEDURAL", "PROCEDURE",
+    "PROCEDURES", "PROGRAM", "PUBLICATION", "QUOTE", "RANGE", "READ", "REAL", "REASSIGN", "RECHECK", "RECURSIVE", "REF",
+    "REFERENCES", "REFERENCING", "REFRESH", "REINDEX", "RELATIVE", "RELEASE", "RENAME", "REPEATABLE", "REPLACE",
+    "REPLICA", "RESET", "RESTART", "RESTRICT", "RETURN", "RETURNING", "RETURNS", "REVOKE", "RIGHT", "ROLE", "ROLLBACK",
+    "ROLLUP", "ROUTINE", "ROUTINES", "ROW", "ROWS", "RULE", "SAVEPOINT", "SCALAR", "SCHEMA", "SCHEMAS", "SCROLL",
+    "SEARCH", "SECOND", "SECURITY", "SELECT", "SEQUENCE", "SEQUENCES", "SERIALIZABLE", "SERVER", "SESSION",
+    "SESSION_USER", "SET", "SETOF", "SETS", "SHARE", "SHOW", "SIMILAR", "SIMPLE", "SKIP", "SMALLINT", "SNAPSHOT",
+    "SOME", "SQL", "STABLE", "STANDALONE", "START", "STATEMENT", "STATISTICS", "STDIN", "STDOUT", "STORAGE", "STORED",
+    "STRICT", "STRIP", "SUBSCRIPTION", "SUBSTRING", "SUPPORT", "SYMMETRIC", "SYSID", "SYSTEM", "SYSTEM_USER", "TABLE",
+    "TABLES", "TABLESAMPLE", "TABLESPACE", "TEMP", "TEMPLATE", "TEMPORARY", "TEXT", "THEN", "TIES", "TIME", "TIMESTAMP",
+    "TO", "TRAILING", "TRANSACTION", "TRANSFORM", "TREAT", "TRIGGER", "TRIM", "TRUE", "TRUNCATE", "TRUSTED", "TYPE",
+    "TYPES", "UESCAPE", "UNBOUNDED", "UNCOMMITTED", "UNENCRYPTED", "UNION", "UNIQUE", "UNKNOWN", "UNLISTEN", "UNLOGGED",
+    "UNTIL", "UPDATE", "USER", "USING", "VACUUM", "VALID", "VALIDATE", "VALIDATOR", "VALUE", "VALUES", "VARCHAR",
+    "VARIADIC", "VARYING", "VERBOSE", "VERSION", "VIEW", "VIEWS", "VOLATILE", "WHEN", "WHERE", "WHITESPACE", "WINDOW",
+    "WITH", "WITHIN", "WITHOUT", "WORK", "WRAPPER", "WRITE", "XML", "XMLATTRIBUTES", "XMLCONCAT", "XMLELEMENT",
+    "XMLEXISTS", "XMLFOREST", "XMLNAMESPACES", "XMLPARSE", "XMLPI", "XMLROOT", "XMLSERIALIZE", "XMLTABLE", "YEAR",
+    "YES", "ZONE",
+];
+
+/// Options that change the normalized form of queries.
+#[derive(Default, Copy, Clone, Debug)]
+pub struct NormalizeOptions {
+    /// collapse_in_lists replaces a list of literals in IN (...) with a single $N placeholder for a
+    /// LiteralType::List param, so queries that differ only in the length of the list have the same form.
+    pub collapse_in_lists: bool,
+    /// preserve_identifier_case uppercases only key words, keeping the case of identifiers as written.
+    /// By default identifiers are uppercased as well. Quoted identifiers are never changed.
+    pub preserve_identifier_case: bool,
+    /// max_bytes stops normalizing the message after about this many bytes of SQL, at the end of the token
+    /// being parsed, and marks the query as truncated (see Query::is_truncated.) 0 for no limit.
+    pub max_bytes: u32,
+}
+
 pub(crate) struct QueryNormalizer<'a> {
     src: &'a [u8],
     pos: usize,
@@ -30,6 +108,11 @@ pub(crate) struct QueryNormalizer<'a> {
     last_char_size: u8,
     comment_level: u8,
     query: Query,
+    /// the range of the $N placeholder in the normalized query of each param
+    placeholders: Vec<Range32>,
+    options: NormalizeOptions,
+    /// the position in src after which the query is truncated
+    truncate_at: usize,
 }
 
 impl<'a> QueryNormalizer<'a> {
@@ -51,11 +134,28 @@ impl<'a> QueryNormalizer<'a> {
             current_char_size: 0,
             comment_level: 0,
             query: Query::new(),
+            placeholders: Vec::new(),
+            options: NormalizeOptions{collapse_in_lists: false, preserve_identifier_case: false, max_bytes: 0},
+            truncate_at: usize::MAX,
         }
     }
 
+    /// Set the options that change the normalized form of the query.
+    pub const fn with_options(mut self, options: NormalizeOptions) -> Self {
+        self.options = options;
+        if options.max_bytes != 0 {
+            self.truncate_at = self.pos + options.max_bytes as usize;
+        }
+        self
+    }
+
     pub fn normalize(mut self, tags: &mut Vec<QueryTag>) -> Result<Query> {
         loop {
+            if self.pos >= self.truncate_at && self.peek() != '\0' {
+                debug!(pos = self.pos, "query too large, not normalizing the rest");
+                self.query.truncated = true;
+                break;
+            }
             let mut c = self.next()?;
             //println!("c {}", c);
 
@@ -92,6 +192,9 @@ impl<'a> QueryNormalizer<'a> {
                 res = self.keyword_or_identifier(c);
             } else if c == '(' || c == ')' || c == '[' || c == ']' || c == ',' {
                 self.append_char(c);
+                if c == ')' && self.options.collapse_in_lists {
+                    self.collapse_in_list();
+                }
             } else if c == ';' {
                 self.end_of_query(c, tags)?;
                 break;
@@ -104,8 +207,11 @@ impl<'a> QueryNormalizer<'a> {
             res?;
         }
 
+        self.set_target_types();
         self.query.ty = QueryType::from(self.query.normalized.as_str());
-        self.query.object_ty = ObjectType::parse(self.query.normalized.as_str(), self.query.ty);
+        let (object_ty, object_name) = ObjectType::parse_with_name(self.query.normalized.as_str(), self.query.ty);
+        self.query.object_ty = object_ty;
+        self.query.object_name = object_name;
         Ok(self.query)
     }
 
@@ -312,11 +418,96 @@ impl<'a> QueryNormalizer<'a> {
             value: Range32::new(param_start, self.query.params_buf.len()),
             ty,
             negated,
-            target_type: Range32::default()
+            target_type: Range32::default(),
+            items: Vec::new(),
         });
 
         self.append_char('$');
+        let placeholder_start = self.query.normalized.len() - 1;
         write!(&mut self.query.normalized, "{}", self.query.params.len()).unwrap();
+        self.placeholders.push(Range32::new(placeholder_start, self.query.normalized.len()));
+    }
+
+    /// Replaces the list of literals just closed by ) with a single placeholder, if it follows IN.
+    /// IN($1, $2, $3) becomes IN($1), where $1 is a LiteralType::List param with the literals as its items,
+    /// and the value "1, 2, 3" (the literals separated by commas.)
+    fn collapse_in_list(&mut self) {
+        let normalized = &self.query.normalized;
+        let open = match normalized.rfind('(') {
+            Some(open) => open,
+            None => return,
+        };
+        let before = &normalized[..open];
+        if !before.ends_with("IN") || before[..before.len() - 2].ends_with(is_identifier_char) {
+            return;
+        }
+        // Each item must be the placeholder of one of the last params, in order
+        let count = normalized[open+1..normalized.len()-1].split(", ").count();
+        if count > self.placeholders.len() || count == 0 {
+            return;
+        }
+        let first = self.placeholders.len() - count;
+        let mut expected_start = open + 1;
+        for (i, placeholder) in self.placeholders[first..].iter().enumerate() {
+            let separator = if i == 0 { 0 } else { 2 };
+            if placeholder.start as usize != expected_start + separator || self.query.params[first + i].ty == LiteralType::List {
+                return;
+            }
+            expected_start = placeholder.end as usize;
+        }
+        if expected_start != normalized.len() - 1 {
+            return;
+        }
+
+        let items: Vec<QueryParam> = self.query.params.drain(first..).collect();
+        self.placeholders.truncate(first);
+        self.query.normalized.truncate(open + 1);
+        let value_start = self.query.params_buf.len();
+        for (i, item) in items.iter().enumerate() {
+            if i != 0 {
+                self.query.params_buf.push_str(", ");
+            }
+            if item.negated {
+                self.query.params_buf.push('-');
+            }
+            let value = item.value.as_range();
+            let value = self.query.params_buf[value].to_string();
+            self.query.params_buf.push_str(&value);
+        }
+        self.query.params.push(QueryParam{
+            value: Range32::new(value_start, self.query.params_buf.len()),
+            ty: LiteralType::List,
+            negated: false,
+            target_type: Range32::default(),
+            items,
+        });
+        let placeholder_start = self.query.normalized.len();
+        write!(&mut self.query.normalized, "${}", self.query.params.len()).unwrap();
+        self.placeholders.push(Range32::new(placeholder_start, self.query.normalized.len()));
+        self.query.normalized.push(')');
+    }
+
+    /// Sets the target_type of each param that's cast to a type with 'literal'::type, type 'literal',
+    /// or CAST('literal' AS type). Array brackets are included, type modifiers like (10) aren't.
+    fn set_target_types(&mut self) {
+        let normalized = self.query.normalized.as_str();
+        for (param, placeholder) in self.query.params.iter_mut().zip(&self.placeholders) {
+            let (start, end) = (placeholder.start as usize, placeholder.end as usize);
+            let before = &normalized[..start];
+            let after = &normalized[end..];
+            param.target_type = if after.starts_with("::") {
+                type_name_at(normalized, end + 2)
+            } else if after.starts_with(" AS ") && before.ends_with("CAST(") {
+                type_name_at(normalized, end + 4)
+            } else if param.ty == LiteralType::String {
+                let before = before.trim_end();
+                MULTI_WORD_TYPES.iter().chain(STRING_PREFIX_TYPES)
+                    .find(|ty| ends_with_ignore_case(before, ty) && !before[..before.len() - ty.len()].ends_with(is_identifier_char))
+                    .map_or(Range32::default(), |ty| Range32::new(before.len() - ty.len(), before.len()))
+            } else {
+                Range32::default()
+            };
+        }
     }
 
     /// appends a NULL literal to params
@@ -425,7 +616,9 @@ impl<'a> QueryNormalizer<'a> {
                 // Normalize the next query, and link to it from this one
                 // This process continues recursively until the entire query has been parsed.
                 self.backup();
-                let next = Self::new_at(self.src, self.pos);
+                let mut next = Self::new_at(self.src, self.pos).with_options(self.options);
+                // The size limit applies to the whole message, not each query
+                next.truncate_at = self.truncate_at;
                 self.query.next = Some(Box::new(next.normalize(tags)?));
                 break;
             }?;
@@ -438,6 +631,7 @@ impl<'a> QueryNormalizer<'a> {
         debug_assert_eq!(c, '/', "c must start a c-style comment");
 
         let start = self.pos;
+        let tag_count = tags.len();
         let mut tag = QueryTag::new();
 
         loop {
@@ -472,9 +666,12 @@ impl<'a> QueryNormalizer<'a> {
                     if c.is_ascii_alphabetic() || c == '.' || c == '-' || c == '_' {
                         i -= 1;
                     } else {
-                        tag.key.start = (i + 1) as u32;
-                        tag.key.end = self.pos as u32 - 1;
-                        tag.val.start = self.pos as u32;
+                        // Not a tag if there's no key, e.g. a = b
+                        if i + 1 < self.pos - 1 {
+                            tag.key.start = (i + 1) as u32;
+                            tag.key.end = self.pos as u32 - 1;
+                            tag.val.start = self.pos as u32;
+                        }
                         break;
                     }
                 }
@@ -493,14 +690,26 @@ impl<'a> QueryNormalizer<'a> {
             }
         }
 
+        // Comments of only tags are expected after literals, e.g. from sqlcommenter
+        if tags.len() == tag_count || !is_only_tags(&self.src[start + 1..self.pos - 2]) {
+            self.note_comment();
+        }
         Ok(())
     }
 
+    /// marks the query as having a comment after a literal, if there were any literals before it.
+    fn note_comment(&mut self) {
+        if !self.query.params.is_empty() {
+            self.query.comment_after_literal = true;
+        }
+    }
+
     fn sql_comment(&mut self, mut c: char) -> Result<()> {
         let c2 = self.next()?;
         // Guaranteed by caller
         debug_assert_eq!(c, '-');
         debug_assert_eq!(c2, '-');
+        self.note_comment();
 
         // Look for a newline or EOF indicating the end of the comment
         // (it's always possible to scan to the end, so this always succeeds)
@@ -537,6 +746,10 @@ impl<'a> QueryNormalizer<'a> {
                     // and it was preceded by an odd number of backslashes.
                     if ty == LiteralType::EscapeString && backslashes%2 != 0 {
                         backslashes = 0;
+                    } else if self.peek() == '\'' {
+                        // A doubled single quote is an escaped quote inside the string
+                        self.next()?;
+                        backslashes = 0;
                     } else {
                         break;
                     }
@@ -580,6 +793,14 @@ impl<'a> QueryNormalizer<'a> {
         debug_assert_eq!(c, '$', "c must start a single quoted string");
 
         let start = self.pos - 1;
+        if self.peek().is_ascii_digit() {
+            // A positional parameter ($1) in a prepared statement, keep it as-is
+            while self.peek().is_ascii_digit() {
+                self.next()?;
+            }
+            self.append_token(&self.src[start..self.pos]);
+            return Ok(());
+        }
         return match self.tail().iter().position(|b| *b == '$' as u8) {
             Some(mut i) => {
                 i += 1; // include the $
@@ -658,7 +879,8 @@ impl<'a> QueryNormalizer<'a> {
     }
 
     fn operator(&mut self, mut c: char) -> Result<()> {
-        if c == '.' {
+        if c == '.' || c == ':' {
+            // A dotted identifier, a :: cast, or an array slice
             self.append_char(c);
             return Ok(());
         }
@@ -731,7 +953,13 @@ impl<'a> QueryNormalizer<'a> {
         }
 
         self.backup();
-        self.append_token_uppercase(&self.src[start..self.pos]);
+        let tok = &self.src[start..self.pos];
+        // Safety: we already parsed this as valid utf8
+        if self.options.preserve_identifier_case && !is_keyword(unsafe { std::str::from_utf8_unchecked(tok) }) {
+            self.append_token(tok);
+        } else {
+            self.append_token_uppercase(tok);
+        }
 
         Ok(())
     }
@@ -824,10 +1052,58 @@ impl<'a> QueryNormalizer<'a> {
     }
 }
 
+/// Returns true if word is a PostgreSQL key word, ignoring case.
+pub fn is_keyword(word: &str) -> bool {
+    KEYWORDS.binary_search_by(|kw| kw.bytes().cmp(word.bytes().map(|b| b.to_ascii_uppercase()))).is_ok()
+}
+
+/// Returns true if s ends with suffix, ignoring ASCII case.
+fn ends_with_ignore_case(s: &str, suffix: &str) -> bool {
+    s.len() >= suffix.len() && s.is_char_boundary(s.len() - suffix.len())
+        && s[s.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+}
+
+/// Returns true if c can be part of an unquoted identifier in the normalized query
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+/// Returns the range of the type name starting at start in the normalized query, possibly schema qualified
+/// or quoted, followed by any array brackets.
+fn type_name_at(normalized: &str, start: usize) -> Range32 {
+    let rest = &normalized[start..];
+    let mut len = match MULTI_WORD_TYPES.iter().find(|ty| rest.starts_with(**ty)) {
+        Some(ty) => ty.len(),
+        None => {
+            let mut quoted = false;
+            rest.find(|c: char| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                !quoted && !is_identifier_char(c) && c != '"' && c != '.'
+            }).unwrap_or(rest.len())
+        },
+    };
+    while rest[len..].starts_with("[]") {
+        len += 2;
+    }
+    Range32::new(start, start + len)
+}
+
 fn append_tag(tags: &mut Vec<QueryTag>, tag: &mut QueryTag) {
     debug_assert_ne!(tag.key_len(), 0);
     debug_assert!(tag.val.start > tag.key.end);
     debug_assert!(tag.val.end >= tag.val.start);
 
     tags.push(std::mem::take(tag));
-}
\ No newline at end of file
+}
+/// Returns true if the body of a comment is only key=value tags, separated by whitespace or commas.
+/// Keys use the same characters as the tags parsed by c_style_comment.
+fn is_only_tags(body: &[u8]) -> bool {
+    body.split(|&b| b.is_ascii_whitespace() || b == b',')
+        .filter(|token| !token.is_empty())
+        .all(|token| match token.iter().position(|&b| b == b'=') {
+            Some(i) => i != 0 && token[..i].iter().all(|&b| b.is_ascii_alphabetic() || b == b'.' || b == b'-' || b == b'_'),
+            None => false,
+        })
+}
@@ -1,11 +1,12 @@
 use std::fmt::{Debug, Formatter};
 use std::ops::Range;
 
-use crate::riverdb::Result;
-use crate::riverdb::pg::protocol::{Tag, Messages};
+use crate::riverdb::{Error, Result};
+use crate::riverdb::pg::protocol::{Tag, Message, Messages, MessageBuilder};
 use crate::riverdb::pg::sql::QueryType;
-use crate::riverdb::pg::sql::normalize::QueryNormalizer;
+use crate::riverdb::pg::sql::normalize::{QueryNormalizer, NormalizeOptions};
 use crate::riverdb::common::Range32;
+use crate::riverdb::pg::{referenced_tables, IsolationLevel, TransactionOptions};
 
 /// The type of object targeted by DDL queries like ALTER, DROP, CREATE
 #[derive(Eq, PartialEq, Debug, Clone, Copy)]
@@ -17,19 +18,128 @@ pub enum ObjectType {
     Schema,
     Index,
     Sequence,
-    Function,
+    Function, // includes PROCEDURE
+    View,
+    MaterializedView,
+    Role, // includes USER and GROUP
+    Type,
+    Extension,
+    Trigger,
 }
 
+/// The keywords that can come between CREATE and the type of object
+const CREATE_MODIFIERS: &[&str] = &["OR REPLACE", "UNIQUE", "LOCAL", "GLOBAL", "TEMPORARY", "TEMP", "UNLOGGED", "RECURSIVE"];
+
+/// The keywords naming each ObjectType
+const OBJECT_KEYWORDS: &[(&str, ObjectType)] = &[
+    ("TABLE", ObjectType::Table),
+    ("DATABASE", ObjectType::Database),
+    ("SCHEMA", ObjectType::Schema),
+    ("INDEX", ObjectType::Index),
+    ("SEQUENCE", ObjectType::Sequence),
+    ("FUNCTION", ObjectType::Function),
+    ("PROCEDURE", ObjectType::Function),
+    ("VIEW", ObjectType::View),
+    ("MATERIALIZED VIEW", ObjectType::MaterializedView),
+    ("ROLE", ObjectType::Role),
+    ("USER", ObjectType::Role),
+    ("GROUP", ObjectType::Role),
+    ("TYPE", ObjectType::Type),
+    ("EXTENSION", ObjectType::Extension),
+    ("TRIGGER", ObjectType::Trigger),
+];
+
 impl ObjectType {
     /// Return the ObjectType affected by the query
     /// given it's normalized form and QueryType.
-    /// Not Implemented (always returns Other.)
+    /// Returns Other for queries other than ALTER, CREATE, or DROP, or unrecognized objects.
     pub fn parse(normalized_query: &str, ty: QueryType) -> ObjectType {
-        match ty {
-            QueryType::Alter | QueryType::Create | QueryType::Drop => ObjectType::Other, // TODO
-            _ => ObjectType::Other, // TODO mostly Table with some exceptions
+        Self::parse_with_name(normalized_query, ty).0
+    }
+
+    /// Like parse, but also returns the range of the name of the (first) object in normalized_query,
+    /// which is empty if there's no name (e.g. CREATE INDEX ON t.)
+    pub fn parse_with_name(normalized_query: &str, ty: QueryType) -> (ObjectType, Range32) {
+        let verb = match ty {
+            QueryType::Alter => "ALTER",
+            QueryType::Create => "CREATE",
+            QueryType::Drop => "DROP",
+            _ => return (ObjectType::Other, Range32::default()),
+        };
+        let mut rest = match normalized_query.strip_prefix(verb) {
+            Some(rest) => rest.trim_start(),
+            None => return (ObjectType::Other, Range32::default()),
+        };
+        if ty == QueryType::Create {
+            while let Some(modifier) = CREATE_MODIFIERS.iter().find(|m| starts_with_keyword(rest, m)) {
+                rest = rest[modifier.len()..].trim_start();
+            }
+        }
+        if starts_with_keyword(rest, "USER MAPPING") {
+            // Not a role, the options for a user on a foreign server
+            return (ObjectType::Other, Range32::default());
+        }
+        let (keyword, object_ty) = match OBJECT_KEYWORDS.iter().find(|(keyword, _)| starts_with_keyword(rest, keyword)) {
+            Some(&(keyword, object_ty)) => (keyword, object_ty),
+            None => return (ObjectType::Other, Range32::default()),
+        };
+        rest = rest[keyword.len()..].trim_start();
+        for option in ["CONCURRENTLY", "IF NOT EXISTS", "IF EXISTS", "ONLY"] {
+            if starts_with_keyword(rest, option) {
+                rest = rest[option.len()..].trim_start();
+            }
+        }
+        if starts_with_keyword(rest, "ON") {
+            // An unnamed index
+            return (object_ty, Range32::default());
+        }
+        let start = normalized_query.len() - rest.len();
+        let mut quoted = false;
+        let len = rest.find(|c: char| {
+            if c == '"' {
+                quoted = !quoted;
+            }
+            !quoted && (c.is_whitespace() || c == '(' || c == ',' || c == ';')
+        }).unwrap_or(rest.len());
+        (object_ty, Range32::new(start, start + len))
+    }
+}
+
+/// Returns the names in the comma separated list of objects of a DROP statement, given the normalized query
+/// from the first name, e.g. FILMS, "Actors" CASCADE. The list ends at CASCADE, RESTRICT, or the end of the statement.
+fn drop_names(s: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                names.push(s[start..i].trim());
+                start = i + 1;
+            },
+            ';' if !quoted => break,
+            _ => (),
         }
     }
+    let mut last = s[start..].split(';').next().unwrap_or("").trim();
+    for option in [" CASCADE", " RESTRICT"] {
+        last = last.strip_suffix(option).unwrap_or(last);
+    }
+    names.push(last.trim());
+    names.retain(|name| !name.is_empty());
+    names
+}
+
+/// Returns true if s starts with keyword, followed by the end of s or a character that can't be part of the keyword.
+fn starts_with_keyword(s: &str, keyword: &str) -> bool {
+    s.starts_with(keyword) && !s[keyword.len()..].starts_with(|c: char| c.is_alphanumeric() || c == '_' || c == '$')
+}
+
+/// Returns true if s contains the uppercase ASCII needle, ignoring case. Identifiers that aren't
+/// key words may be lowercase in the normalized query (see NormalizeOptions::preserve_identifier_case.)
+fn contains_ignore_case(s: &str, needle: &str) -> bool {
+    s.as_bytes().windows(needle.len()).any(|w| w.eq_ignore_ascii_case(needle.as_bytes()))
 }
 
 /// Represents type of a SQL literal value (string, null, numeric, integer, boolean)
@@ -44,7 +154,9 @@ pub enum LiteralType {
     Integer,
     Numeric,
     BitString,
-    Boolean
+    Boolean,
+    /// a list of literals in IN (...), see NormalizeOptions::collapse_in_lists
+    List,
 }
 
 /// A QueryParam represents a query parameter or literal value
@@ -55,6 +167,8 @@ pub struct QueryParam {
     pub ty: LiteralType,
     pub negated: bool,
     pub target_type: Range32, // type name in casts: type 'string', 'string'::type, and CAST ( 'string' AS type )
+    /// the literals in a LiteralType::List, otherwise empty
+    pub items: Vec<QueryParam>,
 }
 
 impl QueryParam {
@@ -63,8 +177,7 @@ impl QueryParam {
         &params_buf[self.value.as_range()]
     }
 
-    /// If there's a target type, get it as a string from the normalized query
-    /// TODO Not implemented, always returns ""
+    /// If there's a target type, get it as a string from the normalized query, otherwise ""
     pub fn target_type<'a>(&self, normalized: &'a str) -> &'a str {
         if self.target_type.is_empty() {
             ""
@@ -139,9 +252,14 @@ pub struct Query {
     pub params_buf: String,
     pub normalized: String,
     pub ty: QueryType,
-    /// Not Implemented (always is set to Other.)
     pub object_ty: ObjectType,
+    /// the name of the object affected by ALTER, CREATE, or DROP queries, as a range in normalized
+    pub object_name: Range32,
     pub params: Vec<QueryParam>,
+    /// true if the normalizer stopped before the end of the query, see is_truncated
+    pub truncated: bool,
+    /// true if a comment without tags follows a literal, see has_comment_after_literal
+    pub comment_after_literal: bool,
     pub next: Option<Box<Query>>
 }
 
@@ -153,7 +271,10 @@ impl Query {
             normalized: String::new(),
             ty: QueryType::Other,
             object_ty: ObjectType::Other,
+            object_name: Range32::default(),
             params: Vec::new(),
+            truncated: false,
+            comment_after_literal: false,
             next: None,
         }
     }
@@ -161,20 +282,38 @@ impl Query {
     /// Return the query type.
     pub fn query_type(&self) -> QueryType { self.ty }
 
+    /// Return true if this is a simple read (SELECT, SHOW, VALUES), ignoring any queries following it.
+    pub fn is_simple_read(&self) -> bool {
+        matches!(self.ty, QueryType::Select | QueryType::Show | QueryType::Values)
+    }
+
+    /// Returns the isolation level requested by a BEGIN, START TRANSACTION, or SET TRANSACTION query,
+    /// or IsolationLevel::None if it doesn't specify one.
+    pub fn isolation_level(&self) -> IsolationLevel {
+        match self.ty {
+            QueryType::Begin | QueryType::SetTransaction => TransactionOptions::parse(&self.normalized).isolation,
+            _ => IsolationLevel::None,
+        }
+    }
+
     /// Returns the object type affected for ALTER, CREATE, or DROP queries
-    /// Not Implemented (always returns Other.)
     pub fn object_type(&self) -> ObjectType {
         self.object_ty
     }
 
+    /// Returns the name of the (first) object affected by ALTER, CREATE, or DROP queries as it appears
+    /// in the normalized query, possibly schema qualified or quoted. Empty if unknown.
+    pub fn object_name(&self) -> &str {
+        &self.normalized[self.object_name.as_range()]
+    }
+
     /// Returns the normalized query. Keywords are made uppercase
     /// and query parameters are replaced with $N placeholders.
     /// All whitespace is collapsed to single spaces.
     ///
     /// Note: currently the algorithm is not perfect, it uppercases
-    /// tables, columns, and other identifiers, and it can confuse
-    /// a unary - with subtraction in some cases if whitespace is unusual.
-    /// These are known limitations that will be addressed in a future release.
+    /// tables, columns, and other identifiers unless NormalizeOptions::preserve_identifier_case
+    /// is set, and it can confuse a unary - with subtraction in some cases if whitespace is unusual.
     pub fn normalized(&self) -> &str {
         &self.normalized
     }
@@ -184,35 +323,160 @@ impl Query {
         &self.params
     }
 
+    /// Returns true if a comment (other than one with query tags) follows a literal in this query or
+    /// one following it. That's unusual in application queries, but typical of SQL injection, where a
+    /// comment in an injected string literal comments out the rest of the query.
+    pub fn has_comment_after_literal(&self) -> bool {
+        let mut query = Some(self);
+        while let Some(q) = query {
+            if q.comment_after_literal {
+                return true;
+            }
+            query = q.next.as_deref();
+        }
+        false
+    }
+
+    /// Returns true if this query, or one following it, was too large to normalize completely
+    /// (see NormalizeOptions::max_bytes.) Such queries are unfingerprinted: the normalized query and params
+    /// only cover the start of the SQL, so they can't be used as a cache key or to rewrite the query,
+    /// and any queries, query tags, or session state changes after that point aren't seen.
+    pub fn is_truncated(&self) -> bool {
+        let mut query = Some(self);
+        while let Some(q) = query {
+            if q.truncated {
+                return true;
+            }
+            query = q.next.as_deref();
+        }
+        false
+    }
+
     /// Returns the value of the specified QueryParam which must have been returned by self.params()
     pub fn param(&self, param: &QueryParam) -> &str {
         param.value(self.params_buf.as_str())
     }
+
+    /// Returns the specified QueryParam as it appeared in the query, including the quotes
+    /// and the - of negative numbers, which makes it valid SQL for splice_params.
+    /// For a LiteralType::List that's the literals separated by commas.
+    pub fn param_literal(&self, param: &QueryParam) -> String {
+        if param.negated {
+            format!("-{}", self.param(param))
+        } else {
+            self.param(param).to_string()
+        }
+    }
+
+    /// Returns true if this query or any following it create state scoped to the database session, which
+    /// is lost if the backend connection is returned to the pool: temporary tables, prepared statements,
+    /// cursors WITH HOLD, and session level advisory locks.
+    pub fn creates_session_state(&self) -> bool {
+        let mut query = Some(self);
+        while let Some(q) = query {
+            let normalized = q.normalized();
+            let creates = match q.query_type() {
+                QueryType::Create => {
+                    let rest = normalized.strip_prefix("CREATE").unwrap_or("").trim_start();
+                    let rest = rest.strip_prefix("LOCAL ").or_else(|| rest.strip_prefix("GLOBAL ")).unwrap_or(rest).trim_start();
+                    rest.starts_with("TEMP ") || rest.starts_with("TEMPORARY ")
+                },
+                QueryType::Prepare => true,
+                QueryType::Cursor => normalized.starts_with("DECLARE") && normalized.contains(" WITH HOLD "),
+                // Not the pg_advisory_xact_lock functions, which are released at the end of the transaction
+                _ => contains_ignore_case(normalized, "PG_ADVISORY_LOCK") || contains_ignore_case(normalized, "PG_TRY_ADVISORY_LOCK"),
+            };
+            if creates {
+                return true;
+            }
+            query = q.next.as_deref();
+        }
+        false
+    }
+
+    /// Returns the tables referenced by this query and any queries following it, as they appear
+    /// in the normalized query (see referenced_tables.) For ALTER, CREATE, or DROP of a table or view
+    /// that's the name of the object (see object_name.) Sorted and deduplicated.
+    pub fn tables(&self) -> Vec<&str> {
+        let mut tables = Vec::new();
+        let mut query = Some(self);
+        while let Some(q) = query {
+            match q.object_type() {
+                ObjectType::Table | ObjectType::View | ObjectType::MaterializedView if !q.object_name().is_empty() => {
+                    if q.query_type() == QueryType::Drop {
+                        // DROP can name several objects, object_name is the first of them
+                        tables.extend(drop_names(&q.normalized()[q.object_name.start as usize..]));
+                    } else {
+                        tables.push(q.object_name());
+                    }
+                },
+                _ => tables.extend(referenced_tables(q.normalized())),
+            }
+            query = q.next.as_deref();
+        }
+        tables.sort_unstable();
+        tables.dedup();
+        tables
+    }
 }
 
 /// Represents a single wire message containing one or more SQL queries
 pub struct QueryMessage {
     msgs: Messages,
     query: Query,
+    parsed: Vec<Query>, // the queries of the Parse messages after the first, see statements
     pub tags: Vec<QueryTag>, // indices that point into msgs.as_slice()
 }
 
 impl QueryMessage {
-    /// Create a new Query object from a Messages buffer where the first
-    /// message contains the SQL query.
+    /// Create a new Query object from a Messages buffer containing either a single Query message,
+    /// or a group of extended query protocol messages (Parse, Bind, Describe, Execute, Close, ending
+    /// with Sync or Flush.) For the extended protocol the SQL is taken from the first Parse message,
+    /// if there is one, otherwise the query is empty (e.g. executing an existing prepared statement.)
+    /// The SQL of any later Parse messages is normalized as well, see statements.
     pub fn new(msgs: Messages) -> Result<Self> {
-        debug_assert_eq!(msgs.count(), 1);
+        Self::new_with_options(msgs, NormalizeOptions::default())
+    }
 
-        let msg = msgs.first().unwrap();
+    /// Like new, but normalizes the query with the given options.
+    pub fn new_with_options(msgs: Messages, options: NormalizeOptions) -> Result<Self> {
         let mut tags: Vec<QueryTag> = Vec::new();
-        let query = if msg.tag() == Tag::QUERY {
-            let normalizer = QueryNormalizer::new(&msg);
-            normalizer.normalize(&mut tags)?
-        } else {
-            Query::new()
-        };
+        let mut query = Query::new();
+        let mut parsed = Vec::new();
+        let mut has_parse = false;
+        for msg in msgs.iter(0) {
+            match msg.tag() {
+                Tag::QUERY => {
+                    debug_assert_eq!(msgs.count(), 1);
+                    let normalizer = QueryNormalizer::new(&msg).with_options(options);
+                    query = normalizer.normalize(&mut tags)?;
+                    break;
+                },
+                Tag::PARSE => {
+                    let mut r = msg.reader();
+                    r.read_str()?; // skip the prepared statement name
+                    let normalizer = QueryNormalizer::new_at(msgs.as_slice(), msg.offset() + r.tell() as usize).with_options(options);
+                    // A pipelining client can prepare several statements in one group, each must be checked
+                    if has_parse {
+                        parsed.push(normalizer.normalize(&mut tags)?);
+                    } else {
+                        query = normalizer.normalize(&mut tags)?;
+                        has_parse = true;
+                    }
+                },
+                _ => (),
+            }
+        }
+
+        Ok(Self{msgs, query, parsed, tags})
+    }
 
-        Ok(Self{msgs, query, tags})
+    /// Returns every statement in the message: those of the Query message, or of each Parse message
+    /// for the extended protocol. query() only has the statements of the first Parse message.
+    pub fn statements(&self) -> impl Iterator<Item=&Query> {
+        std::iter::once(&self.query)
+            .chain(self.parsed.iter())
+            .flat_map(|query| std::iter::successors(Some(query), |q| q.next.as_deref()))
     }
 
     /// Return true if this query is actually multiple queries separated by ;
@@ -221,16 +485,199 @@ impl QueryMessage {
         self.query.next.is_some()
     }
 
+    /// Return true if this is a simple Query message, rather than extended query protocol messages.
+    pub fn is_simple_query(&self) -> bool {
+        self.msgs.first().map_or(false, |msg| msg.tag() == Tag::QUERY)
+    }
+
+    /// Return true if any statement in the message is truncated, see Query::is_truncated.
+    /// Policies can't check what comes after the truncation, and the SQL can't be rewritten.
+    pub fn is_truncated(&self) -> bool {
+        self.statements().any(|q| q.truncated)
+    }
+
+    /// Return true if this message contains only simple reads (SELECT, SHOW, VALUES)
+    /// which don't change the session state. Calls to functions like set_config in a SELECT are not detected.
+    pub fn is_simple_read(&self) -> bool {
+        self.statements().all(|q| q.is_simple_read())
+    }
+
+    /// Returns the name and query of the prepared statement of each Parse message, in order.
+    pub fn parsed_statements(&self) -> impl Iterator<Item=(&str, &Query)> {
+        let names = self.msgs.iter(0)
+            .filter(|msg| msg.tag() == Tag::PARSE)
+            .map(|msg| self.statement_name(&msg).unwrap_or(""))
+            .collect::<Vec<_>>();
+        names.into_iter().zip(std::iter::once(&self.query).chain(self.parsed.iter()))
+    }
+
+    /// Returns the name of the prepared statement of each Bind message, in order.
+    pub fn bound_statements(&self) -> Vec<&str> {
+        self.msgs.iter(0)
+            .filter(|msg| msg.tag() == Tag::BIND)
+            .map(|msg| self.statement_name(&msg).unwrap_or(""))
+            .collect()
+    }
+
+    /// Returns the names of the prepared statements closed by Close messages.
+    pub fn closed_statements(&self) -> Vec<&str> {
+        self.msgs.iter(0)
+            .filter(|msg| msg.tag() == Tag::CLOSE)
+            .filter_map(|msg| self.statement_name(&msg))
+            .collect()
+    }
+
+    /// Returns the prepared statement name of a Parse or Bind message, or of a Close message for a
+    /// prepared statement. Returns None for other messages, or if the name can't be read.
+    fn statement_name(&self, msg: &Message) -> Option<&str> {
+        let mut r = msg.reader();
+        match msg.tag() {
+            Tag::PARSE => (),
+            Tag::BIND => {
+                r.read_str().ok()?; // skip the portal name
+            },
+            Tag::CLOSE => {
+                if r.read_byte() != b'S' {
+                    return None; // closes a portal
+                }
+            },
+            _ => return None,
+        }
+        let start = msg.offset() + r.tell() as usize;
+        let len = r.read_str().ok()?.len();
+        std::str::from_utf8(&self.msgs.as_slice()[start..start + len]).ok()
+    }
+
+    /// Returns the number of requests (Query or Sync messages) in the message, each is answered with a ReadyForQuery.
+    pub fn request_count(&self) -> u32 {
+        self.msgs.iter(0).filter(|msg| msg.tag() == Tag::QUERY || msg.tag() == Tag::SYNC).count() as u32
+    }
+
     /// Return the query.
     pub fn query(&self) -> &Query {
         &self.query
     }
 
+    /// Return a reference to the underlying Messages buffer containing the query
+    pub fn messages(&self) -> &Messages {
+        &self.msgs
+    }
+
     /// Return the underlying Messages buffer containing the query
     pub fn into_messages(self) -> Messages {
         self.msgs
     }
 
+    /// Return the underlying Messages buffer and the parsed Query
+    pub fn into_parts(self) -> (Messages, Query) {
+        (self.msgs, self.query)
+    }
+
+    /// Returns the key and value of each tag in the comments of the query, in order
+    pub fn tags(&self) -> Vec<(&str, &str)> {
+        let msg_body = self.msgs.as_slice();
+        self.tags.iter().map(|tag| (tag.key(msg_body), tag.value(msg_body))).collect()
+    }
+
+    /// Returns a new QueryMessage with the SQL replaced by sql, e.g. for client_rewrite_query.
+    /// A simple query is replaced by a Query message with sql. For the extended protocol, the SQL
+    /// of the Parse message is replaced and the other messages are kept. Returns an error if there's
+    /// no Parse message.
+    pub fn with_sql(&self, sql: &str) -> Result<Self> {
+        if self.is_simple_query() {
+            let mut mb = MessageBuilder::new(Tag::QUERY);
+            mb.write_str(sql);
+            return Self::new(mb.finish());
+        }
+        let mut mb: Option<MessageBuilder> = None;
+        let mut replaced = false;
+        for msg in self.msgs.iter(0) {
+            match mb.as_mut() {
+                Some(mb) => mb.add_new(msg.tag()),
+                None => mb = Some(MessageBuilder::new(msg.tag())),
+            }
+            let mb = mb.as_mut().unwrap();
+            if msg.tag() == Tag::PARSE && !replaced {
+                let mut r = msg.reader();
+                let name = r.read_str()?;
+                r.read_str()?; // the old SQL
+                mb.write_str(name);
+                mb.write_str(sql);
+                mb.write_bytes(&msg.as_slice()[r.tell() as usize..]);
+                replaced = true;
+            } else {
+                mb.write_bytes(msg.body());
+            }
+        }
+        match mb {
+            Some(mb) if replaced => Self::new(mb.finish()),
+            _ => Err(Error::new("can't replace the SQL of extended query protocol messages without a Parse message")),
+        }
+    }
+
+    /// Returns the SQL of the Query message, or of the first Parse message, as sent by the client.
+    /// Returns None if there's no SQL (e.g. executing an existing prepared statement.)
+    pub fn sql(&self) -> Option<&str> {
+        for msg in self.msgs.iter(0) {
+            let mut r = msg.reader();
+            match msg.tag() {
+                Tag::QUERY => (),
+                Tag::PARSE => {
+                    r.read_str().ok()?; // skip the prepared statement name
+                },
+                _ => continue,
+            }
+            let start = msg.offset() + r.tell() as usize;
+            let len = r.read_str().ok()?.len();
+            return std::str::from_utf8(&self.msgs.as_slice()[start..start + len]).ok();
+        }
+        None
+    }
+
+    /// Returns the values of the bound parameter at index i (for placeholder $i+1) in every Bind message,
+    /// skipping Bind messages where it's missing or NULL. Returns an error if it's in binary format, or isn't utf8.
+    pub fn bound_params(&self, i: usize) -> Result<Vec<&str>> {
+        let mut values = Vec::new();
+        for msg in self.msgs.iter(0).filter(|msg| msg.tag() == Tag::BIND) {
+            let mut r = msg.reader();
+            r.read_str()?; // the portal name
+            r.read_str()?; // the prepared statement name
+            let num_formats = r.read_i16().max(0) as usize;
+            let mut format = 0; // text
+            for j in 0..num_formats {
+                let code = r.read_i16();
+                if j == i || (num_formats == 1 && j == 0) {
+                    format = code;
+                }
+            }
+            let num_params = r.read_i16().max(0) as usize;
+            r.error()?;
+            if i >= num_params {
+                continue;
+            }
+            if format != 0 {
+                return Err(Error::new(format!("bound parameter ${} is in binary format", i + 1)));
+            }
+            for _ in 0..i {
+                let len = r.read_i32();
+                if len > 0 {
+                    r.advance(len as u32)?;
+                }
+            }
+            let len = r.read_i32();
+            r.error()?;
+            if len < 0 {
+                continue;
+            }
+            let start = msg.offset() + r.tell() as usize;
+            r.read_bytes(len as u32)?;
+            let value = std::str::from_utf8(&self.msgs.as_slice()[start..start + len as usize])
+                .map_err(|_| Error::new(format!("bound parameter ${} is not valid utf8", i + 1)))?;
+            values.push(value);
+        }
+        Ok(values)
+    }
+
     /// Returns the value of the named tag (ascii case-insensitive) or None
     pub fn tag(&self, name: &str) -> Option<&str> {
         let msg_body = self.msgs.as_slice();
@@ -243,6 +690,12 @@ impl QueryMessage {
     }
 }
 
+impl Debug for Query {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.normalized)
+    }
+}
+
 impl Debug for QueryMessage {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         Debug::fmt(&self.msgs, f)
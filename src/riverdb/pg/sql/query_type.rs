@@ -1,8 +1,9 @@
-use strum::Display;
+use strum::{Display, EnumString};
 
 /// An enum of SQL query types
 /// (see https://www.postgresql.org/docs/current/sql-commands.html for more info)
-#[derive(Display, Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Display, EnumString, Debug, Clone, Copy, Eq, PartialEq)]
+#[strum(ascii_case_insensitive)]
 #[repr(u8)]
 pub enum QueryType {
     Other,
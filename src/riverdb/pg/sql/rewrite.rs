@@ -0,0 +1,166 @@
+use crate::riverdb::Result;
+use crate::riverdb::pg::protocol::{MessageBuilder, Tag};
+use crate::riverdb::pg::sql::{Query, QueryMessage};
+
+
+/// Returns s as a safely escaped single-quoted SQL string literal, like escape_str.
+/// Use it to build the params for splice_params from untrusted values.
+pub fn quote_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('\'');
+    for c in s.chars() {
+        if c == '\'' {
+            out.push('\''); // double it up to escape it
+        }
+        out.push(c);
+    }
+    out.push('\'');
+    out
+}
+
+/// Returns normalized with each $N placeholder replaced by params[N-1], which must be valid SQL:
+/// a literal from Query::param_literal, or a value quoted with quote_str. Placeholders without a param
+/// (e.g. the positional parameters of a prepared statement) and $N inside quoted identifiers are kept.
+pub fn splice_params(normalized: &str, params: &[&str]) -> String {
+    let mut out = String::with_capacity(normalized.len() + params.iter().map(|p| p.len()).sum::<usize>());
+    let bytes = normalized.as_bytes();
+    let mut quoted = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c == b'"' {
+            quoted = !quoted;
+        } else if c == b'$' && !quoted && !out.ends_with(|prev: char| prev.is_alphanumeric() || prev == '_' || prev == '$') {
+            let digits = bytes[i+1..].iter().take_while(|b| b.is_ascii_digit()).count();
+            let param = normalized[i+1..i+1+digits].parse::<usize>().ok()
+                .and_then(|n| n.checked_sub(1))
+                .and_then(|n| params.get(n));
+            if let Some(param) = param {
+                // Don't turn a - followed by a negative number into a -- comment
+                if out.ends_with('-') && param.starts_with('-') {
+                    out.push(' ');
+                }
+                out.push_str(param);
+                i += 1 + digits;
+                continue;
+            }
+        }
+        // Copy the whole utf8 char starting at i
+        let len = normalized[i..].chars().next().map_or(1, |c| c.len_utf8());
+        out.push_str(&normalized[i..i+len]);
+        i += len;
+    }
+    out
+}
+
+/// Returns a c-style comment with tags as key=value pairs, which riverdb parses back out of the query
+/// (see QueryMessage::tag.) Characters in the tags that would end a tag or the comment are replaced with _.
+pub fn tags_comment(tags: &[(&str, &str)]) -> String {
+    let mut out = String::from("/*");
+    for (key, value) in tags {
+        out.push(' ');
+        out.extend(key.chars().map(|c| if c.is_ascii_alphabetic() || c == '.' || c == '-' || c == '_' { c } else { '_' }));
+        out.push('=');
+        out.extend(value.chars().map(|c| if c.is_whitespace() || c == '"' || c == '*' || c == '/' { '_' } else { c }));
+    }
+    out.push_str(" */");
+    out
+}
+
+impl Query {
+    /// Returns the SQL of this query and any queries following it, with the literal parameters
+    /// spliced back into the normalized query. Comments (and so tags) are not included.
+    /// Not for queries with positional parameters ($1), which can't be distinguished from the
+    /// placeholders of literals in the normalized query.
+    pub fn to_sql(&self) -> String {
+        let mut sql = String::new();
+        let mut query = Some(self);
+        while let Some(q) = query {
+            if !sql.is_empty() {
+                sql.push_str("; ");
+            }
+            sql.push_str(&q.statement_sql());
+            query = q.next.as_deref();
+        }
+        sql
+    }
+
+    /// Returns the SQL of just this query, not the queries following it, see to_sql.
+    fn statement_sql(&self) -> String {
+        let literals: Vec<String> = self.params().iter().map(|param| self.param_literal(param)).collect();
+        let literals: Vec<&str> = literals.iter().map(String::as_str).collect();
+        splice_params(self.normalized(), &literals)
+    }
+}
+
+impl QueryMessage {
+    /// Splits a simple Query message with multiple statements into a Query message per statement,
+    /// see split_multi_statement_queries. The tags of the query are copied to each statement.
+    pub fn split(&self) -> Result<Vec<QueryMessage>> {
+        let tags = self.tags();
+        let comment = if tags.is_empty() { String::new() } else { tags_comment(&tags) + " " };
+        let mut statements = Vec::new();
+        let mut query = Some(self.query());
+        while let Some(q) = query {
+            let mut mb = MessageBuilder::new(Tag::QUERY);
+            mb.write_str(&(comment.clone() + &q.statement_sql()));
+            statements.push(QueryMessage::new(mb.finish())?);
+            query = q.next.as_deref();
+        }
+        Ok(statements)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riverdb::pg::sql::QueryType;
+
+    fn parse(sql: &str) -> QueryMessage {
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str(sql);
+        QueryMessage::new(mb.finish()).unwrap()
+    }
+
+    #[test]
+    fn test_quote_str() {
+        assert_eq!(quote_str("Robert'); DROP TABLE students;--"), "'Robert''); DROP TABLE students;--'");
+    }
+
+    #[test]
+    fn test_splice_params() {
+        assert_eq!(splice_params("SELECT * FROM T WHERE A = $1 AND B = $2", &["'x'", "42"]), "SELECT * FROM T WHERE A = 'x' AND B = 42");
+        assert_eq!(splice_params("SELECT $1, $3", &["1"]), "SELECT 1, $3");
+        assert_eq!(splice_params("SELECT \"$1\", A$1, $1", &["1"]), "SELECT \"$1\", A$1, 1");
+        assert_eq!(splice_params("SELECT 5 -$1", &["-1"]), "SELECT 5 - -1");
+        assert_eq!(splice_params("SELECT 'é', $1", &["'ü'"]), "SELECT 'é', 'ü'");
+    }
+
+    #[test]
+    fn test_to_sql() {
+        let query = parse("select * from films where title = 'it''s' and len > -90 and kind = E'd\\'r'; select null, true, $$x$$");
+        assert_eq!(query.query().to_sql(), "SELECT * FROM FILMS WHERE TITLE = 'it''s' AND LEN > -90 AND KIND = E'd\\'r'; SELECT NULL, TRUE, $$x$$");
+        let rewritten = parse(&query.query().to_sql());
+        assert_eq!(rewritten.query().normalized(), query.query().normalized());
+    }
+
+    #[test]
+    fn test_split() {
+        let query = parse("/* team=billing */ insert into t values ('a;b'); select 1;");
+        let statements = query.split().unwrap();
+        assert_eq!(statements.len(), 2);
+        assert_eq!(statements[0].query().normalized(), "INSERT INTO T VALUES($1)");
+        assert_eq!(statements[0].query().query_type(), QueryType::Insert);
+        assert_eq!(statements[1].query().to_sql(), "SELECT 1");
+        assert!(statements.iter().all(|s| !s.is_multi_query() && s.tag("team") == Some("billing")));
+    }
+
+    #[test]
+    fn test_tags_comment() {
+        let comment = tags_comment(&[("team", "billing"), ("route", "/a b */ c")]);
+        assert_eq!(comment, "/* team=billing route=_a_b____c */");
+        let query = parse(&format!("{} SELECT 1", comment));
+        assert_eq!(query.tag("team"), Some("billing"));
+        assert_eq!(query.tag("route"), Some("_a_b____c"));
+    }
+}
@@ -0,0 +1,187 @@
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+
+use fnv::FnvHashMap;
+use tracing::{warn};
+
+use crate::riverdb::config::PostgresCluster;
+use crate::riverdb::common::coarse_monotonic_now;
+
+
+/// Expired entries are pruned from the bans map when it grows beyond this many entries.
+const PRUNE_BANS_THRESHOLD: usize = 10000;
+
+/// Counters for clients that misbehaved before completing startup and authentication.
+#[derive(Default)]
+pub struct StartupStats {
+    oversized_packets: AtomicU64,
+    timeouts: AtomicU64,
+    bans: AtomicU64,
+    rejected_banned: AtomicU64,
+    old_protocol: AtomicU64,
+}
+
+/// A point-in-time copy of StartupStats
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct StartupStatsSnapshot {
+    /// number of clients dropped for sending a message larger than max_startup_packet_bytes before authenticating
+    pub oversized_packets: u64,
+    /// number of clients dropped for not completing startup and authentication within startup_timeout_seconds
+    pub timeouts: u64,
+    /// number of times an IP address was temporarily banned
+    pub bans: u64,
+    /// number of connections closed because the IP address was banned
+    pub rejected_banned: u64,
+    /// number of clients rejected for using the unsupported protocol version 2.0
+    pub old_protocol: u64,
+}
+
+struct BanEntry {
+    violations: u32,
+    /// coarse_monotonic_now() of the last violation
+    last_violation: u32,
+    /// coarse_monotonic_now() when the ban expires, or 0 if not banned
+    banned_until: u32,
+}
+
+/// StartupGuard protects the pre-authentication phase of client connections against oversized
+/// startup packets and slowloris clients (that trickle in bytes to hold connections open),
+/// and optionally temporarily bans IP addresses of repeat offenders. Each PostgresCluster has its own.
+pub struct StartupGuard {
+    config: &'static PostgresCluster,
+    bans: Mutex<FnvHashMap<IpAddr, BanEntry>>,
+    stats: StartupStats,
+}
+
+impl StartupGuard {
+    /// Create a new StartupGuard with the limits from config.
+    pub fn new(config: &'static PostgresCluster) -> Self {
+        Self {
+            config,
+            bans: Mutex::new(FnvHashMap::default()),
+            stats: StartupStats::default(),
+        }
+    }
+
+    /// Returns true if connections from ip are currently banned. Counts the rejected connection if so.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        if self.config.ban_seconds == 0 {
+            return false;
+        }
+        let bans = self.bans.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(entry) = bans.get(&ip) {
+            if entry.banned_until > coarse_monotonic_now() {
+                self.stats.rejected_banned.fetch_add(1, Relaxed);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Record a client that sent a message larger than max_startup_packet_bytes before authenticating.
+    pub fn oversized_packet(&self, ip: Option<IpAddr>) {
+        self.stats.oversized_packets.fetch_add(1, Relaxed);
+        self.violation(ip);
+    }
+
+    /// Record a client that didn't complete startup and authentication within startup_timeout_seconds.
+    pub fn timed_out(&self, ip: Option<IpAddr>) {
+        self.stats.timeouts.fetch_add(1, Relaxed);
+        self.violation(ip);
+    }
+
+    /// Record a client that was rejected for using the unsupported protocol version 2.0.
+    /// This isn't a violation, old clients are not malicious.
+    pub fn old_protocol(&self) {
+        self.stats.old_protocol.fetch_add(1, Relaxed);
+    }
+
+    /// Return a snapshot of the counters
+    pub fn stats(&self) -> StartupStatsSnapshot {
+        StartupStatsSnapshot {
+            oversized_packets: self.stats.oversized_packets.load(Relaxed),
+            timeouts: self.stats.timeouts.load(Relaxed),
+            bans: self.stats.bans.load(Relaxed),
+            rejected_banned: self.stats.rejected_banned.load(Relaxed),
+            old_protocol: self.stats.old_protocol.load(Relaxed),
+        }
+    }
+
+    /// Record a violation for ip, banning it for ban_seconds if it reaches ban_after_violations
+    /// violations, each within ban_seconds of the last.
+    fn violation(&self, ip: Option<IpAddr>) {
+        let ip = match ip {
+            Some(ip) if self.config.ban_seconds != 0 => ip,
+            _ => return,
+        };
+
+        let now = coarse_monotonic_now();
+        let ban_seconds = self.config.ban_seconds;
+        let mut bans = self.bans.lock().unwrap_or_else(PoisonError::into_inner);
+        if bans.len() >= PRUNE_BANS_THRESHOLD {
+            bans.retain(|_, entry| entry.banned_until > now || entry.last_violation + ban_seconds > now);
+        }
+
+        let entry = bans.entry(ip).or_insert(BanEntry{violations: 0, last_violation: now, banned_until: 0});
+        if entry.last_violation + ban_seconds <= now {
+            entry.violations = 0;
+        }
+        entry.violations += 1;
+        entry.last_violation = now;
+        if entry.violations >= self.config.ban_after_violations {
+            entry.violations = 0;
+            entry.banned_until = now + ban_seconds;
+            self.stats.bans.fetch_add(1, Relaxed);
+            warn!(%ip, ban_seconds, "temporarily banning ip address after repeated startup violations");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn guard(ban_seconds: u32, ban_after_violations: u32) -> StartupGuard {
+        let conf = Box::leak(Box::new(PostgresCluster::default()));
+        conf.ban_seconds = ban_seconds;
+        conf.ban_after_violations = ban_after_violations;
+        StartupGuard::new(conf)
+    }
+
+    #[test]
+    fn test_ban_after_violations() {
+        let guard = guard(60, 2);
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        guard.timed_out(Some(ip));
+        assert!(!guard.is_banned(ip));
+        guard.oversized_packet(Some(ip));
+        assert!(guard.is_banned(ip));
+        assert!(!guard.is_banned(other));
+        guard.old_protocol();
+
+        assert_eq!(guard.stats(), StartupStatsSnapshot{
+            oversized_packets: 1,
+            timeouts: 1,
+            bans: 1,
+            rejected_banned: 1,
+            old_protocol: 1,
+        });
+    }
+
+    #[test]
+    fn test_bans_disabled() {
+        let guard = guard(0, 1);
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        guard.timed_out(Some(ip));
+        guard.timed_out(None);
+        assert!(!guard.is_banned(ip));
+        assert_eq!(guard.stats().timeouts, 2);
+        assert_eq!(guard.stats().bans, 0);
+    }
+}
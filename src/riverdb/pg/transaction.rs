@@ -1,5 +1,8 @@
 use strum::Display;
 
+use crate::riverdb::pg::IsolationLevel;
+use crate::riverdb::pg::sql::{Query, QueryType};
+
 
 #[derive(Display, Debug, Clone, Copy, Eq, PartialEq)]
 #[repr(u8)]
@@ -15,23 +18,126 @@ pub enum TransactionType {
 }
 
 impl TransactionType {
+    /// Parse the TransactionType from a normalized BEGIN, START TRANSACTION, or SET TRANSACTION query.
     pub fn parse_from_query(normalized_query: &str) -> Self {
-        if normalized_query.contains("READ ONLY") {
-            Self::ReadOnly
-        } else if let Some(i) = normalized_query.find("COMMITTED") {
-            if (&normalized_query[..i]).ends_with("UN") {
-                Self::ReadUncommitted
-            } else {
-                Self::ReadCommitted
-            }
-        } else if normalized_query.contains("REPEATABLE READ") {
-            Self::RepeatableRead
-        } else if normalized_query.contains("SERIALIZABLE") {
-            Self::Serializable
-        } else if normalized_query.contains("SNAPSHOT") {
-            Self::Snapshot
+        TransactionOptions::parse(normalized_query).transaction_type()
+    }
+
+    /// Returns the TransactionType after applying options from a SET TRANSACTION inside this transaction.
+    /// Options not specified in opts leave the corresponding part of the type unchanged.
+    pub fn with_options(self, opts: &TransactionOptions) -> Self {
+        match opts.read_only {
+            Some(true) => return Self::ReadOnly,
+            Some(false) if self == Self::ReadOnly => return opts.transaction_type(),
+            _ => (),
+        }
+        if self == Self::ReadOnly || (opts.isolation == IsolationLevel::None && !opts.snapshot) {
+            self
         } else {
-            Self::Default
+            opts.transaction_type()
+        }
+    }
+}
+
+/// The transaction modes specified in a BEGIN, START TRANSACTION, or SET TRANSACTION query.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct TransactionOptions {
+    /// the ISOLATION LEVEL, or IsolationLevel::None if not specified
+    pub isolation: IsolationLevel,
+    /// Some(true) for READ ONLY, Some(false) for READ WRITE, None if not specified
+    pub read_only: Option<bool>,
+    /// Some(true) for DEFERRABLE, Some(false) for NOT DEFERRABLE, None if not specified
+    pub deferrable: Option<bool>,
+    /// true for SET TRANSACTION SNAPSHOT
+    pub snapshot: bool,
+}
+
+impl TransactionOptions {
+    /// Parse the transaction modes from a normalized BEGIN, START TRANSACTION, or SET TRANSACTION query.
+    /// Unrecognized words are ignored. If a mode is given more than once, the last one wins.
+    pub fn parse(normalized_query: &str) -> Self {
+        let mut opts = Self::default();
+        let mut words = normalized_query
+            .split(|c: char| c.is_ascii_whitespace() || c == ',' || c == ';')
+            .filter(|w| !w.is_empty());
+        while let Some(word) = words.next() {
+            match word {
+                "ISOLATION" => {
+                    if words.next() != Some("LEVEL") {
+                        continue;
+                    }
+                    opts.isolation = match words.next() {
+                        Some("SERIALIZABLE") => IsolationLevel::Serializable,
+                        Some("REPEATABLE") => {
+                            words.next(); // READ
+                            IsolationLevel::RepeatableRead
+                        },
+                        Some("READ") => match words.next() {
+                            Some("COMMITTED") => IsolationLevel::ReadCommitted,
+                            Some("UNCOMMITTED") => IsolationLevel::ReadUncommitted,
+                            _ => opts.isolation,
+                        },
+                        _ => opts.isolation,
+                    };
+                },
+                "READ" => {
+                    match words.next() {
+                        Some("ONLY") => opts.read_only = Some(true),
+                        Some("WRITE") => opts.read_only = Some(false),
+                        _ => (),
+                    }
+                },
+                "NOT" => {
+                    if words.next() == Some("DEFERRABLE") {
+                        opts.deferrable = Some(false);
+                    }
+                },
+                "DEFERRABLE" => opts.deferrable = Some(true),
+                "SNAPSHOT" => opts.snapshot = true,
+                _ => (),
+            }
+        }
+        opts
+    }
+
+    /// Returns command (BEGIN or SET TRANSACTION) with these transaction modes, e.g. to rewrite a query with
+    /// different modes. SNAPSHOT is omitted, it can't be combined with the other modes.
+    pub fn to_sql(&self, command: &str) -> String {
+        let mut modes = Vec::new();
+        if self.isolation != IsolationLevel::None {
+            modes.push(format!("ISOLATION LEVEL {}", self.isolation.as_sql()));
+        }
+        match self.read_only {
+            Some(true) => modes.push("READ ONLY".to_string()),
+            Some(false) => modes.push("READ WRITE".to_string()),
+            None => (),
+        }
+        match self.deferrable {
+            Some(true) => modes.push("DEFERRABLE".to_string()),
+            Some(false) => modes.push("NOT DEFERRABLE".to_string()),
+            None => (),
+        }
+        if modes.is_empty() {
+            return command.to_string();
+        }
+        format!("{} {}", command, modes.join(", "))
+    }
+
+    /// Returns the TransactionType for a new transaction started with these options.
+    /// READ ONLY takes precedence over the isolation level.
+    pub fn transaction_type(&self) -> TransactionType {
+        if let Some(true) = self.read_only {
+            return TransactionType::ReadOnly;
+        }
+        if self.snapshot {
+            return TransactionType::Snapshot;
+        }
+        match self.isolation {
+            IsolationLevel::None => TransactionType::Default,
+            IsolationLevel::ReadUncommitted => TransactionType::ReadUncommitted,
+            IsolationLevel::ReadCommitted => TransactionType::ReadCommitted,
+            IsolationLevel::RepeatableRead => TransactionType::RepeatableRead,
+            IsolationLevel::Serializable => TransactionType::Serializable,
         }
     }
 }
@@ -40,4 +146,135 @@ impl Default for TransactionType {
     fn default() -> Self {
         TransactionType::None
     }
-}
\ No newline at end of file
+}
+
+/// The active savepoints of a transaction, innermost last, tracked from the SAVEPOINT,
+/// RELEASE SAVEPOINT, and ROLLBACK TO SAVEPOINT statements sent in it.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Savepoints(Vec<String>);
+
+impl Savepoints {
+    /// Returns the number of active savepoints.
+    pub fn depth(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Forget all savepoints, e.g. because the transaction ended.
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// Update the savepoints from the statements of query. Statements naming a savepoint that doesn't
+    /// exist are ignored, they fail the transaction without changing its savepoints.
+    pub fn apply(&mut self, query: &Query) {
+        let mut statement = Some(query);
+        while let Some(q) = statement {
+            let name = q.normalized().trim_end_matches(';').split_ascii_whitespace().last().unwrap_or("");
+            // RELEASE and ROLLBACK TO refer to the most recent savepoint with the name
+            let pos = self.0.iter().rposition(|savepoint| savepoint.eq_ignore_ascii_case(name));
+            match q.query_type() {
+                QueryType::Savepoint => self.0.push(name.to_string()),
+                // Releases the savepoint and all savepoints established after it
+                QueryType::ReleaseSavepoint => if let Some(pos) = pos {
+                    self.0.truncate(pos);
+                },
+                // Destroys the savepoints established after it, but keeps the savepoint
+                QueryType::RollbackSavepoint => if let Some(pos) = pos {
+                    self.0.truncate(pos + 1);
+                },
+                QueryType::Begin | QueryType::Commit | QueryType::Rollback | QueryType::PrepareTransaction => self.0.clear(),
+                _ => (),
+            }
+            statement = q.next.as_deref();
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riverdb::pg::protocol::{MessageBuilder, Tag};
+    use crate::riverdb::pg::sql::QueryMessage;
+
+    #[test]
+    fn test_parse_transaction_options() {
+        let tests = &[
+            ("BEGIN", TransactionOptions::default(), TransactionType::Default),
+            ("START TRANSACTION READ ONLY", TransactionOptions{read_only: Some(true), ..Default::default()}, TransactionType::ReadOnly),
+            ("BEGIN ISOLATION LEVEL SERIALIZABLE, READ ONLY, DEFERRABLE",
+             TransactionOptions{isolation: IsolationLevel::Serializable, read_only: Some(true), deferrable: Some(true), snapshot: false},
+             TransactionType::ReadOnly),
+            ("BEGIN TRANSACTION ISOLATION LEVEL REPEATABLE READ READ WRITE NOT DEFERRABLE",
+             TransactionOptions{isolation: IsolationLevel::RepeatableRead, read_only: Some(false), deferrable: Some(false), snapshot: false},
+             TransactionType::RepeatableRead),
+            ("BEGIN ISOLATION LEVEL READ UNCOMMITTED", TransactionOptions{isolation: IsolationLevel::ReadUncommitted, ..Default::default()}, TransactionType::ReadUncommitted),
+            ("SET TRANSACTION ISOLATION LEVEL READ COMMITTED", TransactionOptions{isolation: IsolationLevel::ReadCommitted, ..Default::default()}, TransactionType::ReadCommitted),
+            ("SET TRANSACTION SNAPSHOT $1", TransactionOptions{snapshot: true, ..Default::default()}, TransactionType::Snapshot),
+        ];
+
+        for (query, opts, ty) in tests {
+            let parsed = TransactionOptions::parse(query);
+            assert_eq!(&parsed, opts, "{}", query);
+            assert_eq!(parsed.transaction_type(), *ty, "{}", query);
+            assert_eq!(TransactionType::parse_from_query(query), *ty, "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_transaction_type_with_options() {
+        let read_only = TransactionOptions::parse("SET TRANSACTION READ ONLY");
+        let read_write = TransactionOptions::parse("SET TRANSACTION READ WRITE");
+        let serializable = TransactionOptions::parse("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE");
+
+        assert_eq!(TransactionType::Default.with_options(&read_only), TransactionType::ReadOnly);
+        assert_eq!(TransactionType::ReadOnly.with_options(&read_write), TransactionType::Default);
+        assert_eq!(TransactionType::ReadOnly.with_options(&serializable), TransactionType::ReadOnly);
+        assert_eq!(TransactionType::Default.with_options(&serializable), TransactionType::Serializable);
+        assert_eq!(TransactionType::RepeatableRead.with_options(&read_write), TransactionType::RepeatableRead);
+    }
+
+    #[test]
+    fn test_isolation_level() {
+        let tests = &[
+            ("BEGIN", IsolationLevel::None),
+            ("BEGIN ISOLATION LEVEL SERIALIZABLE", IsolationLevel::Serializable),
+            ("start transaction read only, isolation level repeatable read", IsolationLevel::RepeatableRead),
+            ("SET TRANSACTION ISOLATION LEVEL READ COMMITTED", IsolationLevel::ReadCommitted),
+            ("SELECT 'ISOLATION LEVEL SERIALIZABLE'", IsolationLevel::None),
+        ];
+        for &(sql, isolation) in tests {
+            let mut mb = MessageBuilder::new(Tag::QUERY);
+            mb.write_str(sql);
+            assert_eq!(QueryMessage::new(mb.finish()).unwrap().query().isolation_level(), isolation, "{}", sql);
+        }
+        assert!(IsolationLevel::Serializable > IsolationLevel::RepeatableRead);
+        assert!(IsolationLevel::ReadUncommitted > IsolationLevel::None);
+
+        let mut opts = TransactionOptions::parse("BEGIN ISOLATION LEVEL SERIALIZABLE READ ONLY DEFERRABLE");
+        opts.isolation = IsolationLevel::RepeatableRead;
+        assert_eq!(opts.to_sql("BEGIN"), "BEGIN ISOLATION LEVEL REPEATABLE READ, READ ONLY, DEFERRABLE");
+        assert_eq!(TransactionOptions::default().to_sql("BEGIN"), "BEGIN");
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let mut savepoints = Savepoints::default();
+        let tests = &[
+            ("BEGIN", 0),
+            ("SAVEPOINT a", 1),
+            ("SAVEPOINT b; SAVEPOINT c", 3),
+            ("ROLLBACK TO SAVEPOINT b", 2),
+            ("ROLLBACK TO c", 2),
+            ("SAVEPOINT a", 3),
+            ("RELEASE a", 2),
+            ("RELEASE SAVEPOINT a", 0),
+            ("SAVEPOINT d", 1),
+            ("COMMIT", 0),
+        ];
+        for &(sql, depth) in tests {
+            let mut mb = MessageBuilder::new(Tag::QUERY);
+            mb.write_str(sql);
+            savepoints.apply(QueryMessage::new(mb.finish()).unwrap().query());
+            assert_eq!(savepoints.depth(), depth, "{}", sql);
+        }
+    }
+}
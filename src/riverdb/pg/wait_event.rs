@@ -0,0 +1,66 @@
+use serde::Serialize;
+use strum::Display;
+
+use crate::riverdb::server::Connections;
+use crate::riverdb::pg::ClientConn;
+
+
+/// What a client session is currently waiting on, the proxy equivalent of Postgres wait events.
+/// Shown by SHOW ACTIVITY and counted per event by wait_event_counts.
+#[derive(Display, Serialize, Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[repr(u8)]
+pub enum WaitEvent {
+    /// Not waiting, riverdb is processing a message from the client
+    None,
+    /// Waiting for the client to send a message
+    ClientRead,
+    /// Waiting on the TLS handshake with the client
+    TlsHandshake,
+    /// Waiting in line for a connection from a pool at max_connections
+    PoolSlot,
+    /// Waiting for a new backend connection to be established and authenticated
+    BackendConnect,
+    /// Waiting for the database to respond to a query
+    BackendResponse,
+}
+
+impl WaitEvent {
+    /// All the WaitEvents, in declaration order.
+    pub const ALL: [WaitEvent; 6] = [
+        WaitEvent::None,
+        WaitEvent::ClientRead,
+        WaitEvent::TlsHandshake,
+        WaitEvent::PoolSlot,
+        WaitEvent::BackendConnect,
+        WaitEvent::BackendResponse,
+    ];
+}
+
+impl Default for WaitEvent {
+    fn default() -> Self {
+        WaitEvent::None
+    }
+}
+
+/// Returns the number of client sessions waiting on each WaitEvent, in the order of WaitEvent::ALL.
+pub fn wait_event_counts(clients: &Connections<ClientConn>) -> [(WaitEvent, usize); 6] {
+    let mut counts = WaitEvent::ALL.map(|event| (event, 0));
+    clients.for_each(|client| {
+        counts[client.wait_event() as usize].1 += 1;
+        false
+    });
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_all() {
+        for (i, event) in WaitEvent::ALL.iter().enumerate() {
+            assert_eq!(*event as usize, i);
+        }
+        assert_eq!(WaitEvent::BackendResponse.to_string(), "BackendResponse");
+    }
+}
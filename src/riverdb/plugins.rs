@@ -1,21 +1,128 @@
-pub trait Plugin: Sized {
+use std::sync::Mutex;
+
+use tokio::runtime::Handle;
+
+use crate::riverdb::Result;
+use crate::riverdb::config::Settings;
+
+
+/// Plugin is implemented by the singleton types registered with event_listener!.
+/// Besides handling events, a plugin is notified of the server lifecycle, in plugin order:
+/// configure when the settings are loaded, start when the server starts, and shutdown
+/// (in reverse order) during graceful shutdown.
+pub trait Plugin: Send + Sync {
     fn order(&self) -> i32 { 0 }
+
+    /// configure is called once after loading the settings and before starting the server.
+    /// Use settings.get_plugin_config to read the plugin's own settings.
+    /// If it returns an error, the server doesn't start.
+    fn configure(&self, _settings: &'static Settings) -> Result<()> { Ok(()) }
+
+    /// start is called once when the server starts, with a handle to the tokio runtime
+    /// for spawning any background tasks (e.g. exporters.)
+    fn start(&self, _runtime: &Handle) {}
+
+    /// shutdown is called once when the server shuts down gracefully (on SIGTERM or SIGINT), before the process exits.
+    /// Flush any buffered state (e.g. audit logs) here, otherwise it's lost.
+    fn shutdown(&self) {}
 }
 
 pub static mut CONFIGURED_PLUGINS: bool = false;
-static mut CONFIGURE_PLUGINS: Vec<unsafe fn()> = Vec::new();
+static mut EVENT_DEFINITIONS: Vec<EventDefinition> = Vec::new();
+/// The plugins registered with event_listener!, each once, for the lifecycle hooks
+static LIFECYCLE_PLUGINS: Mutex<Vec<&'static dyn Plugin>> = Mutex::new(Vec::new());
+
+/// The functions for managing the listeners of an event defined with define_event!.
+pub struct EventDefinition {
+    /// name of the event
+    pub name: &'static str,
+    /// orders the registered listeners, see the configure function of the event module
+    pub configure: unsafe fn(),
+    /// returns the plugin name of each listener, and if it's enabled
+    pub listeners: fn() -> Vec<(&'static str, bool)>,
+    /// enables or disables the listeners of the named plugin, returns the number changed
+    pub set_enabled: fn(&str, bool) -> usize,
+}
 
-pub unsafe fn register_plugin_definition(configure: unsafe fn()) {
-    CONFIGURE_PLUGINS.push(configure);
+pub unsafe fn register_plugin_definition(definition: EventDefinition) {
+    EVENT_DEFINITIONS.push(definition);
 }
 
 pub unsafe fn configure() {
-    for f in &CONFIGURE_PLUGINS {
-        (*f)()
+    for definition in &EVENT_DEFINITIONS {
+        (definition.configure)()
     }
     CONFIGURED_PLUGINS = true;
 }
 
+/// event_listeners returns the event name, plugin name, and whether it's enabled for
+/// each listener of every event, with the listeners of an event in the order they're invoked.
+pub fn event_listeners() -> Vec<(&'static str, &'static str, bool)> {
+    // Safety: EVENT_DEFINITIONS is only modified before main() starts
+    let definitions = unsafe { &EVENT_DEFINITIONS };
+    let mut result = Vec::new();
+    for definition in definitions.iter() {
+        for (plugin, enabled) in (definition.listeners)() {
+            result.push((definition.name, plugin, enabled));
+        }
+    }
+    result
+}
+
+/// set_listener_enabled enables or disables the named plugin (case-insensitive) for the named event,
+/// or for all events if event is None. A disabled listener is skipped when the event is dispatched,
+/// as if it called next, which allows bypassing a misbehaving plugin without restarting the server.
+/// Returns the number of listeners changed.
+pub fn set_listener_enabled(plugin: &str, event: Option<&str>, enabled: bool) -> usize {
+    // Safety: EVENT_DEFINITIONS is only modified before main() starts
+    let definitions = unsafe { &EVENT_DEFINITIONS };
+    definitions.iter()
+        .filter(|definition| event.map_or(true, |event| definition.name.eq_ignore_ascii_case(event)))
+        .map(|definition| (definition.set_enabled)(plugin, enabled))
+        .sum()
+}
+
+/// register_plugin adds plugin to the plugins notified of lifecycle events, if it's not already
+/// registered. It's called by event_listener! for each event the plugin handles.
+pub fn register_plugin(plugin: &'static dyn Plugin) {
+    let mut plugins = LIFECYCLE_PLUGINS.lock().unwrap();
+    let addr = plugin as *const dyn Plugin as *const u8;
+    if !plugins.iter().any(|p| *p as *const dyn Plugin as *const u8 == addr) {
+        plugins.push(plugin);
+    }
+}
+
+/// Returns the registered plugins, sorted by order.
+fn lifecycle_plugins() -> Vec<&'static dyn Plugin> {
+    let mut plugins = LIFECYCLE_PLUGINS.lock().unwrap().clone();
+    plugins.sort_by_key(|p| p.order());
+    plugins
+}
+
+/// configure_plugins calls Plugin::configure for each registered plugin, in order.
+/// Returns the first error, if any.
+pub fn configure_plugins(settings: &'static Settings) -> Result<()> {
+    for plugin in lifecycle_plugins() {
+        plugin.configure(settings)?;
+    }
+    Ok(())
+}
+
+/// start_plugins calls Plugin::start for each registered plugin, in order.
+pub fn start_plugins(runtime: &Handle) {
+    for plugin in lifecycle_plugins() {
+        plugin.start(runtime);
+    }
+}
+
+/// shutdown_plugins calls Plugin::shutdown for each registered plugin, in reverse order,
+/// so plugins shut down after the plugins that come after them in the chain.
+pub fn shutdown_plugins() {
+    for plugin in lifecycle_plugins().into_iter().rev() {
+        plugin.shutdown();
+    }
+}
+
 #[macro_export]
 macro_rules! define_event {
     (
@@ -33,18 +140,56 @@ macro_rules! define_event {
             // We need to be able to return impl dyn Future here to avoid boxing.
             type Plugin<$l> = fn(ctx: &$l mut Event, $event_src: &$l Source, $($arg: $arg_ty),*) -> std::pin::Pin<Box<dyn std::future::Future<Output=$result> + Send + Sync + $l>>;
 
+            /// Filter is a cheap synchronous check of the event source that returns false to skip a plugin
+            /// function for this event, without allocating its boxed Future. See event_listener!.
+            pub type Filter = fn(&Source) -> bool;
+
+            /// A registered plugin function with the name of the plugin type, which can be disabled at runtime
+            struct Listener {
+                name: &'static str,
+                enabled: std::sync::atomic::AtomicBool,
+                f: Plugin<'static>,
+                filter: Option<Filter>,
+            }
+
+            impl Listener {
+                /// Returns true if the plugin function is enabled and its filter (if any) accepts src
+                #[inline]
+                fn accepts(&self, src: &Source) -> bool {
+                    self.enabled.load(std::sync::atomic::Ordering::Relaxed) && self.filter.map_or(true, |filter| filter(src))
+                }
+            }
+
             // See notes on register for safety
-            static mut PLUGINS: Vec<Plugin<'static>> = Vec::new();
-            static mut PLUGINS_UNORDERED: Vec<(i32, Plugin<'static>)> = Vec::new();
+            static mut PLUGINS: Vec<Listener> = Vec::new();
+            static mut PLUGINS_UNORDERED: Vec<(i32, &'static str, Plugin<'static>, Option<Filter>)> = Vec::new();
 
             /// register globally registers a plugin function, it's called by async_plugin! before main() starts.
             /// It's an error to call this once plugins are configured.
-            pub unsafe fn register(order: i32, f: Plugin<'static>) {
+            pub unsafe fn register(order: i32, name: &'static str, f: Plugin<'static>, filter: Option<Filter>) {
                 #[cfg(not(test))]
                 {
                     assert!(!$crate::riverdb::plugins::CONFIGURED_PLUGINS);
                 }
-                PLUGINS_UNORDERED.push((order, f));
+                PLUGINS_UNORDERED.push((order, name, f, filter));
+            }
+
+            /// listeners returns the plugin name of each registered plugin function, in order, and if it's enabled.
+            pub fn listeners() -> Vec<(&'static str, bool)> {
+                let plugins = unsafe { &PLUGINS[..] };
+                plugins.iter().map(|l| (l.name, l.enabled.load(std::sync::atomic::Ordering::Relaxed))).collect()
+            }
+
+            /// set_enabled enables or disables the plugin functions of the named plugin (case-insensitive.)
+            /// Returns the number of plugin functions changed.
+            pub fn set_enabled(name: &str, enabled: bool) -> usize {
+                let plugins = unsafe { &PLUGINS[..] };
+                let mut changed = 0;
+                for l in plugins.iter().filter(|l| l.name.eq_ignore_ascii_case(name)) {
+                    l.enabled.store(enabled, std::sync::atomic::Ordering::Relaxed);
+                    changed += 1;
+                }
+                changed
             }
 
             /// clear all globally registered plugins. This is exposed for use in tests.
@@ -68,16 +213,21 @@ macro_rules! define_event {
                     assert!(!$crate::riverdb::plugins::CONFIGURED_PLUGINS);
                 }
                 // Sort the plugins by the order field in tuple index 0.
-                PLUGINS_UNORDERED.sort_unstable_by_key(|(order,_)| *order);
-                // Populate the PLUGINS Vec by the ordered plugins in tuple index 1.
-                for (_, f) in PLUGINS_UNORDERED.drain(..) {
-                    PLUGINS.push(f);
+                PLUGINS_UNORDERED.sort_unstable_by_key(|(order, _, _, _)| *order);
+                // Populate the PLUGINS Vec by the ordered plugins in tuple index 2.
+                for (_, name, f, filter) in PLUGINS_UNORDERED.drain(..) {
+                    PLUGINS.push(Listener{name, enabled: std::sync::atomic::AtomicBool::new(true), f, filter});
                 }
             }
 
             #[ctor::ctor]
             unsafe fn register_plugin_configure() {
-                $crate::riverdb::plugins::register_plugin_definition(configure);
+                $crate::riverdb::plugins::register_plugin_definition($crate::riverdb::plugins::EventDefinition{
+                    name: stringify!($name),
+                    configure,
+                    listeners,
+                    set_enabled,
+                });
             }
 
             pub struct Event{
@@ -92,12 +242,17 @@ macro_rules! define_event {
 
                 /// next() invokes the next plugin in the chain, or the default behavior
                 pub async fn next<$l>(&$l mut self, $event_src: &$l Source, $($arg: $arg_ty),*) -> $result {
-                    let i = self.index;
+                    let mut i = self.index;
                     let plugins = unsafe { &PLUGINS[..] };
+                    // Skip the plugins disabled at runtime or filtered out, as if they called next.
+                    // If every plugin is skipped, no boxed Future is allocated.
+                    while i < plugins.len() && !plugins[i].accepts($event_src) {
+                        i += 1;
+                    }
                     if i < plugins.len() {
                         let plugin_fn: Plugin = unsafe {
                             // Transmute to change lifetime (including for the slice elements) here from 'static to one more restrictive
-                            std::mem::transmute(*plugins.get_unchecked(i))
+                            std::mem::transmute(plugins.get_unchecked(i).f)
                         };
                         self.index = i + 1;
                         plugin_fn(self, $event_src, $($arg),*).await
@@ -186,6 +341,11 @@ macro_rules! define_event {
 /// it doesn't matter if you use async/await in Python or JavaScript, if the ffi layer is not async.
 /// Use tokio spawn_blocking to convert blocking code to async by running it in a background thread pool.
 ///
+/// Calling a plugin allocates a boxed Future, so a plugin that only cares about some events (e.g. for
+/// selected databases) should pass a filter method after the result type: `-> Result<()>, if wants_client`.
+/// The filter is a synchronous method of $plugin_type taking the event source and returning a bool.
+/// When it returns false the plugin is skipped, as if it called next, without allocating.
+///
 /// A plugin can have internal state, behind a shared reference and can initialize it in configure_plugin.
 /// However, plugins may be called concurrently from multiple threads, so take care to synchronize
 /// access to internal state (they're marked Send+Sync, so rust will enforce this requirement.)
@@ -194,7 +354,7 @@ macro_rules! define_event {
 /// to the plugins repository to update the list of community plugins.
 #[macro_export]
 macro_rules! event_listener {
-    ($plugin:expr, $plugin_type:ident : $event_name:ident<$l:lifetime>($($arg:ident: $arg_ty:ty),*) -> $result:ty) => {
+    ($plugin:expr, $plugin_type:ident : $event_name:ident<$l:lifetime>($($arg:ident: $arg_ty:ty),*) -> $result:ty $(, if $filter:ident)?) => {
         {
             #[allow(non_upper_case_globals)]
             static $plugin_type: std::sync::atomic::AtomicPtr<$plugin_type> = std::sync::atomic::AtomicPtr::new(std::ptr::null_mut());
@@ -206,9 +366,20 @@ macro_rules! event_listener {
                 Box::pin(p.$event_name(ev, src, $($arg),*))
             }
 
+            let _plugin_filter: Option<$event_name::Filter> = None;
+            $(
+                fn _plugin_filter_fn(src: &$event_name::Source) -> bool {
+                    let p = unsafe { &*$plugin_type.load(std::sync::atomic::Ordering::Relaxed) };
+                    p.$filter(src)
+                }
+                let _plugin_filter: Option<$event_name::Filter> = Some(_plugin_filter_fn);
+            )?
+
             $plugin_type.store($plugin as *const $plugin_type as *mut $plugin_type, std::sync::atomic::Ordering::Relaxed);
+            let p: &'static $plugin_type = unsafe { &*$plugin_type.load(std::sync::atomic::Ordering::Relaxed) };
+            $crate::riverdb::plugins::register_plugin(p);
             unsafe {
-                $event_name::register($plugin.order(), _plugin_fn);
+                $event_name::register(p.order(), stringify!($plugin_type), _plugin_fn, _plugin_filter);
             }
         }
     }
@@ -217,10 +388,15 @@ macro_rules! event_listener {
 #[cfg(test)]
 mod tests {
     use std::sync::Mutex;
+    use std::sync::atomic::AtomicU32;
+    use std::sync::atomic::Ordering::Relaxed;
+
+    use tokio::runtime::Handle;
+
+    use super::{Plugin, configure, configure_plugins, start_plugins, shutdown_plugins, event_listeners, set_listener_enabled};
 
-    use super::{Plugin, configure};
-    
     use crate::riverdb::Result;
+    use crate::riverdb::config::{conf, Settings};
 
     pub struct RecordMonitor(Mutex<RecordMonitorState>);
 
@@ -302,4 +478,141 @@ mod tests {
         assert_eq!(Ok("-1b--2b-hello world!-2a--1a-".to_string()), result);
         assert_eq!(monitor.0.lock().unwrap().state, (1+3+5)*5*3);
     }
+
+    define_event!(record_created, (monitor: &'a RecordMonitor, payload: &'a str) -> Result<String>);
+    define_event!(record_deleted, (monitor: &'a RecordMonitor, payload: &'a str) -> Result<String>);
+
+    impl RecordMonitor {
+        async fn record_created(&self, _ev: &mut record_created::Event, payload: &str) -> Result<String> {
+            Ok(payload.to_string())
+        }
+
+        async fn record_deleted(&self, _ev: &mut record_deleted::Event, payload: &str) -> Result<String> {
+            Ok(payload.to_string())
+        }
+    }
+
+    #[derive(Default)]
+    struct LifecycleListener {
+        configured: AtomicU32,
+        started: AtomicU32,
+        shutdown: AtomicU32,
+    }
+
+    impl LifecycleListener {
+        pub async fn record_created(&self, ev: &mut record_created::Event, monitor: &RecordMonitor, payload: &str) -> Result<String> {
+            ev.next(monitor, payload).await
+        }
+
+        pub async fn record_deleted(&self, ev: &mut record_deleted::Event, monitor: &RecordMonitor, payload: &str) -> Result<String> {
+            ev.next(monitor, payload).await
+        }
+    }
+
+    impl Plugin for LifecycleListener {
+        fn configure(&self, _settings: &'static Settings) -> Result<()> {
+            self.configured.fetch_add(1, Relaxed);
+            Ok(())
+        }
+
+        fn start(&self, _runtime: &Handle) {
+            self.started.fetch_add(1, Relaxed);
+        }
+
+        fn shutdown(&self) {
+            self.shutdown.fetch_add(1, Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle() {
+        let p: &'static LifecycleListener = Box::leak(Box::new(LifecycleListener::default()));
+        event_listener!(p, LifecycleListener:record_created<'a>(payload: &'a str) -> Result<String>);
+        event_listener!(p, LifecycleListener:record_deleted<'a>(payload: &'a str) -> Result<String>);
+
+        // Registered for two events, but each hook is called once
+        configure_plugins(conf()).unwrap();
+        start_plugins(&Handle::current());
+        shutdown_plugins();
+        assert_eq!(p.configured.load(Relaxed), 1);
+        assert_eq!(p.started.load(Relaxed), 1);
+        assert_eq!(p.shutdown.load(Relaxed), 1);
+    }
+
+    define_event!(record_updated, (monitor: &'a RecordMonitor, payload: &'a str) -> Result<String>);
+
+    impl RecordMonitor {
+        async fn record_updated(&self, _ev: &mut record_updated::Event, payload: &str) -> Result<String> {
+            Ok(payload.to_string())
+        }
+    }
+
+    struct BracketListener;
+
+    impl BracketListener {
+        pub async fn record_updated(&self, ev: &mut record_updated::Event, monitor: &RecordMonitor, payload: &str) -> Result<String> {
+            Ok(format!("[{}]", ev.next(monitor, payload).await?))
+        }
+    }
+
+    impl Plugin for BracketListener {}
+
+    #[tokio::test]
+    async fn test_disable_listener() {
+        let p: &'static BracketListener = Box::leak(Box::new(BracketListener));
+        event_listener!(p, BracketListener:record_updated<'a>(payload: &'a str) -> Result<String>);
+
+        unsafe {
+            configure();
+        }
+
+        let monitor = RecordMonitor(Mutex::new(RecordMonitorState{ greeting: String::new(), state: 0 }));
+        assert_eq!(record_updated::run(&monitor, "x").await, Ok("[x]".to_string()));
+        assert!(event_listeners().contains(&("record_updated", "BracketListener", true)));
+
+        assert_eq!(set_listener_enabled("bracketlistener", Some("record_updated"), false), 1);
+        assert!(event_listeners().contains(&("record_updated", "BracketListener", false)));
+        assert_eq!(record_updated::run(&monitor, "x").await, Ok("x".to_string()));
+
+        assert_eq!(set_listener_enabled("BracketListener", Some("record_deleted"), true), 0);
+        assert_eq!(set_listener_enabled("BracketListener", None, true), 1);
+        assert_eq!(record_updated::run(&monitor, "x").await, Ok("[x]".to_string()));
+    }
+
+    define_event!(record_viewed, (monitor: &'a RecordMonitor, payload: &'a str) -> Result<String>);
+
+    impl RecordMonitor {
+        async fn record_viewed(&self, _ev: &mut record_viewed::Event, payload: &str) -> Result<String> {
+            Ok(payload.to_string())
+        }
+    }
+
+    struct FilteredListener;
+
+    impl FilteredListener {
+        fn wants_monitor(&self, monitor: &RecordMonitor) -> bool {
+            monitor.0.lock().unwrap().state > 0
+        }
+
+        pub async fn record_viewed(&self, ev: &mut record_viewed::Event, monitor: &RecordMonitor, payload: &str) -> Result<String> {
+            Ok(format!("<{}>", ev.next(monitor, payload).await?))
+        }
+    }
+
+    impl Plugin for FilteredListener {}
+
+    #[tokio::test]
+    async fn test_filter_listener() {
+        let p: &'static FilteredListener = Box::leak(Box::new(FilteredListener));
+        event_listener!(p, FilteredListener:record_viewed<'a>(payload: &'a str) -> Result<String>, if wants_monitor);
+
+        unsafe {
+            configure();
+        }
+
+        let monitor = RecordMonitor(Mutex::new(RecordMonitorState{ greeting: String::new(), state: 0 }));
+        assert_eq!(record_viewed::run(&monitor, "x").await, Ok("x".to_string()));
+        monitor.0.lock().unwrap().state = 1;
+        assert_eq!(record_viewed::run(&monitor, "x").await, Ok("<x>".to_string()));
+    }
 }
@@ -0,0 +1,157 @@
+/// DER tags used in X.509 certificates
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const OID: u8 = 0x06;
+const OCTET_STRING: u8 = 0x04;
+const BOOLEAN: u8 = 0x01;
+const UTF8_STRING: u8 = 0x0c;
+const PRINTABLE_STRING: u8 = 0x13;
+const T61_STRING: u8 = 0x14;
+const IA5_STRING: u8 = 0x16;
+/// [0] EXPLICIT version
+const VERSION: u8 = 0xa0;
+/// [3] EXPLICIT extensions
+const EXTENSIONS: u8 = 0xa3;
+/// [2] IMPLICIT IA5String dNSName in GeneralNames
+const DNS_NAME: u8 = 0x82;
+
+/// OID 2.5.4.3 commonName
+const COMMON_NAME_OID: &[u8] = &[0x55, 0x04, 0x03];
+/// OID 2.5.29.17 subjectAltName
+const SUBJECT_ALT_NAME_OID: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// Returns the subject common names and subject alternative DNS names of the DER encoded X.509 certificate,
+/// or None if it can't be parsed. Names that aren't valid UTF-8 (e.g. BMPString) are skipped.
+/// This doesn't verify the certificate, that's the job of the TLS certificate verifier.
+pub fn certificate_names(cert: &[u8]) -> Option<Vec<String>> {
+    let (cert, _) = expect_tlv(cert, SEQUENCE)?;
+    let (mut tbs, _) = expect_tlv(cert, SEQUENCE)?;
+
+    if tbs.first() == Some(&VERSION) {
+        tbs = read_tlv(tbs)?.2;
+    }
+    // serialNumber, signature, issuer, validity
+    for _ in 0..4 {
+        tbs = read_tlv(tbs)?.2;
+    }
+    let (subject, mut tbs) = expect_tlv(tbs, SEQUENCE)?;
+
+    let mut names = Vec::new();
+    common_names(subject, &mut names)?;
+
+    // subjectPublicKeyInfo, issuerUniqueID, subjectUniqueID, extensions
+    while !tbs.is_empty() {
+        let (tag, value, rest) = read_tlv(tbs)?;
+        if tag == EXTENSIONS {
+            let (extensions, _) = expect_tlv(value, SEQUENCE)?;
+            dns_names(extensions, &mut names)?;
+        }
+        tbs = rest;
+    }
+    Some(names)
+}
+
+/// Appends the commonName attributes of the Name to names.
+fn common_names(mut name: &[u8], names: &mut Vec<String>) -> Option<()> {
+    while !name.is_empty() {
+        let (mut rdn, rest) = expect_tlv(name, SET)?;
+        while !rdn.is_empty() {
+            let (attribute, rest) = expect_tlv(rdn, SEQUENCE)?;
+            let (oid, value) = expect_tlv(attribute, OID)?;
+            if oid == COMMON_NAME_OID {
+                let (tag, value, _) = read_tlv(value)?;
+                if let UTF8_STRING | PRINTABLE_STRING | T61_STRING | IA5_STRING = tag {
+                    if let Ok(s) = std::str::from_utf8(value) {
+                        names.push(s.to_string());
+                    }
+                }
+            }
+            rdn = rest;
+        }
+        name = rest;
+    }
+    Some(())
+}
+
+/// Appends the dNSName entries of the subjectAltName extension, if present, to names.
+fn dns_names(mut extensions: &[u8], names: &mut Vec<String>) -> Option<()> {
+    while !extensions.is_empty() {
+        let (extension, rest) = expect_tlv(extensions, SEQUENCE)?;
+        let (oid, mut extension) = expect_tlv(extension, OID)?;
+        if oid == SUBJECT_ALT_NAME_OID {
+            if extension.first() == Some(&BOOLEAN) {
+                extension = read_tlv(extension)?.2;
+            }
+            let (value, _) = expect_tlv(extension, OCTET_STRING)?;
+            let (mut general_names, _) = expect_tlv(value, SEQUENCE)?;
+            while !general_names.is_empty() {
+                let (tag, value, rest) = read_tlv(general_names)?;
+                if tag == DNS_NAME {
+                    if let Ok(s) = std::str::from_utf8(value) {
+                        names.push(s.to_string());
+                    }
+                }
+                general_names = rest;
+            }
+        }
+        extensions = rest;
+    }
+    Some(())
+}
+
+/// Reads a DER tag-length-value and returns the value and the remaining bytes if the tag is expected_tag.
+fn expect_tlv(buf: &[u8], expected_tag: u8) -> Option<(&[u8], &[u8])> {
+    let (tag, value, rest) = read_tlv(buf)?;
+    if tag == expected_tag {
+        Some((value, rest))
+    } else {
+        None
+    }
+}
+
+/// Reads a DER tag-length-value (with a single byte tag) and returns the tag, value, and remaining bytes.
+fn read_tlv(buf: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *buf.first()?;
+    let first = *buf.get(1)? as usize;
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let num_bytes = first & 0x7f;
+        if num_bytes == 0 || num_bytes > 4 {
+            return None;
+        }
+        let len = buf.get(2..2 + num_bytes)?.iter().fold(0usize, |len, &b| (len << 8) | b as usize);
+        (len, 2 + num_bytes)
+    };
+    let value = buf.get(header..header.checked_add(len)?)?;
+    Some((tag, value, &buf[header + len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_cert(mut pem: &[u8]) -> Vec<u8> {
+        rustls_pemfile::certs(&mut pem).unwrap().pop().unwrap()
+    }
+
+    #[test]
+    fn test_certificate_names() {
+        let cert = load_cert(include_bytes!("../../tests/testdata/test-ca/rsa/client.cert"));
+        assert_eq!(certificate_names(&cert).unwrap(), vec!["ponytown client"]);
+
+        let cert = load_cert(include_bytes!("../../tests/testdata/test-ca/rsa/end.cert"));
+        assert_eq!(certificate_names(&cert).unwrap(), vec!["testserver.com", "testserver.com", "second.testserver.com", "localhost"]);
+
+        let cert = load_cert(include_bytes!("../../tests/testdata/test-ca/ecdsa/end.cert"));
+        assert!(certificate_names(&cert).unwrap().contains(&"localhost".to_string()));
+    }
+
+    #[test]
+    fn test_certificate_names_invalid() {
+        let cert = load_cert(include_bytes!("../../tests/testdata/test-ca/rsa/end.cert"));
+        assert!(certificate_names(&cert[..cert.len() / 2]).is_none());
+        assert!(certificate_names(&[]).is_none());
+        assert!(certificate_names(&[0x30, 0x84, 0xff, 0xff, 0xff, 0xff]).is_none());
+    }
+}
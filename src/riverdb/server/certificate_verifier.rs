@@ -17,6 +17,57 @@ impl ServerCertVerifier for DangerousCertificateNonverifier {
     }
 }
 
+/// The signature algorithms allowed in verified certificate chains, the same as rustls.
+static SUPPORTED_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// CertificateChainVerifier verifies the server certificate is issued by a trusted CA,
+/// but not that it's valid for the hostname (see TlsMode::VerifyCa.)
+pub struct CertificateChainVerifier {
+    /// the DER encoded certificates of the trusted CAs, in addition to webpki_roots
+    roots: Vec<Vec<u8>>,
+}
+
+impl CertificateChainVerifier {
+    /// Trust the CAs in webpki_roots, and the DER encoded roots. Roots that can't be parsed are ignored.
+    pub fn new(roots: Vec<Vec<u8>>) -> Arc<Self> {
+        let roots = roots.into_iter().filter(|root| webpki::TrustAnchor::try_from_cert_der(root).is_ok()).collect();
+        Arc::new(Self{roots})
+    }
+}
+
+fn pki_error(e: webpki::Error) -> Error {
+    Error::InvalidCertificateData(format!("invalid peer certificate: {:?}", e))
+}
+
+impl ServerCertVerifier for CertificateChainVerifier {
+    fn verify_server_cert(&self, end_entity: &Certificate, intermediates: &[Certificate], _server_name: &ServerName, _scts: &mut dyn Iterator<Item=&[u8]>, _ocsp_response: &[u8], now: SystemTime) -> Result<ServerCertVerified, Error> {
+        let cert = webpki::EndEntityCert::try_from(end_entity.0.as_slice()).map_err(pki_error)?;
+        let mut anchors = webpki_roots::TLS_SERVER_ROOTS.0.to_vec();
+        for root in &self.roots {
+            anchors.push(webpki::TrustAnchor::try_from_cert_der(root).map_err(pki_error)?);
+        }
+        let intermediates: Vec<&[u8]> = intermediates.iter().map(|cert| cert.0.as_slice()).collect();
+        let now = webpki::Time::try_from(now).map_err(|_| Error::InvalidCertificateData("invalid current time".to_string()))?;
+        // Unlike WebPkiVerifier, this doesn't call verify_is_valid_for_dns_name
+        cert.verify_is_valid_tls_server_cert(SUPPORTED_SIG_ALGS, &webpki::TlsServerTrustAnchors(&anchors), &intermediates, now)
+            .map_err(pki_error)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
 impl ClientCertVerifier for DangerousCertificateNonverifier {
     fn client_auth_root_subjects(&self, _sni: Option<&DnsName>) -> Option<DistinguishedNames> {
         None
@@ -1,10 +1,12 @@
 
 
 use std::sync::atomic::Ordering::{Relaxed, AcqRel, Acquire, Release};
-use std::sync::atomic::{AtomicPtr, AtomicI64};
-use std::sync::{Mutex};
+use std::sync::atomic::{AtomicPtr, AtomicI64, AtomicU32, AtomicBool};
+use std::sync::{Mutex, PoisonError};
 
 use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
 use tokio::time::{interval, Duration};
 use tracing::{warn, info_span};
 
@@ -14,6 +16,9 @@ use crate::riverdb::config::CHECK_TIMEOUTS_INTERVAL;
 
 pub trait Connection: std::fmt::Debug + AtomicRefCounted {
     fn new(s: TcpStream, connections: &'static Connections<Self>) -> Self where Self: Sized;
+    /// new_unix is like new, but for a connection over a unix domain socket.
+    #[cfg(unix)]
+    fn new_unix(s: UnixStream, connections: &'static Connections<Self>) -> Self where Self: Sized;
     fn id(&self) -> u32;
     fn set_id(&self, id: u32);
     fn last_active(&self) -> u32;
@@ -33,8 +38,9 @@ pub trait Connection: std::fmt::Debug + AtomicRefCounted {
 
 pub struct Connections<C: 'static + Connection> {
     items: &'static [AtomicPtr<C>],
-    timeout_seconds: u32,
-    max_connections: u32,
+    timeout_seconds: AtomicU32,
+    max_connections: AtomicU32,
+    timeouts_started: AtomicBool,
     added: AtomicI64,
     removed: AtomicI64,
     errors: AtomicI64,
@@ -51,8 +57,9 @@ impl<C: 'static + Connection> Connections<C> {
 
         let connections = &*Box::leak(Box::new(Self{
             items: items.leak(),
-            timeout_seconds,
-            max_connections,
+            timeout_seconds: AtomicU32::new(timeout_seconds),
+            max_connections: AtomicU32::new(max_connections),
+            timeouts_started: AtomicBool::new(timeout_seconds > 0),
             added: Default::default(),
             removed: Default::default(),
             errors: Default::default(),
@@ -80,19 +87,58 @@ impl<C: 'static + Connection> Connections<C> {
     }
 
     pub fn is_full(&self) -> bool {
-        self.len() >= self.max_connections as usize
+        self.len() >= self.max_connections() as usize
+    }
+
+    /// Returns the maximum number of connections.
+    pub fn max_connections(&self) -> u32 {
+        self.max_connections.load(Relaxed)
+    }
+
+    /// Change the maximum number of connections. It can't be increased beyond the capacity
+    /// allocated in new(), returns the new maximum.
+    pub fn set_max_connections(&self, max_connections: u32) -> u32 {
+        let capacity = (self.items.len() as f64 / 1.1) as u32;
+        let max_connections = max_connections.min(capacity);
+        self.max_connections.store(max_connections, Relaxed);
+        max_connections
+    }
+
+    /// Returns the number of seconds a connection can be inactive before it's closed, or 0 if disabled.
+    pub fn timeout_seconds(&self) -> u32 {
+        self.timeout_seconds.load(Relaxed)
+    }
+
+    /// Change the number of seconds a connection can be inactive before it's closed, 0 to disable.
+    /// Must be called from within the tokio runtime.
+    pub fn set_timeout_seconds(&'static self, timeout_seconds: u32) {
+        self.timeout_seconds.store(timeout_seconds, Relaxed);
+        if timeout_seconds > 0 && !self.timeouts_started.swap(true, Relaxed) {
+            tokio::spawn(self.timeouts_task());
+        }
     }
 
     pub fn add(&'static self, stream: TcpStream) -> Ark<C> {
+        self.add_with(|connections| C::new(stream, connections))
+    }
+
+    /// add_unix is like add, but for a connection accepted on a unix domain socket.
+    #[cfg(unix)]
+    pub fn add_unix(&'static self, stream: UnixStream) -> Ark<C> {
+        self.add_with(|connections| C::new_unix(stream, connections))
+    }
+
+    fn add_with<F: FnOnce(&'static Self) -> C>(&'static self, new: F) -> Ark<C> {
         // Because remove is loaded second, this might impose a very slightly lower limit (but never higher)
         let added = self.added.fetch_add(1, AcqRel) + 1;
-        if added - self.removed.load(Acquire) > self.max_connections as i64 {
+        let max_connections = self.max_connections();
+        if added - self.removed.load(Acquire) > max_connections as i64 {
             self.added.fetch_add(-1, Relaxed);
-            warn!(limit=self.max_connections, "reached connection limit");
+            warn!(limit=max_connections, "reached connection limit");
             return Ark::default();
         }
 
-        let conn = Ark::new(C::new(stream, self));
+        let conn = Ark::new(new(self));
         // Storing a raw pointer is fine, the object is removed from this collection before the Arc is dropped
         // See decref() -> true for where we do that.
         let conn_ptr = conn.as_ptr() as *mut C;
@@ -130,7 +176,7 @@ impl<C: 'static + Connection> Connections<C> {
         assert!(!current.is_null());
         assert_eq!(current, conn as *const C as *mut C);
 
-        let _guard = self.remove_lock.lock().unwrap();
+        let _guard = self.remove_lock.lock().unwrap_or_else(PoisonError::into_inner);
         // These can all be relaxed loads/stores since the mutex acquire/release will ensure they have total order
         slot.store(std::ptr::null_mut(), Relaxed);
         self.removed.store(self.removed.load(Relaxed) + 1, Relaxed);
@@ -149,7 +195,7 @@ impl<C: 'static + Connection> Connections<C> {
 
         // This must be exclusive with remove to ensure we don't see freed memory
         // A concurrent remove can free the connection memory, after we've seen a pointer to it.
-        let _guard = self.remove_lock.lock().unwrap();
+        let _guard = self.remove_lock.lock().unwrap_or_else(PoisonError::into_inner);
         for slot in self.items.iter() {
             let p = slot.load(Acquire);
             if !p.is_null() {
@@ -165,11 +211,15 @@ impl<C: 'static + Connection> Connections<C> {
     fn do_timeouts(&self) {
         let _span = info_span!("scanning for inactive, timed-out connections", "estimated {} total connections", self.len()).entered();
 
+        let timeout_seconds = self.timeout_seconds();
+        if timeout_seconds == 0 {
+            return;
+        }
         let now = coarse_monotonic_now();
         self.for_each(|conn| {
             let last_active = conn.last_active();
-            if last_active != 0 && last_active + self.timeout_seconds < now {
-                warn!(timeout=self.timeout_seconds, "closing connection {:?} because it timed out", conn);
+            if last_active != 0 && last_active + timeout_seconds < now {
+                warn!(timeout=timeout_seconds, "closing connection {:?} because it timed out", conn);
                 // This will trigger the task that called conn.run() to exit,
                 // and the connection to be dropped (including calling self.remove for it.)
                 conn.close();
@@ -1,59 +1,121 @@
 use std::io;
+use std::net::SocketAddr;
 #[cfg(unix)]
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 
 
 use tokio::net::{TcpListener, TcpSocket, TcpStream};
 use tracing::{debug, error};
 
 use crate::riverdb::{Error, Result};
-use crate::riverdb::config::LISTEN_BACKLOG;
+use crate::riverdb::config::{Settings, LISTEN_BACKLOG};
+#[cfg(target_os = "linux")]
+use crate::riverdb::server::uring::Driver;
 
 
+/// LISTEN_FDS_ENV is the environment variable with the listening sockets inherited from the supervisor
+/// (see supervisor::supervise), as comma separated address=fd pairs.
+pub const LISTEN_FDS_ENV: &str = "RIVERDB_LISTEN_FDS";
+
+/// Socket options for a Listener.
+#[derive(Debug, Clone, Copy)]
+pub struct ListenerOptions {
+    /// set SO_REUSEADDR
+    pub reuseaddr: bool,
+    /// set SO_REUSEPORT (unix only)
+    pub reuseport: bool,
+    /// the accept backlog length
+    pub backlog: u32,
+    /// enable TCP Fast Open with this queue length if non-zero (linux only)
+    pub tcp_fastopen_queue: u32,
+    /// set IPV6_V6ONLY on IPv6 sockets (unix only)
+    pub ipv6_only: bool,
+}
+
+impl ListenerOptions {
+    /// Return the ListenerOptions configured in settings.
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            reuseaddr: settings.reuseaddr,
+            reuseport: settings.reuseport,
+            backlog: settings.listen_backlog,
+            tcp_fastopen_queue: settings.tcp_fastopen_queue,
+            ipv6_only: settings.ipv6_only,
+        }
+    }
+}
+
+impl Default for ListenerOptions {
+    fn default() -> Self {
+        Self {
+            reuseaddr: true,
+            reuseport: false,
+            backlog: LISTEN_BACKLOG,
+            tcp_fastopen_queue: 0,
+            ipv6_only: true,
+        }
+    }
+}
+
 pub struct Listener {
     pub address: String,
     listener: TcpListener,
 }
 
 impl Listener {
-    pub fn new(address: String, reuseport: bool) -> Result<Self> {
-        let addr = address.parse()?;
-        let sock = TcpSocket::new_v4()?;
+    pub fn new(address: String, options: ListenerOptions) -> Result<Self> {
+        let addr: SocketAddr = address.parse()?;
+        #[cfg(unix)]
+        {
+            if let Some(listener) = inherited_listener(&address, &std::env::var(LISTEN_FDS_ENV).unwrap_or_default())? {
+                debug!(%address, "using inherited listening socket");
+                listener.set_nonblocking(true)?;
+                return Ok(Self {
+                    address,
+                    listener: TcpListener::from_std(listener)?,
+                });
+            }
+        }
+        let sock = if addr.is_ipv6() { TcpSocket::new_v6()? } else { TcpSocket::new_v4()? };
+        sock.set_reuseaddr(options.reuseaddr)?;
         #[cfg(unix)]
         {
-            if reuseport {
+            if options.reuseport {
                 sock.set_reuseport(true)?;
             }
+            if addr.is_ipv6() {
+                set_socket_option(&sock, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, options.ipv6_only as libc::c_int)?;
+            }
             // If we're on linux, set TCP_DEFER_ACCEPT
             // The client always sends the first data after connecting.
             #[cfg(target_os = "linux")]
-            unsafe {
-                let optval: libc::c_int = 1;
-                let ret = libc::setsockopt(
-                    sock.as_raw_fd(),
-                    libc::SOL_SOCKET,
-                    libc::TCP_DEFER_ACCEPT,
-                    &optval as *const _ as *const libc::c_void,
-                    std::mem::size_of_val(&optval) as libc::socklen_t);
-                if ret != 0 {
-                    return Err(Error::from(io::Error::last_os_error()));
+            {
+                set_tcp_option(&sock, libc::TCP_DEFER_ACCEPT, 1)?;
+                if options.tcp_fastopen_queue != 0 {
+                    set_tcp_option(&sock, libc::TCP_FASTOPEN, options.tcp_fastopen_queue as libc::c_int)?;
                 }
             }
         }
         sock.bind(addr)?;
-        let listener = sock.listen(LISTEN_BACKLOG)?;
+        let listener = sock.listen(options.backlog)?;
         Ok(Self {
             address,
             listener,
         })
     }
 
+    /// Returns the address the listener is bound to. This differs from address if it was bound
+    /// to port 0, in which case the OS chooses an ephemeral port.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
     pub async fn accept(&self) -> Option<TcpStream>
     {
         loop {
-            match self.listener.accept().await {
-                Ok((sock, remote_addr)) => {
-                    debug!(fd = sock.as_raw_fd(), %remote_addr, server = %self.address.as_str(), "accept connection");
+            match self.accept_one().await {
+                Ok(sock) => {
+                    debug!(fd = sock.as_raw_fd(), remote_addr = ?sock.peer_addr().ok(), server = %self.address.as_str(), "accept connection");
                     return Some(sock);
                 },
                 Err(e) => {
@@ -78,5 +140,109 @@ impl Listener {
             }
         }
     }
+
+    /// Accepts a connection with io_uring if io_engine is io_uring, otherwise with tokio.
+    async fn accept_one(&self) -> io::Result<TcpStream> {
+        #[cfg(target_os = "linux")]
+        if let Some(driver) = Driver::get() {
+            match driver.accept(self.listener.as_raw_fd()).await {
+                // Older kernels don't wait for a connection on non-blocking sockets
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                result => return result,
+            }
+        }
+        self.listener.accept().await.map(|(sock, _)| sock)
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+/// Returns the listening sockets in the LISTEN_FDS_ENV format.
+#[cfg(unix)]
+pub fn format_listen_fds(listeners: &[(String, RawFd)]) -> String {
+    listeners.iter().map(|(address, fd)| format!("{}={}", address, fd)).collect::<Vec<_>>().join(",")
+}
+
+/// Parses the listening sockets in the LISTEN_FDS_ENV format, ignoring invalid entries.
+#[cfg(unix)]
+pub fn parse_listen_fds(value: &str) -> Vec<(String, RawFd)> {
+    value.split(',')
+        .filter_map(|pair| pair.rsplit_once('='))
+        .filter_map(|(address, fd)| Some((address.to_string(), fd.parse().ok()?)))
+        .collect()
+}
+
+/// Returns a duplicate of the inherited listening socket for address in listen_fds (see LISTEN_FDS_ENV), if any.
+/// It's duplicated so each of several Listeners on the address (see reuseport) can own one.
+#[cfg(unix)]
+fn inherited_listener(address: &str, listen_fds: &str) -> Result<Option<std::net::TcpListener>> {
+    let fd = match parse_listen_fds(listen_fds).into_iter().find(|(addr, _)| addr == address) {
+        Some((_, fd)) => fd,
+        None => return Ok(None),
+    };
+    // Safety: the supervisor passed fd open to the listening socket, dup returns a new fd we own
+    let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if dup < 0 {
+        return Err(Error::from(io::Error::last_os_error()));
+    }
+    Ok(Some(unsafe { std::net::TcpListener::from_raw_fd(dup) }))
 }
 
+/// Set an IPPROTO_TCP level socket option to an integer value.
+#[cfg(target_os = "linux")]
+fn set_tcp_option(sock: &TcpSocket, option: libc::c_int, value: libc::c_int) -> Result<()> {
+    set_socket_option(sock, libc::IPPROTO_TCP, option, value)
+}
+
+/// Set a socket option at the given level to an integer value.
+#[cfg(unix)]
+fn set_socket_option(sock: &TcpSocket, level: libc::c_int, option: libc::c_int, value: libc::c_int) -> Result<()> {
+    // Safety: we pass a valid fd and a pointer to a c_int with its size
+    let ret = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            level,
+            option,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t)
+    };
+    if ret != 0 {
+        return Err(Error::from(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_fds() {
+        let listeners = vec![("0.0.0.0:5432".to_string(), 3), ("[::1]:5433".to_string(), 4)];
+        let value = format_listen_fds(&listeners);
+        assert_eq!(value, "0.0.0.0:5432=3,[::1]:5433=4");
+        assert_eq!(parse_listen_fds(&value), listeners);
+        assert_eq!(parse_listen_fds(""), vec![]);
+        assert_eq!(parse_listen_fds("0.0.0.0:5432=x,127.0.0.1:80=7"), vec![("127.0.0.1:80".to_string(), 7)]);
+    }
+
+    #[test]
+    fn test_inherited_listener() {
+        let original = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = original.local_addr().unwrap().to_string();
+        let listen_fds = format_listen_fds(&[(address.clone(), original.as_raw_fd())]);
+        assert!(inherited_listener("127.0.0.1:1", &listen_fds).unwrap().is_none());
+
+        let listener = inherited_listener(&address, &listen_fds).unwrap().expect("inherited listener");
+        assert_ne!(listener.as_raw_fd(), original.as_raw_fd());
+        assert_eq!(listener.local_addr().unwrap().to_string(), address);
+        // The duplicate accepts connections made to the original socket
+        let _client = std::net::TcpStream::connect(&address).unwrap();
+        assert!(listener.accept().is_ok());
+    }
+}
@@ -1,11 +1,19 @@
 mod transport;
 mod transport_stream;
 mod certificate_verifier;
+mod certificate_names;
 mod listener;
 mod transport_tls;
 mod connections;
+mod proxy_protocol;
+#[cfg(target_os = "linux")]
+mod uring;
 
 pub use transport::Transport;
-pub use certificate_verifier::DangerousCertificateNonverifier;
-pub use listener::Listener;
-pub use connections::{Connection, Connections};
\ No newline at end of file
+pub use certificate_verifier::{DangerousCertificateNonverifier, CertificateChainVerifier};
+pub use certificate_names::certificate_names;
+pub use listener::{Listener, ListenerOptions, LISTEN_FDS_ENV};
+#[cfg(unix)]
+pub use listener::{format_listen_fds, parse_listen_fds};
+pub use connections::{Connection, Connections};
+pub use proxy_protocol::ProxyHeader;
\ No newline at end of file
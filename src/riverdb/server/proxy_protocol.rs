@@ -0,0 +1,133 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::riverdb::{Error, Result};
+
+
+/// The signature that starts a PROXY protocol version 2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The maximum length of a PROXY protocol version 1 header, including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// A PROXY protocol (version 1 or 2) header, sent by a load balancer like HAProxy or AWS NLB
+/// before any data from the client, see https://www.haproxy.org/download/2.4/doc/proxy-protocol.txt
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ProxyHeader {
+    /// the length of the header in bytes
+    pub len: usize,
+    /// the address of the client that connected to the proxy. None for health checks from
+    /// the proxy itself (v2 LOCAL) or unknown/unsupported address families.
+    pub source: Option<SocketAddr>,
+}
+
+impl ProxyHeader {
+    /// Parses a PROXY protocol header from the start of buf. Returns Ok(None) if buf doesn't
+    /// contain the complete header yet, and an error if it doesn't start with a valid header.
+    pub fn parse(buf: &[u8]) -> Result<Option<Self>> {
+        if buf.len() < V2_SIGNATURE.len() {
+            if V2_SIGNATURE.starts_with(buf) || b"PROXY ".starts_with(&buf[..buf.len().min(6)]) {
+                return Ok(None);
+            }
+        } else if buf.starts_with(V2_SIGNATURE) {
+            return parse_v2(buf);
+        } else if buf.starts_with(b"PROXY ") {
+            return parse_v1(buf);
+        }
+        Err(Error::new("expected a PROXY protocol header"))
+    }
+}
+
+/// Parses a human-readable version 1 header, e.g. "PROXY TCP4 192.168.0.1 192.168.0.11 56324 5432\r\n"
+fn parse_v1(buf: &[u8]) -> Result<Option<ProxyHeader>> {
+    let end = match buf.windows(2).take(V1_MAX_LEN - 1).position(|w| w == b"\r\n") {
+        Some(i) => i,
+        None if buf.len() >= V1_MAX_LEN => return Err(Error::new("PROXY protocol header is too long")),
+        None => return Ok(None),
+    };
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| Error::new("invalid PROXY protocol header"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    let source = match parts.get(1).copied() {
+        Some("UNKNOWN") => None,
+        Some("TCP4") | Some("TCP6") if parts.len() == 6 => {
+            let ip: IpAddr = parts[2].parse().map_err(|_| Error::new(format!("invalid PROXY protocol source address {}", parts[2])))?;
+            let port: u16 = parts[4].parse().map_err(|_| Error::new(format!("invalid PROXY protocol source port {}", parts[4])))?;
+            Some(SocketAddr::new(ip, port))
+        },
+        _ => return Err(Error::new(format!("invalid PROXY protocol header: {}", line))),
+    };
+    Ok(Some(ProxyHeader{len: end + 2, source}))
+}
+
+/// Parses a binary version 2 header
+fn parse_v2(buf: &[u8]) -> Result<Option<ProxyHeader>> {
+    if buf.len() < 16 {
+        return Ok(None);
+    }
+    let version_command = buf[12];
+    if version_command >> 4 != 2 {
+        return Err(Error::new(format!("unsupported PROXY protocol version {}", version_command >> 4)));
+    }
+    let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let addresses = &buf[16..len];
+    let source = match (version_command & 0xf, buf[13] >> 4) {
+        (0, _) => None, // LOCAL, the connection was made by the proxy itself
+        (1, 1) if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Some(SocketAddr::new(IpAddr::V4(ip), u16::from_be_bytes([addresses[8], addresses[9]])))
+        },
+        (1, 2) if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), u16::from_be_bytes([addresses[32], addresses[33]])))
+        },
+        (1, _) => None, // AF_UNSPEC, AF_UNIX, or truncated addresses, the proxy doesn't know the source
+        (command, _) => return Err(Error::new(format!("unsupported PROXY protocol command {}", command))),
+    };
+    Ok(Some(ProxyHeader{len, source}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_v1() {
+        let buf = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 5432\r\n\0\0\0\x08";
+        let hdr = ProxyHeader::parse(buf).unwrap().unwrap();
+        assert_eq!(hdr.len, buf.len() - 4);
+        assert_eq!(hdr.source, Some("192.168.0.1:56324".parse().unwrap()));
+
+        let hdr = ProxyHeader::parse(b"PROXY TCP6 ::1 ::2 4000 5432\r\n").unwrap().unwrap();
+        assert_eq!(hdr.source, Some("[::1]:4000".parse().unwrap()));
+
+        let hdr = ProxyHeader::parse(b"PROXY UNKNOWN\r\n").unwrap().unwrap();
+        assert_eq!(hdr, ProxyHeader{len: 15, source: None});
+
+        assert_eq!(ProxyHeader::parse(b"PRO").unwrap(), None);
+        assert_eq!(ProxyHeader::parse(b"PROXY TCP4 192.168.0.1").unwrap(), None);
+        assert!(ProxyHeader::parse(b"PROXY TCP4 nope 192.168.0.11 56324 5432\r\n").is_err());
+        assert!(ProxyHeader::parse(&[b'A'; 120]).is_err());
+        assert!(ProxyHeader::parse(&[0, 0, 0, 8, 4, 0xd2, 0x16, 0x2f]).is_err());
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.extend_from_slice(&[0x21, 0x11, 0, 12, 10, 0, 0, 1, 10, 0, 0, 2, 0x1f, 0x90, 0x15, 0x38]);
+        assert_eq!(ProxyHeader::parse(&buf[..20]).unwrap(), None);
+        buf.extend_from_slice(&[0, 0, 0, 8]);
+        let hdr = ProxyHeader::parse(&buf).unwrap().unwrap();
+        assert_eq!(hdr.len, 28);
+        assert_eq!(hdr.source, Some("10.0.0.1:8080".parse().unwrap()));
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0, 0, 0]);
+        assert_eq!(ProxyHeader::parse(&local).unwrap(), Some(ProxyHeader{len: 16, source: None}));
+
+        let mut v1 = V2_SIGNATURE.to_vec();
+        v1.extend_from_slice(&[0x11, 0, 0, 0]);
+        assert!(ProxyHeader::parse(&v1).is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use std::io;
-use std::io::{Read, Write};
+use std::io::{Read, Write, IoSlice};
 
 use std::sync::{Mutex, Arc};
 use std::sync::atomic::{AtomicBool, AtomicU32};
@@ -11,13 +11,14 @@ use tokio::net::{TcpStream};
 use tokio::net::{UnixStream};
 use tokio::io::{Interest, Ready};
 use tracing::{warn, debug};
-use rustls::{ClientConfig, ServerConfig, ClientConnection, ServerConnection, ServerName};
+use rustls::{ClientConfig, ServerConfig, ClientConnection, ServerConnection, ServerName, Certificate};
 
 use crate::riverdb::{Error, Result};
 use crate::riverdb::config::{TlsMode};
 use crate::riverdb::server::transport_stream::{TransportStream, StreamReaderWriter, convert_io_result};
 use crate::riverdb::server::transport_tls::TransportTls;
 use crate::riverdb::common;
+use crate::riverdb::pg::protocol::sasl;
 
 
 pub struct Transport {
@@ -61,6 +62,32 @@ impl Transport
         self.is_tls_protected.load(Relaxed)
     }
 
+    /// peer_certificate returns the end-entity certificate presented by the other side of the TLS connection, if any.
+    /// It's only verified if the TLS config has a certificate verifier for the other side.
+    pub fn peer_certificate(&self) -> Option<Certificate> {
+        let tls = self.tls.lock().unwrap();
+        tls.peer_certificates().and_then(|certs| certs.first()).cloned()
+    }
+
+    /// tls_server_end_point returns the tls-server-end-point channel binding data (see sasl::tls_server_end_point)
+    /// of the certificate presented by the other side of the TLS connection, for SCRAM-SHA-256-PLUS.
+    /// Returns None if this isn't a TLS connection, or channel binding isn't possible with the certificate.
+    pub fn tls_server_end_point(&self) -> Option<Vec<u8>> {
+        self.peer_certificate().and_then(|cert| sasl::tls_server_end_point(&cert.0))
+    }
+
+    /// tls_version returns the negotiated TLS protocol version (e.g. TLSv1.3), if this is a TLS connection.
+    pub fn tls_version(&self) -> Option<String> {
+        let tls = self.tls.lock().unwrap();
+        tls.protocol_version().map(|v| format!("{:?}", v).replace('_', "."))
+    }
+
+    /// tls_cipher_suite returns the IANA name of the negotiated TLS cipher suite, if this is a TLS connection.
+    pub fn tls_cipher_suite(&self) -> Option<String> {
+        let tls = self.tls.lock().unwrap();
+        tls.negotiated_cipher_suite().map(|suite| format!("{:?}", suite.suite()))
+    }
+
     /// is_closed returns true if the connection is not closed or in the process of closing
     pub fn is_closed(&self) -> bool {
         self.is_closing.load(Relaxed)
@@ -128,7 +155,7 @@ impl Transport
         }
 
         let result = if self.is_tls_protected.load(Relaxed) {
-            self.tls_write(buf)
+            self.tls_write(&[IoSlice::new(buf)])
         } else {
             self.stream.try_write(buf)
         };
@@ -139,6 +166,54 @@ impl Transport
         result
     }
 
+    /// try_write_vectored is like try_write, but writes from a sequence of buffers
+    /// with a single system call (writev), or a single TLS session write.
+    /// Returns the total number of bytes written, which may end partway through any buffer.
+    /// Returns Ok(0) without writing if bufs is empty or contains only empty buffers.
+    pub fn try_write_vectored(&self, bufs: &[IoSlice]) -> Result<usize> {
+        if bufs.iter().all(|buf| buf.is_empty()) {
+            return Ok(0);
+        }
+        if self.is_closed() {
+            return Err(Error::closed());
+        }
+
+        let result = if self.is_tls_protected.load(Relaxed) {
+            self.tls_write(bufs)
+        } else {
+            self.stream.try_write_vectored(bufs)
+        };
+
+        if result.is_ok() {
+            self.last_active.store(common::coarse_monotonic_now(), Relaxed);
+        }
+        result
+    }
+
+    /// try_flush writes any TLS ciphertext buffered by try_write to the underlying stream without blocking.
+    /// Returns true if nothing is left to write, which is always the case if the stream isn't TLS encrypted.
+    pub fn try_flush(&self) -> Result<bool> {
+        if !self.is_tls_protected.load(Relaxed) {
+            return Ok(true);
+        }
+        let mut session = self.tls.lock().map_err(Error::from)?;
+        while session.wants_write() {
+            match session.write_tls(&mut StreamReaderWriter::new(&self.stream)) {
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        break;
+                    }
+                    warn!(?e, "TLS write error");
+                    return Err(Error::from(e));
+                },
+                Ok(_) => (),
+            }
+        }
+        // Relaxed because the mutex release below is a global barrier
+        self.want_write.store(session.wants_write(), Relaxed);
+        Ok(!session.wants_write())
+    }
+
     fn tls_read(&self, buf: &mut [u8]) -> Result<usize> {
         let mut session = self.tls.lock().map_err(Error::from)?;
         if session.wants_read() {
@@ -160,7 +235,7 @@ impl Transport
         convert_io_result(session.reader().read(buf))
     }
 
-    fn tls_write(&self, buf: &[u8]) -> Result<usize> {
+    fn tls_write(&self, bufs: &[IoSlice]) -> Result<usize> {
         let mut session = self.tls.lock().map_err(Error::from)?;
         if session.wants_write() {
             let _n = match session.write_tls(&mut StreamReaderWriter::new(&self.stream)) {
@@ -175,7 +250,7 @@ impl Transport
             };
         }
 
-        let result = convert_io_result(session.writer().write(buf));
+        let result = convert_io_result(session.writer().write_vectored(bufs));
         // mirror this value while we hold the mutex
         // Relaxed because the mutex release below is a global barrier
         self.want_write.store(session.wants_write(), Relaxed);
@@ -207,12 +282,17 @@ impl Transport
         }
     }
 
-    pub async fn upgrade_client(&self, config: Arc<ClientConfig>, _mode: TlsMode, _hostname: &str) -> Result<()> {
+    pub async fn upgrade_client(&self, config: Arc<ClientConfig>, mode: TlsMode, hostname: &str) -> Result<()> {
         #[cfg(unix)]
         if self.stream.is_unix() {
             panic!("cannot use tls over a unix socket");
         }
-        let server_name = ServerName::try_from("hostname").map_err(|_|Error::new("invalid dns name"))?;
+        // The certificate is checked against hostname, unless mode doesn't verify it (e.g. an IP address with verify-ca)
+        let server_name = match ServerName::try_from(hostname) {
+            Ok(server_name) => server_name,
+            Err(_) if !mode.verifies_hostname() => ServerName::try_from("localhost").unwrap(),
+            Err(_) => return Err(Error::new(format!("tls_host {} is not a valid DNS name, required to verify the certificate hostname", hostname))),
+        };
         let mut conn = TransportTls::new_client(ClientConnection::new(config, server_name).map_err(Error::new)?);
         self.do_complete_io(&mut conn).await?;
         // Relaxed because the mutex acquire/release below is a global barrier
@@ -239,4 +319,26 @@ impl Transport
         self.is_closing.store(true, Relaxed);
         self.stream.close();
     }
-}
\ No newline at end of file
+}
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_try_write_vectored() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (a, b) = (Transport::new_unix(a), Transport::new_unix(b));
+        a.ready(Interest::WRITABLE).await.unwrap();
+        let bufs = [IoSlice::new(b"SELECT"), IoSlice::new(b""), IoSlice::new(b" 1;")];
+        assert_eq!(a.try_write_vectored(&bufs).unwrap(), 9);
+
+        let mut buf = [0; 16];
+        b.ready(Interest::READABLE).await.unwrap();
+        let n = b.try_read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"SELECT 1;");
+
+        // Nothing to write
+        assert_eq!(a.try_write_vectored(&[]).unwrap(), 0);
+        assert_eq!(a.try_write_vectored(&[IoSlice::new(b"")]).unwrap(), 0);
+    }
+}
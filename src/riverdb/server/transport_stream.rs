@@ -8,18 +8,35 @@ use tokio::net::TcpStream;
 #[cfg(unix)]
 use tokio::net::{UnixStream};
 use tokio::io::{Interest, Ready};
+#[cfg(target_os = "linux")]
+use tracing::warn;
 
 use crate::riverdb::{Error, Result};
+#[cfg(target_os = "linux")]
+use crate::riverdb::server::uring::{Driver, UringStream};
 
 
 pub(crate) enum TransportStream {
     TcpStream(TcpStream),
     #[cfg(unix)]
     UnixSocket(UnixStream),
+    #[cfg(target_os = "linux")]
+    Uring(UringStream),
 }
 
 impl TransportStream {
+    /// Returns a TransportStream for the TCP socket, which uses io_uring if io_engine is io_uring.
     pub fn new_tcp(stream: TcpStream) -> Self {
+        #[cfg(target_os = "linux")]
+        if let Some(driver) = Driver::get() {
+            return match UringStream::new(driver, stream) {
+                Ok(s) => TransportStream::Uring(s),
+                Err((stream, e)) => {
+                    warn!(%e, "could not use io_uring for the connection");
+                    TransportStream::TcpStream(stream)
+                },
+            };
+        }
         TransportStream::TcpStream(stream)
     }
 
@@ -40,6 +57,8 @@ impl TransportStream {
             TransportStream::TcpStream(s) => s.ready(interest).await.map_err(Error::from),
             #[cfg(unix)]
             TransportStream::UnixSocket(s) => s.ready(interest).await.map_err(Error::from),
+            #[cfg(target_os = "linux")]
+            TransportStream::Uring(s) => s.ready(interest).await.map_err(Error::from),
         }
     }
 
@@ -47,7 +66,9 @@ impl TransportStream {
         convert_io_result(match self {
             TransportStream::TcpStream(s) => s.try_read(buf),
             #[cfg(unix)]
-            TransportStream::UnixSocket(_s) => unimplemented!(),
+            TransportStream::UnixSocket(s) => s.try_read(buf),
+            #[cfg(target_os = "linux")]
+            TransportStream::Uring(s) => s.read(buf),
         })
     }
 
@@ -55,18 +76,35 @@ impl TransportStream {
         convert_io_result(match self {
             TransportStream::TcpStream(s) => s.try_write(buf),
             #[cfg(unix)]
-            TransportStream::UnixSocket(_s) => unimplemented!(),
+            TransportStream::UnixSocket(s) => s.try_write(buf),
+            #[cfg(target_os = "linux")]
+            TransportStream::Uring(s) => s.write_vectored(&[io::IoSlice::new(buf)]),
+        })
+    }
+
+    pub fn try_write_vectored(&self, bufs: &[io::IoSlice]) -> Result<usize> {
+        convert_io_result(match self {
+            TransportStream::TcpStream(s) => s.try_write_vectored(bufs),
+            #[cfg(unix)]
+            TransportStream::UnixSocket(s) => s.try_write_vectored(bufs),
+            #[cfg(target_os = "linux")]
+            TransportStream::Uring(s) => s.write_vectored(bufs),
         })
     }
 
+    /// Shut down both directions of the socket, which wakes any tasks awaiting readiness.
+    /// The file descriptor itself is closed when the stream is dropped, closing it here
+    /// would close it twice (and possibly close an unrelated fd that reused the number.)
     pub fn close(&self) {
         let raw_fd = match self {
             TransportStream::TcpStream(s) => s.as_raw_fd(),
             #[cfg(unix)]
             TransportStream::UnixSocket(s) => s.as_raw_fd(),
+            #[cfg(target_os = "linux")]
+            TransportStream::Uring(s) => return s.shutdown(),
         };
         unsafe {
-            libc::close(raw_fd);
+            libc::shutdown(raw_fd, libc::SHUT_RDWR);
         }
     }
 }
@@ -88,21 +126,24 @@ pub(crate) fn convert_io_result(result: io::Result<usize>) -> Result<usize> {
     }
 }
 
-enum Stream {
+enum Stream<'a> {
     Empty,
     Tcp(std::net::TcpStream),
     #[cfg(unix)]
     Unix(std::os::unix::net::UnixStream),
+    /// UringStream is read from and written to through its buffers, not the socket
+    #[cfg(target_os = "linux")]
+    Uring(&'a UringStream),
 }
 
-impl Default for Stream {
+impl Default for Stream<'_> {
     fn default() -> Self {
         Stream::Empty
     }
 }
 
 pub(crate) struct StreamReaderWriter<'a>{
-    stream: Stream,
+    stream: Stream<'a>,
     _phantom: PhantomData<&'a TransportStream>
 }
 
@@ -116,6 +157,8 @@ impl<'a> StreamReaderWriter<'a> {
                 TransportStream::UnixSocket(s) => unsafe {
                     Stream::Unix(std::os::unix::net::UnixStream::from_raw_fd(s.as_raw_fd()))
                 },
+                #[cfg(target_os = "linux")]
+                TransportStream::Uring(s) => Stream::Uring(s),
             },
             _phantom: PhantomData,
         }
@@ -135,6 +178,8 @@ impl<'a> Read for StreamReaderWriter<'a> {
             Stream::Tcp(s) => s.read(buf),
             #[cfg(unix)]
             Stream::Unix(s) => s.read(buf),
+            #[cfg(target_os = "linux")]
+            Stream::Uring(s) => s.read(buf),
             _ => unreachable!(),
         }
     }
@@ -146,6 +191,8 @@ impl<'a> Write for StreamReaderWriter<'a> {
             Stream::Tcp(s) => s.write(buf),
             #[cfg(unix)]
             Stream::Unix(s) => s.write(buf),
+            #[cfg(target_os = "linux")]
+            Stream::Uring(s) => s.write_vectored(&[io::IoSlice::new(buf)]),
             _ => unreachable!(),
         }
     }
@@ -155,6 +202,8 @@ impl<'a> Write for StreamReaderWriter<'a> {
             Stream::Tcp(s) => s.flush(),
             #[cfg(unix)]
             Stream::Unix(s) => s.flush(),
+            #[cfg(target_os = "linux")]
+            Stream::Uring(_) => Ok(()),
             _ => unreachable!(),
         }
     }
@@ -1,7 +1,7 @@
 use std::io;
 use std::result::Result;
 
-use rustls::{IoState, ClientConnection, ServerConnection, Connection, Reader, Writer};
+use rustls::{IoState, ClientConnection, ServerConnection, Connection, Reader, Writer, Certificate};
 
 
 pub enum TransportTls {
@@ -87,6 +87,31 @@ impl TransportTls {
         }
     }
 
+    /// Returns the certificate chain presented by the other side of the connection, if any.
+    pub fn peer_certificates(&self) -> Option<&[Certificate]> {
+        match self {
+            TransportTls::NoTls => None,
+            TransportTls::Client(c) => c.peer_certificates(),
+            TransportTls::Server(c) => c.peer_certificates(),
+        }
+    }
+
+    pub fn protocol_version(&self) -> Option<rustls::ProtocolVersion> {
+        match self {
+            TransportTls::NoTls => None,
+            TransportTls::Client(c) => c.protocol_version(),
+            TransportTls::Server(c) => c.protocol_version(),
+        }
+    }
+
+    pub fn negotiated_cipher_suite(&self) -> Option<rustls::SupportedCipherSuite> {
+        match self {
+            TransportTls::NoTls => None,
+            TransportTls::Client(c) => c.negotiated_cipher_suite(),
+            TransportTls::Server(c) => c.negotiated_cipher_suite(),
+        }
+    }
+
     pub fn process_new_packets(&mut self) -> Result<IoState, rustls::Error> {
         match self {
             TransportTls::NoTls => panic!("not a tls connection"),
@@ -0,0 +1,605 @@
+//! The io_uring alternative to the readiness based tokio sockets of TransportStream (see IoEngine::IoUring.)
+//!
+//! A single io_uring is shared by the process. Operations are submitted from any thread, and a dedicated
+//! thread waits for them to complete and runs their handlers, which wake the tasks waiting on them.
+//! UringStream buffers what it receives and sends, so it offers the same non-blocking read, write, and
+//! ready methods as the tokio sockets, which the rest of the code (including TLS) is built on.
+
+use std::future::Future;
+use std::io::{self, IoSlice};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::pin::Pin;
+use std::cell::Cell;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::task::{Context, Poll, Waker};
+
+use fnv::FnvHashMap;
+use io_uring::{IoUring, opcode, squeue, types};
+use tokio::io::{Interest, Ready};
+use tokio::net::TcpStream;
+use tracing::{error, info, warn};
+
+use crate::riverdb::config::{conf, IoEngine};
+
+
+/// The number of submission queue entries (the completion queue is twice as large.)
+const RING_ENTRIES: u32 = 4096;
+/// The maximum number of bytes a UringStream buffers for sending before write returns WouldBlock.
+const SEND_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Handler is called with the result of a completed operation (a negated errno on failure.)
+type Handler = Box<dyn FnOnce(i32) + Send>;
+
+/// Driver owns the io_uring, and calls the handlers of the operations as they complete.
+pub(crate) struct Driver {
+    ring: IoUring,
+    /// held while adding to the submission queue, which must have a single producer
+    submit_lock: Mutex<()>,
+    handlers: Mutex<FnvHashMap<u64, Handler>>,
+    /// operations submitted by handlers while the submission queue was full, the completion thread submits them
+    overflow: Mutex<Vec<squeue::Entry>>,
+    next_id: AtomicU64,
+}
+
+thread_local! {
+    /// set on the thread running Driver::run, which can't wait for room in the submission queue
+    static COMPLETION_THREAD: Cell<bool> = Cell::new(false);
+}
+
+impl Driver {
+    /// Returns the Driver if io_engine is io_uring and the kernel supports it, otherwise None.
+    pub fn get() -> Option<&'static Driver> {
+        static DRIVER: OnceLock<Option<&'static Driver>> = OnceLock::new();
+
+        if conf().io_engine != IoEngine::IoUring {
+            return None;
+        }
+        *DRIVER.get_or_init(|| {
+            match Driver::start() {
+                Ok(driver) => Some(driver),
+                Err(e) => {
+                    warn!(%e, "io_uring is not available, using io_engine readiness");
+                    None
+                },
+            }
+        })
+    }
+
+    fn start() -> io::Result<&'static Driver> {
+        let driver: &'static Driver = Box::leak(Box::new(Driver{
+            ring: IoUring::new(RING_ENTRIES)?,
+            submit_lock: Mutex::new(()),
+            handlers: Mutex::new(FnvHashMap::default()),
+            overflow: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(1),
+        }));
+        std::thread::Builder::new()
+            .name("riverdb-uring".to_string())
+            .spawn(move || driver.run())?;
+        info!(entries = RING_ENTRIES, "started io_uring driver");
+        Ok(driver)
+    }
+
+    /// Submits the operation described by entry, and calls handler with its result when it completes.
+    /// Returns the id of the operation, which can be passed to cancel.
+    ///
+    /// Safety: any memory entry points to must remain valid until handler is called, usually by moving it into handler.
+    unsafe fn submit(&self, entry: squeue::Entry, handler: Handler) -> u64 {
+        let id = self.next_id.fetch_add(1, Relaxed);
+        self.handlers.lock().unwrap().insert(id, handler);
+        let entry = entry.user_data(id);
+
+        let _guard = self.submit_lock.lock().unwrap();
+        // Safety: we hold submit_lock, so this is the only SubmissionQueue
+        while self.ring.submission_shared().push(&entry).is_err() {
+            // The submission queue is full. The kernel may not consume it until completions are reaped,
+            // so the completion thread (running a handler) can't wait here, it submits the entry later.
+            if COMPLETION_THREAD.with(Cell::get) {
+                self.overflow.lock().unwrap().push(entry);
+                return id;
+            }
+            // Have the kernel consume it
+            self.enter();
+        }
+        self.enter();
+        id
+    }
+
+    /// Adds the entries queued by submit on the completion thread to the submission queue, as many as fit.
+    fn submit_overflow(&self) {
+        let mut overflow = std::mem::take(&mut *self.overflow.lock().unwrap());
+        if overflow.is_empty() {
+            return;
+        }
+        let _guard = self.submit_lock.lock().unwrap();
+        // Safety: we hold submit_lock, so this is the only SubmissionQueue, and the entries were
+        // submitted with the same guarantees as submit requires
+        let mut queue = unsafe { self.ring.submission_shared() };
+        let n = overflow.iter().take_while(|&entry| unsafe { queue.push(entry) }.is_ok()).count();
+        drop(queue);
+        overflow.drain(..n);
+        if !overflow.is_empty() {
+            // Keep the order, anything queued while we held the entries goes after them
+            let mut queued = self.overflow.lock().unwrap();
+            overflow.append(&mut queued);
+            *queued = overflow;
+        }
+    }
+
+    /// Submits the queued operations to the kernel. If that fails, the completion thread submits them later.
+    fn enter(&self) {
+        if let Err(e) = self.ring.submitter().submit() {
+            if !is_transient(&e) {
+                error!(%e, "io_uring submit error");
+            }
+        }
+    }
+
+    /// Cancels the operation with the given id, if it's still in progress, it completes with ECANCELED.
+    fn cancel(&self, id: u64) {
+        // Safety: AsyncCancel doesn't point to any memory
+        unsafe {
+            self.submit(opcode::AsyncCancel::new(id).build(), Box::new(|_| ()));
+        }
+    }
+
+    /// Waits for completed operations and calls their handlers, forever.
+    fn run(&self) {
+        COMPLETION_THREAD.with(|c| c.set(true));
+        let mut completed = Vec::new();
+        loop {
+            self.submit_overflow();
+            if let Err(e) = self.ring.submitter().submit_and_wait(1) {
+                if !is_transient(&e) {
+                    error!(%e, "io_uring wait error");
+                }
+            }
+            // Safety: this is the only thread that uses the completion queue
+            completed.extend(unsafe { self.ring.completion_shared() }.map(|cqe| (cqe.user_data(), cqe.result())));
+            for (id, result) in completed.drain(..) {
+                let handler = self.handlers.lock().unwrap().remove(&id);
+                if let Some(handler) = handler {
+                    handler(result);
+                }
+            }
+        }
+    }
+
+    /// Accepts a connection on the listening socket.
+    pub async fn accept(&'static self, listener: RawFd) -> io::Result<TcpStream> {
+        let fd = Accept::new(self, listener).await?;
+        // Safety: fd is the accepted socket, which we own
+        let stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+        // Callers expect a tokio TcpStream, TransportStream::new_tcp moves it back to io_uring
+        stream.set_nonblocking(true)?;
+        TcpStream::from_std(stream)
+    }
+}
+
+/// Returns true for errors from io_uring_enter that resolve themselves by trying again later.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::EBUSY))
+}
+
+fn wake_all(waiters: &mut Vec<Waker>) {
+    for waker in waiters.drain(..) {
+        waker.wake();
+    }
+}
+
+fn add_waiter(waiters: &mut Vec<Waker>, waker: &Waker) {
+    if !waiters.iter().any(|w| w.will_wake(waker)) {
+        waiters.push(waker.clone());
+    }
+}
+
+#[derive(Default)]
+struct AcceptState {
+    result: Option<i32>,
+    waker: Option<Waker>,
+    /// set if the Accept future was dropped before the operation completed
+    abandoned: bool,
+}
+
+/// Accept is a future for the fd of a connection accepted with an io_uring operation.
+struct Accept {
+    driver: &'static Driver,
+    id: u64,
+    state: Arc<Mutex<AcceptState>>,
+    done: bool,
+}
+
+impl Accept {
+    fn new(driver: &'static Driver, listener: RawFd) -> Self {
+        let state = Arc::new(Mutex::new(AcceptState::default()));
+        let handler_state = state.clone();
+        let entry = opcode::Accept::new(types::Fd(listener), std::ptr::null_mut(), std::ptr::null_mut())
+            .flags(libc::SOCK_CLOEXEC)
+            .build();
+        let handler = Box::new(move |result| {
+            let mut state = handler_state.lock().unwrap();
+            if state.abandoned {
+                if result >= 0 {
+                    unsafe { libc::close(result); }
+                }
+            } else {
+                state.result = Some(result);
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            }
+        });
+        // Safety: we don't ask for the peer address, so entry doesn't point to any memory
+        let id = unsafe { driver.submit(entry, handler) };
+        Self{driver, id, state, done: false}
+    }
+}
+
+impl Future for Accept {
+    type Output = io::Result<RawFd>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut state = this.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => {
+                this.done = true;
+                Poll::Ready(if result < 0 { Err(io::Error::from_raw_os_error(-result)) } else { Ok(result) })
+            },
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+impl Drop for Accept {
+    /// Cancels the operation if it's still in progress, otherwise the listening socket stays open until it completes.
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(fd) if fd >= 0 => unsafe { libc::close(fd); },
+            Some(_) => (),
+            None => {
+                state.abandoned = true;
+                self.driver.cancel(self.id);
+            },
+        }
+    }
+}
+
+/// UringStream is a TCP socket that's read from and written to with io_uring operations.
+pub(crate) struct UringStream {
+    inner: Arc<StreamInner>,
+}
+
+/// StreamInner is shared by the UringStream and its operations in progress, which keep the socket open.
+struct StreamInner {
+    driver: &'static Driver,
+    fd: RawFd,
+    recv_buffer_size: usize,
+    state: Mutex<StreamState>,
+}
+
+#[derive(Default)]
+struct StreamState {
+    /// received bytes, read up to recv_pos
+    recv_buf: Vec<u8>,
+    recv_pos: usize,
+    /// the id of the recv operation in progress, if any, which owns the receive buffer until it completes
+    recv_op: Option<u64>,
+    /// set once the peer closed the connection
+    recv_eof: bool,
+    /// bytes waiting to be sent after the send operation in progress
+    send_buf: Vec<u8>,
+    /// the id of the send operation in progress, if any, and the number of bytes it has left to send
+    send_op: Option<u64>,
+    sending: usize,
+    /// set by shutdown
+    shutdown: bool,
+    /// the first error (an errno) an operation failed with, it's returned by all subsequent reads and writes
+    error: Option<i32>,
+    /// tasks waiting for the stream to become readable or writable
+    read_waiters: Vec<Waker>,
+    write_waiters: Vec<Waker>,
+}
+
+impl UringStream {
+    /// Moves stream from tokio to io_uring, or returns it with the error if that's not possible.
+    pub fn new(driver: &'static Driver, stream: TcpStream) -> std::result::Result<Self, (TcpStream, io::Error)> {
+        // Safety: duplicates an open fd, we own the duplicate
+        let fd = unsafe { libc::fcntl(stream.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err((stream, io::Error::last_os_error()));
+        }
+        // This deregisters the socket from tokio and closes the original fd, the duplicate keeps it open
+        drop(stream);
+        // io_uring waits for the socket to be ready itself, older kernels fail with EAGAIN if it's non-blocking.
+        // Nothing else reads from or writes to the socket directly.
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags >= 0 {
+                libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK);
+            }
+        }
+        Ok(Self{
+            inner: Arc::new(StreamInner{
+                driver,
+                fd,
+                recv_buffer_size: conf().recv_buffer_size as usize,
+                state: Mutex::new(StreamState::default()),
+            }),
+        })
+    }
+
+    /// Waits until the stream is ready for any of interest. It's readable if there are received bytes
+    /// (or the connection is closed), and writable if the send buffer has room (or the connection failed.)
+    pub async fn ready(&self, interest: Interest) -> io::Result<Ready> {
+        futures::future::poll_fn(|cx| self.poll_ready(interest, cx)).await
+    }
+
+    fn poll_ready(&self, interest: Interest, cx: &mut Context<'_>) -> Poll<io::Result<Ready>> {
+        let mut state = self.inner.state.lock().unwrap();
+        let closed = state.error.is_some() || state.shutdown;
+        let mut ready = Ready::EMPTY;
+        if interest.is_readable() && (state.recv_pos < state.recv_buf.len() || state.recv_eof || closed) {
+            ready |= Ready::READABLE;
+        }
+        if interest.is_writable() && (state.send_buf.len() + state.sending < SEND_BUFFER_SIZE || closed) {
+            ready |= Ready::WRITABLE;
+        }
+        if !ready.is_empty() {
+            return Poll::Ready(Ok(ready));
+        }
+
+        if interest.is_readable() {
+            add_waiter(&mut state.read_waiters, cx.waker());
+            StreamInner::start_recv(&self.inner, &mut state);
+        }
+        if interest.is_writable() {
+            add_waiter(&mut state.write_waiters, cx.waker());
+        }
+        Poll::Pending
+    }
+
+    /// Reads received bytes into buf without blocking, returns WouldBlock if there are none
+    /// (and starts receiving more), or Ok(0) if the connection is closed.
+    pub fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.inner.state.lock().unwrap();
+        let available = state.recv_buf.len() - state.recv_pos;
+        if available == 0 {
+            if let Some(errno) = state.error {
+                return Err(io::Error::from_raw_os_error(errno));
+            }
+            if state.recv_eof || state.shutdown {
+                return Ok(0);
+            }
+            StreamInner::start_recv(&self.inner, &mut state);
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        let n = available.min(buf.len());
+        let start = state.recv_pos;
+        buf[..n].copy_from_slice(&state.recv_buf[start..start + n]);
+        state.recv_pos += n;
+        if state.recv_pos == state.recv_buf.len() {
+            StreamInner::start_recv(&self.inner, &mut state);
+        }
+        Ok(n)
+    }
+
+    /// Copies as much of bufs as fits into the send buffer without blocking, and starts sending it.
+    /// Returns the number of bytes copied, or WouldBlock if the send buffer is full.
+    pub fn write_vectored(&self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let mut state = self.inner.state.lock().unwrap();
+        if let Some(errno) = state.error {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        if state.shutdown {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let room = SEND_BUFFER_SIZE.saturating_sub(state.send_buf.len() + state.sending);
+        if room == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        let mut n = 0;
+        for buf in bufs {
+            let len = buf.len().min(room - n);
+            state.send_buf.extend_from_slice(&buf[..len]);
+            n += len;
+            if n == room {
+                break;
+            }
+        }
+        if state.send_op.is_none() && !state.send_buf.is_empty() {
+            StreamInner::start_send(&self.inner, &mut state);
+        }
+        Ok(n)
+    }
+
+    /// Shuts down the socket, which completes the recv in progress and wakes any waiting tasks.
+    /// The sending direction is shut down once the bytes already in the send buffer are sent.
+    pub fn shutdown(&self) {
+        unsafe {
+            libc::shutdown(self.inner.fd, libc::SHUT_RD);
+        }
+        let mut state = self.inner.state.lock().unwrap();
+        state.shutdown = true;
+        if state.send_op.is_none() {
+            // Otherwise send_complete does this when it's done
+            unsafe {
+                libc::shutdown(self.inner.fd, libc::SHUT_WR);
+            }
+        }
+        wake_all(&mut state.read_waiters);
+        wake_all(&mut state.write_waiters);
+    }
+}
+
+impl AsRawFd for UringStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.fd
+    }
+}
+
+impl Drop for UringStream {
+    /// Cancels the recv in progress. The send in progress (and what's buffered after it) is still sent,
+    /// once the operations complete the socket is closed (see StreamInner::drop.)
+    fn drop(&mut self) {
+        let state = self.inner.state.lock().unwrap();
+        if let Some(id) = state.recv_op {
+            self.inner.driver.cancel(id);
+        }
+    }
+}
+
+impl StreamInner {
+    /// Starts receiving into the receive buffer, unless it has bytes left to read or a recv is already in progress.
+    fn start_recv(this: &Arc<Self>, state: &mut StreamState) {
+        if state.recv_op.is_some() || state.recv_pos < state.recv_buf.len() || state.recv_eof || state.shutdown || state.error.is_some() {
+            return;
+        }
+        let mut buf = std::mem::take(&mut state.recv_buf);
+        buf.clear();
+        buf.reserve(this.recv_buffer_size);
+        state.recv_pos = 0;
+
+        let entry = opcode::Recv::new(types::Fd(this.fd), buf.as_mut_ptr(), buf.capacity() as u32).build();
+        let inner = this.clone();
+        // Safety: the handler owns buf until the operation completes, moving a Vec doesn't move its contents
+        state.recv_op = Some(unsafe {
+            this.driver.submit(entry, Box::new(move |result| inner.recv_complete(buf, result)))
+        });
+    }
+
+    fn recv_complete(&self, mut buf: Vec<u8>, result: i32) {
+        let mut state = self.state.lock().unwrap();
+        state.recv_op = None;
+        if result > 0 {
+            // Safety: the kernel initialized the first result bytes
+            unsafe { buf.set_len(result as usize); }
+            state.recv_buf = buf;
+            state.recv_pos = 0;
+        } else if result == 0 {
+            state.recv_eof = true;
+        } else if result == -libc::EAGAIN || result == -libc::EINTR {
+            // Woken readers will start another recv
+            state.recv_buf = buf;
+        } else if result != -libc::ECANCELED {
+            state.error.get_or_insert(-result);
+            wake_all(&mut state.write_waiters);
+        }
+        wake_all(&mut state.read_waiters);
+    }
+
+    /// Starts sending the send buffer.
+    fn start_send(this: &Arc<Self>, state: &mut StreamState) {
+        let buf = std::mem::take(&mut state.send_buf);
+        Self::send(this, state, buf, 0);
+    }
+
+    fn send(this: &Arc<Self>, state: &mut StreamState, buf: Vec<u8>, offset: usize) {
+        let len = buf.len() - offset;
+        let entry = opcode::Send::new(types::Fd(this.fd), buf[offset..].as_ptr(), len as u32)
+            .flags(libc::MSG_NOSIGNAL)
+            .build();
+        let inner = this.clone();
+        state.sending = len;
+        // Safety: the handler owns buf until the operation completes, moving a Vec doesn't move its contents
+        state.send_op = Some(unsafe {
+            this.driver.submit(entry, Box::new(move |result| Self::send_complete(&inner, buf, offset, result)))
+        });
+    }
+
+    fn send_complete(this: &Arc<Self>, mut buf: Vec<u8>, offset: usize, result: i32) {
+        let mut state = this.state.lock().unwrap();
+        state.send_op = None;
+        state.sending = 0;
+        if result > 0 || result == -libc::EAGAIN || result == -libc::EINTR {
+            let offset = offset + result.max(0) as usize;
+            if offset < buf.len() {
+                // Send the rest before anything buffered after it
+                Self::send(this, &mut state, buf, offset);
+            } else if !state.send_buf.is_empty() {
+                Self::start_send(this, &mut state);
+            } else {
+                // Reuse the allocation
+                buf.clear();
+                state.send_buf = buf;
+                if state.shutdown {
+                    // Everything was sent, finish the shutdown
+                    unsafe {
+                        libc::shutdown(this.fd, libc::SHUT_WR);
+                    }
+                }
+            }
+        } else if result != -libc::ECANCELED {
+            // A send of zero bytes means the connection is closed
+            state.error.get_or_insert(if result == 0 { libc::EPIPE } else { -result });
+            wake_all(&mut state.read_waiters);
+        }
+        wake_all(&mut state.write_waiters);
+    }
+}
+
+impl Drop for StreamInner {
+    /// Closes the socket, after the last operation using it completed.
+    fn drop(&mut self) {
+        // Safety: Close doesn't point to any memory, and nothing uses fd after this
+        unsafe {
+            self.driver.submit(opcode::Close::new(types::Fd(self.fd)).build(), Box::new(|_| ()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::riverdb::config::test_config_mut;
+
+    #[tokio::test]
+    async fn test_uring_stream() {
+        unsafe {
+            test_config_mut().io_engine = IoEngine::IoUring;
+            test_config_mut().recv_buffer_size = 4096;
+        }
+        let driver = match Driver::get() {
+            Some(driver) => driver,
+            None => {
+                // The kernel doesn't support io_uring (or it's disabled, e.g. by seccomp in a container)
+                unsafe { test_config_mut().io_engine = IoEngine::Readiness; }
+                return;
+            },
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let stream = UringStream::new(driver, driver.accept(listener.as_raw_fd()).await.unwrap()).ok().unwrap();
+
+        let bufs = [IoSlice::new(b"hello "), IoSlice::new(b"world")];
+        assert!(stream.ready(Interest::WRITABLE).await.unwrap().is_writable());
+        assert_eq!(stream.write_vectored(&bufs).unwrap(), 11);
+        let mut buf = [0; 11];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello world");
+
+        let mut buf = [0; 16];
+        assert_eq!(stream.read(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        client.write_all(b"ping").await.unwrap();
+        assert!(stream.ready(Interest::READABLE).await.unwrap().is_readable());
+        assert_eq!(stream.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ping");
+
+        // The peer sees the connection closed once the stream is dropped
+        drop(stream);
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        unsafe { test_config_mut().io_engine = IoEngine::Readiness; }
+    }
+}
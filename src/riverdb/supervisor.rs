@@ -0,0 +1,242 @@
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+use tokio::io::AsyncReadExt;
+use tokio::process::{Child, Command};
+use tokio::runtime::Builder;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{sleep, timeout, Instant, Duration};
+use tracing::{info, warn, error};
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::config::{Settings, LogSettings, Jitter};
+use crate::riverdb::logging::init_logging;
+use crate::riverdb::server::{Listener, ListenerOptions, LISTEN_FDS_ENV, format_listen_fds};
+use crate::riverdb::pg::{backoff_delay_ms, jittered_delay_ms};
+
+
+/// SUPERVISE_FLAG is the command line flag to run riverdb as the supervisor of a worker process, see supervise.
+pub const SUPERVISE_FLAG: &str = "--supervise";
+/// RESTART_BACKOFF_MS is the delay before restarting a worker that exited abnormally, doubled for each consecutive failure.
+const RESTART_BACKOFF_MS: u64 = 1000;
+/// MAX_RESTART_BACKOFF_MS is the maximum delay before restarting a worker.
+const MAX_RESTART_BACKOFF_MS: u64 = 60 * 1000;
+/// STABLE_RUN is how long a worker has to run before it exits for its restart delay to start over at RESTART_BACKOFF_MS.
+const STABLE_RUN: Duration = Duration::from_secs(60);
+/// READY_FD_ENV is the environment variable with the worker's end of a socket it writes a byte to once it's ready
+/// to accept connections (see notify_ready), set for the new worker started by SIGUSR2.
+pub const READY_FD_ENV: &str = "RIVERDB_READY_FD";
+/// READY_TIMEOUT is how long SIGUSR2 waits for the new worker to be ready before giving up on it.
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Run riverdb as a supervisor: a parent process that binds the listening sockets and runs the server in a worker
+/// process, a copy of this process started with the same arguments except SUPERVISE_FLAG. The worker inherits the
+/// listening sockets (see LISTEN_FDS_ENV), so connections wait in the accept backlog while a worker is restarted.
+///
+/// A worker that exits abnormally (with an error status or killed by a signal) is restarted with exponential backoff.
+/// SIGHUP and SIGUSR1 are forwarded to the worker. SIGTERM and SIGINT are forwarded to the worker, and the supervisor
+/// exits when it does. SIGUSR2 starts a new worker, waits until it's ready to accept connections, and then shuts down
+/// the old one with SIGTERM, for zero-downtime restarts (e.g. after upgrading the binary.) If the new worker fails to
+/// start, the old one keeps running. Returns when the worker exits successfully, or is shut down.
+pub fn supervise(conf: &'static Settings) -> Result<()> {
+    // Log to stdout, not to the worker's log file, so only the worker rotates it
+    init_logging(&LogSettings{
+        level: conf.log.level.clone(),
+        modules: conf.log.modules.clone(),
+        format: conf.log.format,
+        ..Default::default()
+    })?;
+    let tokio = Builder::new_current_thread().enable_all().build()?;
+    // Listener registers its socket with the runtime, so it must be created inside it
+    tokio.block_on(async { Supervisor::new(conf)?.run().await })
+}
+
+struct Supervisor {
+    /// the listening sockets inherited by the workers
+    listeners: Vec<Listener>,
+    /// the arguments to start the workers with
+    args: Vec<String>,
+}
+
+impl Supervisor {
+    fn new(conf: &'static Settings) -> Result<Self> {
+        let mut addresses = Vec::new();
+        for cluster in conf.postgres_clusters().filter(|cluster| cluster.port != 0) {
+            addresses.extend(conf.cluster_listen_addresses(cluster));
+        }
+        if conf.http_port != 0 {
+            addresses.push(conf.http_listen_address());
+        }
+        // A single socket per address, the worker's listeners all accept on it (see reuseport)
+        let options = ListenerOptions{reuseport: false, ..ListenerOptions::from_settings(conf)};
+        let mut listeners = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            listeners.push(Listener::new(address, options)?);
+        }
+        let args = std::env::args().skip(1).filter(|arg| arg != SUPERVISE_FLAG).collect();
+        Ok(Self{listeners, args})
+    }
+
+    /// Start a worker process, which inherits the listening sockets, and ready_fd if given (see READY_FD_ENV.)
+    fn spawn(&self, ready_fd: Option<RawFd>) -> Result<Child> {
+        let listen_fds: Vec<_> = self.listeners.iter().map(|l| (l.address.clone(), l.as_raw_fd())).collect();
+        let mut fds: Vec<RawFd> = listen_fds.iter().map(|(_, fd)| *fd).collect();
+        let mut command = Command::new(std::env::current_exe()?);
+        command.args(&self.args).env(LISTEN_FDS_ENV, format_listen_fds(&listen_fds));
+        if let Some(fd) = ready_fd {
+            command.env(READY_FD_ENV, fd.to_string());
+            fds.push(fd);
+        }
+        // Safety: this runs in the child between fork and exec, and only calls fcntl, which is async-signal-safe
+        unsafe {
+            command.pre_exec(move || {
+                // Clear FD_CLOEXEC so the sockets stay open in the worker
+                for &fd in &fds {
+                    if libc::fcntl(fd, libc::F_SETFD, 0) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn()?;
+        info!(pid = ?child.id(), "started worker");
+        Ok(child)
+    }
+
+    /// Start a worker process and wait until it's ready to accept connections. If it exits first,
+    /// or isn't ready within READY_TIMEOUT, it's killed and this returns an error.
+    async fn spawn_ready(&self) -> Result<Child> {
+        let (ready, worker_end) = UnixStream::pair()?;
+        let mut child = self.spawn(Some(worker_end.as_raw_fd()))?;
+        // Close our copy of the worker's end, so reading returns EOF if the worker exits
+        drop(worker_end);
+        ready.set_nonblocking(true)?;
+        let mut ready = tokio::net::UnixStream::from_std(ready)?;
+        let mut buf = [0u8; 1];
+        let error = match timeout(READY_TIMEOUT, ready.read(&mut buf)).await {
+            Ok(Ok(1)) => return Ok(child),
+            Ok(Ok(_)) => "the worker exited before it was ready".to_string(),
+            Ok(Err(e)) => format!("could not wait for the worker to be ready: {}", e),
+            Err(_) => format!("the worker wasn't ready within {:?}", READY_TIMEOUT),
+        };
+        if let Err(e) = child.kill().await {
+            warn!(%e, "could not kill the worker");
+        }
+        Err(Error::new(error))
+    }
+
+    async fn run(self) -> Result<()> {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let mut sighup = signal(SignalKind::hangup())?;
+        let mut sigusr1 = signal(SignalKind::user_defined1())?;
+        let mut sigusr2 = signal(SignalKind::user_defined2())?;
+
+        let mut child = self.spawn(None)?;
+        let mut started = Instant::now();
+        let mut failures = 0;
+        loop {
+            let shutdown = tokio::select! {
+                status = child.wait() => {
+                    let status = status?;
+                    if status.success() {
+                        info!("worker exited, shutting down");
+                        return Ok(());
+                    }
+                    failures = if started.elapsed() >= STABLE_RUN { 1 } else { failures + 1 };
+                    error!(%status, failures, "worker exited abnormally, restarting it");
+                    // Keep trying with backoff if the worker can't be started either
+                    loop {
+                        let delay = restart_delay(failures);
+                        info!(?delay, "waiting to restart the worker");
+                        tokio::select! {
+                            _ = sleep(delay) => (),
+                            _ = sigterm.recv() => return Ok(()),
+                            _ = sigint.recv() => return Ok(()),
+                        }
+                        match self.spawn(None) {
+                            Ok(new) => {
+                                child = new;
+                                break;
+                            },
+                            Err(e) => {
+                                failures += 1;
+                                error!(%e, failures, "could not start the worker");
+                            },
+                        }
+                    }
+                    started = Instant::now();
+                    None
+                },
+                _ = sigterm.recv() => Some(libc::SIGTERM),
+                _ = sigint.recv() => Some(libc::SIGINT),
+                _ = sighup.recv() => {
+                    signal_child(&child, libc::SIGHUP);
+                    None
+                },
+                _ = sigusr1.recv() => {
+                    signal_child(&child, libc::SIGUSR1);
+                    None
+                },
+                _ = sigusr2.recv() => {
+                    info!("restarting worker");
+                    // Other signals wait until the new worker is ready, or READY_TIMEOUT
+                    match self.spawn_ready().await {
+                        Ok(new) => {
+                            let mut old = std::mem::replace(&mut child, new);
+                            started = Instant::now();
+                            signal_child(&old, libc::SIGTERM);
+                            tokio::spawn(async move {
+                                match old.wait().await {
+                                    Ok(status) => info!(%status, "previous worker exited"),
+                                    Err(e) => warn!(%e, "could not wait for the previous worker to exit"),
+                                }
+                            });
+                        },
+                        Err(e) => error!(%e, "could not start a new worker, keeping the current one"),
+                    }
+                    None
+                },
+            };
+            if let Some(sig) = shutdown {
+                info!(sig, "shutting down worker");
+                signal_child(&child, sig);
+                let status = child.wait().await?;
+                info!(%status, "worker exited, shutting down");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Returns the delay before restarting a worker after failures consecutive failures, with jitter.
+fn restart_delay(failures: u32) -> Duration {
+    let delay_ms = backoff_delay_ms(RESTART_BACKOFF_MS, MAX_RESTART_BACKOFF_MS, failures);
+    Duration::from_millis(jittered_delay_ms(delay_ms, Jitter::Equal, rand::random()))
+}
+
+/// Tell the supervisor this worker is ready to accept connections, if it's waiting for that (see READY_FD_ENV.)
+/// Must only be called once.
+pub fn notify_ready() {
+    let fd: RawFd = match std::env::var(READY_FD_ENV).ok().and_then(|fd| fd.parse().ok()) {
+        Some(fd) => fd,
+        None => return,
+    };
+    // Safety: the supervisor passed fd open to our end of the socket, nothing else uses it
+    let mut stream = unsafe { UnixStream::from_raw_fd(fd) };
+    if let Err(e) = stream.write_all(b"R") {
+        warn!(%e, "could not tell the supervisor the worker is ready");
+    }
+}
+
+/// Send the signal to the child process, if it's still running.
+fn signal_child(child: &Child, sig: libc::c_int) {
+    if let Some(pid) = child.id() {
+        // Safety: kill has no memory safety requirements
+        unsafe {
+            libc::kill(pid as libc::pid_t, sig);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use crate::tests::common::{self, TestServer};
+use crate::tests::harness::{TestClient, start_mock_server};
+use crate::riverdb::pg::WaitEvent;
+
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_admin_commands_require_admin_users() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(common::mock_settings(backend.port(), "admin_users: [riverdb_admin]", "")?)?;
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    for command in ["SHOW CLIENTS", "SHOW ACTIVITY", "RELOAD", "DUMP STATE", "SET LOG LEVEL 'debug'"] {
+        let err = client.simple_query(command).await.expect_err(command);
+        assert!(err.to_string().contains("admin_users"), "{}: {}", command, err);
+    }
+    // The riverdb_*() functions only describe the client's own session and are available to all clients
+    let result = client.simple_query("SELECT riverdb_version()").await?;
+    assert_eq!(result.rows.len(), 1);
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_admin_command_errors() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(common::mock_settings(backend.port(), &format!("admin_users: [{}]", common::TEST_USER), "")?)?;
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    // A failed command returns an error, the session continues
+    for (command, expected) in [
+        ("RELOAD", "RELOAD failed"), // there's no config file to read
+        ("DRAIN SERVER '10.0.0.1:5432'", "unknown server 10.0.0.1:5432"),
+        ("ENABLE PLUGIN 'NoSuchPlugin'", "no listeners for plugin NoSuchPlugin"),
+        ("SET LOG LEVEL 'riverdb=nope'", "SET LOG LEVEL failed"),
+    ] {
+        let err = client.simple_query(command).await.expect_err(command);
+        assert!(err.to_string().contains(expected), "{}: {}", command, err);
+        assert_eq!(client.tx_status, b'I');
+        client.simple_query("SELECT 1").await?;
+    }
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_dump_state() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(common::mock_settings(backend.port(), &format!("admin_users: [{}]", common::TEST_USER), "")?)?;
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    client.simple_query("BEGIN").await?;
+    // The snapshot is written to the log, the session and its transaction continue
+    let result = client.simple_query("DUMP STATE").await?;
+    assert_eq!(result.tags, vec!["DUMP".to_string()]);
+    assert_eq!(client.tx_status, b'T');
+    client.simple_query("COMMIT").await?;
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_show_wait_events() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(common::mock_settings(backend.port(), &format!("admin_users: [{}]", common::TEST_USER), "")?)?;
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    let idle = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    let result = client.simple_query("SHOW WAIT EVENTS").await?;
+    let events: Vec<_> = result.rows.iter().map(|row| row[0].clone().unwrap_or_default()).collect();
+    assert_eq!(events, WaitEvent::ALL.iter().map(|event| event.to_string()).collect::<Vec<_>>());
+    // The idle session waits for its next query, this one is being answered
+    let sessions = |event: WaitEvent| result.rows[event as usize][1].clone().unwrap_or_default();
+    assert_eq!(sessions(WaitEvent::ClientRead), "1");
+    assert_eq!(sessions(WaitEvent::None), "1");
+
+    // SHOW ACTIVITY only lists sessions
+    let result = client.simple_query("SHOW ACTIVITY").await?;
+    assert_eq!(result.rows.len(), 2);
+    idle.terminate().await?;
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
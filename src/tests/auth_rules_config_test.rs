@@ -0,0 +1,46 @@
+use crate::riverdb::config::AuthMethod;
+use crate::tests::common::load;
+
+#[test]
+fn test_auth_rules() {
+    let settings = load(r#"
+postgres:
+  servers: []
+  auth_rules:
+    rules:
+      - {address: 127.0.0.1, method: trust}
+      - {address: "::1/128", method: trust}
+      - {address: 10.0.0.0/8, user: admin, method: reject}
+      - {address: 10.0.0.0/8, database: app, method: scram}
+      - {method: md5}
+plugins: []
+"#).expect("valid settings");
+
+    let rules = &settings.postgres.auth_rules;
+    let method = |ip: Option<&str>, database, user| {
+        rules.rule_for(ip.map(|ip| ip.parse().unwrap()), database, user).map(|rule| rule.method)
+    };
+    assert_eq!(method(Some("127.0.0.1"), "app", "web"), Some(AuthMethod::Trust));
+    assert_eq!(method(Some("::ffff:127.0.0.1"), "app", "web"), Some(AuthMethod::Trust));
+    assert_eq!(method(Some("::1"), "app", "web"), Some(AuthMethod::Trust));
+    assert_eq!(method(Some("10.1.2.3"), "app", "admin"), Some(AuthMethod::Reject));
+    assert_eq!(method(Some("10.1.2.3"), "app", "web"), Some(AuthMethod::Scram));
+    assert_eq!(method(Some("10.1.2.3"), "other", "web"), Some(AuthMethod::Md5));
+    assert_eq!(method(Some("11.1.2.3"), "app", "admin"), Some(AuthMethod::Md5));
+    assert_eq!(method(None, "app", "web"), Some(AuthMethod::Md5));
+}
+
+#[test]
+fn test_auth_rules_invalid_address() {
+    let result = load("postgres: {servers: [], auth_rules: {rules: [{address: 10.0.0.0/33, method: trust}]}}\nplugins: []");
+    assert!(result.err().unwrap().contains("invalid prefix length"));
+
+    let result = load("postgres: {servers: [], auth_rules: {rules: [{address: localhost, method: trust}]}}\nplugins: []");
+    assert!(result.err().unwrap().contains("not a valid IP address"));
+}
+
+#[test]
+fn test_auth_rules_cert_requires_ca() {
+    let result = load("postgres: {servers: [], auth_rules: {rules: [{method: cert}]}}\nplugins: []");
+    assert!(result.err().unwrap().contains("require tls_client_ca_certificate"));
+}
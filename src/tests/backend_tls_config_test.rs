@@ -0,0 +1,104 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::time::{Duration, SystemTime};
+
+use rustls::{Certificate, ServerCertVerifier, ServerName};
+
+use crate::riverdb::config::TlsMode;
+use crate::riverdb::server::CertificateChainVerifier;
+use crate::tests::common::load;
+
+const CLIENT_CERT: &str = "src/tests/testdata/test-ca/rsa/client.fullchain";
+const CLIENT_KEY: &str = "src/tests/testdata/test-ca/rsa/client.key";
+const CA_CERT: &str = "src/tests/testdata/test-ca/rsa/ca.cert";
+const SERVER_CERT: &str = "src/tests/testdata/test-ca/rsa/end.fullchain";
+
+fn cluster_yaml(tls: &str) -> String {
+    format!(r#"
+postgres:
+  backend_tls: required
+  {}
+  default: {{database: app, can_query: true, replicas: []}}
+  servers:
+    - {{database: app, host: 127.0.0.1, can_query: true, replicas: []}}
+plugins: []
+"#, tls)
+}
+
+#[test]
+fn test_backend_client_certificate() {
+    let settings = load(&cluster_yaml(&format!(
+        "tls_client_certificate: {}\n  tls_client_key: {}", CLIENT_CERT, CLIENT_KEY))).expect("valid settings");
+    assert!(settings.postgres.backend_tls_config.is_some());
+
+    // Inlined PEM values work the same as file paths
+    let cert = std::fs::read_to_string(CLIENT_CERT).unwrap();
+    let key = std::fs::read_to_string(CLIENT_KEY).unwrap();
+    let inline = format!("tls_client_certificate: {:?}\n  tls_client_key: {:?}", cert, key);
+    let settings = load(&cluster_yaml(&inline)).expect("valid settings");
+    assert!(settings.postgres.backend_tls_config.is_some());
+}
+
+#[test]
+fn test_backend_client_certificate_invalid() {
+    let err = load(&cluster_yaml(&format!("tls_client_certificate: {}", CLIENT_CERT))).err().unwrap();
+    assert_eq!(err, "tls_client_certificate requires tls_client_key");
+
+    let err = load(&cluster_yaml(&format!("tls_client_key: {}", CLIENT_KEY))).err().unwrap();
+    assert_eq!(err, "tls_client_key requires tls_client_certificate");
+
+    let err = load(&cluster_yaml(&format!(
+        "tls_client_certificate: missing.cert\n  tls_client_key: {}", CLIENT_KEY))).err().unwrap();
+    assert_eq!(err, "tls_client_certificate does not exist");
+
+    let err = load(&cluster_yaml(&format!(
+        "tls_client_certificate: {}\n  tls_client_key: {}", CLIENT_KEY, CLIENT_KEY))).err().unwrap();
+    assert_eq!(err, "tls_client_certificate does not contain any certificates");
+}
+
+#[test]
+fn test_backend_tls_verify_modes() {
+    let settings = load(&cluster_yaml("").replace("required", "verify-ca")).expect("valid settings");
+    assert!(matches!(settings.postgres.backend_tls, TlsMode::VerifyCa));
+    assert!(settings.postgres.backend_tls_config.is_some());
+
+    let settings = load(&cluster_yaml("").replace("required", "verify-full")).expect("valid settings");
+    assert!(matches!(settings.postgres.backend_tls, TlsMode::VerifyFull));
+    assert!(settings.postgres.backend_tls_config.is_some());
+
+    assert!(!TlsMode::VerifyCa.verifies_hostname());
+    assert!(TlsMode::VerifyFull.verifies_hostname());
+    assert!(TlsMode::Prefer.verifies_hostname());
+
+    let err = load(&cluster_yaml("client_tls: verify-ca")).err().unwrap();
+    assert!(err.starts_with("client_tls does not support verify-ca or verify-full"), "{}", err);
+}
+
+fn read_certs(path: &str) -> Vec<Certificate> {
+    let mut r = BufReader::new(File::open(path).unwrap());
+    rustls_pemfile::certs(&mut r).unwrap().into_iter().map(Certificate).collect()
+}
+
+#[test]
+fn test_certificate_chain_verifier() {
+    let chain = read_certs(SERVER_CERT);
+    // The test certificates have expired, verify them as of when they were still valid
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let verify = |roots: Vec<Vec<u8>>, name: &str| {
+        CertificateChainVerifier::new(roots).verify_server_cert(
+            &chain[0], &chain[1..], &ServerName::try_from(name).unwrap(), &mut std::iter::empty(), &[], now)
+    };
+    let trusted = || read_certs(CA_CERT).into_iter().map(|c| c.0).collect::<Vec<_>>();
+
+    assert!(verify(trusted(), "localhost").is_ok());
+    // The hostname is not verified
+    assert!(verify(trusted(), "db.example.com").is_ok());
+    // But the chain is
+    assert!(verify(Vec::new(), "localhost").is_err());
+    assert!(verify(vec![b"not a certificate".to_vec()], "localhost").is_err());
+    // And so is the expiry
+    let expired = CertificateChainVerifier::new(trusted()).verify_server_cert(
+        &chain[0], &chain[1..], &ServerName::try_from("localhost").unwrap(), &mut std::iter::empty(), &[], SystemTime::now());
+    assert!(expired.is_err());
+}
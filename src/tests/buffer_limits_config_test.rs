@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+
+use crate::tests::common::{self, TestServer};
+use crate::tests::harness::{TestClient, start_mock_server};
+use crate::riverdb::Error;
+use crate::riverdb::config::Settings;
+use crate::riverdb::pg::protocol::{MessageBuilder, PostgresError, Tag};
+
+
+fn buffer_limits_settings(port: u16, max_message_bytes: u32, max_backlog_bytes: u32) -> Result<Settings, serde_yaml::Error> {
+    common::mock_settings(port, &format!("max_message_bytes: {}\nmax_backlog_bytes: {}", max_message_bytes, max_backlog_bytes), "")
+}
+
+#[test]
+fn test_buffer_limits_config() -> Result<(), serde_yaml::Error> {
+    let mut settings = buffer_limits_settings(5432, 1024, 4096)?;
+    settings.load(PathBuf::new()).unwrap();
+    assert_eq!(settings.postgres.max_message_bytes, 1024);
+    assert_eq!(settings.postgres.max_backlog_bytes, 4096);
+
+    let mut settings: Settings = serde_yaml::from_str("postgres: {servers: []}\nplugins: []")?;
+    settings.load(PathBuf::new()).unwrap();
+    assert_eq!(settings.postgres.max_message_bytes, 0);
+    assert_eq!(settings.postgres.max_backlog_bytes, 0);
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_max_message_bytes() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(buffer_limits_settings(backend.port(), 1024, 0)?)?;
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    client.simple_query("SELECT 1").await?;
+    let mut mb = MessageBuilder::new(Tag::QUERY);
+    mb.write_str(&format!("SELECT 1 /* {} */", "x".repeat(2000)));
+    client.send(mb.finish()).await?;
+    let msgs = client.read_message().await?;
+    assert_eq!(msgs.first().unwrap().tag(), Tag::ERROR_RESPONSE);
+    let err = PostgresError::new(msgs)?;
+    assert!(err.to_string().contains("exceeds the maximum of 1024"), "{}", err);
+    assert_eq!(client.read_message().await.unwrap_err(), Error::closed());
+    server.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_max_backlog_bytes() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(buffer_limits_settings(backend.port(), 0, 1024 * 1024)?)?;
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    // A result larger than the socket buffers and max_backlog_bytes disconnects the client before it's sent
+    let mut mb = MessageBuilder::new(Tag::QUERY);
+    mb.write_str("SELECT repeat('x', 33554432)");
+    client.send(mb.finish()).await?;
+    assert!(client.read_until_ready().await.is_err());
+
+    // Results that fit are unaffected
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    let result = client.simple_query("SELECT repeat('x', 100000)").await?;
+    assert_eq!(result.rows[0][0].as_ref().map(String::len), Some(100000));
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
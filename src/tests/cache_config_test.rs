@@ -0,0 +1,54 @@
+use crate::riverdb::Result;
+use crate::riverdb::config::CacheSettings;
+use crate::riverdb::pg::protocol::{MessageBuilder, Tag};
+use crate::riverdb::pg::sql::QueryMessage;
+
+fn make_query(query: &str) -> Result<QueryMessage> {
+    let mut mb = MessageBuilder::new(Tag::QUERY);
+    mb.write_str(query);
+    QueryMessage::new(mb.finish())
+}
+
+#[test]
+fn test_cache_policies() {
+    let yaml = r#"
+enabled: true
+policies:
+  - query: "select * from users where id = 42"
+    ttl_seconds: 60
+  - {"tag": "cache=products*", "ttl_seconds": 5, "max_entry_bytes": 1024, "stale_while_revalidate_seconds": 10}
+"#;
+    let mut settings: CacheSettings = serde_yaml::from_str(yaml).expect("invalid yaml");
+    settings.load().expect("invalid cache settings");
+
+    assert_eq!(settings.policies[0].query, "SELECT * FROM USERS WHERE ID = $1");
+    assert_eq!(settings.policies[0].max_entry_bytes, settings.max_entry_bytes);
+
+    let q = make_query("SELECT * FROM users WHERE id = 7").unwrap();
+    assert_eq!(settings.policy_for(&q).map(|p| p.ttl_seconds), Some(60));
+
+    let q = make_query("SELECT /* cache=products.list */ * FROM products").unwrap();
+    assert_eq!(settings.policy_for(&q).map(|p| p.ttl_seconds), Some(5));
+
+    let q = make_query("SELECT /* cache=orders */ * FROM orders").unwrap();
+    assert!(settings.policy_for(&q).is_none());
+
+    settings.enabled = false;
+    let q = make_query("SELECT * FROM users WHERE id = 7").unwrap();
+    assert!(settings.policy_for(&q).is_none());
+}
+
+#[test]
+fn test_cache_policies_invalid() {
+    let tests = &[
+        ("policies: [{ttl_seconds: 5}]", "cache policies[0]: one of query or tag is required"),
+        ("policies: [{tag: foo, ttl_seconds: 5}]", "cache policies[0]: tag must be of the form key=value, got foo"),
+        ("policies: [{tag: foo=bar, ttl_seconds: 0}]", "cache policies[0]: ttl_seconds must be > 0"),
+    ];
+
+    for (yaml, err) in tests {
+        let mut settings: CacheSettings = serde_yaml::from_str(yaml).expect("invalid yaml");
+        let res = settings.load();
+        assert_eq!(res.unwrap_err().to_string().as_str(), *err);
+    }
+}
@@ -0,0 +1,75 @@
+use std::sync::atomic::Ordering::Relaxed;
+
+use crate::tests::common::{self, prepare};
+use crate::tests::harness::{TestClient, start_mock_server, SERVED};
+use crate::riverdb::pg::protocol::{Messages, MessageBuilder, Tag};
+
+
+const CACHED_QUERY: &str = "SELECT /* cache=counters */ served FROM counters";
+
+/// Returns the value returned by CACHED_QUERY, which changes each time the mock server answers it.
+async fn served(client: &mut TestClient) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    let result = client.simple_query(CACHED_QUERY).await?;
+    Ok(result.rows[0][0].clone().unwrap_or_default())
+}
+
+/// Returns the messages to execute the named prepared statement, without a Parse.
+fn execute(name: &str) -> Messages {
+    let mut mb = MessageBuilder::new(Tag::BIND);
+    mb.write_str(""); // unnamed portal
+    mb.write_str(name);
+    mb.write_i16(0);
+    mb.write_i16(0);
+    mb.write_i16(0);
+    mb.add_new(Tag::EXECUTE);
+    mb.write_str("");
+    mb.write_i32(0);
+    mb.add_new(Tag::SYNC);
+    mb.finish()
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_cache_invalidation() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let mut settings = common::mock_settings(backend.port(), "", "")?;
+    settings.cache = serde_yaml::from_str("{enabled: true, policies: [{tag: cache=counters, ttl_seconds: 600}]}")?;
+    let (server, mut client) = common::connect_proxy(settings).await?;
+
+    let cached = served(&mut client).await?;
+    assert_eq!(cached, SERVED.load(Relaxed).to_string());
+    assert_eq!(served(&mut client).await?, cached);
+
+    // A write to a different table (quoted identifiers are case sensitive) doesn't invalidate the result
+    client.simple_query("UPDATE \"Counters\" SET n = n + 1").await?;
+    assert_eq!(served(&mut client).await?, cached);
+    // Writing the same table under another spelling does
+    client.simple_query("UPDATE public.COUNTERS SET n = n + 1").await?;
+    let cached = served(&mut client).await?;
+    assert_eq!(cached, SERVED.load(Relaxed).to_string());
+    assert_eq!(served(&mut client).await?, cached);
+
+    // Executing a prepared read doesn't invalidate the result, executing a prepared write does
+    client.send(prepare("read", "SELECT n FROM counters")).await?;
+    client.read_until_ready().await?;
+    client.send(prepare("write", "UPDATE counters SET n = $1")).await?;
+    client.read_until_ready().await?;
+    assert_eq!(served(&mut client).await?, cached);
+    client.send(execute("read")).await?;
+    client.read_until_ready().await?;
+    assert_eq!(served(&mut client).await?, cached);
+    client.send(execute("write")).await?;
+    client.read_until_ready().await?;
+    let cached = served(&mut client).await?;
+    assert_eq!(cached, SERVED.load(Relaxed).to_string());
+    assert_eq!(served(&mut client).await?, cached);
+
+    // What a statement riverdb didn't see prepared writes is unknown, executing it invalidates all results
+    client.send(execute("unknown")).await?;
+    client.read_until_ready().await?;
+    assert_ne!(served(&mut client).await?, cached);
+
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
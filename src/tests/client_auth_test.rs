@@ -6,6 +6,7 @@ use test_env_log::test;
 
 use crate::register_scoped;
 use crate::tests::common;
+use crate::tests::harness::TestClient;
 use crate::riverdb::{Error, Result, Plugin};
 use crate::riverdb::pg::{PostgresCluster, ClientConn, ClientState, client_authenticate};
 use crate::riverdb::pg::protocol::{Messages, AuthType};
@@ -53,7 +54,8 @@ async fn test_client_auth() -> std::result::Result<(), Box<dyn std::error::Error
     }
 
     let listener = common::listener();
-    let _psql = common::psql(format!("host=localhost port={}", listener.local_addr().unwrap().port()).as_str(), "");
+    let addr = listener.local_addr().unwrap();
+    let _client = tokio::spawn(TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD));
 
     let (s, _) = listener.accept().await?;
     let client = ClientConn::new(s, Connections::new(16, 0));
@@ -61,7 +63,6 @@ async fn test_client_auth() -> std::result::Result<(), Box<dyn std::error::Error
     let plugin = AuthPlugin::new();
     register_scoped!(plugin, Cleanup, AuthPlugin:client_authenticate<'a>(auth_type: AuthType, msgs: Messages) -> Result<()>);
 
-    //psql.stdin.unwrap().write("\\q\n".as_bytes());
     assert_eq!(client.run().await, Err(Error::closed()));
     assert_eq!(client.state(), ClientState::Closed);
     assert!(plugin.passed.load(Acquire));
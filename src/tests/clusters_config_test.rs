@@ -0,0 +1,57 @@
+use crate::tests::common::load;
+
+#[test]
+fn test_clusters_ports() {
+    let settings = load(r#"
+postgres: {servers: [], port: 5432}
+clusters:
+  - {servers: [], port: 5433, max_connections: 100}
+plugins: []
+"#).expect("valid settings");
+
+    let ports: Vec<u16> = settings.postgres_clusters().map(|c| c.port).collect();
+    assert_eq!(ports, vec![5432, 5433]);
+    assert_eq!(settings.clusters[0].max_connections, 100);
+    assert_eq!(settings.cluster_listen_address(&settings.clusters[0]), "0.0.0.0:5433");
+}
+
+#[test]
+fn test_clusters_listen() {
+    let settings = load(r#"
+postgres: {servers: [], port: 5432, listen: ["0.0.0.0", "::", "[::1]"]}
+clusters:
+  - {servers: [], port: 5433}
+plugins: []
+"#).expect("valid settings");
+
+    assert_eq!(settings.cluster_listen_addresses(&settings.postgres), vec!["0.0.0.0:5432", "[::]:5432", "[::1]:5432"]);
+    assert_eq!(settings.cluster_listen_address(&settings.postgres), "0.0.0.0:5432");
+    assert_eq!(settings.cluster_listen_addresses(&settings.clusters[0]), vec!["0.0.0.0:5433"]);
+}
+
+#[test]
+fn test_http_host() {
+    let settings = load("postgres: {servers: []}\nhttp_port: 8080\nplugins: []").expect("valid settings");
+    // The HTTP service isn't authenticated, so it doesn't listen on the public host by default
+    assert_eq!(settings.host, "0.0.0.0");
+    assert_eq!(settings.http_listen_address(), "127.0.0.1:8080");
+
+    let settings = load("postgres: {servers: []}\nhttp_port: 8080\nhttp_host: \"::\"\nplugins: []").expect("valid settings");
+    assert_eq!(settings.http_listen_address(), "[::]:8080");
+}
+
+#[test]
+fn test_clusters_invalid() {
+    let tests = &[
+        ("postgres: {servers: []}\nclusters: [{servers: []}]\nplugins: []", "port 5432 is used by more than one cluster"),
+        ("postgres: {servers: []}\nclusters: [{servers: [], port: 0}]\nplugins: []", "clusters must each specify a port"),
+        ("postgres: {servers: []}\nclusters: [{servers: [], port: 5433}, {servers: [], port: 5433}]\nplugins: []", "port 5433 is used by more than one cluster"),
+        ("postgres: {servers: [], listen: [localhost]}\nplugins: []", "invalid listen address localhost"),
+    ];
+
+    for (yaml, err) in tests {
+        let result = load(yaml);
+        assert!(result.is_err(), "{}", yaml);
+        assert!(result.err().unwrap().contains(err), "{}", yaml);
+    }
+}
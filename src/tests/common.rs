@@ -1,14 +1,21 @@
 use std::env;
-use std::sync::atomic::AtomicU16;
-use std::sync::atomic::Ordering::{Relaxed};
 use std::net::{Ipv4Addr, SocketAddr, IpAddr};
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::path::Path;
 use std::process::{Command, Child, Stdio};
 
 use tokio::net::{TcpListener, TcpSocket};
+use tokio::task::JoinHandle;
 
 
-use crate::riverdb::config;
-use crate::riverdb::pg::PostgresCluster;
+use crate::riverdb::{config, Result};
+use crate::riverdb::config::{Settings, test_config_mut};
+use crate::riverdb::pg::{PostgresCluster, PostgresService, ClientConn, IsolationLevel};
+use crate::riverdb::pg::protocol::{Messages, MessageBuilder, Tag};
+use crate::riverdb::server::{Connection, Connections, ListenerOptions};
+use crate::riverdb::worker::init_workers;
+use crate::tests::harness::TestClient;
 
 
 pub const TEST_DATABASE: &str = "riverdb_test";
@@ -16,19 +23,52 @@ pub const TEST_USER: &str = TEST_DATABASE;
 pub const TEST_USER_RO: &str = "riverdb_test_ro";
 pub const TEST_PASSWORD: &str = "1234"; // the kind of thing an idiot might put on their luggage
 pub const TEST_PASSWORD_RO: &str = "openseasame";
-pub static LISTEN_PORT: AtomicU16 = AtomicU16::new(10101);
 
+/// Loads and validates the settings in yaml, returning the error message if they're invalid.
+pub fn load(yaml: &str) -> std::result::Result<Settings, String> {
+    let mut settings: Settings = serde_yaml::from_str(yaml).map_err(|e| e.to_string())?;
+    settings.load(PathBuf::new()).map_err(|e| e.to_string())?;
+    Ok(settings)
+}
+
+/// Returns settings with a cluster of one server, the harness::mock_server listening on port, for
+/// TestServer::with_settings. The lines of postgres are added to the postgres section, and server
+/// to the options of the server, e.g. mock_settings(port, "unbuffered_begin: true", "retry_reads: true").
+pub fn mock_settings(port: u16, postgres: &str, server: &str) -> std::result::Result<Settings, serde_yaml::Error> {
+    let postgres: String = postgres.lines().map(|line| format!("  {}\n", line)).collect();
+    let server = if server.is_empty() { String::new() } else { format!(", {}", server) };
+    serde_yaml::from_str(&format!(r#"
+postgres:
+{postgres}  servers:
+    - {{database: {database}, host: 127.0.0.1, port: {port}, user: {user}, password: "{password}", max_connections: 16, can_query: true, replicas: []{server}}}
+plugins: []
+"#, postgres=postgres, database=TEST_DATABASE, port=port, user=TEST_USER, password=TEST_PASSWORD, server=server))
+}
+
+/// Start a TestServer with settings and connect a TestClient to it as TEST_USER.
+pub async fn connect_proxy(settings: Settings) -> Result<(TestServer, TestClient)> {
+    let server = TestServer::with_settings(settings)?;
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), server.port());
+    let client = TestClient::connect(addr, TEST_USER, TEST_DATABASE, TEST_PASSWORD).await?;
+    Ok((server, client))
+}
+
+/// Returns the messages to prepare the named statement for sql, ending with Sync.
+pub fn prepare(name: &str, sql: &str) -> Messages {
+    let mut mb = MessageBuilder::new(Tag::PARSE);
+    mb.write_str(name);
+    mb.write_str(sql);
+    mb.write_i16(0);
+    mb.add_new(Tag::SYNC);
+    mb.finish()
+}
+
+/// Returns a TcpListener bound to an ephemeral port on localhost chosen by the OS.
 pub fn listener() -> TcpListener {
-    let mut port: u16 = 0;
-    for _i in 0..10 {
-        port = LISTEN_PORT.fetch_add(1, Relaxed);
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
-        let sock = TcpSocket::new_v4().unwrap();
-        if let Ok(_) = sock.bind(addr) {
-            return sock.listen(32).expect("couldn't listen on socket");
-        }
-    }
-    panic!("couldn't find an available listen port between {}-{}", port-10, port);
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+    let sock = TcpSocket::new_v4().unwrap();
+    sock.bind(addr).expect("couldn't bind socket");
+    sock.listen(32).expect("couldn't listen on socket")
 }
 
 pub fn cluster() -> &'static PostgresCluster {
@@ -46,23 +86,66 @@ pub fn cluster() -> &'static PostgresCluster {
                 user: TEST_USER.to_string(),
                 password: TEST_PASSWORD.to_string(),
                 tls_host: "".to_string(),
+                gss_keytab: "".to_string(),
+                gss_service_name: "postgres".to_string(),
                 port: 5432,
                 is_master: true,
                 can_query: true,
+                weight: 1,
+                failover_priority: 0,
+                replica_read_only: false,
                 max_concurrent_transactions: 10,
                 max_connections: 16,
                 idle_timeout_seconds: 0,
+                server_lifetime_seconds: 0,
+                min_idle: 0,
+                connect_rate: 10,
+                too_many_connections_retries: 5,
+                too_many_connections_backoff_ms: 100,
+                pool_wait_timeout_ms: 5000,
+                max_pool_waiters: 1000,
+                pool_mode: config::PoolMode::Transaction,
+                retry_reads: false,
+                max_isolation: IsolationLevel::None,
+                max_isolation_action: config::IsolationAction::Reject,
+                user_pools: vec![],
                 replicas: vec![],
                 address: None,
-                cluster: None
+                cluster: None,
+                user_pool_configs: vec![]
             }
         ],
         default: Default::default(),
         port: 5433,
+        listen: vec![],
+        unix_socket_dir: String::new(),
+        accept_proxy_protocol: false,
         pinned_sessions: false,
         defer_begin: false,
+        unbuffered_begin: false,
+        server_reset_query: "RESET ROLE; RESET ALL".to_string(),
+        server_reset_query_always: false,
         max_connections: 16,
         idle_timeout_seconds: 0,
+        drain_timeout_seconds: 60,
+        max_startup_packet_bytes: 10000,
+        max_message_bytes: 0,
+        max_backlog_bytes: 0,
+        startup_timeout_seconds: 15,
+        ban_seconds: 0,
+        ban_after_violations: 3,
+        reject_old_protocol_silently: false,
+        protocol_options: Default::default(),
+        request_id_application_name: false,
+        split_multi_statement_queries: false,
+        collapse_in_lists: false,
+        preserve_identifier_case: false,
+        max_normalize_bytes: 1024 * 1024,
+        client_auth: Default::default(),
+        auth_query: "".to_string(),
+        auth_rules: Default::default(),
+        jwt: Default::default(),
+        admin_users: vec![],
         client_tls: Default::default(),
         backend_tls: Default::default(),
         tls_client_certificate: "".to_string(),
@@ -70,13 +153,123 @@ pub fn cluster() -> &'static PostgresCluster {
         tls_root_certificate: "".to_string(),
         tls_server_certificate: "".to_string(),
         tls_server_key: "".to_string(),
+        tls_client_ca_certificate: "".to_string(),
+        tls_min_version: Default::default(),
+        tls_cipher_suites: vec![],
+        tls_session_cache_size: 256,
+        tls_session_tickets: true,
+        tls_alpn_protocols: vec![],
+        tls_reload_check_seconds: 60,
+        in_pool_notifications: Default::default(),
+        dropped_messages_grace_ms: 0,
+        query_timeout_ms: 0,
+        replica_selection: Default::default(),
+        replica_warmup_seconds: 0,
+        max_replica_lag_ms: 0,
+        replica_lag_check_ms: 1000,
+        shard_map: Default::default(),
+        sharding: Default::default(),
+        error_stats: Default::default(),
+        slow_replica: Default::default(),
+        slow_query: Default::default(),
+        health_check: Default::default(),
+        connect_retry: Default::default(),
+        latency_injection: Default::default(),
+        rate_limit: Default::default(),
+        query_tags: Default::default(),
+        query_types: Default::default(),
         tls_config: None,
+        tls_server_end_point: vec![],
         backend_tls_config: None
     }));
     conf.load().expect("invalid config");
     Box::leak(Box::new(PostgresCluster::new(&*conf)))
 }
 
+/// TestServer runs a PostgresService in-process on an ephemeral port of localhost,
+/// for tests that connect to riverdb the way a client would.
+/// The service is stopped by shutdown() or when the TestServer is dropped.
+pub struct TestServer {
+    service: &'static PostgresService,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Start a server for cluster (e.g. common::cluster()) with the default ListenerOptions.
+    /// Must be called from within a tokio runtime.
+    pub fn start(cluster: &'static PostgresCluster) -> Self {
+        Self::start_with_options(cluster, ListenerOptions::default())
+    }
+
+    /// Start a server for the postgres cluster in settings, after validating them.
+    /// settings replace the thread-local Settings returned by conf() for the rest of the test.
+    pub fn with_settings(settings: Settings) -> Result<Self> {
+        // Load in place, the server configs store pointers to their PostgresCluster
+        let conf = unsafe {
+            let conf = test_config_mut();
+            *conf = settings;
+            conf.load(PathBuf::new())?;
+            &*conf
+        };
+        let cluster = Box::leak(Box::new(PostgresCluster::new(&conf.postgres)));
+        Ok(Self::start_with_options(cluster, ListenerOptions::from_settings(conf)))
+    }
+
+    fn start_with_options(cluster: &'static PostgresCluster, options: ListenerOptions) -> Self {
+        unsafe {
+            init_workers(1);
+        }
+        let service: &'static PostgresService = Box::leak(Box::new(PostgresService::new(
+            "127.0.0.1:0".to_string(), cluster, options)));
+        Self{
+            service,
+            task: tokio::spawn(service.run()),
+        }
+    }
+
+    /// Returns the port the server is listening on.
+    pub fn port(&self) -> u16 {
+        self.service.local_addr().expect("couldn't get listener address").port()
+    }
+
+    /// Returns a libpq connection string for the server, without user or database.
+    pub fn connection_string(&self) -> String {
+        format!("host=localhost port={}", self.port())
+    }
+
+    /// Returns the cluster clients of this server connect to, to inspect its pools and stats.
+    pub fn cluster(&self) -> &'static PostgresCluster {
+        self.service.cluster()
+    }
+
+    /// Returns the path of the unix domain socket the server listens on, if unix_socket_dir is set.
+    #[cfg(unix)]
+    pub fn unix_socket_path(&self) -> Option<&Path> {
+        self.service.unix_socket_path()
+    }
+
+    /// Returns the client connections accepted by the server and not yet closed.
+    pub fn connections(&self) -> &'static Connections<ClientConn> {
+        self.service.connections()
+    }
+
+    /// Stop accepting connections, close all client connections, and wait for the service to stop.
+    pub async fn shutdown(mut self) {
+        self.task.abort();
+        self.connections().for_each(|client| {
+            client.close();
+            false
+        });
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
 pub fn psql(connection_str: &str, mut password: &str) -> Child {
     let s = if connection_str.contains("user") {
         connection_str.to_string()
@@ -0,0 +1,52 @@
+use crate::riverdb::config::Jitter;
+use crate::tests::common::load;
+
+#[test]
+fn test_connect_retry() {
+    let settings = load(r#"
+postgres:
+  connect_retry:
+    retries: 3
+    base_delay_ms: 50
+    jitter: full
+  servers:
+    - {database: app, host: 127.0.0.1, can_query: true, replicas: []}
+plugins: []
+"#).expect("valid settings");
+
+    let connect_retry = &settings.postgres.connect_retry;
+    assert_eq!(connect_retry.retries, 3);
+    assert_eq!(connect_retry.base_delay_ms, 50);
+    assert_eq!(connect_retry.max_delay_ms, 5000);
+    assert_eq!(connect_retry.jitter, Jitter::Full);
+}
+
+#[test]
+fn test_connect_retry_defaults() {
+    let settings = load(r#"
+postgres:
+  servers:
+    - {database: app, host: 127.0.0.1, can_query: true, replicas: []}
+plugins: []
+"#).expect("valid settings");
+
+    let connect_retry = &settings.postgres.connect_retry;
+    assert_eq!(connect_retry.retries, 0);
+    assert_eq!(connect_retry.base_delay_ms, 100);
+    assert_eq!(connect_retry.jitter, Jitter::Equal);
+}
+
+#[test]
+fn test_connect_retry_invalid() {
+    let err = load(r#"
+postgres:
+  connect_retry:
+    retries: 3
+    base_delay_ms: 1000
+    max_delay_ms: 500
+  servers:
+    - {database: app, host: 127.0.0.1, can_query: true, replicas: []}
+plugins: []
+"#).err().expect("invalid settings");
+    assert!(err.contains("max_delay_ms"));
+}
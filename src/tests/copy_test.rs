@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use crate::tests::common::{self, TestServer};
+use crate::tests::harness::{TestClient, start_mock_server};
+use crate::riverdb::pg::extended_query;
+use crate::riverdb::pg::protocol::{MessageBuilder, Messages, Tag};
+
+
+/// Returns two CopyData messages followed by CopyDone.
+fn copy_rows() -> Messages {
+    let mut mb = MessageBuilder::new(Tag::COPY_DATA);
+    mb.write_bytes(b"1\n");
+    mb.add_new(Tag::COPY_DATA);
+    mb.write_bytes(b"2\n");
+    mb.add_new(Tag::COPY_DONE);
+    mb.finish()
+}
+
+/// Read messages from client until one with tag.
+async fn read_until(client: &mut TestClient, tag: Tag) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    loop {
+        let msgs = client.read_message().await?;
+        match msgs.first().unwrap().tag() {
+            t if t == tag => return Ok(()),
+            Tag::ERROR_RESPONSE => return Err(format!("unexpected error waiting for {}", tag).into()),
+            _ => (),
+        }
+    }
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_copy_from_stdin_extended() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(common::mock_settings(backend.port(), "", "")?)?;
+    let pool = server.cluster().nodes[0].master().expect("master");
+
+    // The Sync after the Execute is sent before the COPY starts, the database ignores it
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    client.send(extended_query("COPY t FROM STDIN", &[])?).await?;
+    read_until(&mut client, Tag::COPY_IN_RESPONSE).await?;
+    client.send(copy_rows()).await?;
+    read_until(&mut client, Tag::COMMAND_COMPLETE).await?;
+
+    // The Sync after CopyDone is answered with the ReadyForQuery for the COPY, it's sent after the COPY completed
+    client.send(MessageBuilder::new(Tag::SYNC).finish()).await?;
+    client.read_until_ready().await?;
+    assert_eq!(client.tx_status, b'I');
+
+    // With no requests left pending, the connection is returned to the pool
+    for _ in 0..100 {
+        if pool.pooled() != 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(pool.pooled(), 1);
+    let result = client.simple_query("SELECT 1").await?;
+    assert_eq!(result.rows, vec![vec![Some("1".to_string())]]);
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_copy_from_stdin_simple() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(common::mock_settings(backend.port(), "", "")?)?;
+    let pool = server.cluster().nodes[0].master().expect("master");
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    let mut mb = MessageBuilder::new(Tag::QUERY);
+    mb.write_str("COPY t FROM STDIN");
+    client.send(mb.finish()).await?;
+    read_until(&mut client, Tag::COPY_IN_RESPONSE).await?;
+    client.send(copy_rows()).await?;
+    let result = client.read_until_ready().await?;
+    assert_eq!(result.tags, vec!["COPY 2".to_string()]);
+
+    for _ in 0..100 {
+        if pool.pooled() != 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(pool.pooled(), 1);
+    let result = client.simple_query("SELECT 1").await?;
+    assert_eq!(result.rows, vec![vec![Some("1".to_string())]]);
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
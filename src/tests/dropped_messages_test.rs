@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+use crate::tests::common::{self, TestServer};
+use crate::tests::harness::{TestClient, start_mock_server};
+use crate::riverdb::pg::protocol::{MessageBuilder, Tag};
+
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_dropped_messages() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(common::mock_settings(backend.port(), "", "")?)?;
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    client.simple_query("BEGIN").await?;
+    let pool = server.cluster().nodes[0].master().expect("master");
+    assert_eq!(pool.dropped_messages(), 0);
+
+    // The client leaves before the result arrives, so there's no client to forward it to.
+    // The connection isn't pooled until its transaction is rolled back, after the result.
+    let mut mb = MessageBuilder::new(Tag::QUERY);
+    mb.write_str("SELECT pg_sleep(0.2)");
+    client.send(mb.finish()).await?;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    client.terminate().await?;
+
+    for _ in 0..100 {
+        if pool.dropped_messages() != 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    // DataRow, CommandComplete, and ReadyForQuery
+    assert_eq!(pool.dropped_messages(), 3);
+    assert!(pool.dropped_bytes() > 0);
+    assert_eq!(pool.late_messages(), 0);
+    server.shutdown().await;
+    Ok(())
+}
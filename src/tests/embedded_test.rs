@@ -0,0 +1,65 @@
+use crate::tests::common;
+use crate::tests::harness::start_mock_server;
+use crate::riverdb::config;
+use crate::riverdb::pg::{Pool, TransactionType};
+use crate::riverdb::worker::init_workers;
+
+
+fn pool_config(port: u16) -> config::PostgresCluster {
+    config::PostgresCluster{
+        servers: vec![
+            config::Postgres{
+                database: common::TEST_DATABASE.to_string(),
+                host: "127.0.0.1".to_string(),
+                port,
+                user: common::TEST_USER.to_string(),
+                password: common::TEST_PASSWORD.to_string(),
+                max_connections: 16,
+                can_query: true,
+                ..Default::default()
+            }
+        ],
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_pool() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    unsafe { init_workers(1); }
+    let addr = start_mock_server();
+    let pool = Pool::with_config(pool_config(addr.port()))?;
+    pool.start().await?;
+    assert_eq!(pool.cluster().get_startup_params().get("server_version"), Some("14.0"));
+
+    assert_eq!(pool.execute("SELECT 1").await?, 1);
+    assert!(pool.execute("SELEC 1").await.unwrap_err().to_string().contains("syntax error"));
+
+    let conn = pool.get().await?;
+    let mut rows = conn.query("SELECT $1", &[Some("hello")]).await?;
+    assert!(rows.next().await?);
+    assert_eq!(rows.get_str(0)?, "hello");
+    assert!(!rows.next().await?);
+    drop(rows);
+    conn.release().await;
+
+    let conn = pool.acquire(0, true, TransactionType::None).await?;
+    assert_eq!(conn.execute("SELECT 1").await?, 1);
+    conn.release().await;
+
+    assert!(pool.acquire(1, false, TransactionType::None).await.is_err());
+    Ok(())
+}
+
+#[test]
+fn test_pool_invalid_config() {
+    let mut config = pool_config(5432);
+    config.servers[0].max_connections = 0;
+    assert!(Pool::with_config(config).is_err());
+}
+
+#[test]
+fn test_pool_new_once() {
+    let _ = Pool::new(config::Settings::default());
+    let err = Pool::new(config::Settings::default()).err().expect("second Pool::new should fail");
+    assert!(err.to_string().contains("can only be called once"));
+}
@@ -0,0 +1,56 @@
+use crate::riverdb::pg::protocol::gssapi::Gss;
+use crate::tests::common::load;
+
+#[test]
+fn test_gss_service_name() {
+    let settings = load(r#"
+postgres:
+  default: {database: app, can_query: true, replicas: []}
+  servers:
+    - database: app
+      host: 127.0.0.1
+      can_query: true
+      user_pools:
+        - {user: reporting, max_connections: 16}
+      replicas:
+        - {database: app, host: 127.0.0.2, can_query: true, replicas: []}
+    - {database: app, host: 127.0.0.3, can_query: true, gss_service_name: pg, replicas: [{database: app, host: 127.0.0.4, can_query: true, replicas: []}]}
+plugins: []
+"#).expect("valid settings");
+    let servers = &settings.postgres.servers;
+    assert_eq!(servers[0].gss_service_name, "postgres");
+    assert_eq!(servers[0].gss_keytab, "");
+    assert_eq!(servers[0].user_pool_configs[0].gss_service_name, "postgres");
+    assert_eq!(servers[1].gss_service_name, "pg");
+    // Replicas inherit it from the master
+    assert_eq!(servers[1].replicas[0].gss_service_name, "pg");
+}
+
+#[cfg(not(feature = "gssapi"))]
+#[test]
+fn test_gssapi_feature_disabled() {
+    let err = load(r#"
+postgres:
+  servers:
+    - {database: app, host: 127.0.0.1, can_query: true, gss_keytab: riverdb.keytab, replicas: []}
+plugins: []
+"#).err().unwrap();
+    assert_eq!(err, "gss_keytab requires riverdb to be built with the gssapi feature");
+
+    let err = Gss::new("postgres", "localhost").err().unwrap();
+    assert_eq!(err.to_string(), "GSSAPI authentication requires riverdb to be built with the gssapi feature");
+}
+
+#[cfg(feature = "gssapi")]
+#[test]
+fn test_gss_keytab_must_match() {
+    // The keytab is set for the whole process, so the servers can't use different ones
+    let err = load(r#"
+postgres:
+  servers:
+    - {database: app, host: 127.0.0.1, can_query: true, gss_keytab: Cargo.toml, replicas: []}
+    - {database: app, host: 127.0.0.2, can_query: true, gss_keytab: Cargo.lock, replicas: []}
+plugins: []
+"#).err().unwrap();
+    assert_eq!(err, "all servers must use the same gss_keytab");
+}
@@ -0,0 +1,715 @@
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Interest};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+use crate::riverdb::{Error, Result};
+use crate::riverdb::common::ErrorKind;
+use crate::riverdb::config::TlsMode;
+use crate::riverdb::server::{DangerousCertificateNonverifier, Transport};
+use crate::riverdb::pg::extended_query;
+use crate::riverdb::pg::protocol::{
+    Tag, Messages, MessageBuilder, MessageParser, ServerParams, AuthType, PostgresError, PROTOCOL_VERSION,
+    hash_md5_password, sasl, error_codes, CANCEL_REQUEST,
+};
+use crate::tests::common;
+
+
+const SSL_REQUEST: &[u8] = &[0, 0, 0, 8, 4, 210, 22, 47];
+
+/// The number of SELECT served FROM ... queries answered by the mock servers, see mock_server.
+pub static SERVED: AtomicU32 = AtomicU32::new(0);
+
+/// The number of CancelRequests received by the mock servers, see mock_server.
+pub static CANCELS: AtomicU32 = AtomicU32::new(0);
+
+/// The result of a query run by TestClient.
+#[derive(Default, Debug)]
+pub struct QueryResult {
+    /// the rows returned by the query (or the statements of a multi-statement query), in text format
+    pub rows: Vec<Vec<Option<String>>>,
+    /// the command tag of each completed statement, e.g. "SELECT 1" or "INSERT 0 1"
+    pub tags: Vec<String>,
+}
+
+/// TestClient is a programmatic Postgres client for driving riverdb (or Postgres) in integration
+/// tests and benchmarks without spawning psql. It supports trust, clear text, MD5, and SCRAM-SHA-256
+/// authentication, the simple query protocol, and the extended query protocol with text parameters,
+/// optionally over TLS (without verifying the server certificate.)
+pub struct TestClient {
+    stream: Transport,
+    parser: MessageParser,
+    /// the ParameterStatus values reported by the server
+    pub params: ServerParams,
+    /// the process id and secret key from BackendKeyData, for CancelRequest
+    pub backend_key: Option<(i32, i32)>,
+    /// the transaction status from the last ReadyForQuery: b'I' idle, b'T' in a transaction, or b'E' failed transaction
+    pub tx_status: u8,
+}
+
+impl TestClient {
+    /// Connect to addr as user to database and authenticate with password.
+    pub async fn connect(addr: SocketAddr, user: &str, database: &str, password: &str) -> Result<Self> {
+        Self::connect_with_params(addr, &[("user", user), ("database", database)], password).await
+    }
+
+    /// Connect to addr with the startup params (which must include user) and authenticate with password.
+    pub async fn connect_with_params(addr: SocketAddr, params: &[(&str, &str)], password: &str) -> Result<Self> {
+        Self::new(addr).await?.startup(params, password).await
+    }
+
+    /// Like connect, but over the unix domain socket at path.
+    #[cfg(unix)]
+    pub async fn connect_unix(path: &Path, user: &str, database: &str, password: &str) -> Result<Self> {
+        let stream = UnixStream::connect(path).await?;
+        let client = Self{
+            stream: Transport::new_unix(stream),
+            parser: MessageParser::new(),
+            params: ServerParams::new(),
+            backend_key: None,
+            tx_status: 0,
+        };
+        client.startup(&[("user", user), ("database", database)], password).await
+    }
+
+    /// Like connect_with_params, but first upgrades the connection to TLS. The server certificate isn't verified.
+    pub async fn connect_tls_with_params(addr: SocketAddr, params: &[(&str, &str)], password: &str) -> Result<Self> {
+        let mut client = Self::new(addr).await?;
+        client.write_all(SSL_REQUEST).await?;
+        let mut buf = [0u8; 1];
+        while client.stream.try_read(&mut buf)? == 0 {
+            client.stream.ready(Interest::READABLE).await?;
+        }
+        if buf[0] != b'S' {
+            return Err(Error::new("server declined TLS"));
+        }
+        let config = rustls::client_config_builder_with_safe_defaults()
+            .with_custom_certificate_verifier(DangerousCertificateNonverifier::new())
+            .with_no_client_auth();
+        client.stream.upgrade_client(Arc::new(config), TlsMode::DangerouslyUnverifiedCertificates, "localhost").await?;
+        client.startup(params, password).await
+    }
+
+    async fn new(addr: SocketAddr) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self{
+            stream: Transport::new(stream),
+            parser: MessageParser::new(),
+            params: ServerParams::new(),
+            backend_key: None,
+            tx_status: 0,
+        })
+    }
+
+    /// Send the startup message with params and authenticate with password.
+    async fn startup(mut self, params: &[(&str, &str)], password: &str) -> Result<Self> {
+        let mut startup = ServerParams::new();
+        for &(k, v) in params {
+            startup.add(k.to_string(), v.to_string());
+        }
+        let user = startup.get("user").ok_or_else(|| Error::new("user is a required parameter"))?.to_string();
+        let mut mb = MessageBuilder::new(Tag::UNTAGGED);
+        mb.write_i32(PROTOCOL_VERSION);
+        mb.write_params(&startup);
+        mb.write_byte(0); // null-terminator at end of startup packet
+        self.send(mb.finish()).await?;
+
+        self.authenticate(&user, password).await?;
+        self.read_until_ready().await?;
+        Ok(self)
+    }
+
+    /// Respond to the authentication requests of the server until AuthenticationOk.
+    async fn authenticate(&mut self, user: &str, password: &str) -> Result<()> {
+        let mut scram: Option<sasl::ScramSha256> = None;
+        loop {
+            let msgs = self.read_message().await?;
+            let msg = msgs.first().unwrap();
+            match msg.tag() {
+                Tag::AUTHENTICATION_OK => (),
+                Tag::ERROR_RESPONSE => return Err(Error::from(PostgresError::new(msgs)?)),
+                Tag::NEGOTIATE_PROTOCOL_VERSION => continue,
+                tag => return Err(Error::new(format!("unexpected message {} during authentication", tag))),
+            }
+            let mut r = msg.reader();
+            let auth_type = AuthType::try_from(r.read_i32())?;
+            let mut mb = MessageBuilder::new(Tag::PASSWORD_MESSAGE);
+            match auth_type {
+                AuthType::Ok => return Ok(()),
+                AuthType::ClearText => mb.write_str(password),
+                AuthType::MD5 => mb.write_str(&hash_md5_password(user, password, r.read_i32())),
+                AuthType::SASL => {
+                    let s = sasl::ScramSha256::new(password.as_bytes(), sasl::ChannelBinding::unrequested());
+                    mb.write_str(sasl::SCRAM_SHA_256);
+                    mb.write_i32(s.message().len() as i32);
+                    mb.write_bytes(s.message());
+                    scram = Some(s);
+                },
+                AuthType::SASLContinue | AuthType::SASLFinal => {
+                    let s = scram.as_mut().ok_or_else(|| Error::new("unexpected SASL message"))?;
+                    s.update_from_message(msgs.clone())?;
+                    if auth_type == AuthType::SASLFinal {
+                        continue;
+                    }
+                    mb.write_bytes(s.message());
+                },
+                _ => return Err(Error::new(format!("unsupported authentication type {}", auth_type))),
+            }
+            self.send(mb.finish()).await?;
+        }
+    }
+
+    /// Run sql with the simple query protocol. It may contain multiple statements.
+    /// Returns the first error, if any, after reading the whole result.
+    pub async fn simple_query(&mut self, sql: &str) -> Result<QueryResult> {
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str(sql);
+        self.send(mb.finish()).await?;
+        self.read_until_ready().await
+    }
+
+    /// Run sql with the extended query protocol (Parse, Bind, Describe, Execute, Sync) and the
+    /// text format params, where None is NULL.
+    pub async fn query(&mut self, sql: &str, params: &[Option<&str>]) -> Result<QueryResult> {
+        self.send(extended_query(sql, params)?).await?;
+        self.read_until_ready().await
+    }
+
+    /// Send the Terminate message and close the connection.
+    pub async fn terminate(mut self) -> Result<()> {
+        self.send(MessageBuilder::new(Tag::TERMINATE).finish()).await?;
+        self.stream.close();
+        Ok(())
+    }
+
+    /// Send msgs to the server as-is.
+    pub async fn send(&mut self, msgs: Messages) -> Result<()> {
+        self.write_all(msgs.as_slice()).await
+    }
+
+    async fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            let n = self.stream.try_write(buf)?;
+            if n == 0 {
+                self.stream.ready(Interest::WRITABLE).await?;
+            }
+            buf = &buf[n..];
+        }
+        // Write the ciphertext buffered by the TLS session, if any
+        while !self.stream.try_flush()? {
+            self.stream.ready(Interest::WRITABLE).await?;
+        }
+        Ok(())
+    }
+
+    /// Read the next message from the server.
+    pub async fn read_message(&mut self) -> Result<Messages> {
+        let mut buf = [0u8; 8192];
+        loop {
+            if let Some(result) = self.parser.next(true) {
+                return result;
+            }
+            // Returns Error::closed() at EOF
+            match self.stream.try_read(&mut buf)? {
+                0 => { self.stream.ready(Interest::READABLE).await?; },
+                n => self.parser.bytes_mut().extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    /// Read messages up to and including ReadyForQuery, collecting the result.
+    /// Returns the first ErrorResponse as an error.
+    pub async fn read_until_ready(&mut self) -> Result<QueryResult> {
+        let mut result = QueryResult::default();
+        let mut error = None;
+        loop {
+            let msgs = self.read_message().await?;
+            let msg = msgs.first().unwrap();
+            let mut r = msg.reader();
+            match msg.tag() {
+                Tag::DATA_ROW => {
+                    let num_fields = r.read_i16().max(0) as usize;
+                    let mut row = Vec::with_capacity(num_fields);
+                    for _ in 0..num_fields {
+                        let len = r.read_i32();
+                        if len < 0 {
+                            row.push(None);
+                        } else {
+                            row.push(Some(std::str::from_utf8(r.read_bytes(len as u32)?)?.to_string()));
+                        }
+                    }
+                    r.error()?;
+                    result.rows.push(row);
+                },
+                Tag::COMMAND_COMPLETE => result.tags.push(r.read_str()?.to_string()),
+                Tag::ERROR_RESPONSE => {
+                    if error.is_none() {
+                        error = Some(PostgresError::new(msgs.clone())?);
+                    }
+                },
+                Tag::PARAMETER_STATUS => {
+                    let name = r.read_str()?.to_string();
+                    let value = r.read_str()?.to_string();
+                    self.params.set(name, value);
+                },
+                Tag::BACKEND_KEY_DATA => {
+                    let pid = r.read_i32();
+                    let secret = r.read_i32();
+                    r.error()?;
+                    self.backend_key = Some((pid, secret));
+                },
+                Tag::READY_FOR_QUERY => {
+                    self.tx_status = r.read_byte();
+                    return match error {
+                        Some(e) => Err(Error::from(e)),
+                        None => Ok(result),
+                    };
+                },
+                // RowDescription, ParseComplete, BindComplete, NoData, EmptyQueryResponse, notices, and notifications
+                _ => (),
+            }
+        }
+    }
+}
+
+/// The results of run_load.
+#[derive(Default, Debug)]
+pub struct LoadStats {
+    /// the number of queries that completed successfully
+    pub queries: u64,
+    /// the number of queries that returned an error
+    pub errors: u64,
+    /// the slowest query
+    pub max_latency: Duration,
+    /// the total time taken
+    pub elapsed: Duration,
+}
+
+impl LoadStats {
+    /// Returns the successful queries per second.
+    pub fn queries_per_second(&self) -> f64 {
+        self.queries as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Runs a pgbench-style load: connects clients concurrently to addr, each running sql queries_per_client
+/// times with the extended query protocol (if extended) or the simple query protocol. Returns an error if
+/// a client can't connect or its connection fails, errors returned by queries are only counted.
+pub async fn run_load(addr: SocketAddr, user: &str, database: &str, password: &str, sql: &str, clients: usize, queries_per_client: usize, extended: bool) -> Result<LoadStats> {
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..clients).map(|_| {
+        let (user, database, password, sql) = (user.to_string(), database.to_string(), password.to_string(), sql.to_string());
+        tokio::spawn(async move {
+            let mut stats = LoadStats::default();
+            let mut client = TestClient::connect(addr, &user, &database, &password).await?;
+            for _ in 0..queries_per_client {
+                let query_start = Instant::now();
+                let result = if extended {
+                    client.query(&sql, &[]).await
+                } else {
+                    client.simple_query(&sql).await
+                };
+                match result {
+                    Ok(_) => stats.queries += 1,
+                    Err(e) if matches!(e.kind(), ErrorKind::PostgresError{..}) => stats.errors += 1,
+                    Err(e) => return Err(e),
+                }
+                stats.max_latency = stats.max_latency.max(query_start.elapsed());
+            }
+            client.terminate().await?;
+            Ok::<_, Error>(stats)
+        })
+    }).collect();
+
+    let mut total = LoadStats::default();
+    for task in tasks {
+        let stats = task.await.map_err(|e| Error::new(format!("load client panicked: {}", e)))??;
+        total.queries += stats.queries;
+        total.errors += stats.errors;
+        total.max_latency = total.max_latency.max(stats.max_latency);
+    }
+    total.elapsed = start.elapsed();
+    Ok(total)
+}
+
+const SALT: i32 = 0x5a17;
+
+/// A minimal Postgres server that authenticates with MD5, answers SELECT 1, SELECT inet_server_port(), SET, RESET,
+/// BEGIN, COMMIT, ROLLBACK (optionally followed by RESET statements),
+/// SAVEPOINT, SHOW (of a setting it received a SET for and no RESET since, role, or transaction_isolation), UPDATE, and SELECT served FROM
+/// (with the count in SERVED), echoes the first parameter of extended queries (failing those that Parse FAIL), and fails any other query.
+/// COPY ... FROM STDIN, with either protocol, counts the CopyData messages up to CopyDone as the rows copied, ignoring Syncs until then.
+/// It answers the Sync after CopyDone of an extended query COPY with just the ReadyForQuery.
+/// The BEGIN and SET statements held by riverdb are answered with a CommandComplete each, either at the start
+/// of the next Query (separated by newline and ;) or executed before the next extended query.
+/// SELECT pg_sleep(seconds) fails with query_canceled (57014) if a CancelRequest arrives while it sleeps (counted in CANCELS.)
+/// The first shutdowns SELECT queries it receives are answered with an admin_shutdown (57P01) error,
+/// after which it closes the connection, as Postgres does when it's shut down.
+pub async fn mock_server(listener: TcpListener, shutdowns: Arc<AtomicU32>) {
+    loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        tokio::spawn(serve(stream, shutdowns.clone()));
+    }
+}
+
+async fn serve(mut stream: TcpStream, shutdowns: Arc<AtomicU32>) -> Result<()> {
+    let len = stream.read_i32().await?;
+    let mut startup = vec![0; len as usize - 4];
+    stream.read_exact(&mut startup).await?;
+    if startup.len() == 12 && startup[..4] == CANCEL_REQUEST.to_be_bytes() {
+        CANCELS.fetch_add(1, Relaxed);
+        return Ok(());
+    }
+
+    let mut mb = MessageBuilder::new(Tag::AUTHENTICATION_OK);
+    mb.write_i32(AuthType::MD5.as_i32());
+    mb.write_i32(SALT);
+    stream.write_all(mb.finish().as_slice()).await?;
+
+    let mut parser = MessageParser::new();
+    let password = mock_read_message(&mut stream, &mut parser).await?;
+    let expected = hash_md5_password(common::TEST_USER, common::TEST_PASSWORD, SALT);
+    if password.first().unwrap().reader().read_str()? != expected {
+        stream.write_all(Messages::new_error(error_codes::INVALID_PASSWORD, "password authentication failed").as_slice()).await?;
+        return Ok(());
+    }
+    let mut mb = MessageBuilder::new(Tag::AUTHENTICATION_OK);
+    mb.write_i32(AuthType::Ok.as_i32());
+    mb.add_new(Tag::PARAMETER_STATUS);
+    mb.write_str("server_version");
+    mb.write_str("14.0");
+    mb.add_new(Tag::BACKEND_KEY_DATA);
+    mb.write_i32(42);
+    mb.write_i32(1234);
+    mb.add_new(Tag::READY_FOR_QUERY);
+    mb.write_byte(b'I');
+    stream.write_all(mb.finish().as_slice()).await?;
+
+    let port = stream.local_addr()?.port();
+    let mut param = None;
+    let mut parse_failed = false;
+    let mut copy = false;
+    let mut copied = false;
+    let mut parsed = String::new();
+    let mut tx_status = b'I';
+    let mut isolation = String::new();
+    let mut settings = HashMap::new();
+    loop {
+        let mut msgs = mock_read_message(&mut stream, &mut parser).await?;
+        if msgs.first().unwrap().tag() == Tag::QUERY {
+            let sql = msgs.first().unwrap().reader().read_str()?.to_string();
+            if tx_status == b'I' && sql.starts_with("BEGIN") {
+                isolation.clear();
+            }
+            if let Some(i) = sql.rfind("ISOLATION LEVEL ") {
+                let level = sql[i + "ISOLATION LEVEL ".len()..].split(|c| c == ',' || c == ';').next().unwrap();
+                isolation = level.trim().to_lowercase();
+            }
+            for statement in sql.split("; ").map(|statement| strip_tags(statement.trim())) {
+                let set = statement.strip_prefix("SET LOCAL ").or_else(|| statement.strip_prefix("SET "));
+                if let Some(role) = set.and_then(|set| set.strip_prefix("ROLE ")) {
+                    settings.insert("role".to_string(), unquote(role));
+                } else if let Some((name, value)) = set.and_then(|set| set.split_once(" TO ")) {
+                    settings.insert(name.to_string(), unquote(value));
+                } else if statement == "RESET ALL" {
+                    settings.clear();
+                } else if let Some(name) = statement.strip_prefix("RESET ") {
+                    settings.remove(name);
+                }
+            }
+            // The statements held by riverdb are sent at the start of the next query (see unbuffered_begin)
+            if let Some((held, query)) = sql.rsplit_once("\n; ") {
+                tx_status = b'T';
+                let mut commands = held.split("\n; ").map(|statement| if statement.starts_with("SET ") { "SET" } else { "BEGIN" });
+                let mut mb = MessageBuilder::new(Tag::COMMAND_COMPLETE);
+                mb.write_str(commands.next().unwrap());
+                for command in commands {
+                    mb.add_new(Tag::COMMAND_COMPLETE);
+                    mb.write_str(command);
+                }
+                stream.write_all(mb.finish().as_slice()).await?;
+                let mut mb = MessageBuilder::new(Tag::QUERY);
+                mb.write_str(query);
+                msgs = mb.finish();
+            }
+        }
+        let msg = msgs.first().unwrap();
+        let mut r = msg.reader();
+        let mut mb = MessageBuilder::new(Tag::READY_FOR_QUERY);
+        match msg.tag() {
+            Tag::QUERY if msg.reader().read_str()?.starts_with("SELECT") && shutdowns.fetch_update(Relaxed, Relaxed, |n| n.checked_sub(1)).is_ok() => {
+                stream.write_all(Messages::new_error(error_codes::ADMIN_SHUTDOWN, "terminating connection due to administrator command").as_slice()).await?;
+                return Ok(());
+            },
+            Tag::QUERY if r.read_str()? == "SELECT 1" => {
+                mb = MessageBuilder::new(Tag::DATA_ROW);
+                mb.write_i16(1);
+                mb.write_i32(1);
+                mb.write_bytes(b"1");
+                mb.add_new(Tag::COMMAND_COMPLETE);
+                mb.write_str("SELECT 1");
+                mb.add_new(Tag::READY_FOR_QUERY);
+            },
+            Tag::QUERY if msg.reader().read_str()?.starts_with("SELECT repeat('x', ") => {
+                // A single row with a value of the requested length, for testing large results
+                let sql = msg.reader().read_str()?;
+                let len: usize = sql["SELECT repeat('x', ".len()..].trim_end_matches(')').parse().unwrap_or(0);
+                mb = MessageBuilder::new(Tag::DATA_ROW);
+                mb.write_i16(1);
+                mb.write_i32(len as i32);
+                mb.write_bytes(&vec![b'x'; len]);
+                mb.add_new(Tag::COMMAND_COMPLETE);
+                mb.write_str("SELECT 1");
+                mb.add_new(Tag::READY_FOR_QUERY);
+            },
+            Tag::QUERY if msg.reader().read_str()?.starts_with("SELECT pg_sleep(") => {
+                let sql = msg.reader().read_str()?;
+                let seconds: f64 = sql["SELECT pg_sleep(".len()..].trim_end_matches(')').parse().unwrap_or(0.0);
+                let cancels = CANCELS.load(Relaxed);
+                let deadline = Instant::now() + Duration::from_secs_f64(seconds);
+                while Instant::now() < deadline && CANCELS.load(Relaxed) == cancels {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                if CANCELS.load(Relaxed) != cancels {
+                    stream.write_all(Messages::new_error(error_codes::QUERY_CANCELED, "canceling statement due to user request").as_slice()).await?;
+                    if tx_status != b'I' {
+                        tx_status = b'E';
+                    }
+                } else {
+                    mb = MessageBuilder::new(Tag::DATA_ROW);
+                    mb.write_i16(1);
+                    mb.write_i32(0);
+                    mb.add_new(Tag::COMMAND_COMPLETE);
+                    mb.write_str("SELECT 1");
+                    mb.add_new(Tag::READY_FOR_QUERY);
+                }
+            },
+            Tag::QUERY if msg.reader().read_str()?.starts_with("SELECT inet_server_port()") => {
+                let port = port.to_string();
+                mb = MessageBuilder::new(Tag::DATA_ROW);
+                mb.write_i16(1);
+                mb.write_i32(port.len() as i32);
+                mb.write_bytes(port.as_bytes());
+                mb.add_new(Tag::COMMAND_COMPLETE);
+                mb.write_str("SELECT 1");
+                mb.add_new(Tag::READY_FOR_QUERY);
+            },
+            Tag::QUERY if strip_tags(msg.reader().read_str()?).starts_with("SELECT served FROM ") => {
+                // The number of times the mock servers answered this query, to tell if a result came from riverdb's cache
+                let served = (SERVED.fetch_add(1, Relaxed) + 1).to_string();
+                mb = MessageBuilder::new(Tag::DATA_ROW);
+                mb.write_i16(1);
+                mb.write_i32(served.len() as i32);
+                mb.write_bytes(served.as_bytes());
+                mb.add_new(Tag::COMMAND_COMPLETE);
+                mb.write_str("SELECT 1");
+                mb.add_new(Tag::READY_FOR_QUERY);
+            },
+            Tag::QUERY if strip_tags(msg.reader().read_str()?).starts_with("UPDATE ") => {
+                mb = MessageBuilder::new(Tag::COMMAND_COMPLETE);
+                mb.write_str("UPDATE 1");
+                mb.add_new(Tag::READY_FOR_QUERY);
+            },
+            Tag::QUERY if strip_tags(msg.reader().read_str()?).starts_with("SHOW ") => {
+                let name = &strip_tags(msg.reader().read_str()?)["SHOW ".len()..];
+                let value = match name {
+                    "transaction_isolation" if tx_status == b'I' || isolation.is_empty() => "read committed",
+                    "transaction_isolation" => isolation.as_str(),
+                    _ => settings.get(name).map(String::as_str).unwrap_or(""),
+                };
+                mb = MessageBuilder::new(Tag::DATA_ROW);
+                mb.write_i16(1);
+                mb.write_i32(value.len() as i32);
+                mb.write_bytes(value.as_bytes());
+                mb.add_new(Tag::COMMAND_COMPLETE);
+                mb.write_str("SHOW");
+                mb.add_new(Tag::READY_FOR_QUERY);
+            },
+            Tag::QUERY if msg.reader().read_str()?.starts_with("SET ") || msg.reader().read_str()?.starts_with("RESET ") => {
+                mb = MessageBuilder::new(Tag::COMMAND_COMPLETE);
+                mb.write_str("SET");
+                mb.add_new(Tag::READY_FOR_QUERY);
+            },
+            Tag::QUERY if msg.reader().read_str()?.starts_with("BEGIN") => {
+                tx_status = b'T';
+                mb = MessageBuilder::new(Tag::COMMAND_COMPLETE);
+                mb.write_str("BEGIN");
+                mb.add_new(Tag::READY_FOR_QUERY);
+            },
+            Tag::QUERY if msg.reader().read_str()?.starts_with("ROLLBACK; RESET ") => {
+                // The server_reset_query after a client left a transaction open
+                tx_status = b'I';
+                mb = MessageBuilder::new(Tag::COMMAND_COMPLETE);
+                mb.write_str("ROLLBACK");
+                let statements = msg.reader().read_str()?.split("; ").count();
+                for _ in 1..statements {
+                    mb.add_new(Tag::COMMAND_COMPLETE);
+                    mb.write_str("RESET");
+                }
+                mb.add_new(Tag::READY_FOR_QUERY);
+            },
+            Tag::QUERY if msg.reader().read_str()?.starts_with("SAVEPOINT ") => {
+                mb = MessageBuilder::new(Tag::COMMAND_COMPLETE);
+                mb.write_str("SAVEPOINT");
+                mb.add_new(Tag::READY_FOR_QUERY);
+            },
+            Tag::QUERY if matches!(msg.reader().read_str()?, "COMMIT" | "ROLLBACK") => {
+                let command = msg.reader().read_str()?;
+                tx_status = b'I';
+                mb = MessageBuilder::new(Tag::COMMAND_COMPLETE);
+                mb.write_str(command);
+                mb.add_new(Tag::READY_FOR_QUERY);
+            },
+            Tag::QUERY if msg.reader().read_str()?.starts_with("COPY ") => {
+                let rows = mock_copy_in(&mut stream, &mut parser).await?;
+                mb = MessageBuilder::new(Tag::COMMAND_COMPLETE);
+                mb.write_str(&format!("COPY {}", rows));
+                mb.add_new(Tag::READY_FOR_QUERY);
+            },
+            Tag::QUERY => {
+                stream.write_all(Messages::new_error(error_codes::SYNTAX_ERROR, "syntax error").as_slice()).await?;
+                if tx_status != b'I' {
+                    tx_status = b'E';
+                }
+            },
+            Tag::BIND => {
+                r.read_str()?;
+                r.read_str()?;
+                r.read_i16();
+                r.read_i16();
+                let len = r.read_i32();
+                param = if len < 0 { None } else { Some(r.read_bytes(len as u32)?.to_vec()) };
+                continue;
+            },
+            Tag::PARSE => {
+                r.read_str()?;
+                let sql = r.read_str()?;
+                parse_failed = sql == "FAIL";
+                copy = sql.starts_with("COPY ");
+                parsed = sql.to_string();
+                continue;
+            },
+            Tag::EXECUTE if copy => {
+                copy = false;
+                let mut mb = MessageBuilder::new(Tag::PARSE_COMPLETE);
+                mb.add_new(Tag::BIND_COMPLETE);
+                stream.write_all(mb.finish().as_slice()).await?;
+                let rows = mock_copy_in(&mut stream, &mut parser).await?;
+                let mut mb = MessageBuilder::new(Tag::COMMAND_COMPLETE);
+                mb.write_str(&format!("COPY {}", rows));
+                stream.write_all(mb.finish().as_slice()).await?;
+                copied = true;
+                continue;
+            },
+            Tag::EXECUTE if parsed.starts_with("BEGIN") || parsed.starts_with("SET ") => {
+                // A statement held by riverdb, sent before the next extended query (see unbuffered_begin)
+                let mut mb = MessageBuilder::new(Tag::PARSE_COMPLETE);
+                mb.add_new(Tag::BIND_COMPLETE);
+                mb.add_new(Tag::COMMAND_COMPLETE);
+                if parsed.starts_with("BEGIN") {
+                    tx_status = b'T';
+                    mb.write_str("BEGIN");
+                } else {
+                    mb.write_str("SET");
+                }
+                stream.write_all(mb.finish().as_slice()).await?;
+                continue;
+            },
+            Tag::SYNC if copied => {
+                copied = false;
+            },
+            Tag::SYNC if parse_failed => {
+                parse_failed = false;
+                stream.write_all(Messages::new_error(error_codes::SYNTAX_ERROR, "syntax error").as_slice()).await?;
+            },
+            Tag::SYNC => {
+                mb = MessageBuilder::new(Tag::PARSE_COMPLETE);
+                mb.add_new(Tag::BIND_COMPLETE);
+                mb.add_new(Tag::DATA_ROW);
+                mb.write_i16(1);
+                match param.take() {
+                    Some(value) => {
+                        mb.write_i32(value.len() as i32);
+                        mb.write_bytes(&value);
+                    },
+                    None => mb.write_i32(-1),
+                }
+                mb.add_new(Tag::COMMAND_COMPLETE);
+                mb.write_str("SELECT 1");
+                mb.add_new(Tag::READY_FOR_QUERY);
+            },
+            Tag::TERMINATE => return Ok(()),
+            _ => continue,
+        }
+        mb.write_byte(tx_status);
+        stream.write_all(mb.finish().as_slice()).await?;
+    }
+}
+
+/// Returns the value of a SET statement without its quotes.
+fn unquote(value: &str) -> String {
+    value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')).map(|v| v.replace("''", "'")).unwrap_or_else(|| value.to_string())
+}
+
+/// Returns sql without a leading /* */ comment with query tags.
+fn strip_tags(sql: &str) -> &str {
+    match sql.strip_prefix("/*").and_then(|rest| rest.split_once("*/")) {
+        Some((_, rest)) => rest.trim_start(),
+        None => sql,
+    }
+}
+
+/// Start a COPY FROM STDIN, returning the number of CopyData messages received before CopyDone.
+async fn mock_copy_in(stream: &mut TcpStream, parser: &mut MessageParser) -> Result<u32> {
+    let mut mb = MessageBuilder::new(Tag::COPY_IN_RESPONSE);
+    mb.write_byte(0);
+    mb.write_i16(0);
+    stream.write_all(mb.finish().as_slice()).await?;
+    let mut rows = 0;
+    loop {
+        let msgs = mock_read_message(stream, parser).await?;
+        match msgs.first().unwrap().tag() {
+            Tag::COPY_DATA => rows += 1,
+            Tag::COPY_DONE => return Ok(rows),
+            // Postgres ignores these during COPY FROM STDIN
+            Tag::SYNC | Tag::FLUSH => (),
+            tag => return Err(Error::new(format!("unexpected message {} during COPY FROM STDIN", tag))),
+        }
+    }
+}
+
+async fn mock_read_message(stream: &mut TcpStream, parser: &mut MessageParser) -> Result<Messages> {
+    loop {
+        if let Some(result) = parser.next(true) {
+            return result;
+        }
+        if stream.read_buf(parser.bytes_mut()).await? == 0 {
+            return Err(Error::closed());
+        }
+    }
+}
+
+/// Start mock_server on an ephemeral port of localhost, returning its address.
+/// Must be called from within a tokio runtime.
+pub fn start_mock_server() -> SocketAddr {
+    start_mock_server_with_shutdowns(0)
+}
+
+/// Start mock_server on an ephemeral port of localhost, answering the first shutdowns SELECT queries
+/// with admin_shutdown, returning its address. Must be called from within a tokio runtime.
+pub fn start_mock_server_with_shutdowns(shutdowns: u32) -> SocketAddr {
+    let listener = common::listener();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(mock_server(listener, Arc::new(AtomicU32::new(shutdowns))));
+    addr
+}
@@ -0,0 +1,43 @@
+use crate::tests::common;
+use crate::tests::harness::{TestClient, run_load, start_mock_server};
+
+
+#[tokio::test]
+async fn test_client() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let addr = start_mock_server();
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    assert_eq!(client.params.get("server_version"), Some("14.0"));
+    assert_eq!(client.backend_key, Some((42, 1234)));
+    assert_eq!(client.tx_status, b'I');
+
+    let result = client.simple_query("SELECT 1").await?;
+    assert_eq!(result.rows, vec![vec![Some("1".to_string())]]);
+    assert_eq!(result.tags, vec!["SELECT 1".to_string()]);
+    assert!(client.simple_query("SELEC 1").await.unwrap_err().to_string().contains("syntax error"));
+
+    let result = client.query("SELECT $1", &[Some("hello")]).await?;
+    assert_eq!(result.rows, vec![vec![Some("hello".to_string())]]);
+    let result = client.query("SELECT $1", &[None]).await?;
+    assert_eq!(result.rows, vec![vec![None]]);
+    client.terminate().await?;
+
+    assert!(TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, "wrong").await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_run_load() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let addr = start_mock_server();
+    let stats = run_load(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD, "SELECT 1", 8, 25, false).await?;
+    assert_eq!(stats.queries, 200);
+    assert_eq!(stats.errors, 0);
+    assert!(stats.queries_per_second() > 0.0);
+
+    let stats = run_load(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD, "SELECT $1", 4, 10, true).await?;
+    assert_eq!(stats.queries, 40);
+
+    let stats = run_load(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD, "SELEC 1", 2, 5, false).await?;
+    assert_eq!(stats.queries, 0);
+    assert_eq!(stats.errors, 10);
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use crate::tests::common::{cluster, load};
+
+#[test]
+fn test_health_check() {
+    let settings = load(r#"
+postgres:
+  health_check:
+    enabled: true
+    check_ms: 500
+    promote_replica: true
+  servers:
+    - database: app
+      host: 127.0.0.1
+      can_query: true
+      replicas:
+        - {database: app, host: 127.0.0.2, can_query: true, failover_priority: 1, replicas: []}
+        - {database: app, host: 127.0.0.3, can_query: true, replicas: []}
+plugins: []
+"#).expect("valid settings");
+
+    let health_check = &settings.postgres.health_check;
+    assert!(health_check.enabled);
+    assert!(health_check.promote_replica);
+    assert_eq!(health_check.check_ms, 500);
+    assert_eq!(health_check.timeout_ms, 2000);
+    assert_eq!(health_check.unhealthy_threshold, 3);
+
+    let replicas = &settings.postgres.servers[0].replicas;
+    assert_eq!(replicas[0].failover_priority, 1);
+    assert_eq!(replicas[1].failover_priority, 0);
+}
+
+#[test]
+fn test_health_check_defaults() {
+    let settings = load(r#"
+postgres:
+  servers:
+    - {database: app, host: 127.0.0.1, can_query: true, replicas: []}
+plugins: []
+"#).expect("valid settings");
+
+    let health_check = &settings.postgres.health_check;
+    assert!(!health_check.enabled);
+    assert!(!health_check.promote_replica);
+    assert_eq!(health_check.check_ms, 1000);
+}
+
+#[test]
+fn test_health_check_reachable() {
+    let pool = cluster().nodes[0].master().expect("master");
+    // A server is healthy until the health checks fail, but not reachable until it was connected to
+    assert!(pool.is_healthy());
+    assert!(!pool.is_reachable());
+
+    assert_eq!(pool.record_health_check(false, 1), Some(false));
+    assert!(!pool.is_reachable());
+    assert_eq!(pool.record_health_check(true, 1), Some(true));
+    assert!(pool.is_healthy());
+    assert!(pool.is_reachable());
+}
@@ -0,0 +1,58 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::riverdb::config::test_config_mut;
+use crate::riverdb::http::HttpService;
+use crate::riverdb::server::ListenerOptions;
+
+
+async fn get(port: u16, request: &str) -> std::io::Result<String> {
+    let mut client = TcpStream::connect(("127.0.0.1", port)).await?;
+    client.write_all(request.as_bytes()).await?;
+    let mut response = String::new();
+    client.read_to_string(&mut response).await?;
+    Ok(response)
+}
+
+#[tokio::test]
+async fn test_http_service() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let service: &'static HttpService = Box::leak(Box::new(HttpService::new(
+        "127.0.0.1:0".to_string(), ListenerOptions::default(), None, vec![])));
+    let port = service.local_addr()?.port();
+    let task = tokio::spawn(service.run());
+
+    let response = get(port, "GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n").await?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nok\n"), "{}", response);
+
+    // There are no services, so nothing is reachable
+    let response = get(port, "GET /readyz HTTP/1.1\r\n\r\n").await?;
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", response);
+
+    let response = get(port, "GET /metrics HTTP/1.1\r\n\r\n").await?;
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"), "{}", response);
+    assert!(response.contains("\nriverdb_ready 0\n"), "{}", response);
+
+    let response = get(port, "GET /status HTTP/1.1\r\n\r\n").await?;
+    let body = response.split("\r\n\r\n").nth(1).unwrap_or("");
+    let status: serde_json::Value = serde_json::from_str(body)?;
+    assert_eq!(status["ready"], false);
+    assert!(status["clusters"].is_array());
+
+    let response = get(port, "GET /nope HTTP/1.1\r\n\r\n").await?;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+    let response = get(port, "POST /healthz HTTP/1.1\r\n\r\n").await?;
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}", response);
+    // POST /query is disabled unless http_query is set
+    let response = get(port, "POST /query HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}").await?;
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}", response);
+    // Basic authentication over plain HTTP is refused, even if http_query is set
+    unsafe { test_config_mut().http_query = true; }
+    let response = get(port, "POST /query HTTP/1.1\r\nAuthorization: Basic dXNlcjpwYXNz\r\nContent-Length: 2\r\n\r\n{}").await;
+    unsafe { test_config_mut().http_query = false; }
+    let response = response?;
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}", response);
+
+    task.abort();
+    Ok(())
+}
@@ -0,0 +1,24 @@
+use crate::riverdb::config::NotificationPolicy;
+use crate::tests::common::load;
+
+#[test]
+fn test_in_pool_notifications() {
+    let cluster = |option: &str| format!(r#"
+postgres:
+  {}
+  default: {{database: app, can_query: true, replicas: []}}
+  servers:
+    - {{database: app, host: 127.0.0.1, can_query: true, replicas: []}}
+plugins: []
+"#, option);
+
+    let settings = load(&cluster("")).expect("valid settings");
+    assert_eq!(settings.postgres.in_pool_notifications, NotificationPolicy::Unlisten);
+
+    for (value, policy) in [("drop", NotificationPolicy::Drop), ("close", NotificationPolicy::Close)] {
+        let settings = load(&cluster(&format!("in_pool_notifications: {}", value))).expect("valid settings");
+        assert_eq!(settings.postgres.in_pool_notifications, policy);
+    }
+
+    assert!(load(&cluster("in_pool_notifications: forward")).is_err());
+}
@@ -0,0 +1,46 @@
+use std::path::PathBuf;
+
+use crate::tests::common::{self, TestServer};
+use crate::tests::harness::{TestClient, start_mock_server};
+use crate::riverdb::config::{Settings, IoEngine};
+
+
+fn io_uring_settings(port: u16) -> Result<Settings, serde_yaml::Error> {
+    let mut settings = common::mock_settings(port, "", "")?;
+    settings.io_engine = IoEngine::IoUring;
+    Ok(settings)
+}
+
+#[test]
+fn test_io_engine_config() -> Result<(), serde_yaml::Error> {
+    let mut settings: Settings = serde_yaml::from_str("io_engine: io_uring\npostgres: {servers: []}\nplugins: []")?;
+    settings.load(PathBuf::new()).unwrap();
+    assert_eq!(settings.io_engine, IoEngine::IoUring);
+
+    let settings: Settings = serde_yaml::from_str("postgres: {servers: []}\nplugins: []")?;
+    assert_eq!(settings.io_engine, IoEngine::Readiness);
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_io_uring_queries() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(io_uring_settings(backend.port())?)?;
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    let result = client.simple_query("SELECT 1").await?;
+    assert_eq!(result.rows[0][0].as_deref(), Some("1"));
+    // A result larger than the send buffers
+    let result = client.simple_query("SELECT repeat('x', 1000000)").await?;
+    assert_eq!(result.rows[0][0].as_ref().map(String::len), Some(1000000));
+    client.terminate().await?;
+
+    // Closed connections are removed, and new ones are accepted
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    client.simple_query("SELECT 1").await?;
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
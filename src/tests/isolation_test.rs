@@ -0,0 +1,113 @@
+use crate::tests::common::{self, TestServer};
+use crate::tests::harness::{TestClient, start_mock_server};
+use crate::riverdb::config::Settings;
+
+
+fn isolation_settings(port: u16, action: &str, unbuffered_begin: bool) -> Result<Settings, serde_yaml::Error> {
+    common::mock_settings(port, &format!("unbuffered_begin: {}", unbuffered_begin),
+        &format!("max_isolation: repeatable_read, max_isolation_action: {}", action))
+}
+
+async fn isolation(client: &mut TestClient) -> std::result::Result<Option<String>, Box<dyn std::error::Error>> {
+    let result = client.simple_query("SHOW transaction_isolation").await?;
+    Ok(result.rows[0][0].clone())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_reject_isolation() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(isolation_settings(backend.port(), "reject", false)?)?;
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    // The held BEGIN is rejected with the next statement, the transaction stays failed until it's rolled back
+    client.simple_query("BEGIN ISOLATION LEVEL SERIALIZABLE").await?;
+    let err = client.simple_query("SELECT 1").await.unwrap_err();
+    assert!(err.to_string().contains("isolation level SERIALIZABLE is not permitted"), "{}", err);
+    assert_eq!(client.tx_status, b'E');
+    assert!(client.simple_query("SELECT 1").await.is_err());
+    client.simple_query("ROLLBACK").await?;
+    assert_eq!(client.tx_status, b'I');
+
+    client.simple_query("BEGIN ISOLATION LEVEL REPEATABLE READ").await?;
+    assert_eq!(isolation(&mut client).await?.as_deref(), Some("repeatable read"));
+    client.simple_query("COMMIT").await?;
+    assert_eq!(client.tx_status, b'I');
+    client.terminate().await?;
+    server.shutdown().await;
+
+    // Without buffering the BEGIN itself fails, so the client isn't in a transaction
+    let server = TestServer::with_settings(isolation_settings(backend.port(), "reject", true)?)?;
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    assert!(client.simple_query("START TRANSACTION ISOLATION LEVEL SERIALIZABLE").await.is_err());
+    assert_eq!(client.tx_status, b'I');
+    client.simple_query("SELECT 1").await?;
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_downgrade_isolation() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    for unbuffered_begin in [false, true] {
+        let server = TestServer::with_settings(isolation_settings(backend.port(), "downgrade", unbuffered_begin)?)?;
+
+        let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+        let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+        client.simple_query("BEGIN ISOLATION LEVEL SERIALIZABLE").await?;
+        assert_eq!(isolation(&mut client).await?.as_deref(), Some("repeatable read"));
+        assert_eq!(client.tx_status, b'T');
+        client.simple_query("COMMIT").await?;
+
+        client.simple_query("BEGIN").await?;
+        client.simple_query("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE").await?;
+        assert_eq!(isolation(&mut client).await?.as_deref(), Some("repeatable read"));
+        client.simple_query("COMMIT").await?;
+        assert_eq!(client.tx_status, b'I');
+        client.terminate().await?;
+        server.shutdown().await;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_session_default_isolation() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(isolation_settings(backend.port(), "reject", false)?)?;
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    // A BEGIN without an isolation level uses the session default
+    client.simple_query("SET SESSION CHARACTERISTICS AS TRANSACTION ISOLATION LEVEL SERIALIZABLE").await?;
+    client.simple_query("BEGIN").await?;
+    let err = client.simple_query("SELECT 1").await.unwrap_err();
+    assert!(err.to_string().contains("isolation level SERIALIZABLE is not permitted"), "{}", err);
+    client.simple_query("ROLLBACK").await?;
+    client.simple_query("SET default_transaction_isolation TO 'read committed'").await?;
+    client.simple_query("BEGIN").await?;
+    client.simple_query("SELECT 1").await?;
+    client.simple_query("COMMIT").await?;
+    client.terminate().await?;
+    server.shutdown().await;
+
+    for unbuffered_begin in [false, true] {
+        let server = TestServer::with_settings(isolation_settings(backend.port(), "downgrade", unbuffered_begin)?)?;
+        let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+        let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+        client.simple_query("SET default_transaction_isolation TO 'serializable'").await?;
+        client.simple_query("BEGIN").await?;
+        assert_eq!(isolation(&mut client).await?.as_deref(), Some("repeatable read"));
+        client.simple_query("COMMIT").await?;
+        client.simple_query("RESET default_transaction_isolation").await?;
+        client.simple_query("BEGIN").await?;
+        assert_eq!(isolation(&mut client).await?.as_deref(), Some("read committed"));
+        client.simple_query("COMMIT").await?;
+        client.terminate().await?;
+        server.shutdown().await;
+    }
+    Ok(())
+}
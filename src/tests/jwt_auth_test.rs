@@ -0,0 +1,205 @@
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::rand::SystemRandom;
+use ring::signature::{self, EcdsaKeyPair, KeyPair, RsaKeyPair};
+use serde_json::{json, Value};
+
+use crate::riverdb::config::JwtSettings;
+use crate::riverdb::pg::JwtVerifier;
+use crate::tests::common::{self, load, TestServer};
+use crate::tests::harness::{TestClient, start_mock_server};
+
+const RSA_KEY: &str = "src/tests/testdata/test-ca/rsa/end.rsa";
+const SERVER_CERT: &str = "src/tests/testdata/test-ca/rsa/end.fullchain";
+const ISSUER: &str = "https://auth.example.com";
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// An EC P-256 signing key and its JWK.
+fn ec_key(kid: &str) -> (EcdsaKeyPair, Value) {
+    let rng = SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+    let key = EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap();
+    let point = key.public_key().as_ref();
+    let jwk = json!({"kty": "EC", "crv": "P-256", "kid": kid, "x": b64(&point[1..33]), "y": b64(&point[33..])});
+    (key, jwk)
+}
+
+fn ec_token(key: &EcdsaKeyPair, kid: &str, claims: Value) -> String {
+    let message = format!("{}.{}", b64(json!({"alg": "ES256", "typ": "JWT", "kid": kid}).to_string().as_bytes()), b64(claims.to_string().as_bytes()));
+    let sig = key.sign(&SystemRandom::new(), message.as_bytes()).unwrap();
+    format!("{}.{}", message, b64(sig.as_ref()))
+}
+
+fn claims(role: &str) -> Value {
+    json!({"iss": ISSUER, "aud": ["riverdb", "other"], "sub": "lambda-1", "role": role, "exp": now() + 300})
+}
+
+fn verifier(jwks: &str, extra: &str) -> JwtVerifier {
+    let mut settings: JwtSettings = serde_yaml::from_str(&format!("{{issuer: {:?}, jwks: {:?}{}}}", ISSUER, jwks, extra)).unwrap();
+    settings.load().expect("valid jwt settings");
+    JwtVerifier::new(Box::leak(Box::new(settings)))
+}
+
+#[test]
+fn test_jwt_settings() {
+    let (_, jwk) = ec_key("k1");
+    let jwks = json!({"keys": [jwk]}).to_string();
+    let settings = load(&format!(r#"
+postgres:
+  auth_rules:
+    rules:
+      - {{user: app, method: jwt}}
+  jwt: {{issuer: {:?}, jwks: {:?}}}
+  servers: []
+plugins: []
+"#, ISSUER, jwks)).expect("valid settings");
+    assert_eq!(settings.postgres.jwt.role_claim, "role");
+    assert_eq!(settings.postgres.jwt.leeway_seconds, 60);
+
+    let err = load(r#"
+postgres:
+  auth_rules:
+    rules:
+      - {user: app, method: jwt}
+  servers: []
+plugins: []
+"#).err().unwrap();
+    assert_eq!(err, "auth_rules with the jwt method require jwt issuer and jwks");
+
+    let err = load(r#"
+postgres:
+  jwt: {issuer: x, jwks: '{"keys": [{"kty": "oct", "k": "c2VjcmV0"}]}'}
+  servers: []
+plugins: []
+"#).err().unwrap();
+    assert_eq!(err, "invalid jwt jwks: no RSA or EC (P-256 or P-384) signing keys");
+}
+
+#[test]
+fn test_jwt_verify() {
+    let (key, jwk) = ec_key("k1");
+    let (other_key, _) = ec_key("k1");
+    let jwks = json!({"keys": [jwk]}).to_string();
+    let v = verifier(&jwks, ", audience: riverdb");
+
+    assert_eq!(v.verify(&ec_token(&key, "k1", claims("app_reader"))).unwrap(), "app_reader");
+
+    // Signed with a different key
+    assert!(v.verify(&ec_token(&other_key, "k1", claims("app_reader"))).is_err());
+    // A tampered token
+    let token = ec_token(&key, "k1", claims("app_reader"));
+    let mut parts: Vec<&str> = token.split('.').collect();
+    let forged = b64(claims("postgres").to_string().as_bytes());
+    parts[1] = &forged;
+    assert!(v.verify(&parts.join(".")).is_err());
+    // An unsigned token
+    let unsigned = format!("{}.{}.", b64(br#"{"alg":"none"}"#), b64(claims("app_reader").to_string().as_bytes()));
+    assert!(v.verify(&unsigned).is_err());
+    assert_eq!(v.verify(&ec_token(&key, "k2", claims("app_reader"))).err().unwrap().to_string(), "unknown JWT key id k2");
+
+    let mut expired = claims("app_reader");
+    expired["exp"] = json!(now() - 120);
+    assert_eq!(v.verify(&ec_token(&key, "k1", expired)).err().unwrap().to_string(), "JWT has expired");
+    // Within the leeway
+    let mut expired = claims("app_reader");
+    expired["exp"] = json!(now() - 10);
+    assert!(v.verify(&ec_token(&key, "k1", expired)).is_ok());
+
+    let mut wrong = claims("app_reader");
+    wrong["iss"] = json!("https://evil.example.com");
+    assert_eq!(v.verify(&ec_token(&key, "k1", wrong)).err().unwrap().to_string(), "JWT has the wrong issuer");
+    let mut wrong = claims("app_reader");
+    wrong["aud"] = json!("other");
+    assert_eq!(v.verify(&ec_token(&key, "k1", wrong)).err().unwrap().to_string(), "JWT has the wrong audience");
+    let mut wrong = claims("app_reader");
+    wrong.as_object_mut().unwrap().remove("role");
+    assert_eq!(v.verify(&ec_token(&key, "k1", wrong)).err().unwrap().to_string(), "JWT has no role claim");
+}
+
+#[test]
+fn test_jwt_roles() {
+    let (key, jwk) = ec_key("k1");
+    let jwks = json!({"keys": [jwk]}).to_string();
+    let v = verifier(&jwks, ", role_claim: tier, roles: {free: app_limited, paid: app_full}");
+
+    let mut c = claims("");
+    c["tier"] = json!("paid");
+    assert_eq!(v.verify(&ec_token(&key, "k1", c.clone())).unwrap(), "app_full");
+    c["tier"] = json!("admin");
+    assert_eq!(v.verify(&ec_token(&key, "k1", c)).err().unwrap().to_string(), "JWT tier claim admin is not mapped to a role");
+}
+
+#[test]
+fn test_jwt_rsa_and_key_rotation() {
+    let der = rustls_pemfile::rsa_private_keys(&mut BufReader::new(File::open(RSA_KEY).unwrap())).unwrap().remove(0);
+    let rsa = RsaKeyPair::from_der(&der).unwrap();
+    let rsa_jwk = json!({"kty": "RSA", "kid": "rsa1", "alg": "RS256", "use": "sig",
+        "n": b64(rsa.public_key().modulus().big_endian_without_leading_zero()),
+        "e": b64(rsa.public_key().exponent().big_endian_without_leading_zero())});
+    let rsa_token = |claims: Value| {
+        let message = format!("{}.{}", b64(br#"{"alg":"RS256","kid":"rsa1"}"#), b64(claims.to_string().as_bytes()));
+        let mut sig = vec![0; rsa.public_modulus_len()];
+        rsa.sign(&signature::RSA_PKCS1_SHA256, &SystemRandom::new(), message.as_bytes(), &mut sig).unwrap();
+        format!("{}.{}", message, b64(&sig))
+    };
+
+    let path = std::env::temp_dir().join(format!("riverdb-jwks-{}.json", std::process::id()));
+    fs::write(&path, json!({"keys": [rsa_jwk]}).to_string()).unwrap();
+    let v = verifier(path.to_str().unwrap(), "");
+    assert_eq!(v.verify(&rsa_token(claims("app_reader"))).unwrap(), "app_reader");
+
+    // A token signed with a new key is accepted once the key is added to the jwks file
+    let (key, jwk) = ec_key("k2");
+    assert!(v.verify(&ec_token(&key, "k2", claims("app_reader"))).is_err());
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    fs::write(&path, json!({"keys": [rsa_jwk, jwk]}).to_string()).unwrap();
+    assert_eq!(v.verify(&ec_token(&key, "k2", claims("app_writer"))).unwrap(), "app_writer");
+    assert_eq!(v.verify(&rsa_token(claims("app_reader"))).unwrap(), "app_reader");
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_jwt_proxy() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (key, jwk) = ec_key("k1");
+    let jwks = json!({"keys": [jwk]}).to_string();
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(common::mock_settings(backend.port(), &format!(r#"client_tls: prefer
+tls_server_certificate: {}
+tls_server_key: {}
+auth_rules:
+  rules:
+    - {{user: app, method: jwt}}
+jwt: {{issuer: {:?}, jwks: {:?}}}"#, SERVER_CERT, RSA_KEY, ISSUER, jwks), "")?)?;
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let params = [("user", "app"), ("database", common::TEST_DATABASE)];
+    let token = ec_token(&key, "k1", claims("app_reader"));
+    // The token is a bearer credential, it's not accepted over an unencrypted connection
+    let err = TestClient::connect_with_params(addr, &params, &token).await.err().unwrap();
+    assert!(err.to_string().contains("requires a TLS connection"), "{}", err);
+    assert!(TestClient::connect_tls_with_params(addr, &params, "not a token").await.is_err());
+
+    let mut client = TestClient::connect_tls_with_params(addr, &params, &token).await?;
+    // The backend connection switched to the role from the token
+    let result = client.simple_query("SHOW role").await?;
+    assert_eq!(result.rows[0][0].as_deref(), Some("app_reader"));
+    for sql in ["SET ROLE postgres", "SET SESSION AUTHORIZATION postgres", "RESET ROLE", "RESET ALL", "DISCARD ALL"] {
+        let err = client.simple_query(sql).await.expect_err(sql);
+        assert!(err.to_string().contains("can't change the role"), "{}: {}", sql, err);
+    }
+    let result = client.simple_query("SHOW role").await?;
+    assert_eq!(result.rows[0][0].as_deref(), Some("app_reader"));
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use crate::riverdb::config::{LogFormat, LogRotation};
+use crate::tests::common::load;
+
+#[test]
+fn test_log_defaults() {
+    let settings = load("postgres: {servers: []}\nplugins: []").expect("valid settings");
+    assert_eq!(settings.log.filter(), "info");
+    assert_eq!(settings.log.format, LogFormat::Text);
+    assert!(settings.log.file.is_empty());
+    assert_eq!(settings.log.max_files, 7);
+}
+
+#[test]
+fn test_log_settings() {
+    let settings = load(r#"
+postgres: {servers: []}
+log:
+  level: WARN
+  modules: {riverdb::pg::backend: debug, riverdb::pg::client: trace}
+  format: json
+  file: /var/log/riverdb.log
+  max_file_bytes: 104857600
+  rotation: daily
+plugins: []
+"#).expect("valid settings");
+    assert_eq!(settings.log.filter(), "warn,riverdb::pg::backend=debug,riverdb::pg::client=trace");
+    assert_eq!(settings.log.format, LogFormat::Json);
+    assert_eq!(settings.log.rotation, LogRotation::Daily);
+}
+
+#[test]
+fn test_log_settings_invalid() {
+    let result = load("postgres: {servers: []}\nlog: {level: verbose}\nplugins: []");
+    assert!(result.err().unwrap().contains("invalid log level 'verbose'"));
+
+    let result = load("postgres: {servers: []}\nlog: {modules: {riverdb: loud}}\nplugins: []");
+    assert!(result.err().unwrap().contains("invalid log level 'loud'"));
+
+    let result = load("postgres: {servers: []}\nlog: {rotation: hourly}\nplugins: []");
+    assert!(result.err().unwrap().contains("requires a log file"));
+}
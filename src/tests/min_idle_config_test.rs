@@ -0,0 +1,36 @@
+use crate::tests::common::load;
+
+#[test]
+fn test_min_idle() {
+    let settings = load(r#"
+postgres:
+  default: {database: app, can_query: true, min_idle: 4, replicas: []}
+  servers:
+    - database: app
+      host: 127.0.0.1
+      can_query: true
+      connect_rate: 50
+      user_pools:
+        - {user: reporting}
+      replicas:
+        - {database: app, host: 127.0.0.2, can_query: true, min_idle: 2, replicas: []}
+plugins: []
+"#).expect("valid settings");
+
+    let server = &settings.postgres.servers[0];
+    assert_eq!((server.min_idle, server.connect_rate), (4, 50));
+    assert_eq!((server.replicas[0].min_idle, server.replicas[0].connect_rate), (2, 10));
+    // User pools connect on demand
+    assert_eq!(server.user_pool_configs[0].min_idle, 0);
+}
+
+#[test]
+fn test_min_idle_invalid() {
+    let err = load(r#"
+postgres:
+  servers:
+    - {database: app, host: 127.0.0.1, can_query: true, max_connections: 20, min_idle: 30, replicas: []}
+plugins: []
+"#).err().expect("expected an error");
+    assert_eq!(err, "min_idle 30 cannot be greater than max_connections 20");
+}
@@ -10,9 +10,50 @@ See: https://matklad.github.io/2021/02/27/delete-cargo-integration-tests.html
 
 #[macro_use]
 mod common;
+mod harness;
 mod tls_test;
 mod backend_auth_test;
 mod client_auth_test;
 mod proxy_queries_test;
 mod proxy_transactions_test;
-mod normalize_test;
\ No newline at end of file
+mod normalize_test;
+mod cache_config_test;
+mod clusters_config_test;
+mod query_tags_config_test;
+mod query_types_config_test;
+mod sharding_config_test;
+mod auth_rules_config_test;
+mod logging_config_test;
+mod user_pools_config_test;
+mod replica_read_only_config_test;
+mod health_check_config_test;
+mod connect_retry_config_test;
+mod min_idle_config_test;
+mod backend_tls_config_test;
+mod in_pool_notifications_config_test;
+mod test_server_test;
+mod harness_test;
+mod retry_reads_test;
+mod transaction_routing_test;
+mod savepoints_test;
+mod isolation_test;
+mod trace_propagation_test;
+mod embedded_test;
+mod http_test;
+mod panic_test;
+mod buffer_limits_config_test;
+mod io_uring_config_test;
+mod tls_settings_config_test;
+mod gssapi_config_test;
+mod jwt_auth_test;
+mod admin_test;
+mod cache_test;
+mod pinning_test;
+mod query_timeout_test;
+mod dropped_messages_test;
+mod pool_wait_test;
+mod user_pool_test;
+mod protocol_options_test;
+mod copy_test;
+#[cfg(unix)]
+mod unix_socket_test;
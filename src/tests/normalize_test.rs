@@ -2,7 +2,8 @@ use bytes::{Bytes};
 
 use crate::riverdb::{Result};
 use crate::riverdb::pg::protocol::{Messages, MessageBuilder, Tag};
-use crate::riverdb::pg::sql::{QueryMessage, LiteralType};
+use crate::riverdb::pg::sql::{QueryMessage, QueryType, LiteralType, ObjectType, NormalizeOptions, is_keyword};
+use crate::riverdb::cache::cache_tables;
 
 #[derive(Debug)]
 struct QueryParamTest {
@@ -183,6 +184,14 @@ fn test_normalize_ok() {
                 QueryParamTest { value: "e'foo\\''", ty: LiteralType::EscapeString, negated: false, target_type: "" },
             ],
         ),
+        (
+            "select 'it''s', ''''",
+            "SELECT $1, $2",
+            vec![
+                QueryParamTest { value: "'it''s'", ty: LiteralType::String, negated: false, target_type: "" },
+                QueryParamTest { value: "''''", ty: LiteralType::String, negated: false, target_type: "" },
+            ],
+        ),
         (
             r#"select "fo""o" from bar"#,
             r#"SELECT "fo""o" FROM BAR"#,
@@ -256,6 +265,34 @@ fn test_normalize_ok() {
                 QueryParamTest { value: "1", ty: LiteralType::Integer, negated: true, target_type: "" },
             ],
         ),
+        (
+            "select '1'::int, '{1}'::public.int_list[], 2::double precision, 'a'::varchar(10)",
+            "SELECT $1::INT, $2::PUBLIC.INT_LIST[], $3::DOUBLE PRECISION, $4::VARCHAR($5)",
+            vec![
+                QueryParamTest { value: "'1'", ty: LiteralType::String, negated: false, target_type: "INT" },
+                QueryParamTest { value: "'{1}'", ty: LiteralType::String, negated: false, target_type: "PUBLIC.INT_LIST[]" },
+                QueryParamTest { value: "2", ty: LiteralType::Integer, negated: false, target_type: "DOUBLE PRECISION" },
+                QueryParamTest { value: "'a'", ty: LiteralType::String, negated: false, target_type: "VARCHAR" },
+                QueryParamTest { value: "10", ty: LiteralType::Integer, negated: false, target_type: "" },
+            ],
+        ),
+        (
+            "select date '2020-01-01', timestamp with time zone '2020-01-01 00:00', now() at time zone 'UTC'",
+            "SELECT DATE $1, TIMESTAMP WITH TIME ZONE $2, NOW() AT TIME ZONE $3",
+            vec![
+                QueryParamTest { value: "'2020-01-01'", ty: LiteralType::String, negated: false, target_type: "DATE" },
+                QueryParamTest { value: "'2020-01-01 00:00'", ty: LiteralType::String, negated: false, target_type: "TIMESTAMP WITH TIME ZONE" },
+                QueryParamTest { value: "'UTC'", ty: LiteralType::String, negated: false, target_type: "" },
+            ],
+        ),
+        (
+            "select cast('5' as bigint), cast(-5 as \"my type\"), cast(a as int)",
+            "SELECT CAST($1 AS BIGINT), CAST($2 AS \"my type\"), CAST(A AS INT)",
+            vec![
+                QueryParamTest { value: "'5'", ty: LiteralType::String, negated: false, target_type: "BIGINT" },
+                QueryParamTest { value: "5", ty: LiteralType::Integer, negated: true, target_type: "\"my type\"" },
+            ],
+        ),
     ];
 
     for (query, normalized, params) in tests {
@@ -267,6 +304,7 @@ fn test_normalize_ok() {
             assert_eq!(param.ty, expected.ty);
             assert_eq!(param.negated, expected.negated);
             assert_eq!(query.param(param), expected.value);
+            assert_eq!(param.target_type(query.normalized()), expected.target_type);
         }
     }
 }
@@ -403,4 +441,305 @@ fn test_normalize_err() {
         let err_msg = res.expect_err("expected an error").to_string();
         assert!(err_msg.contains(err), "expected {} in err {}", err, err_msg);
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_is_simple_read() {
+    const TESTS: &[(&'static str, bool)] = &[
+        ("select * from users", true),
+        ("show timezone", true),
+        ("values (1, 2)", true),
+        ("select 1; show timezone", true),
+        ("select 1; set timezone = 'UTC'", false),
+        ("set role foo", false),
+        ("insert into users values (1)", false),
+        ("begin", false),
+    ];
+
+    for &(query, expected) in TESTS {
+        let q = make_query(query.as_bytes()).expect("valid query");
+        assert_eq!(q.is_simple_read(), expected, "{}", query);
+    }
+}
+
+#[test]
+fn test_object_type() {
+    const TESTS: &[(&'static str, ObjectType, &'static str)] = &[
+        ("create table films (id int)", ObjectType::Table, "FILMS"),
+        ("CREATE TEMP TABLE IF NOT EXISTS public.films(id int)", ObjectType::Table, "PUBLIC.FILMS"),
+        ("create unique index concurrently if not exists films_idx on films (id)", ObjectType::Index, "FILMS_IDX"),
+        ("create index on films (id)", ObjectType::Index, ""),
+        ("drop index concurrently films_idx", ObjectType::Index, "FILMS_IDX"),
+        ("drop table if exists films, actors cascade", ObjectType::Table, "FILMS"),
+        ("alter table only films add column title text", ObjectType::Table, "FILMS"),
+        ("create or replace function add(a int, b int) returns int as 'select a + b' language sql", ObjectType::Function, "ADD"),
+        ("drop procedure if exists cleanup", ObjectType::Function, "CLEANUP"),
+        ("create materialized view film_stats as select 1", ObjectType::MaterializedView, "FILM_STATS"),
+        ("create recursive view v(n) as select 1", ObjectType::View, "V"),
+        ("create schema reporting", ObjectType::Schema, "REPORTING"),
+        ("drop database if exists \"My DB\"", ObjectType::Database, "\"My DB\""),
+        ("create sequence films_seq", ObjectType::Sequence, "FILMS_SEQ"),
+        ("alter user bob with password 'secret'", ObjectType::Role, "BOB"),
+        ("create user mapping for bob server films_server", ObjectType::Other, ""),
+        ("drop user mapping if exists for bob server films_server", ObjectType::Other, ""),
+        ("create extension if not exists pgcrypto", ObjectType::Extension, "PGCRYPTO"),
+        ("create type mood as enum ('happy')", ObjectType::Type, "MOOD"),
+        ("create trigger t before insert on films execute function f()", ObjectType::Trigger, "T"),
+        ("alter default privileges grant select on tables to bob", ObjectType::Other, ""),
+        ("select * from films", ObjectType::Other, ""),
+    ];
+
+    for &(query, object_ty, name) in TESTS {
+        let q = make_query(query.as_bytes()).expect("valid query");
+        assert_eq!(q.query().object_type(), object_ty, "{}", query);
+        assert_eq!(q.query().object_name(), name, "{}", query);
+    }
+
+    let q = make_query(b"drop table if exists films").unwrap();
+    assert_eq!(q.query().tables(), vec!["FILMS"]);
+    let q = make_query(b"drop table if exists films, public.actors cascade").unwrap();
+    assert_eq!(q.query().tables(), vec!["FILMS", "PUBLIC.ACTORS"]);
+    let q = make_query(b"drop view \"a, b\", c restrict").unwrap();
+    assert_eq!(q.query().tables(), vec!["\"a, b\"", "C"]);
+}
+
+#[test]
+fn test_collapse_in_lists() {
+    let options = NormalizeOptions{collapse_in_lists: true, ..Default::default()};
+    let normalize = |sql: &str| {
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str(sql);
+        QueryMessage::new_with_options(mb.finish(), options).expect("valid query")
+    };
+
+    let short = normalize("select * from t where id in (1, 2) and kind = 'a'");
+    let long = normalize("select * from t where id in (1,2, -3, 4, 5) and kind = 'b'");
+    assert_eq!(short.query().normalized(), "SELECT * FROM T WHERE ID IN($1) AND KIND = $2");
+    assert_eq!(long.query().normalized(), short.query().normalized());
+
+    let query = long.query();
+    let list = &query.params()[0];
+    assert_eq!(list.ty, LiteralType::List);
+    assert_eq!(query.param(list), "1, 2, -3, 4, 5");
+    assert_eq!(list.items.len(), 5);
+    assert!(list.items[2].negated);
+    assert_eq!(query.param(&query.params()[1]), "'b'");
+    assert_eq!(query.to_sql(), "SELECT * FROM T WHERE ID IN(1, 2, -3, 4, 5) AND KIND = 'b'");
+
+    // Lists with other expressions, or not after IN, are kept
+    assert_eq!(normalize("select * from t where id in (1, a)").query().normalized(), "SELECT * FROM T WHERE ID IN($1, A)");
+    assert_eq!(normalize("select * from t where id in ($1, 2)").query().normalized(), "SELECT * FROM T WHERE ID IN($1, $1)");
+    assert_eq!(normalize("insert into t values (1, 2)").query().normalized(), "INSERT INTO T VALUES($1, $2)");
+    assert_eq!(normalize("select * from t where (a, b) in ((1, 2))").query().normalized(), "SELECT * FROM T WHERE(A, B) IN(($1, $2))");
+
+    // Without the option the list is kept
+    let query = make_query(b"select * from t where id in (1, 2)").unwrap();
+    assert_eq!(query.query().normalized(), "SELECT * FROM T WHERE ID IN($1, $2)");
+}
+
+#[test]
+fn test_creates_session_state() {
+    const TESTS: &[(&'static str, bool)] = &[
+        ("create temp table t (id int)", true),
+        ("CREATE LOCAL TEMPORARY TABLE t AS SELECT 1", true),
+        ("create table temp (id int)", false),
+        ("prepare q as select 1", true),
+        ("declare c cursor with hold for select 1", true),
+        ("declare c cursor for select 1", false),
+        ("select 1; select pg_advisory_lock(42)", true),
+        ("select pg_try_advisory_lock(42)", true),
+        ("select pg_advisory_xact_lock(42)", false),
+        ("select * from users", false),
+    ];
+
+    for &(query, expected) in TESTS {
+        let q = make_query(query.as_bytes()).expect("valid query");
+        assert_eq!(q.query().creates_session_state(), expected, "{}", query);
+    }
+}
+
+#[test]
+fn test_extended_query() {
+    // A Close message before the Parse, so the query isn't in the first message
+    let mut mb = MessageBuilder::new(Tag::CLOSE);
+    mb.write_byte('S' as u8);
+    mb.write_str("s0");
+    mb.add_new(Tag::PARSE);
+    mb.write_str("s1");
+    mb.write_str("select /* app=web */ * from users where id = $1");
+    mb.write_i16(0);
+    let parse = mb.finish();
+
+    let mut mb = MessageBuilder::new(Tag::BIND);
+    mb.write_str("");
+    mb.write_str("s1");
+    mb.write_i16(0);
+    mb.write_i16(0);
+    mb.write_i16(0);
+    mb.add_new(Tag::EXECUTE);
+    mb.write_str("");
+    mb.write_i32(0);
+    mb.add_new(Tag::SYNC);
+    let execute = mb.finish();
+
+    let q = QueryMessage::new(parse.clone().append(execute.clone())).expect("valid query");
+    assert_eq!(q.query().normalized.as_str(), "SELECT * FROM USERS WHERE ID = $1");
+    assert_eq!(q.tag("app"), Some("web"));
+    assert_eq!(q.tags(), vec![("app", "web")]);
+    assert!(q.is_simple_read());
+
+    // Replacing the SQL keeps the other messages and the statement name
+    let rewritten = q.with_sql("select * from app.users where id = $1").expect("valid query");
+    assert_eq!(rewritten.query().normalized.as_str(), "SELECT * FROM APP.USERS WHERE ID = $1");
+    let msgs = rewritten.into_messages();
+    assert_eq!(msgs.count(), 5);
+    let parse_msg = msgs.iter(0).nth(1).unwrap();
+    let mut r = parse_msg.reader();
+    assert_eq!(r.read_str().unwrap(), "s1");
+    assert_eq!(r.read_str().unwrap(), "select * from app.users where id = $1");
+    assert_eq!(r.read_i16(), 0);
+    assert_eq!(q.into_messages().count(), 5);
+
+    // Executing an existing prepared statement has no SQL
+    let q = QueryMessage::new(execute).expect("valid query");
+    assert_eq!(q.query().normalized.as_str(), "");
+    assert!(!q.is_simple_read());
+    assert!(q.with_sql("select 1").is_err());
+    // A pipelined group with several Parse messages, every statement is classified
+    let mut mb = MessageBuilder::new(Tag::PARSE);
+    mb.write_str("s2");
+    mb.write_str("delete from users where id = $1");
+    mb.write_i16(0);
+    let q = QueryMessage::new(parse.append(mb.finish()).append(execute)).expect("valid query");
+    assert_eq!(q.query().normalized.as_str(), "SELECT * FROM USERS WHERE ID = $1");
+    let statements: Vec<_> = q.statements().map(|s| s.normalized()).collect();
+    assert_eq!(statements, vec!["SELECT * FROM USERS WHERE ID = $1", "DELETE FROM USERS WHERE ID = $1"]);
+    assert!(!q.is_simple_read());
+}
+
+#[test]
+fn test_query_tables() {
+    let tests: &[(&'static [u8], &[&str])] = &[
+        (b"select * from users where id = 1", &["USERS"]),
+        (b"select u.name from users u, public.orders as o where u.id = o.user_id and o.id in (1, 2)", &["PUBLIC.ORDERS", "USERS"]),
+        (b"select * from users join orders on users.id = orders.user_id left join items using (order_id)", &["ITEMS", "ORDERS", "USERS"]),
+        (b"select * from (select * from users) u where exists (select 1 from orders)", &["ORDERS", "USERS"]),
+        (b"select count(*) from generate_series(1, 10)", &["GENERATE_SERIES"]),
+        (b"insert into orders(user_id, total) values (1, 2.5) returning id", &["ORDERS"]),
+        (b"update only users set name = 'x' from orders where orders.user_id = users.id", &["ORDERS", "USERS"]),
+        (b"delete from \"Items\" where id = 3", &["\"Items\""]),
+        (b"truncate table orders, items restart identity", &["ITEMS", "ORDERS"]),
+        (b"insert into users values (1); select * from users", &["USERS"]),
+        (b"begin", &[]),
+    ];
+
+    for (query, tables) in tests {
+        let q = make_query(query).expect("valid query");
+        assert_eq!(&q.query().tables(), tables, "{}", q.query().normalized());
+    }
+}
+
+#[test]
+fn test_cache_tables() {
+    let tests: &[(&'static [u8], &[&str])] = &[
+        (b"select * from users u, public.Users, \"public\".\"users\"", &["users"]),
+        (b"update \"Users\" set name = 'x' from \"a.b\"", &["Users", "a.b"]),
+        (b"delete from \"say\"\"hi\"\"\"", &["say\"hi\""]),
+    ];
+
+    for (query, tables) in tests {
+        let q = make_query(query).expect("valid query");
+        assert_eq!(&cache_tables(q.statements()), tables, "{}", q.query().normalized());
+    }
+}
+
+#[test]
+fn test_prepared_statement_names() {
+    let mut mb = MessageBuilder::new(Tag::PARSE);
+    mb.write_str("s1");
+    mb.write_str("select 1");
+    mb.write_i16(0);
+    mb.add_new(Tag::PARSE);
+    mb.write_str("");
+    mb.write_str("update users set name = 'x'");
+    mb.write_i16(0);
+    for name in ["s1", "s2"] {
+        mb.add_new(Tag::BIND);
+        mb.write_str("portal");
+        mb.write_str(name);
+        mb.write_i16(0);
+        mb.write_i16(0);
+        mb.write_i16(0);
+    }
+    mb.add_new(Tag::CLOSE);
+    mb.write_byte(b'P');
+    mb.write_str("portal");
+    mb.add_new(Tag::CLOSE);
+    mb.write_byte(b'S');
+    mb.write_str("s0");
+    mb.add_new(Tag::SYNC);
+    let query = QueryMessage::new(mb.finish()).expect("valid query");
+
+    let parsed: Vec<_> = query.parsed_statements().map(|(name, q)| (name, q.query_type())).collect();
+    assert_eq!(parsed, vec![("s1", QueryType::Select), ("", QueryType::Update)]);
+    assert_eq!(query.bound_statements(), vec!["s1", "s2"]);
+    assert_eq!(query.closed_statements(), vec!["s0"]);
+}
+
+#[test]
+fn test_preserve_identifier_case() {
+    let options = NormalizeOptions{preserve_identifier_case: true, ..Default::default()};
+    let normalize = |sql: &str| {
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str(sql);
+        QueryMessage::new_with_options(mb.finish(), options).expect("valid query")
+    };
+
+    assert!(is_keyword("select"));
+    assert!(is_keyword("Current_Timestamp"));
+    assert!(!is_keyword("users"));
+    assert!(!is_keyword("pg_advisory_lock"));
+
+    let query = normalize("select Id, count(*) from public.UserAccounts where \"Name\" = 'a' group by Id");
+    assert_eq!(query.query().normalized(), "SELECT Id, count(*) FROM public.UserAccounts WHERE \"Name\" = $1 GROUP BY Id");
+    assert_eq!(query.query().tables(), vec!["public.UserAccounts"]);
+
+    let query = normalize("insert into Orders (createdAt) values (date '2021-01-01'); select pg_advisory_lock(1)");
+    assert_eq!(query.query().normalized(), "INSERT INTO Orders(createdAt) VALUES(date $1)");
+    assert_eq!(query.query().params()[0].target_type(query.query().normalized()), "date");
+    assert!(query.query().creates_session_state());
+
+    let query = normalize("create table if not exists Events (id bigint)");
+    assert_eq!(query.query().object_type(), ObjectType::Table);
+    assert_eq!(query.query().object_name(), "Events");
+
+    // Without the option identifiers are uppercased
+    let query = make_query(b"select Id from UserAccounts").unwrap();
+    assert_eq!(query.query().normalized(), "SELECT ID FROM USERACCOUNTS");
+}
+
+#[test]
+fn test_truncate_large_queries() {
+    let options = NormalizeOptions{max_bytes: 32, ..Default::default()};
+    let normalize = |sql: &str| {
+        let mut mb = MessageBuilder::new(Tag::QUERY);
+        mb.write_str(sql);
+        QueryMessage::new_with_options(mb.finish(), options).expect("valid query")
+    };
+
+    let query = normalize("insert into t values (1, 'a'), (2, 'b'), (3, 'c'), (4, 'd')");
+    let q = query.query();
+    assert!(q.is_truncated());
+    assert_eq!(q.normalized(), "INSERT INTO T VALUES($1, $2),(");
+    assert_eq!(q.params().len(), 2);
+    assert_eq!(q.query_type(), QueryType::Insert);
+
+    // The limit applies to the whole message
+    let query = normalize("select 1; select 2; select 3; select 4; select 5; select 6");
+    assert!(query.query().is_truncated());
+    assert!(!query.query().truncated);
+    assert!(query.is_multi_query());
+
+    let query = normalize("select * from t where id = 1 ;  ");
+    assert!(!query.query().is_truncated());
+    assert!(!make_query(b"insert into t values (1, 'a'), (2, 'b'), (3, 'c'), (4, 'd')").unwrap().query().is_truncated());
+}
@@ -0,0 +1,56 @@
+use crate::register_scoped;
+use crate::tests::common::{self, TestServer};
+use crate::tests::harness::{TestClient, start_mock_server};
+use crate::riverdb::{Error, Result, Plugin};
+use crate::riverdb::common::panic_count;
+use crate::riverdb::pg::{ClientConn, client_query};
+use crate::riverdb::pg::sql::QueryMessage;
+use crate::riverdb::pg::protocol::{MessageBuilder, PostgresError, Tag, error_codes};
+
+
+struct PanicPlugin {}
+
+impl PanicPlugin {
+    pub async fn client_query(&self, ev: &mut client_query::Event, client: &ClientConn, query: QueryMessage) -> Result<()> {
+        if query.tag("panic").is_some() {
+            panic!("panic requested by the query");
+        }
+        ev.next(client, query).await
+    }
+}
+
+impl Plugin for PanicPlugin {}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_client_panic() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let plugin: &'static PanicPlugin = Box::leak(Box::new(PanicPlugin{}));
+    register_scoped!(plugin, CleanupQuery, PanicPlugin:client_query<'a>(query: QueryMessage) -> Result<()>);
+
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(common::mock_settings(backend.port(), "", "")?)?;
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+
+    let panics = panic_count();
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    client.simple_query("BEGIN").await?;
+    client.simple_query("SELECT 1").await?;
+    // The panic closes the session (and its backend connection) with an error
+    let mut mb = MessageBuilder::new(Tag::QUERY);
+    mb.write_str("/* panic=1 */ SELECT 1");
+    client.send(mb.finish()).await?;
+    let msgs = client.read_message().await?;
+    assert_eq!(msgs.first().unwrap().tag(), Tag::ERROR_RESPONSE);
+    assert_eq!(PostgresError::new(msgs)?.code(), error_codes::SYSTEM_ERROR);
+    assert_eq!(client.read_message().await.unwrap_err(), Error::closed());
+    // Other tests in the binary may panic concurrently (the count is global), so it's at least one more
+    assert!(panic_count() > panics);
+
+    // The server keeps running, and doesn't hand out the backend connection left in a transaction
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    client.simple_query("SELECT 1").await?;
+    assert_eq!(client.tx_status, b'I');
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
@@ -0,0 +1,35 @@
+use crate::tests::common::{self, TestServer, prepare};
+use crate::tests::harness::start_mock_server;
+
+
+/// Returns true if the only client of server is pinned to its backend connection.
+fn is_pinned(server: &TestServer) -> bool {
+    let mut pinned = false;
+    server.connections().for_each(|client| {
+        pinned = client.is_pinned();
+        false
+    });
+    pinned
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_pin_named_prepared_statement() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let (server, mut client) = common::connect_proxy(common::mock_settings(backend.port(), "", "pool_mode: transaction")?).await?;
+
+    // The unnamed statement doesn't outlive the query
+    client.query("SELECT $1", &[Some("1")]).await?;
+    assert!(!is_pinned(&server));
+    // A named statement that fails to prepare doesn't exist
+    client.send(prepare("s1", "FAIL")).await?;
+    assert!(client.read_until_ready().await.is_err());
+    assert!(!is_pinned(&server));
+
+    client.send(prepare("s1", "SELECT $1")).await?;
+    client.read_until_ready().await?;
+    assert!(is_pinned(&server));
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
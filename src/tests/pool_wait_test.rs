@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+use crate::tests::common::{self, TestServer};
+use crate::tests::harness::{TestClient, start_mock_server};
+use crate::riverdb::config::Settings;
+use crate::riverdb::pg::TransactionType;
+
+
+/// Settings for a pool of a single connection, so a client in a transaction makes the next one wait.
+fn single_connection_settings(port: u16, pool_wait_timeout_ms: u32) -> Result<Settings, serde_yaml::Error> {
+    let mut settings = common::mock_settings(port, "", &format!("pool_wait_timeout_ms: {}", pool_wait_timeout_ms))?;
+    settings.postgres.servers[0].max_connections = 1;
+    Ok(settings)
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_pool_wait() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(single_connection_settings(backend.port(), 5000)?)?;
+    let pool = server.cluster().nodes[0].master().expect("master");
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut first = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    first.simple_query("BEGIN").await?;
+    first.simple_query("SELECT 1").await?;
+
+    let mut second = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    let waiting = tokio::spawn(async move {
+        let result = second.simple_query("SELECT 1").await.map(|result| result.rows);
+        (second, result)
+    });
+    for _ in 0..100 {
+        if pool.queue_depth() != 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(pool.queue_depth(), 1);
+
+    // The connection is handed to the waiting client when the transaction ends
+    first.simple_query("COMMIT").await?;
+    let (second, result) = waiting.await?;
+    assert_eq!(result?, vec![vec![Some("1".to_string())]]);
+    assert_eq!(pool.queue_depth(), 0);
+    assert_eq!(pool.waits(), 1);
+    assert_eq!(pool.wait_timeouts(), 0);
+    first.terminate().await?;
+    second.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_pool_wait_timeout() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(single_connection_settings(backend.port(), 100)?)?;
+    let pool = server.cluster().nodes[0].master().expect("master");
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut first = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    first.simple_query("BEGIN").await?;
+    first.simple_query("SELECT 1").await?;
+
+    let mut second = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    let err = second.simple_query("SELECT 1").await.expect_err("expected pool wait timeout");
+    assert!(err.to_string().contains("timed out waiting for a database connection"), "{}", err);
+    assert_eq!(pool.wait_timeouts(), 1);
+
+    // The connection wasn't lost, it's still usable once the transaction ends
+    first.simple_query("COMMIT").await?;
+    let mut third = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    third.simple_query("SELECT 1").await?;
+    first.terminate().await?;
+    third.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_pool_wait_timeout_transaction() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(single_connection_settings(backend.port(), 100)?)?;
+    let pool = server.cluster().nodes[0].master().expect("master");
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut first = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    first.simple_query("BEGIN").await?;
+    first.simple_query("SELECT 1").await?;
+
+    // A transaction that times out waiting for a connection doesn't count against max_concurrent_transactions
+    let conn = pool.get("riverdb", "", TransactionType::Default).await?;
+    assert!(conn.is_none());
+    assert_eq!(pool.wait_timeouts(), 1);
+
+    first.simple_query("COMMIT").await?;
+    for _ in 0..100 {
+        if pool.pooled() != 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(pool.pooled(), 1);
+    assert_eq!(pool.active_transactions(), 0);
+    first.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_pool_wait_timeout_connect_retries() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    // Nothing is listening on the port once the listener is closed
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let settings = common::mock_settings(port, "connect_retry: {retries: 100, base_delay_ms: 50}", "pool_wait_timeout_ms: 200")?;
+    let server = TestServer::with_settings(settings)?;
+    let pool = server.cluster().nodes[0].master().expect("master");
+
+    // The retries stop at the pool_wait_timeout_ms, and the transaction is no longer counted
+    let start = tokio::time::Instant::now();
+    assert!(pool.get("riverdb", "", TransactionType::Default).await.is_err());
+    assert!(start.elapsed() < Duration::from_secs(2), "{:?}", start.elapsed());
+    assert_eq!(pool.active_transactions(), 0);
+    server.shutdown().await;
+    Ok(())
+}
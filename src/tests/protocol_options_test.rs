@@ -0,0 +1,42 @@
+use std::time::Duration;
+
+use crate::tests::common::{self, TestServer};
+use crate::tests::harness::{TestClient, start_mock_server};
+
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_protocol_options_not_pooled() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(common::mock_settings(backend.port(), "protocol_options: passthrough", "")?)?;
+    let pool = server.cluster().nodes[0].master().expect("master");
+
+    // The backend connection that negotiated the client's protocol options is closed, not returned to the pool
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let params = [("user", common::TEST_USER), ("database", common::TEST_DATABASE), ("_pq_.compression", "zstd")];
+    let mut client = TestClient::connect_with_params(addr, &params, common::TEST_PASSWORD).await?;
+    client.simple_query("SELECT 1").await?;
+    client.terminate().await?;
+    for _ in 0..100 {
+        if pool.connections.len() == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(pool.connections.len(), 0);
+    assert_eq!(pool.pooled(), 0);
+
+    // Connections of clients without protocol options are pooled as usual
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    client.simple_query("SELECT 1").await?;
+    client.terminate().await?;
+    for _ in 0..100 {
+        if pool.pooled() != 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(pool.pooled(), 1);
+    server.shutdown().await;
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use crate::riverdb::config::{TagViolationAction, TracePropagation};
+use crate::tests::common::load;
+
+#[test]
+fn test_required_tags() {
+    let settings = load(r#"
+postgres:
+  servers: []
+  query_tags:
+    required:
+      - {user: reporting, tags: [team, request_id], action: reject}
+      - {database: app, tags: [team]}
+plugins: []
+"#).expect("valid settings");
+
+    let query_tags = &settings.postgres.query_tags;
+    let rule = query_tags.required_for("app", "reporting").expect("matching rule");
+    assert_eq!(rule.action, TagViolationAction::Reject);
+    assert_eq!(rule.missing(|tag| tag == "team"), vec!["request_id"]);
+
+    let rule = query_tags.required_for("app", "web").expect("matching rule");
+    assert_eq!(rule.action, TagViolationAction::Log);
+    assert!(rule.missing(|tag| tag == "team").is_empty());
+
+    assert!(query_tags.required_for("other", "web").is_none());
+}
+
+#[test]
+fn test_required_tags_invalid() {
+    let result = load("postgres: {servers: [], query_tags: {required: [{tags: []}]}}\nplugins: []");
+    assert!(result.err().unwrap().contains("must list one or more tag names"));
+}
+
+#[test]
+fn test_trace_propagation() {
+    let settings = load("postgres: {servers: [], query_tags: {propagate: set_local}}\nplugins: []").expect("valid settings");
+    let query_tags = &settings.postgres.query_tags;
+    assert_eq!(query_tags.propagate, TracePropagation::SetLocal);
+    assert_eq!(query_tags.trace_setting, "riverdb.trace_id");
+    assert_eq!(query_tags.trace_tag(|name| if name == "request_id" { Some("r1") } else { None }), Some(("request_id", "r1")));
+    assert_eq!(query_tags.trace_tag(|_| None), None);
+
+    // request_id_application_name is shorthand for propagating request_id to the application_name
+    let settings = load("postgres: {servers: [], request_id_application_name: true}\nplugins: []").expect("valid settings");
+    let query_tags = &settings.postgres.query_tags;
+    assert_eq!(query_tags.propagate, TracePropagation::ApplicationName);
+    assert_eq!(query_tags.trace_tags, vec!["request_id".to_string()]);
+
+    let result = load("postgres: {servers: [], query_tags: {propagate: set_local, trace_setting: trace_id}}\nplugins: []");
+    assert!(result.err().unwrap().contains("must be a custom setting"));
+}
@@ -0,0 +1,31 @@
+use std::sync::atomic::Ordering::Relaxed;
+
+use crate::tests::common::{self, TestServer};
+use crate::tests::harness::{TestClient, start_mock_server, CANCELS};
+
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_query_timeout() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(common::mock_settings(backend.port(), "", "")?)?;
+    tokio::spawn(server.cluster().run_query_timeouts());
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    // Disabled by the default query_timeout_ms of 0
+    client.simple_query("SELECT pg_sleep(0.1)").await?;
+
+    // Enabled at runtime, as RELOAD does
+    server.cluster().set_query_timeout_ms(50);
+    let cancels = CANCELS.load(Relaxed);
+    let err = client.simple_query("SELECT pg_sleep(5)").await.expect_err("query should be cancelled");
+    assert!(err.to_string().contains("canceling statement"), "{}", err);
+    assert_eq!(CANCELS.load(Relaxed), cancels + 1);
+    // Queries that finish in time aren't cancelled
+    client.simple_query("SELECT 1").await?;
+    assert_eq!(CANCELS.load(Relaxed), cancels + 1);
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
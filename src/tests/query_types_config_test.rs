@@ -0,0 +1,39 @@
+use crate::riverdb::pg::sql::QueryType;
+use crate::tests::common::load;
+
+#[test]
+fn test_query_types() {
+    let settings = load(r#"
+postgres:
+  servers: []
+  query_types:
+    rules:
+      - {user: app, deny: [Drop, truncate, COPY, Alter]}
+      - {database: reports, allow: [Select, Show], deny: [SelectInto]}
+plugins: []
+"#).expect("valid settings");
+
+    let query_types = &settings.postgres.query_types;
+    let rule = query_types.rule_for("reports", "app").expect("matching rule");
+    assert!(!rule.permits(QueryType::Drop));
+    assert!(!rule.permits(QueryType::Truncate));
+    assert!(!rule.permits(QueryType::Copy));
+    assert!(rule.permits(QueryType::Select));
+    assert!(rule.permits(QueryType::Create));
+
+    let rule = query_types.rule_for("reports", "web").expect("matching rule");
+    assert!(rule.permits(QueryType::Select));
+    assert!(rule.permits(QueryType::Show));
+    assert!(!rule.permits(QueryType::SelectInto));
+    assert!(!rule.permits(QueryType::Insert));
+
+    assert!(query_types.rule_for("app", "web").is_none());
+}
+
+#[test]
+fn test_query_types_invalid() {
+    let result = load("postgres: {servers: [], query_types: {rules: [{user: app, deny: [Dorp]}]}}\nplugins: []");
+    assert!(result.err().unwrap().contains("unknown query type Dorp"));
+    let result = load("postgres: {servers: [], query_types: {rules: [{user: app}]}}\nplugins: []");
+    assert!(result.err().unwrap().contains("must allow or deny"));
+}
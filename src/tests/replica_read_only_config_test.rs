@@ -0,0 +1,42 @@
+use std::path::PathBuf;
+
+use crate::riverdb::config::Settings;
+use crate::riverdb::pg::PostgresReplicationGroup;
+
+#[test]
+fn test_replica_read_only() {
+    let yaml = r#"
+postgres:
+  servers:
+    - database: app
+      host: 127.0.0.1
+      can_query: true
+      replica_read_only: true
+      user_pools:
+        - {user: reporting, max_connections: 16}
+      replicas:
+        - {database: app, host: 127.0.0.2, can_query: true, replicas: []}
+plugins: []
+"#;
+    let mut settings: Settings = serde_yaml::from_str(yaml).expect("invalid yaml");
+    settings.load(PathBuf::new()).expect("valid settings");
+    let settings: &'static Settings = Box::leak(Box::new(settings));
+
+    // Replicas inherit the setting from the master
+    let server = &settings.postgres.servers[0];
+    assert!(server.replicas[0].replica_read_only);
+    assert!(server.replicas[0].user_pool_configs[0].replica_read_only);
+
+    // Only the replica pools are read only
+    let group = PostgresReplicationGroup::new(server);
+    let master = group.master().unwrap();
+    let replica = group.replicas()[0];
+    assert!(!master.is_read_only());
+    assert!(replica.is_read_only());
+    assert!(replica.user_pool("reporting", "app").unwrap().is_read_only());
+
+    // Until the replica is promoted
+    group.set_master(replica);
+    assert!(!replica.is_read_only());
+    assert!(!replica.user_pool("reporting", "app").unwrap().is_read_only());
+}
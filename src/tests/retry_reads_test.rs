@@ -0,0 +1,23 @@
+use crate::tests::common::{self, TestServer};
+use crate::tests::harness::{TestClient, start_mock_server_with_shutdowns};
+
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_retry_reads() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server_with_shutdowns(1);
+    let server = TestServer::with_settings(common::mock_settings(backend.port(), "", "retry_reads: true")?)?;
+    assert!(server.cluster().config.servers[0].retry_reads);
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    // The first SELECT fails with admin_shutdown and the connection closes, it's retried on a new connection
+    let result = client.simple_query("SELECT 1").await?;
+    assert_eq!(result.rows, vec![vec![Some("1".to_string())]]);
+    assert_eq!(client.tx_status, b'I');
+    let result = client.simple_query("SELECT 1").await?;
+    assert_eq!(result.rows, vec![vec![Some("1".to_string())]]);
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
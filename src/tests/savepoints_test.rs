@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+use crate::tests::common::{self, TestServer};
+use crate::tests::harness::{TestClient, start_mock_server};
+
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_rollback_open_transaction() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(common::mock_settings(backend.port(), &format!("admin_users: [{}]", common::TEST_USER), "")?)?;
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    client.simple_query("BEGIN").await?;
+    client.simple_query("SAVEPOINT a").await?;
+    client.simple_query("SAVEPOINT b").await?;
+    assert_eq!(client.tx_status, b'T');
+    let result = client.simple_query("SHOW CLIENTS").await?;
+    assert_eq!(result.rows[0][6].as_deref(), Some("2"));
+    // Disconnect in the transaction, the connection is rolled back before it's returned to the pool
+    client.terminate().await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    client.simple_query("SELECT 1").await?;
+    assert_eq!(client.tx_status, b'I');
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
@@ -0,0 +1,47 @@
+use crate::riverdb::config::ShardingStrategy;
+use crate::tests::common::load;
+
+const SERVERS: &str = r#"
+    - {host: 127.0.0.3, database: app, can_query: true, replicas: []}
+    - {host: 127.0.0.1, database: app, can_query: true, replicas: []}
+    - {host: 127.0.0.2, database: app, can_query: true, replicas: []}"#;
+
+#[test]
+fn test_sharding() {
+    let settings = load(&format!(r#"
+postgres:
+  servers:{}
+  sharding:
+    enabled: true
+    strategy: range
+    key_column: tenant_id
+    shards:
+      - server: 0
+      - {{server: 1, min: 1000}}
+      - {{server: 2, min: 5000}}
+plugins: []
+"#, SERVERS)).expect("valid settings");
+
+    let sharding = &settings.postgres.sharding;
+    assert_eq!(sharding.strategy, ShardingStrategy::Range);
+    assert_eq!(sharding.key_tag, "shard_key");
+    assert_eq!(sharding.shards[0].min, Some(i64::MIN));
+    assert_eq!(sharding.shards[2].min, Some(5000));
+    assert!(!sharding.require_key);
+}
+
+#[test]
+fn test_sharding_invalid() {
+    let invalid = &[
+        ("{enabled: true}", "at least one shard"),
+        ("{enabled: true, shards: [{server: 3}]}", "does not exist"),
+        ("{enabled: true, strategy: range, shards: [{server: 0}, {server: 1}]}", "requires a min"),
+        ("{enabled: true, strategy: range, shards: [{server: 0, min: 10}, {server: 1, min: 10}]}", "must be greater"),
+    ];
+    for &(sharding, error) in invalid {
+        let result = load(&format!("postgres:\n  servers:{}\n  sharding: {}\nplugins: []", SERVERS, sharding));
+        assert!(result.err().expect(sharding).contains(error), "{}", sharding);
+    }
+    let result = load(&format!("postgres:\n  servers:{}\n  sharding: {{enabled: true, shards: [{{server: 0}}]}}\n  shard_map: {{enabled: true}}\nplugins: []", SERVERS));
+    assert!(result.err().unwrap().contains("can't both be enabled"));
+}
@@ -0,0 +1,35 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::tests::common::TestServer;
+use crate::riverdb::config::Settings;
+
+
+const SSL_REQUEST: &[u8] = &[0, 0, 0, 8, 4, 210, 22, 47];
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_server_shutdown() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let settings: Settings = serde_yaml::from_str(r#"
+postgres:
+  default: {database: app, can_query: true, replicas: []}
+  servers:
+    - {database: app, host: 127.0.0.1, can_query: true, replicas: []}
+plugins: []
+"#)?;
+    let server = TestServer::with_settings(settings)?;
+    assert_ne!(server.port(), 0);
+    assert_eq!(server.cluster().config.servers[0].database, "app");
+
+    // The server doesn't accept the connection until the client sends something (TCP_DEFER_ACCEPT)
+    let mut client = TcpStream::connect(("127.0.0.1", server.port())).await?;
+    client.write_all(SSL_REQUEST).await?;
+    let mut buf = [0u8; 16];
+    assert_eq!(client.read(&mut buf).await?, 1);
+    assert_eq!(buf[0], b'N');
+    assert_eq!(server.connections().len(), 1);
+
+    server.shutdown().await;
+    assert_eq!(client.read(&mut buf).await?, 0);
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::{ClientConnection, ServerConnection, ServerName, ProtocolVersion};
+
+use crate::riverdb::config::{Settings, TlsVersion};
+use crate::riverdb::pg::{PostgresCluster, ServerTls};
+use crate::tests::common::load;
+
+const SERVER_CERT: &str = "src/tests/testdata/test-ca/rsa/end.fullchain";
+const SERVER_KEY: &str = "src/tests/testdata/test-ca/rsa/end.rsa";
+const OTHER_CERT: &str = "src/tests/testdata/test-ca/rsa/client.fullchain";
+const OTHER_KEY: &str = "src/tests/testdata/test-ca/rsa/client.rsa";
+
+/// Returns settings with both client_tls and backend_tls enabled, and the given extra TLS settings.
+/// The test certificates have expired, so backend_tls doesn't verify them.
+fn cluster_yaml(tls: &str) -> String {
+    format!(r#"
+postgres:
+  client_tls: required
+  backend_tls: dangerouslyunverifiedcertificates
+  tls_server_certificate: {}
+  tls_server_key: {}
+  {}
+  default: {{database: app, can_query: true, replicas: []}}
+  servers:
+    - {{database: app, host: 127.0.0.1, can_query: true, replicas: []}}
+plugins: []
+"#, SERVER_CERT, SERVER_KEY, tls)
+}
+
+/// Completes a TLS handshake in memory between the backend_tls config (as the client)
+/// and the client_tls config (as the server) of settings.
+fn handshake(settings: &Settings) -> (ClientConnection, ServerConnection) {
+    let server_name = ServerName::try_from("localhost").unwrap();
+    let mut client = ClientConnection::new(Arc::clone(settings.postgres.backend_tls_config.as_ref().unwrap()), server_name).unwrap();
+    let mut server = ServerConnection::new(Arc::clone(settings.postgres.tls_config.as_ref().unwrap())).unwrap();
+    while client.is_handshaking() || server.is_handshaking() {
+        let mut buf = Vec::new();
+        while client.wants_write() {
+            client.write_tls(&mut buf).unwrap();
+        }
+        server.read_tls(&mut buf.as_slice()).unwrap();
+        server.process_new_packets().unwrap();
+
+        let mut buf = Vec::new();
+        while server.wants_write() {
+            server.write_tls(&mut buf).unwrap();
+        }
+        client.read_tls(&mut buf.as_slice()).unwrap();
+        client.process_new_packets().unwrap();
+    }
+    (client, server)
+}
+
+#[test]
+fn test_tls_settings_defaults() {
+    let settings = load(&cluster_yaml("")).expect("valid settings");
+    assert_eq!(settings.postgres.tls_min_version, TlsVersion::Tls12);
+    assert!(settings.postgres.tls_cipher_suites.is_empty());
+    assert_eq!(settings.postgres.tls_session_cache_size, 256);
+    assert!(settings.postgres.tls_session_tickets);
+    assert!(settings.postgres.tls_alpn_protocols.is_empty());
+    assert_eq!(settings.postgres.tls_reload_check_seconds, 60);
+
+    let (client, server) = handshake(&settings);
+    assert_eq!(server.protocol_version(), Some(ProtocolVersion::TLSv1_3));
+    assert_eq!(client.alpn_protocol(), None);
+}
+
+#[test]
+fn test_tls_settings_cipher_suites() {
+    let settings = load(&cluster_yaml(
+        "tls_cipher_suites: [TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256]")).expect("valid settings");
+    let (_, server) = handshake(&settings);
+    assert_eq!(server.protocol_version(), Some(ProtocolVersion::TLSv1_2));
+    assert_eq!(format!("{:?}", server.negotiated_cipher_suite().unwrap().suite()), "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256");
+
+    let err = load(&cluster_yaml("tls_cipher_suites: [TLS_RSA_WITH_RC4_128_MD5]")).err().unwrap();
+    assert_eq!(err, "unknown cipher suite TLS_RSA_WITH_RC4_128_MD5 in tls_cipher_suites");
+
+    // A TLS 1.2 cipher suite can't be used with TLS 1.3 only
+    let err = load(&cluster_yaml(
+        "tls_min_version: tls13\n  tls_cipher_suites: [TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256]")).err().unwrap();
+    assert!(err.starts_with("invalid TLS settings"), "{}", err);
+}
+
+#[test]
+fn test_tls_settings_alpn() {
+    let settings = load(&cluster_yaml(
+        "tls_min_version: tls13\n  tls_alpn_protocols: [postgresql]\n  tls_session_tickets: false\n  tls_session_cache_size: 0")).expect("valid settings");
+    assert_eq!(settings.postgres.tls_min_version, TlsVersion::Tls13);
+    let (client, server) = handshake(&settings);
+    assert_eq!(server.protocol_version(), Some(ProtocolVersion::TLSv1_3));
+    assert_eq!(client.alpn_protocol(), Some(&b"postgresql"[..]));
+    assert_eq!(server.alpn_protocol(), Some(&b"postgresql"[..]));
+}
+
+#[tokio::test]
+async fn test_tls_certificate_reload() {
+    let dir = std::env::temp_dir().join(format!("riverdb-tls-reload-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let cert = dir.join("server.cert");
+    let key = dir.join("server.key");
+    fs::copy(SERVER_CERT, &cert).unwrap();
+    fs::copy(SERVER_KEY, &key).unwrap();
+
+    let settings = load(&format!(r#"
+postgres:
+  client_tls: required
+  tls_server_certificate: {}
+  tls_server_key: {}
+  tls_reload_check_seconds: 1
+  servers: []
+plugins: []
+"#, cert.display(), key.display())).expect("valid settings");
+    let settings: &'static Settings = Box::leak(Box::new(settings));
+    let cluster: &'static PostgresCluster = Box::leak(Box::new(PostgresCluster::new(&settings.postgres)));
+    let before = cluster.server_tls().unwrap();
+    tokio::spawn(cluster.run_tls_reload_checks());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Renew the certificate, it's reloaded once the files are unchanged for one check
+    fs::copy(OTHER_CERT, &cert).unwrap();
+    fs::copy(OTHER_KEY, &key).unwrap();
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if !Arc::ptr_eq(&cluster.server_tls().unwrap(), &before) {
+            break;
+        }
+    }
+    let after = cluster.server_tls().unwrap();
+    assert!(!Arc::ptr_eq(&after, &before));
+    assert_ne!(after.end_point, before.end_point);
+
+    // An invalid certificate is not loaded, the current one is kept
+    fs::write(&cert, "not a certificate").unwrap();
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert!(Arc::ptr_eq(&cluster.server_tls().unwrap(), &after));
+
+    // After a RELOAD with other certificate files, the files of the old config are no longer watched
+    let other_cert = dir.join("other.cert");
+    let other_key = dir.join("other.key");
+    fs::copy(SERVER_CERT, &other_cert).unwrap();
+    fs::copy(SERVER_KEY, &other_key).unwrap();
+    let reloaded = load(&format!(r#"
+postgres:
+  client_tls: required
+  tls_server_certificate: {}
+  tls_server_key: {}
+  tls_reload_check_seconds: 1
+  servers: []
+plugins: []
+"#, other_cert.display(), other_key.display())).expect("valid settings");
+    let reloaded: &'static Settings = Box::leak(Box::new(reloaded));
+    let after_reload = ServerTls::from_config(&reloaded.postgres).unwrap();
+    cluster.set_server_tls(after_reload.clone());
+    fs::copy(OTHER_CERT, &cert).unwrap();
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert!(Arc::ptr_eq(&cluster.server_tls().unwrap(), &after_reload));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -9,6 +9,7 @@ use rustls::{PrivateKey, Certificate};
 use crate::tests::common;
 use crate::riverdb::config::TlsMode;
 use crate::riverdb::server::{DangerousCertificateNonverifier, Transport};
+use crate::riverdb::pg::protocol::sasl;
 
 
 const SSL_REQUEST: &[u8] = &[0, 0, 0, 8, 4, 210, 22, 47];
@@ -76,3 +77,40 @@ async fn test_tls_server_handshake() -> Result<(), Box<dyn Error>> {
     psql.kill()?;
     Ok(())
 }
+
+#[tokio::test]
+async fn test_tls_server_end_point() -> Result<(), Box<dyn Error>> {
+    let mut certs: &[u8] = include_bytes!("testdata/test-ca/rsa/end.fullchain");
+    let mut private_key: &[u8] = include_bytes!("testdata/test-ca/rsa/end.rsa");
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut certs)?
+        .into_iter()
+        .map(|cert| Certificate(cert))
+        .collect();
+    let end_point = sasl::tls_server_end_point(&certs[0].0).expect("the certificate is signed with sha256WithRSAEncryption");
+    let key = PrivateKey(rustls_pemfile::rsa_private_keys(&mut private_key)?.pop().unwrap());
+    let server_conf = rustls::server_config_builder_with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    // A blocking TLS server on another thread, standing in for Postgres
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = std::thread::spawn(move || {
+        let (mut sock, _) = listener.accept().unwrap();
+        let mut conn = rustls::ServerConnection::new(Arc::new(server_conf)).unwrap();
+        // The client's Finished message isn't sent until it writes, so this ends when it disconnects
+        while conn.is_handshaking() && conn.complete_io(&mut sock).is_ok() {}
+    });
+
+    let conf = rustls::client_config_builder_with_safe_defaults()
+        .with_custom_certificate_verifier(DangerousCertificateNonverifier::new())
+        .with_no_client_auth();
+    let t = Transport::new(TcpStream::connect(addr).await?);
+    assert_eq!(t.tls_server_end_point(), None);
+    t.upgrade_client(Arc::new(conf), TlsMode::DangerouslyUnverifiedCertificates, "localhost").await?;
+    // The channel binding data of the server certificate, used for SCRAM-SHA-256-PLUS
+    assert_eq!(t.tls_server_end_point(), Some(end_point));
+    t.close();
+    server.join().unwrap();
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use crate::tests::common::{self, TestServer};
+use crate::tests::harness::{TestClient, start_mock_server};
+use crate::riverdb::config::Settings;
+
+
+fn trace_settings(port: u16, propagate: &str) -> Result<Settings, serde_yaml::Error> {
+    common::mock_settings(port, &format!("query_tags: {{propagate: {}}}", propagate), "")
+}
+
+async fn show(client: &mut TestClient, sql: &str) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    let result = client.simple_query(sql).await?;
+    Ok(result.rows[0][0].clone().unwrap_or_default())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_application_name_propagation() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(trace_settings(backend.port(), "application_name")?)?;
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    let name = show(&mut client, "/* trace_id=abc-123 */ SHOW application_name").await?;
+    assert!(name.ends_with(" trace_id=abc-123"), "{}", name);
+    // trace_id is preferred to request_id
+    let name = show(&mut client, "/* request_id=r1 trace_id=t1 */ SHOW application_name").await?;
+    assert!(name.ends_with(" trace_id=t1"), "{}", name);
+    let name = show(&mut client, "/* request_id=r2 */ SHOW application_name").await?;
+    assert!(name.ends_with(" request_id=r2"), "{}", name);
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_set_local_propagation() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(trace_settings(backend.port(), "set_local")?)?;
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    assert_eq!(show(&mut client, "/* trace_id=o'brien */ SHOW riverdb.trace_id").await?, "o'brien");
+
+    // Added to the held BEGIN
+    client.simple_query("BEGIN").await?;
+    assert_eq!(show(&mut client, "/* trace_id=t2 */ SHOW riverdb.trace_id").await?, "t2");
+    assert_eq!(show(&mut client, "/* trace_id=t3 */ SHOW riverdb.trace_id").await?, "t3");
+    client.simple_query("COMMIT").await?;
+    assert_eq!(client.tx_status, b'I');
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_set_local_propagation_without_reset_query() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let settings = common::mock_settings(backend.port(), "query_tags: {propagate: set_local}\nserver_reset_query: ''", "")?;
+    let server = TestServer::with_settings(settings)?;
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    assert_eq!(show(&mut client, "/* trace_id=t1 */ SHOW riverdb.trace_id").await?, "t1");
+    // The trace id doesn't outlive the query outside a transaction, even without a server_reset_query
+    assert_eq!(show(&mut client, "SHOW riverdb.trace_id").await?, "");
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
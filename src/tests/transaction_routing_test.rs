@@ -0,0 +1,115 @@
+use crate::tests::common::{self, TestServer};
+use crate::tests::harness::{QueryResult, TestClient, start_mock_server};
+use crate::riverdb::config::Settings;
+use crate::riverdb::pg::extended_query;
+
+
+/// Settings for a cluster of two mock servers, sharded by tenant_id: below 1000 on shard0, 1000 and up on shard1.
+fn sharded_settings(shard0: u16, shard1: u16, unbuffered_begin: bool) -> Settings {
+    serde_yaml::from_str(&format!(r#"
+postgres:
+  servers:
+    - {{database: {database}, host: 127.0.0.1, port: {port0}, user: {user}, password: "{password}", max_connections: 16, can_query: true, replicas: []}}
+    - {{database: {database}, host: 127.0.0.1, port: {port1}, user: {user}, password: "{password}", max_connections: 16, can_query: true, replicas: []}}
+  unbuffered_begin: {unbuffered_begin}
+  sharding:
+    enabled: true
+    strategy: range
+    key_column: tenant_id
+    shards:
+      - server: 0
+      - {{server: 1, min: 1000}}
+plugins: []
+"#, database=common::TEST_DATABASE, user=common::TEST_USER, password=common::TEST_PASSWORD, port0=shard0, port1=shard1,
+        unbuffered_begin=unbuffered_begin)).expect("valid settings")
+}
+
+fn port(result: QueryResult) -> u16 {
+    result.rows[0][0].as_ref().unwrap().parse().unwrap()
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_transaction_routing() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (shard0, shard1) = (start_mock_server(), start_mock_server());
+    let server = TestServer::with_settings(sharded_settings(shard0.port(), shard1.port(), true))?;
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+
+    // BEGIN has no shard key, the transaction is bound to the node of the database
+    client.simple_query("BEGIN").await?;
+    assert_eq!(client.tx_status, b'T');
+    assert_eq!(port(client.simple_query("SELECT inet_server_port() WHERE tenant_id = 5").await?), shard0.port());
+    let err = client.simple_query("SELECT inet_server_port() WHERE tenant_id = 1500").await.unwrap_err();
+    assert!(err.to_string().contains("can't switch nodes"), "{}", err);
+    assert_eq!(port(client.simple_query("SELECT inet_server_port()").await?), shard0.port());
+    client.simple_query("COMMIT").await?;
+    assert_eq!(client.tx_status, b'I');
+
+    // After the transaction, queries are routed by their shard key again
+    assert_eq!(port(client.simple_query("SELECT inet_server_port() WHERE tenant_id = 1500").await?), shard1.port());
+    assert_eq!(port(client.simple_query("SELECT inet_server_port() WHERE tenant_id = 5").await?), shard0.port());
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_buffered_begin() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (shard0, shard1) = (start_mock_server(), start_mock_server());
+    let server = TestServer::with_settings(sharded_settings(shard0.port(), shard1.port(), false))?;
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+
+    // BEGIN and SET are answered by riverdb, and sent with the first query, which chooses the node
+    let result = client.simple_query("BEGIN").await?;
+    assert_eq!(result.tags, vec!["BEGIN".to_string()]);
+    assert_eq!(client.tx_status, b'T');
+    let result = client.simple_query("SET LOCAL statement_timeout = 1000").await?;
+    assert_eq!(result.tags, vec!["SET".to_string()]);
+    // The results of the held statements aren't forwarded a second time
+    let result = client.simple_query("SELECT inet_server_port() WHERE tenant_id = 1500").await?;
+    assert_eq!(result.tags, vec!["SELECT 1".to_string()]);
+    assert_eq!(port(result), shard1.port());
+    assert_eq!(client.tx_status, b'T');
+    let err = client.simple_query("SELECT inet_server_port() WHERE tenant_id = 5").await.unwrap_err();
+    assert!(err.to_string().contains("can't switch nodes"), "{}", err);
+    client.simple_query("COMMIT").await?;
+    assert_eq!(client.tx_status, b'I');
+
+    // A transaction that's only BEGIN is sent to the database on COMMIT
+    client.simple_query("BEGIN").await?;
+    client.simple_query("COMMIT").await?;
+    assert_eq!(client.tx_status, b'I');
+    assert_eq!(port(client.simple_query("SELECT inet_server_port() WHERE tenant_id = 5").await?), shard0.port());
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_buffered_begin_extended() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(common::mock_settings(backend.port(), "", "")?)?;
+
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    client.simple_query("BEGIN").await?;
+    client.simple_query("SET LOCAL statement_timeout = 1000").await?;
+
+    // The held statements are executed before an extended query, in the same write
+    client.send(extended_query("SELECT $1", &[Some("a")])?).await?;
+    let result = client.read_until_ready().await?;
+    assert_eq!(result.tags, vec!["SELECT 1".to_string()]);
+    assert_eq!(result.rows, vec![vec![Some("a".to_string())]]);
+    assert_eq!(client.tx_status, b'T');
+    client.simple_query("COMMIT").await?;
+    assert_eq!(client.tx_status, b'I');
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
@@ -0,0 +1,35 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+use crate::tests::common::{self, TestServer};
+use crate::tests::harness::{TestClient, start_mock_server};
+
+
+const SSL_REQUEST: &[u8] = &[0, 0, 0, 8, 4, 210, 22, 47];
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_unix_socket() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join(format!("riverdb-unix-socket-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let backend = start_mock_server();
+    let server = TestServer::with_settings(common::mock_settings(backend.port(), &format!("unix_socket_dir: {}", dir.display()), "")?)?;
+    let path = server.unix_socket_path().expect("unix socket").to_path_buf();
+    assert_eq!(path, dir.join(format!(".s.PGSQL.{}", server.cluster().config.port)));
+
+    let mut client = TestClient::connect_unix(&path, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    let result = client.simple_query("SELECT 1").await?;
+    assert_eq!(result.rows, vec![vec![Some("1".to_string())]]);
+    client.terminate().await?;
+
+    // TLS isn't offered on the unix socket
+    let mut stream = UnixStream::connect(&path).await?;
+    stream.write_all(SSL_REQUEST).await?;
+    let mut buf = [0u8; 16];
+    assert_eq!(stream.read(&mut buf).await?, 1);
+    assert_eq!(buf[0], b'N');
+
+    server.shutdown().await;
+    let _ = std::fs::remove_dir_all(&dir);
+    Ok(())
+}
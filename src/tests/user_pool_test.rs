@@ -0,0 +1,31 @@
+use crate::tests::common::{self, TestServer};
+use crate::tests::harness::{TestClient, start_mock_server};
+
+
+#[tokio::test]
+#[serial_test::serial]
+async fn test_user_pool_database() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let backend = start_mock_server();
+    let user_pools = format!("user_pools: [{{user: {}, password: \"{}\", database: analytics, max_connections: 2}}]",
+        common::TEST_USER, common::TEST_PASSWORD);
+    let server = TestServer::with_settings(common::mock_settings(backend.port(), "", &user_pools)?)?;
+    let master = server.cluster().nodes[0].master().expect("master");
+    let user_pool = master.user_pool(common::TEST_USER, "analytics").expect("user pool");
+
+    // Clients connecting to the database of the user pool use it, not the main pool of the server
+    let addr = format!("127.0.0.1:{}", server.port()).parse()?;
+    let mut client = TestClient::connect(addr, common::TEST_USER, "analytics", common::TEST_PASSWORD).await?;
+    let result = client.simple_query("SELECT 1").await?;
+    assert_eq!(result.rows, vec![vec![Some("1".to_string())]]);
+    assert_eq!(user_pool.connections.len(), 1);
+    assert_eq!(master.connections.len(), 0);
+    client.terminate().await?;
+
+    let mut client = TestClient::connect(addr, common::TEST_USER, common::TEST_DATABASE, common::TEST_PASSWORD).await?;
+    client.simple_query("SELECT 1").await?;
+    assert_eq!(master.connections.len(), 1);
+    assert_eq!(user_pool.connections.len(), 1);
+    client.terminate().await?;
+    server.shutdown().await;
+    Ok(())
+}
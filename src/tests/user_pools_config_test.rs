@@ -0,0 +1,48 @@
+use crate::tests::common::load;
+
+#[test]
+fn test_user_pools() {
+    let settings = load(r#"
+postgres:
+  servers:
+    - database: app
+      host: 127.0.0.1
+      user: riverdb
+      can_query: true
+      user_pools:
+        - {user: reporting, password: secret}
+        - {user: reporting, database: analytics, max_connections: 5}
+      replicas:
+        - {database: app, host: 127.0.0.2, can_query: true, replicas: []}
+plugins: []
+"#).expect("valid settings");
+
+    let server = &settings.postgres.servers[0];
+    assert_eq!(server.user_pools[0].database, "app");
+    assert_eq!(server.user_pools[0].max_connections, 10);
+    let reporting = &server.user_pool_configs[0];
+    assert_eq!((reporting.user.as_str(), reporting.password.as_str(), reporting.database.as_str()), ("reporting", "secret", "app"));
+    assert_eq!(reporting.address, server.address);
+    let analytics = &server.user_pool_configs[1];
+    assert_eq!((analytics.database.as_str(), analytics.max_connections), ("analytics", 5));
+
+    // Replicas use the user pools of the master
+    let replica = &server.replicas[0];
+    assert_eq!(replica.user_pool_configs.len(), 2);
+    assert_eq!(replica.user_pool_configs[0].address, replica.address);
+}
+
+#[test]
+fn test_user_pools_invalid() {
+    let tests = &[
+        (r#"user_pools: [{user: "", password: secret}]"#, "user_pools entry missing user at index 0"),
+        ("user_pools: [{user: a}, {user: a, database: app}]", "user_pools has more than one pool for a@app"),
+    ];
+
+    for (user_pools, err) in tests {
+        let yaml = format!("postgres:\n  servers:\n    - {{database: app, host: 127.0.0.1, can_query: true, replicas: [], {}}}\nplugins: []", user_pools);
+        let result = load(&yaml);
+        assert!(result.is_err(), "{}", yaml);
+        assert!(result.err().unwrap().contains(err), "{}", yaml);
+    }
+}